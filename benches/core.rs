@@ -0,0 +1,203 @@
+//! Microbenchmarks of the hot structures: the blocks of the tables and the skiplist of the
+//! memtables
+//!
+//! The blocks save an index snapshot every 10 entries, a constant of their on-disk format: the
+//! block benchmarks vary the value size instead, and with it the entries per block and so the
+//! snapshots per block. The skiplist lookups vary how many inserts happen per snapshot.
+//!
+//! Run with `cargo bench --bench core`, optionally filtered, e.g. `cargo bench -- block_insert`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use fyodor::key::{self, ValueType};
+use fyodor::memtable::{MemTable, SkipList};
+use fyodor::storage::{Block, BlockBuffer};
+
+/// Size of the blocks benchmarked, the default block size of the tables
+const BLOCK_SIZE: usize = 4096;
+
+/// Sizes of the values of the entries, the keys being 16 bytes
+const VALUE_SIZES: [usize; 4] = [8, 64, 256, 1024];
+
+/// Number of entries in the skiplists benchmarked
+const SKIPLIST_ENTRIES: u64 = 10_000;
+
+/// Number of keys written over and over in the skiplist lookups
+const SKIPLIST_LOOKUP_KEYS: u64 = 1_000;
+
+fn entry_key(n: u64) -> [u8; 16] {
+    let mut key = [0; 16];
+    key[8..].copy_from_slice(&n.to_be_bytes());
+    key
+}
+
+/// Returns a block filled with entries of values of `value_size` bytes, and how many it holds
+fn full_block(value_size: usize) -> (BlockBuffer, u64) {
+    let mut buffer = BlockBuffer::new(BLOCK_SIZE);
+    let value = vec![0; value_size];
+    let mut entries = 0;
+
+    while buffer
+        .block_mut()
+        .insert(&entry_key(entries), &value)
+        .is_ok()
+    {
+        entries += 1;
+    }
+
+    (buffer, entries)
+}
+
+/// Fills a block, the time of each fill depending on how many entries it takes
+fn block_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_insert");
+
+    for value_size in VALUE_SIZES {
+        let value = vec![0; value_size];
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(value_size),
+            &value,
+            |b, value| {
+                b.iter_batched_ref(
+                    || BlockBuffer::new(BLOCK_SIZE),
+                    |buffer| {
+                        let block: &mut Block = buffer.block_mut();
+                        let mut n = 0;
+
+                        while block.insert(&entry_key(n), value).is_ok() {
+                            n += 1;
+                        }
+                    },
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Looks up every entry of a full block: the binary search over the index snapshots, then the
+/// scan from the closest one, whose length depends on the entries per block
+fn block_binary_search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_binary_search");
+
+    for value_size in VALUE_SIZES {
+        let (buffer, entries) = full_block(value_size);
+        let block = buffer.block();
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(value_size),
+            &entries,
+            |b, &entries| {
+                let mut n = 0;
+
+                b.iter(|| {
+                    let target = entry_key(n % entries);
+                    n += 1;
+
+                    let offset = block.binary_search(|key| key.cmp(&target));
+
+                    block
+                        .iter_from(offset)
+                        .find(|entry| entry.key() >= target.as_slice())
+                        .map(|entry| black_box(entry.value().len()))
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Iterates over all the entries of a full block
+fn block_iterator(c: &mut Criterion) {
+    let mut group = c.benchmark_group("block_iterator");
+
+    for value_size in VALUE_SIZES {
+        let (buffer, _) = full_block(value_size);
+        let block = buffer.block();
+
+        group.bench_function(BenchmarkId::from_parameter(value_size), |b| {
+            b.iter(|| {
+                block
+                    .into_iter()
+                    .map(|entry| entry.key().len() + entry.value().len())
+                    .sum::<usize>()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Inserts [SKIPLIST_ENTRIES] internal keys in random order into a skiplist
+fn skiplist_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("skiplist_insert");
+    group.sample_size(20);
+
+    for value_size in VALUE_SIZES {
+        let value = vec![0; value_size];
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(value_size),
+            &value,
+            |b, value| {
+                b.iter(|| {
+                    let mut list = SkipList::new();
+
+                    for n in 0..SKIPLIST_ENTRIES {
+                        // A bijection of 0..2^64, so that the keys are unique but not sorted
+                        let user_key = entry_key(n.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+                        list.insert(key::encode(&user_key, n, ValueType::Value), value.clone());
+                    }
+
+                    list
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Looks up keys in a memtable where a snapshot is taken every `inserts_per_snapshot` inserts,
+/// as of the latest snapshot just before the next one is due: the lookups skip the versions
+/// written since, a key being written once every [SKIPLIST_LOOKUP_KEYS] inserts
+fn skiplist_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("skiplist_lookup");
+    let keys = SKIPLIST_LOOKUP_KEYS;
+
+    for inserts_per_snapshot in [1_000, 4_000, 16_000, 64_000] {
+        let mem = MemTable::new();
+
+        // The snapshot is taken after the first round of writes, seeing every key
+        let snapshot = keys;
+
+        for seq in 1..snapshot + inserts_per_snapshot {
+            mem.add(seq, ValueType::Value, &entry_key(seq % keys), &[0; 64]);
+        }
+
+        group.bench_function(BenchmarkId::from_parameter(inserts_per_snapshot), |b| {
+            let mut n = 0;
+
+            b.iter(|| {
+                let found = mem.get(&entry_key(n % keys), snapshot);
+                n += 1;
+                black_box(found.is_some())
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    block_insert,
+    block_binary_search,
+    block_iterator,
+    skiplist_insert,
+    skiplist_lookup
+);
+criterion_main!(benches);
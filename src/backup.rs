@@ -0,0 +1,695 @@
+use crate::blob;
+use crate::db::{self, Db, DbError};
+use crate::rate_limiter::RateLimiter;
+use crate::table;
+use crate::version;
+use crate::wal;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes read or written at once when copying a file to a backup
+const COPY_CHUNK_SIZE: usize = 1 << 20;
+
+/// Knobs of a [BackupEngine]
+#[derive(Clone, Debug, Default)]
+pub struct BackupOptions {
+    /// Limits the bytes written to the backups, and the ones read to checksum the files if it
+    /// [limits reads](RateLimiter::limits_reads)
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl BackupOptions {
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+}
+
+/// Knobs of a [BackupEngine::restore]
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// Keeps the logs already in the log directory, the ones of the backup only being restored
+    /// where no log has their number: the writes logged since the backup are then recovered on
+    /// top of it, as long as no log was retired since
+    pub keep_log_files: bool,
+}
+
+impl RestoreOptions {
+    pub fn with_keep_log_files(mut self, keep_log_files: bool) -> Self {
+        self.keep_log_files = keep_log_files;
+        self
+    }
+}
+
+/// A backup kept by a [BackupEngine]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: u32,
+    /// Seconds since the Unix epoch at which the backup was created
+    pub timestamp: u64,
+    /// Bytes of the files of the backup, the ones shared with other backups included
+    pub size: u64,
+    pub num_files: usize,
+}
+
+/// A file of a backup, as listed in its metadata
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BackupFile {
+    /// Path of the file relative to the backup directory
+    pub(crate) path: String,
+    /// Name of the file in the database
+    pub(crate) name: String,
+    pub(crate) size: u64,
+    pub(crate) crc: u32,
+}
+
+/// Backs databases up to a directory, one numbered backup per [BackupEngine::create_backup]
+///
+/// The directory holds:
+/// - `shared/`, the tables and blob files, named after their name in the database, their CRC32
+///   and their size, so that a file unchanged since a previous backup is only copied once
+/// - `private/<id>/`, the logs, the manifest and the OPTIONS file of every backup
+/// - `meta/<id>`, the list of the files of every backup, written last so that a backup
+///   interrupted midway doesn't exist
+///
+/// The files shared by the backups are only deleted along with the last backup listing them. A
+/// backup directory must only be used by one engine at a time.
+pub struct BackupEngine {
+    dir: PathBuf,
+    options: BackupOptions,
+}
+
+impl BackupEngine {
+    /// Opens the backups at `dir`, creating the directory if needed and deleting what the
+    /// backups interrupted midway left
+    pub fn open<P: AsRef<Path>>(dir: P, options: BackupOptions) -> Result<BackupEngine, DbError> {
+        let engine = BackupEngine {
+            dir: dir.as_ref().to_path_buf(),
+            options,
+        };
+
+        for subdir in ["shared", "private", "meta"] {
+            std::fs::create_dir_all(engine.dir.join(subdir))?;
+        }
+
+        for subdir in ["shared", "private", "meta"] {
+            for entry in std::fs::read_dir(engine.dir.join(subdir))? {
+                let path = entry?.path();
+
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    remove_path(&path)?;
+                }
+            }
+        }
+
+        engine.delete_unreferenced_files()?;
+
+        Ok(engine)
+    }
+
+    /// Backs `db` up as of now, returning the new backup
+    ///
+    /// The backup starts from a [Db::checkpoint]: the tables and blob files already in
+    /// `shared/` with the same CRC32 and size are reused rather than copied again.
+    pub fn create_backup(&self, db: &Db) -> Result<BackupInfo, DbError> {
+        let id = self.backup_ids()?.last().map_or(1, |id| id + 1);
+        let private_dir = self.dir.join("private").join(id.to_string());
+        let staging_dir = private_dir.with_extension("tmp");
+        std::fs::create_dir_all(&staging_dir)?;
+
+        let result = self.write_backup(db, id, &staging_dir, &private_dir);
+
+        if result.is_err() && staging_dir.exists() {
+            std::fs::remove_dir_all(&staging_dir)?;
+        }
+
+        result
+    }
+
+    fn write_backup(
+        &self,
+        db: &Db,
+        id: u32,
+        staging_dir: &Path,
+        private_dir: &Path,
+    ) -> Result<BackupInfo, DbError> {
+        let checkpoint = db.write_checkpoint(staging_dir)?;
+        let mut files = Vec::new();
+
+        for (path, name) in &checkpoint.files {
+            let (size, crc) = self.checksum(path)?;
+            let shared_path = shared_file_path(name, size, crc);
+            let target = self.dir.join(&shared_path);
+
+            if !target.exists() {
+                let tmp_target = target.with_extension("tmp");
+
+                // The file may only change if the database was corrupted meanwhile
+                if self.copy(path, &tmp_target)? != (size, crc) {
+                    return Err(DbError::Corruption("file changed while backed up"));
+                }
+
+                std::fs::rename(&tmp_target, &target)?;
+            }
+
+            files.push(BackupFile {
+                path: shared_path,
+                name: name.clone(),
+                size,
+                crc,
+            });
+        }
+
+        drop(checkpoint);
+        db::sync_dir(&self.dir.join("shared"))?;
+
+        for entry in std::fs::read_dir(staging_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let (size, crc) = self.checksum(&entry.path())?;
+
+            files.push(BackupFile {
+                path: format!("private/{}/{}", id, name),
+                name,
+                size,
+                crc,
+            });
+        }
+
+        std::fs::rename(staging_dir, private_dir)?;
+        db::sync_dir(&self.dir.join("private"))?;
+
+        let info = BackupInfo {
+            id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs()),
+            size: files.iter().map(|file| file.size).sum(),
+            num_files: files.len(),
+        };
+        self.write_meta(&info, &files)?;
+
+        log::info!(
+            "created backup {} in {}: {} files, {} bytes",
+            id,
+            self.dir.display(),
+            info.num_files,
+            info.size
+        );
+
+        Ok(info)
+    }
+
+    /// Restores the backup `id` as the database at `db_dir`, whose logs go to `wal_dir`, checking
+    /// the CRC32 of every file on the way
+    ///
+    /// The files of the database already there are deleted first, its logs too unless
+    /// [RestoreOptions::keep_log_files]. The database must not be open meanwhile.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        id: u32,
+        db_dir: P,
+        wal_dir: Q,
+        options: &RestoreOptions,
+    ) -> Result<(), DbError> {
+        let (db_dir, wal_dir) = (db_dir.as_ref(), wal_dir.as_ref());
+        let (_, files) = self.read_meta(id)?;
+
+        std::fs::create_dir_all(db_dir)?;
+        std::fs::create_dir_all(wal_dir)?;
+        let _lock = db::lock_db(db_dir)?;
+
+        for dir in [db_dir, wal_dir] {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name();
+                let Some(name) = name.to_str() else {
+                    continue;
+                };
+
+                let log = wal::parse_log_file_name(name).is_some();
+                let db_file = table::parse_table_file_name(name).is_some()
+                    || blob::parse_blob_file_name(name).is_some()
+                    || version::parse_manifest_file_name(name).is_some()
+                    || name == "CURRENT"
+                    || name == "OPTIONS";
+
+                if db_file && dir == db_dir || log && !options.keep_log_files {
+                    std::fs::remove_file(entry.path())?;
+                }
+            }
+        }
+
+        for file in &files {
+            let target = match wal::parse_log_file_name(&file.name) {
+                Some(_) => wal_dir.join(&file.name),
+                None => db_dir.join(&file.name),
+            };
+
+            if target.exists() {
+                continue;
+            }
+
+            if self.copy(&self.dir.join(&file.path), &target)? != (file.size, file.crc) {
+                std::fs::remove_file(&target)?;
+                return Err(DbError::Corruption("backup file checksum mismatch"));
+            }
+        }
+
+        db::sync_dir(db_dir)?;
+        db::sync_dir(wal_dir)?;
+
+        log::info!("restored backup {} to {}", id, db_dir.display());
+
+        Ok(())
+    }
+
+    /// Returns the backups from the oldest to the newest
+    pub fn backups(&self) -> Result<Vec<BackupInfo>, DbError> {
+        self.backup_ids()?
+            .into_iter()
+            .map(|id| {
+                let (info, _) = self.read_meta(id)?;
+                Ok(info)
+            })
+            .collect()
+    }
+
+    /// Checks that every file of the backup `id` is there, with the size and the CRC32 it had
+    /// when backed up
+    pub fn verify_backup(&self, id: u32) -> Result<(), DbError> {
+        let (_, files) = self.read_meta(id)?;
+
+        for file in files {
+            let path = self.dir.join(&file.path);
+
+            if !path.exists() {
+                return Err(DbError::Corruption("backup file is missing"));
+            }
+
+            let (size, crc) = self.checksum(&path)?;
+
+            if size != file.size {
+                return Err(DbError::Corruption("backup file has the wrong size"));
+            }
+
+            if crc != file.crc {
+                return Err(DbError::Corruption("backup file checksum mismatch"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the backup `id`, along with the shared files no other backup lists
+    pub fn delete_backup(&self, id: u32) -> Result<(), DbError> {
+        let meta_path = self.meta_path(id);
+
+        if !meta_path.exists() {
+            return Err(DbError::InvalidArgument("no such backup"));
+        }
+
+        // The backup is gone once its metadata is
+        std::fs::remove_file(&meta_path)?;
+        db::sync_dir(&self.dir.join("meta"))?;
+
+        self.delete_unreferenced_files()
+    }
+
+    /// Deletes the oldest backups until only the `num_backups_to_keep` newest ones are left
+    pub fn purge_old_backups(&self, num_backups_to_keep: usize) -> Result<(), DbError> {
+        let ids = self.backup_ids()?;
+        let num_to_delete = ids.len().saturating_sub(num_backups_to_keep);
+
+        for id in &ids[..num_to_delete] {
+            self.delete_backup(*id)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the ids of the backups in increasing order
+    fn backup_ids(&self) -> Result<Vec<u32>, DbError> {
+        let mut ids = Vec::new();
+
+        for entry in std::fs::read_dir(self.dir.join("meta"))? {
+            if let Some(id) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            {
+                ids.push(id);
+            }
+        }
+
+        ids.sort_unstable();
+
+        Ok(ids)
+    }
+
+    /// Deletes the private directories of the backups which don't exist, and the shared files
+    /// no backup lists
+    fn delete_unreferenced_files(&self) -> Result<(), DbError> {
+        let ids: HashSet<u32> = self.backup_ids()?.into_iter().collect();
+        let mut shared = HashSet::new();
+
+        for id in &ids {
+            let (_, files) = self.read_meta(*id)?;
+            shared.extend(files.into_iter().map(|file| file.path));
+        }
+
+        for entry in std::fs::read_dir(self.dir.join("private"))? {
+            let entry = entry?;
+            let id = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok());
+
+            if id.is_none_or(|id| !ids.contains(&id)) {
+                remove_path(&entry.path())?;
+            }
+        }
+
+        for entry in std::fs::read_dir(self.dir.join("shared"))? {
+            let entry = entry?;
+            let path = format!("shared/{}", entry.file_name().to_string_lossy());
+
+            if !shared.contains(&path) {
+                log::debug!("deleting unreferenced backup file {}", path);
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn meta_path(&self, id: u32) -> PathBuf {
+        self.dir.join("meta").join(id.to_string())
+    }
+
+    /// Writes the metadata of the backup `info`, made of `files`
+    ///
+    /// The file starts with `id=`, `timestamp=`, `size=` and `num_files=` lines, followed by a
+    /// `file=<path> <name> <size> <crc>` line per file. It's written under a temporary name and
+    /// renamed once complete.
+    fn write_meta(&self, info: &BackupInfo, files: &[BackupFile]) -> Result<(), DbError> {
+        let mut contents = format!(
+            "id={}\ntimestamp={}\nsize={}\nnum_files={}\n",
+            info.id, info.timestamp, info.size, info.num_files
+        );
+
+        for file in files {
+            contents.push_str(&format!(
+                "file={} {} {} {:08x}\n",
+                file.path, file.name, file.size, file.crc
+            ));
+        }
+
+        let path = self.meta_path(info.id);
+        let tmp_path = path.with_extension("tmp");
+
+        let mut meta = File::create(&tmp_path)?;
+        meta.write_all(contents.as_bytes())?;
+        meta.sync_all()?;
+
+        std::fs::rename(&tmp_path, &path)?;
+        db::sync_dir(&self.dir.join("meta"))
+    }
+
+    /// Reads the metadata of the backup `id`, see [BackupEngine::write_meta]
+    pub(crate) fn read_meta(&self, id: u32) -> Result<(BackupInfo, Vec<BackupFile>), DbError> {
+        let contents = match std::fs::read_to_string(self.meta_path(id)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(DbError::InvalidArgument("no such backup"))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let bad_meta = || DbError::Corruption("bad backup metadata");
+        let mut info = BackupInfo {
+            id,
+            timestamp: 0,
+            size: 0,
+            num_files: 0,
+        };
+        let mut files = Vec::new();
+
+        for line in contents.lines() {
+            let (name, value) = line.split_once('=').ok_or_else(bad_meta)?;
+
+            match name {
+                "id" => info.id = value.parse().map_err(|_| bad_meta())?,
+                "timestamp" => info.timestamp = value.parse().map_err(|_| bad_meta())?,
+                "size" => info.size = value.parse().map_err(|_| bad_meta())?,
+                "num_files" => info.num_files = value.parse().map_err(|_| bad_meta())?,
+                "file" => {
+                    let fields: Vec<_> = value.split(' ').collect();
+                    let [path, name, size, crc] = fields[..] else {
+                        return Err(bad_meta());
+                    };
+
+                    files.push(BackupFile {
+                        path: path.to_string(),
+                        name: name.to_string(),
+                        size: size.parse().map_err(|_| bad_meta())?,
+                        crc: u32::from_str_radix(crc, 16).map_err(|_| bad_meta())?,
+                    });
+                }
+                _ => return Err(bad_meta()),
+            }
+        }
+
+        if files.len() != info.num_files {
+            return Err(bad_meta());
+        }
+
+        Ok((info, files))
+    }
+
+    /// Returns the size and the CRC32 of the file at `path`
+    fn checksum(&self, path: &Path) -> Result<(u64, u32), DbError> {
+        let mut file = File::open(path)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut chunk = vec![0; COPY_CHUNK_SIZE];
+        let mut size = 0;
+
+        loop {
+            let read = file.read(&mut chunk)?;
+
+            if read == 0 {
+                return Ok((size, hasher.finalize()));
+            }
+
+            if let Some(limiter) = self
+                .options
+                .rate_limiter
+                .as_ref()
+                .filter(|limiter| limiter.limits_reads())
+            {
+                limiter.request(read as u64);
+            }
+
+            hasher.update(&chunk[..read]);
+            size += read as u64;
+        }
+    }
+
+    /// Copies the file at `from` to `to`, waiting for the rate limiter before every chunk, and
+    /// returns the size and the CRC32 of what was copied
+    fn copy(&self, from: &Path, to: &Path) -> Result<(u64, u32), DbError> {
+        let mut reader = File::open(from)?;
+        let mut writer = File::create(to)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut chunk = vec![0; COPY_CHUNK_SIZE];
+        let mut size = 0;
+
+        loop {
+            let read = reader.read(&mut chunk)?;
+
+            if read == 0 {
+                break;
+            }
+
+            if let Some(limiter) = &self.options.rate_limiter {
+                limiter.request(read as u64);
+            }
+
+            hasher.update(&chunk[..read]);
+            writer.write_all(&chunk[..read])?;
+            size += read as u64;
+        }
+
+        writer.sync_all()?;
+
+        Ok((size, hasher.finalize()))
+    }
+}
+
+/// Returns the path in the backup directory of the shared file named `name` in the database,
+/// whose size and CRC32 are `size` and `crc`
+fn shared_file_path(name: &str, size: u64, crc: u32) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => format!("shared/{}_{:08x}_{}.{}", stem, crc, size, extension),
+        None => format!("shared/{}_{:08x}_{}", name, crc, size),
+    }
+}
+
+/// Deletes the file or the directory at `path`
+fn remove_path(path: &Path) -> Result<(), DbError> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)?;
+    } else {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backup::{BackupEngine, BackupOptions, RestoreOptions};
+    use crate::options::{ColumnFamilyOptions, Options};
+    use crate::rate_limiter::RateLimiter;
+    use crate::{Db, DbError};
+    use std::sync::Arc;
+
+    #[test]
+    fn backups_share_unchanged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_dir = dir.path().join("backups");
+        let options = Options::default()
+            .with_default_cf_options(ColumnFamilyOptions::default().with_blob_files(true));
+        let db = Db::open(dir.path().join("db"), options).unwrap();
+        let limiter = Arc::new(RateLimiter::new(1 << 30));
+        let engine = BackupEngine::open(
+            &backup_dir,
+            BackupOptions::default().with_rate_limiter(limiter.clone()),
+        )
+        .unwrap();
+        let shared_files = || {
+            std::fs::read_dir(backup_dir.join("shared"))
+                .unwrap()
+                .count()
+        };
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        db.put(b"unflushed", b"value").unwrap();
+
+        let first = engine.create_backup(&db).unwrap();
+        assert_eq!(first.id, 1);
+        // A table and a blob file
+        assert_eq!(shared_files(), 2);
+        let written = limiter.total_bytes_through();
+        assert!(written > 0);
+
+        for n in 0..10_u32 {
+            db.put(&n.to_be_bytes(), &[2; 100]).unwrap();
+        }
+        db.flush().unwrap();
+
+        let second = engine.create_backup(&db).unwrap();
+        assert_eq!(second.id, 2);
+        assert_eq!(shared_files(), 4);
+        // Only the new files were copied
+        assert!(limiter.total_bytes_through() - written < first.size);
+
+        assert_eq!(engine.backups().unwrap(), vec![first, second.clone()]);
+        engine.verify_backup(1).unwrap();
+        engine.verify_backup(2).unwrap();
+
+        let (_, files) = engine.read_meta(1).unwrap();
+        let shared = files
+            .iter()
+            .find(|file| file.path.starts_with("shared/"))
+            .unwrap();
+        std::fs::write(backup_dir.join(&shared.path), b"corrupted").unwrap();
+        assert!(matches!(
+            engine.verify_backup(1),
+            Err(DbError::Corruption(_))
+        ));
+
+        // The files shared with the deleted backup stay
+        engine.purge_old_backups(1).unwrap();
+        assert_eq!(engine.backups().unwrap(), vec![second]);
+        assert!(matches!(
+            engine.verify_backup(1),
+            Err(DbError::InvalidArgument(_))
+        ));
+        assert_eq!(shared_files(), 4);
+        assert!(!backup_dir.join("private/1").exists());
+
+        engine.delete_backup(2).unwrap();
+        assert_eq!(shared_files(), 0);
+    }
+
+    #[test]
+    fn restores_check_files_and_may_keep_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_dir = dir.path().join("db");
+        let options = Options::default()
+            .with_default_cf_options(ColumnFamilyOptions::default().with_blob_files(true));
+        let engine =
+            BackupEngine::open(dir.path().join("backups"), BackupOptions::default()).unwrap();
+
+        {
+            let db = Db::open(&db_dir, options.clone()).unwrap();
+
+            for n in 0..100_u32 {
+                db.put(&n.to_be_bytes(), &[1; 100]).unwrap();
+            }
+            db.flush().unwrap();
+            db.put(b"unflushed", b"value").unwrap();
+
+            engine.create_backup(&db).unwrap();
+            db.put(b"after", b"value").unwrap();
+        }
+
+        let restored = dir.path().join("restored");
+        engine
+            .restore(1, &restored, &restored, &RestoreOptions::default())
+            .unwrap();
+        {
+            let db = Db::open(&restored, options.clone()).unwrap();
+            assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+            assert_eq!(db.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(db.get(b"after").unwrap(), None);
+        }
+
+        // The writes logged since the backup are replayed over it
+        let keep_logs = RestoreOptions::default().with_keep_log_files(true);
+        engine.restore(1, &db_dir, &db_dir, &keep_logs).unwrap();
+        {
+            let db = Db::open(&db_dir, options.clone()).unwrap();
+            assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+            assert_eq!(db.get(b"after").unwrap(), Some(b"value".to_vec()));
+        }
+
+        engine
+            .restore(1, &db_dir, &db_dir, &RestoreOptions::default())
+            .unwrap();
+        {
+            let db = Db::open(&db_dir, options.clone()).unwrap();
+            assert_eq!(db.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(db.get(b"after").unwrap(), None);
+        }
+
+        let (_, files) = engine.read_meta(1).unwrap();
+        let shared = files
+            .iter()
+            .find(|file| file.path.starts_with("shared/"))
+            .unwrap();
+        std::fs::write(dir.path().join("backups").join(&shared.path), b"corrupted").unwrap();
+        assert!(matches!(
+            engine.restore(1, &restored, &restored, &RestoreOptions::default()),
+            Err(DbError::Corruption(_))
+        ));
+    }
+}
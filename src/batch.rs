@@ -0,0 +1,797 @@
+use crate::blob::BlobIndex;
+use crate::column_family::{ColumnFamily, DEFAULT_COLUMN_FAMILY_ID};
+use crate::db::{Db, DbError};
+use crate::key::{SequenceNumber, ValueType};
+use crate::memtable::{LookupResult, MemTable};
+use crate::timestamp::{self, Timestamp};
+use crate::ttl;
+use integer_encoding::*;
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::ops::Range;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Bytes taken by the sequence number and the count at the start of every batch
+const BATCH_HEADER_SIZE: usize = size_of::<u64>() + size_of::<u32>();
+
+/// Set in the type of the records of column families other than the default one, which are
+/// followed by the column family id
+const COLUMN_FAMILY_FLAG: u8 = 0x80;
+
+/// Tag of the first [Marker], which follow the records
+const MARKER_TAG: u8 = 0x20;
+
+/// Marks the batch of a step of the two-phase commit of a named
+/// [Transaction](crate::Transaction), see [Transaction::prepare](crate::Transaction::prepare)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Marker {
+    /// The mutations of the batch are the ones of the transaction, only applied once it commits
+    Prepare,
+    /// Commits the prepared transaction, whose mutations the batch holds again
+    Commit,
+    /// Discards the prepared transaction
+    Rollback,
+}
+
+impl Marker {
+    fn from_tag(tag: u8) -> Option<Marker> {
+        match tag.checked_sub(MARKER_TAG)? {
+            0 => Some(Marker::Prepare),
+            1 => Some(Marker::Commit),
+            2 => Some(Marker::Rollback),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BatchError {
+    #[error("Write batch is corrupted: {0}")]
+    Corrupted(&'static str),
+    #[error("No savepoint to roll back to")]
+    NoSavepoint,
+}
+
+/// A group of mutations applied atomically, which is also the payload of every WAL record
+///
+/// The memory layout is:
+/// [ seq, count, records... ]
+/// where seq is a little-endian u64, count a little-endian u32 and every record is
+/// [ value_type, key_size, key, value_size, value ]
+/// with key_size and value_size being varints. Deletions have no value_size and value, while
+/// range deletions store the end of the range as value. Merges store their operand as value.
+///
+/// The records of column families other than the default one have the high bit of value_type
+/// set, and a varint column family id right after it.
+///
+/// The n-th record of the batch gets the sequence number `seq + n`.
+///
+/// The batches of the two-phase commit of a transaction end with a [Marker] after the records,
+/// which isn't counted: [ tag, name_size, name ].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteBatch {
+    data: Vec<u8>,
+    /// The size and the count of the batch at each savepoint, see [WriteBatch::set_savepoint]
+    savepoints: Vec<(usize, u32)>,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}
+
+/// A single mutation of a [WriteBatch]
+#[derive(Debug, PartialEq, Eq)]
+pub struct BatchOp<'a> {
+    pub column_family: u32,
+    pub value_type: ValueType,
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            data: vec![0; BATCH_HEADER_SIZE],
+            savepoints: Vec::new(),
+        }
+    }
+
+    /// Rebuilds a batch from its serialized form, validating every record
+    pub fn from_data(data: Vec<u8>) -> Result<WriteBatch, BatchError> {
+        if data.len() < BATCH_HEADER_SIZE {
+            Err(BatchError::Corrupted("batch header too small"))?
+        }
+
+        let batch = WriteBatch {
+            data,
+            savepoints: Vec::new(),
+        };
+        let mut records = 0;
+
+        for op in batch.iter() {
+            op?;
+            records += 1;
+        }
+
+        if records != batch.count() {
+            Err(BatchError::Corrupted("wrong record count"))?
+        }
+
+        if batch.marker_offset() < batch.data.len() && batch.marker().is_none() {
+            Err(BatchError::Corrupted("bad marker"))?
+        }
+
+        Ok(batch)
+    }
+
+    /// Returns the serialized form of the batch
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the number of mutations in the batch
+    pub fn count(&self) -> u32 {
+        u32::from_le_bytes(self.data[8..12].try_into().unwrap())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    fn set_count(&mut self, count: u32) {
+        self.data[8..12].copy_from_slice(&count.to_le_bytes());
+    }
+
+    /// Returns the sequence number of the first mutation
+    pub fn sequence(&self) -> SequenceNumber {
+        u64::from_le_bytes(self.data[..8].try_into().unwrap())
+    }
+
+    pub fn set_sequence(&mut self, seq: SequenceNumber) {
+        self.data[..8].copy_from_slice(&seq.to_le_bytes());
+    }
+
+    /// Returns the sequence number of the last mutation
+    pub fn last_sequence(&self) -> SequenceNumber {
+        (self.sequence() + self.count() as u64).saturating_sub(1)
+    }
+
+    fn push_slice(&mut self, slice: &[u8]) {
+        let mut size = [0_u8; 10];
+        let varint_size = slice.len().encode_var(&mut size);
+
+        self.data.extend_from_slice(&size[..varint_size]);
+        self.data.extend_from_slice(slice);
+    }
+
+    fn push_record(
+        &mut self,
+        column_family: u32,
+        value_type: ValueType,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) {
+        if column_family == DEFAULT_COLUMN_FAMILY_ID {
+            self.data.push(value_type as u8);
+        } else {
+            self.data.push(value_type as u8 | COLUMN_FAMILY_FLAG);
+            self.data.extend_from_slice(&column_family.encode_var_vec());
+        }
+
+        self.push_slice(key);
+
+        if let Some(value) = value {
+            self.push_slice(value);
+        }
+
+        self.set_count(self.count() + 1);
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.push_record(DEFAULT_COLUMN_FAMILY_ID, ValueType::Value, key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.push_record(DEFAULT_COLUMN_FAMILY_ID, ValueType::Deletion, key, None);
+    }
+
+    /// Adds a merge operand for `key`, combined with its value by the merge operator on reads
+    pub fn merge(&mut self, key: &[u8], operand: &[u8]) {
+        self.push_record(
+            DEFAULT_COLUMN_FAMILY_ID,
+            ValueType::Merge,
+            key,
+            Some(operand),
+        );
+    }
+
+    /// Deletes every key in [start, end)
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) {
+        self.push_record(
+            DEFAULT_COLUMN_FAMILY_ID,
+            ValueType::RangeDeletion,
+            start,
+            Some(end),
+        );
+    }
+
+    /// Same as [WriteBatch::put], in the column family `cf`
+    pub fn put_cf(&mut self, cf: &ColumnFamily, key: &[u8], value: &[u8]) {
+        self.push_record(cf.id(), ValueType::Value, key, Some(value));
+    }
+
+    /// Same as [WriteBatch::put], the value reading as deleted once `ttl` has elapsed
+    pub fn put_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) {
+        self.push_record(
+            DEFAULT_COLUMN_FAMILY_ID,
+            ValueType::ValueWithExpiry,
+            key,
+            Some(&ttl::encode_value(value, ttl)),
+        );
+    }
+
+    /// Same as [WriteBatch::put_with_ttl], in the column family `cf`
+    pub fn put_cf_with_ttl(&mut self, cf: &ColumnFamily, key: &[u8], value: &[u8], ttl: Duration) {
+        self.push_record(
+            cf.id(),
+            ValueType::ValueWithExpiry,
+            key,
+            Some(&ttl::encode_value(value, ttl)),
+        );
+    }
+
+    /// Same as [WriteBatch::delete], in the column family `cf`
+    pub fn delete_cf(&mut self, cf: &ColumnFamily, key: &[u8]) {
+        self.push_record(cf.id(), ValueType::Deletion, key, None);
+    }
+
+    /// Same as [WriteBatch::merge], in the column family `cf`
+    pub fn merge_cf(&mut self, cf: &ColumnFamily, key: &[u8], operand: &[u8]) {
+        self.push_record(cf.id(), ValueType::Merge, key, Some(operand));
+    }
+
+    /// Same as [WriteBatch::delete_range], in the column family `cf`
+    pub fn delete_range_cf(&mut self, cf: &ColumnFamily, start: &[u8], end: &[u8]) {
+        self.push_record(cf.id(), ValueType::RangeDeletion, start, Some(end));
+    }
+
+    /// Writes `key` with the value `index` points to, already in a blob file, see
+    /// [Db::put_reader](crate::Db::put_reader)
+    pub(crate) fn put_blob_index_cf(&mut self, cf: &ColumnFamily, key: &[u8], index: &BlobIndex) {
+        self.push_record(cf.id(), ValueType::BlobIndex, key, Some(&index.encode()));
+    }
+
+    /// Writes the version of `key` at the timestamp `ts`, in the column family `cf` which must
+    /// have [ColumnFamilyOptions::user_timestamps](crate::ColumnFamilyOptions::user_timestamps)
+    pub fn put_cf_with_ts(&mut self, cf: &ColumnFamily, key: &[u8], ts: Timestamp, value: &[u8]) {
+        self.put_cf(
+            cf,
+            &timestamp::encode_key(key, ts),
+            &timestamp::encode_value(Some(value)),
+        );
+    }
+
+    /// Deletes `key` as of the timestamp `ts`, hiding its older versions from the reads at or
+    /// after `ts`, in the column family `cf` which must have user timestamps
+    pub fn delete_cf_with_ts(&mut self, cf: &ColumnFamily, key: &[u8], ts: Timestamp) {
+        self.put_cf(
+            cf,
+            &timestamp::encode_key(key, ts),
+            &timestamp::encode_value(None),
+        );
+    }
+
+    /// Ends the batch with `marker` for the transaction `name`, see [Marker]
+    pub(crate) fn set_marker(&mut self, marker: Marker, name: &str) {
+        self.data.push(MARKER_TAG + marker as u8);
+        self.push_slice(name.as_bytes());
+    }
+
+    /// Returns the marker ending the batch, with the name of its transaction, if any
+    pub fn marker(&self) -> Option<(Marker, String)> {
+        let offset = self.marker_offset();
+        let (&tag, mut name) = self.data[offset..].split_first()?;
+        let marker = Marker::from_tag(tag)?;
+        let (size, varint_size) = usize::decode_var(name)?;
+        name = name.get(varint_size..varint_size + size)?;
+
+        Some((marker, String::from_utf8_lossy(name).into_owned()))
+    }
+
+    /// Returns the batch without its marker
+    pub(crate) fn without_marker(&self) -> WriteBatch {
+        WriteBatch {
+            data: self.data[..self.marker_offset()].to_vec(),
+            savepoints: Vec::new(),
+        }
+    }
+
+    /// Returns the offset of the marker, the end of the batch if it has none
+    fn marker_offset(&self) -> usize {
+        let mut iter = self.iter();
+        while let Some(Ok(_)) = iter.next() {}
+
+        self.data.len() - iter.data.len()
+    }
+
+    /// Removes every mutation from the batch, along with its savepoints
+    pub fn clear(&mut self) {
+        self.data.truncate(BATCH_HEADER_SIZE);
+        self.data.fill(0);
+        self.savepoints.clear();
+    }
+
+    /// Records the current contents of the batch, which [WriteBatch::rollback_to_savepoint]
+    /// goes back to
+    ///
+    /// Savepoints nest: each rollback goes back to the last savepoint not rolled back or popped.
+    pub fn set_savepoint(&mut self) {
+        self.savepoints.push((self.data.len(), self.count()));
+    }
+
+    /// Removes the mutations added since the last savepoint, which is removed too
+    pub fn rollback_to_savepoint(&mut self) -> Result<(), BatchError> {
+        let (len, count) = self.savepoints.pop().ok_or(BatchError::NoSavepoint)?;
+
+        self.data.truncate(len);
+        self.set_count(count);
+
+        Ok(())
+    }
+
+    /// Removes the last savepoint, keeping the mutations added since
+    pub fn pop_savepoint(&mut self) -> Result<(), BatchError> {
+        self.savepoints.pop().ok_or(BatchError::NoSavepoint)?;
+
+        Ok(())
+    }
+
+    /// Iterates the mutations of the batch, in insertion order
+    pub fn iter(&self) -> BatchIterator<'_> {
+        BatchIterator {
+            data: &self.data[BATCH_HEADER_SIZE..],
+        }
+    }
+
+    /// Applies every mutation of the batch to `mem`, starting at the batch sequence number
+    ///
+    /// The mutations of column families other than the default one are skipped.
+    pub fn insert_into(&self, mem: &MemTable) -> Result<(), BatchError> {
+        self.insert_into_column_families(|column_family| {
+            (column_family == DEFAULT_COLUMN_FAMILY_ID).then_some(mem)
+        })
+    }
+
+    /// Applies every mutation of the batch to the memtable of its column family, as returned by
+    /// `mem_of`, starting at the batch sequence number
+    ///
+    /// The mutations of the column families without a memtable (i.e. dropped ones) are skipped.
+    pub fn insert_into_column_families<'a, F>(&self, mut mem_of: F) -> Result<(), BatchError>
+    where
+        F: FnMut(u32) -> Option<&'a MemTable>,
+    {
+        for (n, op) in self.iter().enumerate() {
+            let op = op?;
+
+            if let Some(mem) = mem_of(op.column_family) {
+                mem.add(self.sequence() + n as u64, op.value_type, op.key, op.value);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct BatchIterator<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> BatchIterator<'a> {
+    fn read_slice(&mut self) -> Result<&'a [u8], BatchError> {
+        let (size, varint_size) =
+            usize::decode_var(self.data).ok_or(BatchError::Corrupted("bad varint"))?;
+
+        let slice = self
+            .data
+            .get(varint_size..varint_size + size)
+            .ok_or(BatchError::Corrupted("record out of bounds"))?;
+
+        self.data = &self.data[varint_size + size..];
+
+        Ok(slice)
+    }
+
+    fn read_op(&mut self) -> Result<BatchOp<'a>, BatchError> {
+        let tag = self.data[0];
+        let value_type = ValueType::from_u8(tag & !COLUMN_FAMILY_FLAG)
+            .ok_or(BatchError::Corrupted("unknown record type"))?;
+        self.data = &self.data[1..];
+
+        let column_family = if tag & COLUMN_FAMILY_FLAG != 0 {
+            let (column_family, varint_size) =
+                u32::decode_var(self.data).ok_or(BatchError::Corrupted("bad varint"))?;
+            self.data = &self.data[varint_size..];
+
+            column_family
+        } else {
+            DEFAULT_COLUMN_FAMILY_ID
+        };
+
+        let key = self.read_slice()?;
+        let value = match value_type {
+            ValueType::Value
+            | ValueType::RangeDeletion
+            | ValueType::Merge
+            | ValueType::ValueWithExpiry
+            | ValueType::BlobIndex => self.read_slice()?,
+            ValueType::Deletion => &[],
+        };
+
+        Ok(BatchOp {
+            column_family,
+            value_type,
+            key,
+            value,
+        })
+    }
+}
+
+impl<'a> Iterator for BatchIterator<'a> {
+    type Item = Result<BatchOp<'a>, BatchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The marker follows the last record
+        if self
+            .data
+            .first()
+            .is_none_or(|&tag| Marker::from_tag(tag).is_some())
+        {
+            return None;
+        }
+
+        let op = self.read_op();
+
+        // Stop at the first corrupted record, there's no way to find the next one
+        if op.is_err() {
+            self.data = &[];
+        }
+
+        Some(op)
+    }
+}
+
+/// A [WriteBatch] which also keeps an index of its mutations by key, so that they can be read
+/// back before the batch is written (read-your-own-writes)
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatchWithIndex {
+    batch: WriteBatch,
+    /// For every column family and key, the type of its last mutation and the position of its
+    /// value in the batch
+    index: BTreeMap<(u32, Vec<u8>), (ValueType, Range<usize>)>,
+}
+
+impl WriteBatchWithIndex {
+    pub fn new() -> WriteBatchWithIndex {
+        WriteBatchWithIndex::default()
+    }
+
+    /// Indexes the puts and deletes of `batch`, failing if it holds other mutations
+    pub fn from_batch(batch: WriteBatch) -> Result<WriteBatchWithIndex, BatchError> {
+        for op in batch.iter() {
+            if !matches!(op?.value_type, ValueType::Value | ValueType::Deletion) {
+                return Err(BatchError::Corrupted(
+                    "only puts and deletes can be indexed",
+                ));
+            }
+        }
+
+        let mut indexed = WriteBatchWithIndex {
+            batch,
+            index: BTreeMap::new(),
+        };
+        indexed.reindex()?;
+
+        Ok(indexed)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.put(key, value);
+        self.index_value(DEFAULT_COLUMN_FAMILY_ID, key, value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.batch.delete(key);
+        self.index_deletion(DEFAULT_COLUMN_FAMILY_ID, key);
+    }
+
+    pub fn put_cf(&mut self, cf: &ColumnFamily, key: &[u8], value: &[u8]) {
+        self.batch.put_cf(cf, key, value);
+        self.index_value(cf.id(), key, value);
+    }
+
+    pub fn delete_cf(&mut self, cf: &ColumnFamily, key: &[u8]) {
+        self.batch.delete_cf(cf, key);
+        self.index_deletion(cf.id(), key);
+    }
+
+    /// Indexes `value`, the end of the batch, as the value of `key`
+    fn index_value(&mut self, column_family: u32, key: &[u8], value: &[u8]) {
+        let end = self.batch.data.len();
+        self.index.insert(
+            (column_family, key.to_vec()),
+            (ValueType::Value, end - value.len()..end),
+        );
+    }
+
+    fn index_deletion(&mut self, column_family: u32, key: &[u8]) {
+        self.index
+            .insert((column_family, key.to_vec()), (ValueType::Deletion, 0..0));
+    }
+
+    pub fn clear(&mut self) {
+        self.batch.clear();
+        self.index.clear();
+    }
+
+    /// Same as [WriteBatch::set_savepoint]
+    pub fn set_savepoint(&mut self) {
+        self.batch.set_savepoint();
+    }
+
+    /// Same as [WriteBatch::rollback_to_savepoint], the keys read back as they were at the
+    /// savepoint
+    pub fn rollback_to_savepoint(&mut self) -> Result<(), BatchError> {
+        self.batch.rollback_to_savepoint()?;
+        self.reindex()
+    }
+
+    /// Rebuilds the index from the mutations of the batch
+    fn reindex(&mut self) -> Result<(), BatchError> {
+        let start = self.batch.data.as_ptr() as usize;
+        let mut index = BTreeMap::new();
+
+        for op in self.batch.iter() {
+            let op = op?;
+            // Only the values are located in the batch, see [WriteBatchWithIndex::index_deletion]
+            let value = match op.value_type {
+                ValueType::Value => {
+                    let offset = op.value.as_ptr() as usize - start;
+
+                    offset..offset + op.value.len()
+                }
+                _ => 0..0,
+            };
+
+            index.insert((op.column_family, op.key.to_vec()), (op.value_type, value));
+        }
+
+        self.index = index;
+
+        Ok(())
+    }
+
+    /// Same as [WriteBatch::pop_savepoint]
+    pub fn pop_savepoint(&mut self) -> Result<(), BatchError> {
+        self.batch.pop_savepoint()
+    }
+
+    /// Returns the number of mutations in the batch
+    pub fn count(&self) -> u32 {
+        self.batch.count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+
+    pub fn batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+
+    /// Returns the underlying batch, e.g. to write it with [Db::write]
+    pub fn into_batch(self) -> WriteBatch {
+        self.batch
+    }
+
+    fn op<'a>(
+        &'a self,
+        (column_family, key): &'a (u32, Vec<u8>),
+        (value_type, value): &(ValueType, Range<usize>),
+    ) -> BatchOp<'a> {
+        BatchOp {
+            column_family: *column_family,
+            value_type: *value_type,
+            key,
+            value: &self.batch.data[value.clone()],
+        }
+    }
+
+    /// Looks up the last mutation of `key` in the batch only
+    ///
+    /// Returns None if the batch doesn't touch the key
+    pub fn get_from_batch(&self, key: &[u8]) -> Option<LookupResult> {
+        self.lookup(DEFAULT_COLUMN_FAMILY_ID, key)
+    }
+
+    /// Same as [WriteBatchWithIndex::get_from_batch], in the column family `cf`
+    pub fn get_from_batch_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Option<LookupResult> {
+        self.lookup(cf.id(), key)
+    }
+
+    fn lookup(&self, column_family: u32, key: &[u8]) -> Option<LookupResult> {
+        let (index_key, location) = self.index.get_key_value(&(column_family, key.to_vec()))?;
+        let op = self.op(index_key, location);
+
+        match op.value_type {
+            ValueType::Value => Some(LookupResult::Value(op.value.to_vec())),
+            ValueType::Deletion => Some(LookupResult::Deleted),
+            ValueType::RangeDeletion
+            | ValueType::Merge
+            | ValueType::ValueWithExpiry
+            | ValueType::BlobIndex => unreachable!("only puts and deletes are indexed"),
+        }
+    }
+
+    /// Returns the value `key` would have if the batch was written to `db` now
+    pub fn get_from_batch_and_db(&self, db: &Db, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        match self.get_from_batch(key) {
+            Some(LookupResult::Value(value)) => Ok(Some(value)),
+            Some(LookupResult::Deleted) => Ok(None),
+            None => db.get(key),
+        }
+    }
+
+    /// Iterates the last mutation of every key of the default column family touched by the
+    /// batch, in key order, starting from the first key >= `from`
+    pub fn iter_from<'a>(&'a self, from: &[u8]) -> impl Iterator<Item = BatchOp<'a>> + 'a {
+        self.index
+            .range((DEFAULT_COLUMN_FAMILY_ID, from.to_vec())..)
+            .take_while(|((column_family, _), _)| *column_family == DEFAULT_COLUMN_FAMILY_ID)
+            .map(|(key, location)| self.op(key, location))
+    }
+
+    /// Iterates the last mutation of every key of the default column family touched by the
+    /// batch, in key order
+    pub fn iter(&self) -> impl Iterator<Item = BatchOp<'_>> {
+        self.iter_from(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{WriteBatch, WriteBatchWithIndex};
+    use crate::column_family::ColumnFamily;
+    use crate::db::Db;
+    use crate::key::ValueType;
+    use crate::memtable::{LookupResult, MemTable};
+    use crate::options::Options;
+
+    #[test]
+    fn savepoints_roll_back_the_mutations_added_since() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1");
+        batch.set_savepoint();
+        batch.delete(b"b");
+        batch.set_savepoint();
+        batch.put(b"c", b"2");
+
+        batch.rollback_to_savepoint().unwrap();
+        assert_eq!(batch.count(), 2);
+        batch.pop_savepoint().unwrap();
+        assert!(batch.rollback_to_savepoint().is_err());
+
+        let mut expected = WriteBatch::new();
+        expected.put(b"a", b"1");
+        expected.delete(b"b");
+        assert_eq!(batch.data(), expected.data());
+
+        let mut batch = WriteBatchWithIndex::new();
+        batch.put(b"a", b"1");
+        batch.set_savepoint();
+        batch.put(b"a", b"2");
+        batch.delete(b"b");
+        batch.rollback_to_savepoint().unwrap();
+        assert_eq!(
+            batch.get_from_batch(b"a"),
+            Some(LookupResult::Value(b"1".to_vec()))
+        );
+        assert_eq!(batch.get_from_batch(b"b"), None);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut batch = WriteBatch::new();
+
+        batch.set_sequence(42);
+        batch.put(b"key", b"value");
+        batch.delete(b"other");
+
+        let decoded = WriteBatch::from_data(batch.data().to_vec()).unwrap();
+        let ops: Vec<_> = decoded.iter().map(Result::unwrap).collect();
+
+        assert_eq!(decoded.sequence(), 42);
+        assert_eq!(decoded.last_sequence(), 43);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].key, b"key");
+        assert_eq!(ops[0].value, b"value");
+        assert_eq!(ops[1].value_type, ValueType::Deletion);
+        assert_eq!(ops[1].key, b"other");
+    }
+
+    #[test]
+    fn column_family_records_roundtrip() {
+        let cf = ColumnFamily::new(300, "other");
+        let mut batch = WriteBatch::new();
+
+        batch.put(b"key", b"default");
+        batch.put_cf(&cf, b"key", b"other");
+        batch.delete_range_cf(&cf, b"a", b"b");
+
+        let decoded = WriteBatch::from_data(batch.data().to_vec()).unwrap();
+        let ops: Vec<_> = decoded.iter().map(Result::unwrap).collect();
+
+        assert_eq!(ops[0].column_family, 0);
+        assert_eq!(ops[1].column_family, 300);
+        assert_eq!(ops[1].value, b"other");
+        assert_eq!(ops[2].column_family, 300);
+        assert_eq!(ops[2].value_type, ValueType::RangeDeletion);
+
+        // Only the writes of the default column family go to a lone memtable
+        let mem = MemTable::new();
+        decoded.insert_into(&mem).unwrap();
+
+        assert_eq!(mem.len(), 1);
+    }
+
+    #[test]
+    fn truncated_batch_is_rejected() {
+        let mut batch = WriteBatch::new();
+        batch.put(b"key", b"value");
+
+        let data = batch.data();
+
+        assert!(WriteBatch::from_data(data[..data.len() - 1].to_vec()).is_err());
+    }
+
+    #[test]
+    fn batch_with_index_reads_its_own_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        db.put(b"a", b"db").unwrap();
+        db.put(b"b", b"db").unwrap();
+
+        let mut batch = WriteBatchWithIndex::new();
+        batch.put(b"c", b"first");
+        batch.put(b"c", b"batch");
+        batch.delete(b"b");
+
+        assert_eq!(
+            batch.get_from_batch(b"c"),
+            Some(LookupResult::Value(b"batch".to_vec()))
+        );
+        assert_eq!(batch.get_from_batch(b"a"), None);
+        assert_eq!(
+            batch.get_from_batch_and_db(&db, b"a").unwrap(),
+            Some(b"db".to_vec())
+        );
+        assert_eq!(batch.get_from_batch_and_db(&db, b"b").unwrap(), None);
+
+        let keys: Vec<_> = batch.iter().map(|op| (op.key, op.value_type)).collect();
+        assert_eq!(
+            keys,
+            vec![
+                (&b"b"[..], ValueType::Deletion),
+                (&b"c"[..], ValueType::Value)
+            ]
+        );
+
+        db.write(batch.into_batch()).unwrap();
+
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"batch".to_vec()));
+    }
+}
@@ -0,0 +1,591 @@
+//! Inspects and manipulates a fyodor database from the command line, e.g.
+//!
+//! ```text
+//! fyodor-cli --db /var/lib/app/db scan --from user: --limit 10
+//! fyodor-cli --db /var/lib/app/db --hex get 0x00ff
+//! fyodor-cli --db /var/lib/app/db stats
+//! ```
+//!
+//! The database is opened with the options of its OPTIONS file, if any: its merge operators
+//! and custom prefix extractors can't be restored, so the keys with merge operands can't be read.
+//! The reading commands open it read only, so they also work while another process writes it.
+
+use clap::{Parser, Subcommand, ValueEnum};
+use fyodor::table::{parse_table_file_name, Table, TableDump};
+use fyodor::{json, key};
+use fyodor::{load_latest_options, ColumnFamily, CompactRangeOptions, Db, DbIterator, Options};
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "fyodor-cli",
+    about = "Inspects and manipulates a fyodor database"
+)]
+struct Cli {
+    /// Directory of the database
+    #[arg(long)]
+    db: PathBuf,
+    /// Column family to operate on
+    #[arg(long, default_value = "default")]
+    cf: String,
+    /// Reads and prints the keys and values in hexadecimal, instead of as escaped text
+    #[arg(long)]
+    hex: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    /// A JSON object, the keys and values as strings of hexadecimal digits
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the value of a key
+    Get { key: String },
+    /// Writes the value of a key
+    Put { key: String, value: String },
+    /// Prints the keys and values in [from, to)
+    Scan {
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+        /// Prints at most this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Prints the keys and values of every column family, or everything a table file holds
+    Dump {
+        /// Table file to dump instead, which needn't belong to the database
+        #[arg(long)]
+        table: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Compacts the keys in [from, to] down to the bottom level
+    Compact {
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: Option<String>,
+        /// Also rewrites the tables already in the bottom level, dropping their deletions
+        #[arg(long)]
+        bottommost: bool,
+    },
+    /// Creates a checkpoint of the database at a directory which must not exist
+    Checkpoint { dir: PathBuf },
+    /// Prints the statistics of the levels and the compactions of every column family
+    Stats,
+    /// Rebuilds the manifest from the tables, moving the files which can't be used to `lost`
+    Repair,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(&cli, &mut io::stdout().lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: &Cli, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    let parse = |arg: &str| parse_bytes(arg, cli.hex);
+    let format = |bytes: &[u8]| format_bytes(bytes, cli.hex);
+
+    match &cli.command {
+        Command::Get { key } => {
+            let db = open(&cli.db, true)?;
+
+            match db.get_cf(&cf(&db, &cli.cf)?, &parse(key)?)? {
+                Some(value) => writeln!(out, "{}", format(&value))?,
+                None => Err("key not found")?,
+            }
+        }
+        Command::Put { key, value } => {
+            let db = open(&cli.db, false)?;
+            db.put_cf(&cf(&db, &cli.cf)?, &parse(key)?, &parse(value)?)?;
+        }
+        Command::Scan { from, to, limit } => {
+            let db = open(&cli.db, true)?;
+            let mut iter = db.iter_cf(&cf(&db, &cli.cf)?, &Default::default())?;
+            let to = to.as_deref().map(parse).transpose()?;
+
+            match from {
+                Some(from) => iter.seek(&parse(from)?)?,
+                None => iter.seek_to_first()?,
+            }
+
+            let mut remaining = limit.unwrap_or(usize::MAX);
+
+            while iter.valid() && remaining > 0 {
+                if to.as_deref().is_some_and(|to| iter.key() >= to) {
+                    break;
+                }
+
+                print_entry(out, &iter, cli.hex)?;
+                iter.next()?;
+                remaining -= 1;
+            }
+        }
+        Command::Dump {
+            table: Some(path),
+            format,
+        } => {
+            let number = path
+                .file_name()
+                .and_then(|name| parse_table_file_name(name.to_str()?))
+                .unwrap_or_default();
+            let dump = Table::open(path, number)?.dump()?;
+
+            match format {
+                Format::Text => print_table(out, &dump, cli.hex)?,
+                Format::Json => writeln!(out, "{}", dump.to_json())?,
+            }
+        }
+        Command::Dump {
+            table: None,
+            format,
+        } => {
+            let db = open(&cli.db, true)?;
+            let mut column_families = Vec::new();
+
+            for cf in db.cf_handles() {
+                let mut iter = db.iter_cf(&cf, &Default::default())?;
+                let mut entries = Vec::new();
+                let mut keys = 0;
+
+                if *format == Format::Text {
+                    writeln!(out, "[{}]", cf.name())?;
+                }
+
+                iter.seek_to_first()?;
+
+                while iter.valid() {
+                    match format {
+                        Format::Text => print_entry(out, &iter, cli.hex)?,
+                        Format::Json => entries.push(format!(
+                            r#"{{"key":{},"value":{}}}"#,
+                            json::hex(iter.key()),
+                            json::hex(iter.value())
+                        )),
+                    }
+
+                    iter.next()?;
+                    keys += 1;
+                }
+
+                match format {
+                    Format::Text => writeln!(out, "keys: {keys}")?,
+                    Format::Json => column_families.push(format!(
+                        r#"{{"name":{},"entries":[{}]}}"#,
+                        json::string(cf.name()),
+                        entries.join(",")
+                    )),
+                }
+            }
+
+            if *format == Format::Json {
+                writeln!(
+                    out,
+                    r#"{{"column_families":[{}]}}"#,
+                    column_families.join(",")
+                )?;
+            }
+        }
+        Command::Compact {
+            from,
+            to,
+            bottommost,
+        } => {
+            let db = open(&cli.db, false)?;
+            let from = from.as_deref().map(parse).transpose()?;
+            let to = to.as_deref().map(parse).transpose()?;
+            let options = CompactRangeOptions {
+                bottommost_level_compaction: *bottommost,
+                ..CompactRangeOptions::default()
+            };
+
+            db.compact_range_cf(&cf(&db, &cli.cf)?, from.as_deref(), to.as_deref(), &options)?;
+        }
+        Command::Checkpoint { dir } => {
+            let db = open(&cli.db, false)?;
+            db.checkpoint(dir)?;
+        }
+        Command::Stats => {
+            let db = open(&cli.db, true)?;
+            writeln!(
+                out,
+                "latest sequence number: {}",
+                db.latest_sequence_number()
+            )?;
+
+            for cf in db.cf_handles() {
+                writeln!(out, "\n** column family {} **", cf.name())?;
+
+                for name in [
+                    "fyodor.estimate-num-keys",
+                    "fyodor.total-sst-files-size",
+                    "fyodor.cur-size-active-mem-table",
+                    "fyodor.num-entries-active-mem-table",
+                    "fyodor.estimate-pending-compaction-bytes",
+                ] {
+                    if let Some(value) = db.get_property_cf(&cf, name)? {
+                        writeln!(out, "{}: {}", name.trim_start_matches("fyodor."), value)?;
+                    }
+                }
+
+                for name in ["fyodor.levelstats", "fyodor.compaction-stats"] {
+                    if let Some(value) = db.get_property_cf(&cf, name)? {
+                        write!(out, "\n{value}")?;
+                    }
+                }
+            }
+        }
+        Command::Repair => {
+            let report = fyodor::repair(&cli.db, &latest_options(&cli.db)?)?;
+            writeln!(
+                out,
+                "tables: {}, lost blocks: {}",
+                report.tables, report.lost_blocks
+            )?;
+
+            for path in &report.moved_aside {
+                writeln!(out, "moved aside: {}", path.display())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens the database at `path`, which must exist, with the options it was last opened with
+fn open(path: &Path, read_only: bool) -> Result<Db, Box<dyn Error>> {
+    if !path.join("CURRENT").exists() {
+        Err(format!("no database at {}", path.display()))?
+    }
+
+    let options = latest_options(path)?;
+
+    Ok(if read_only {
+        Db::open_read_only(path, options)?
+    } else {
+        Db::open(path, options)?
+    })
+}
+
+/// Returns the options of the OPTIONS file of the database at `path`, the default ones without it
+fn latest_options(path: &Path) -> Result<Options, Box<dyn Error>> {
+    Ok(if path.join("OPTIONS").exists() {
+        load_latest_options(path)?
+    } else {
+        Options::default()
+    })
+}
+
+fn cf(db: &Db, name: &str) -> Result<ColumnFamily, Box<dyn Error>> {
+    db.cf_handle(name)
+        .ok_or_else(|| format!("no column family called {name}").into())
+}
+
+fn print_entry(out: &mut impl Write, iter: &DbIterator, hex: bool) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} ==> {}",
+        format_bytes(iter.key(), hex),
+        format_bytes(iter.value(), hex)
+    )
+}
+
+/// Prints the blocks, their entries, the filter and the properties of a table
+fn print_table(out: &mut impl Write, dump: &TableDump, hex: bool) -> io::Result<()> {
+    let properties = &dump.properties;
+    let internal_key = |internal_key: &[u8]| match key::parse(internal_key) {
+        Some((user_key, seq, value_type)) => {
+            format!("{} @ {seq} : {value_type:?}", format_bytes(user_key, hex))
+        }
+        None => format!("bad internal key {}", format_bytes(internal_key, true)),
+    };
+
+    writeln!(out, "table {}: {} bytes", dump.number, dump.file_size)?;
+    writeln!(
+        out,
+        "entries: {}, deletions: {}, range deletions: {}, merge operands: {}",
+        properties.num_entries,
+        properties.num_deletions,
+        properties.num_range_deletions,
+        properties.num_merge_operands
+    )?;
+    writeln!(
+        out,
+        "sequence numbers: [{}, {}]",
+        properties.smallest_seqno, properties.largest_seqno
+    )?;
+    writeln!(
+        out,
+        "smallest key: {}",
+        internal_key(&properties.smallest_key)
+    )?;
+    writeln!(
+        out,
+        "largest key: {}",
+        internal_key(&properties.largest_key)
+    )?;
+    writeln!(
+        out,
+        "column family: {}, created at: {}, prefix extractor: {}",
+        properties.column_family_id, properties.creation_time, properties.prefix_extractor_name
+    )?;
+    writeln!(
+        out,
+        "index: {} bytes at {}",
+        dump.index_handle.size, dump.index_handle.offset
+    )?;
+    writeln!(
+        out,
+        "filter: {} bytes at {}, {} probes, {}/{} bits set",
+        dump.filter.handle.size,
+        dump.filter.handle.offset,
+        dump.filter.probes,
+        dump.filter.bits_set,
+        dump.filter.bits
+    )?;
+
+    for (n, block) in dump.blocks.iter().enumerate() {
+        writeln!(
+            out,
+            "block {n}: {} bytes at {}, compression: {:?}, {} entries",
+            block.handle.size,
+            block.handle.offset,
+            block.compression,
+            block.entries.len()
+        )?;
+
+        for entry in &block.entries {
+            writeln!(
+                out,
+                "  {} @ {} : {:?} ==> {}",
+                format_bytes(&entry.user_key, hex),
+                entry.sequence,
+                entry.value_type,
+                format_bytes(&entry.value, hex)
+            )?;
+        }
+
+        if let Some(error) = &block.error {
+            writeln!(out, "  error: {error}")?;
+        }
+    }
+
+    for tombstone in &dump.range_tombstones {
+        writeln!(
+            out,
+            "range tombstone: [{}, {}) @ {}",
+            format_bytes(&tombstone.start, hex),
+            format_bytes(&tombstone.end, hex),
+            tombstone.seq
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Reads the bytes of a key or value from the command line, either as text or, with `hex`, as
+/// hexadecimal digits optionally prefixed by `0x`
+fn parse_bytes(arg: &str, hex: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !hex {
+        return Ok(arg.as_bytes().to_vec());
+    }
+
+    let digits = arg.strip_prefix("0x").unwrap_or(arg);
+
+    if !digits.len().is_multiple_of(2) {
+        Err(format!("odd number of hexadecimal digits in {arg}"))?
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("bad hexadecimal digits in {arg}").into())
+        })
+        .collect()
+}
+
+/// Formats the bytes of a key or value either as text, the non printable bytes escaped, or with
+/// `hex` as hexadecimal digits prefixed by `0x`
+fn format_bytes(bytes: &[u8], hex: bool) -> String {
+    if hex {
+        let digits: String = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+        format!("0x{digits}")
+    } else {
+        bytes.escape_ascii().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{format_bytes, parse_bytes, run, Cli};
+    use clap::Parser;
+
+    fn run_args(args: &[&str]) -> Result<String, String> {
+        let cli = Cli::try_parse_from(std::iter::once("fyodor-cli").chain(args.iter().copied()))
+            .map_err(|error| error.to_string())?;
+        let mut out = Vec::new();
+
+        run(&cli, &mut out).map_err(|error| error.to_string())?;
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn keys_and_values_round_trip() {
+        assert_eq!(parse_bytes("0x00fF", true).unwrap(), vec![0, 255]);
+        assert_eq!(parse_bytes("00ff", true).unwrap(), vec![0, 255]);
+        assert!(parse_bytes("0x0", true).is_err());
+        assert!(parse_bytes("zz", true).is_err());
+        assert_eq!(parse_bytes("0x00", false).unwrap(), b"0x00".to_vec());
+
+        assert_eq!(format_bytes(&[0, 255], true), "0x00FF");
+        assert_eq!(format_bytes(b"key\n\x00", false), "key\\n\\x00");
+    }
+
+    #[test]
+    fn json_dumps_round_trip_keys_and_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let db = path.to_str().unwrap();
+        let key = b"\"\\\x01\n\x00";
+        let name = "we\"ird\\\x01";
+
+        {
+            let db = fyodor::Db::open(db, Default::default()).unwrap();
+            let cf = db.create_cf(name).unwrap();
+            db.put_cf(&cf, key, b"value").unwrap();
+            db.flush().unwrap();
+        }
+
+        let dump: serde_json::Value =
+            serde_json::from_str(&run_args(&["--db", db, "dump", "--format", "json"]).unwrap())
+                .unwrap();
+        assert_eq!(dump["column_families"][1]["name"], name);
+        assert_eq!(
+            dump["column_families"][1]["entries"][0]["key"],
+            "225c010a00"
+        );
+
+        let table = std::fs::read_dir(&path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|extension| extension == "sst"))
+            .unwrap();
+        let table = table.to_str().unwrap();
+        let dump: serde_json::Value = serde_json::from_str(
+            &run_args(&["--db", db, "dump", "--table", table, "--format", "json"]).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(dump["blocks"][0]["entries"][0]["user_key"], "225c010a00");
+    }
+
+    #[test]
+    fn commands_operate_on_the_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        let db = db.to_str().unwrap();
+
+        assert_eq!(
+            run_args(&["--db", db, "get", "a"]).unwrap_err(),
+            format!("no database at {db}")
+        );
+
+        fyodor::Db::open(db, Default::default()).unwrap();
+
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            run_args(&["--db", db, "put", key, value]).unwrap();
+        }
+        run_args(&["--db", db, "--hex", "put", "0x00", "0xff"]).unwrap();
+
+        assert_eq!(run_args(&["--db", db, "get", "b"]).unwrap(), "2\n");
+        assert_eq!(
+            run_args(&["--db", db, "get", "d"]).unwrap_err(),
+            "key not found"
+        );
+        assert_eq!(
+            run_args(&["--db", db, "--cf", "other", "get", "b"]).unwrap_err(),
+            "no column family called other"
+        );
+        assert_eq!(
+            run_args(&["--db", db, "scan", "--from", "a", "--to", "c"]).unwrap(),
+            "a ==> 1\nb ==> 2\n"
+        );
+        assert_eq!(
+            run_args(&["--db", db, "scan", "--limit", "1"]).unwrap(),
+            "\\x00 ==> \\xff\n"
+        );
+        assert_eq!(
+            run_args(&["--db", db, "--hex", "scan", "--to", "0x62"]).unwrap(),
+            "0x00 ==> 0xFF\n0x61 ==> 0x31\n"
+        );
+
+        run_args(&["--db", db, "compact", "--bottommost"]).unwrap();
+        assert_eq!(
+            run_args(&["--db", db, "dump"]).unwrap(),
+            "[default]\n\\x00 ==> \\xff\na ==> 1\nb ==> 2\nc ==> 3\nkeys: 4\n"
+        );
+
+        let stats = run_args(&["--db", db, "stats"]).unwrap();
+        assert!(stats.starts_with("latest sequence number: 4\n"));
+        assert!(stats.contains("estimate-num-keys: 4\n"));
+        assert!(stats.contains("Level Files Size(MB)\n"));
+
+        let dump: serde_json::Value =
+            serde_json::from_str(&run_args(&["--db", db, "dump", "--format", "json"]).unwrap())
+                .unwrap();
+        assert_eq!(dump["column_families"][0]["name"], "default");
+        assert_eq!(
+            dump["column_families"][0]["entries"][0],
+            serde_json::json!({"key": "00", "value": "ff"})
+        );
+
+        // The compaction left a single table
+        let table = std::fs::read_dir(db)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.extension().is_some_and(|extension| extension == "sst"))
+            .unwrap();
+        let table = table.to_str().unwrap();
+        let dump: serde_json::Value = serde_json::from_str(
+            &run_args(&["--db", db, "dump", "--table", table, "--format", "json"]).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(dump["properties"]["num_entries"], 4);
+        assert_eq!(dump["blocks"][0]["entries"][1]["user_key"], "61");
+        assert_eq!(dump["blocks"][0]["entries"][1]["value"], "31");
+
+        let dump = run_args(&["--db", db, "dump", "--table", table]).unwrap();
+        assert!(dump.contains("entries: 4, deletions: 0"));
+        assert!(dump.contains("\n  a @ 1 : Value ==> 1\n"));
+
+        let checkpoint = dir.path().join("checkpoint");
+        let checkpoint = checkpoint.to_str().unwrap();
+        run_args(&["--db", db, "checkpoint", checkpoint]).unwrap();
+        assert_eq!(run_args(&["--db", checkpoint, "get", "c"]).unwrap(), "3\n");
+
+        // The manifest left without CURRENT is moved aside
+        std::fs::remove_file(dir.path().join("checkpoint").join("CURRENT")).unwrap();
+        let repair = run_args(&["--db", checkpoint, "repair"]).unwrap();
+        assert!(repair.starts_with("tables: 1, lost blocks: 0\nmoved aside: "));
+        assert!(repair.contains("MANIFEST-"));
+        assert_eq!(run_args(&["--db", checkpoint, "get", "c"]).unwrap(), "3\n");
+    }
+}
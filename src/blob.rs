@@ -0,0 +1,437 @@
+use crate::version::BlobFileMetaData;
+use integer_encoding::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// Magic number opening every blob file
+const BLOB_MAGIC: u64 = 0x626f_6c62_6f64_796f;
+
+/// Bytes taken by the header of a record: the key size and the value size
+const RECORD_HEADER_SIZE: usize = size_of::<u32>() + size_of::<u64>();
+
+/// Bytes taken by the CRC32 following the value of a record
+const RECORD_TRAILER_SIZE: usize = size_of::<u32>();
+
+/// Bytes of a value streamed to or from a blob file at once
+const STREAM_CHUNK_SIZE: usize = 64 << 10;
+
+#[derive(Error, Debug)]
+pub enum BlobError {
+    #[error("I/O error on a blob file")]
+    Io(#[from] io::Error),
+    #[error("Corrupted blob file: {0}")]
+    Corruption(&'static str),
+}
+
+/// Returns the path of the blob file with the given number
+pub fn blob_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.blob", number))
+}
+
+/// Extracts the blob file number from a blob file name, returning None for any other file
+pub fn parse_blob_file_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".blob")?.parse().ok()
+}
+
+/// Where a value separated from its key lives: the entry of the key in the tables holds this
+/// index instead of the value, with the [ValueType::BlobIndex](crate::key::ValueType::BlobIndex)
+/// type
+///
+/// The memory layout is:
+/// [ file_number, offset, size ]
+/// where all three are varints, the offset being the one of the value in the blob file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobIndex {
+    pub file_number: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlobIndex {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(3 * u64::MAX.required_space());
+
+        buffer.extend_from_slice(&self.file_number.encode_var_vec());
+        buffer.extend_from_slice(&self.offset.encode_var_vec());
+        buffer.extend_from_slice(&self.size.encode_var_vec());
+
+        buffer
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<BlobIndex, BlobError> {
+        let mut varint = || {
+            let (value, size) =
+                u64::decode_var(data).ok_or(BlobError::Corruption("bad blob index"))?;
+            data = &data[size..];
+
+            Ok::<_, BlobError>(value)
+        };
+
+        Ok(BlobIndex {
+            file_number: varint()?,
+            offset: varint()?,
+            size: varint()?,
+        })
+    }
+}
+
+/// Writes the values separated from their keys to a blob file
+///
+/// The file starts with a magic number followed by one record per value:
+/// [ key_size, value_size, key, value, crc ]
+/// where key_size is a little-endian u32, value_size a little-endian u64, and crc a little-endian
+/// CRC32 of the value. The key is only kept to tell which entries the values belong to.
+///
+/// The file is written under a temporary name and renamed once complete, like tables.
+pub struct BlobFileBuilder {
+    writer: BufWriter<File>,
+    dir: PathBuf,
+    number: u64,
+    offset: u64,
+    blob_count: u64,
+    /// Bytes of the values
+    blob_bytes: u64,
+}
+
+impl BlobFileBuilder {
+    pub fn new(dir: &Path, number: u64) -> Result<BlobFileBuilder, BlobError> {
+        let mut writer = BufWriter::new(File::create(tmp_file_name(dir, number))?);
+        writer.write_all(&BLOB_MAGIC.to_le_bytes())?;
+
+        Ok(BlobFileBuilder {
+            writer,
+            dir: dir.to_path_buf(),
+            number,
+            offset: size_of::<u64>() as u64,
+            blob_count: 0,
+            blob_bytes: 0,
+        })
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// Returns true if no value was added yet
+    pub fn is_empty(&self) -> bool {
+        self.blob_count == 0
+    }
+
+    /// Appends the value of `user_key`, returning where it was written
+    pub fn add(&mut self, user_key: &[u8], value: &[u8]) -> Result<BlobIndex, BlobError> {
+        self.writer
+            .write_all(&(user_key.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(value.len() as u64).to_le_bytes())?;
+        self.writer.write_all(user_key)?;
+        self.writer.write_all(value)?;
+        self.writer
+            .write_all(&crc32fast::hash(value).to_le_bytes())?;
+
+        Ok(self.added(user_key, value.len() as u64))
+    }
+
+    /// Same as [BlobFileBuilder::add], the value being read from `value` until its end one chunk
+    /// at a time rather than held in memory
+    ///
+    /// The size of the value is only known at the end, so it's written over the header then.
+    pub fn add_reader(
+        &mut self,
+        user_key: &[u8],
+        value: &mut dyn Read,
+    ) -> Result<BlobIndex, BlobError> {
+        let header_offset = self.offset;
+        self.writer
+            .write_all(&(user_key.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&0_u64.to_le_bytes())?;
+        self.writer.write_all(user_key)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+        let mut size = 0_u64;
+
+        loop {
+            let read = match value.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            hasher.update(&chunk[..read]);
+            self.writer.write_all(&chunk[..read])?;
+            size += read as u64;
+        }
+
+        self.writer.write_all(&hasher.finalize().to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer
+            .get_ref()
+            .write_all_at(&size.to_le_bytes(), header_offset + size_of::<u32>() as u64)?;
+
+        Ok(self.added(user_key, size))
+    }
+
+    /// Accounts for the record of `user_key` just written, whose value takes `size` bytes,
+    /// returning its index
+    fn added(&mut self, user_key: &[u8], size: u64) -> BlobIndex {
+        let index = BlobIndex {
+            file_number: self.number,
+            offset: self.offset + (RECORD_HEADER_SIZE + user_key.len()) as u64,
+            size,
+        };
+
+        self.offset = index.offset + index.size + RECORD_TRAILER_SIZE as u64;
+        self.blob_count += 1;
+        self.blob_bytes += index.size;
+
+        index
+    }
+
+    /// Makes the file durable under its final name, returning its manifest entry
+    pub fn finish(self) -> Result<BlobFileMetaData, BlobError> {
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        std::fs::rename(
+            tmp_file_name(&self.dir, self.number),
+            blob_file_name(&self.dir, self.number),
+        )?;
+        File::open(&self.dir)?.sync_all()?;
+
+        Ok(BlobFileMetaData {
+            number: self.number,
+            blob_count: self.blob_count,
+            blob_bytes: self.blob_bytes,
+            file_size: self.offset,
+            garbage_count: 0,
+            garbage_bytes: 0,
+        })
+    }
+
+    /// Deletes the file written so far
+    pub fn abandon(self) -> Result<(), BlobError> {
+        drop(self.writer);
+        std::fs::remove_file(tmp_file_name(&self.dir, self.number))?;
+
+        Ok(())
+    }
+}
+
+fn tmp_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.blob.tmp", number))
+}
+
+/// The blob files of a database opened for reading, each one opened once
+#[derive(Debug)]
+pub(crate) struct BlobFiles {
+    dir: PathBuf,
+    files: Mutex<HashMap<u64, Arc<File>>>,
+}
+
+impl BlobFiles {
+    pub(crate) fn new(dir: &Path) -> BlobFiles {
+        BlobFiles {
+            dir: dir.to_path_buf(),
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn file(&self, number: u64) -> Result<Arc<File>, BlobError> {
+        let mut files = self.files.lock().unwrap();
+
+        if let Some(file) = files.get(&number) {
+            return Ok(file.clone());
+        }
+
+        let file = Arc::new(File::open(blob_file_name(&self.dir, number))?);
+        files.insert(number, file.clone());
+
+        Ok(file)
+    }
+
+    /// Reads the value `index` points to, checking its CRC
+    pub(crate) fn get(&self, index: &BlobIndex) -> Result<Vec<u8>, BlobError> {
+        let file = self.file(index.file_number)?;
+        let mut contents = vec![0; index.size as usize + RECORD_TRAILER_SIZE];
+        file.read_exact_at(&mut contents, index.offset)?;
+
+        let crc = contents.split_off(index.size as usize);
+
+        if crc32fast::hash(&contents).to_le_bytes() != crc.as_slice() {
+            return Err(BlobError::Corruption("blob checksum mismatch"));
+        }
+
+        Ok(contents)
+    }
+
+    /// Returns a reader of the value `index` points to, which checks its CRC once read to the
+    /// end
+    ///
+    /// The reader keeps the file open, so it may outlive the file.
+    pub(crate) fn reader(&self, index: &BlobIndex) -> Result<BlobReader, BlobError> {
+        Ok(BlobReader {
+            file: self.file(index.file_number)?,
+            offset: index.offset,
+            remaining: index.size,
+            hasher: crc32fast::Hasher::new(),
+        })
+    }
+
+    /// Closes the files other than `live`, which may be deleted
+    pub(crate) fn retain(&self, live: &HashSet<u64>) {
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|number, _| live.contains(number));
+    }
+}
+
+/// Reads a value from its blob file, see [Db::get_reader](crate::Db::get_reader)
+#[derive(Debug)]
+pub struct BlobReader {
+    file: Arc<File>,
+    /// Offset of the next byte to read
+    offset: u64,
+    /// Bytes of the value not read yet
+    remaining: u64,
+    /// CRC32 of the bytes read so far
+    hasher: crc32fast::Hasher,
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+
+        let size = buffer.len().min(self.remaining as usize);
+        let read = self.file.read_at(&mut buffer[..size], self.offset)?;
+
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        self.hasher.update(&buffer[..read]);
+        self.offset += read as u64;
+        self.remaining -= read as u64;
+
+        if self.remaining == 0 {
+            let mut crc = [0; RECORD_TRAILER_SIZE];
+            self.file.read_exact_at(&mut crc, self.offset)?;
+
+            if self.hasher.clone().finalize().to_le_bytes() != crc {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    BlobError::Corruption("blob checksum mismatch"),
+                ));
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+/// A value read a chunk at a time, see [Db::get_reader](crate::Db::get_reader)
+///
+/// The values in blob files are read from their file as the reader goes, the other ones are in
+/// memory already.
+#[derive(Debug)]
+pub enum ValueReader {
+    InMemory(Cursor<Vec<u8>>),
+    Blob(BlobReader),
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::InMemory(reader) => reader.read(buffer),
+            ValueReader::Blob(reader) => reader.read(buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::{BlobError, BlobFileBuilder, BlobFiles, BlobIndex};
+    use std::io::{ErrorKind, Read};
+
+    #[test]
+    fn blobs_are_read_back_by_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = BlobFileBuilder::new(dir.path(), 7).unwrap();
+
+        let indexes: Vec<_> = (0..100_u32)
+            .map(|n| {
+                let value = vec![n as u8; n as usize * 10];
+                builder.add(&n.to_be_bytes(), &value).unwrap()
+            })
+            .collect();
+
+        let meta = builder.finish().unwrap();
+        assert_eq!(meta.number, 7);
+        assert_eq!(meta.blob_count, 100);
+        assert_eq!(meta.blob_bytes, (0..100).map(|n| n * 10).sum::<u64>());
+
+        let files = BlobFiles::new(dir.path());
+
+        for (n, index) in indexes.iter().enumerate() {
+            assert_eq!(BlobIndex::decode(&index.encode()).unwrap(), *index);
+            assert_eq!(files.get(index).unwrap(), vec![n as u8; n * 10]);
+        }
+
+        let bad_index = BlobIndex {
+            offset: indexes[50].offset + 1,
+            ..indexes[50]
+        };
+        assert!(matches!(
+            files.get(&bad_index),
+            Err(BlobError::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn streamed_blobs_are_read_back_by_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = BlobFileBuilder::new(dir.path(), 7).unwrap();
+
+        let small = builder.add(b"small", b"value").unwrap();
+        let streamed_value = vec![1; 200_000];
+        let streamed = builder
+            .add_reader(b"streamed", &mut streamed_value.as_slice())
+            .unwrap();
+
+        let meta = builder.finish().unwrap();
+        assert_eq!(meta.blob_count, 2);
+        assert_eq!(meta.blob_bytes, 5 + 200_000);
+
+        let files = BlobFiles::new(dir.path());
+        assert_eq!(files.get(&small).unwrap(), b"value");
+        assert_eq!(files.get(&streamed).unwrap(), streamed_value);
+
+        let mut contents = Vec::new();
+        files
+            .reader(&streamed)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(contents, streamed_value);
+
+        let bad_index = BlobIndex {
+            offset: small.offset + 1,
+            ..small
+        };
+        let error = files
+            .reader(&bad_index)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
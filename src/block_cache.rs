@@ -0,0 +1,742 @@
+use crate::perf_context;
+use crate::storage::BlockBuffer;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Number of shards of a [BlockCache] or a [RowCache](crate::RowCache), each locked on its own
+pub(crate) const NUM_SHARDS: usize = 16;
+
+/// Identifies a data block: the id a table got from the cache, and the offset of the block
+type CacheKey = (u64, u64);
+
+/// How a [BlockCache] picks the blocks to evict
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Evicts the least recently used blocks
+    #[default]
+    Lru,
+    /// Evicts the first block not used since the last sweep of a clock hand over the blocks,
+    /// which only takes a shared lock on lookups: cheaper for reads from many threads
+    Clock,
+}
+
+/// Priority of a block in a [BlockCache]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePriority {
+    /// Evicted first
+    #[default]
+    Low,
+    /// Evicted only once over the share of the cache reserved by
+    /// [BlockCache::with_high_pri_pool_ratio], or once no low priority block is left: index and
+    /// filter blocks, which a large scan would otherwise push out
+    High,
+}
+
+/// Keeps the data blocks read recently in memory, up to a number of bytes, so that reading them
+/// again doesn't hit the disk, once set as [ColumnFamilyOptions::block_cache] of the column
+/// families sharing it
+///
+/// The blocks are spread over shards by key, each evicting blocks according to the
+/// [CachePolicy] once over its share of the capacity, so that concurrent reads rarely wait for
+/// each other.
+///
+/// [ColumnFamilyOptions::block_cache]: crate::ColumnFamilyOptions::block_cache
+pub struct BlockCache {
+    shards: Vec<Box<dyn Cache>>,
+    capacity: usize,
+    policy: CachePolicy,
+    /// Share of the capacity reserved to high priority blocks
+    high_pri_pool_ratio: f64,
+    /// Where the evicted blocks go, see [BlockCache::with_secondary_cache]
+    secondary: Option<Arc<SecondaryCache>>,
+    /// Id handed to the next table, see [BlockCache::new_id]
+    next_id: AtomicU64,
+}
+
+/// A shard of a [BlockCache], evicting blocks once over its capacity
+trait Cache: Send + Sync {
+    /// Returns the block at `key`, recording its use
+    fn lookup(&self, key: CacheKey) -> Option<Arc<BlockBuffer>>;
+
+    /// Adds `block`, returning the blocks evicted to make room for it
+    fn insert(
+        &self,
+        key: CacheKey,
+        block: Arc<BlockBuffer>,
+        priority: CachePriority,
+    ) -> Vec<(CacheKey, Arc<BlockBuffer>)>;
+
+    /// Returns the bytes of the blocks held
+    fn usage(&self) -> usize;
+
+    /// Returns the keys of the blocks held, the ones most likely to be evicted first
+    fn keys(&self) -> Vec<CacheKey>;
+}
+
+/// The bytes an entry takes in a cache
+pub(crate) trait Charge {
+    fn charge(&self) -> usize;
+}
+
+impl Charge for Arc<BlockBuffer> {
+    fn charge(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Charge for Vec<u8> {
+    fn charge(&self) -> usize {
+        self.len()
+    }
+}
+
+/// A shard of a cache evicting its least recently used entries, blocks or else
+pub(crate) struct LruShard<K, T> {
+    capacity: usize,
+    /// Bytes of the high priority blocks spared while other blocks are left
+    high_pri_capacity: usize,
+    /// Bytes of the blocks held
+    pub(crate) usage: usize,
+    /// Bytes of the high priority blocks held
+    high_pri_usage: usize,
+    /// Incremented on every use of a block
+    clock: u64,
+    /// The blocks, with the value of the clock when they were last used and their priority
+    blocks: HashMap<K, (T, u64, CachePriority)>,
+    /// The keys of the low priority blocks, from the least to the most recently used
+    lru: BTreeMap<u64, K>,
+    /// The keys of the high priority blocks, from the least to the most recently used
+    high_pri_lru: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, T: Charge> LruShard<K, T> {
+    pub(crate) fn new(capacity: usize) -> LruShard<K, T> {
+        LruShard::with_high_pri_capacity(capacity, 0)
+    }
+
+    fn with_high_pri_capacity(capacity: usize, high_pri_capacity: usize) -> LruShard<K, T> {
+        LruShard {
+            capacity,
+            high_pri_capacity,
+            usage: 0,
+            high_pri_usage: 0,
+            clock: 0,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            high_pri_lru: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the list of the blocks of `priority`
+    fn lru_of(&mut self, priority: CachePriority) -> &mut BTreeMap<u64, K> {
+        match priority {
+            CachePriority::Low => &mut self.lru,
+            CachePriority::High => &mut self.high_pri_lru,
+        }
+    }
+
+    /// Returns the block at `key`, now the most recently used
+    pub(crate) fn lookup(&mut self, key: &K) -> Option<&T> {
+        let (_, last_use, priority) = *self.blocks.get(key)?;
+
+        self.clock += 1;
+        let clock = self.clock;
+        let lru = self.lru_of(priority);
+        lru.remove(&last_use);
+        lru.insert(clock, key.clone());
+
+        let (block, last_use, _) = self.blocks.get_mut(key)?;
+        *last_use = clock;
+
+        Some(block)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<T> {
+        let (block, last_use, priority) = self.blocks.remove(key)?;
+
+        self.usage -= block.charge();
+        if priority == CachePriority::High {
+            self.high_pri_usage -= block.charge();
+        }
+        self.lru_of(priority).remove(&last_use);
+
+        Some(block)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, block: T) -> Vec<(K, T)> {
+        self.insert_with_priority(key, block, CachePriority::Low)
+    }
+
+    /// Adds `block`, evicting the least recently used low priority blocks first: the high
+    /// priority ones over their reserved capacity become low priority ones, the others are
+    /// only evicted once alone
+    fn insert_with_priority(&mut self, key: K, block: T, priority: CachePriority) -> Vec<(K, T)> {
+        // A block which doesn't fit would evict everything else for nothing
+        if block.charge() > self.capacity {
+            return vec![(key, block)];
+        }
+
+        // Without a reserved capacity, every block is evicted in the same order
+        let priority = match self.high_pri_capacity {
+            0 => CachePriority::Low,
+            _ => priority,
+        };
+
+        self.remove(&key);
+
+        self.clock += 1;
+        let clock = self.clock;
+        self.usage += block.charge();
+        if priority == CachePriority::High {
+            self.high_pri_usage += block.charge();
+        }
+        self.lru_of(priority).insert(clock, key.clone());
+        self.blocks.insert(key, (block, clock, priority));
+
+        while self.high_pri_usage > self.high_pri_capacity {
+            let Some((last_use, key)) = self.high_pri_lru.pop_first() else {
+                break;
+            };
+            let (block, _, priority) = self.blocks.get_mut(&key).unwrap();
+
+            *priority = CachePriority::Low;
+            self.high_pri_usage -= block.charge();
+            self.lru.insert(last_use, key);
+        }
+
+        let mut evicted = Vec::new();
+
+        while self.usage > self.capacity {
+            let lru = match self.lru.is_empty() {
+                true => &mut self.high_pri_lru,
+                false => &mut self.lru,
+            };
+            let Some((_, key)) = lru.pop_first() else {
+                break;
+            };
+
+            if let Some((block, _, priority)) = self.blocks.remove(&key) {
+                self.usage -= block.charge();
+                if priority == CachePriority::High {
+                    self.high_pri_usage -= block.charge();
+                }
+                evicted.push((key, block));
+            }
+        }
+
+        evicted
+    }
+}
+
+impl Cache for Mutex<LruShard<CacheKey, Arc<BlockBuffer>>> {
+    fn lookup(&self, key: CacheKey) -> Option<Arc<BlockBuffer>> {
+        self.lock().unwrap().lookup(&key).cloned()
+    }
+
+    fn insert(
+        &self,
+        key: CacheKey,
+        block: Arc<BlockBuffer>,
+        priority: CachePriority,
+    ) -> Vec<(CacheKey, Arc<BlockBuffer>)> {
+        self.lock()
+            .unwrap()
+            .insert_with_priority(key, block, priority)
+    }
+
+    fn usage(&self) -> usize {
+        self.lock().unwrap().usage
+    }
+
+    fn keys(&self) -> Vec<CacheKey> {
+        let shard = self.lock().unwrap();
+
+        shard
+            .lru
+            .values()
+            .chain(shard.high_pri_lru.values())
+            .copied()
+            .collect()
+    }
+}
+
+struct ClockShard {
+    capacity: usize,
+    /// Bytes of the high priority blocks spared while other blocks are left
+    high_pri_capacity: usize,
+    /// Bytes of the blocks held
+    usage: usize,
+    /// Bytes of the high priority blocks held
+    high_pri_usage: usize,
+    /// The blocks, in the order the hand sweeps them, None once evicted
+    slots: Vec<Option<ClockSlot>>,
+    /// Index of the slot of each block
+    index: HashMap<CacheKey, usize>,
+    /// Slots freed by evictions
+    free: Vec<usize>,
+    /// Slot the hand last passed, the sweeps start after it
+    hand: usize,
+}
+
+struct ClockSlot {
+    key: CacheKey,
+    block: Arc<BlockBuffer>,
+    priority: CachePriority,
+    /// Set on every use of the block, cleared as the hand passes it
+    referenced: AtomicBool,
+}
+
+impl ClockShard {
+    /// Evicts blocks until `len` more bytes fit, sparing the ones used since the hand last
+    /// passed them and the high priority ones within their reserved capacity, and returns them
+    fn make_room(&mut self, len: usize) -> Vec<(CacheKey, Arc<BlockBuffer>)> {
+        let mut evicted = Vec::new();
+
+        while self.usage + len > self.capacity && !self.index.is_empty() {
+            self.hand = (self.hand + 1) % self.slots.len();
+
+            let Some(slot) = &self.slots[self.hand] else {
+                continue;
+            };
+
+            // Once the high priority blocks are alone, they are within their capacity
+            let spared = slot.priority == CachePriority::High
+                && self.high_pri_usage <= self.high_pri_capacity
+                && self.high_pri_usage < self.usage;
+
+            if spared || slot.referenced.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+
+            self.remove(self.hand);
+            let slot = self.slots[self.hand].take().unwrap();
+            evicted.push((slot.key, slot.block));
+        }
+
+        evicted
+    }
+
+    /// Forgets the block in slot `i`, left to take by the caller
+    fn remove(&mut self, i: usize) {
+        let slot = self.slots[i].as_ref().unwrap();
+
+        self.usage -= slot.block.len();
+        if slot.priority == CachePriority::High {
+            self.high_pri_usage -= slot.block.len();
+        }
+        self.index.remove(&slot.key);
+        self.free.push(i);
+    }
+}
+
+impl Cache for RwLock<ClockShard> {
+    fn lookup(&self, key: CacheKey) -> Option<Arc<BlockBuffer>> {
+        let shard = self.read().unwrap();
+        let slot = shard.slots[*shard.index.get(&key)?].as_ref().unwrap();
+
+        slot.referenced.store(true, Ordering::Relaxed);
+
+        Some(slot.block.clone())
+    }
+
+    fn insert(
+        &self,
+        key: CacheKey,
+        block: Arc<BlockBuffer>,
+        priority: CachePriority,
+    ) -> Vec<(CacheKey, Arc<BlockBuffer>)> {
+        let mut shard = self.write().unwrap();
+
+        // A block which doesn't fit would evict everything else for nothing
+        if block.len() > shard.capacity {
+            return vec![(key, block)];
+        }
+
+        // Without a reserved capacity, every block is evicted in the same order
+        let priority = match shard.high_pri_capacity {
+            0 => CachePriority::Low,
+            _ => priority,
+        };
+
+        if let Some(&i) = shard.index.get(&key) {
+            shard.remove(i);
+            shard.slots[i] = None;
+        }
+
+        // Evicting first spares the new block from the sweep
+        let evicted = shard.make_room(block.len());
+        shard.usage += block.len();
+        if priority == CachePriority::High {
+            shard.high_pri_usage += block.len();
+        }
+
+        let slot = Some(ClockSlot {
+            key,
+            block,
+            priority,
+            referenced: AtomicBool::new(false),
+        });
+        let i = match shard.free.pop() {
+            Some(i) => {
+                shard.slots[i] = slot;
+                i
+            }
+            None => {
+                shard.slots.push(slot);
+                shard.slots.len() - 1
+            }
+        };
+        shard.index.insert(key, i);
+
+        evicted
+    }
+
+    fn usage(&self) -> usize {
+        self.read().unwrap().usage
+    }
+
+    fn keys(&self) -> Vec<CacheKey> {
+        let shard = self.read().unwrap();
+
+        // The hand sweeps the slots after it first
+        (1..=shard.slots.len())
+            .filter_map(|n| shard.slots[(shard.hand + n) % shard.slots.len()].as_ref())
+            .map(|slot| slot.key)
+            .collect()
+    }
+}
+
+impl BlockCache {
+    /// Returns an empty cache holding at most `capacity` bytes of blocks, evicting the least
+    /// recently used ones
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache::with_policy(capacity, CachePolicy::Lru)
+    }
+
+    /// Returns an empty cache holding at most `capacity` bytes of blocks, evicting them
+    /// according to `policy`
+    pub fn with_policy(capacity: usize, policy: CachePolicy) -> BlockCache {
+        BlockCache {
+            shards: new_shards(capacity, 0, policy),
+            capacity,
+            policy,
+            high_pri_pool_ratio: 0.0,
+            secondary: None,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserves `ratio` of the capacity to the [CachePriority::High] blocks: the index and
+    /// filter blocks cached with [ColumnFamilyOptions::cache_index_and_filter_blocks] are only
+    /// evicted once over this share, so that scans reading many data blocks don't evict them
+    ///
+    /// [ColumnFamilyOptions::cache_index_and_filter_blocks]:
+    /// crate::ColumnFamilyOptions::cache_index_and_filter_blocks
+    pub fn with_high_pri_pool_ratio(mut self, ratio: f64) -> BlockCache {
+        let ratio = ratio.clamp(0.0, 1.0);
+        let high_pri_capacity = (self.capacity as f64 * ratio) as usize;
+
+        self.shards = new_shards(self.capacity, high_pri_capacity, self.policy);
+        self.high_pri_pool_ratio = ratio;
+        self
+    }
+
+    /// Keeps the blocks evicted from the cache in `secondary`, compressed, where they are looked
+    /// up before reading them from their table again
+    pub fn with_secondary_cache(mut self, secondary: Arc<SecondaryCache>) -> BlockCache {
+        self.secondary = Some(secondary);
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn secondary_cache(&self) -> Option<&Arc<SecondaryCache>> {
+        self.secondary.as_ref()
+    }
+
+    pub fn policy(&self) -> CachePolicy {
+        self.policy
+    }
+
+    pub fn high_pri_pool_ratio(&self) -> f64 {
+        self.high_pri_pool_ratio
+    }
+
+    /// Returns the bytes of the blocks in the cache, not counting its secondary cache
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.usage()).sum()
+    }
+
+    /// Returns a new id for the blocks of a table, unique among the tables of every database
+    /// sharing the cache, unlike their file numbers
+    pub(crate) fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the ids of the tables and the offsets of the blocks in the cache, not counting
+    /// its secondary cache, from the ones most likely to be evicted first
+    pub(crate) fn keys(&self) -> Vec<(u64, u64)> {
+        self.shards.iter().flat_map(|shard| shard.keys()).collect()
+    }
+
+    /// Returns the block at `offset` of the table `id`, if cached, moving it back from the
+    /// secondary cache if it's there
+    pub(crate) fn lookup(&self, id: u64, offset: u64) -> Option<Arc<BlockBuffer>> {
+        let key = (id, offset);
+
+        if let Some(block) = self.shard(key).lookup(key) {
+            return Some(block);
+        }
+
+        let block = Arc::new(self.secondary.as_ref()?.take(key)?);
+        perf_context::record(|ctx| ctx.secondary_cache_hit_count += 1);
+        self.insert(id, offset, block.clone(), CachePriority::Low);
+
+        Some(block)
+    }
+
+    /// Caches `block`, found at `offset` of the table `id`
+    pub(crate) fn insert(
+        &self,
+        id: u64,
+        offset: u64,
+        block: Arc<BlockBuffer>,
+        priority: CachePriority,
+    ) {
+        let evicted = self
+            .shard((id, offset))
+            .insert((id, offset), block, priority);
+
+        if let Some(secondary) = &self.secondary {
+            for (key, block) in evicted {
+                secondary.insert(key, &block);
+            }
+        }
+    }
+
+    fn shard(&self, key: CacheKey) -> &dyn Cache {
+        self.shards[shard_index(key)].as_ref()
+    }
+}
+
+/// Returns the shards of a [BlockCache] of `capacity` bytes, `high_pri_capacity` of which are
+/// reserved to high priority blocks
+fn new_shards(
+    capacity: usize,
+    high_pri_capacity: usize,
+    policy: CachePolicy,
+) -> Vec<Box<dyn Cache>> {
+    let shard_capacity = capacity.div_ceil(NUM_SHARDS);
+    let high_pri_capacity = high_pri_capacity.div_ceil(NUM_SHARDS);
+
+    (0..NUM_SHARDS)
+        .map(|_| -> Box<dyn Cache> {
+            match policy {
+                CachePolicy::Lru => Box::new(Mutex::new(LruShard::with_high_pri_capacity(
+                    shard_capacity,
+                    high_pri_capacity,
+                ))),
+                CachePolicy::Clock => Box::new(RwLock::new(ClockShard {
+                    capacity: shard_capacity,
+                    high_pri_capacity,
+                    usage: 0,
+                    high_pri_usage: 0,
+                    slots: Vec::new(),
+                    index: HashMap::new(),
+                    free: Vec::new(),
+                    hand: 0,
+                })),
+            }
+        })
+        .collect()
+}
+
+/// Keeps the blocks evicted from a [BlockCache] in memory, compressed with LZ4, once set with
+/// [BlockCache::with_secondary_cache]: more blocks fit than in the block cache, at the cost of
+/// decompressing them, which is still much cheaper than reading them from their table
+///
+/// The blocks are moved back to the block cache when looked up, and the least recently
+/// evicted ones are dropped once over the capacity.
+pub struct SecondaryCache {
+    shards: Vec<Mutex<LruShard<CacheKey, Vec<u8>>>>,
+    capacity: usize,
+}
+
+impl SecondaryCache {
+    /// Returns an empty cache holding at most `capacity` bytes of compressed blocks
+    pub fn new(capacity: usize) -> SecondaryCache {
+        SecondaryCache {
+            shards: (0..NUM_SHARDS)
+                .map(|_| Mutex::new(LruShard::new(capacity.div_ceil(NUM_SHARDS))))
+                .collect(),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the bytes of the compressed blocks in the cache
+    pub fn usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().usage)
+            .sum()
+    }
+
+    fn insert(&self, key: CacheKey, block: &BlockBuffer) {
+        let compressed = lz4_flex::compress_prepend_size(block.as_bytes());
+
+        self.shards[shard_index(key)]
+            .lock()
+            .unwrap()
+            .insert(key, compressed);
+    }
+
+    /// Removes the block at `key` from the cache, and returns it decompressed
+    fn take(&self, key: CacheKey) -> Option<BlockBuffer> {
+        let compressed = self.shards[shard_index(key)].lock().unwrap().remove(&key)?;
+        let contents = lz4_flex::decompress_size_prepended(&compressed).ok()?;
+
+        BlockBuffer::from_bytes(&contents).ok()
+    }
+}
+
+/// Returns the index of the shard holding the entry at `key`
+pub(crate) fn shard_index<K: Hash>(key: K) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    hasher.finish() as usize % NUM_SHARDS
+}
+
+impl fmt::Debug for SecondaryCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecondaryCache")
+            .field("capacity", &self.capacity)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .field("high_pri_pool_ratio", &self.high_pri_pool_ratio)
+            .field("secondary", &self.secondary)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block_cache::{shard_index, BlockCache, CachePolicy, CachePriority, SecondaryCache};
+    use crate::storage::BlockBuffer;
+    use std::sync::Arc;
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        evicts_blocks_not_used_since(CachePolicy::Lru);
+    }
+
+    #[test]
+    fn clock_evicts_blocks_not_used_since_the_last_sweep() {
+        evicts_blocks_not_used_since(CachePolicy::Clock);
+    }
+
+    fn evicts_blocks_not_used_since(policy: CachePolicy) {
+        let block = || Arc::new(BlockBuffer::new(1000));
+        // A single shard holds the blocks of the test
+        let cache = BlockCache::with_policy(16 * 2500, policy);
+        let id = cache.new_id();
+
+        let keys: Vec<_> = (0..)
+            .filter(|offset| shard_index((id, *offset)) == shard_index((id, 0)))
+            .take(3)
+            .collect();
+
+        cache.insert(id, keys[0], block(), CachePriority::Low);
+        cache.insert(id, keys[1], block(), CachePriority::Low);
+        assert!(cache.lookup(id, keys[0]).is_some());
+
+        // The second block is the least recently used one
+        cache.insert(id, keys[2], block(), CachePriority::Low);
+        assert!(cache.lookup(id, keys[0]).is_some());
+        assert!(cache.lookup(id, keys[1]).is_none());
+        assert!(cache.lookup(id, keys[2]).is_some());
+        assert_eq!(cache.usage(), 2000);
+
+        assert!(cache.lookup(cache.new_id(), keys[0]).is_none());
+    }
+
+    #[test]
+    fn high_priority_blocks_survive_scans_in_their_pool() {
+        spares_high_priority_blocks(CachePolicy::Lru);
+        spares_high_priority_blocks(CachePolicy::Clock);
+    }
+
+    fn spares_high_priority_blocks(policy: CachePolicy) {
+        let block = || Arc::new(BlockBuffer::new(1000));
+        // A single shard holds the blocks of the test, a third of which for high priority ones
+        let cache = BlockCache::with_policy(16 * 3000, policy).with_high_pri_pool_ratio(0.34);
+        let id = cache.new_id();
+
+        let keys: Vec<_> = (0..)
+            .filter(|offset| shard_index((id, *offset)) == shard_index((id, 0)))
+            .take(6)
+            .collect();
+
+        cache.insert(id, keys[0], block(), CachePriority::High);
+
+        // A scan of low priority blocks, never looked up again
+        for &key in &keys[1..5] {
+            cache.insert(id, key, block(), CachePriority::Low);
+        }
+        assert!(cache.lookup(id, keys[0]).is_some());
+        assert_eq!(cache.usage(), 3000);
+
+        // Over their pool, the high priority blocks are evicted like the others
+        cache.insert(id, keys[5], block(), CachePriority::High);
+        cache.insert(id, keys[1], block(), CachePriority::High);
+        assert_eq!(cache.usage(), 3000);
+        assert!(cache.lookup(id, keys[1]).is_some());
+    }
+
+    #[test]
+    fn evicted_blocks_come_back_from_the_secondary_cache() {
+        let block = || Arc::new(BlockBuffer::new(1000));
+        let secondary = Arc::new(SecondaryCache::new(1 << 20));
+        let cache = BlockCache::new(16 * 1500).with_secondary_cache(secondary.clone());
+        let id = cache.new_id();
+
+        let keys: Vec<_> = (0..)
+            .filter(|offset| shard_index((id, *offset)) == shard_index((id, 0)))
+            .take(2)
+            .collect();
+
+        cache.insert(id, keys[0], block(), CachePriority::Low);
+        assert_eq!(secondary.usage(), 0);
+
+        cache.insert(id, keys[1], block(), CachePriority::Low);
+        let compressed = secondary.usage();
+        assert!(compressed > 0 && compressed < 1000);
+
+        // The first block swaps places with the second one
+        assert_eq!(cache.lookup(id, keys[0]).unwrap().len(), 1000);
+        assert_eq!(cache.usage(), 1000);
+        assert_eq!(secondary.usage(), compressed);
+        assert!(cache.lookup(id, keys[1]).is_some());
+        assert!(cache.lookup(id + 1, keys[0]).is_none());
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+/// Id of the column family every database has, which can't be dropped
+pub const DEFAULT_COLUMN_FAMILY_ID: u32 = 0;
+
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+/// Handle of a column family, i.e. an independent keyspace of a [Db](crate::Db)
+///
+/// Column families share the write-ahead log, so a [WriteBatch](crate::batch::WriteBatch) can
+/// update several of them atomically, but each one has its own memtable and tables.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColumnFamily {
+    id: u32,
+    name: Arc<str>,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(id: u32, name: &str) -> ColumnFamily {
+        ColumnFamily {
+            id,
+            name: name.into(),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Returns true if `name` can name a column family, which the OPTIONS file stores on a line of
+/// its own
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('\n')
+}
@@ -0,0 +1,1497 @@
+use crate::blob::{BlobFileBuilder, BlobFiles, BlobIndex};
+use crate::db::{self, DbError};
+use crate::iterator::{InternalIterator, MergingIterator};
+use crate::key::{self, SequenceNumber, ValueType};
+use crate::merge;
+use crate::options::{ColumnFamilyOptions, CompactionPriority, CompactionStyle};
+use crate::range_del::{FragmentedRangeTombstones, RangeTombstone};
+use crate::rate_limiter::RateLimiter;
+use crate::table::{self, Table, TableBuilder};
+use crate::timestamp::HistoryTrimmer;
+use crate::ttl;
+use crate::version::{BlobFileMetaData, ColumnFamilyFiles, FileMetaData, VersionEdit, NUM_LEVELS};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bytes read at once from the input tables, which are scanned from start to end
+const COMPACTION_READAHEAD_SIZE: usize = 2 << 20;
+
+/// Fewest level 0 files merged together, see [pick_intra_level0_compaction]
+const MIN_FILES_FOR_INTRA_LEVEL0_COMPACTION: usize = 4;
+
+/// Bytes of the level below the output level an output table may overlap, in target file sizes,
+/// so that compacting it later doesn't rewrite too much of that level
+const MAX_GRANDPARENT_OVERLAP_FACTOR: u64 = 10;
+
+/// An internal key and its value, as written to an output table
+type Entry = (Vec<u8>, Vec<u8>);
+
+/// A set of tables of a column family to merge into a table of `output_level`
+#[derive(Debug)]
+pub(crate) struct Compaction {
+    pub(crate) column_family_id: u32,
+    /// The input files with their level, the ones of `output_level` last
+    pub(crate) inputs: Vec<(usize, Arc<FileMetaData>)>,
+    pub(crate) output_level: usize,
+    /// No level below `output_level` holds keys in the range of the inputs, so the deletions
+    /// don't need to be kept to hide older versions
+    pub(crate) bottommost: bool,
+    /// The inputs are deleted rather than merged, see
+    /// [CompactionStyle::Fifo](crate::options::CompactionStyle::Fifo)
+    pub(crate) delete_inputs: bool,
+    /// The user keys splitting the subcompactions, see [Compaction::split]
+    pub(crate) boundaries: Vec<Vec<u8>>,
+    /// The numbers of the first output table files, one per subcompaction
+    pub(crate) output_numbers: Vec<u64>,
+    /// The files of the level below `output_level` overlapping the inputs, see
+    /// [MAX_GRANDPARENT_OVERLAP_FACTOR]
+    pub(crate) grandparents: Vec<Arc<FileMetaData>>,
+    /// The blob files whose values the outputs point to are moved to a new blob file, see
+    /// [ColumnFamilyOptions::blob_garbage_collection_ratio]
+    pub(crate) rewritten_blob_files: HashSet<u64>,
+}
+
+impl Compaction {
+    /// Builds the compaction of `files` of `level` with the files of `output_level` they
+    /// overlap, unless the files are rewritten in their own level
+    fn new(
+        column_family_id: u32,
+        cf_files: &ColumnFamilyFiles,
+        level: usize,
+        files: Vec<Arc<FileMetaData>>,
+        output_level: usize,
+    ) -> Compaction {
+        let (smallest, largest) = user_key_range(&files);
+        let output_level_files = if output_level == level {
+            Vec::new()
+        } else {
+            overlapping_files(&cf_files.levels[output_level], smallest, largest)
+        };
+
+        let mut inputs: Vec<_> = files.into_iter().map(|file| (level, file)).collect();
+        inputs.extend(
+            output_level_files
+                .into_iter()
+                .map(|file| (output_level, file)),
+        );
+
+        let all_files: Vec<_> = inputs.iter().map(|(_, file)| file.clone()).collect();
+        let (smallest, largest) = user_key_range(&all_files);
+        let bottommost = cf_files.levels[output_level + 1..]
+            .iter()
+            .all(|files| overlapping_files(files, smallest, largest).is_empty());
+        let grandparents = match cf_files.levels.get(output_level + 1) {
+            Some(files) if output_level > 0 => overlapping_files(files, smallest, largest),
+            _ => Vec::new(),
+        };
+
+        Compaction {
+            column_family_id,
+            inputs,
+            output_level,
+            bottommost,
+            delete_inputs: false,
+            boundaries: Vec::new(),
+            output_numbers: Vec::new(),
+            grandparents,
+            rewritten_blob_files: HashSet::new(),
+        }
+    }
+
+    /// Returns the numbers of the input files
+    pub(crate) fn input_numbers(&self) -> Vec<u64> {
+        self.inputs.iter().map(|(_, file)| file.number).collect()
+    }
+
+    /// Splits the compaction into at most `max_subcompactions` subcompactions of disjoint key
+    /// ranges, cut at the smallest keys of the input files
+    ///
+    /// The column families with user timestamps aren't split, since their trimmed history spans
+    /// several user keys, and neither are the compactions into level 0, whose files should be
+    /// few.
+    pub(crate) fn split(&mut self, max_subcompactions: usize, options: &ColumnFamilyOptions) {
+        self.boundaries.clear();
+
+        if self.delete_inputs || self.output_level == 0 || options.user_timestamps {
+            return;
+        }
+
+        let mut keys: Vec<&[u8]> = self
+            .inputs
+            .iter()
+            .map(|(_, file)| key::user_key(&file.smallest_key))
+            .collect();
+        keys.sort();
+        keys.dedup();
+
+        // No subcompaction starts before the first key
+        let candidates = keys.get(1..).unwrap_or_default();
+        let count = max_subcompactions.min(candidates.len() + 1);
+
+        self.boundaries = (1..count)
+            .map(|n| candidates[n * candidates.len() / count].to_vec())
+            .collect();
+    }
+
+    /// Returns the number of subcompactions, each writing at least one table file
+    pub(crate) fn num_subcompactions(&self) -> usize {
+        self.boundaries.len() + 1
+    }
+
+    /// Returns whether some input file is in `compacting`, i.e. already being compacted
+    pub(crate) fn conflicts(&self, compacting: &HashSet<u64>) -> bool {
+        self.inputs
+            .iter()
+            .any(|(_, file)| compacting.contains(&file.number))
+    }
+
+    /// Returns whether the compaction only has to move its input file down to the output level,
+    /// no file there overlapping it
+    ///
+    /// The input keeps its deletions and older versions, which are dropped once it's merged.
+    pub(crate) fn is_trivial_move(&self) -> bool {
+        !self.delete_inputs
+            && matches!(self.inputs.as_slice(), [(level, _)] if *level != self.output_level)
+    }
+
+    /// Returns the edit replacing the inputs with `outputs`
+    pub(crate) fn edit(&self, outputs: Vec<FileMetaData>) -> VersionEdit {
+        let mut edit = VersionEdit::default();
+
+        for (level, file) in &self.inputs {
+            edit.delete_file(self.column_family_id, *level, file.number);
+        }
+
+        for output in outputs {
+            edit.add_file(self.column_family_id, self.output_level, output);
+        }
+
+        edit
+    }
+}
+
+/// Returns the smallest and the largest user keys of `files`
+fn user_key_range(files: &[Arc<FileMetaData>]) -> (&[u8], &[u8]) {
+    let smallest = files
+        .iter()
+        .map(|file| key::user_key(&file.smallest_key))
+        .min()
+        .unwrap_or_default();
+    let largest = files
+        .iter()
+        .map(|file| key::user_key(&file.largest_key))
+        .max()
+        .unwrap_or_default();
+
+    (smallest, largest)
+}
+
+/// Returns the files among `files` holding user keys in [smallest, largest]
+fn overlapping_files(
+    files: &[Arc<FileMetaData>],
+    smallest: &[u8],
+    largest: &[u8],
+) -> Vec<Arc<FileMetaData>> {
+    files
+        .iter()
+        .filter(|file| file.overlaps(smallest, largest))
+        .cloned()
+        .collect()
+}
+
+/// Returns the smallest and largest sequence numbers of the entries of `file`, from the
+/// properties of its table, or from its boundary keys if the table isn't among `tables`
+fn seqno_range(file: &FileMetaData, tables: &[Arc<Table>]) -> (SequenceNumber, SequenceNumber) {
+    match tables.iter().find(|table| table.number() == file.number) {
+        Some(table) => (
+            table.properties().smallest_seqno,
+            table.properties().largest_seqno,
+        ),
+        None => {
+            let seq = |internal_key: &[u8]| key::parse(internal_key).map_or(0, |(_, seq, _)| seq);
+            let (smallest, largest) = (seq(&file.smallest_key), seq(&file.largest_key));
+
+            (smallest.min(largest), smallest.max(largest))
+        }
+    }
+}
+
+/// Returns the number of seconds since the UNIX epoch, as in [TableProperties::creation_time]
+///
+/// [TableProperties::creation_time]: crate::table::TableProperties::creation_time
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Returns the size level `level` (from 1) may grow to before it's compacted
+pub(crate) fn max_bytes_for_level(options: &ColumnFamilyOptions, level: usize) -> u64 {
+    (1..level).fold(options.max_bytes_for_level_base, |size, _| {
+        size.saturating_mul(options.max_bytes_for_level_multiplier)
+    })
+}
+
+/// Returns how urgently each level but the last one needs to be compacted: level 0 by its number
+/// of files, the others by their size, relative to their triggers
+fn level_scores(files: &ColumnFamilyFiles, options: &ColumnFamilyOptions) -> Vec<f64> {
+    let mut scores =
+        vec![files.levels[0].len() as f64 / options.level0_file_num_compaction_trigger as f64];
+
+    for level in 1..NUM_LEVELS - 1 {
+        let size: u64 = files.levels[level].iter().map(|file| file.file_size).sum();
+        scores.push(size as f64 / max_bytes_for_level(options, level) as f64);
+    }
+
+    scores
+}
+
+/// Picks the next compaction of a column family organized in levels, if some level is over its
+/// trigger: the level with the highest score is compacted into the next one, unless the files
+/// it would merge are in `compacting`, the files of the compactions running
+///
+/// Every level 0 file goes into the compaction, since their key ranges overlap. In the other
+/// levels, a single file is picked according to [ColumnFamilyOptions::compaction_priority]. If
+/// level 0 can't be compacted into level 1 while the other compactions run, its newest files
+/// may be merged together instead, see [pick_intra_level0_compaction]. If no level is over its
+/// trigger, the tables with enough deletions, see
+/// [ColumnFamilyOptions::deletion_compaction_ratio], then the ones pointing to blob files with
+/// enough garbage, see [ColumnFamilyOptions::blob_garbage_collection_ratio], then the ones older
+/// than [ColumnFamilyOptions::periodic_compaction_seconds] are compacted, see
+/// [pick_marked_compaction].
+pub(crate) fn pick_level_compaction(
+    column_family_id: u32,
+    files: &ColumnFamilyFiles,
+    tables: &[Arc<Table>],
+    options: &ColumnFamilyOptions,
+    compacting: &HashSet<u64>,
+    oldest_snapshot: SequenceNumber,
+) -> Option<Compaction> {
+    let mut scores: Vec<_> = level_scores(files, options)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, score)| *score >= 1.0)
+        .collect();
+    scores.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let by_score = scores.into_iter().find_map(|(level, _)| {
+        if level == 0 {
+            let compaction =
+                Compaction::new(column_family_id, files, 0, files.levels[0].clone(), 1);
+
+            return match compaction.conflicts(compacting) {
+                true => pick_intra_level0_compaction(column_family_id, files, compacting),
+                false => Some(compaction),
+            };
+        }
+
+        let candidates = files.levels[level]
+            .iter()
+            .map(|file| {
+                Compaction::new(
+                    column_family_id,
+                    files,
+                    level,
+                    vec![file.clone()],
+                    level + 1,
+                )
+            })
+            .filter(|compaction| !compaction.conflicts(compacting));
+        let input = |compaction: &Compaction| compaction.inputs[0].1.clone();
+
+        match options.compaction_priority {
+            CompactionPriority::OldestSmallestSeqFirst => {
+                candidates.min_by_key(|compaction| seqno_range(&input(compaction), tables).0)
+            }
+            CompactionPriority::OldestLargestSeqFirst => {
+                candidates.min_by_key(|compaction| seqno_range(&input(compaction), tables).1)
+            }
+            CompactionPriority::MinOverlappingRatio => candidates.min_by(|a, b| {
+                let ratio = |compaction: &Compaction| {
+                    let overlapping: u64 = compaction.inputs[1..]
+                        .iter()
+                        .map(|(_, file)| file.file_size)
+                        .sum();
+
+                    overlapping as f64 / input(compaction).file_size.max(1) as f64
+                };
+
+                ratio(a).total_cmp(&ratio(b))
+            }),
+        }
+    });
+
+    let last_level = NUM_LEVELS - 1;
+    let now = unix_time();
+
+    by_score
+        .or_else(|| {
+            let ratio = options.deletion_compaction_ratio?;
+
+            // The deletions are dropped from the last level once every snapshot sees them
+            pick_marked_compaction(
+                column_family_id,
+                files,
+                tables,
+                compacting,
+                |level, table| {
+                    full_of_deletions(table, ratio)
+                        && (level < last_level
+                            || table.properties().largest_seqno <= oldest_snapshot)
+                },
+            )
+        })
+        .or_else(|| {
+            let collected = blob_files_to_collect(files, options);
+
+            if collected.is_empty() {
+                return None;
+            }
+
+            pick_marked_compaction(column_family_id, files, tables, compacting, |_, table| {
+                table
+                    .properties()
+                    .blob_references
+                    .keys()
+                    .any(|number| collected.contains(number))
+            })
+        })
+        .or_else(|| {
+            let period = options.periodic_compaction_seconds?;
+
+            pick_marked_compaction(column_family_id, files, tables, compacting, |_, table| {
+                now.saturating_sub(table.properties().creation_time) >= period
+            })
+        })
+}
+
+/// Returns whether a share of at least `ratio` of the entries of `table` are deletions, range
+/// tombstones included
+fn full_of_deletions(table: &Table, ratio: f64) -> bool {
+    let properties = table.properties();
+    let deletions = properties.num_deletions + properties.num_range_deletions;
+    let entries = properties.num_entries + properties.num_range_deletions;
+
+    deletions > 0 && deletions as f64 >= ratio * entries as f64
+}
+
+/// Returns the blob files of a column family whose share of garbage reached
+/// [ColumnFamilyOptions::blob_garbage_collection_ratio], if set
+pub(crate) fn blob_files_to_collect(
+    files: &ColumnFamilyFiles,
+    options: &ColumnFamilyOptions,
+) -> HashSet<u64> {
+    let Some(ratio) = options.blob_garbage_collection_ratio else {
+        return HashSet::new();
+    };
+
+    files
+        .blob_files
+        .values()
+        .filter(|file| file.garbage_ratio() >= ratio)
+        .map(|file| file.number)
+        .collect()
+}
+
+/// Returns the values of the blob files which become garbage when the tables `removed` of the
+/// column family `column_family_id` are replaced with `added`, as
+/// [VersionEdit::blob_file_garbage]: the ones the former point to and the latter don't
+pub(crate) fn blob_garbage(
+    column_family_id: u32,
+    removed: &[Arc<Table>],
+    added: &[Arc<Table>],
+) -> Vec<(u32, u64, u64, u64)> {
+    let references = |tables: &[Arc<Table>]| {
+        let mut references: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+
+        for table in tables {
+            for (number, (count, bytes)) in &table.properties().blob_references {
+                let total = references.entry(*number).or_default();
+                total.0 += count;
+                total.1 += bytes;
+            }
+        }
+
+        references
+    };
+    let added = references(added);
+
+    references(removed)
+        .into_iter()
+        .filter_map(|(number, (count, bytes))| {
+            let (added_count, added_bytes) = added.get(&number).copied().unwrap_or_default();
+            let count = count.saturating_sub(added_count);
+
+            (count > 0).then(|| {
+                (
+                    column_family_id,
+                    number,
+                    count,
+                    bytes.saturating_sub(added_bytes),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Picks the compaction of the first table `marked` for compaction by its level, from the top
+/// level down, whatever the sizes of the levels
+///
+/// The table is compacted into the next level, along with the whole level 0 if it's there, and
+/// rewritten in place in the last level.
+pub(crate) fn pick_marked_compaction<F>(
+    column_family_id: u32,
+    files: &ColumnFamilyFiles,
+    tables: &[Arc<Table>],
+    compacting: &HashSet<u64>,
+    marked: F,
+) -> Option<Compaction>
+where
+    F: Fn(usize, &Table) -> bool,
+{
+    let last_level = NUM_LEVELS - 1;
+
+    (0..NUM_LEVELS)
+        .flat_map(|level| files.levels[level].iter().map(move |file| (level, file)))
+        .filter(|(level, file)| {
+            tables
+                .iter()
+                .find(|table| table.number() == file.number)
+                .is_some_and(|table| marked(*level, table))
+        })
+        .map(|(level, file)| {
+            let (inputs, output_level) = match level {
+                0 => (files.levels[0].clone(), 1),
+                _ => (vec![file.clone()], (level + 1).min(last_level)),
+            };
+
+            Compaction::new(column_family_id, files, level, inputs, output_level)
+        })
+        .find(|compaction| !compaction.conflicts(compacting))
+}
+
+/// Picks a compaction merging the newest level 0 files together, when a running compaction
+/// keeps level 0 from being compacted into level 1: the reads then have fewer files to look at
+/// until it can be
+///
+/// Only the files newer than every level 0 file being compacted are merged, so that the output
+/// keeps the place of its inputs in the order of level 0.
+fn pick_intra_level0_compaction(
+    column_family_id: u32,
+    files: &ColumnFamilyFiles,
+    compacting: &HashSet<u64>,
+) -> Option<Compaction> {
+    // Level 0 is sorted from the newest to the oldest file
+    let inputs: Vec<_> = files.levels[0]
+        .iter()
+        .take_while(|file| !compacting.contains(&file.number))
+        .cloned()
+        .collect();
+
+    (inputs.len() >= MIN_FILES_FOR_INTRA_LEVEL0_COMPACTION)
+        .then(|| Compaction::new(column_family_id, files, 0, inputs, 0))
+}
+
+/// A level 0 file, or a whole level below it, i.e. a set of files whose key ranges don't overlap
+#[derive(Debug)]
+struct SortedRun {
+    level: usize,
+    files: Vec<Arc<FileMetaData>>,
+    size: u64,
+}
+
+/// Returns the sorted runs of a column family from the newest to the oldest
+fn sorted_runs(files: &ColumnFamilyFiles) -> Vec<SortedRun> {
+    let level0_runs = files.levels[0].iter().map(|file| SortedRun {
+        level: 0,
+        files: vec![file.clone()],
+        size: file.file_size,
+    });
+    let level_runs = files
+        .levels
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, files)| !files.is_empty())
+        .map(|(level, files)| SortedRun {
+            level,
+            files: files.clone(),
+            size: files.iter().map(|file| file.file_size).sum(),
+        });
+
+    level0_runs.chain(level_runs).collect()
+}
+
+/// Picks the next compaction of a column family compacted in the universal style, once it has
+/// at least [ColumnFamilyOptions::level0_file_num_compaction_trigger] sorted runs
+///
+/// The newest runs are merged as long as the next one isn't much larger than them together,
+/// see [ColumnFamilyOptions::universal_size_ratio], so that every byte is rewritten only when
+/// the data it's merged with grew comparably. If that merges too few runs, the newest ones are
+/// merged anyway to bring their number back under the trigger.
+///
+/// The output goes to the level of the oldest run merged, or right above the next older run, so
+/// that the runs stay ordered by age from level 0 down. Nothing is picked while a compaction of
+/// some file in `compacting` runs, since the runs wouldn't be ordered anymore.
+pub(crate) fn pick_universal_compaction(
+    column_family_id: u32,
+    files: &ColumnFamilyFiles,
+    options: &ColumnFamilyOptions,
+    compacting: &HashSet<u64>,
+) -> Option<Compaction> {
+    if files.files().any(|file| compacting.contains(&file.number)) {
+        return None;
+    }
+
+    let runs = sorted_runs(files);
+
+    if runs.len() < options.level0_file_num_compaction_trigger.max(2) {
+        return None;
+    }
+
+    let max_width = options.universal_max_merge_width.min(runs.len());
+    let mut width = 1;
+    let mut merged_size = runs[0].size;
+
+    while width < max_width {
+        let limit = merged_size.saturating_mul(100 + options.universal_size_ratio) / 100;
+
+        if runs[width].size > limit {
+            break;
+        }
+
+        merged_size += runs[width].size;
+        width += 1;
+    }
+
+    if width < options.universal_min_merge_width {
+        let excess_runs = runs.len() + 1 - options.level0_file_num_compaction_trigger.max(2);
+        width = (excess_runs + 1).clamp(2, max_width.max(2));
+    }
+
+    let oldest = &runs[width - 1];
+    let output_level = match runs.get(width) {
+        _ if oldest.level > 0 => oldest.level,
+        Some(next) => next.level.saturating_sub(1),
+        None => NUM_LEVELS - 1,
+    };
+
+    Some(Compaction {
+        column_family_id,
+        inputs: runs[..width]
+            .iter()
+            .flat_map(|run| run.files.iter().map(|file| (run.level, file.clone())))
+            .collect(),
+        output_level,
+        bottommost: width == runs.len(),
+        delete_inputs: false,
+        boundaries: Vec::new(),
+        output_numbers: Vec::new(),
+        grandparents: Vec::new(),
+        rewritten_blob_files: HashSet::new(),
+    })
+}
+
+/// Picks the tables of a column family compacted in the FIFO style to delete: the ones older
+/// than [ColumnFamilyOptions::fifo_ttl], then the oldest ones until the others fit in
+/// [ColumnFamilyOptions::fifo_max_table_files_size]
+///
+/// Every table stays in level 0, where the flushes put them.
+pub(crate) fn pick_fifo_compaction(
+    column_family_id: u32,
+    files: &ColumnFamilyFiles,
+    tables: &[Arc<Table>],
+    options: &ColumnFamilyOptions,
+) -> Option<Compaction> {
+    let now = unix_time();
+    let expired = |file: &FileMetaData| {
+        let creation_time = tables
+            .iter()
+            .find(|table| table.number() == file.number)
+            .map_or(now, |table| table.properties().creation_time);
+
+        options
+            .fifo_ttl
+            .is_some_and(|ttl| now.saturating_sub(creation_time) >= ttl.as_secs())
+    };
+
+    let mut size: u64 = files.levels[0].iter().map(|file| file.file_size).sum();
+    let mut inputs = Vec::new();
+
+    // Level 0 is sorted from the newest to the oldest table
+    for file in files.levels[0].iter().rev() {
+        if size <= options.fifo_max_table_files_size && !expired(file) {
+            break;
+        }
+
+        size -= file.file_size;
+        inputs.push((0, file.clone()));
+    }
+
+    (!inputs.is_empty()).then_some(Compaction {
+        column_family_id,
+        inputs,
+        output_level: 0,
+        bottommost: false,
+        delete_inputs: true,
+        boundaries: Vec::new(),
+        output_numbers: Vec::new(),
+        grandparents: Vec::new(),
+        rewritten_blob_files: HashSet::new(),
+    })
+}
+
+/// Picks the next step of a manual compaction of the user keys in [start, end] down to the
+/// bottom level, compacting the files of `level` in the range, see
+/// [Db::compact_range](crate::Db::compact_range)
+///
+/// The files go down to the next level holding keys in their range, or to the bottom level if
+/// none does, so that a range is compacted once per level holding it. Column families compacted
+/// in the universal style compact all their files at once, whatever the range, since their
+/// sorted runs overlap; the ones compacted in the FIFO style never merge tables.
+pub(crate) fn pick_range_compaction(
+    column_family_id: u32,
+    files: &ColumnFamilyFiles,
+    options: &ColumnFamilyOptions,
+    level: usize,
+    start: Option<&[u8]>,
+    end: Option<&[u8]>,
+    bottommost_level_compaction: bool,
+) -> Option<Compaction> {
+    let last_level = NUM_LEVELS - 1;
+
+    match options.compaction_style {
+        CompactionStyle::Level => {}
+        CompactionStyle::Universal => {
+            let runs = sorted_runs(files);
+            let rewrite_last_run =
+                bottommost_level_compaction || runs.iter().any(|run| run.level != last_level);
+
+            if level > 0 || runs.is_empty() || !rewrite_last_run {
+                return None;
+            }
+
+            return Some(Compaction {
+                column_family_id,
+                inputs: runs
+                    .iter()
+                    .flat_map(|run| run.files.iter().map(|file| (run.level, file.clone())))
+                    .collect(),
+                output_level: last_level,
+                bottommost: true,
+                delete_inputs: false,
+                boundaries: Vec::new(),
+                output_numbers: Vec::new(),
+                grandparents: Vec::new(),
+                rewritten_blob_files: HashSet::new(),
+            });
+        }
+        CompactionStyle::Fifo => return None,
+    }
+
+    let in_range: Vec<_> = files.levels[level]
+        .iter()
+        .filter(|file| {
+            start.is_none_or(|start| !file.ends_before(start))
+                && end.is_none_or(|end| key::user_key(&file.smallest_key) <= end)
+        })
+        .cloned()
+        .collect();
+
+    if in_range.is_empty() || (level == last_level && !bottommost_level_compaction) {
+        return None;
+    }
+
+    // The level 0 files overlap each other, the older ones can't stay above the newer ones
+    let inputs = if level == 0 {
+        files.levels[0].clone()
+    } else {
+        in_range
+    };
+
+    let (smallest, largest) = user_key_range(&inputs);
+    let output_level = (level + 1..last_level)
+        .find(|&output_level| {
+            !overlapping_files(&files.levels[output_level], smallest, largest).is_empty()
+        })
+        .unwrap_or(last_level);
+
+    Some(Compaction::new(
+        column_family_id,
+        files,
+        level,
+        inputs,
+        output_level,
+    ))
+}
+
+/// Returns the size of the tables written by the compactions into `level`, see
+/// [ColumnFamilyOptions::target_file_size_base]
+fn target_file_size(options: &ColumnFamilyOptions, level: usize) -> u64 {
+    let exponent = level.saturating_sub(1) as u32;
+
+    options
+        .target_file_size_base
+        .saturating_mul(options.target_file_size_multiplier.saturating_pow(exponent))
+}
+
+/// How [run] gets the table files it writes
+pub(crate) struct OutputFiles<'a> {
+    /// Allocates the number of an output table file after the first one of a subcompaction, see
+    /// [Compaction::output_numbers]
+    pub(crate) new_file_number: &'a (dyn Fn() -> u64 + Sync),
+    pub(crate) rate_limiter: Option<&'a Arc<RateLimiter>>,
+    /// Where the values moved out of [Compaction::rewritten_blob_files] are read from
+    pub(crate) blob_files: &'a BlobFiles,
+}
+
+/// Merges the input tables of `compaction` into new table files, and returns the ones which
+/// weren't left empty along with the blob files the values of [Compaction::rewritten_blob_files]
+/// were moved to, one per subcompaction at most
+///
+/// The subcompactions, see [Compaction::split], run on their own threads and write the table
+/// files [Compaction::output_numbers], waiting for the rate limiter of `output_files` if any.
+/// With [CompactionStyle::Level], a subcompaction moves on to a new table file once the current
+/// one reaches the target size of the output level, or earlier at the end of a file of the level
+/// below, so that compacting the output later merges fewer files.
+///
+/// The versions of a key hidden from every snapshot by a newer one are dropped, and so are the
+/// keys deleted by a range tombstone every snapshot sees. The merge operands every snapshot sees
+/// are combined with the value they apply to, see [Subcompaction::merge_operands]. In the
+/// bottommost level, the deletions and range tombstones every snapshot sees are dropped as well,
+/// and so are the expired entries.
+pub(crate) fn run(
+    dir: &Path,
+    options: &ColumnFamilyOptions,
+    compaction: &Compaction,
+    tables: &[Arc<Table>],
+    oldest_snapshot: SequenceNumber,
+    history: Option<HistoryTrimmer>,
+    output_files: &OutputFiles,
+) -> Result<(Vec<Arc<Table>>, Vec<BlobFileMetaData>), DbError> {
+    let range_tombstones: Vec<RangeTombstone> = tables
+        .iter()
+        .flat_map(|table| table.range_tombstones())
+        .cloned()
+        .collect();
+    let fragmented_tombstones = FragmentedRangeTombstones::new(&range_tombstones);
+    let boundaries = &compaction.boundaries;
+    let numbers = &compaction.output_numbers;
+
+    // Level 0 keeps its files ordered by number, which the outputs allocated later would break
+    let target_file_size = match options.compaction_style {
+        CompactionStyle::Level if compaction.output_level > 0 && !options.user_timestamps => {
+            target_file_size(options, compaction.output_level)
+        }
+        _ => u64::MAX,
+    };
+
+    let subcompaction = |n: usize| Subcompaction {
+        start: n.checked_sub(1).map(|n| boundaries[n].as_slice()),
+        end: boundaries.get(n).map(Vec::as_slice),
+        number: numbers[n],
+        range_tombstones: &range_tombstones,
+        fragmented_tombstones: &fragmented_tombstones,
+        oldest_snapshot,
+        history: history.clone(),
+        output_files,
+        target_file_size,
+        grandparent_index: 0,
+        overlapped_bytes: 0,
+        blob_output: None,
+    };
+
+    let outputs = if numbers.len() == 1 {
+        vec![subcompaction(0).run(dir, options, compaction, tables)]
+    } else {
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..numbers.len())
+                .map(|n| {
+                    let subcompaction = subcompaction(n);
+                    scope.spawn(move || subcompaction.run(dir, options, compaction, tables))
+                })
+                .collect();
+
+            threads
+                .into_iter()
+                .map(|thread| thread.join().expect("subcompaction panicked"))
+                .collect()
+        })
+    };
+
+    let outputs: Vec<_> = outputs.into_iter().collect::<Result<_, _>>()?;
+    let (tables, blob_files): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+
+    Ok((
+        tables.into_iter().flatten().collect(),
+        blob_files.into_iter().flatten().collect(),
+    ))
+}
+
+/// The part of a compaction merging the user keys in [start, end), None standing for no bound
+struct Subcompaction<'a> {
+    start: Option<&'a [u8]>,
+    end: Option<&'a [u8]>,
+    /// Number of the first output table file
+    number: u64,
+    range_tombstones: &'a [RangeTombstone],
+    fragmented_tombstones: &'a FragmentedRangeTombstones,
+    oldest_snapshot: SequenceNumber,
+    history: Option<HistoryTrimmer>,
+    output_files: &'a OutputFiles<'a>,
+    target_file_size: u64,
+    /// Index of the first file of [Compaction::grandparents] not entirely before the keys written
+    grandparent_index: usize,
+    /// Bytes of the grandparents the current output table overlaps
+    overlapped_bytes: u64,
+    /// The blob file the values of [Compaction::rewritten_blob_files] are moved to, created with
+    /// the first one
+    blob_output: Option<BlobFileBuilder>,
+}
+
+impl Subcompaction<'_> {
+    /// Writes the output table files, returning the ones which weren't left empty along with
+    /// the blob file the values were moved to, if any
+    fn run(
+        mut self,
+        dir: &Path,
+        options: &ColumnFamilyOptions,
+        compaction: &Compaction,
+        tables: &[Arc<Table>],
+    ) -> Result<(Vec<Arc<Table>>, Option<BlobFileMetaData>), DbError> {
+        let oldest_snapshot = self.oldest_snapshot;
+        let children = tables
+            .iter()
+            .map(|table| {
+                Box::new(
+                    table
+                        .iter()
+                        .with_readahead_size(COMPACTION_READAHEAD_SIZE)
+                        .with_fill_cache(false)
+                        .with_rate_limiter(self.output_files.rate_limiter),
+                ) as Box<dyn InternalIterator + Send>
+            })
+            .collect();
+        let mut iter = MergingIterator::new(children);
+
+        match self.start {
+            Some(start) => iter.seek(&key::seek_key(start, key::MAX_SEQUENCE_NUMBER))?,
+            None => iter.seek_to_first()?,
+        }
+
+        let mut current_user_key: Option<Vec<u8>> = None;
+        // Whether the older versions of the current key are hidden from every snapshot
+        let mut hidden = false;
+        // The first entries of the next output table, read while writing the current one
+        let mut next_entries: Vec<Entry> = Vec::new();
+        let mut output_start = self.start.map(<[u8]>::to_vec);
+        let mut number = self.number;
+        let mut outputs = Vec::new();
+
+        loop {
+            let mut output_end = None;
+
+            let output = db::write_table(
+                dir,
+                options,
+                compaction.output_level,
+                options.output_compression(compaction.output_level, compaction.bottommost),
+                self.output_files.rate_limiter,
+                number,
+                compaction.column_family_id,
+                |builder| {
+                    // The user key of the last entry written
+                    let mut last_user_key = None;
+
+                    for (key, value) in next_entries.drain(..) {
+                        last_user_key = key::parse(&key).map(|(user_key, _, _)| user_key.to_vec());
+                        builder.add(&key, &value)?;
+                    }
+
+                    while iter.valid() {
+                        let (user_key, seq, value_type) = key::parse(iter.key())
+                            .ok_or(DbError::Corruption("bad internal key"))?;
+
+                        if self.end.is_some_and(|end| user_key >= end) {
+                            break;
+                        }
+
+                        if current_user_key.as_deref() != Some(user_key) {
+                            current_user_key = Some(user_key.to_vec());
+                            hidden = false;
+                        }
+
+                        let kept_by_history = self
+                            .history
+                            .as_mut()
+                            .is_none_or(|history| history.keep(iter.key()));
+                        let (value_type, _) = ttl::resolve(value_type, iter.value());
+                        let deleted_by_range = self
+                            .fragmented_tombstones
+                            .max_covering_seq(user_key, oldest_snapshot)
+                            > seq;
+
+                        let drop = hidden
+                            || !kept_by_history
+                            || deleted_by_range
+                            || (compaction.bottommost
+                                && value_type == ValueType::Deletion
+                                && seq <= oldest_snapshot);
+
+                        if seq <= oldest_snapshot && value_type != ValueType::Merge {
+                            hidden = true;
+                        }
+
+                        if drop {
+                            iter.next()?;
+                            continue;
+                        }
+
+                        let user_key = user_key.to_vec();
+                        let merged = value_type == ValueType::Merge
+                            && seq <= oldest_snapshot
+                            && self.history.is_none()
+                            && options.merge_operator.is_some();
+
+                        let entries = if merged {
+                            self.merge_operands(options, compaction, &mut iter, &mut hidden)?
+                        } else {
+                            let entry = match ttl::expired_tombstone(iter.key(), iter.value()) {
+                                Some(tombstone) => (tombstone, Vec::new()),
+                                None if value_type == ValueType::BlobIndex => (
+                                    iter.key().to_vec(),
+                                    self.relocate_blob(dir, compaction, &user_key, iter.value())?,
+                                ),
+                                None => (iter.key().to_vec(), iter.value().to_vec()),
+                            };
+                            iter.next()?;
+
+                            vec![entry]
+                        };
+
+                        // The versions of a user key stay in the same table
+                        if last_user_key.as_deref() != Some(user_key.as_slice()) {
+                            if self.should_stop_before(compaction, &user_key, builder) {
+                                output_end = Some(user_key);
+                                next_entries = entries;
+                                break;
+                            }
+
+                            last_user_key = Some(user_key);
+                        }
+
+                        for (key, value) in entries {
+                            builder.add(&key, &value)?;
+                        }
+                    }
+
+                    let output_end = output_end.as_deref().or(self.end);
+
+                    for tombstone in self.range_tombstones {
+                        if compaction.bottommost && tombstone.seq <= oldest_snapshot {
+                            continue;
+                        }
+
+                        // Every output keeps its part of the tombstones, which ends where the
+                        // next output starts, so that their ranges don't overlap
+                        let start = match &output_start {
+                            Some(start) => tombstone.start.as_slice().max(start),
+                            None => &tombstone.start,
+                        };
+                        let end = match output_end {
+                            Some(end) => tombstone.end.as_slice().min(end),
+                            None => &tombstone.end,
+                        };
+
+                        if start < end {
+                            builder.add_range_tombstone(start, end, tombstone.seq);
+                        }
+                    }
+
+                    Ok(())
+                },
+            )?;
+
+            let properties = output.properties();
+
+            if properties.num_entries == 0 && properties.num_range_deletions == 0 {
+                std::fs::remove_file(table::table_file_name(dir, number))?;
+            } else {
+                outputs.push(output);
+            }
+
+            match output_end {
+                Some(end) => output_start = Some(end),
+                None => {
+                    let blob_file = match self.blob_output.take() {
+                        Some(builder) => Some(builder.finish()?),
+                        None => None,
+                    };
+
+                    return Ok((outputs, blob_file));
+                }
+            }
+
+            number = (self.output_files.new_file_number)();
+        }
+    }
+
+    /// Combines the merge operands every snapshot sees, from the one `iter` is positioned at to
+    /// the value they apply to, and returns the entries to write instead, moving past the
+    /// operands
+    ///
+    /// Once a value or a deletion of the key is reached, or the oldest operand is in the
+    /// bottommost level, the operands are replaced with their result, a value with the sequence
+    /// number of the newest one, which hides the older versions. Otherwise they're written as
+    /// they are.
+    fn merge_operands(
+        &mut self,
+        options: &ColumnFamilyOptions,
+        compaction: &Compaction,
+        iter: &mut MergingIterator,
+        hidden: &mut bool,
+    ) -> Result<Vec<Entry>, DbError> {
+        let (user_key, seq, _) =
+            key::parse(iter.key()).ok_or(DbError::Corruption("bad internal key"))?;
+        let user_key = user_key.to_vec();
+        let merged_key = key::encode(&user_key, seq, ValueType::Value);
+
+        let mut entries = vec![(iter.key().to_vec(), iter.value().to_vec())];
+        // The value the operands apply to, None for a deletion, once reached
+        let mut existing_value = None;
+        let mut end_of_key = true;
+
+        iter.next()?;
+
+        while iter.valid() {
+            let (next_user_key, seq, value_type) =
+                key::parse(iter.key()).ok_or(DbError::Corruption("bad internal key"))?;
+
+            if next_user_key != user_key.as_slice() {
+                break;
+            }
+
+            let deleted_by_range = self
+                .fragmented_tombstones
+                .max_covering_seq(&user_key, self.oldest_snapshot)
+                > seq;
+
+            match ttl::resolve(value_type, iter.value()) {
+                _ if deleted_by_range => existing_value = Some(None),
+                (ValueType::Merge, _) => {
+                    entries.push((iter.key().to_vec(), iter.value().to_vec()));
+                    iter.next()?;
+
+                    continue;
+                }
+                (ValueType::Deletion, _) => existing_value = Some(None),
+                // The value may expire before the result, which couldn't follow
+                (ValueType::Value, _) if value_type == ValueType::ValueWithExpiry => {
+                    end_of_key = false;
+                }
+                (ValueType::Value, value) => existing_value = Some(Some(value.to_vec())),
+                (ValueType::BlobIndex, index) => {
+                    let index = BlobIndex::decode(index)?;
+                    existing_value = Some(Some(self.output_files.blob_files.get(&index)?));
+                }
+                _ => end_of_key = false,
+            }
+
+            break;
+        }
+
+        let existing_value = match existing_value {
+            Some(value) => value,
+            None if end_of_key && compaction.bottommost => None,
+            None => return Ok(entries),
+        };
+
+        let operands: Vec<_> = entries.into_iter().map(|(_, operand)| operand).collect();
+        let value = merge::full_merge(
+            options.merge_operator.as_deref(),
+            &user_key,
+            existing_value.as_deref(),
+            &operands,
+        )?;
+
+        // The value or the deletion reached is dropped along with the older versions
+        *hidden = true;
+
+        Ok(vec![(merged_key, value)])
+    }
+
+    /// Returns the blob index to write for the value of `user_key` pointed to by `blob_index`,
+    /// after moving the value to the blob file of the subcompaction if its file is rewritten
+    fn relocate_blob(
+        &mut self,
+        dir: &Path,
+        compaction: &Compaction,
+        user_key: &[u8],
+        blob_index: &[u8],
+    ) -> Result<Vec<u8>, DbError> {
+        let index = BlobIndex::decode(blob_index)?;
+
+        if !compaction.rewritten_blob_files.contains(&index.file_number) {
+            return Ok(blob_index.to_vec());
+        }
+
+        let value = self.output_files.blob_files.get(&index)?;
+        let builder = match &mut self.blob_output {
+            Some(builder) => builder,
+            None => self.blob_output.insert(BlobFileBuilder::new(
+                dir,
+                (self.output_files.new_file_number)(),
+            )?),
+        };
+
+        Ok(builder.add(user_key, &value)?.encode())
+    }
+
+    /// Returns whether the output table written by `builder` should end before `user_key`, the
+    /// user key of the next entry written
+    fn should_stop_before(
+        &mut self,
+        compaction: &Compaction,
+        user_key: &[u8],
+        builder: &TableBuilder,
+    ) -> bool {
+        let has_entries = builder.num_entries() > 0;
+        let mut crossed_grandparent = false;
+
+        while let Some(file) = compaction.grandparents.get(self.grandparent_index) {
+            if !file.ends_before(user_key) {
+                break;
+            }
+
+            if has_entries {
+                self.overlapped_bytes += file.file_size;
+                crossed_grandparent = true;
+            }
+
+            self.grandparent_index += 1;
+        }
+
+        let size = builder.file_size();
+        let stop = has_entries
+            && (size >= self.target_file_size
+                || self.overlapped_bytes
+                    > MAX_GRANDPARENT_OVERLAP_FACTOR.saturating_mul(self.target_file_size)
+                || (crossed_grandparent && size >= self.target_file_size / 2));
+
+        if stop {
+            self.overlapped_bytes = 0;
+        }
+
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compaction::{
+        max_bytes_for_level, pick_fifo_compaction, pick_level_compaction, pick_range_compaction,
+        pick_universal_compaction,
+    };
+    use crate::key::{self, ValueType};
+    use crate::options::{ColumnFamilyOptions, CompactionPriority, CompactionStyle};
+    use crate::version::{ColumnFamilyFiles, FileMetaData, NUM_LEVELS};
+    use std::collections::{BTreeMap, HashSet};
+    use std::sync::Arc;
+
+    fn file(number: u64, smallest: &[u8], largest: &[u8], file_size: u64) -> Arc<FileMetaData> {
+        Arc::new(FileMetaData {
+            number,
+            file_size,
+            smallest_key: key::encode(smallest, number, ValueType::Value),
+            largest_key: key::encode(largest, number, ValueType::Value),
+        })
+    }
+
+    fn cf_files(levels: Vec<Vec<Arc<FileMetaData>>>) -> ColumnFamilyFiles {
+        let mut levels = levels;
+        levels.resize(NUM_LEVELS, Vec::new());
+
+        ColumnFamilyFiles {
+            name: "default".to_string(),
+            levels,
+            full_history_ts_low: 0,
+            log_number: 0,
+            blob_files: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn levels_over_their_trigger_are_compacted_into_the_next_one() {
+        let options = ColumnFamilyOptions::default()
+            .with_level0_file_num_compaction_trigger(2)
+            .with_max_bytes_for_level(100, 10);
+
+        assert_eq!(max_bytes_for_level(&options, 3), 10_000);
+
+        let files = cf_files(vec![
+            vec![file(5, b"c", b"d", 10)],
+            vec![file(1, b"a", b"b", 50), file(2, b"c", b"e", 40)],
+            vec![file(3, b"a", b"z", 999)],
+        ]);
+        assert!(pick_level_compaction(0, &files, &[], &options, &HashSet::new(), 0).is_none());
+
+        let files = cf_files(vec![
+            vec![file(6, b"d", b"f", 10), file(5, b"c", b"d", 10)],
+            vec![
+                file(1, b"a", b"b", 50),
+                file(2, b"c", b"e", 40),
+                file(4, b"f", b"g", 5),
+            ],
+            vec![file(3, b"a", b"z", 999)],
+        ]);
+        let compaction =
+            pick_level_compaction(0, &files, &[], &options, &HashSet::new(), 0).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![6, 5, 2, 4]);
+        assert_eq!(compaction.output_level, 1);
+        assert!(!compaction.bottommost);
+
+        let files = cf_files(vec![
+            vec![],
+            vec![file(2, b"c", b"e", 100), file(1, b"f", b"g", 100)],
+            vec![file(3, b"a", b"d", 400), file(4, b"e", b"z", 400)],
+        ]);
+        let compaction =
+            pick_level_compaction(0, &files, &[], &options, &HashSet::new(), 0).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![1, 4]);
+        assert_eq!(compaction.output_level, 2);
+        assert!(compaction.bottommost);
+    }
+
+    #[test]
+    fn compaction_priorities_pick_different_files() {
+        let with_seqnos = |number, smallest: &[u8], largest: &[u8], seqnos: (u64, u64)| {
+            Arc::new(FileMetaData {
+                number,
+                file_size: 100,
+                smallest_key: key::encode(smallest, seqnos.0, ValueType::Value),
+                largest_key: key::encode(largest, seqnos.1, ValueType::Value),
+            })
+        };
+        let files = cf_files(vec![
+            vec![],
+            vec![
+                with_seqnos(1, b"a", b"b", (5, 50)),
+                with_seqnos(2, b"c", b"d", (10, 20)),
+                with_seqnos(3, b"e", b"f", (30, 40)),
+            ],
+            vec![file(4, b"a", b"b", 1000), file(5, b"c", b"d", 500)],
+        ]);
+        let options = ColumnFamilyOptions::default().with_max_bytes_for_level(100, 10);
+
+        for (priority, inputs) in [
+            (CompactionPriority::OldestSmallestSeqFirst, vec![1, 4]),
+            (CompactionPriority::OldestLargestSeqFirst, vec![2, 5]),
+            (CompactionPriority::MinOverlappingRatio, vec![3]),
+        ] {
+            let options = options.clone().with_compaction_priority(priority);
+            let compaction =
+                pick_level_compaction(0, &files, &[], &options, &HashSet::new(), 0).unwrap();
+            assert_eq!(compaction.input_numbers(), inputs, "{priority:?}");
+        }
+    }
+
+    #[test]
+    fn files_being_compacted_are_left_alone() {
+        let options = ColumnFamilyOptions::default()
+            .with_level0_file_num_compaction_trigger(2)
+            .with_max_bytes_for_level(100, 10);
+        let files = cf_files(vec![
+            vec![
+                file(9, b"a", b"c", 10),
+                file(8, b"b", b"d", 10),
+                file(7, b"a", b"b", 10),
+                file(6, b"c", b"e", 10),
+            ],
+            vec![file(1, b"a", b"b", 60), file(2, b"c", b"e", 60)],
+            vec![file(3, b"a", b"b", 100)],
+        ]);
+
+        // Level 1 is over its target too, but level 0 is more urgent
+        let compaction =
+            pick_level_compaction(0, &files, &[], &options, &HashSet::new(), 0).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![9, 8, 7, 6, 1, 2]);
+
+        // With level 1 busy, the level 0 files are merged together
+        let compacting = HashSet::from([2, 3]);
+        let compaction = pick_level_compaction(0, &files, &[], &options, &compacting, 0).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![9, 8, 7, 6]);
+        assert_eq!(compaction.output_level, 0);
+
+        // ...as long as there are enough of them newer than the ones being compacted
+        let compacting = HashSet::from([7]);
+        let compaction = pick_level_compaction(0, &files, &[], &options, &compacting, 0).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![2]);
+        assert_eq!(compaction.output_level, 2);
+
+        let compacting = HashSet::from([6, 1, 2]);
+        assert!(pick_level_compaction(0, &files, &[], &options, &compacting, 0).is_none());
+        assert!(pick_universal_compaction(0, &files, &options, &HashSet::from([9])).is_none());
+    }
+
+    #[test]
+    fn universal_compactions_merge_similarly_sized_runs() {
+        let options = ColumnFamilyOptions::default().with_level0_file_num_compaction_trigger(4);
+
+        let files = cf_files(vec![vec![
+            file(3, b"a", b"z", 10),
+            file(2, b"a", b"z", 10),
+            file(1, b"a", b"z", 10),
+        ]]);
+        assert!(pick_universal_compaction(0, &files, &options, &HashSet::new()).is_none());
+
+        let files = cf_files(vec![vec![
+            file(4, b"a", b"z", 10),
+            file(3, b"a", b"z", 10),
+            file(2, b"a", b"z", 10),
+            file(1, b"a", b"z", 10),
+        ]]);
+        let compaction = pick_universal_compaction(0, &files, &options, &HashSet::new()).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![4, 3, 2, 1]);
+        assert_eq!(compaction.output_level, NUM_LEVELS - 1);
+        assert!(compaction.bottommost);
+
+        // The old run is too large to be merged with the newer ones
+        let mut levels = vec![vec![
+            file(7, b"a", b"z", 10),
+            file(6, b"a", b"z", 10),
+            file(5, b"a", b"z", 15),
+        ]];
+        levels.resize(NUM_LEVELS - 1, Vec::new());
+        levels.push(vec![file(1, b"a", b"m", 500), file(2, b"n", b"z", 500)]);
+        let files = cf_files(levels);
+        let compaction = pick_universal_compaction(0, &files, &options, &HashSet::new()).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![7, 6, 5]);
+        assert_eq!(compaction.output_level, NUM_LEVELS - 2);
+        assert!(!compaction.bottommost);
+
+        // Too few runs merge by size ratio, the newest ones are merged to cut their number
+        let mut levels = vec![vec![
+            file(9, b"a", b"z", 1),
+            file(8, b"a", b"z", 10),
+            file(7, b"a", b"z", 100),
+            file(6, b"a", b"z", 1000),
+        ]];
+        levels.resize(NUM_LEVELS, Vec::new());
+        levels[3] = vec![file(5, b"a", b"z", 10_000)];
+        let files = cf_files(levels);
+        let compaction = pick_universal_compaction(0, &files, &options, &HashSet::new()).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![9, 8, 7]);
+        assert_eq!(compaction.output_level, 0);
+    }
+
+    #[test]
+    fn fifo_compactions_delete_the_oldest_files_over_the_cap() {
+        let options = ColumnFamilyOptions::default().with_fifo_max_table_files_size(100);
+
+        let files = cf_files(vec![vec![
+            file(3, b"a", b"z", 40),
+            file(2, b"a", b"z", 40),
+            file(1, b"a", b"z", 20),
+        ]]);
+        assert!(pick_fifo_compaction(0, &files, &[], &options).is_none());
+
+        let files = cf_files(vec![vec![
+            file(4, b"a", b"z", 40),
+            file(3, b"a", b"z", 40),
+            file(2, b"a", b"z", 40),
+            file(1, b"a", b"z", 20),
+        ]]);
+        let compaction = pick_fifo_compaction(0, &files, &[], &options).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![1, 2]);
+        assert!(compaction.delete_inputs);
+        assert!(compaction.edit(Vec::new()).new_files.is_empty());
+    }
+
+    #[test]
+    fn range_compactions_go_down_to_the_next_level_holding_the_range() {
+        let options = ColumnFamilyOptions::default();
+        let mut levels = vec![
+            vec![file(6, b"x", b"z", 10), file(5, b"a", b"b", 10)],
+            vec![file(4, b"m", b"p", 10)],
+            vec![],
+            vec![file(3, b"a", b"c", 10), file(2, b"d", b"z", 10)],
+        ];
+        levels.resize(NUM_LEVELS, Vec::new());
+        levels[NUM_LEVELS - 1] = vec![file(1, b"a", b"z", 100)];
+        let files = cf_files(levels);
+
+        // Every level 0 file goes down, even the ones out of the range
+        let compaction =
+            pick_range_compaction(0, &files, &options, 0, Some(b"a"), Some(b"c"), false).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![6, 5, 4]);
+        assert_eq!(compaction.output_level, 1);
+
+        let compaction =
+            pick_range_compaction(0, &files, &options, 1, Some(b"a"), None, false).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![4, 2]);
+        assert_eq!(compaction.output_level, 3);
+
+        assert!(pick_range_compaction(0, &files, &options, 1, None, Some(b"l"), false).is_none());
+        assert!(pick_range_compaction(0, &files, &options, 2, None, None, false).is_none());
+
+        let compaction = pick_range_compaction(0, &files, &options, 3, None, None, false).unwrap();
+        assert_eq!(compaction.output_level, NUM_LEVELS - 1);
+        assert!(compaction.bottommost);
+
+        let last_level = NUM_LEVELS - 1;
+        assert!(
+            pick_range_compaction(0, &files, &options, last_level, None, None, false).is_none()
+        );
+        let compaction =
+            pick_range_compaction(0, &files, &options, last_level, None, None, true).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![1]);
+        assert_eq!(compaction.output_level, last_level);
+
+        let options = options.with_compaction_style(CompactionStyle::Universal);
+        let compaction =
+            pick_range_compaction(0, &files, &options, 0, Some(b"a"), Some(b"b"), false).unwrap();
+        assert_eq!(compaction.input_numbers(), vec![6, 5, 4, 3, 2, 1]);
+        assert_eq!(compaction.output_level, last_level);
+    }
+
+    #[test]
+    fn compactions_are_split_at_the_smallest_keys_of_their_inputs() {
+        let options = ColumnFamilyOptions::default();
+        let files = cf_files(vec![
+            vec![file(9, b"a", b"z", 10)],
+            vec![
+                file(1, b"a", b"c", 10),
+                file(2, b"d", b"f", 10),
+                file(3, b"g", b"i", 10),
+                file(4, b"j", b"l", 10),
+            ],
+        ]);
+        let mut compaction = pick_level_compaction(
+            0,
+            &files,
+            &[],
+            &options.clone().with_level0_file_num_compaction_trigger(1),
+            &HashSet::new(),
+            0,
+        )
+        .unwrap();
+
+        compaction.split(1, &options);
+        assert_eq!(compaction.num_subcompactions(), 1);
+
+        compaction.split(2, &options);
+        assert_eq!(compaction.boundaries, vec![b"g".to_vec()]);
+
+        compaction.split(10, &options);
+        assert_eq!(
+            compaction.boundaries,
+            vec![b"d".to_vec(), b"g".to_vec(), b"j".to_vec()]
+        );
+
+        compaction.split(10, &options.with_user_timestamps(true));
+        assert!(compaction.boundaries.is_empty());
+    }
+}
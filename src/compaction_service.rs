@@ -0,0 +1,313 @@
+use crate::blob::BlobFiles;
+use crate::compaction::{self, Compaction, OutputFiles};
+use crate::db::{self, DbError};
+use crate::key::SequenceNumber;
+use crate::options::ColumnFamilyOptions;
+use crate::table::{self, Table};
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Runs the compactions of a database somewhere else, e.g. on a host with spare resources
+/// sharing the database directory, once registered with
+/// [Options::with_compaction_service](crate::Options::with_compaction_service)
+///
+/// The database keeps picking the compactions and installs their outputs: the service only
+/// merges the input tables, typically by sending [CompactionServiceJob::encode] to a process
+/// calling [run_compaction_job]. The column families with user timestamps are always compacted
+/// by the database itself, and so are the compactions moving the values of blob files, see
+/// [ColumnFamilyOptions::blob_garbage_collection_ratio].
+pub trait CompactionService: Debug + Send + Sync {
+    /// Runs `job`, returning once its output tables are written
+    fn run(&self, job: &CompactionServiceJob) -> Result<CompactionServiceResult, DbError>;
+}
+
+/// A compaction handed to a [CompactionService]
+#[derive(Clone, Debug)]
+pub struct CompactionServiceJob {
+    /// Directory of the database, holding the input tables
+    pub db_path: PathBuf,
+    /// Directory the output tables are written to, created by the database and deleted once the
+    /// tables are moved out of it
+    pub output_dir: PathBuf,
+    pub column_family_id: u32,
+    pub column_family_name: String,
+    /// Custom prefix extractors and merge operators are only known by name, see
+    /// [ColumnFamilyOptions::set]
+    pub options: ColumnFamilyOptions,
+    /// Numbers of the input tables
+    pub input_files: Vec<u64>,
+    pub output_level: usize,
+    /// No level below the output level holds keys in the range of the inputs
+    pub bottommost: bool,
+    /// Sequence number of the oldest snapshot, the last write if none
+    pub oldest_snapshot: SequenceNumber,
+}
+
+impl CompactionServiceJob {
+    /// Returns the job as text, one `name=value` line per field then per knob of
+    /// [CompactionServiceJob::options]
+    pub fn encode(&self) -> String {
+        let input_files: Vec<_> = self.input_files.iter().map(u64::to_string).collect();
+        let mut contents = format!(
+            "db_path={}\noutput_dir={}\ncolumn_family_id={}\ncolumn_family_name={}\n\
+             input_files={}\noutput_level={}\nbottommost={}\noldest_snapshot={}\n",
+            self.db_path.display(),
+            self.output_dir.display(),
+            self.column_family_id,
+            self.column_family_name,
+            input_files.join(","),
+            self.output_level,
+            self.bottommost,
+            self.oldest_snapshot
+        );
+
+        for (name, value) in self.options.to_pairs() {
+            contents.push_str(&format!("option.{}={}\n", name, value));
+        }
+
+        contents
+    }
+
+    /// Parses a job encoded by [CompactionServiceJob::encode]
+    pub fn decode(contents: &str) -> Result<CompactionServiceJob, DbError> {
+        let bad_job = || DbError::InvalidArgument("bad compaction job");
+        let mut job = CompactionServiceJob {
+            db_path: PathBuf::new(),
+            output_dir: PathBuf::new(),
+            column_family_id: 0,
+            column_family_name: String::new(),
+            options: ColumnFamilyOptions::default(),
+            input_files: Vec::new(),
+            output_level: 0,
+            bottommost: false,
+            oldest_snapshot: 0,
+        };
+
+        for line in contents.lines() {
+            let (name, value) = line.split_once('=').ok_or_else(bad_job)?;
+
+            if let Some(name) = name.strip_prefix("option.") {
+                job.options.set(name, value)?;
+                continue;
+            }
+
+            match name {
+                "db_path" => job.db_path = value.into(),
+                "output_dir" => job.output_dir = value.into(),
+                "column_family_id" => {
+                    job.column_family_id = value.parse().map_err(|_| bad_job())?
+                }
+                "column_family_name" => job.column_family_name = value.to_string(),
+                "input_files" => {
+                    job.input_files = value
+                        .split(',')
+                        .filter(|number| !number.is_empty())
+                        .map(|number| number.parse().map_err(|_| bad_job()))
+                        .collect::<Result<_, _>>()?
+                }
+                "output_level" => job.output_level = value.parse().map_err(|_| bad_job())?,
+                "bottommost" => job.bottommost = value.parse().map_err(|_| bad_job())?,
+                "oldest_snapshot" => job.oldest_snapshot = value.parse().map_err(|_| bad_job())?,
+                _ => return Err(bad_job()),
+            }
+        }
+
+        Ok(job)
+    }
+}
+
+/// The outcome of a [CompactionServiceJob]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionServiceResult {
+    /// Paths of the output tables, in [CompactionServiceJob::output_dir]
+    pub output_files: Vec<PathBuf>,
+}
+
+impl CompactionServiceResult {
+    /// Returns the result as text, one path per line
+    pub fn encode(&self) -> String {
+        self.output_files
+            .iter()
+            .map(|path| format!("{}\n", path.display()))
+            .collect()
+    }
+
+    /// Parses a result encoded by [CompactionServiceResult::encode]
+    pub fn decode(contents: &str) -> CompactionServiceResult {
+        CompactionServiceResult {
+            output_files: contents.lines().map(PathBuf::from).collect(),
+        }
+    }
+}
+
+/// Runs `job` in this process, reading the input tables from the database directory while the
+/// database is open: what a [CompactionService] eventually calls
+pub fn run_compaction_job(job: &CompactionServiceJob) -> Result<CompactionServiceResult, DbError> {
+    let tables = job
+        .input_files
+        .iter()
+        .map(|&number| {
+            let path = table::table_file_name(&job.db_path, number);
+            Ok(Arc::new(Table::open(&path, number)?))
+        })
+        .collect::<Result<Vec<_>, DbError>>()?;
+
+    // The tables are numbered from 1 in the output directory, and renamed once installed
+    let compaction = Compaction {
+        column_family_id: job.column_family_id,
+        inputs: Vec::new(),
+        output_level: job.output_level,
+        bottommost: job.bottommost,
+        delete_inputs: false,
+        boundaries: Vec::new(),
+        output_numbers: vec![1],
+        grandparents: Vec::new(),
+        rewritten_blob_files: HashSet::new(),
+    };
+    let last_number = AtomicU64::new(1);
+    let new_file_number = || last_number.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // The jobs don't rewrite blob files, so they only write tables
+    let (outputs, _) = compaction::run(
+        &job.output_dir,
+        &job.options,
+        &compaction,
+        &tables,
+        job.oldest_snapshot,
+        None,
+        &OutputFiles {
+            new_file_number: &new_file_number,
+            rate_limiter: None,
+            blob_files: &BlobFiles::new(&job.db_path),
+        },
+    )?;
+
+    Ok(CompactionServiceResult {
+        output_files: outputs
+            .iter()
+            .map(|table| table::table_file_name(&job.output_dir, table.number()))
+            .collect(),
+    })
+}
+
+/// Returns the directory the outputs of the compaction whose first output table is `number`
+/// are written to by a [CompactionService]
+pub(crate) fn output_dir_name(db_path: &Path, number: u64) -> PathBuf {
+    db_path.join(format!("{:06}.compaction", number))
+}
+
+/// Parses the number of a directory named by [output_dir_name]
+pub(crate) fn parse_output_dir_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".compaction")?.parse().ok()
+}
+
+/// Runs `job` with `service`, then moves its outputs into the database directory `db_path` as
+/// the table files [Compaction::output_numbers] of `compaction`, then the ones `new_file_number`
+/// allocates, and returns them
+pub(crate) fn run(
+    db_path: &Path,
+    service: &dyn CompactionService,
+    job: &CompactionServiceJob,
+    compaction: &Compaction,
+    new_file_number: &dyn Fn() -> u64,
+) -> Result<Vec<Arc<Table>>, DbError> {
+    std::fs::create_dir_all(&job.output_dir)?;
+
+    let outputs = service.run(job).and_then(|result| {
+        let mut numbers = compaction.output_numbers.iter().copied();
+        let mut outputs = Vec::new();
+
+        for path in &result.output_files {
+            if !path.starts_with(&job.output_dir) {
+                return Err(DbError::InvalidArgument(
+                    "compaction output outside of the output directory",
+                ));
+            }
+
+            let number = numbers.next().unwrap_or_else(new_file_number);
+            let table_path = table::table_file_name(db_path, number);
+            std::fs::rename(path, &table_path)?;
+            let table = db::open_table(&table_path, number, &job.options, job.output_level)?;
+            outputs.push(Arc::new(table));
+        }
+
+        db::sync_dir(db_path)?;
+
+        Ok(outputs)
+    });
+
+    std::fs::remove_dir_all(&job.output_dir)?;
+
+    outputs
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compaction_service::{
+        run_compaction_job, CompactionService, CompactionServiceJob, CompactionServiceResult,
+    };
+    use crate::options::{ColumnFamilyOptions, CompactRangeOptions, Options};
+    use crate::{Db, DbError};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Runs the jobs in this process, going through their text form as a remote host would
+    #[derive(Debug, Default)]
+    struct Remote {
+        jobs: AtomicUsize,
+    }
+
+    impl CompactionService for Remote {
+        fn run(&self, job: &CompactionServiceJob) -> Result<CompactionServiceResult, DbError> {
+            self.jobs.fetch_add(1, Ordering::Relaxed);
+
+            let job = CompactionServiceJob::decode(&job.encode())?;
+            let result = run_compaction_job(&job)?;
+
+            Ok(CompactionServiceResult::decode(&result.encode()))
+        }
+    }
+
+    #[test]
+    fn compactions_run_on_the_service() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = Arc::new(Remote::default());
+        let options = Options::default()
+            .with_default_cf_options(
+                ColumnFamilyOptions::default()
+                    .with_disable_auto_compactions(true)
+                    .with_target_file_size(4 << 10, 1),
+            )
+            .with_compaction_service(remote.clone());
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        for value in [1, 2] {
+            for n in 0..200_u32 {
+                db.put(&n.to_be_bytes(), &[value; 100]).unwrap();
+            }
+            db.delete(&7_u32.to_be_bytes()).unwrap();
+            db.flush().unwrap();
+        }
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+
+        assert_eq!(remote.jobs.load(Ordering::Relaxed), 1);
+        let files = db.live_files();
+        assert!(files.len() > 1);
+        assert_eq!(files.iter().map(|file| file.num_deletions).sum::<u64>(), 0);
+
+        // Only the tables are left in the directory
+        let entries = std::fs::read_dir(dir.path()).unwrap();
+        assert!(entries
+            .into_iter()
+            .all(|entry| entry.unwrap().path().is_file()));
+
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(db.get(&42_u32.to_be_bytes()).unwrap(), Some(vec![2; 100]));
+    }
+}
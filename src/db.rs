@@ -0,0 +1,8501 @@
+use crate::batch::{BatchError, Marker, WriteBatch};
+use crate::blob::{self, BlobError, BlobFileBuilder, BlobFiles, BlobIndex, ValueReader};
+use crate::column_family::{
+    self, ColumnFamily, DEFAULT_COLUMN_FAMILY_ID, DEFAULT_COLUMN_FAMILY_NAME,
+};
+use crate::compaction::{self, Compaction};
+use crate::compaction_service::{self, CompactionServiceJob};
+use crate::db_iter::{DbIterator, TailingIterator};
+use crate::instrument::span;
+use crate::iterator::InternalIterator;
+use crate::key::{self, SequenceNumber, ValueType};
+use crate::key_lock::KeyLocks;
+use crate::listener::{
+    CompactionJobInfo, EventListener, FlushJobInfo, TableFileCreationInfo, TableFileCreationReason,
+    TableFileDeletionInfo,
+};
+use crate::lock_manager::{DeadlockInfo, LockManager};
+use crate::memtable::{GetContext, LookupResult, MemTable};
+use crate::merge;
+use crate::options::{
+    self, ColumnFamilyOptions, CompactRangeOptions, CompactionStyle, CompressionType, Options,
+    ReadOptions, TransactionOptions, WriteOptions, MUTABLE_CF_OPTIONS,
+};
+use crate::perf_context;
+use crate::rate_limiter::RateLimiter;
+use crate::scheduler::{Priority, Scheduler};
+use crate::snapshot::{Snapshot, SnapshotList};
+use crate::storage::PinnedValue;
+use crate::table::{self, Table, TableBuilder, TableError};
+use crate::timestamp::{self, HistoryTrimmer, Timestamp, TimestampedIterator};
+use crate::trace::{TraceOp, Tracer};
+use crate::transaction::Transaction;
+use crate::ttl;
+use crate::version::{
+    self, BlobFileMetaData, ColumnFamilyFiles, FileMetaData, Version, VersionEdit, VersionError,
+    VersionSet, NUM_LEVELS,
+};
+use crate::wal::{self, UpdatesIterator, WalArchive, WalError};
+use crate::watch::{Watch, Watchers};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, TryLockError};
+use std::io::{self, Cursor, Read, Write};
+use std::ops::{Range, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DbError {
+    #[error("I/O error on the database directory")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Wal(#[from] WalError),
+    #[error(transparent)]
+    Table(#[from] TableError),
+    #[error(transparent)]
+    Batch(#[from] BatchError),
+    #[error(transparent)]
+    Version(#[from] VersionError),
+    #[error(transparent)]
+    Blob(#[from] BlobError),
+    #[error("Database is corrupted: {0}")]
+    Corruption(&'static str),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
+    #[error("Database is already opened by another process")]
+    Locked,
+    #[error("Writes are stalled until flushes or compactions catch up")]
+    WriteStalled,
+    #[error("Transaction conflicts with a write made since it read or wrote a key")]
+    Conflict,
+    #[error("Timed out waiting for the lock of a key held by another transaction")]
+    LockTimeout,
+    #[error("Waiting for the lock of a key would deadlock: {0:?}")]
+    Deadlock(DeadlockInfo),
+    #[error("Replicated batch starts at sequence number {received} instead of {expected}")]
+    ReplicationGap {
+        expected: SequenceNumber,
+        received: SequenceNumber,
+    },
+}
+
+/// Makes the creation, renaming and deletion of the files in `dir` durable
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), DbError> {
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Hard links the file at `from` to `to`, or copies it if they aren't on the same file system
+pub(crate) fn link_or_copy(from: &Path, to: &Path) -> Result<(), DbError> {
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        File::open(to)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Takes the advisory lock of the database at `path`, held until the returned file is closed
+///
+/// The lock is only checked by other opens: two writers of the same directory would corrupt it.
+pub(crate) fn lock_db(path: &Path) -> Result<File, DbError> {
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join("LOCK"))?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(DbError::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Logs the batches of the `prepared` transactions again in `wal`, a new log, so that they
+/// survive the retirement of the old ones
+fn log_prepared(
+    wal: &mut wal::Writer,
+    prepared: &BTreeMap<String, WriteBatch>,
+) -> Result<(), DbError> {
+    for (name, batch) in prepared {
+        let mut record = batch.clone();
+        record.set_marker(Marker::Prepare, name);
+        wal.add_record_with_sync(record.data(), true)?;
+    }
+
+    Ok(())
+}
+
+/// Opens the table file `number` at `path`, of a column family with `options`, to be read at
+/// `level`
+pub(crate) fn open_table(
+    path: &Path,
+    number: u64,
+    options: &ColumnFamilyOptions,
+    level: usize,
+) -> Result<Table, DbError> {
+    Ok(Table::open(path, number)?
+        .with_block_cache(options.block_cache.clone())
+        .with_cache_index_and_filter_blocks(
+            options.cache_index_and_filter_blocks,
+            level == 0 && options.pin_l0_filter_and_index_blocks_in_cache,
+        )
+        .with_row_cache(options.row_cache.clone()))
+}
+
+/// Writes the table file `number` of the column family `column_family_id` at `level` with the
+/// entries `fill` adds to the builder, returning the opened table
+///
+/// The table is written under a temporary name and renamed once complete, so that a crash
+/// never leaves a partial table behind.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn write_table<F>(
+    dir: &Path,
+    options: &ColumnFamilyOptions,
+    level: usize,
+    compression: CompressionType,
+    rate_limiter: Option<&Arc<RateLimiter>>,
+    number: u64,
+    column_family_id: u32,
+    fill: F,
+) -> Result<Arc<Table>, DbError>
+where
+    F: FnOnce(&mut TableBuilder) -> Result<(), DbError>,
+{
+    let tmp_path = dir.join(format!("{:06}.sst.tmp", number));
+    let path = table::table_file_name(dir, number);
+
+    let mut builder = TableBuilder::new(File::create(&tmp_path)?, options)
+        .with_column_family_id(column_family_id)
+        .with_compression(compression)
+        .with_rate_limiter(rate_limiter.cloned());
+
+    if let Err(e) = fill(&mut builder) {
+        drop(builder);
+        std::fs::remove_file(&tmp_path)?;
+
+        return Err(e);
+    }
+
+    builder.finish()?;
+
+    std::fs::rename(&tmp_path, &path)?;
+    sync_dir(dir)?;
+
+    Ok(Arc::new(open_table(&path, number, options, level)?))
+}
+
+/// Writes the contents of `mem`, the memtable of the column family `column_family_id`, to the
+/// table file `number`, returning the opened table
+///
+/// The values of at least [ColumnFamilyOptions::min_blob_size] bytes go to the blob file
+/// `blob_number` if any, returned too unless left empty, the table only holding their blob
+/// indexes.
+#[allow(clippy::too_many_arguments)]
+fn build_table(
+    dir: &Path,
+    options: &ColumnFamilyOptions,
+    rate_limiter: Option<&Arc<RateLimiter>>,
+    number: u64,
+    column_family_id: u32,
+    mem: &Arc<MemTable>,
+    mut history: Option<HistoryTrimmer>,
+    blob_number: Option<u64>,
+) -> Result<(Arc<Table>, Option<BlobFileMetaData>), DbError> {
+    let mut blobs = blob_number
+        .map(|number| BlobFileBuilder::new(dir, number))
+        .transpose()?;
+
+    let table = write_table(
+        dir,
+        options,
+        0,
+        options.output_compression(0, false),
+        rate_limiter,
+        number,
+        column_family_id,
+        |builder| {
+            let mut iter = mem.iter();
+            iter.seek_to_first()?;
+
+            while iter.valid() {
+                let keep = history
+                    .as_mut()
+                    .is_none_or(|history| history.keep(iter.key()));
+
+                match ttl::expired_tombstone(iter.key(), iter.value()) {
+                    _ if !keep => {}
+                    Some(tombstone) => builder.add(&tombstone, &[])?,
+                    None => match (&mut blobs, key::parse(iter.key())) {
+                        (Some(blobs), Some((user_key, seq, ValueType::Value)))
+                            if iter.value().len() as u64 >= options.min_blob_size =>
+                        {
+                            let index = blobs.add(user_key, iter.value())?;
+                            let key = key::encode(user_key, seq, ValueType::BlobIndex);
+
+                            builder.add(&key, &index.encode())?
+                        }
+                        _ => builder.add(iter.key(), iter.value())?,
+                    },
+                }
+
+                iter.next()?;
+            }
+
+            for tombstone in mem.range_tombstones() {
+                builder.add_range_tombstone(&tombstone.start, &tombstone.end, tombstone.seq);
+            }
+
+            Ok(())
+        },
+    )?;
+
+    let blob_file = match blobs {
+        Some(blobs) if blobs.is_empty() => {
+            blobs.abandon()?;
+            None
+        }
+        Some(blobs) => Some(blobs.finish()?),
+        None => None,
+    };
+
+    Ok((table, blob_file))
+}
+
+/// Returns what drops the versions of the keys of the column family `column_family_id` older
+/// than its history horizon, if it has user timestamps and a horizon
+fn history_trimmer(
+    version: &Version,
+    column_family_id: u32,
+    options: &ColumnFamilyOptions,
+    oldest_snapshot: SequenceNumber,
+) -> Option<HistoryTrimmer> {
+    let full_history_ts_low = version.column_family(column_family_id)?.full_history_ts_low;
+
+    (options.user_timestamps && full_history_ts_low > 0)
+        .then(|| HistoryTrimmer::new(full_history_ts_low, oldest_snapshot))
+}
+
+/// Returns the manifest entry of `table`
+///
+/// The key range of the entry covers the range tombstones of the table too, so that compactions
+/// bring the tombstones together with the keys they delete.
+pub(crate) fn file_meta_data(table: &Table) -> FileMetaData {
+    let properties = table.properties();
+    let mut smallest_key = properties.smallest_key.clone();
+    let mut largest_key = properties.largest_key.clone();
+
+    for tombstone in table.range_tombstones() {
+        let start = key::encode(&tombstone.start, tombstone.seq, ValueType::RangeDeletion);
+        // Sorts before every version of the end key, which the tombstone doesn't delete
+        let end = key::seek_key(&tombstone.end, key::MAX_SEQUENCE_NUMBER);
+
+        if smallest_key.is_empty() || key::compare(&start, &smallest_key).is_lt() {
+            smallest_key = start;
+        }
+
+        if largest_key.is_empty() || key::compare(&end, &largest_key).is_gt() {
+            largest_key = end;
+        }
+    }
+
+    FileMetaData {
+        number: table.number(),
+        file_size: table.file_size(),
+        smallest_key,
+        largest_key,
+    }
+}
+
+/// Returns what `listeners` are told about the creation of `table`, in the database directory
+/// `dir`
+fn table_file_creation_info(
+    dir: &Path,
+    column_family_id: u32,
+    table: &Table,
+    reason: TableFileCreationReason,
+) -> TableFileCreationInfo {
+    TableFileCreationInfo {
+        column_family_id,
+        file_number: table.number(),
+        path: table::table_file_name(dir, table.number()),
+        file_size: table.file_size(),
+        properties: table.properties().clone(),
+        reason,
+    }
+}
+
+/// Deletes the files of the database directory `path` which no version needs: the tables and blob
+/// files not in `live_files`, the leftovers of interrupted flushes and the manifests other than the current one
+///
+/// `listeners` are told about the deleted tables.
+fn delete_obsolete_tables(
+    path: &Path,
+    live_files: &HashSet<u64>,
+    manifest_number: u64,
+    listeners: &[Arc<dyn EventListener>],
+) -> Result<(), DbError> {
+    let mut deleted = false;
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let obsolete_table =
+            table::parse_table_file_name(name).filter(|number| !live_files.contains(number));
+        // The outputs of a compaction service interrupted by a crash
+        if compaction_service::parse_output_dir_name(name)
+            .is_some_and(|number| !live_files.contains(&number))
+        {
+            log::debug!("deleting obsolete directory {}", entry.path().display());
+            std::fs::remove_dir_all(entry.path())?;
+            continue;
+        }
+
+        let obsolete = name
+            .strip_suffix(".tmp")
+            .and_then(|name| {
+                table::parse_table_file_name(name).or_else(|| blob::parse_blob_file_name(name))
+            })
+            .is_some_and(|number| !live_files.contains(&number))
+            || obsolete_table.is_some()
+            || blob::parse_blob_file_name(name).is_some_and(|number| !live_files.contains(&number))
+            || version::parse_manifest_file_name(name)
+                .is_some_and(|number| number != manifest_number);
+
+        if obsolete {
+            log::debug!("deleting obsolete file {}", entry.path().display());
+            std::fs::remove_file(entry.path())?;
+            deleted = true;
+        }
+
+        if let Some(file_number) = obsolete_table {
+            let info = TableFileDeletionInfo {
+                file_number,
+                path: entry.path(),
+            };
+
+            for listener in listeners {
+                listener.on_table_file_deleted(&info);
+            }
+        }
+    }
+
+    if deleted {
+        sync_dir(path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the memtable of the column family `id` of `column_families` the writes of the log
+/// `log_number` are replayed into, unless they're already in its tables as of `version`
+fn recovered_mem<'a>(
+    column_families: &'a BTreeMap<u32, ColumnFamilyData>,
+    version: &Version,
+    id: u32,
+    log_number: u64,
+) -> Option<&'a MemTable> {
+    let files = version.column_family(id)?;
+
+    column_families
+        .get(&id)
+        .filter(|_| log_number >= files.log_number)
+        .map(|data| data.mem.as_ref())
+}
+
+/// Opens the tables of every column family of the current version of `versions`, except the ones
+/// already in `open_tables`
+fn open_column_families(
+    path: &Path,
+    options: &Options,
+    versions: &VersionSet,
+    open_tables: &HashMap<u64, Arc<Table>>,
+) -> Result<BTreeMap<u32, ColumnFamilyData>, DbError> {
+    let mut column_families = BTreeMap::new();
+
+    for (id, files) in versions.current().column_families() {
+        let mut data = ColumnFamilyData::new(
+            ColumnFamily::new(id, &files.name),
+            options.column_family_options(&files.name).clone(),
+        );
+
+        for (level, file) in files
+            .levels
+            .iter()
+            .enumerate()
+            .flat_map(|(level, files)| files.iter().map(move |file| (level, file)))
+        {
+            if let Some(table) = open_tables.get(&file.number) {
+                data.tables.push(table.clone());
+                continue;
+            }
+
+            let table_path = table::table_file_name(path, file.number);
+
+            if !table_path.exists() {
+                return Err(DbError::Corruption("missing table file"));
+            }
+
+            let table = open_table(&table_path, file.number, &data.options, level)?;
+            data.tables.push(Arc::new(table));
+        }
+
+        column_families.insert(id, data);
+    }
+
+    Ok(column_families)
+}
+
+/// Starts the manifest of a new database at `path`, which only has the default column family
+fn create_manifest(path: &Path) -> Result<VersionSet, DbError> {
+    let edit = VersionEdit {
+        log_number: Some(0),
+        next_file_number: Some(1),
+        last_sequence: Some(0),
+        next_column_family_id: Some(DEFAULT_COLUMN_FAMILY_ID + 1),
+        added_column_families: vec![(
+            DEFAULT_COLUMN_FAMILY_ID,
+            DEFAULT_COLUMN_FAMILY_NAME.to_string(),
+        )],
+        ..VersionEdit::default()
+    };
+
+    Ok(VersionSet::create(path, &edit)?)
+}
+
+/// Returns whether the database at `path`, whose logs are in `wal_dir`, holds tables, blob files
+/// or logs, whose writes a new manifest would lose track of
+fn has_data(path: &Path, wal_dir: &Path) -> Result<bool, DbError> {
+    for entry in std::fs::read_dir(path)? {
+        let name = entry?.file_name();
+        let name = name.to_str().unwrap_or_default();
+
+        if table::parse_table_file_name(name).is_some()
+            || blob::parse_blob_file_name(name).is_some()
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(!wal::list_logs(wal_dir)?.is_empty())
+}
+
+/// Returns the timestamp before which the history of `cf` may be dropped, 0 if it was dropped
+fn full_history_ts_low(state: &DbState, cf: &ColumnFamily) -> Timestamp {
+    state
+        .versions
+        .current()
+        .column_family(cf.id())
+        .map_or(0, |files| files.full_history_ts_low)
+}
+
+/// Description of a table of the database, see [Db::live_files]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveFileMetaData {
+    pub column_family_name: String,
+    pub file_number: u64,
+    pub path: PathBuf,
+    pub level: usize,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Smallest user key of the table, range tombstones excluded
+    pub smallest_key: Vec<u8>,
+    /// Largest user key of the table, range tombstones excluded
+    pub largest_key: Vec<u8>,
+    pub smallest_seqno: SequenceNumber,
+    pub largest_seqno: SequenceNumber,
+    pub num_entries: u64,
+    pub num_deletions: u64,
+}
+
+/// The tables of a column family exported by [Db::export_cf], to be imported by
+/// [Db::create_cf_with_import]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExportImportFilesMetaData {
+    /// The tables in the order reads look at them, their paths being in the export directory
+    pub files: Vec<LiveFileMetaData>,
+}
+
+/// Describes the tables of the column family `data`, whose live files are `files`, in the
+/// database directory `dir`
+fn column_family_live_files(
+    dir: &Path,
+    data: &ColumnFamilyData,
+    files: &ColumnFamilyFiles,
+) -> Vec<LiveFileMetaData> {
+    let mut live_files = Vec::new();
+
+    for (level, level_files) in files.levels.iter().enumerate() {
+        for file in level_files {
+            let table = data
+                .tables
+                .iter()
+                .find(|table| table.number() == file.number);
+            let properties = table.map(|table| table.properties().clone());
+            let properties = properties.unwrap_or_default();
+
+            live_files.push(LiveFileMetaData {
+                column_family_name: data.handle.name().to_string(),
+                file_number: file.number,
+                path: table::table_file_name(dir, file.number),
+                level,
+                size: file.file_size,
+                smallest_key: live_file_user_key(&file.smallest_key),
+                largest_key: live_file_user_key(&file.largest_key),
+                smallest_seqno: properties.smallest_seqno,
+                largest_seqno: properties.largest_seqno,
+                num_entries: properties.num_entries,
+                num_deletions: properties.num_deletions,
+            });
+        }
+    }
+
+    live_files
+}
+
+/// Creates `dir`, which must not exist, with the files `fill` writes to it
+///
+/// The directory is filled under a temporary name and renamed once complete, so that it's never
+/// seen half written.
+fn create_dir_atomically<F>(dir: &Path, fill: F) -> Result<(), DbError>
+where
+    F: FnOnce(&Path) -> Result<(), DbError>,
+{
+    let mut tmp_dir = dir.as_os_str().to_owned();
+    tmp_dir.push(".tmp");
+    let tmp_dir = PathBuf::from(tmp_dir);
+
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+
+    std::fs::create_dir_all(&tmp_dir)?;
+
+    if let Err(e) = fill(&tmp_dir).and_then(|_| sync_dir(&tmp_dir)) {
+        std::fs::remove_dir_all(&tmp_dir)?;
+        return Err(e);
+    }
+
+    std::fs::rename(&tmp_dir, dir)?;
+
+    match dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_dir(parent),
+        _ => sync_dir(Path::new(".")),
+    }
+}
+
+/// Bytes of memory used by a database, see [Db::memory_usage]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Memtables of the column families, written to or waiting to be flushed
+    pub mem_tables: usize,
+    /// Memtables already flushed to tables, kept alive by the iterators still reading them
+    pub pinned_mem_tables: usize,
+    /// Indexes, filters and range tombstones of the open tables
+    pub table_readers: usize,
+    /// Data blocks held by the block caches of the column families and their secondary caches,
+    /// each cache counted once
+    pub block_cache: usize,
+    /// Rows held by the row caches of the column families, each cache counted once
+    pub row_cache: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.mem_tables
+            + self.pinned_mem_tables
+            + self.table_readers
+            + self.block_cache
+            + self.row_cache
+    }
+}
+
+/// Returns `caches` without the ones shared by several column families counted more than once
+fn distinct<'a, T>(caches: impl Iterator<Item = &'a Arc<T>>) -> Vec<&'a Arc<T>> {
+    let mut distinct: Vec<&Arc<T>> = Vec::new();
+
+    for cache in caches {
+        if !distinct.iter().any(|other| Arc::ptr_eq(other, cache)) {
+            distinct.push(cache);
+        }
+    }
+
+    distinct
+}
+
+/// Statistics of the flushes and compactions writing into a level of a column family since the
+/// database was opened, see [Db::compaction_stats]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompactionStats {
+    pub level: usize,
+    /// Number of tables in the level
+    pub num_files: usize,
+    /// Bytes of the tables in the level
+    pub size: u64,
+    /// Number of compactions into the level, counting the flushes for level 0
+    pub count: u64,
+    /// Bytes read by the compactions from the levels above, or from the memtables by the
+    /// flushes
+    pub bytes_read_input_levels: u64,
+    /// Bytes read by the compactions from the level itself
+    pub bytes_read_output_level: u64,
+    /// Bytes of the tables written into the level
+    pub bytes_written: u64,
+    /// Bytes of the tables moved into the level as they were
+    pub bytes_moved: u64,
+    /// Time spent writing the tables of the level
+    pub time: Duration,
+}
+
+impl CompactionStats {
+    /// Returns the bytes written per byte coming from above, the cost of keeping the level
+    /// sorted
+    pub fn write_amplification(&self) -> f64 {
+        ratio(self.bytes_written, self.bytes_read_input_levels)
+    }
+
+    /// Returns the bytes read per byte coming from above
+    pub fn read_amplification(&self) -> f64 {
+        ratio(
+            self.bytes_read_input_levels + self.bytes_read_output_level,
+            self.bytes_read_input_levels,
+        )
+    }
+
+    /// Returns the bytes written per second spent writing
+    pub fn write_throughput(&self) -> f64 {
+        if self.time.is_zero() {
+            return 0.0;
+        }
+
+        self.bytes_written as f64 / self.time.as_secs_f64()
+    }
+
+    /// Counts a compaction reading `read` bytes from the levels above and `read_output_level`
+    /// from the level itself to write `written` bytes in `time`
+    fn add(&mut self, read: u64, read_output_level: u64, written: u64, time: Duration) {
+        self.count += 1;
+        self.bytes_read_input_levels += read;
+        self.bytes_read_output_level += read_output_level;
+        self.bytes_written += written;
+        self.time += time;
+    }
+}
+
+/// Returns `n / d`, 0 if `d` is 0
+fn ratio(n: u64, d: u64) -> f64 {
+    match d {
+        0 => 0.0,
+        _ => n as f64 / d as f64,
+    }
+}
+
+/// Returns the user key of a bound of a table, empty for tables without entries
+fn live_file_user_key(internal_key: &[u8]) -> Vec<u8> {
+    key::parse(internal_key)
+        .map(|(user_key, _, _)| user_key.to_vec())
+        .unwrap_or_default()
+}
+
+/// The answer of [Db::key_may_exist]
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyMayExist {
+    /// The key surely doesn't exist
+    No,
+    /// The key may exist, but finding out would take reading data blocks
+    Maybe,
+    /// The key exists, with this value found in the memtable
+    Found(Vec<u8>),
+}
+
+/// Answers [Db::key_may_exist] from the sources of a read
+fn key_may_exist_in(view: ReadView, key: &[u8]) -> KeyMayExist {
+    let mut ctx = GetContext::default();
+    let result = view
+        .mems
+        .iter()
+        .find_map(|mem| mem.get_with_context(key, view.last_sequence, &mut ctx));
+
+    match result {
+        // The value of a merged key depends on the operator, which may fail
+        _ if !ctx.operands.is_empty() => KeyMayExist::Maybe,
+        Some(LookupResult::Value(value)) => KeyMayExist::Found(value),
+        Some(LookupResult::Deleted) => KeyMayExist::No,
+        None if view.tables.iter().any(|table| table.key_may_exist(key)) => KeyMayExist::Maybe,
+        None => KeyMayExist::No,
+    }
+}
+
+/// Number of stripes the keys are hashed to by [Db::update] and [Db::compare_and_swap]
+const NUM_KEY_LOCK_STRIPES: usize = 64;
+
+/// The memtables and the tables of a column family
+struct ColumnFamilyData {
+    handle: ColumnFamily,
+    options: Arc<ColumnFamilyOptions>,
+    mem: Arc<MemTable>,
+    /// The oldest log holding writes of `mem`
+    mem_log_number: u64,
+    /// Full memtables waiting to be flushed, newest first
+    imm: Vec<ImmutableMemTable>,
+    /// Tables, newest first
+    tables: Vec<Arc<Table>>,
+    /// Statistics of every level, see [Db::compaction_stats]
+    compaction_stats: Vec<CompactionStats>,
+    /// Numbers of the tables to compact once no level is over its trigger, see
+    /// [Db::suggest_compact_range_cf]
+    marked_for_compaction: HashSet<u64>,
+}
+
+impl ColumnFamilyData {
+    fn new(handle: ColumnFamily, options: ColumnFamilyOptions) -> ColumnFamilyData {
+        ColumnFamilyData {
+            handle,
+            options: Arc::new(options),
+            mem: Arc::new(MemTable::new()),
+            mem_log_number: 0,
+            imm: Vec::new(),
+            tables: Vec::new(),
+            compaction_stats: (0..NUM_LEVELS)
+                .map(|level| CompactionStats {
+                    level,
+                    ..CompactionStats::default()
+                })
+                .collect(),
+            marked_for_compaction: HashSet::new(),
+        }
+    }
+
+    /// Returns the memtables, newest first: the one written to, then the immutable ones
+    fn mems(&self) -> impl Iterator<Item = &Arc<MemTable>> {
+        std::iter::once(&self.mem).chain(self.imm.iter().map(|imm| &imm.mem))
+    }
+
+    /// Returns the oldest log holding writes of the column family which aren't in its tables,
+    /// once its `flushed` oldest immutable memtables are
+    fn oldest_log_number(&self, flushed: usize) -> u64 {
+        self.imm[..self.imm.len() - flushed]
+            .last()
+            .map_or(self.mem_log_number, |imm| imm.log_number)
+    }
+}
+
+/// A full memtable waiting to be flushed
+struct ImmutableMemTable {
+    mem: Arc<MemTable>,
+    /// The oldest log holding writes of `mem`
+    log_number: u64,
+}
+
+/// The sources of a read in a column family, taken out of the [Db] mutex
+struct ReadView {
+    column_family_id: u32,
+    options: Arc<ColumnFamilyOptions>,
+    /// Memtables, newest first
+    mems: Vec<Arc<MemTable>>,
+    /// Tables, newest first
+    tables: Vec<Arc<Table>>,
+    /// Sequence number of the last write
+    last_sequence: SequenceNumber,
+    /// Keeps the files of the tables from being deleted while they're read
+    version: Arc<Version>,
+}
+
+impl ReadView {
+    fn new(data: &ColumnFamilyData, state: &DbState) -> ReadView {
+        ReadView {
+            column_family_id: data.handle.id(),
+            options: data.options.clone(),
+            mems: data.mems().cloned().collect(),
+            tables: data.tables.clone(),
+            last_sequence: state.last_sequence,
+            version: state.versions.current().clone(),
+        }
+    }
+
+    /// Returns the sequence number a read sees, as chosen by `read_options`
+    fn sequence(&self, read_options: &ReadOptions) -> SequenceNumber {
+        match (&read_options.snapshot, read_options.sequence) {
+            (Some(snapshot), _) => snapshot.sequence(),
+            (None, Some(sequence)) => sequence.min(self.last_sequence),
+            (None, None) => self.last_sequence,
+        }
+    }
+
+    /// Returns the sequence number of the last write of `key`, if it was ever written and its
+    /// versions weren't all dropped since
+    ///
+    /// Range deletions covering the key don't count as writes of it.
+    fn latest_sequence(&self, key: &[u8]) -> Result<Option<SequenceNumber>, DbError> {
+        let target = key::seek_key(key, key::MAX_SEQUENCE_NUMBER);
+        let mems = self
+            .mems
+            .iter()
+            .map(|mem| Box::new(mem.iter()) as Box<dyn InternalIterator>);
+        let tables = self
+            .tables
+            .iter()
+            .filter(|table| table.may_contain(key))
+            .map(|table| Box::new(table.iter()) as Box<dyn InternalIterator>);
+
+        // The newest source holding the key has its last version
+        for mut iter in mems.chain(tables) {
+            iter.seek(&target)?;
+
+            match iter.valid().then(|| key::parse(iter.key())).flatten() {
+                Some((user_key, seq, _)) if user_key == key => return Ok(Some(seq)),
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The mutable state of a [Db], guarded by its mutex
+struct DbState {
+    /// Column families by id
+    column_families: BTreeMap<u32, ColumnFamilyData>,
+    /// The live files and the counters persisted in the manifest
+    versions: VersionSet,
+    /// None if the database is read-only
+    wal: Option<wal::Writer>,
+    log_number: u64,
+    /// Obsolete logs waiting to be reused by the next log
+    recyclable_logs: Vec<PathBuf>,
+    /// Sequence number of the last visible write
+    last_sequence: SequenceNumber,
+    /// Reads as of older sequence numbers may miss versions of keys dropped by compactions
+    oldest_readable_sequence: SequenceNumber,
+    /// The writes of [Options::unordered_write] not visible yet by first sequence number: their
+    /// last sequence number, and whether they were inserted into the memtables
+    unordered_writes: BTreeMap<SequenceNumber, (SequenceNumber, bool)>,
+    /// Bytes of memtables charged to [Options::write_buffer_manager]
+    write_buffer_usage: usize,
+    /// The memtables flushed so far, as long as something reads them, see [Db::memory_usage]
+    flushed_mems: Vec<Weak<MemTable>>,
+    /// Pin the sequence numbers of the persistent snapshots, by name
+    persistent_snapshots: BTreeMap<String, Snapshot>,
+    /// Number of background jobs which failed, see [EventListener::on_background_error]
+    background_errors: u64,
+    /// Numbers of the table files being compacted, which the other compactions leave alone
+    compacting: HashSet<u64>,
+    /// Numbers of the table files being written by the compactions, which aren't obsolete even
+    /// though no version has them yet
+    pending_outputs: HashSet<u64>,
+    /// Number of compactions running
+    running_compactions: usize,
+    /// Whether a flush is writing the immutable memtables to tables
+    flushing: bool,
+    /// The batches of the prepared transactions by name, logged again in every new log until
+    /// they commit or roll back, see [Transaction::prepare]
+    prepared: BTreeMap<String, WriteBatch>,
+}
+
+impl DbState {
+    fn default_column_family(&self) -> &ColumnFamilyData {
+        &self.column_families[&DEFAULT_COLUMN_FAMILY_ID]
+    }
+
+    /// Returns the data of `cf`, failing if it was dropped
+    fn column_family(&self, cf: &ColumnFamily) -> Result<&ColumnFamilyData, DbError> {
+        self.column_families
+            .get(&cf.id())
+            .ok_or(DbError::InvalidArgument("column family was dropped"))
+    }
+
+    /// Returns the write-ahead log, failing if the database is read-only
+    fn wal(&mut self) -> Result<&mut wal::Writer, DbError> {
+        self.wal
+            .as_mut()
+            .ok_or(DbError::InvalidArgument("database is read-only"))
+    }
+
+    /// Returns the sources of a read in `cf` as of the last write
+    fn read_view(&self, cf: &ColumnFamily) -> Result<ReadView, DbError> {
+        Ok(ReadView::new(self.column_family(cf)?, self))
+    }
+
+    /// Returns the sequence number of the next write, which comes after the unordered writes in
+    /// progress
+    fn next_sequence(&self) -> SequenceNumber {
+        let last_allocated = self
+            .unordered_writes
+            .last_key_value()
+            .map_or(self.last_sequence, |(_, (last, _))| *last);
+
+        last_allocated + 1
+    }
+
+    /// Marks the unordered write starting at `first_sequence` as inserted, making it visible
+    /// along with the following ones if every write before them is inserted too
+    fn complete_unordered_write(&mut self, first_sequence: SequenceNumber) {
+        if let Some((_, inserted)) = self.unordered_writes.get_mut(&first_sequence) {
+            *inserted = true;
+        }
+
+        while let Some(entry) = self.unordered_writes.first_entry() {
+            let (last, inserted) = *entry.get();
+
+            if !inserted {
+                break;
+            }
+
+            entry.remove();
+            self.last_sequence = last;
+        }
+    }
+
+    /// Returns the column families whose memtables should be flushed: the ones grown too large,
+    /// or else the one with the largest memtable once all of them together did
+    fn memtables_to_flush(&self, options: &Options) -> Vec<u32> {
+        let full: Vec<u32> = self
+            .column_families
+            .iter()
+            .filter(|(_, data)| {
+                data.mem.approximate_memory_usage() >= data.options.write_buffer_size
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        let over_threshold = options
+            .write_buffer_manager
+            .as_ref()
+            .is_some_and(|manager| manager.should_flush());
+
+        if !full.is_empty() || !over_threshold {
+            return full;
+        }
+
+        self.column_families
+            .iter()
+            .filter(|(_, data)| !data.mem.is_empty())
+            .max_by_key(|(_, data)| data.mem.approximate_memory_usage())
+            .map(|(id, _)| *id)
+            .into_iter()
+            .collect()
+    }
+
+    /// Returns how writes should be held back for flushes or compactions to catch up, if they
+    /// should
+    fn write_stall(&self) -> Option<WriteStall> {
+        let version = self.versions.current();
+
+        self.column_families
+            .iter()
+            .filter_map(|(id, data)| {
+                let levels = version
+                    .column_family(*id)
+                    .map(|files| files.levels.as_slice());
+
+                memtables_stall(data).max(levels.and_then(|levels| compactions_stall(levels, data)))
+            })
+            .max()
+    }
+}
+
+/// Returns how writes to the column family of `data` should be held back for its flushes to
+/// catch up, if they should: its memtable can't be switched out for a new one once full, with
+/// [ColumnFamilyOptions::max_write_buffer_number] memtables already
+fn memtables_stall(data: &ColumnFamilyData) -> Option<WriteStall> {
+    let max = data.options.max_write_buffer_number;
+    let memtables = data.imm.len() + 1;
+    let full = data.mem.approximate_memory_usage() >= data.options.write_buffer_size;
+
+    if memtables >= max && full {
+        Some(WriteStall::Stopped)
+    } else if max > 3 && memtables >= max - 1 {
+        Some(WriteStall::Delayed)
+    } else {
+        None
+    }
+}
+
+/// Returns how writes to the column family of `data` should be held back for its compactions to
+/// catch up given its `levels`, if they should
+///
+/// The column families compacted in the FIFO style never stall: their level 0 only shrinks once
+/// over its size cap.
+fn compactions_stall(
+    levels: &[Vec<Arc<FileMetaData>>],
+    data: &ColumnFamilyData,
+) -> Option<WriteStall> {
+    let options = &data.options;
+
+    if options.compaction_style == CompactionStyle::Fifo {
+        return None;
+    }
+
+    let level0_files = levels.first().map_or(0, Vec::len);
+    let pending_bytes = estimate_pending_compaction_bytes(levels, options);
+
+    let over = |limit: u64| limit > 0 && pending_bytes >= limit;
+
+    if level0_files >= options.level0_stop_writes_trigger
+        || over(options.hard_pending_compaction_bytes_limit)
+    {
+        Some(WriteStall::Stopped)
+    } else if level0_files >= options.level0_slowdown_writes_trigger
+        || over(options.soft_pending_compaction_bytes_limit)
+    {
+        Some(WriteStall::Delayed)
+    } else {
+        None
+    }
+}
+
+/// The tables and blob files of a checkpoint, see [Db::write_checkpoint], which stay until
+/// dropped
+pub(crate) struct CheckpointFiles {
+    /// The path of each file in the database, with its name
+    pub(crate) files: Vec<(PathBuf, String)>,
+    _version: Arc<Version>,
+}
+
+/// How writes are held back when flushes or compactions fall behind
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum WriteStall {
+    /// Writes are slowed down to [Options::delayed_write_rate]
+    Delayed,
+    /// Writes wait until flushes or compactions catch up
+    Stopped,
+}
+
+/// Returns how often to look for tables due for a periodic compaction: as often as the shortest
+/// period of the column families, and at least once an hour for the ones it's set on later
+fn periodic_compaction_check_interval(options: &Options) -> Duration {
+    std::iter::once(&options.default_cf_options)
+        .chain(options.cf_options.values())
+        .filter_map(|cf_options| cf_options.periodic_compaction_seconds)
+        .map(Duration::from_secs)
+        .fold(Duration::from_secs(3600), Duration::min)
+}
+
+/// Picks the next compaction of the column families, unless the automatic compactions of every
+/// column family whose levels are over their triggers are disabled, or the files to compact are
+/// being compacted already
+fn pick_compaction(state: &DbState, oldest_snapshot: SequenceNumber) -> Option<Compaction> {
+    let version = state.versions.current();
+
+    state.column_families.iter().find_map(|(id, data)| {
+        let options = &data.options;
+        let files = version.column_family(*id)?;
+
+        match options.compaction_style {
+            _ if options.disable_auto_compactions => None,
+            CompactionStyle::Level => compaction::pick_level_compaction(
+                *id,
+                files,
+                &data.tables,
+                options,
+                &state.compacting,
+                oldest_snapshot,
+            )
+            .or_else(|| {
+                compaction::pick_marked_compaction(
+                    *id,
+                    files,
+                    &data.tables,
+                    &state.compacting,
+                    |_, table| data.marked_for_compaction.contains(&table.number()),
+                )
+            }),
+            CompactionStyle::Universal => {
+                compaction::pick_universal_compaction(*id, files, options, &state.compacting)
+            }
+            CompactionStyle::Fifo => {
+                compaction::pick_fifo_compaction(*id, files, &data.tables, options)
+            }
+        }
+    })
+}
+
+/// Returns the total size of the files of a level
+fn level_size(files: &[Arc<FileMetaData>]) -> u64 {
+    files.iter().map(|file| file.file_size).sum()
+}
+
+/// Returns the statistics of every level of the column family `data`, whose files are `levels`
+fn level_compaction_stats(
+    data: &ColumnFamilyData,
+    levels: &[Vec<Arc<FileMetaData>>],
+) -> Vec<CompactionStats> {
+    data.compaction_stats
+        .iter()
+        .map(|stats| {
+            let files = levels.get(stats.level).map_or(&[][..], Vec::as_slice);
+
+            CompactionStats {
+                num_files: files.len(),
+                size: level_size(files),
+                ..stats.clone()
+            }
+        })
+        .collect()
+}
+
+/// Estimates the bytes compactions have to rewrite to bring every level of a column family
+/// back under its target size
+fn estimate_pending_compaction_bytes(
+    levels: &[Vec<Arc<FileMetaData>>],
+    options: &ColumnFamilyOptions,
+) -> u64 {
+    let mut pending = 0;
+
+    if let Some(level0) = levels.first() {
+        if level0.len() >= options.level0_file_num_compaction_trigger {
+            pending += level_size(level0);
+        }
+    }
+
+    let mut target = options.max_bytes_for_level_base;
+
+    for files in levels.iter().skip(1) {
+        pending += level_size(files).saturating_sub(target);
+        target = target.saturating_mul(options.max_bytes_for_level_multiplier);
+    }
+
+    pending
+}
+
+/// An embedded key-value store
+///
+/// Writes are appended to the write-ahead log and applied to the memtable, which is flushed to a
+/// new table once it grows beyond [ColumnFamilyOptions::write_buffer_size]. Reads look at the memtable
+/// first, then at the tables from the newest to the oldest. Compactions merge the tables into
+/// levels of growing size, dropping the versions of the keys nothing reads anymore. The flushes
+/// and the compactions the database decides on run on background threads, see
+/// [Options::max_background_flushes] and [Options::max_background_compactions].
+///
+/// The keys live in column families, independent keyspaces with their own memtable and tables
+/// which share the write-ahead log. The methods without a column family use the default one.
+///
+/// Writers of the same key are serialized by key-striped locks, which makes [Db::update] and
+/// [Db::compare_and_swap] atomic.
+pub struct Db {
+    inner: Arc<DbInner>,
+}
+
+/// The parts of a [Db] shared with its background jobs
+struct DbInner {
+    /// Handed to the background jobs, which give up once the database is dropped
+    this: Weak<DbInner>,
+    path: PathBuf,
+    wal_dir: PathBuf,
+    options: Options,
+    archive: WalArchive,
+    snapshots: Arc<SnapshotList>,
+    key_locks: KeyLocks,
+    /// Locks the keys of the pessimistic transactions
+    lock_manager: LockManager,
+    /// Reads the values of the column families with [ColumnFamilyOptions::enable_blob_files]
+    blob_files: Arc<BlobFiles>,
+    state: Mutex<DbState>,
+    /// Set by [Db::cancel_all_background_work]
+    background_work_cancelled: AtomicBool,
+    /// Notified when writes may no longer need to be stalled, see [DbState::write_stall]
+    stall_cleared: Condvar,
+    /// Notified when unordered writes become visible, see [Options::unordered_write]
+    unordered_writes_visible: Condvar,
+    /// Notified when a compaction completes, releasing its input files
+    compaction_done: Condvar,
+    /// Notified when a flush completes, letting the next one start
+    flush_done: Condvar,
+    watchers: Watchers,
+    /// Runs the flushes and the compactions the database decides on
+    scheduler: Scheduler,
+    /// Records the operations while a trace runs, see [Db::start_trace]
+    tracer: Mutex<Option<Arc<Tracer>>>,
+    /// Holds the lock of the directory until the database is dropped, unless read-only
+    _lock: Option<File>,
+}
+
+impl Db {
+    /// Opens the database at `path`, creating it if it doesn't exist
+    ///
+    /// The live tables are read from the manifest, then the writes which didn't make it into a
+    /// table are replayed from the write-ahead logs newer than its log numbers and flushed. Files
+    /// left over by a crash, which the manifest doesn't know about, are deleted.
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Db, DbError> {
+        options.validate()?;
+
+        let path = path.as_ref().to_path_buf();
+        let wal_dir = options.wal_dir(&path).to_path_buf();
+
+        std::fs::create_dir_all(&path)?;
+        std::fs::create_dir_all(&wal_dir)?;
+
+        let lock = lock_db(&path)?;
+
+        let mut versions = {
+            let span = span!("recover_manifest"; manifest_number);
+            let versions = match VersionSet::recover(&path)? {
+                Some(versions) => versions,
+                None if has_data(&path, &wal_dir)? => {
+                    return Err(DbError::Corruption(
+                        "no CURRENT file next to the tables or logs, run repair to rebuild the manifest",
+                    ))
+                }
+                None => create_manifest(&path)?,
+            };
+            span.record("manifest_number", versions.manifest_number());
+
+            versions
+        };
+
+        let mut column_families = {
+            let span = span!("open_tables"; tables);
+            let column_families =
+                open_column_families(&path, &options, &versions, &HashMap::new())?;
+            let tables = column_families.values().map(|data| data.tables.len());
+            span.record("tables", tables.sum::<usize>() as u64);
+
+            column_families
+        };
+
+        // Leftovers of a crash, which the manifest doesn't know about
+        delete_obsolete_tables(
+            &path,
+            &versions.live_files(),
+            versions.manifest_number(),
+            &options.listeners,
+        )?;
+
+        let mut recyclable_logs = Vec::new();
+
+        for entry in std::fs::read_dir(&wal_dir)? {
+            let entry = entry?;
+
+            if let Some(name) = entry.file_name().to_str() {
+                if wal::parse_recyclable_log_file_name(name).is_some() {
+                    recyclable_logs.push(entry.path());
+                }
+            }
+        }
+
+        let recovery = {
+            let _span = span!("replay_logs", min_log_number = versions.log_number());
+
+            wal::recover(&path, &options, versions.log_number(), |id, log_number| {
+                recovered_mem(&column_families, versions.current(), id, log_number)
+            })?
+        };
+
+        // The writes a persistent snapshot sees may have been lost along with the log, but the
+        // writes after it must still get greater sequence numbers
+        let last_sequence = recovery
+            .last_sequence
+            .unwrap_or(0)
+            .max(versions.last_sequence())
+            .max(versions.snapshots().values().copied().max().unwrap_or(0));
+
+        for (log_number, _) in &recovery.logs {
+            versions.mark_file_number_used(*log_number);
+        }
+
+        let mut edit = VersionEdit::default();
+
+        // The recovered writes are flushed right away, so that the logs can be retired
+        for (id, data) in &mut column_families {
+            if !data.mem.is_empty() {
+                let number = versions.new_file_number();
+                let span = span!(
+                    "flush_recovered",
+                    column_family = data.handle.name(),
+                    file_number = number,
+                    entries = data.mem.len() as u64;
+                    bytes
+                );
+                // Nothing reads the database yet
+                let history =
+                    history_trimmer(versions.current(), *id, &data.options, last_sequence);
+                let blob_number = data
+                    .options
+                    .enable_blob_files
+                    .then(|| versions.new_file_number());
+                let (table, blob_file) = build_table(
+                    &path,
+                    &data.options,
+                    options.rate_limiter.as_ref(),
+                    number,
+                    *id,
+                    &data.mem,
+                    history,
+                    blob_number,
+                )?;
+
+                log::info!(
+                    "flushed the recovered writes of column family {} to table {}",
+                    data.handle.name(),
+                    number
+                );
+                span.record("bytes", table.file_size());
+
+                let info =
+                    table_file_creation_info(&path, *id, &table, TableFileCreationReason::Recovery);
+
+                for listener in &options.listeners {
+                    listener.on_table_file_created(&info);
+                }
+
+                edit.add_file(*id, 0, file_meta_data(&table));
+                edit.new_blob_files
+                    .extend(blob_file.map(|file| (*id, file)));
+                data.tables.insert(0, table);
+                data.mem = Arc::new(MemTable::new());
+            }
+        }
+
+        let archive = WalArchive::new(&wal_dir, options.wal_retention);
+
+        let log_number = versions.new_file_number();
+        let mut wal = Db::create_log(&wal_dir, &options, &mut recyclable_logs, log_number)?;
+        log_prepared(&mut wal, &recovery.prepared)?;
+
+        for data in column_families.values_mut() {
+            data.mem_log_number = log_number;
+        }
+
+        edit.log_number = Some(log_number);
+        edit.last_sequence = Some(last_sequence);
+        versions.log_and_apply(edit)?;
+
+        log::info!(
+            "opened the database at {}, last sequence number {}",
+            path.display(),
+            last_sequence
+        );
+
+        let db = Db {
+            inner: Arc::new_cyclic(|this| DbInner {
+                this: this.clone(),
+                scheduler: Scheduler::new(
+                    options.max_background_flushes,
+                    options.max_background_compactions,
+                ),
+                blob_files: Arc::new(BlobFiles::new(&path)),
+                path,
+                wal_dir,
+                options,
+                archive,
+                snapshots: Arc::new(SnapshotList::new()),
+                background_work_cancelled: AtomicBool::new(false),
+                stall_cleared: Condvar::new(),
+                unordered_writes_visible: Condvar::new(),
+                compaction_done: Condvar::new(),
+                flush_done: Condvar::new(),
+                tracer: Mutex::new(None),
+                watchers: Watchers::default(),
+                key_locks: KeyLocks::new(NUM_KEY_LOCK_STRIPES),
+                lock_manager: LockManager::new(),
+                state: Mutex::new(DbState {
+                    column_families,
+                    versions,
+                    wal: Some(wal),
+                    log_number,
+                    recyclable_logs,
+                    last_sequence,
+                    oldest_readable_sequence: 0,
+                    unordered_writes: BTreeMap::new(),
+                    write_buffer_usage: 0,
+                    flushed_mems: Vec::new(),
+                    persistent_snapshots: BTreeMap::new(),
+                    background_errors: 0,
+                    compacting: HashSet::new(),
+                    pending_outputs: HashSet::new(),
+                    running_compactions: 0,
+                    flushing: false,
+                    prepared: recovery.prepared,
+                }),
+                _lock: Some(lock),
+            }),
+        };
+
+        {
+            let mut state = db.inner.state.lock().unwrap();
+
+            state.persistent_snapshots = state
+                .versions
+                .snapshots()
+                .iter()
+                .map(|(name, seq)| (name.clone(), db.inner.snapshots.acquire(*seq)))
+                .collect();
+
+            db.inner.charge_write_buffers(&mut state);
+            db.inner.delete_obsolete_files(&mut state)?;
+            db.write_options_file(&state)?;
+
+            // The compactions before the restart may have dropped the versions hidden by the ones
+            // they kept
+            let compacted: HashSet<u64> = state
+                .versions
+                .current()
+                .column_families()
+                .flat_map(|(_, files)| files.levels[1..].iter().flatten())
+                .map(|file| file.number)
+                .collect();
+            state.oldest_readable_sequence = state
+                .column_families
+                .values()
+                .flat_map(|data| &data.tables)
+                .filter(|table| compacted.contains(&table.number()))
+                .map(|table| table.properties().largest_seqno)
+                .max()
+                .unwrap_or(0);
+
+            db.inner.schedule_compaction();
+        }
+
+        // The tables age even when nothing is written, see
+        // [ColumnFamilyOptions::periodic_compaction_seconds]
+        let this = Arc::downgrade(&db.inner);
+        db.inner.scheduler.schedule_every(
+            periodic_compaction_check_interval(&db.inner.options),
+            move || {
+                if let Some(db) = this.upgrade() {
+                    db.schedule_compaction();
+                }
+            },
+        );
+
+        Ok(db)
+    }
+
+    /// Opens the database at `path` for reading only, e.g. while another process writes it
+    ///
+    /// Neither the lock of the directory nor any file is taken or written: the writes of the
+    /// write-ahead logs are replayed into memtables which are never flushed, and every write
+    /// fails. The database is seen as of the open, until [Db::try_catch_up_with_primary].
+    pub fn open_read_only<P: AsRef<Path>>(path: P, options: Options) -> Result<Db, DbError> {
+        options.validate()?;
+
+        let path = path.as_ref().to_path_buf();
+        let wal_dir = options.wal_dir(&path).to_path_buf();
+        let state = Db::load_read_only_state(&path, &options, &HashMap::new())?;
+
+        Ok(Db {
+            inner: Arc::new_cyclic(|this| DbInner {
+                this: this.clone(),
+                // Nothing is ever flushed nor compacted
+                scheduler: Scheduler::new(0, 0),
+                archive: WalArchive::new(&wal_dir, options.wal_retention),
+                blob_files: Arc::new(BlobFiles::new(&path)),
+                path,
+                wal_dir,
+                options,
+                snapshots: Arc::new(SnapshotList::new()),
+                background_work_cancelled: AtomicBool::new(false),
+                stall_cleared: Condvar::new(),
+                unordered_writes_visible: Condvar::new(),
+                compaction_done: Condvar::new(),
+                flush_done: Condvar::new(),
+                tracer: Mutex::new(None),
+                watchers: Watchers::default(),
+                key_locks: KeyLocks::new(NUM_KEY_LOCK_STRIPES),
+                lock_manager: LockManager::new(),
+                state: Mutex::new(state),
+                _lock: None,
+            }),
+        })
+    }
+
+    /// Reads the current state of the database at `path` from its manifest and logs, reusing the
+    /// tables of `open_tables` instead of opening them again
+    fn load_read_only_state(
+        path: &Path,
+        options: &Options,
+        open_tables: &HashMap<u64, Arc<Table>>,
+    ) -> Result<DbState, DbError> {
+        let versions = VersionSet::open_read_only(path)?
+            .ok_or(DbError::InvalidArgument("no database to open read-only"))?;
+        let column_families = open_column_families(path, options, &versions, open_tables)?;
+
+        let recovery = wal::recover(path, options, versions.log_number(), |id, log_number| {
+            recovered_mem(&column_families, versions.current(), id, log_number)
+        })?;
+
+        let last_sequence = recovery
+            .last_sequence
+            .unwrap_or(0)
+            .max(versions.last_sequence());
+
+        Ok(DbState {
+            column_families,
+            log_number: versions.log_number(),
+            versions,
+            wal: None,
+            recyclable_logs: Vec::new(),
+            last_sequence,
+            oldest_readable_sequence: 0,
+            unordered_writes: BTreeMap::new(),
+            write_buffer_usage: 0,
+            flushed_mems: Vec::new(),
+            persistent_snapshots: BTreeMap::new(),
+            background_errors: 0,
+            compacting: HashSet::new(),
+            pending_outputs: HashSet::new(),
+            running_compactions: 0,
+            flushing: false,
+            prepared: recovery.prepared,
+        })
+    }
+
+    /// Makes a database opened with [Db::open_read_only] see the writes made since by the process
+    /// which has it open for writing (the primary), by reading its manifest and logs again
+    ///
+    /// This can fail if the primary replaces the files being read meanwhile, in which case it's
+    /// fine to try again. The iterators and snapshots already taken are not affected.
+    pub fn try_catch_up_with_primary(&self) -> Result<(), DbError> {
+        let open_tables: HashMap<_, _> = {
+            let state = self.inner.state.lock().unwrap();
+
+            if state.wal.is_some() {
+                return Err(DbError::InvalidArgument(
+                    "only read-only databases catch up with a primary",
+                ));
+            }
+
+            state
+                .column_families
+                .values()
+                .flat_map(|data| &data.tables)
+                .map(|table| (table.number(), table.clone()))
+                .collect()
+        };
+
+        let new_state =
+            Db::load_read_only_state(&self.inner.path, &self.inner.options, &open_tables)?;
+
+        let mut state = self.inner.state.lock().unwrap();
+
+        // Another catch-up may have gone further meanwhile
+        if new_state.last_sequence >= state.last_sequence {
+            *state = new_state;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the log `log_number`, reusing a recyclable log if there's one
+    fn create_log(
+        wal_dir: &Path,
+        options: &Options,
+        recyclable_logs: &mut Vec<PathBuf>,
+        log_number: u64,
+    ) -> Result<wal::Writer, DbError> {
+        let path = wal::log_file_name(wal_dir, log_number);
+
+        let mut writer = match recyclable_logs.pop() {
+            Some(old_path) => {
+                wal::Writer::recycle(old_path, &path, log_number, options.wal_sync_policy)?
+            }
+            None => wal::Writer::create(&path, log_number, options.wal_sync_policy)?,
+        };
+
+        writer.set_compression_threshold(options.wal_compression_threshold);
+        writer.set_manual_flush(options.manual_wal_flush)?;
+
+        if options.wal_preallocate_size > 0 {
+            writer.preallocate(options.wal_preallocate_size)?;
+        }
+
+        sync_dir(wal_dir)?;
+
+        Ok(writer)
+    }
+
+    /// Saves the options in effect, including the ones of every column family, to the OPTIONS
+    /// file
+    fn write_options_file(&self, state: &DbState) -> Result<(), DbError> {
+        let column_families = state
+            .column_families
+            .values()
+            .map(|data| (data.handle.name(), data.options.as_ref()));
+
+        options::write_options_file(&self.inner.path, &self.inner.options, column_families)
+    }
+
+    /// Applies every mutation of `batch` atomically
+    ///
+    /// The keys of the batch are locked while it's written, so that it can't slip between the read
+    /// and the write of an [Db::update] of one of them. Range deletions are not locked.
+    pub fn write(&self, batch: WriteBatch) -> Result<(), DbError> {
+        self.write_with_options(batch, &WriteOptions::default())
+    }
+
+    /// Same as [Db::write], with the durability chosen by `write_options`
+    pub fn write_with_options(
+        &self,
+        batch: WriteBatch,
+        write_options: &WriteOptions,
+    ) -> Result<(), DbError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let keys = batch
+            .iter()
+            .filter_map(Result::ok)
+            .filter(|op| op.value_type != ValueType::RangeDeletion)
+            .map(|op| op.key);
+        let _guards = self.inner.key_locks.lock_all(keys);
+
+        self.write_locked(batch, write_options)
+    }
+
+    /// Same as [Db::write_with_options], for callers which already hold the locks of the keys of
+    /// `batch`
+    fn write_locked(&self, batch: WriteBatch, write_options: &WriteOptions) -> Result<(), DbError> {
+        self.write_committing(batch, write_options, None, None)
+    }
+
+    /// Applies `batch`, read from the logs of a primary database with
+    /// [Db::get_updates_since], at the sequence number `seq` it had there
+    ///
+    /// A replica applying every batch of its primary in order is a copy of it as of the last
+    /// batch, e.g. when starting from a [Db::checkpoint] of the primary, so that the column
+    /// families have the same ids. The batches already applied are skipped, so the updates can be
+    /// fetched again from [Db::latest_sequence_number] after a restart, while a batch leaving a
+    /// gap fails with [DbError::ReplicationGap]. The replica must not be written to otherwise.
+    pub fn apply_replicated_batch(
+        &self,
+        seq: SequenceNumber,
+        batch: WriteBatch,
+    ) -> Result<(), DbError> {
+        let keys: Vec<_> = batch
+            .iter()
+            .filter_map(Result::ok)
+            .filter(|op| op.value_type != ValueType::RangeDeletion)
+            .map(|op| op.key.to_vec())
+            .collect();
+        let _guards = self
+            .inner
+            .key_locks
+            .lock_all(keys.iter().map(Vec::as_slice));
+
+        self.write_committing(batch, &WriteOptions::default(), None, Some(seq))
+    }
+
+    /// Same as [Db::write_locked], the batch being the commit of the prepared transaction
+    /// `committed` if any, which isn't prepared anymore once the batch is in the log, and
+    /// getting the sequence number `replicated` if any, see [Db::apply_replicated_batch]
+    fn write_committing(
+        &self,
+        mut batch: WriteBatch,
+        write_options: &WriteOptions,
+        committed: Option<&str>,
+        replicated: Option<SequenceNumber>,
+    ) -> Result<(), DbError> {
+        if batch.is_empty() && committed.is_none() {
+            return Ok(());
+        }
+
+        let mut state = self.inner.state.lock().unwrap();
+        let mut stopped = false;
+
+        while let Some(stall) = state.write_stall() {
+            if write_options.no_slowdown
+                || self.inner.background_work_cancelled.load(Ordering::Relaxed)
+            {
+                return Err(DbError::WriteStalled);
+            }
+
+            match stall {
+                WriteStall::Delayed => {
+                    let delay = Duration::from_secs_f64(
+                        batch.data().len() as f64 / self.inner.options.delayed_write_rate as f64,
+                    );
+
+                    drop(state);
+                    std::thread::sleep(delay);
+                    state = self.inner.state.lock().unwrap();
+
+                    break;
+                }
+                WriteStall::Stopped => {
+                    if !stopped {
+                        log::warn!("writes are stopped until flushes or compactions catch up");
+                        stopped = true;
+                    }
+
+                    // Woken up early by compactions, the timeout only catches a cancellation
+                    // racing with the wait
+                    state = self
+                        .inner
+                        .stall_cleared
+                        .wait_timeout(state, Duration::from_millis(100))
+                        .unwrap()
+                        .0;
+                }
+            }
+        }
+
+        for op in batch.iter() {
+            let op = op?;
+            let data = state
+                .column_families
+                .get(&op.column_family)
+                .ok_or(DbError::InvalidArgument("column family was dropped"))?;
+
+            if data.options.user_timestamps && !timestamp::is_timestamped(&op) {
+                return Err(DbError::InvalidArgument(
+                    "writes to column families with user timestamps need a timestamp",
+                ));
+            }
+        }
+
+        let first_sequence = state.next_sequence();
+
+        match replicated {
+            Some(seq) if seq + batch.count() as u64 <= first_sequence => return Ok(()),
+            Some(seq) if seq != first_sequence => {
+                return Err(DbError::ReplicationGap {
+                    expected: first_sequence,
+                    received: seq,
+                })
+            }
+            _ => {}
+        }
+
+        batch.set_sequence(first_sequence);
+        // Traced in the order of the sequence numbers
+        self.trace(|| TraceOp::write(&batch));
+
+        let wal = state.wal()?;
+
+        if write_options.sync {
+            wal.add_record_with_sync(batch.data(), true)?;
+        } else if !write_options.disable_wal || committed.is_some() {
+            wal.add_record(batch.data())?;
+        }
+
+        // Logging the prepared transaction again in a new log would bring it back
+        if let Some(name) = committed {
+            state.prepared.remove(name);
+        }
+
+        if self.inner.options.unordered_write {
+            let mems: BTreeMap<u32, Arc<MemTable>> = state
+                .column_families
+                .iter()
+                .map(|(id, data)| (*id, data.mem.clone()))
+                .collect();
+
+            state
+                .unordered_writes
+                .insert(first_sequence, (batch.last_sequence(), false));
+            drop(state);
+
+            let inserted = batch.insert_into_column_families(|column_family_id| {
+                mems.get(&column_family_id).map(Arc::as_ref)
+            });
+
+            state = self.inner.state.lock().unwrap();
+            state.complete_unordered_write(first_sequence);
+            self.inner.unordered_writes_visible.notify_all();
+
+            // Read your own writes
+            while state.last_sequence < first_sequence {
+                state = self.inner.unordered_writes_visible.wait(state).unwrap();
+            }
+
+            inserted?;
+            self.inner.watchers.publish(&batch);
+        } else {
+            batch.insert_into_column_families(|column_family_id| {
+                state
+                    .column_families
+                    .get(&column_family_id)
+                    .map(|data| data.mem.as_ref())
+            })?;
+            state.last_sequence = batch.last_sequence();
+            self.inner.watchers.publish(&batch);
+        }
+
+        self.inner.charge_write_buffers(&mut state);
+
+        if !state.unordered_writes.is_empty() {
+            // The memtables can't be flushed until the last write in progress is inserted, which
+            // checks whether they're full
+            return Ok(());
+        }
+
+        if !state.memtables_to_flush(&self.inner.options).is_empty()
+            && !self.inner.background_work_cancelled.load(Ordering::Relaxed)
+        {
+            let over_cap = self
+                .inner
+                .options
+                .write_buffer_manager
+                .as_ref()
+                .is_some_and(|manager| manager.memory_usage() >= manager.buffer_size());
+
+            // The writes can't wait for the background flush once the memtables are over their
+            // cap
+            if over_cap {
+                drop(self.inner.flush_full_memtables(state)?);
+            } else {
+                self.inner.schedule_flush();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the handle of the default column family
+    pub fn default_cf(&self) -> ColumnFamily {
+        ColumnFamily::new(DEFAULT_COLUMN_FAMILY_ID, DEFAULT_COLUMN_FAMILY_NAME)
+    }
+
+    /// Returns the handle of the column family called `name`, if it exists
+    pub fn cf_handle(&self, name: &str) -> Option<ColumnFamily> {
+        let state = self.inner.state.lock().unwrap();
+
+        state
+            .column_families
+            .values()
+            .find(|data| data.handle.name() == name)
+            .map(|data| data.handle.clone())
+    }
+
+    /// Returns the handles of every column family, by id
+    pub fn cf_handles(&self) -> Vec<ColumnFamily> {
+        let state = self.inner.state.lock().unwrap();
+
+        state
+            .column_families
+            .values()
+            .map(|data| data.handle.clone())
+            .collect()
+    }
+
+    /// Returns the handle of the column family `id`, if it exists
+    pub(crate) fn cf_handle_by_id(&self, id: u32) -> Option<ColumnFamily> {
+        let state = self.inner.state.lock().unwrap();
+
+        state
+            .column_families
+            .get(&id)
+            .map(|data| data.handle.clone())
+    }
+
+    /// Creates a new, empty column family called `name`, with the options set for it in
+    /// [Options::cf_options] if any
+    pub fn create_cf(&self, name: &str) -> Result<ColumnFamily, DbError> {
+        self.create_cf_with_options(name, self.inner.options.column_family_options(name).clone())
+    }
+
+    /// Creates a new, empty column family called `name`, with the options `cf_options`
+    ///
+    /// The options are saved in the OPTIONS file, and can be reloaded with
+    /// [load_latest_options](crate::options::load_latest_options) for the next open.
+    pub fn create_cf_with_options(
+        &self,
+        name: &str,
+        cf_options: ColumnFamilyOptions,
+    ) -> Result<ColumnFamily, DbError> {
+        self.create_cf_with_import(name, cf_options, &ExportImportFilesMetaData::default())
+    }
+
+    /// Creates a new column family called `name`, with the options `cf_options`, made of the
+    /// tables exported by [Db::export_cf], possibly from another database
+    ///
+    /// The tables are hard links to the exported ones, or copies if they're on another file
+    /// system, and keep their levels. Their keys keep their sequence numbers too, the sequence
+    /// number of the database moving past them if needed, so the options must encode the keys
+    /// the same way as the ones of the exported column family, e.g. for user timestamps.
+    pub fn create_cf_with_import(
+        &self,
+        name: &str,
+        cf_options: ColumnFamilyOptions,
+        metadata: &ExportImportFilesMetaData,
+    ) -> Result<ColumnFamily, DbError> {
+        cf_options.validate()?;
+
+        if !column_family::is_valid_name(name) {
+            return Err(DbError::InvalidArgument("bad column family name"));
+        }
+
+        let cf_exists = |state: &DbState| {
+            state
+                .column_families
+                .values()
+                .any(|data| data.handle.name() == name)
+        };
+
+        // Level 0 tables are sorted by number, so the newest ones, listed first, get the largest
+        // numbers
+        let numbers: Vec<u64> = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.wal()?;
+
+            if cf_exists(&state) {
+                return Err(DbError::InvalidArgument("column family already exists"));
+            }
+
+            let mut numbers: Vec<_> = metadata
+                .files
+                .iter()
+                .map(|_| state.versions.new_file_number())
+                .collect();
+            numbers.reverse();
+            state.pending_outputs.extend(&numbers);
+
+            numbers
+        };
+
+        let imported: Result<Vec<_>, DbError> = metadata
+            .files
+            .iter()
+            .zip(&numbers)
+            .map(|(file, number)| {
+                if file.level >= NUM_LEVELS {
+                    return Err(DbError::InvalidArgument("bad level"));
+                }
+
+                let path = table::table_file_name(&self.inner.path, *number);
+                link_or_copy(&file.path, &path)?;
+
+                Ok((
+                    file.level,
+                    open_table(&path, *number, &cf_options, file.level)?,
+                ))
+            })
+            .collect();
+        let imported = imported.and_then(|tables| {
+            sync_dir(&self.inner.path)?;
+            Ok(tables)
+        });
+
+        let mut state = self.inner.state.lock().unwrap();
+
+        for number in &numbers {
+            state.pending_outputs.remove(number);
+        }
+
+        // The imported keys must be visible to the reads as of the last sequence number, which
+        // the unordered writes in progress would move back
+        while !state.unordered_writes.is_empty() {
+            state = self.inner.unordered_writes_visible.wait(state).unwrap();
+        }
+
+        let added = imported.and_then(|tables| {
+            if cf_exists(&state) {
+                return Err(DbError::InvalidArgument("column family already exists"));
+            }
+
+            let last_sequence = tables
+                .iter()
+                .map(|(_, table)| table.properties().largest_seqno)
+                .fold(state.last_sequence, SequenceNumber::max);
+            let handle = ColumnFamily::new(state.versions.next_column_family_id(), name);
+
+            let mut edit = VersionEdit {
+                next_column_family_id: Some(handle.id() + 1),
+                added_column_families: vec![(handle.id(), name.to_string())],
+                last_sequence: Some(last_sequence),
+                ..VersionEdit::default()
+            };
+
+            for (level, table) in &tables {
+                edit.add_file(handle.id(), *level, file_meta_data(table));
+            }
+
+            state.versions.log_and_apply(edit)?;
+            state.last_sequence = last_sequence;
+
+            Ok((handle, tables))
+        });
+
+        let (handle, tables) = match added {
+            Ok(added) => added,
+            Err(e) => {
+                self.inner.delete_obsolete_files(&mut state)?;
+                return Err(e);
+            }
+        };
+
+        // Reads look at the tables in the order of the version
+        let mut data = ColumnFamilyData::new(handle.clone(), cf_options);
+        data.mem_log_number = state.log_number;
+        let open_tables: HashMap<u64, Arc<Table>> = tables
+            .into_iter()
+            .map(|(_, table)| (table.number(), Arc::new(table)))
+            .collect();
+        data.tables = state
+            .versions
+            .current()
+            .column_family(handle.id())
+            .map(|files| {
+                files
+                    .files()
+                    .filter_map(|file| open_tables.get(&file.number).cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        state.column_families.insert(handle.id(), data);
+
+        if !numbers.is_empty() {
+            log::info!(
+                "imported {} tables as column family {}",
+                numbers.len(),
+                name
+            );
+            self.inner.schedule_compaction();
+        }
+
+        self.write_options_file(&state)?;
+
+        Ok(handle)
+    }
+
+    /// Drops the column family `cf` along with its contents
+    ///
+    /// The handles of the column family become unusable, while the iterators already reading it
+    /// keep working until they are dropped.
+    pub fn drop_cf(&self, cf: &ColumnFamily) -> Result<(), DbError> {
+        if cf.id() == DEFAULT_COLUMN_FAMILY_ID {
+            return Err(DbError::InvalidArgument(
+                "the default column family can't be dropped",
+            ));
+        }
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.wal()?;
+        state.column_family(cf)?;
+
+        state.versions.log_and_apply(VersionEdit {
+            dropped_column_families: vec![cf.id()],
+            ..VersionEdit::default()
+        })?;
+
+        // Its writes still in the log are skipped on recovery, the id being unknown
+        state.column_families.remove(&cf.id());
+
+        self.inner.delete_obsolete_files(&mut state)?;
+        self.write_options_file(&state)
+    }
+
+    /// Changes knobs of the default column family while the database is open, see
+    /// [Db::set_options_cf]
+    pub fn set_options(&self, options: &[(&str, &str)]) -> Result<(), DbError> {
+        self.set_options_cf(&self.default_cf(), options)
+    }
+
+    /// Changes knobs of the column family `cf` while the database is open, from (name, value)
+    /// pairs in the format of [ColumnFamilyOptions::set]
+    ///
+    /// Only the knobs in [MUTABLE_CF_OPTIONS] can be changed. Either all the changes are applied
+    /// or none is; they are effective from the next write, and saved in the OPTIONS file.
+    pub fn set_options_cf(
+        &self,
+        cf: &ColumnFamily,
+        options: &[(&str, &str)],
+    ) -> Result<(), DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.wal()?;
+
+        let mut cf_options = state.column_family(cf)?.options.as_ref().clone();
+
+        for (name, value) in options {
+            if !MUTABLE_CF_OPTIONS.contains(name) {
+                return Err(DbError::InvalidArgument(
+                    "option can't be changed on an open database",
+                ));
+            }
+
+            cf_options.set(name, value)?;
+        }
+
+        cf_options.validate()?;
+
+        // Readers keep the options they started with
+        state.column_families.get_mut(&cf.id()).unwrap().options = Arc::new(cf_options);
+
+        // The stall triggers may have been raised, and the compaction triggers lowered
+        self.inner.stall_cleared.notify_all();
+        self.inner.schedule_compaction();
+
+        self.write_options_file(&state)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        self.put_cf(&self.default_cf(), key, value)
+    }
+
+    pub fn put_cf(&self, cf: &ColumnFamily, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf(cf, key, value);
+
+        self.write(batch)
+    }
+
+    /// Writes `key` with the value read from `value` until its end, which is streamed to a blob
+    /// file of its own rather than held in memory: meant for values too large for [Db::put]
+    pub fn put_reader(&self, key: &[u8], value: impl Read) -> Result<(), DbError> {
+        self.put_reader_cf(&self.default_cf(), key, value)
+    }
+
+    /// Same as [Db::put_reader], in the column family `cf`
+    ///
+    /// The blob file is added to the column family before the write of the key, which points to
+    /// it. Read the value back with [Db::get_reader_cf_with_options] to keep it out of memory as
+    /// well.
+    pub fn put_reader_cf(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        mut value: impl Read,
+    ) -> Result<(), DbError> {
+        let number = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.wal()?;
+            state.column_family(cf)?;
+
+            let number = state.versions.new_file_number();
+            state.pending_outputs.insert(number);
+
+            number
+        };
+
+        let written = BlobFileBuilder::new(&self.inner.path, number).and_then(|mut builder| {
+            let index = builder.add_reader(key, &mut value)?;
+
+            Ok((index, builder.finish()?))
+        });
+
+        let index = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.pending_outputs.remove(&number);
+
+            let added = written.map_err(DbError::from).and_then(|(index, file)| {
+                state.column_family(cf)?;
+
+                let mut edit = VersionEdit::default();
+                edit.new_blob_files.push((cf.id(), file));
+                state.versions.log_and_apply(edit)?;
+
+                Ok(index)
+            });
+
+            match added {
+                Ok(index) => index,
+                Err(e) => {
+                    self.inner.delete_obsolete_files(&mut state)?;
+                    return Err(e);
+                }
+            }
+        };
+
+        let mut batch = WriteBatch::new();
+        batch.put_blob_index_cf(cf, key, &index);
+
+        self.write(batch)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), DbError> {
+        self.delete_cf(&self.default_cf(), key)
+    }
+
+    pub fn delete_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<(), DbError> {
+        let mut batch = WriteBatch::new();
+        batch.delete_cf(cf, key);
+
+        self.write(batch)
+    }
+
+    /// Deletes every key in [start, end) with a single range tombstone
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), DbError> {
+        self.delete_range_cf(&self.default_cf(), start, end)
+    }
+
+    /// Same as [Db::delete_range], in the column family `cf`
+    pub fn delete_range_cf(
+        &self,
+        cf: &ColumnFamily,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<(), DbError> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range_cf(cf, start, end);
+
+        self.write(batch)
+    }
+
+    /// Writes the version of `key` at the timestamp `ts`, in the column family `cf` which must
+    /// have [ColumnFamilyOptions::user_timestamps]
+    pub fn put_cf_with_ts(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        ts: Timestamp,
+        value: &[u8],
+    ) -> Result<(), DbError> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf_with_ts(cf, key, ts, value);
+
+        self.write(batch)
+    }
+
+    /// Deletes `key` as of the timestamp `ts`, in the column family `cf` which must have user
+    /// timestamps: the reads as of `ts` or later don't see it anymore, the older ones still do
+    pub fn delete_cf_with_ts(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        ts: Timestamp,
+    ) -> Result<(), DbError> {
+        let mut batch = WriteBatch::new();
+        batch.delete_cf_with_ts(cf, key, ts);
+
+        self.write(batch)
+    }
+
+    /// Writes `value` for `key`, which reads as deleted once `ttl` has elapsed
+    ///
+    /// The expiry has a precision of a second.
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<(), DbError> {
+        self.put_cf_with_ttl(&self.default_cf(), key, value, ttl)
+    }
+
+    /// Same as [Db::put_with_ttl], in the column family `cf`
+    pub fn put_cf_with_ttl(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        value: &[u8],
+        ttl: Duration,
+    ) -> Result<(), DbError> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf_with_ttl(cf, key, value, ttl);
+
+        self.write(batch)
+    }
+
+    /// Writes a merge operand for `key`, combined with the current value by
+    /// [ColumnFamilyOptions::merge_operator] when the key is read
+    pub fn merge(&self, key: &[u8], operand: &[u8]) -> Result<(), DbError> {
+        self.merge_cf(&self.default_cf(), key, operand)
+    }
+
+    /// Same as [Db::merge], in the column family `cf`
+    pub fn merge_cf(&self, cf: &ColumnFamily, key: &[u8], operand: &[u8]) -> Result<(), DbError> {
+        let state = self.inner.state.lock().unwrap();
+
+        if state.column_family(cf)?.options.merge_operator.is_none() {
+            return Err(DbError::InvalidArgument("no merge operator configured"));
+        }
+
+        drop(state);
+
+        let mut batch = WriteBatch::new();
+        batch.merge_cf(cf, key, operand);
+
+        self.write(batch)
+    }
+
+    /// Atomically replaces the value of `key` with the one computed by `f` from the current value,
+    /// deleting the key if `f` returns None
+    ///
+    /// Concurrent writers of the key wait for the update to complete, so no write is lost in
+    /// between. Returns the new value.
+    pub fn update<F>(&self, key: &[u8], f: F) -> Result<Option<Vec<u8>>, DbError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        self.update_cf(&self.default_cf(), key, f)
+    }
+
+    /// Same as [Db::update], in the column family `cf`
+    pub fn update_cf<F>(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        f: F,
+    ) -> Result<Option<Vec<u8>>, DbError>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let _guard = self.inner.key_locks.lock(key);
+
+        let old = self.get_cf(cf, key)?;
+        let new = f(old.as_deref());
+
+        let mut batch = WriteBatch::new();
+
+        match &new {
+            Some(value) => batch.put_cf(cf, key, value),
+            None => batch.delete_cf(cf, key),
+        }
+
+        self.write_locked(batch, &WriteOptions::default())?;
+
+        Ok(new)
+    }
+
+    /// Atomically sets `key` to `new` (deleting it if None), provided that its current value is
+    /// `expected` (None meaning that the key doesn't exist)
+    ///
+    /// Returns false, leaving the key untouched, if the current value is not the expected one.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, DbError> {
+        self.compare_and_swap_cf(&self.default_cf(), key, expected, new)
+    }
+
+    /// Same as [Db::compare_and_swap], in the column family `cf`
+    pub fn compare_and_swap_cf(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, DbError> {
+        let _guard = self.inner.key_locks.lock(key);
+
+        if self.get_cf(cf, key)?.as_deref() != expected {
+            return Ok(false);
+        }
+
+        let mut batch = WriteBatch::new();
+
+        match new {
+            Some(value) => batch.put_cf(cf, key, value),
+            None => batch.delete_cf(cf, key),
+        }
+
+        self.write_locked(batch, &WriteOptions::default())?;
+
+        Ok(true)
+    }
+
+    /// Returns the oldest sequence number which can be read as of with
+    /// [ReadOptions::sequence], seeing every key as it was then
+    ///
+    /// The versions of the keys older than this may have been dropped, unless a [Snapshot] kept
+    /// them.
+    pub fn oldest_readable_sequence_number(&self) -> SequenceNumber {
+        self.inner.state.lock().unwrap().oldest_readable_sequence
+    }
+
+    /// Returns the sequence number of the last write, which survives restarts: the writes made
+    /// after reopening the database always get greater ones
+    pub fn latest_sequence_number(&self) -> SequenceNumber {
+        self.inner.state.lock().unwrap().last_sequence
+    }
+
+    /// Returns a snapshot of the database as of now, which keeps seeing the same data no matter
+    /// the writes that come after it
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.inner.state.lock().unwrap();
+
+        self.inner.snapshots.acquire(state.last_sequence)
+    }
+
+    /// Starts a [Transaction] with the default [TransactionOptions]: snapshot isolation
+    pub fn transaction(&self) -> Transaction<'_> {
+        self.transaction_with_options(TransactionOptions::default())
+    }
+
+    /// Starts a [Transaction] isolated from the concurrent writes as chosen by `options`
+    pub fn transaction_with_options(&self, options: TransactionOptions) -> Transaction<'_> {
+        Transaction::new(self, options)
+    }
+
+    /// Returns the transactions prepared but neither committed nor rolled back, e.g. before the
+    /// database was last closed, to commit or roll back
+    pub fn prepared_transactions(&self) -> Result<Vec<Transaction<'_>>, DbError> {
+        let prepared = self.inner.state.lock().unwrap().prepared.clone();
+
+        prepared
+            .into_iter()
+            .map(|(name, batch)| Transaction::recovered(self, name, batch))
+            .collect()
+    }
+
+    /// Writes `batch`, the writes of a transaction, unless one of the `tracked` keys by column
+    /// family was written after its sequence number
+    pub(crate) fn commit_transaction(
+        &self,
+        batch: WriteBatch,
+        tracked: &HashMap<(u32, Vec<u8>), SequenceNumber>,
+        write_options: &WriteOptions,
+    ) -> Result<(), DbError> {
+        let _guards = self
+            .inner
+            .key_locks
+            .lock_all(tracked.keys().map(|(_, key)| key.as_slice()));
+
+        self.check_conflicts(tracked)?;
+        self.write_locked(batch, write_options)
+    }
+
+    /// Logs `batch`, the writes of the transaction `name`, as prepared, unless one of the
+    /// `tracked` keys by column family was written after its sequence number
+    ///
+    /// The keys of the batch conflict with the ones of the other transactions until it commits
+    /// or rolls back.
+    pub(crate) fn prepare_transaction(
+        &self,
+        name: &str,
+        batch: &WriteBatch,
+        tracked: &HashMap<(u32, Vec<u8>), SequenceNumber>,
+        write_options: &WriteOptions,
+    ) -> Result<(), DbError> {
+        let _guards = self
+            .inner
+            .key_locks
+            .lock_all(tracked.keys().map(|(_, key)| key.as_slice()));
+
+        self.check_conflicts(tracked)?;
+
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.prepared.contains_key(name) {
+            return Err(DbError::InvalidArgument(
+                "a transaction with this name is already prepared",
+            ));
+        }
+
+        let mut record = batch.clone();
+        record.set_marker(Marker::Prepare, name);
+        state
+            .wal()?
+            .add_record_with_sync(record.data(), write_options.sync)?;
+        state.prepared.insert(name.to_string(), batch.clone());
+
+        Ok(())
+    }
+
+    /// Writes `batch`, the writes of the prepared transaction `name`
+    pub(crate) fn commit_prepared_transaction(
+        &self,
+        name: &str,
+        mut batch: WriteBatch,
+        write_options: &WriteOptions,
+    ) -> Result<(), DbError> {
+        let keys: Vec<_> = batch
+            .iter()
+            .filter_map(Result::ok)
+            .map(|op| op.key.to_vec())
+            .collect();
+        let _guards = self
+            .inner
+            .key_locks
+            .lock_all(keys.iter().map(Vec::as_slice));
+
+        batch.set_marker(Marker::Commit, name);
+        self.write_committing(batch, write_options, Some(name), None)
+    }
+
+    /// Discards the writes of the prepared transaction `name`
+    pub(crate) fn rollback_prepared_transaction(&self, name: &str) -> Result<(), DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+        let mut record = WriteBatch::new();
+
+        record.set_marker(Marker::Rollback, name);
+        state.wal()?.add_record(record.data())?;
+        state.prepared.remove(name);
+
+        Ok(())
+    }
+
+    /// Returns a new id for a transaction
+    pub(crate) fn new_transaction_id(&self) -> u64 {
+        self.inner.lock_manager.new_id()
+    }
+
+    /// Locks `key` of `cf` for the pessimistic transaction `id`, see [LockManager::lock]
+    pub(crate) fn lock_key(
+        &self,
+        id: u64,
+        cf: &ColumnFamily,
+        key: &[u8],
+        options: &TransactionOptions,
+    ) -> Result<(), DbError> {
+        self.inner.lock_manager.lock(
+            id,
+            cf.id(),
+            key,
+            options.lock_timeout,
+            options.deadlock_detect,
+        )
+    }
+
+    /// Unlocks the keys of the pessimistic transaction `id`
+    pub(crate) fn unlock_keys(&self, id: u64) {
+        self.inner.lock_manager.unlock_all(id);
+    }
+
+    /// Fails with [DbError::Conflict] if one of the `tracked` keys by column family was written
+    /// after its sequence number, or is written by a prepared transaction
+    pub(crate) fn check_conflicts(
+        &self,
+        tracked: &HashMap<(u32, Vec<u8>), SequenceNumber>,
+    ) -> Result<(), DbError> {
+        for ((column_family, key), seq) in tracked {
+            let view = {
+                let state = self.inner.state.lock().unwrap();
+                let data = state
+                    .column_families
+                    .get(column_family)
+                    .ok_or(DbError::InvalidArgument("column family was dropped"))?;
+
+                let prepared = state.prepared.values().any(|batch| {
+                    batch
+                        .iter()
+                        .filter_map(Result::ok)
+                        .any(|op| op.column_family == *column_family && op.key == key)
+                });
+
+                if prepared {
+                    return Err(DbError::Conflict);
+                }
+
+                ReadView::new(data, &state)
+            };
+
+            if view
+                .latest_sequence(key)?
+                .is_some_and(|latest| latest > *seq)
+            {
+                return Err(DbError::Conflict);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a snapshot of the database as of now, like [Db::snapshot], also recorded in the
+    /// manifest under `name`: it survives restarts, keeping the versions of the keys it sees from
+    /// being dropped until [Db::release_persistent_snapshot]
+    pub fn create_persistent_snapshot(&self, name: &str) -> Result<Snapshot, DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.wal()?;
+
+        if state.versions.snapshots().contains_key(name) {
+            return Err(DbError::InvalidArgument("snapshot already exists"));
+        }
+
+        let snapshot = self.inner.snapshots.acquire(state.last_sequence);
+
+        state.versions.log_and_apply(VersionEdit {
+            added_snapshots: vec![(name.to_string(), snapshot.sequence())],
+            ..VersionEdit::default()
+        })?;
+        state
+            .persistent_snapshots
+            .insert(name.to_string(), snapshot.clone());
+
+        Ok(snapshot)
+    }
+
+    /// Returns the persistent snapshot called `name`, if any
+    pub fn persistent_snapshot(&self, name: &str) -> Option<Snapshot> {
+        let state = self.inner.state.lock().unwrap();
+        let seq = state.versions.snapshots().get(name)?;
+
+        Some(self.inner.snapshots.acquire(*seq))
+    }
+
+    /// Returns the names of the persistent snapshots, in order
+    pub fn persistent_snapshot_names(&self) -> Vec<String> {
+        let state = self.inner.state.lock().unwrap();
+
+        state.versions.snapshots().keys().cloned().collect()
+    }
+
+    /// Removes the persistent snapshot called `name` from the manifest
+    ///
+    /// The handles of the snapshot keep working until they are dropped.
+    pub fn release_persistent_snapshot(&self, name: &str) -> Result<(), DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.wal()?;
+
+        if !state.versions.snapshots().contains_key(name) {
+            return Err(DbError::InvalidArgument("no such snapshot"));
+        }
+
+        state.versions.log_and_apply(VersionEdit {
+            released_snapshots: vec![name.to_string()],
+            ..VersionEdit::default()
+        })?;
+        state.persistent_snapshots.remove(name);
+
+        Ok(())
+    }
+
+    /// Returns the current value of `key`, if any
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        self.get_cf(&self.default_cf(), key)
+    }
+
+    /// Returns the current value of `key` in the column family `cf`, if any
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        self.get_cf_with_options(cf, key, &ReadOptions::default())
+    }
+
+    /// Returns the value `key` had when `snapshot` was taken, if any
+    pub fn get_at(&self, key: &[u8], snapshot: &Snapshot) -> Result<Option<Vec<u8>>, DbError> {
+        self.get_at_cf(&self.default_cf(), key, snapshot)
+    }
+
+    /// Returns the value `key` had in the column family `cf` when `snapshot` was taken, if any
+    pub fn get_at_cf(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        snapshot: &Snapshot,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        self.get_cf_with_options(
+            cf,
+            key,
+            &ReadOptions {
+                snapshot: Some(snapshot.clone()),
+                ..ReadOptions::default()
+            },
+        )
+    }
+
+    /// Returns the value of `key` as restricted by `read_options`, if any
+    ///
+    /// The iteration bounds of `read_options` are ignored.
+    pub fn get_with_options(
+        &self,
+        key: &[u8],
+        read_options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        self.get_cf_with_options(&self.default_cf(), key, read_options)
+    }
+
+    /// Same as [Db::get_with_options], in the column family `cf`
+    pub fn get_cf_with_options(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        read_options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        let (options, result, ctx) = self.lookup(cf, key, read_options, false)?;
+
+        self.finish_get(&options, key, result, ctx)
+    }
+
+    /// Same as [Db::get], but the value borrows the data block it's found in rather than being
+    /// copied, which saves copying large values
+    ///
+    /// The values found in the memtable or merged from operands are owned, as usual.
+    pub fn get_pinned(&self, key: &[u8]) -> Result<Option<PinnedValue>, DbError> {
+        self.get_pinned_cf_with_options(&self.default_cf(), key, &ReadOptions::default())
+    }
+
+    /// Same as [Db::get_pinned], in the column family `cf` and as restricted by `read_options`
+    pub fn get_pinned_cf_with_options(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        read_options: &ReadOptions,
+    ) -> Result<Option<PinnedValue>, DbError> {
+        let (options, mut result, mut ctx) = self.lookup(cf, key, read_options, true)?;
+
+        if let (Some(pinned), Some(LookupResult::Value(value))) = (ctx.pinned.take(), &mut result) {
+            if ctx.operands.is_empty() {
+                return Ok(Some(pinned));
+            }
+
+            *value = pinned.to_vec();
+        }
+
+        Ok(self
+            .finish_get(&options, key, result, ctx)?
+            .map(PinnedValue::from))
+    }
+
+    /// Returns a reader of the value of `key`, if any, which only reads the values in blob files
+    /// a chunk at a time, see [Db::put_reader]
+    pub fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader>, DbError> {
+        self.get_reader_cf_with_options(&self.default_cf(), key, &ReadOptions::default())
+    }
+
+    /// Same as [Db::get_reader], in the column family `cf` and as restricted by `read_options`
+    ///
+    /// The reader keeps the blob file open, so the value stays readable even once overwritten.
+    /// The values with merge operands are merged in memory.
+    pub fn get_reader_cf_with_options(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        read_options: &ReadOptions,
+    ) -> Result<Option<ValueReader>, DbError> {
+        let (options, result, mut ctx, _version) = self.find(cf, key, read_options, false)?;
+
+        if ctx.operands.is_empty() {
+            if let Some(index) = &ctx.blob_index {
+                let reader = self.inner.blob_files.reader(&BlobIndex::decode(index)?)?;
+
+                return Ok(Some(ValueReader::Blob(reader)));
+            }
+        }
+
+        let result = self.read_blob(result, &mut ctx)?;
+
+        Ok(self
+            .finish_get(&options, key, result, ctx)?
+            .map(|value| ValueReader::InMemory(Cursor::new(value))))
+    }
+
+    /// Looks `key` up in the memtable then in the tables, the value found kept in its data block
+    /// if `pin_value`, see [GetContext::pin_value]
+    fn lookup(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        read_options: &ReadOptions,
+        pin_value: bool,
+    ) -> Result<(Arc<ColumnFamilyOptions>, Option<LookupResult>, GetContext), DbError> {
+        let (options, result, mut ctx, _version) = self.find(cf, key, read_options, pin_value)?;
+        let result = self.read_blob(result, &mut ctx)?;
+
+        Ok((options, result, ctx))
+    }
+
+    /// Same as [Db::lookup], leaving the value found in a blob file unread: the version returned
+    /// must be held until it is, see [Db::read_blob]
+    #[allow(clippy::type_complexity)]
+    fn find(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        read_options: &ReadOptions,
+        pin_value: bool,
+    ) -> Result<
+        (
+            Arc<ColumnFamilyOptions>,
+            Option<LookupResult>,
+            GetContext,
+            Arc<Version>,
+        ),
+        DbError,
+    > {
+        let _timer = perf_context::timer(|ctx, elapsed| {
+            ctx.get_count += 1;
+            ctx.get_time += elapsed;
+        });
+
+        self.trace(|| TraceOp::Get {
+            column_family: cf.id(),
+            key: key.to_vec(),
+        });
+
+        let view = self.inner.state.lock().unwrap().read_view(cf)?;
+        let seq = view.sequence(read_options);
+        let ReadView {
+            options,
+            mems,
+            tables,
+            version,
+            ..
+        } = view;
+
+        let mut ctx = GetContext {
+            pin_value,
+            ..GetContext::default()
+        };
+        let mut result = {
+            let _timer = perf_context::timer(|ctx, elapsed| ctx.get_from_memtable_time += elapsed);
+
+            mems.iter()
+                .find_map(|mem| mem.get_with_context(key, seq, &mut ctx))
+        };
+
+        for table in tables {
+            if result.is_some() {
+                break;
+            }
+
+            result = table.get_with_context(key, seq, &mut ctx, read_options)?;
+        }
+
+        Ok((options, result, ctx, version))
+    }
+
+    /// Reads the value of a lookup which found a blob index, see [GetContext::blob_index]
+    ///
+    /// The version of the lookup must still be held, so that the blob file is still there.
+    fn read_blob(
+        &self,
+        result: Option<LookupResult>,
+        ctx: &mut GetContext,
+    ) -> Result<Option<LookupResult>, DbError> {
+        match ctx.blob_index.take() {
+            Some(index) => {
+                let value = self.inner.blob_files.get(&BlobIndex::decode(&index)?)?;
+
+                Ok(Some(LookupResult::Value(value)))
+            }
+            None => Ok(result),
+        }
+    }
+
+    /// Tells whether `key` may exist, only looking at the memtable and at the key ranges and
+    /// filters of the tables, without any I/O
+    ///
+    /// False positives are possible, false negatives are not: useful to skip the deletion or the
+    /// update of a key which is surely not there.
+    pub fn key_may_exist(&self, key: &[u8]) -> KeyMayExist {
+        let view = {
+            let state = self.inner.state.lock().unwrap();
+
+            ReadView::new(state.default_column_family(), &state)
+        };
+
+        key_may_exist_in(view, key)
+    }
+
+    /// Same as [Db::key_may_exist], in the column family `cf`
+    pub fn key_may_exist_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<KeyMayExist, DbError> {
+        let view = self.inner.state.lock().unwrap().read_view(cf)?;
+
+        Ok(key_may_exist_in(view, key))
+    }
+
+    /// Estimates the bytes taken by the keys of each of `ranges`, from the index blocks of the
+    /// tables (and by the memtable too if `include_memtable`)
+    ///
+    /// The estimates are in data block granularity, and don't account for the versions of the
+    /// keys hidden by newer ones.
+    pub fn approximate_sizes(
+        &self,
+        ranges: &[Range<&[u8]>],
+        include_memtable: bool,
+    ) -> Result<Vec<u64>, DbError> {
+        self.approximate_sizes_cf(&self.default_cf(), ranges, include_memtable)
+    }
+
+    /// Same as [Db::approximate_sizes], in the column family `cf`
+    pub fn approximate_sizes_cf(
+        &self,
+        cf: &ColumnFamily,
+        ranges: &[Range<&[u8]>],
+        include_memtable: bool,
+    ) -> Result<Vec<u64>, DbError> {
+        let ReadView { mems, tables, .. } = self.inner.state.lock().unwrap().read_view(cf)?;
+
+        ranges
+            .iter()
+            .map(|range| {
+                let mut size = 0;
+
+                for table in &tables {
+                    if table.overlaps(Some(range.start), Some(range.end)) {
+                        size += table.approximate_range_size(range.start, range.end)?;
+                    }
+                }
+
+                if include_memtable {
+                    size += mems
+                        .iter()
+                        .map(|mem| mem.approximate_range_size(range.start, range.end))
+                        .sum::<u64>();
+                }
+
+                Ok(size)
+            })
+            .collect()
+    }
+
+    /// Returns the value of the property called `name` of the default column family, or None if
+    /// there's no such property, see [Db::get_property_cf]
+    pub fn get_property(&self, name: &str) -> Option<String> {
+        // The default column family can't be dropped
+        self.get_property_cf(&self.default_cf(), name)
+            .ok()
+            .flatten()
+    }
+
+    /// Returns the value of the property called `name` of the column family `cf`, or None if
+    /// there's no such property
+    ///
+    /// The properties are meant for dashboards and debugging:
+    /// - `fyodor.num-files-at-level<N>`: number of tables at level N
+    /// - `fyodor.levelstats`: number of tables and bytes of every level, as a table
+    /// - `fyodor.compaction-stats`: [Db::compaction_stats_cf] of every level, as a table
+    /// - `fyodor.total-sst-files-size`: bytes of all the tables
+    /// - `fyodor.cur-size-active-mem-table`: approximate bytes of the memtable
+    /// - `fyodor.num-entries-active-mem-table`: number of entries of the memtable
+    /// - `fyodor.num-immutable-mem-table`: number of full memtables waiting to be flushed
+    /// - `fyodor.estimate-num-keys`: approximate number of keys, counting neither the
+    ///   overwritten nor the deleted ones
+    /// - `fyodor.estimate-pending-compaction-bytes`: approximate bytes compactions have to
+    ///   rewrite to bring every level back under its target size
+    /// - `fyodor.background-errors`: number of flushes and compactions which failed in the
+    ///   background, for the whole database
+    /// - `fyodor.num-running-compactions`: number of compactions writing their tables, for the
+    ///   whole database
+    pub fn get_property_cf(
+        &self,
+        cf: &ColumnFamily,
+        name: &str,
+    ) -> Result<Option<String>, DbError> {
+        let state = self.inner.state.lock().unwrap();
+        let data = state.column_family(cf)?;
+        let version = state.versions.current();
+        let levels = match version.column_family(cf.id()) {
+            Some(files) => files.levels.as_slice(),
+            None => &[],
+        };
+
+        if let Some(level) = name.strip_prefix("fyodor.num-files-at-level") {
+            return Ok(level
+                .parse::<usize>()
+                .ok()
+                .and_then(|level| levels.get(level))
+                .map(|files| files.len().to_string()));
+        }
+
+        let value = match name {
+            "fyodor.levelstats" => {
+                let mut stats = String::from("Level Files Size(MB)\n");
+
+                for (level, files) in levels.iter().enumerate() {
+                    stats.push_str(&format!(
+                        "{:>5} {:>5} {:>8.1}\n",
+                        level,
+                        files.len(),
+                        level_size(files) as f64 / (1 << 20) as f64
+                    ));
+                }
+
+                stats
+            }
+            "fyodor.compaction-stats" => {
+                let mut stats = String::from(
+                    "Level Files Size(MB) Rn(MB) Rnp1(MB) Write(MB) Moved(MB) W-Amp R-Amp \
+                     Comp(sec) Comp(cnt) Wr(MB/s)\n",
+                );
+                let mb = |bytes: u64| bytes as f64 / (1 << 20) as f64;
+
+                for level in level_compaction_stats(data, levels) {
+                    stats.push_str(&format!(
+                        "{:>5} {:>5} {:>8.1} {:>6.1} {:>8.1} {:>9.1} {:>9.1} {:>5.1} {:>5.1} \
+                         {:>9.3} {:>9} {:>8.1}\n",
+                        level.level,
+                        level.num_files,
+                        mb(level.size),
+                        mb(level.bytes_read_input_levels),
+                        mb(level.bytes_read_output_level),
+                        mb(level.bytes_written),
+                        mb(level.bytes_moved),
+                        level.write_amplification(),
+                        level.read_amplification(),
+                        level.time.as_secs_f64(),
+                        level.count,
+                        level.write_throughput() / (1 << 20) as f64,
+                    ));
+                }
+
+                stats
+            }
+            "fyodor.total-sst-files-size" => levels
+                .iter()
+                .map(|files| level_size(files))
+                .sum::<u64>()
+                .to_string(),
+            "fyodor.background-errors" => state.background_errors.to_string(),
+            "fyodor.num-running-compactions" => state.running_compactions.to_string(),
+            "fyodor.cur-size-active-mem-table" => data.mem.approximate_memory_usage().to_string(),
+            "fyodor.num-entries-active-mem-table" => data.mem.len().to_string(),
+            "fyodor.num-immutable-mem-table" => data.imm.len().to_string(),
+            "fyodor.estimate-num-keys" => {
+                let properties = data.tables.iter().map(|table| table.properties());
+                let entries: u64 = properties.clone().map(|p| p.num_entries).sum();
+                let deletions: u64 = properties.map(|p| p.num_deletions).sum();
+
+                let mem_entries: usize = data.mems().map(|mem| mem.len()).sum();
+
+                // A deletion hides a key on top of not being one
+                (mem_entries as u64 + entries)
+                    .saturating_sub(2 * deletions)
+                    .to_string()
+            }
+            "fyodor.estimate-pending-compaction-bytes" => {
+                estimate_pending_compaction_bytes(levels, &data.options).to_string()
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(value))
+    }
+
+    /// Returns the statistics of the flushes and compactions of every level of the default
+    /// column family since the database was opened
+    pub fn compaction_stats(&self) -> Vec<CompactionStats> {
+        // The default column family can't be dropped
+        self.compaction_stats_cf(&self.default_cf())
+            .unwrap_or_default()
+    }
+
+    /// Returns the statistics of the flushes and compactions of every level of the column family
+    /// `cf` since the database was opened, to tune its options by
+    pub fn compaction_stats_cf(&self, cf: &ColumnFamily) -> Result<Vec<CompactionStats>, DbError> {
+        let state = self.inner.state.lock().unwrap();
+        let data = state.column_family(cf)?;
+        let levels = match state.versions.current().column_family(cf.id()) {
+            Some(files) => files.levels.as_slice(),
+            None => &[],
+        };
+
+        Ok(level_compaction_stats(data, levels))
+    }
+
+    /// Describes every table of the current version of the database, by column family then by
+    /// level
+    ///
+    /// The files can be copied (e.g. for backups) while the database is open, as long as they
+    /// are not deleted in the meantime: see [Db::flush] and the compactions.
+    pub fn live_files(&self) -> Vec<LiveFileMetaData> {
+        let state = self.inner.state.lock().unwrap();
+        let mut files = Vec::new();
+
+        for (id, data) in &state.column_families {
+            if let Some(cf_files) = state.versions.current().column_family(*id) {
+                files.extend(column_family_live_files(&self.inner.path, data, cf_files));
+            }
+        }
+
+        files
+    }
+
+    /// Returns the memory used by the database, so that it can be accounted for in the budget
+    /// of the process
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut state = self.inner.state.lock().unwrap();
+        state.flushed_mems.retain(|mem| mem.strong_count() > 0);
+
+        let pinned_mem_tables = state
+            .flushed_mems
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|mem| mem.approximate_memory_usage())
+            .sum();
+
+        let column_families = state.column_families.values();
+        let block_caches = distinct(
+            column_families
+                .clone()
+                .filter_map(|data| data.options.block_cache.as_ref()),
+        );
+        let row_caches = distinct(
+            column_families
+                .clone()
+                .filter_map(|data| data.options.row_cache.as_ref()),
+        );
+
+        MemoryUsage {
+            mem_tables: column_families
+                .clone()
+                .flat_map(|data| data.mems())
+                .map(|mem| mem.approximate_memory_usage())
+                .sum(),
+            pinned_mem_tables,
+            table_readers: column_families
+                .flat_map(|data| &data.tables)
+                .map(|table| table.approximate_memory_usage())
+                .sum(),
+            block_cache: block_caches
+                .iter()
+                .map(|cache| {
+                    let secondary = cache.secondary_cache().map_or(0, |cache| cache.usage());
+                    cache.usage() + secondary
+                })
+                .sum(),
+            row_cache: row_caches.iter().map(|cache| cache.usage()).sum(),
+        }
+    }
+
+    /// Writes the blocks of the tables of the database held by their block caches to the file at
+    /// `path`, and returns how many, so that [Db::load_block_cache] warms the caches up with them
+    /// after a restart
+    ///
+    /// The file holds a `<file number> <offset>` line per block, from the blocks most likely to
+    /// be evicted first. It's written under a temporary name and renamed once complete.
+    pub fn dump_block_cache(&self, path: &Path) -> Result<usize, DbError> {
+        let tables = self.all_tables();
+        let numbers: HashMap<_, _> = tables
+            .iter()
+            .filter_map(|table| {
+                let (cache, id) = table.block_cache()?;
+                Some(((Arc::as_ptr(cache), *id), table.number()))
+            })
+            .collect();
+        let mut contents = String::new();
+        let mut count = 0;
+
+        for cache in distinct(
+            tables
+                .iter()
+                .filter_map(|table| Some(&table.block_cache()?.0)),
+        ) {
+            for (id, offset) in cache.keys() {
+                if let Some(number) = numbers.get(&(Arc::as_ptr(cache), id)) {
+                    contents.push_str(&format!("{} {}\n", number, offset));
+                    count += 1;
+                }
+            }
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+
+        Ok(count)
+    }
+
+    /// Reads the blocks listed in the file at `path` by [Db::dump_block_cache] into the block
+    /// caches, e.g. right after opening the database, and returns how many
+    ///
+    /// The blocks of the tables deleted since the dump are skipped.
+    pub fn load_block_cache(&self, path: &Path) -> Result<usize, DbError> {
+        let bad_dump = || DbError::InvalidArgument("bad block cache dump");
+        let mut offsets: HashMap<u64, HashSet<u64>> = HashMap::new();
+
+        for line in std::fs::read_to_string(path)?.lines() {
+            let (number, offset) = line.split_once(' ').ok_or_else(bad_dump)?;
+            offsets
+                .entry(number.parse().map_err(|_| bad_dump())?)
+                .or_default()
+                .insert(offset.parse().map_err(|_| bad_dump())?);
+        }
+
+        let mut loaded = 0;
+
+        for table in self.all_tables() {
+            match offsets.get(&table.number()) {
+                Some(offsets) if table.block_cache().is_some() => {
+                    loaded += table.warm_block_cache(offsets)?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    /// Creates at `dir`, which must not exist, a copy of the database as of now, which opens
+    /// like any database
+    ///
+    /// The tables and blob files are hard links to the ones of the database, so the checkpoint
+    /// takes little space until compactions replace them, or copies if `dir` is on another file
+    /// system. The logs holding the writes not in tables yet are copied while the writes wait,
+    /// which is short since the memtables bound their size, and the manifest is written afresh
+    /// from the current version. The checkpoint is built under a temporary name and renamed once
+    /// complete.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> Result<(), DbError> {
+        let dir = dir.as_ref();
+
+        if dir.exists() {
+            return Err(DbError::InvalidArgument(
+                "checkpoint directory already exists",
+            ));
+        }
+
+        create_dir_atomically(dir, |tmp_dir| {
+            let files = self.write_checkpoint(tmp_dir)?;
+
+            for (path, name) in &files.files {
+                link_or_copy(path, &tmp_dir.join(name))?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Exports the tables of the column family `cf` to `dir`, which must not exist, once its
+    /// memtable is flushed, so that another database can import them as a column family of its
+    /// own with [Db::create_cf_with_import]
+    ///
+    /// The tables are hard links to the ones of the database, or copies if `dir` is on another
+    /// file system. Column families with blob files can't be exported, since their tables point
+    /// to the blob files by number.
+    pub fn export_cf<P: AsRef<Path>>(
+        &self,
+        cf: &ColumnFamily,
+        dir: P,
+    ) -> Result<ExportImportFilesMetaData, DbError> {
+        let dir = dir.as_ref();
+
+        if dir.exists() {
+            return Err(DbError::InvalidArgument("export directory already exists"));
+        }
+
+        self.flush()?;
+
+        // Holding the version keeps its files from being deleted
+        let (_version, mut files) = {
+            let state = self.inner.state.lock().unwrap();
+            let data = state.column_family(cf)?;
+            let version = state.versions.current().clone();
+            let cf_files = version
+                .column_family(cf.id())
+                .ok_or(DbError::InvalidArgument("column family was dropped"))?;
+
+            if !cf_files.blob_files.is_empty() {
+                return Err(DbError::InvalidArgument(
+                    "column families with blob files can't be exported",
+                ));
+            }
+
+            let files = column_family_live_files(&self.inner.path, data, cf_files);
+
+            (version, files)
+        };
+
+        create_dir_atomically(dir, |tmp_dir| {
+            for file in &files {
+                link_or_copy(&file.path, &tmp_dir.join(file.path.file_name().unwrap()))?;
+            }
+
+            Ok(())
+        })?;
+
+        for file in &mut files {
+            file.path = dir.join(file.path.file_name().unwrap());
+        }
+
+        log::info!(
+            "exported {} tables of column family {} to {}",
+            files.len(),
+            cf.name(),
+            dir.display()
+        );
+
+        Ok(ExportImportFilesMetaData { files })
+    }
+
+    /// Writes the logs, the OPTIONS file and the manifest of a [Db::checkpoint] to `dir`, and
+    /// returns the tables and blob files it needs too
+    pub(crate) fn write_checkpoint(&self, dir: &Path) -> Result<CheckpointFiles, DbError> {
+        // The checkpoint keeps its logs next to its tables
+        let options = Options {
+            wal_dir: None,
+            ..self.inner.options.clone()
+        };
+
+        let (version, edit) = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.wal()?.flush(false)?;
+
+            for log_number in wal::list_logs(&self.inner.wal_dir)? {
+                if log_number >= state.versions.log_number() {
+                    let to = wal::log_file_name(dir, log_number);
+                    std::fs::copy(wal::log_file_name(&self.inner.wal_dir, log_number), &to)?;
+                    File::open(&to)?.sync_all()?;
+                }
+            }
+
+            let column_families = state
+                .column_families
+                .values()
+                .map(|data| (data.handle.name(), data.options.as_ref()));
+            options::write_options_file(dir, &options, column_families)?;
+
+            // Holding the version keeps its files from being deleted
+            (state.versions.current().clone(), state.versions.snapshot())
+        };
+
+        let paths = version.column_families().flat_map(|(_, files)| {
+            files
+                .files()
+                .map(|file| table::table_file_name(&self.inner.path, file.number))
+                .chain(
+                    files
+                        .blob_files
+                        .keys()
+                        .map(|number| blob::blob_file_name(&self.inner.path, *number)),
+                )
+        });
+        let files = paths
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (path, name)
+            })
+            .collect();
+
+        VersionSet::create(dir, &edit)?;
+
+        Ok(CheckpointFiles {
+            files,
+            _version: version,
+        })
+    }
+
+    /// Returns the tables of every column family
+    fn all_tables(&self) -> Vec<Arc<Table>> {
+        let state = self.inner.state.lock().unwrap();
+
+        state
+            .column_families
+            .values()
+            .flat_map(|data| data.tables.iter().cloned())
+            .collect()
+    }
+
+    /// Returns the current values of `keys`, in the same order, as of a single point in time
+    ///
+    /// Cheaper than a [Db::get] per key: the keys are looked up in sorted order, so that the
+    /// keys falling in the same data block of a table share a single read of the block.
+    pub fn multi_get(&self, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>, DbError> {
+        self.multi_get_cf(&self.default_cf(), keys)
+    }
+
+    /// Same as [Db::multi_get], in the column family `cf`
+    pub fn multi_get_cf(
+        &self,
+        cf: &ColumnFamily,
+        keys: &[&[u8]],
+    ) -> Result<Vec<Option<Vec<u8>>>, DbError> {
+        self.multi_get_cf_with_options(cf, keys, &ReadOptions::default())
+    }
+
+    /// Same as [Db::multi_get], as restricted by `read_options`
+    pub fn multi_get_with_options(
+        &self,
+        keys: &[&[u8]],
+        read_options: &ReadOptions,
+    ) -> Result<Vec<Option<Vec<u8>>>, DbError> {
+        self.multi_get_cf_with_options(&self.default_cf(), keys, read_options)
+    }
+
+    /// Same as [Db::multi_get_with_options], in the column family `cf`
+    pub fn multi_get_cf_with_options(
+        &self,
+        cf: &ColumnFamily,
+        keys: &[&[u8]],
+        read_options: &ReadOptions,
+    ) -> Result<Vec<Option<Vec<u8>>>, DbError> {
+        self.trace(|| TraceOp::MultiGet {
+            column_family: cf.id(),
+            keys: keys.iter().map(|key| key.to_vec()).collect(),
+        });
+
+        let view = self.inner.state.lock().unwrap().read_view(cf)?;
+        let seq = view.sequence(read_options);
+        let ReadView {
+            mems,
+            tables,
+            version: _version,
+            options,
+            ..
+        } = view;
+
+        let mut sorted_keys = keys.to_vec();
+        sorted_keys.sort_unstable();
+        sorted_keys.dedup();
+
+        let mut ctxs: Vec<_> = sorted_keys.iter().map(|_| GetContext::default()).collect();
+        let mut results: Vec<_> = sorted_keys
+            .iter()
+            .zip(&mut ctxs)
+            .map(|(key, ctx)| {
+                mems.iter()
+                    .find_map(|mem| mem.get_with_context(key, seq, ctx))
+            })
+            .collect();
+
+        for table in tables {
+            let pending: Vec<_> = (0..sorted_keys.len())
+                .filter(|&n| results[n].is_none())
+                .collect();
+
+            if pending.is_empty() {
+                break;
+            }
+
+            let pending_keys: Vec<_> = pending.iter().map(|&n| sorted_keys[n]).collect();
+            let mut pending_ctxs: Vec<_> = pending
+                .iter()
+                .map(|&n| std::mem::take(&mut ctxs[n]))
+                .collect();
+
+            let found = table.multi_get_with_context(
+                &pending_keys,
+                seq,
+                &mut pending_ctxs,
+                read_options,
+            )?;
+
+            for ((n, ctx), result) in pending.into_iter().zip(pending_ctxs).zip(found) {
+                ctxs[n] = ctx;
+                results[n] = result;
+            }
+        }
+
+        let values = sorted_keys
+            .iter()
+            .zip(results)
+            .zip(ctxs)
+            .map(|((key, result), mut ctx)| {
+                let result = self.read_blob(result, &mut ctx)?;
+
+                self.finish_get(&options, key, result, ctx)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(keys
+            .iter()
+            .map(|key| match sorted_keys.binary_search(key) {
+                Ok(n) => values[n].clone(),
+                Err(_) => unreachable!("every key was looked up"),
+            })
+            .collect())
+    }
+
+    /// Turns the outcome of a lookup of `key` into its value, applying the merge operands
+    /// collected along the way
+    fn finish_get(
+        &self,
+        options: &ColumnFamilyOptions,
+        key: &[u8],
+        result: Option<LookupResult>,
+        ctx: GetContext,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        let value = match result {
+            Some(LookupResult::Value(value)) => Some(value),
+            Some(LookupResult::Deleted) | None => None,
+        };
+
+        if ctx.operands.is_empty() {
+            return Ok(value);
+        }
+
+        merge::full_merge(
+            options.merge_operator.as_deref(),
+            key,
+            value.as_deref(),
+            &ctx.operands,
+        )
+        .map(Some)
+    }
+
+    /// Returns an iterator over the current contents of the database
+    pub fn iter(&self) -> DbIterator {
+        self.iter_with_options(&ReadOptions::default())
+    }
+
+    /// Returns an iterator over the current contents of the database, as restricted by
+    /// `read_options`
+    pub fn iter_with_options(&self, read_options: &ReadOptions) -> DbIterator {
+        let state = self.inner.state.lock().unwrap();
+
+        self.new_iterator(
+            ReadView::new(state.default_column_family(), &state),
+            read_options,
+        )
+    }
+
+    /// Returns an iterator over the current contents of the column family `cf`, as restricted by
+    /// `read_options`
+    pub fn iter_cf(
+        &self,
+        cf: &ColumnFamily,
+        read_options: &ReadOptions,
+    ) -> Result<DbIterator, DbError> {
+        let state = self.inner.state.lock().unwrap();
+        let view = state.read_view(cf)?;
+
+        Ok(self.new_iterator(view, read_options))
+    }
+
+    /// Returns an iterator over the current contents of the database which also sees the writes
+    /// made after its creation, as restricted by `read_options`
+    ///
+    /// Fails if `read_options` has a snapshot or a sequence number, since the iterator always
+    /// reads the latest writes.
+    pub fn tailing_iter(&self, read_options: &ReadOptions) -> Result<TailingIterator<'_>, DbError> {
+        self.tailing_iter_cf(&self.default_cf(), read_options)
+    }
+
+    /// Same as [Db::tailing_iter], in the column family `cf`
+    pub fn tailing_iter_cf(
+        &self,
+        cf: &ColumnFamily,
+        read_options: &ReadOptions,
+    ) -> Result<TailingIterator<'_>, DbError> {
+        if read_options.snapshot.is_some() || read_options.sequence.is_some() {
+            return Err(DbError::InvalidArgument(
+                "tailing iterators read the latest writes",
+            ));
+        }
+
+        TailingIterator::new(self, cf.clone(), read_options.clone())
+    }
+
+    /// Returns a watch receiving the writes made from now on to the keys in `range`, once they're
+    /// visible
+    ///
+    /// Range deletions are received if they overlap `range`. The writes replayed from the logs
+    /// when opening the database aren't received again.
+    pub fn watch<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Watch {
+        self.inner.watchers.add(
+            DEFAULT_COLUMN_FAMILY_ID,
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        )
+    }
+
+    /// Same as [Db::watch], in the column family `cf`
+    pub fn watch_cf<R: RangeBounds<Vec<u8>>>(
+        &self,
+        cf: &ColumnFamily,
+        range: R,
+    ) -> Result<Watch, DbError> {
+        let state = self.inner.state.lock().unwrap();
+
+        if !state.column_families.contains_key(&cf.id()) {
+            return Err(DbError::InvalidArgument("column family was dropped"));
+        }
+
+        Ok(self.inner.watchers.add(
+            cf.id(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        ))
+    }
+
+    /// Returns the value `key` had in the column family `cf`, which must have user timestamps, as
+    /// of [ReadOptions::timestamp], if any
+    ///
+    /// Fails if the timestamp is older than [Db::full_history_ts_low].
+    pub fn get_cf_with_ts(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        read_options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        let read_options = ReadOptions {
+            iterate_lower_bound: Some(key.to_vec()),
+            iterate_upper_bound: Some([key, &[0]].concat()),
+            ..read_options.clone()
+        };
+        let mut iter = self.iter_cf_with_ts(cf, &read_options)?;
+        iter.seek(key)?;
+
+        Ok(iter.valid().then(|| iter.value().to_vec()))
+    }
+
+    /// Returns an iterator over the keys of the column family `cf`, which must have user
+    /// timestamps, as of [ReadOptions::timestamp] and as restricted by `read_options`
+    ///
+    /// Fails if the timestamp is older than [Db::full_history_ts_low].
+    pub fn iter_cf_with_ts(
+        &self,
+        cf: &ColumnFamily,
+        read_options: &ReadOptions,
+    ) -> Result<TimestampedIterator, DbError> {
+        let state = self.inner.state.lock().unwrap();
+        let view = state.read_view(cf)?;
+
+        if !view.options.user_timestamps {
+            return Err(DbError::InvalidArgument(
+                "column family has no user timestamps",
+            ));
+        }
+
+        let read_ts = read_options.timestamp.unwrap_or(Timestamp::MAX);
+
+        if read_ts < full_history_ts_low(&state, cf) {
+            return Err(DbError::InvalidArgument(
+                "timestamp older than the history kept",
+            ));
+        }
+
+        let read_options = ReadOptions {
+            iterate_lower_bound: read_options
+                .iterate_lower_bound
+                .as_deref()
+                .map(timestamp::key_prefix),
+            iterate_upper_bound: read_options
+                .iterate_upper_bound
+                .as_deref()
+                .map(timestamp::key_prefix),
+            ..read_options.clone()
+        };
+
+        Ok(TimestampedIterator::new(
+            self.new_iterator(view, &read_options),
+            read_ts,
+        ))
+    }
+
+    /// Returns the timestamp before which the history of the column family `cf`, which must
+    /// have user timestamps, may be dropped, see [Db::increase_full_history_ts_low]
+    pub fn full_history_ts_low(&self, cf: &ColumnFamily) -> Result<Timestamp, DbError> {
+        let state = self.inner.state.lock().unwrap();
+        state.column_family(cf)?;
+
+        Ok(full_history_ts_low(&state, cf))
+    }
+
+    /// Gives up the history of the column family `cf` before the timestamp `ts`: the reads as of
+    /// older timestamps are refused from now on, and the flushes drop the versions only they
+    /// would see, bounding the space taken by the history
+    ///
+    /// The history can't be recovered, so `ts` can't be lower than the current
+    /// [Db::full_history_ts_low].
+    pub fn increase_full_history_ts_low(
+        &self,
+        cf: &ColumnFamily,
+        ts: Timestamp,
+    ) -> Result<(), DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if !state.column_family(cf)?.options.user_timestamps {
+            return Err(DbError::InvalidArgument(
+                "column family has no user timestamps",
+            ));
+        }
+
+        if ts < full_history_ts_low(&state, cf) {
+            return Err(DbError::InvalidArgument(
+                "the history kept can't be extended",
+            ));
+        }
+
+        state.versions.log_and_apply(VersionEdit {
+            full_history_ts_low: vec![(cf.id(), ts)],
+            ..VersionEdit::default()
+        })?;
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the keys in [lower, upper)
+    pub fn range(&self, lower: &[u8], upper: &[u8]) -> DbIterator {
+        self.iter_with_options(&ReadOptions {
+            iterate_lower_bound: Some(lower.to_vec()),
+            iterate_upper_bound: Some(upper.to_vec()),
+            ..ReadOptions::default()
+        })
+    }
+
+    /// Returns an iterator over the contents of the database when `snapshot` was taken
+    pub fn iter_at(&self, snapshot: &Snapshot) -> DbIterator {
+        self.iter_with_options(&ReadOptions {
+            snapshot: Some(snapshot.clone()),
+            ..ReadOptions::default()
+        })
+    }
+
+    /// Iterates the memtable and the tables of a column family, from the newest to the oldest,
+    /// as of the snapshot or the sequence number of `read_options`, or else as of the view
+    fn new_iterator(&self, view: ReadView, read_options: &ReadOptions) -> DbIterator {
+        let snapshot = match &read_options.snapshot {
+            Some(snapshot) => snapshot.clone(),
+            None => self.inner.snapshots.acquire(view.sequence(read_options)),
+        };
+
+        DbIterator::new(
+            view.mems,
+            view.tables,
+            view.version,
+            self.inner.blob_files.clone(),
+            &view.options,
+            read_options,
+            snapshot,
+        )
+        .with_tracer(self.inner.tracer(), view.column_family_id)
+    }
+
+    /// Starts recording every write, point lookup and iterator seek made on the database to a
+    /// trace file at `path`, until [Db::end_trace]
+    ///
+    /// Every record holds the keys of the operation, the sizes of the values written, the time
+    /// since the start of the trace and the thread which made it: a [Replayer](crate::Replayer)
+    /// makes the operations again on another database. The operations made while reading or
+    /// writing, e.g. by transactions, are recorded too.
+    pub fn start_trace<P: AsRef<Path>>(&self, path: P) -> Result<(), DbError> {
+        let mut tracer = self.inner.tracer.lock().unwrap();
+
+        if tracer.is_some() {
+            return Err(DbError::InvalidArgument("a trace is already running"));
+        }
+
+        *tracer = Some(Arc::new(Tracer::create(path.as_ref())?));
+
+        Ok(())
+    }
+
+    /// Stops the trace started by [Db::start_trace], and returns the first error writing it, if
+    /// any, which stopped it early
+    pub fn end_trace(&self) -> Result<(), DbError> {
+        let tracer = self.inner.tracer.lock().unwrap().take();
+
+        match tracer {
+            Some(tracer) => tracer.finish(),
+            None => Err(DbError::InvalidArgument("no trace is running")),
+        }
+    }
+
+    /// Records the operation returned by `op` if a trace runs
+    fn trace<F: FnOnce() -> TraceOp>(&self, op: F) {
+        if let Some(tracer) = self.inner.tracer() {
+            tracer.record(op());
+        }
+    }
+
+    /// Flushes the memtables to new tables right away, instead of waiting for them to fill up,
+    /// and returns once the tables are written
+    ///
+    /// The memtables of every column family are flushed. Nothing needs to be replayed from the
+    /// logs after a flush, which bounds the time the next open takes.
+    pub fn flush(&self) -> Result<(), DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.wal()?;
+
+        while !state.unordered_writes.is_empty() {
+            state = self.inner.unordered_writes_visible.wait(state).unwrap();
+        }
+
+        let ids: Vec<u32> = state.column_families.keys().copied().collect();
+        self.inner.switch_memtables(&mut state, &ids)?;
+        self.inner.flush_memtables(state).map(drop)
+    }
+
+    /// Hands the WAL records buffered because of [Options::manual_wal_flush] to the OS, also
+    /// syncing the log if `sync`
+    pub fn flush_wal(&self, sync: bool) -> Result<(), DbError> {
+        match &mut self.inner.state.lock().unwrap().wal {
+            Some(wal) => Ok(wal.flush(sync)?),
+            // Nothing was ever written
+            None => Ok(()),
+        }
+    }
+
+    /// Returns an iterator over the write batches of the database with sequence numbers >= `seq`,
+    /// read from its live and archived logs, to be applied to a replica with
+    /// [Db::apply_replicated_batch]
+    ///
+    /// The iterator ends with the last batch written so far. The logs retired since `seq` must
+    /// still be archived, see [Options::wal_retention], or the iterator fails with
+    /// [WalError::UpdatesUnavailable]: the replica then has to start over from a new copy.
+    pub fn get_updates_since(&self, seq: SequenceNumber) -> Result<UpdatesIterator, DbError> {
+        self.flush_wal(false)?;
+
+        Ok(wal::get_updates_since(&self.inner.wal_dir, seq)?)
+    }
+
+    /// Compacts the user keys in [start, end] of the default column family down to the bottom
+    /// level, see [Db::compact_range_cf]
+    pub fn compact_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        options: &CompactRangeOptions,
+    ) -> Result<(), DbError> {
+        self.compact_range_cf(&self.default_cf(), start, end, options)
+    }
+
+    /// Compacts the user keys in [start, end] of the column family `cf` down to the bottom
+    /// level, None standing for no bound, and returns once done
+    ///
+    /// This reclaims the space taken by the versions overwritten or deleted, e.g. after bulk
+    /// deletions, without waiting for the automatic compactions to reach the range, which also
+    /// run when [ColumnFamilyOptions::disable_auto_compactions] is set. The compactions keep the
+    /// versions seen by snapshots.
+    pub fn compact_range_cf(
+        &self,
+        cf: &ColumnFamily,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        options: &CompactRangeOptions,
+    ) -> Result<(), DbError> {
+        if options.flush {
+            self.flush()?;
+        }
+
+        for level in 0..version::NUM_LEVELS {
+            let mut state = self.inner.state.lock().unwrap();
+            state.wal()?;
+            let cf_options = state.column_family(cf)?.options.clone();
+
+            // The files may be part of a compaction running in the background
+            let compaction = loop {
+                let Some(files) = state.versions.current().column_family(cf.id()) else {
+                    return Ok(());
+                };
+                let compaction = compaction::pick_range_compaction(
+                    cf.id(),
+                    files,
+                    &cf_options,
+                    level,
+                    start,
+                    end,
+                    options.bottommost_level_compaction,
+                );
+
+                match compaction {
+                    Some(compaction) if compaction.conflicts(&state.compacting) => {
+                        state = self.inner.compaction_done.wait(state).unwrap();
+                    }
+                    compaction => break compaction,
+                }
+            };
+
+            if let Some(compaction) = compaction {
+                self.inner.run_compaction(state, compaction)?;
+            }
+        }
+
+        // The blob files left with enough garbage are collected in the background
+        self.inner.schedule_compaction();
+
+        Ok(())
+    }
+
+    /// Hints that the user keys in [start, end] of the default column family are mostly stale,
+    /// see [Db::suggest_compact_range_cf]
+    pub fn suggest_compact_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), DbError> {
+        self.suggest_compact_range_cf(&self.default_cf(), start, end)
+    }
+
+    /// Hints that the user keys in [start, end] of the column family `cf` are mostly stale, e.g.
+    /// after dropping a whole range of the application's data, None standing for no bound
+    ///
+    /// The tables holding keys in the range are compacted down one level once no level is over
+    /// its trigger, in the background: unlike [Db::compact_range_cf], this returns right away.
+    /// Only the column families with [CompactionStyle::Level] and automatic compactions take the
+    /// hint.
+    pub fn suggest_compact_range_cf(
+        &self,
+        cf: &ColumnFamily,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.wal()?;
+        state.column_family(cf)?;
+
+        let version = state.versions.current().clone();
+        let in_range = version
+            .column_family(cf.id())
+            .into_iter()
+            .flat_map(|files| files.files())
+            .filter(|file| {
+                start.is_none_or(|start| !file.ends_before(start))
+                    && end.is_none_or(|end| key::user_key(&file.smallest_key) <= end)
+            })
+            .map(|file| file.number);
+
+        let data = state.column_families.get_mut(&cf.id()).unwrap();
+        data.marked_for_compaction.extend(in_range);
+        drop(state);
+
+        self.inner.schedule_compaction();
+
+        Ok(())
+    }
+
+    /// Deletes the tables of the default column family holding only user keys in [start, end],
+    /// see [Db::delete_files_in_range_cf]
+    pub fn delete_files_in_range(
+        &self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), DbError> {
+        self.delete_files_in_range_cf(&self.default_cf(), start, end)
+    }
+
+    /// Deletes the tables of the column family `cf` holding only user keys in [start, end], None
+    /// standing for no bound, to reclaim their space right away when the range is discarded
+    ///
+    /// This isn't a deletion of the range: the keys of the memtable and of the tables partly in
+    /// the range stay, while the older versions of the deleted keys kept by the levels below may
+    /// show up again, and the snapshots lose the deleted keys too. Follow up with
+    /// [Db::delete_range_cf] to delete the keys left. The tables being compacted are skipped.
+    pub fn delete_files_in_range_cf(
+        &self,
+        cf: &ColumnFamily,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), DbError> {
+        let mut state = self.inner.state.lock().unwrap();
+        state.wal()?;
+        let data = state.column_family(cf)?;
+
+        let version = state.versions.current().clone();
+        let Some(files) = version.column_family(cf.id()) else {
+            return Ok(());
+        };
+        let mut edit = VersionEdit::default();
+        let mut deleted = HashSet::new();
+
+        for (level, level_files) in files.levels.iter().enumerate() {
+            for file in level_files {
+                let in_range = start.is_none_or(|start| key::user_key(&file.smallest_key) >= start)
+                    && end.is_none_or(|end| key::user_key(&file.largest_key) <= end);
+
+                if in_range && !state.compacting.contains(&file.number) {
+                    edit.delete_file(cf.id(), level, file.number);
+                    deleted.insert(file.number);
+                }
+            }
+        }
+
+        if deleted.is_empty() {
+            return Ok(());
+        }
+
+        let deleted_tables: Vec<_> = data
+            .tables
+            .iter()
+            .filter(|table| deleted.contains(&table.number()))
+            .cloned()
+            .collect();
+        edit.blob_file_garbage = compaction::blob_garbage(cf.id(), &deleted_tables, &[]);
+
+        // The files of the versions still referenced aren't obsolete
+        drop(version);
+        state.versions.log_and_apply(edit)?;
+
+        let data = state.column_families.get_mut(&cf.id()).unwrap();
+        data.tables
+            .retain(|table| !deleted.contains(&table.number()));
+        data.marked_for_compaction
+            .retain(|number| !deleted.contains(number));
+
+        log::info!(
+            "deleted {} tables of column family {} in range",
+            deleted.len(),
+            cf.name()
+        );
+
+        self.inner.stall_cleared.notify_all();
+        self.inner.delete_obsolete_files(&mut state)
+    }
+
+    /// Waits until the flushes and compactions queued or running in the background are done,
+    /// e.g. to measure the space taken by the tables once compacted
+    pub fn wait_for_background_work(&self) {
+        self.inner.scheduler.wait_idle();
+    }
+
+    /// Stops the work the database does on its own, i.e. flushing the memtables once full and
+    /// compacting the tables, so that it can be shut down in a bounded time. If `wait`, also
+    /// waits for the work in progress to complete.
+    ///
+    /// The writes keep going to the memtables, which grow without bounds: this is meant to be
+    /// called right before closing the database. Explicit calls like [Db::flush] keep working,
+    /// while the writes stalled fail with [DbError::WriteStalled].
+    pub fn cancel_all_background_work(&self, wait: bool) {
+        self.inner
+            .background_work_cancelled
+            .store(true, Ordering::Relaxed);
+
+        // The stopped writes would otherwise wait forever
+        self.inner.stall_cleared.notify_all();
+
+        if wait {
+            // The jobs still queued give up right away
+            self.inner.scheduler.wait_idle();
+        }
+    }
+
+    /// Closes the database, first flushing the memtables to tables if `flush`, and syncing the
+    /// log so that every write survives, returning the errors met on the way
+    ///
+    /// Dropping the database does the same without flushing the memtables, ignoring errors.
+    pub fn close(self, flush: bool) -> Result<(), DbError> {
+        self.inner.scheduler.shutdown();
+        let mut state = self.inner.state.lock().unwrap();
+
+        if flush && state.wal.is_some() {
+            let ids: Vec<u32> = state.column_families.keys().copied().collect();
+            self.inner.switch_memtables(&mut state, &ids)?;
+            state = self.inner.flush_memtables(state)?;
+        }
+
+        self.shutdown(&mut state)
+    }
+
+    /// Stops writing the log, once the background work is shut down. The lock of the directory
+    /// is released when the database is dropped.
+    fn shutdown(&self, state: &mut DbState) -> Result<(), DbError> {
+        if let Some(mut wal) = state.wal.take() {
+            wal.flush(true)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DbInner {
+    /// Returns the tracer of the running trace, if any
+    fn tracer(&self) -> Option<Arc<Tracer>> {
+        self.tracer.lock().unwrap().clone()
+    }
+
+    /// Gets rid of a log whose writes are all stored in tables: it's kept for recycling if
+    /// possible, otherwise it's handed to the archive
+    fn retire_log(&self, state: &mut DbState, log_number: u64, path: &Path) -> Result<(), DbError> {
+        let recycle = state.recyclable_logs.len() < self.options.recycle_log_file_num
+            && !self.options.wal_retention.keeps_logs()
+            && path.parent() == Some(self.wal_dir.as_path());
+
+        if recycle {
+            let recyclable_path = wal::recyclable_log_file_name(&self.wal_dir, log_number);
+            log::debug!("keeping log {} to be recycled", log_number);
+
+            std::fs::rename(path, &recyclable_path)?;
+            state.recyclable_logs.push(recyclable_path);
+        } else {
+            log::debug!("retiring log {}", log_number);
+            self.archive.retire(path, log_number)?;
+        }
+
+        Ok(())
+    }
+
+    /// Turns the memtables of the column families `ids` into immutable ones, switching to a new
+    /// log, for the next flush to write them to tables
+    ///
+    /// The immutable memtables get no writes from the new log on, so each column family is
+    /// flushed on its own: a log is only retired once every column family flushed past it.
+    fn switch_memtables(&self, state: &mut DbState, ids: &[u32]) -> Result<(), DbError> {
+        if ids.iter().all(|id| {
+            state
+                .column_families
+                .get(id)
+                .is_none_or(|data| data.mem.is_empty())
+        }) {
+            return Ok(());
+        }
+
+        let log_number = state.versions.new_file_number();
+
+        let mut wal = Db::create_log(
+            &self.wal_dir,
+            &self.options,
+            &mut state.recyclable_logs,
+            log_number,
+        )?;
+        log_prepared(&mut wal, &state.prepared)?;
+        state.wal = Some(wal);
+        state.log_number = log_number;
+
+        for (id, data) in &mut state.column_families {
+            if ids.contains(id) && !data.mem.is_empty() {
+                let mem = std::mem::replace(&mut data.mem, Arc::new(MemTable::new()));
+                data.imm.insert(
+                    0,
+                    ImmutableMemTable {
+                        mem,
+                        log_number: data.mem_log_number,
+                    },
+                );
+            }
+
+            // The column families without writes don't hold back the older logs
+            if data.mem.is_empty() {
+                data.mem_log_number = log_number;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the immutable memtables of every column family to new tables, once the flush
+    /// running if any is done, see [DbInner::switch_memtables]
+    ///
+    /// The database is unlocked while the tables are written: the reads keep finding the writes
+    /// in the immutable memtables meanwhile, and the writes go to the new memtables.
+    fn flush_memtables<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DbState>,
+    ) -> Result<MutexGuard<'a, DbState>, DbError> {
+        // The flushes retire the logs in order
+        while state.flushing {
+            state = self.flush_done.wait(state).unwrap();
+        }
+
+        let listeners = &self.options.listeners;
+        let oldest_snapshot = self.snapshots.oldest().unwrap_or(state.last_sequence);
+        let state_ref = &mut *state;
+        let mut jobs = Vec::new();
+
+        for (id, data) in &state_ref.column_families {
+            // Oldest first, so that the newer memtables get the greater file numbers
+            for ImmutableMemTable { mem, .. } in data.imm.iter().rev() {
+                let number = state_ref.versions.new_file_number();
+                let info = FlushJobInfo {
+                    column_family_id: *id,
+                    column_family_name: data.handle.name().to_string(),
+                    file_number: number,
+                    num_entries: mem.len() as u64,
+                };
+                let history = history_trimmer(
+                    state_ref.versions.current(),
+                    *id,
+                    &data.options,
+                    oldest_snapshot,
+                );
+                let blob_number = data
+                    .options
+                    .enable_blob_files
+                    .then(|| state_ref.versions.new_file_number());
+
+                state_ref.pending_outputs.insert(number);
+                state_ref.pending_outputs.extend(blob_number);
+                jobs.push((
+                    info,
+                    data.options.clone(),
+                    mem.clone(),
+                    history,
+                    blob_number,
+                ));
+            }
+        }
+
+        if jobs.is_empty() {
+            return Ok(state);
+        }
+
+        state.flushing = true;
+        drop(state);
+
+        let mut tables = Vec::new();
+        let mut result = Ok(());
+
+        for (info, options, mem, history, blob_number) in &mut jobs {
+            for listener in listeners {
+                listener.on_flush_begin(info);
+            }
+
+            let span = span!(
+                "flush",
+                column_family = info.column_family_name.as_str(),
+                file_number = info.file_number,
+                entries = info.num_entries;
+                bytes
+            );
+
+            let start = Instant::now();
+            let built = build_table(
+                &self.path,
+                options,
+                self.options.rate_limiter.as_ref(),
+                info.file_number,
+                info.column_family_id,
+                mem,
+                history.take(),
+                *blob_number,
+            );
+
+            match built {
+                Ok((table, blob_file)) => {
+                    log::info!(
+                        "flushed column family {} to table {}: {} entries, {} bytes",
+                        info.column_family_name,
+                        info.file_number,
+                        table.properties().num_entries,
+                        table.file_size()
+                    );
+                    span.record("bytes", table.file_size());
+
+                    let creation_info = table_file_creation_info(
+                        &self.path,
+                        info.column_family_id,
+                        &table,
+                        TableFileCreationReason::Flush,
+                    );
+
+                    for listener in listeners {
+                        listener.on_table_file_created(&creation_info);
+                    }
+
+                    tables.push((table, blob_file, start.elapsed()));
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.flushing = false;
+
+        for (info, _, _, _, blob_number) in &jobs {
+            state.pending_outputs.remove(&info.file_number);
+
+            if let Some(number) = blob_number {
+                state.pending_outputs.remove(number);
+            }
+        }
+
+        self.flush_done.notify_all();
+        // The memtables stay immutable, for the next flush to try again
+        result?;
+
+        let mut edit = VersionEdit::default();
+        let mut flushed = HashMap::new();
+
+        for ((info, ..), (table, blob_file, _)) in jobs.iter().zip(&tables) {
+            // The tables of a column family dropped meanwhile are obsolete already
+            if state.column_families.contains_key(&info.column_family_id) {
+                let id = info.column_family_id;
+
+                edit.add_file(id, 0, file_meta_data(table));
+                edit.new_blob_files
+                    .extend(blob_file.clone().map(|file| (id, file)));
+                *flushed.entry(id).or_insert(0) += 1;
+            }
+        }
+
+        // The old logs can only be retired once the manifest says their writes are in tables
+        let oldest_log_numbers: Vec<(u32, u64)> = state
+            .column_families
+            .iter()
+            .map(|(id, data)| {
+                let count = flushed.get(id).copied().unwrap_or(0);
+                (*id, data.oldest_log_number(count))
+            })
+            .collect();
+        edit.log_number = oldest_log_numbers
+            .iter()
+            .map(|(_, log_number)| *log_number)
+            .min()
+            .or(Some(state.log_number));
+        edit.column_family_log_numbers = oldest_log_numbers
+            .into_iter()
+            .filter(|(id, _)| flushed.contains_key(id))
+            .collect();
+        edit.last_sequence = Some(state.last_sequence);
+        state.versions.log_and_apply(edit)?;
+
+        let state_ref = &mut *state;
+
+        for ((info, _, mem, ..), (table, _, elapsed)) in jobs.into_iter().zip(tables) {
+            let Some(data) = state_ref.column_families.get_mut(&info.column_family_id) else {
+                continue;
+            };
+
+            let properties = table.properties();
+            data.compaction_stats[0].add(
+                properties.raw_key_size + properties.raw_value_size,
+                0,
+                table.file_size(),
+                elapsed,
+            );
+            data.tables.insert(0, table);
+            data.imm.retain(|imm| !Arc::ptr_eq(&imm.mem, &mem));
+            state_ref.flushed_mems.push(Arc::downgrade(&mem));
+
+            for listener in listeners {
+                listener.on_flush_completed(&info);
+            }
+        }
+
+        self.charge_write_buffers(state_ref);
+        self.stall_cleared.notify_all();
+        self.delete_obsolete_files(state_ref)?;
+        self.schedule_compaction();
+
+        Ok(state)
+    }
+
+    /// Switches out the memtables which should be flushed, see [DbState::memtables_to_flush],
+    /// then flushes the immutable memtables
+    fn flush_full_memtables<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DbState>,
+    ) -> Result<MutexGuard<'a, DbState>, DbError> {
+        // The flush running makes room for new memtables, and the memtables can't be switched
+        // until the unordered writes in progress are inserted
+        loop {
+            if state.flushing {
+                state = self.flush_done.wait(state).unwrap();
+            } else if !state.unordered_writes.is_empty() {
+                state = self.unordered_writes_visible.wait(state).unwrap();
+            } else {
+                break;
+            }
+        }
+
+        // The full memtables stay put while there's no room for new ones, see
+        // [ColumnFamilyOptions::max_write_buffer_number], and the immutable memtables a failed
+        // flush left behind are flushed all the same
+        let ids: Vec<u32> = state
+            .memtables_to_flush(&self.options)
+            .into_iter()
+            .filter(|id| {
+                let data = &state.column_families[id];
+                data.imm.len() + 1 < data.options.max_write_buffer_number
+            })
+            .collect();
+
+        self.switch_memtables(&mut state, &ids)?;
+        self.flush_memtables(state)
+    }
+
+    /// Queues a flush of the memtables, unless one is already waiting to start
+    fn schedule_flush(&self) {
+        let this = self.this.clone();
+
+        self.scheduler.schedule(Priority::High, "flush", move || {
+            if let Some(db) = this.upgrade() {
+                db.background_flush();
+            }
+        });
+    }
+
+    /// Queues a compaction, unless one is already waiting to start
+    fn schedule_compaction(&self) {
+        let this = self.this.clone();
+
+        self.scheduler
+            .schedule(Priority::Low, "compaction", move || {
+                if let Some(db) = this.upgrade() {
+                    db.background_compaction();
+                }
+            });
+    }
+
+    /// Flushes the memtables if they're still full, unless the background work was cancelled
+    fn background_flush(&self) {
+        let state = self.state.lock().unwrap();
+
+        if self.background_work_cancelled.load(Ordering::Relaxed) || state.wal.is_none() {
+            return;
+        }
+
+        if let Err(e) = self.flush_full_memtables(state) {
+            let mut state = self.state.lock().unwrap();
+            self.report_background_error(&mut state, "flush", e);
+        }
+    }
+
+    /// Runs a compaction if some level of a column family is over its trigger, then queues the
+    /// next one, unless the background work was cancelled
+    ///
+    /// The database is unlocked while the compaction writes its tables, so that the flushes, the
+    /// writes and the other compactions get to run.
+    fn background_compaction(&self) {
+        let state = self.state.lock().unwrap();
+
+        if self.background_work_cancelled.load(Ordering::Relaxed) || state.wal.is_none() {
+            return;
+        }
+
+        let oldest_snapshot = self.snapshots.oldest().unwrap_or(state.last_sequence);
+        let Some(compaction) = pick_compaction(&state, oldest_snapshot) else {
+            return;
+        };
+
+        match self.run_compaction(state, compaction) {
+            Ok(()) => self.schedule_compaction(),
+            Err(e) => {
+                let mut state = self.state.lock().unwrap();
+                self.report_background_error(&mut state, "compaction", e);
+            }
+        }
+    }
+
+    /// Records the failure of a background job, which no caller gets to see
+    fn report_background_error(&self, state: &mut DbState, job: &str, error: DbError) {
+        log::error!("background {} failed: {}", job, error);
+        state.background_errors += 1;
+
+        for listener in &self.options.listeners {
+            listener.on_background_error(&error);
+        }
+    }
+
+    /// Merges the inputs of `compaction` into new tables, and installs them in their place
+    ///
+    /// The database is unlocked while the tables are written: the inputs are marked as being
+    /// compacted meanwhile, so that no other compaction picks them.
+    fn run_compaction<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, DbState>,
+        mut compaction: Compaction,
+    ) -> Result<(), DbError> {
+        let listeners = &self.options.listeners;
+        let id = compaction.column_family_id;
+        let data = &state.column_families[&id];
+        let options = data.options.clone();
+
+        let mut info = CompactionJobInfo {
+            column_family_id: id,
+            column_family_name: data.handle.name().to_string(),
+            input_files: compaction.input_numbers(),
+            output_files: Vec::new(),
+            output_level: compaction.output_level,
+        };
+
+        for listener in listeners {
+            listener.on_compaction_begin(&info);
+        }
+
+        let tables: Vec<_> = data
+            .tables
+            .iter()
+            .filter(|table| info.input_files.contains(&table.number()))
+            .cloned()
+            .collect();
+        let input_bytes: u64 = tables.iter().map(|table| table.file_size()).sum();
+        let span = span!(
+            "compaction",
+            column_family = info.column_family_name.as_str(),
+            output_level = compaction.output_level,
+            input_files = info.input_files.len(),
+            input_bytes = input_bytes;
+            output_files,
+            output_bytes
+        );
+
+        let oldest_snapshot = self.snapshots.oldest().unwrap_or(state.last_sequence);
+        let trivial_move = compaction.is_trivial_move();
+        let mut elapsed = Duration::ZERO;
+        let (outputs, blob_outputs) = if compaction.delete_inputs || trivial_move {
+            (Vec::new(), Vec::new())
+        } else {
+            compaction.split(self.options.max_subcompactions, &options);
+            compaction.output_numbers = (0..compaction.num_subcompactions())
+                .map(|_| state.versions.new_file_number())
+                .collect();
+            let history = history_trimmer(state.versions.current(), id, &options, oldest_snapshot);
+
+            if let Some(files) = state.versions.current().column_family(id) {
+                let referenced: HashSet<u64> = tables
+                    .iter()
+                    .flat_map(|table| table.properties().blob_references.keys().copied())
+                    .collect();
+                compaction.rewritten_blob_files =
+                    compaction::blob_files_to_collect(files, &options)
+                        .intersection(&referenced)
+                        .copied()
+                        .collect();
+            }
+
+            state.compacting.extend(&info.input_files);
+            state.pending_outputs.extend(&compaction.output_numbers);
+            state.running_compactions += 1;
+            drop(state);
+
+            // The subcompactions writing several tables allocate the numbers of the next ones
+            let output_numbers = Mutex::new(compaction.output_numbers.clone());
+            let new_file_number = || {
+                let mut state = self.state.lock().unwrap();
+                let number = state.versions.new_file_number();
+                state.pending_outputs.insert(number);
+                output_numbers.lock().unwrap().push(number);
+
+                number
+            };
+            let output_files = compaction::OutputFiles {
+                new_file_number: &new_file_number,
+                rate_limiter: self.options.rate_limiter.as_ref(),
+                blob_files: &self.blob_files,
+            };
+
+            let start = Instant::now();
+            let outputs = match &self.options.compaction_service {
+                Some(service)
+                    if !options.user_timestamps && compaction.rewritten_blob_files.is_empty() =>
+                {
+                    let job = CompactionServiceJob {
+                        db_path: self.path.clone(),
+                        output_dir: compaction_service::output_dir_name(
+                            &self.path,
+                            compaction.output_numbers[0],
+                        ),
+                        column_family_id: id,
+                        column_family_name: info.column_family_name.clone(),
+                        options: options.as_ref().clone(),
+                        input_files: info.input_files.clone(),
+                        output_level: compaction.output_level,
+                        bottommost: compaction.bottommost,
+                        oldest_snapshot,
+                    };
+
+                    compaction_service::run(
+                        &self.path,
+                        service.as_ref(),
+                        &job,
+                        &compaction,
+                        &new_file_number,
+                    )
+                    .map(|outputs| (outputs, Vec::new()))
+                }
+                _ => compaction::run(
+                    &self.path,
+                    &options,
+                    &compaction,
+                    &tables,
+                    oldest_snapshot,
+                    history,
+                    &output_files,
+                ),
+            };
+            elapsed = start.elapsed();
+
+            state = self.state.lock().unwrap();
+            state.running_compactions -= 1;
+
+            for number in &info.input_files {
+                state.compacting.remove(number);
+            }
+
+            for number in output_numbers.lock().unwrap().iter() {
+                state.pending_outputs.remove(number);
+            }
+
+            self.compaction_done.notify_all();
+
+            // The outputs of a column family dropped meanwhile are obsolete already
+            if !state.column_families.contains_key(&id) {
+                return self.delete_obsolete_files(&mut state);
+            }
+
+            outputs?
+        };
+        let state = &mut *state;
+
+        span.record("output_files", outputs.len() as u64);
+        span.record(
+            "output_bytes",
+            outputs.iter().map(|table| table.file_size()).sum(),
+        );
+
+        for table in &outputs {
+            let creation_info = table_file_creation_info(
+                &self.path,
+                id,
+                table,
+                TableFileCreationReason::Compaction,
+            );
+
+            for listener in listeners {
+                listener.on_table_file_created(&creation_info);
+            }
+
+            info.output_files.push(table.number());
+        }
+
+        let new_files = if trivial_move {
+            info.output_files = compaction.input_numbers();
+            compaction
+                .inputs
+                .iter()
+                .map(|(_, file)| file.as_ref().clone())
+                .collect()
+        } else {
+            outputs.iter().map(|table| file_meta_data(table)).collect()
+        };
+        let mut edit = compaction.edit(new_files);
+
+        if !trivial_move {
+            edit.new_blob_files
+                .extend(blob_outputs.into_iter().map(|file| (id, file)));
+            edit.blob_file_garbage = compaction::blob_garbage(id, &tables, &outputs);
+        }
+
+        state.versions.log_and_apply(edit)?;
+
+        if trivial_move {
+            log::info!(
+                "moved table {} of column family {} to level {}",
+                info.output_files[0],
+                info.column_family_name,
+                compaction.output_level
+            );
+        } else {
+            log::info!(
+                "compacted {} tables of column family {} into level {}: {} bytes in, {} bytes out",
+                tables.len(),
+                info.column_family_name,
+                compaction.output_level,
+                input_bytes,
+                outputs.iter().map(|table| table.file_size()).sum::<u64>()
+            );
+        }
+
+        // Reads look at the tables in the order of the version: level 0 from the newest to the
+        // oldest, then the other levels by key
+        let data = state.column_families.get_mut(&id).unwrap();
+        let stats = &mut data.compaction_stats[compaction.output_level];
+
+        if trivial_move {
+            stats.bytes_moved += input_bytes;
+        } else if !compaction.delete_inputs {
+            let read_output_level: u64 = compaction
+                .inputs
+                .iter()
+                .filter(|(level, _)| *level == compaction.output_level)
+                .map(|(_, file)| file.file_size)
+                .sum();
+            let written = outputs.iter().map(|table| table.file_size()).sum();
+
+            stats.add(
+                input_bytes - read_output_level,
+                read_output_level,
+                written,
+                elapsed,
+            );
+        }
+
+        let open_tables: HashMap<u64, Arc<Table>> = data
+            .tables
+            .iter()
+            .cloned()
+            .chain(outputs)
+            .map(|table| (table.number(), table))
+            .collect();
+        data.tables = state
+            .versions
+            .current()
+            .column_family(id)
+            .map(|files| {
+                files
+                    .files()
+                    .filter_map(|file| open_tables.get(&file.number).cloned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let tables = &data.tables;
+        data.marked_for_compaction
+            .retain(|number| tables.iter().any(|table| table.number() == *number));
+
+        // The reads as of older sequence numbers may miss the versions dropped
+        if !trivial_move {
+            state.oldest_readable_sequence = state.oldest_readable_sequence.max(oldest_snapshot);
+        }
+
+        for listener in listeners {
+            listener.on_compaction_completed(&info);
+        }
+
+        self.stall_cleared.notify_all();
+        self.delete_obsolete_files(state)
+    }
+
+    /// Brings the bytes charged to [Options::write_buffer_manager] in line with the memory used
+    /// by the memtables
+    fn charge_write_buffers(&self, state: &mut DbState) {
+        let Some(manager) = &self.options.write_buffer_manager else {
+            return;
+        };
+
+        let usage = state
+            .column_families
+            .values()
+            .flat_map(|data| data.mems())
+            .map(|mem| mem.approximate_memory_usage())
+            .sum();
+
+        if usage > state.write_buffer_usage {
+            manager.reserve(usage - state.write_buffer_usage);
+        } else {
+            manager.free(state.write_buffer_usage - usage);
+        }
+
+        state.write_buffer_usage = usage;
+    }
+
+    /// Deletes the files no reader needs anymore: the tables which are neither in the current
+    /// version nor in an older one still being read, and the logs whose writes are all in tables
+    ///
+    /// The tables of the versions still being read are deleted by a later call, once released.
+    fn delete_obsolete_files(&self, state: &mut DbState) -> Result<(), DbError> {
+        let mut live_files = state.versions.live_files();
+        live_files.extend(&state.pending_outputs);
+        self.blob_files.retain(&live_files);
+        delete_obsolete_tables(
+            &self.path,
+            &live_files,
+            state.versions.manifest_number(),
+            &self.options.listeners,
+        )?;
+
+        let mut dirs = vec![self.wal_dir.clone()];
+
+        if self.wal_dir != self.path {
+            dirs.push(self.path.clone());
+        }
+
+        for dir in dirs {
+            for log_number in wal::list_logs(&dir)? {
+                if log_number < state.versions.log_number() {
+                    self.retire_log(state, log_number, &wal::log_file_name(&dir, log_number))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        // The background jobs lock the state
+        self.inner.scheduler.shutdown();
+
+        // The state may be inconsistent after a panic, but the log is still worth syncing
+        let mut state = match self.inner.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Err(e) = self.shutdown(&mut state) {
+            log::error!(
+                "failed to close the database at {}: {}",
+                self.inner.path.display(),
+                e
+            );
+        }
+
+        if let Some(manager) = &self.inner.options.write_buffer_manager {
+            manager.free(state.write_buffer_usage);
+        }
+
+        self.inner.watchers.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::WriteBatch;
+    use crate::blob::{self, ValueReader};
+    use crate::block_cache::BlockCache;
+    use crate::db::{Db, DbError, KeyMayExist};
+    use crate::db_iter::DbIterator;
+    use crate::key;
+    use crate::listener::{
+        CompactionJobInfo, EventListener, FlushJobInfo, TableFileCreationInfo,
+        TableFileDeletionInfo,
+    };
+    use crate::merge::MergeOperator;
+    use crate::merge_operators::UInt64Add;
+    use crate::options::{
+        load_latest_options, ColumnFamilyOptions, CompactRangeOptions, CompactionStyle,
+        CompressionType, Options, ReadOptions, WriteOptions,
+    };
+    use crate::perf_context;
+    use crate::prefix::FixedPrefix;
+    use crate::rate_limiter::RateLimiter;
+    use crate::row_cache::RowCache;
+    use crate::version::NUM_LEVELS;
+    use crate::wal::{RetentionPolicy, SyncPolicy};
+    use crate::watch::Change;
+    use crate::write_buffer_manager::WriteBufferManager;
+    use std::fs::File;
+    use std::io::Read;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::Duration;
+
+    /// Simulates a crash: the background work stops and nothing gets to run on the way out, but
+    /// the lock of the directory is released along with the process
+    fn crash(db: Db) {
+        db.inner.scheduler.shutdown();
+        db.inner._lock.as_ref().unwrap().unlock().unwrap();
+        std::mem::forget(db);
+    }
+
+    fn small_cf_options() -> ColumnFamilyOptions {
+        ColumnFamilyOptions::default()
+            .with_write_buffer_size(4096)
+            .with_block_size(512)
+    }
+
+    fn small_options() -> Options {
+        Options::default().with_default_cf_options(small_cf_options())
+    }
+
+    #[test]
+    fn put_get_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        db.put(b"key", b"value").unwrap();
+        db.put(b"other", b"value").unwrap();
+        db.delete(b"other").unwrap();
+
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(db.get(b"other").unwrap(), None);
+        assert_eq!(db.get(b"missing").unwrap(), None);
+    }
+
+    #[test]
+    fn reopen_recovers_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal_dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            wal_dir: Some(wal_dir.path().to_path_buf()),
+            recycle_log_file_num: 2,
+            ..small_options()
+        };
+
+        {
+            let db = Db::open(dir.path(), options.clone()).unwrap();
+
+            for n in 0..1000_u32 {
+                db.put(&n.to_be_bytes(), format!("value{}", n).as_bytes())
+                    .unwrap();
+            }
+
+            for n in (0..1000_u32).step_by(3) {
+                db.delete(&n.to_be_bytes()).unwrap();
+            }
+        }
+
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for n in 0..1000_u32 {
+            let expected = (n % 3 != 0).then(|| format!("value{}", n).into_bytes());
+
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn manual_wal_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            manual_wal_flush: true,
+            ..Options::default()
+        };
+
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        db.put(b"key", b"value").unwrap();
+        db.flush_wal(true).unwrap();
+
+        // Simulates a crash: the flushed record must be in the log even if the Db is leaked
+        crash(db);
+
+        let db = Db::open(dir.path(), options).unwrap();
+
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn snapshots_ignore_newer_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        db.put(b"key", b"old").unwrap();
+        db.put(b"deleted", b"value").unwrap();
+
+        let snapshot = db.snapshot();
+
+        db.put(b"key", b"new").unwrap();
+        db.delete(b"deleted").unwrap();
+        db.put(b"created", b"value").unwrap();
+
+        // Pushes the versions seen by the snapshot out of the memtable
+        for n in 0..500_u32 {
+            db.put(&n.to_be_bytes(), &[0; 32]).unwrap();
+        }
+
+        assert_eq!(db.get_at(b"key", &snapshot).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(
+            db.get_at(b"deleted", &snapshot).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(db.get_at(b"created", &snapshot).unwrap(), None);
+        assert_eq!(db.get(b"key").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"deleted").unwrap(), None);
+        assert_eq!(db.inner.snapshots.oldest(), Some(snapshot.sequence()));
+
+        drop(snapshot);
+
+        assert!(db.inner.snapshots.is_empty());
+    }
+
+    #[test]
+    fn iterator_merges_memtable_and_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        for n in 0..300_u32 {
+            db.put(&n.to_be_bytes(), b"old").unwrap();
+        }
+
+        for n in (0..300_u32).step_by(2) {
+            db.put(&n.to_be_bytes(), b"new").unwrap();
+        }
+
+        let snapshot = db.snapshot();
+
+        for n in (0..300_u32).step_by(3) {
+            db.delete(&n.to_be_bytes()).unwrap();
+        }
+
+        let mut iter = db.iter();
+        iter.seek_to_first().unwrap();
+
+        for n in (0..300_u32).filter(|n| n % 3 != 0) {
+            let expected: &[u8] = if n % 2 == 0 { b"new" } else { b"old" };
+
+            assert!(iter.valid());
+            assert_eq!(iter.key(), n.to_be_bytes());
+            assert_eq!(iter.value(), expected);
+
+            iter.next().unwrap();
+        }
+
+        assert!(!iter.valid());
+
+        let mut iter = db.iter_at(&snapshot);
+        iter.seek(&150_u32.to_be_bytes()).unwrap();
+
+        for n in 150..300_u32 {
+            assert_eq!(iter.key(), n.to_be_bytes());
+
+            iter.next().unwrap();
+        }
+
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn range_scan_respects_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        for n in 0..1000_u32 {
+            db.put(&n.to_be_bytes(), &n.to_le_bytes()).unwrap();
+        }
+
+        let mut iter = db.range(&100_u32.to_be_bytes(), &700_u32.to_be_bytes());
+        let mut keys = Vec::new();
+
+        iter.seek_to_first().unwrap();
+
+        while iter.valid() {
+            keys.push(u32::from_be_bytes(iter.key().try_into().unwrap()));
+            iter.next().unwrap();
+        }
+
+        assert_eq!(keys, (100..700).collect::<Vec<_>>());
+
+        // Seeks are clamped to the bounds
+        iter.seek(&0_u32.to_be_bytes()).unwrap();
+        assert_eq!(iter.key(), 100_u32.to_be_bytes());
+
+        iter.seek(&800_u32.to_be_bytes()).unwrap();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn iterator_moves_backwards() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        for n in 0..300_u32 {
+            db.put(&n.to_be_bytes(), b"old").unwrap();
+        }
+
+        for n in (0..300_u32).step_by(2) {
+            db.put(&n.to_be_bytes(), b"new").unwrap();
+        }
+
+        for n in (0..300_u32).step_by(3) {
+            db.delete(&n.to_be_bytes()).unwrap();
+        }
+
+        let mut iter = db.iter();
+        iter.seek_to_last().unwrap();
+
+        for n in (0..300_u32).filter(|n| n % 3 != 0).rev() {
+            let expected: &[u8] = if n % 2 == 0 { b"new" } else { b"old" };
+
+            assert_eq!(iter.key(), n.to_be_bytes());
+            assert_eq!(iter.value(), expected);
+
+            iter.prev().unwrap();
+        }
+
+        assert!(!iter.valid());
+
+        // 150 is deleted, so the last key <= 150 is 149
+        iter.seek_for_prev(&150_u32.to_be_bytes()).unwrap();
+        assert_eq!(iter.key(), 149_u32.to_be_bytes());
+
+        iter.next().unwrap();
+        assert_eq!(iter.key(), 151_u32.to_be_bytes());
+
+        iter.prev().unwrap();
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), 148_u32.to_be_bytes());
+
+        let mut iter = db.range(&100_u32.to_be_bytes(), &200_u32.to_be_bytes());
+        iter.seek_to_last().unwrap();
+        assert_eq!(iter.key(), 199_u32.to_be_bytes());
+    }
+
+    #[test]
+    fn prefix_seek_stays_within_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            small_cf_options().with_prefix_extractor(Arc::new(FixedPrefix(4))),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+
+        // Four tenants, with 100 keys each
+        for n in 0..400_u32 {
+            let mut key = (n / 100).to_be_bytes().to_vec();
+            key.extend_from_slice(&n.to_be_bytes());
+
+            db.put(&key, b"value").unwrap();
+        }
+
+        let mut iter = db.iter_with_options(&ReadOptions {
+            prefix_same_as_start: true,
+            ..ReadOptions::default()
+        });
+        let mut count = 0;
+
+        iter.seek(&2_u32.to_be_bytes()).unwrap();
+
+        while iter.valid() {
+            assert_eq!(iter.key()[..4], 2_u32.to_be_bytes());
+
+            count += 1;
+            iter.next().unwrap();
+        }
+
+        assert_eq!(count, 100);
+
+        iter.seek_to_first().unwrap();
+        iter.prev().unwrap();
+        assert!(!iter.valid());
+
+        iter.seek(&9_u32.to_be_bytes()).unwrap();
+        assert!(!iter.valid());
+
+        // Without prefix mode the iteration moves on to the next tenant
+        let mut iter = db.iter();
+        iter.seek(&[0, 0, 0, 2, 0, 0, 1, 43]).unwrap();
+        iter.next().unwrap();
+
+        assert_eq!(iter.key()[..4], 3_u32.to_be_bytes());
+    }
+
+    #[test]
+    fn delete_range_hides_older_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = small_options();
+
+        {
+            let db = Db::open(dir.path(), options.clone()).unwrap();
+
+            for n in 0..300_u32 {
+                db.put(&n.to_be_bytes(), b"value").unwrap();
+            }
+
+            let snapshot = db.snapshot();
+
+            db.delete_range(&100_u32.to_be_bytes(), &200_u32.to_be_bytes())
+                .unwrap();
+            db.put(&150_u32.to_be_bytes(), b"rewritten").unwrap();
+
+            assert_eq!(
+                db.get_at(&120_u32.to_be_bytes(), &snapshot).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+
+        // Reopening flushes the tombstone to a table
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for n in 0..300_u32 {
+            let expected = match n {
+                150 => Some(b"rewritten".to_vec()),
+                100..200 => None,
+                _ => Some(b"value".to_vec()),
+            };
+
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), expected);
+        }
+
+        let mut iter = db.iter();
+        let mut count = 0;
+
+        iter.seek(&99_u32.to_be_bytes()).unwrap();
+        assert_eq!(iter.key(), 99_u32.to_be_bytes());
+
+        iter.next().unwrap();
+        assert_eq!(iter.key(), 150_u32.to_be_bytes());
+
+        iter.seek_to_last().unwrap();
+
+        while iter.valid() {
+            count += 1;
+            iter.prev().unwrap();
+        }
+
+        assert_eq!(count, 201);
+    }
+
+    /// Adds up little-endian u64 operands
+    #[derive(Debug)]
+    struct Counter;
+
+    impl MergeOperator for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn full_merge(
+            &self,
+            _key: &[u8],
+            existing_value: Option<&[u8]>,
+            operands: &[&[u8]],
+        ) -> Option<Vec<u8>> {
+            let mut sum = match existing_value {
+                Some(value) => u64::from_le_bytes(value.try_into().ok()?),
+                None => 0,
+            };
+
+            for operand in operands {
+                sum += u64::from_le_bytes((*operand).try_into().ok()?);
+            }
+
+            Some(sum.to_le_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn merge_combines_operands() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default()
+            .with_default_cf_options(small_cf_options().with_merge_operator(Arc::new(Counter)));
+
+        {
+            let db = Db::open(dir.path(), options.clone()).unwrap();
+
+            for n in 0..100_u32 {
+                db.put(&n.to_be_bytes(), &10_u64.to_le_bytes()).unwrap();
+            }
+
+            for n in (0..100_u32).step_by(2) {
+                db.merge(&n.to_be_bytes(), &1_u64.to_le_bytes()).unwrap();
+            }
+
+            db.delete(&4_u32.to_be_bytes()).unwrap();
+            db.merge(&4_u32.to_be_bytes(), &5_u64.to_le_bytes())
+                .unwrap();
+        }
+
+        // Reopening flushes the operands to a table, under the ones written afterwards
+        let db = Db::open(dir.path(), options).unwrap();
+
+        let snapshot = db.snapshot();
+        db.merge(&0_u32.to_be_bytes(), &1_u64.to_le_bytes())
+            .unwrap();
+        db.merge(&200_u32.to_be_bytes(), &7_u64.to_le_bytes())
+            .unwrap();
+
+        let expected = |n: u32| -> u64 {
+            match n {
+                0 => 12,
+                4 => 5,
+                200 => 7,
+                _ if n.is_multiple_of(2) => 11,
+                _ => 10,
+            }
+        };
+
+        for n in (0..100_u32).chain([200]) {
+            assert_eq!(
+                db.get(&n.to_be_bytes()).unwrap(),
+                Some(expected(n).to_le_bytes().to_vec())
+            );
+        }
+
+        assert_eq!(
+            db.get_at(&0_u32.to_be_bytes(), &snapshot).unwrap(),
+            Some(11_u64.to_le_bytes().to_vec())
+        );
+
+        let mut iter = db.iter();
+        let mut forward = Vec::new();
+        iter.seek_to_first().unwrap();
+
+        while iter.valid() {
+            forward.push(u64::from_le_bytes(iter.value().try_into().unwrap()));
+            iter.next().unwrap();
+        }
+
+        let mut backward = Vec::new();
+        iter.seek_to_last().unwrap();
+
+        while iter.valid() {
+            backward.push(u64::from_le_bytes(iter.value().try_into().unwrap()));
+            iter.prev().unwrap();
+        }
+
+        backward.reverse();
+        let all: Vec<_> = (0..100_u32).chain([200]).map(expected).collect();
+        assert_eq!(forward, all);
+        assert_eq!(backward, all);
+
+        // Changing direction right after a merged key
+        iter.seek(&2_u32.to_be_bytes()).unwrap();
+        iter.prev().unwrap();
+        assert_eq!(iter.key(), 1_u32.to_be_bytes());
+        iter.next().unwrap();
+        assert_eq!(iter.key(), 2_u32.to_be_bytes());
+        assert_eq!(iter.value(), 11_u64.to_le_bytes());
+    }
+
+    #[test]
+    fn merge_requires_an_operator() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        assert!(matches!(
+            db.merge(b"key", b"operand"),
+            Err(DbError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn update_is_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(Db::open(dir.path(), small_options()).unwrap());
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+
+                std::thread::spawn(move || {
+                    for _ in 0..250 {
+                        db.update(b"counter", |old| {
+                            let count =
+                                old.map_or(0, |old| u64::from_le_bytes(old.try_into().unwrap()));
+
+                            Some((count + 1).to_le_bytes().to_vec())
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(
+            db.get(b"counter").unwrap(),
+            Some(1000_u64.to_le_bytes().to_vec())
+        );
+
+        assert_eq!(db.update(b"counter", |_| None).unwrap(), None);
+        assert_eq!(db.get(b"counter").unwrap(), None);
+    }
+
+    #[test]
+    fn compare_and_swap_checks_current_value() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        assert!(db.compare_and_swap(b"key", None, Some(b"a")).unwrap());
+        assert!(!db.compare_and_swap(b"key", None, Some(b"b")).unwrap());
+        assert!(!db.compare_and_swap(b"key", Some(b"b"), Some(b"c")).unwrap());
+        assert_eq!(db.get(b"key").unwrap(), Some(b"a".to_vec()));
+
+        assert!(db.compare_and_swap(b"key", Some(b"a"), Some(b"b")).unwrap());
+        assert_eq!(db.get(b"key").unwrap(), Some(b"b".to_vec()));
+
+        assert!(db.compare_and_swap(b"key", Some(b"b"), None).unwrap());
+        assert_eq!(db.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn multi_get_returns_values_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        for n in 0..500_u32 {
+            db.put(&n.to_be_bytes(), &(n * 2).to_be_bytes()).unwrap();
+        }
+
+        for n in (0..500_u32).step_by(3) {
+            db.delete(&n.to_be_bytes()).unwrap();
+        }
+
+        let keys: Vec<_> = [499_u32, 1, 3, 1, 1000, 250, 2]
+            .iter()
+            .map(|n| n.to_be_bytes())
+            .collect();
+        let keys: Vec<_> = keys.iter().map(|key| key.as_slice()).collect();
+
+        let values = db.multi_get(&keys).unwrap();
+        let expected: Vec<_> = keys.iter().map(|key| db.get(key).unwrap()).collect();
+
+        assert_eq!(values, expected);
+        assert_eq!(values[0], Some(998_u32.to_be_bytes().to_vec()));
+        assert_eq!(values[2], None);
+        assert_eq!(values[4], None);
+    }
+
+    #[test]
+    fn key_may_exist_skips_absent_keys() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = Db::open(dir.path(), Options::default()).unwrap();
+
+            for n in (0..1000_u32).step_by(2) {
+                db.put(&n.to_be_bytes(), b"flushed").unwrap();
+            }
+        }
+
+        // Reopening flushes the keys to a table
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"mem", b"value").unwrap();
+        db.delete(&0_u32.to_be_bytes()).unwrap();
+
+        assert_eq!(
+            db.key_may_exist(b"mem"),
+            KeyMayExist::Found(b"value".to_vec())
+        );
+        assert_eq!(db.key_may_exist(&0_u32.to_be_bytes()), KeyMayExist::No);
+        assert_eq!(db.key_may_exist(&2_u32.to_be_bytes()), KeyMayExist::Maybe);
+        assert_eq!(db.key_may_exist(&5000_u32.to_be_bytes()), KeyMayExist::No);
+
+        // The filter rules out most of the absent keys in range
+        let maybe = (1..1000_u32)
+            .step_by(2)
+            .filter(|n| db.key_may_exist(&n.to_be_bytes()) != KeyMayExist::No)
+            .count();
+
+        assert!(maybe < 100, "{} false positives", maybe);
+    }
+
+    #[test]
+    fn approximate_sizes_follow_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let value = [7_u8; 100];
+
+        {
+            let db = Db::open(dir.path(), Options::default()).unwrap();
+
+            for n in 0..1000_u32 {
+                db.put(&n.to_be_bytes(), &value).unwrap();
+            }
+        }
+
+        // Reopening flushes the keys to a table
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        for n in 2000..2100_u32 {
+            db.put(&n.to_be_bytes(), &value).unwrap();
+        }
+
+        let (zero, half, all) = (
+            0_u32.to_be_bytes(),
+            500_u32.to_be_bytes(),
+            1000_u32.to_be_bytes(),
+        );
+        let (mem_start, mem_end) = (2000_u32.to_be_bytes(), 3000_u32.to_be_bytes());
+        let ranges = [
+            zero.as_slice()..all.as_slice(),
+            zero.as_slice()..half.as_slice(),
+            mem_start.as_slice()..mem_end.as_slice(),
+            half.as_slice()..zero.as_slice(),
+        ];
+
+        let on_disk = db.approximate_sizes(&ranges, false).unwrap();
+        let with_mem = db.approximate_sizes(&ranges, true).unwrap();
+
+        assert!(on_disk[0] >= 100_000, "{}", on_disk[0]);
+        assert!(on_disk[1] * 3 > on_disk[0] && on_disk[1] * 3 < on_disk[0] * 2);
+        assert_eq!(on_disk[2], 0);
+        assert_eq!(on_disk[3], 0);
+
+        assert_eq!(with_mem[0], on_disk[0]);
+        assert_eq!(with_mem[2], 100 * (4 + 8 + 100));
+    }
+
+    #[test]
+    fn column_families_are_independent() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = Db::open(dir.path(), small_options()).unwrap();
+            let meta = db.create_cf("meta").unwrap();
+
+            assert!(db.create_cf("meta").is_err());
+            assert_eq!(db.cf_handle("meta"), Some(meta.clone()));
+
+            let mut batch = WriteBatch::new();
+            batch.put(b"key", b"data");
+            batch.put_cf(&meta, b"key", b"meta");
+            db.write(batch).unwrap();
+
+            // Enough to flush both column families to tables
+            for n in 0..200_u32 {
+                db.put(&n.to_be_bytes(), b"data").unwrap();
+                db.put_cf(&meta, &n.to_be_bytes(), b"meta").unwrap();
+            }
+
+            db.delete_cf(&meta, &0_u32.to_be_bytes()).unwrap();
+            db.create_cf("doomed").unwrap();
+        }
+
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        let meta = db.cf_handle("meta").unwrap();
+
+        assert_eq!(db.get(b"key").unwrap(), Some(b"data".to_vec()));
+        assert_eq!(db.get_cf(&meta, b"key").unwrap(), Some(b"meta".to_vec()));
+        assert_eq!(
+            db.get(&0_u32.to_be_bytes()).unwrap(),
+            Some(b"data".to_vec())
+        );
+        assert_eq!(db.get_cf(&meta, &0_u32.to_be_bytes()).unwrap(), None);
+
+        let mut iter = db.iter_cf(&meta, &ReadOptions::default()).unwrap();
+        let mut count = 0;
+        iter.seek_to_first().unwrap();
+
+        while iter.valid() {
+            assert_eq!(iter.value(), b"meta");
+            count += 1;
+            iter.next().unwrap();
+        }
+
+        assert_eq!(count, 200);
+
+        let doomed = db.cf_handle("doomed").unwrap();
+        db.put_cf(&doomed, b"key", b"value").unwrap();
+        db.drop_cf(&doomed).unwrap();
+
+        assert!(matches!(
+            db.get_cf(&doomed, b"key"),
+            Err(DbError::InvalidArgument(_))
+        ));
+        assert!(db.drop_cf(&db.default_cf()).is_err());
+        drop(db);
+
+        // The writes of the dropped column family are not replayed into the new one
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        assert_eq!(db.cf_handle("doomed"), None);
+
+        let doomed = db.create_cf("doomed").unwrap();
+        assert_eq!(db.get_cf(&doomed, b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn manifest_tracks_live_tables() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = Db::open(dir.path(), small_options()).unwrap();
+
+            for n in 0..200_u32 {
+                db.put(&n.to_be_bytes(), b"value").unwrap();
+            }
+        }
+
+        let table_number = std::fs::read_dir(dir.path())
+            .unwrap()
+            .find_map(|entry| {
+                crate::table::parse_table_file_name(entry.unwrap().file_name().to_str().unwrap())
+            })
+            .unwrap();
+
+        // A table the manifest doesn't know about, e.g. written by a flush which crashed before
+        // being recorded
+        std::fs::copy(
+            crate::table::table_file_name(dir.path(), table_number),
+            crate::table::table_file_name(dir.path(), 9999),
+        )
+        .unwrap();
+
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        assert!(!crate::table::table_file_name(dir.path(), 9999).exists());
+
+        for n in 0..200_u32 {
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), Some(b"value".to_vec()));
+        }
+
+        let manifests = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                crate::version::parse_manifest_file_name(name.to_str().unwrap()).is_some()
+            })
+            .count();
+
+        assert_eq!(manifests, 1);
+    }
+
+    #[test]
+    fn lost_manifests_are_left_to_repair() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = Db::open(dir.path(), small_options()).unwrap();
+
+            for n in 0..200_u32 {
+                db.put(&n.to_be_bytes(), b"value").unwrap();
+            }
+        }
+
+        std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+
+        assert!(matches!(
+            Db::open(dir.path(), small_options()),
+            Err(DbError::Corruption(_))
+        ));
+
+        crate::repair(dir.path(), &small_options()).unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        for n in 0..200_u32 {
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), Some(b"value".to_vec()));
+        }
+    }
+
+    /// Copies the files of the database at `from` to `to`, the way a crash would leave them
+    fn copy_db(from: &std::path::Path, to: &std::path::Path) {
+        std::fs::create_dir_all(to).unwrap();
+
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(entry.path(), to.join(entry.file_name())).unwrap();
+        }
+    }
+
+    #[test]
+    fn recovers_from_crashes_at_any_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut written = 0_u32;
+
+        for crash_after in [0, 1, 37, 150, 300] {
+            let db = Db::open(dir.path(), small_options()).unwrap();
+
+            assert_eq!(db.latest_sequence_number(), written as u64);
+
+            for n in 0..written {
+                assert_eq!(
+                    db.get(&n.to_be_bytes()).unwrap(),
+                    Some(n.to_le_bytes().to_vec())
+                );
+            }
+
+            for n in written..written + crash_after {
+                db.put(&n.to_be_bytes(), &n.to_le_bytes()).unwrap();
+            }
+
+            written += crash_after;
+
+            // Nothing gets to run on the way out, as if the process was killed
+            crash(db);
+
+            // A manifest written by an open which crashed before switching to it
+            std::fs::write(dir.path().join("MANIFEST-999999"), b"garbage").unwrap();
+            std::fs::write(dir.path().join("CURRENT.tmp"), b"MANIFEST-99").unwrap();
+        }
+
+        Db::open(dir.path(), small_options()).unwrap();
+
+        assert!(!dir.path().join("MANIFEST-999999").exists());
+    }
+
+    /// Builds in `to` what a crash in the middle of the work turning the files of `before` into
+    /// the ones of `after` leaves behind: the files of both, the manifest being cut `manifest_len`
+    /// bytes in, and the tables new in `after` being cut in half with `torn_tables`, as if killed
+    /// while being written
+    fn crash_between(
+        before: &std::path::Path,
+        after: &std::path::Path,
+        manifest_len: u64,
+        torn_tables: bool,
+        to: &std::path::Path,
+    ) {
+        copy_db(before, to);
+
+        let current = std::fs::read_to_string(after.join("CURRENT")).unwrap();
+        let manifest = current.trim_end();
+        assert_eq!(
+            std::fs::read_to_string(before.join("CURRENT")).unwrap(),
+            current
+        );
+
+        for entry in std::fs::read_dir(after).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name();
+            let path = to.join(&name);
+
+            if name == manifest {
+                let contents = std::fs::read(entry.path()).unwrap();
+                assert!(contents.starts_with(&std::fs::read(&path).unwrap()));
+                std::fs::write(&path, &contents[..manifest_len as usize]).unwrap();
+            } else if !path.exists() {
+                std::fs::copy(entry.path(), &path).unwrap();
+
+                let table = crate::table::parse_table_file_name(name.to_str().unwrap());
+
+                if torn_tables && table.is_some() {
+                    let file = File::options().write(true).open(&path).unwrap();
+                    file.set_len(file.metadata().unwrap().len() / 2).unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn recovers_from_crashes_inside_flushes_and_compactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let states = tempfile::tempdir().unwrap();
+        let options = Options::default()
+            .with_default_cf_options(ColumnFamilyOptions::default().with_block_size(512));
+
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        let write = |keys: std::ops::Range<u32>, value: &[u8]| {
+            for n in keys {
+                db.put(&n.to_be_bytes(), value).unwrap();
+            }
+        };
+
+        write(0..200, b"old");
+        db.flush().unwrap();
+        write(0..100, b"new");
+        copy_db(dir.path(), &states.path().join("before_flush"));
+
+        db.flush().unwrap();
+        copy_db(dir.path(), &states.path().join("after_flush"));
+
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        copy_db(dir.path(), &states.path().join("after_compaction"));
+        crash(db);
+
+        let manifest_len = |state: &str| {
+            let state = states.path().join(state);
+            let current = std::fs::read_to_string(state.join("CURRENT")).unwrap();
+            std::fs::metadata(state.join(current.trim_end()))
+                .unwrap()
+                .len()
+        };
+
+        for (before, after) in [
+            ("before_flush", "after_flush"),
+            ("after_flush", "after_compaction"),
+        ] {
+            let (before_len, after_len) = (manifest_len(before), manifest_len(after));
+            assert!(before_len < after_len);
+
+            // Killed while writing the tables, then at every point of the manifest write
+            let crashes = std::iter::once((before_len, true))
+                .chain((before_len..after_len).step_by(5).map(|len| (len, false)))
+                .chain([(after_len, false)]);
+
+            for (manifest_len, torn_tables) in crashes {
+                let crash_dir = tempfile::tempdir().unwrap();
+                crash_between(
+                    &states.path().join(before),
+                    &states.path().join(after),
+                    manifest_len,
+                    torn_tables,
+                    crash_dir.path(),
+                );
+
+                let db = Db::open(crash_dir.path(), options.clone()).unwrap();
+                assert_eq!(db.latest_sequence_number(), 300);
+
+                for n in 0..200_u32 {
+                    let expected: &[u8] = if n < 100 { b"new" } else { b"old" };
+                    assert_eq!(db.get(&n.to_be_bytes()).unwrap().as_deref(), Some(expected));
+                }
+
+                // The tables the manifest doesn't record, torn or not, are gone
+                let mut live: Vec<_> = db
+                    .live_files()
+                    .iter()
+                    .map(|file| file.file_number)
+                    .collect();
+                let mut tables = table_numbers(crash_dir.path());
+                live.sort_unstable();
+                tables.sort_unstable();
+                assert_eq!(tables, live);
+            }
+        }
+    }
+
+    #[test]
+    fn torn_log_recovers_a_prefix_of_the_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default();
+
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        let log_number = db.inner.state.lock().unwrap().log_number;
+
+        for n in 0..20_u32 {
+            db.put(&n.to_be_bytes(), b"value").unwrap();
+        }
+
+        crash(db);
+
+        let log_path = crate::wal::log_file_name(dir.path(), log_number);
+        let log_len = std::fs::metadata(&log_path).unwrap().len();
+        let mut last_recovered = 0;
+
+        for len in (0..=log_len).step_by(7).chain([log_len]) {
+            let crash_dir = tempfile::tempdir().unwrap();
+            copy_db(dir.path(), crash_dir.path());
+
+            let crash_log = crate::wal::log_file_name(crash_dir.path(), log_number);
+            File::options()
+                .write(true)
+                .open(&crash_log)
+                .unwrap()
+                .set_len(len)
+                .unwrap();
+
+            let db = Db::open(crash_dir.path(), options.clone()).unwrap();
+            let recovered = db.latest_sequence_number();
+
+            // The longer the log, the more writes survive, without gaps
+            assert!(recovered >= last_recovered);
+            last_recovered = recovered;
+
+            for n in 0..20_u32 {
+                let expected = ((n as u64) < recovered).then(|| b"value".to_vec());
+                assert_eq!(db.get(&n.to_be_bytes()).unwrap(), expected);
+            }
+
+            db.put(b"after", b"crash").unwrap();
+            assert_eq!(db.latest_sequence_number(), recovered + 1);
+        }
+
+        assert_eq!(last_recovered, 20);
+    }
+
+    /// Returns the numbers of the table files in `dir`
+    fn table_numbers(dir: &std::path::Path) -> Vec<u64> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|entry| {
+                crate::table::parse_table_file_name(entry.unwrap().file_name().to_str().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn obsolete_tables_outlive_their_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        let doomed = db.create_cf("doomed").unwrap();
+
+        for n in 0..200_u32 {
+            db.put_cf(&doomed, &n.to_be_bytes(), b"value").unwrap();
+        }
+
+        let doomed_tables = table_numbers(dir.path());
+        assert!(!doomed_tables.is_empty());
+
+        let mut iter = db.iter_cf(&doomed, &ReadOptions::default()).unwrap();
+        db.drop_cf(&doomed).unwrap();
+
+        // The iterator still needs the tables of the dropped column family
+        assert_eq!(table_numbers(dir.path()), doomed_tables);
+
+        iter.seek_to_first().unwrap();
+        assert!(iter.valid());
+        drop(iter);
+
+        // Enough to flush the default column family, which collects the obsolete tables
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), b"value").unwrap();
+        }
+
+        let tables = table_numbers(dir.path());
+
+        assert!(!tables.is_empty());
+        assert!(tables.iter().all(|number| !doomed_tables.contains(number)));
+    }
+
+    #[test]
+    fn open_locks_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        assert!(matches!(
+            Db::open(dir.path(), Options::default()),
+            Err(DbError::Locked)
+        ));
+
+        drop(db);
+        Db::open(dir.path(), Options::default()).unwrap();
+    }
+
+    #[test]
+    fn read_only_open_sees_the_data_but_cannot_change_it() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(Db::open_read_only(dir.path(), Options::default()).is_err());
+
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        let meta = db.create_cf("meta").unwrap();
+
+        // Some of the writes in tables, the others only in the log
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), b"value").unwrap();
+        }
+
+        db.put_cf(&meta, b"key", b"meta").unwrap();
+
+        let reader = Db::open_read_only(dir.path(), small_options()).unwrap();
+        let reader_meta = reader.cf_handle("meta").unwrap();
+
+        assert_eq!(reader.latest_sequence_number(), db.latest_sequence_number());
+        for n in 0..200_u32 {
+            assert_eq!(
+                reader.get(&n.to_be_bytes()).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+        assert_eq!(
+            reader.get_cf(&reader_meta, b"key").unwrap(),
+            Some(b"meta".to_vec())
+        );
+
+        assert!(matches!(
+            reader.put(b"key", b"value"),
+            Err(DbError::InvalidArgument(_))
+        ));
+        assert!(reader.create_cf("other").is_err());
+        assert!(reader.drop_cf(&reader_meta).is_err());
+
+        // The writer keeps going, unaffected
+        db.put(b"key", b"value").unwrap();
+        assert_eq!(reader.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn secondary_catches_up_with_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        let primary = Db::open(dir.path(), small_options()).unwrap();
+
+        primary.put(b"key", b"old").unwrap();
+
+        let secondary = Db::open_read_only(dir.path(), small_options()).unwrap();
+        let snapshot = secondary.snapshot();
+
+        // Enough to flush and retire the log the secondary read
+        for n in 0..200_u32 {
+            primary.put(&n.to_be_bytes(), b"value").unwrap();
+        }
+
+        primary.put(b"key", b"new").unwrap();
+        let meta = primary.create_cf("meta").unwrap();
+        primary.put_cf(&meta, b"key", b"meta").unwrap();
+
+        assert_eq!(secondary.get(b"key").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(secondary.cf_handle("meta"), None);
+
+        secondary.try_catch_up_with_primary().unwrap();
+
+        assert_eq!(
+            secondary.latest_sequence_number(),
+            primary.latest_sequence_number()
+        );
+        assert_eq!(secondary.get(b"key").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(
+            secondary.get_at(b"key", &snapshot).unwrap(),
+            Some(b"old".to_vec())
+        );
+        assert_eq!(
+            secondary.get(&199_u32.to_be_bytes()).unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        let secondary_meta = secondary.cf_handle("meta").unwrap();
+        assert_eq!(
+            secondary.get_cf(&secondary_meta, b"key").unwrap(),
+            Some(b"meta".to_vec())
+        );
+
+        assert!(primary.try_catch_up_with_primary().is_err());
+    }
+
+    #[test]
+    fn flush_writes_the_memtables_to_tables() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = Db::open(dir.path(), Options::default()).unwrap();
+            let meta = db.create_cf("meta").unwrap();
+
+            db.put(b"key", b"value").unwrap();
+            db.put_cf(&meta, b"key", b"meta").unwrap();
+            db.flush().unwrap();
+
+            assert_eq!(table_numbers(dir.path()).len(), 2);
+            assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+            // Nothing left to flush
+            db.flush().unwrap();
+            assert_eq!(table_numbers(dir.path()).len(), 2);
+
+            crash(db);
+        }
+
+        // Nothing is replayed from the log, so no new table is written
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        let meta = db.cf_handle("meta").unwrap();
+
+        assert_eq!(table_numbers(dir.path()).len(), 2);
+        assert_eq!(db.get_cf(&meta, b"key").unwrap(), Some(b"meta".to_vec()));
+
+        let reader = Db::open_read_only(dir.path(), Options::default()).unwrap();
+        assert!(reader.flush().is_err());
+    }
+
+    #[test]
+    fn close_makes_writes_durable() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options {
+            manual_wal_flush: true,
+            ..Options::default()
+        };
+
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        db.put(b"logged", b"value").unwrap();
+        db.close(false).unwrap();
+
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        assert_eq!(db.get(b"logged").unwrap(), Some(b"value".to_vec()));
+
+        let tables = table_numbers(dir.path()).len();
+        db.put(b"flushed", b"value").unwrap();
+        db.close(true).unwrap();
+
+        assert_eq!(table_numbers(dir.path()).len(), tables + 1);
+
+        let db = Db::open(dir.path(), options).unwrap();
+
+        // Nothing was left in the log to replay
+        assert_eq!(table_numbers(dir.path()).len(), tables + 1);
+        assert_eq!(db.get(b"flushed").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn column_families_have_their_own_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = small_options().with_cf_options(
+            "counters",
+            small_cf_options()
+                .with_merge_operator(Arc::new(Counter))
+                .with_compression(CompressionType::Lz4),
+        );
+
+        assert!(Db::open(
+            dir.path(),
+            Options::default()
+                .with_default_cf_options(ColumnFamilyOptions::default().with_block_size(0))
+        )
+        .is_err());
+
+        {
+            let db = Db::open(dir.path(), options.clone()).unwrap();
+            let counters = db.create_cf("counters").unwrap();
+
+            assert!(db.merge(b"key", &1_u64.to_le_bytes()).is_err());
+
+            for n in 0..300_u32 {
+                db.merge_cf(&counters, &(n % 10).to_be_bytes(), &1_u64.to_le_bytes())
+                    .unwrap();
+            }
+        }
+
+        let db = Db::open(dir.path(), options).unwrap();
+        let counters = db.cf_handle("counters").unwrap();
+
+        assert_eq!(
+            db.get_cf(&counters, &0_u32.to_be_bytes()).unwrap(),
+            Some(30_u64.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn options_are_reloaded_from_the_options_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = Db::open(
+                dir.path(),
+                small_options()
+                    .with_wal_sync_policy(SyncPolicy::EveryNWrites(8))
+                    .with_wal_compression_threshold(128),
+            )
+            .unwrap();
+
+            db.create_cf_with_options(
+                "counters",
+                small_cf_options()
+                    .with_merge_operator(Arc::new(UInt64Add))
+                    .with_prefix_extractor(Arc::new(FixedPrefix(2)))
+                    .with_compression(CompressionType::Lz4),
+            )
+            .unwrap();
+            let dropped = db.create_cf("dropped").unwrap();
+            db.drop_cf(&dropped).unwrap();
+        }
+
+        let options = load_latest_options(dir.path()).unwrap();
+
+        assert_eq!(options.wal_sync_policy, SyncPolicy::EveryNWrites(8));
+        assert_eq!(options.wal_compression_threshold, Some(128));
+        assert_eq!(options.default_cf_options.write_buffer_size, 4096);
+        assert!(!options.cf_options.contains_key("dropped"));
+
+        let counters = &options.cf_options["counters"];
+        assert_eq!(counters.block_size, 512);
+        assert_eq!(counters.compression, CompressionType::Lz4);
+        assert!(counters.prefix_extractor.is_some());
+
+        let db = Db::open(dir.path(), options).unwrap();
+        let counters = db.cf_handle("counters").unwrap();
+
+        db.merge_cf(&counters, b"key", &2_u64.to_le_bytes())
+            .unwrap();
+        db.merge_cf(&counters, b"key", &3_u64.to_le_bytes())
+            .unwrap();
+
+        assert_eq!(
+            db.get_cf(&counters, b"key").unwrap(),
+            Some(5_u64.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn set_options_changes_live_column_families() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(
+            dir.path(),
+            Options::default()
+                .with_default_cf_options(ColumnFamilyOptions::default().with_block_size(512)),
+        )
+        .unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        assert!(table_numbers(dir.path()).is_empty());
+
+        assert!(db.set_options(&[("block_size", "1024")]).is_err());
+        assert!(db.set_options(&[("write_buffer_size", "big")]).is_err());
+        assert!(db
+            .set_options(&[("write_buffer_size", "4096"), ("write_buffer_size", "0")])
+            .is_err());
+
+        db.set_options(&[
+            ("write_buffer_size", "4096"),
+            ("level0_file_num_compaction_trigger", "8"),
+        ])
+        .unwrap();
+
+        for n in 100..200_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        db.wait_for_background_work();
+        assert!(!table_numbers(dir.path()).is_empty());
+
+        for n in 0..200_u32 {
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+        }
+
+        let options = load_latest_options(dir.path()).unwrap();
+        assert_eq!(options.default_cf_options.write_buffer_size, 4096);
+        assert_eq!(
+            options
+                .default_cf_options
+                .level0_file_num_compaction_trigger,
+            8
+        );
+        assert_eq!(options.default_cf_options.block_size, 512);
+    }
+
+    fn collect(mut iter: DbIterator) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        iter.seek_to_first().unwrap();
+
+        while iter.valid() {
+            entries.push((iter.key().to_vec(), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+
+        entries
+    }
+
+    #[test]
+    fn reads_honor_the_read_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), &[b'a'; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        // The tables stay put from now on
+        db.wait_for_background_work();
+
+        let snapshot = db.snapshot();
+        db.put(&0_u32.to_be_bytes(), b"new").unwrap();
+
+        let at_snapshot = ReadOptions {
+            snapshot: Some(snapshot),
+            ..ReadOptions::default()
+        };
+        let keys = [0_u32.to_be_bytes(), 1_u32.to_be_bytes()];
+        let keys: Vec<_> = keys.iter().map(|key| key.as_slice()).collect();
+
+        assert_eq!(
+            db.get_with_options(&0_u32.to_be_bytes(), &at_snapshot)
+                .unwrap(),
+            Some(vec![b'a'; 100])
+        );
+        assert_eq!(
+            db.multi_get_with_options(&keys, &at_snapshot).unwrap(),
+            vec![Some(vec![b'a'; 100]), Some(vec![b'a'; 100])]
+        );
+        assert_eq!(
+            collect(db.iter_with_options(&at_snapshot))[0].1,
+            vec![b'a'; 100]
+        );
+        assert_eq!(
+            db.multi_get(&keys).unwrap()[0].as_deref(),
+            Some(&b"new"[..])
+        );
+
+        let with_readahead = ReadOptions {
+            readahead_size: 4096,
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            collect(db.iter_with_options(&with_readahead)),
+            collect(db.iter())
+        );
+
+        // Damage a value in a data block, keeping the block well-formed
+        let number = table_numbers(dir.path())[0];
+        let path = crate::table::table_file_name(dir.path(), number);
+        let mut contents = std::fs::read(&path).unwrap();
+        let offset = contents
+            .windows(100)
+            .rposition(|window| window == [b'a'; 100])
+            .unwrap();
+        contents[offset] = b'b';
+        std::fs::write(&path, contents).unwrap();
+        // The damaged table is the one the reads go through
+        assert!(db.live_files().iter().any(|file| file.path == path));
+
+        let unverified = ReadOptions {
+            verify_checksums: false,
+            ..ReadOptions::default()
+        };
+        let values: Vec<_> = (1..200_u32)
+            .map(|n| db.get_with_options(&n.to_be_bytes(), &unverified).unwrap())
+            .collect();
+        assert!(values
+            .iter()
+            .any(|value| value.as_deref().is_some_and(|value| value[0] == b'b')));
+        assert!((1..200_u32).any(|n| db.get(&n.to_be_bytes()).is_err()));
+
+        assert_eq!(collect(db.iter_with_options(&unverified)).len(), 200);
+        let mut iter = db.iter();
+        let scan = iter.seek_to_first().and_then(|()| {
+            while iter.valid() {
+                iter.next()?;
+            }
+            Ok(())
+        });
+        assert!(scan.is_err());
+    }
+
+    #[test]
+    fn writes_honor_the_write_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_wal_sync_policy(SyncPolicy::EveryNWrites(1000)),
+        )
+        .unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"logged", b"1");
+        db.write(batch).unwrap();
+        assert!(db
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .wal()
+            .unwrap()
+            .has_unsynced_writes());
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"synced", b"2");
+        db.write_with_options(
+            batch,
+            &WriteOptions {
+                sync: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(!db
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .wal()
+            .unwrap()
+            .has_unsynced_writes());
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"unlogged", b"3");
+        db.write_with_options(
+            batch,
+            &WriteOptions {
+                disable_wal: true,
+                ..WriteOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(db.get(b"unlogged").unwrap(), Some(b"3".to_vec()));
+
+        crash(db);
+
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(db.get(b"logged").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"synced").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"unlogged").unwrap(), None);
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingListener {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl EventListener for RecordingListener {
+        fn on_flush_begin(&self, info: &FlushJobInfo) {
+            let event = format!("flush begin {}", info.column_family_name);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_flush_completed(&self, info: &FlushJobInfo) {
+            let event = format!("flush completed {}", info.column_family_name);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+            let event = format!("compaction completed {}", info.output_level);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_table_file_created(&self, info: &TableFileCreationInfo) {
+            assert!(info.path.exists());
+
+            let event = format!("created {:?} {}", info.reason, info.properties.num_entries);
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn on_table_file_deleted(&self, info: &TableFileDeletionInfo) {
+            assert!(!info.path.exists());
+
+            self.events.lock().unwrap().push("deleted".to_string());
+        }
+
+        fn on_background_error(&self, error: &DbError) {
+            let event = format!("background error {}", error);
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    /// Holds the flushes until told to go on
+    #[derive(Debug)]
+    struct BlockingListener {
+        begun: Mutex<mpsc::Sender<()>>,
+        resume: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl BlockingListener {
+        /// Returns the listener, along with the receiver told when a flush begins and the sender
+        /// letting it go on, or letting every flush go on once dropped
+        fn new() -> (Arc<BlockingListener>, mpsc::Receiver<()>, mpsc::Sender<()>) {
+            let (begun_sender, begun) = mpsc::channel();
+            let (resume, resume_receiver) = mpsc::channel();
+            let listener = BlockingListener {
+                begun: Mutex::new(begun_sender),
+                resume: Mutex::new(resume_receiver),
+            };
+
+            (Arc::new(listener), begun, resume)
+        }
+    }
+
+    impl EventListener for BlockingListener {
+        fn on_flush_begin(&self, _info: &FlushJobInfo) {
+            let _ = self.begun.lock().unwrap().send(());
+            let _ = self.resume.lock().unwrap().recv();
+        }
+    }
+
+    #[test]
+    fn listeners_are_told_about_flushes_and_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = Arc::new(RecordingListener::default());
+        let options = Options::default().with_listener(listener.clone());
+
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        let doomed = db.create_cf("doomed").unwrap();
+
+        db.put(b"key", b"value").unwrap();
+        db.put_cf(&doomed, b"key", b"value").unwrap();
+        db.put_cf(&doomed, b"other", b"value").unwrap();
+        db.flush().unwrap();
+
+        assert_eq!(
+            std::mem::take(&mut *listener.events.lock().unwrap()),
+            [
+                "flush begin default",
+                "created Flush 1",
+                "flush begin doomed",
+                "created Flush 2",
+                "flush completed default",
+                "flush completed doomed",
+            ]
+        );
+
+        db.drop_cf(&doomed).unwrap();
+        db.put(b"key", b"new value").unwrap();
+        crash(db);
+
+        let _db = Db::open(dir.path(), options).unwrap();
+
+        assert_eq!(
+            *listener.events.lock().unwrap(),
+            ["deleted", "created Recovery 1"]
+        );
+    }
+
+    #[test]
+    fn properties_describe_the_column_families() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(
+                ColumnFamilyOptions::default()
+                    .with_level0_file_num_compaction_trigger(2)
+                    .with_disable_auto_compactions(true),
+            ),
+        )
+        .unwrap();
+
+        for n in 0..10_u32 {
+            db.put(&n.to_be_bytes(), b"value").unwrap();
+        }
+        db.flush().unwrap();
+        db.delete(&0_u32.to_be_bytes()).unwrap();
+
+        let property = |name| db.get_property(name).unwrap();
+
+        assert_eq!(property("fyodor.num-files-at-level0"), "1");
+        assert_eq!(property("fyodor.num-files-at-level1"), "0");
+        assert_eq!(property("fyodor.num-entries-active-mem-table"), "1");
+        assert_eq!(property("fyodor.estimate-num-keys"), "11");
+        assert_eq!(property("fyodor.estimate-pending-compaction-bytes"), "0");
+        assert!(property("fyodor.levelstats").starts_with("Level Files Size(MB)\n    0     1"));
+        assert_eq!(db.get_property("fyodor.num-files-at-level7"), None);
+        assert_eq!(db.get_property("fyodor.unknown"), None);
+
+        db.flush().unwrap();
+
+        assert_eq!(property("fyodor.num-files-at-level0"), "2");
+        assert_eq!(property("fyodor.estimate-num-keys"), "9");
+        assert_eq!(
+            property("fyodor.estimate-pending-compaction-bytes"),
+            property("fyodor.total-sst-files-size")
+        );
+
+        let cf = db.create_cf("empty").unwrap();
+        assert_eq!(
+            db.get_property_cf(&cf, "fyodor.total-sst-files-size")
+                .unwrap()
+                .as_deref(),
+            Some("0")
+        );
+        db.drop_cf(&cf).unwrap();
+        assert!(db.get_property_cf(&cf, "fyodor.levelstats").is_err());
+    }
+
+    #[test]
+    fn live_files_describe_the_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        let other = db.create_cf("other").unwrap();
+
+        db.put(b"b", b"value").unwrap();
+        db.put(b"a", b"value").unwrap();
+        db.delete(b"c").unwrap();
+        db.put_cf(&other, b"key", b"value").unwrap();
+        db.flush().unwrap();
+
+        let files = db.live_files();
+        assert_eq!(files.len(), 2);
+
+        let default = &files[0];
+        assert_eq!(default.column_family_name, "default");
+        assert_eq!(default.level, 0);
+        assert_eq!(default.smallest_key, b"a");
+        assert_eq!(default.largest_key, b"c");
+        assert_eq!((default.smallest_seqno, default.largest_seqno), (1, 3));
+        assert_eq!((default.num_entries, default.num_deletions), (3, 1));
+        assert_eq!(
+            default.size,
+            std::fs::metadata(&default.path).unwrap().len()
+        );
+
+        assert_eq!(files[1].column_family_name, "other");
+        assert_eq!(files[1].smallest_key, b"key");
+    }
+
+    #[test]
+    fn cancelled_background_work_stops_flushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        db.cancel_all_background_work(true);
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        assert!(table_numbers(dir.path()).is_empty());
+
+        db.close(true).unwrap();
+        assert!(!table_numbers(dir.path()).is_empty());
+
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        assert_eq!(db.get(&99_u32.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+    }
+
+    #[test]
+    fn writes_stall_when_the_memtables_wait_for_flushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (listener, begun, resume) = BlockingListener::new();
+        let options = Options::default()
+            .with_default_cf_options(small_cf_options().with_max_write_buffer_number(2))
+            .with_listener(listener);
+        let db = Db::open(dir.path(), options).unwrap();
+        let no_slowdown = WriteOptions {
+            no_slowdown: true,
+            ..WriteOptions::default()
+        };
+        let put = |n: u32| {
+            let mut batch = WriteBatch::new();
+            batch.put(&n.to_be_bytes(), &[0; 100]);
+            db.write_with_options(batch, &no_slowdown)
+        };
+
+        for n in 0..50 {
+            put(n).unwrap();
+        }
+        // The flush of the first memtable is held back
+        begun.recv().unwrap();
+
+        // Stopped once the second one is full too
+        let stalled = (50..1000).map(put).find(Result::is_err);
+        assert!(matches!(stalled, Some(Err(DbError::WriteStalled))));
+        assert_eq!(
+            db.get_property("fyodor.num-immutable-mem-table").unwrap(),
+            "1"
+        );
+
+        drop(resume);
+        db.wait_for_background_work();
+        put(1000).unwrap();
+        assert_eq!(db.get(&1000_u32.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+    }
+
+    #[test]
+    fn writes_stall_when_level0_fills_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(
+            Db::open(
+                dir.path(),
+                Options::default().with_default_cf_options(
+                    ColumnFamilyOptions::default()
+                        .with_level0_writes_triggers(2, 3)
+                        .with_disable_auto_compactions(true),
+                ),
+            )
+            .unwrap(),
+        );
+        let no_slowdown = WriteOptions {
+            no_slowdown: true,
+            ..WriteOptions::default()
+        };
+        let put = |key: &[u8], write_options: &WriteOptions| {
+            let mut batch = WriteBatch::new();
+            batch.put(key, b"value");
+            db.write_with_options(batch, write_options)
+        };
+
+        for key in [b"a", b"b"] {
+            put(key, &no_slowdown).unwrap();
+            db.flush().unwrap();
+        }
+
+        // Slowed down
+        assert!(matches!(
+            put(b"c", &no_slowdown),
+            Err(DbError::WriteStalled)
+        ));
+        put(b"c", &WriteOptions::default()).unwrap();
+        db.flush().unwrap();
+
+        // Stopped until the trigger is raised
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || db.put(b"d", b"value"))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!writer.is_finished());
+        assert_eq!(db.get(b"d").unwrap(), None);
+
+        db.set_options(&[("level0_stop_writes_trigger", "4")])
+            .unwrap();
+        writer.join().unwrap().unwrap();
+        assert_eq!(db.get(b"d").unwrap(), Some(b"value".to_vec()));
+
+        // Stopped until the background work is cancelled
+        db.flush().unwrap();
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || db.put(b"e", b"value"))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        db.cancel_all_background_work(true);
+        assert!(matches!(writer.join().unwrap(), Err(DbError::WriteStalled)));
+    }
+
+    #[test]
+    fn unordered_writes_become_visible_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let db =
+            Arc::new(Db::open(dir.path(), small_options().with_unordered_write(true)).unwrap());
+
+        let threads: Vec<_> = (0..4_u32)
+            .map(|thread| {
+                let db = db.clone();
+
+                std::thread::spawn(move || {
+                    for n in 0..250_u32 {
+                        let key = (thread * 1000 + n).to_be_bytes();
+                        let mut batch = WriteBatch::new();
+                        batch.put(&key, b"first");
+                        batch.put(&key, b"second");
+                        db.write(batch).unwrap();
+
+                        // Everything before the snapshot was inserted
+                        let snapshot = db.snapshot();
+                        let read_options = ReadOptions {
+                            snapshot: Some(snapshot),
+                            ..ReadOptions::default()
+                        };
+                        assert_eq!(
+                            db.get_with_options(&key, &read_options).unwrap(),
+                            Some(b"second".to_vec())
+                        );
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(db.latest_sequence_number(), 2000);
+        db.wait_for_background_work();
+        assert!(!table_numbers(dir.path()).is_empty());
+
+        drop(db);
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        assert_eq!(collect(db.iter()).len(), 1000);
+        assert!(collect(db.iter())
+            .iter()
+            .all(|(_, value)| value == b"second"));
+    }
+
+    #[test]
+    fn write_buffer_manager_caps_the_memtables_of_every_database() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let manager = Arc::new(WriteBufferManager::new(64 << 10));
+        let options = Options::default().with_write_buffer_manager(manager.clone());
+        let dbs: Vec<_> = dirs
+            .iter()
+            .map(|dir| Db::open(dir.path(), options.clone()).unwrap())
+            .collect();
+
+        for n in 0..1000_u32 {
+            for db in &dbs {
+                db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+            }
+
+            assert!(manager.memory_usage() < manager.buffer_size());
+        }
+
+        for (db, dir) in dbs.iter().zip(&dirs) {
+            db.wait_for_background_work();
+            assert!(!table_numbers(dir.path()).is_empty());
+        }
+
+        assert!(manager.memory_usage() > 0);
+        drop(dbs);
+        assert_eq!(manager.memory_usage(), 0);
+    }
+
+    #[test]
+    fn write_buffer_manager_flushes_the_largest_memtable() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(WriteBufferManager::new(64 << 10));
+        let options = Options::default().with_write_buffer_manager(manager);
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        let small = db.create_cf("small").unwrap();
+
+        for n in 0..10_u32 {
+            db.put_cf(&small, &n.to_be_bytes(), b"small").unwrap();
+        }
+
+        for n in 0..1000_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+
+        db.wait_for_background_work();
+        let default = db.default_cf();
+        let property = |cf, name| db.get_property_cf(cf, name).unwrap().unwrap();
+
+        assert_ne!(property(&default, "fyodor.num-files-at-level0"), "0");
+        assert_eq!(property(&small, "fyodor.num-files-at-level0"), "0");
+        assert_eq!(
+            property(&small, "fyodor.num-entries-active-mem-table"),
+            "10"
+        );
+
+        // The logs holding the writes of the column family not flushed are kept
+        crash(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        let small = db.cf_handle("small").unwrap();
+
+        assert_eq!(collect(db.iter()).len(), 1000);
+        assert_eq!(
+            collect(db.iter_cf(&small, &ReadOptions::default()).unwrap()).len(),
+            10
+        );
+        assert_eq!(
+            db.get_property_cf(&small, "fyodor.num-files-at-level0")
+                .unwrap()
+                .unwrap(),
+            "1"
+        );
+    }
+
+    #[test]
+    fn memory_usage_follows_memtables_and_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+
+        let usage = db.memory_usage();
+        assert!(usage.mem_tables > 100 * 100);
+        assert_eq!(usage.pinned_mem_tables, 0);
+        assert_eq!(usage.table_readers, 0);
+
+        let iter = db.iter();
+        db.flush().unwrap();
+
+        let flushed = db.memory_usage();
+        assert_eq!(flushed.mem_tables, 0);
+        assert_eq!(flushed.pinned_mem_tables, usage.mem_tables);
+        assert!(flushed.table_readers > 0);
+
+        drop(iter);
+        assert_eq!(db.memory_usage().pinned_mem_tables, 0);
+        assert_eq!(db.memory_usage().total(), flushed.table_readers);
+    }
+
+    #[test]
+    fn blocks_are_read_from_the_shared_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let cf_options = ColumnFamilyOptions::default().with_block_cache(cache.clone());
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(cf_options),
+        )
+        .unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        db.flush().unwrap();
+
+        let no_fill = ReadOptions {
+            fill_cache: false,
+            ..ReadOptions::default()
+        };
+        perf_context::enable();
+        perf_context::reset();
+
+        db.get_with_options(&7_u32.to_be_bytes(), &no_fill).unwrap();
+        assert_eq!(cache.usage(), 0);
+
+        db.get(&7_u32.to_be_bytes()).unwrap();
+        assert!(cache.usage() > 0);
+        assert_eq!(db.memory_usage().block_cache, cache.usage());
+        let reads = perf_context::get();
+
+        db.get(&7_u32.to_be_bytes()).unwrap();
+        let cached = perf_context::get();
+        perf_context::disable();
+
+        assert_eq!(reads.block_cache_hit_count, 0);
+        assert_eq!(cached.block_read_count, reads.block_read_count);
+        assert_eq!(cached.block_cache_hit_count, 1);
+    }
+
+    #[test]
+    fn index_and_filter_blocks_are_charged_to_the_block_cache() {
+        let open = |dir: &std::path::Path, capacity: usize, pin: bool| {
+            let cache = Arc::new(BlockCache::new(capacity));
+            let cf_options = ColumnFamilyOptions::default()
+                .with_block_cache(cache.clone())
+                .with_cache_index_and_filter_blocks(true, pin);
+            let db = Db::open(dir, Options::default().with_default_cf_options(cf_options)).unwrap();
+
+            for n in 0..100_u32 {
+                db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+            }
+            db.flush().unwrap();
+
+            (db, cache)
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let (db, cache) = open(dir.path(), 1 << 20, false);
+        assert_eq!(db.memory_usage().table_readers, 0);
+        assert!(cache.usage() > 0);
+        assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+        drop(db);
+
+        // Evicted right away, the blocks are read back from the file
+        let dir = tempfile::tempdir().unwrap();
+        let (db, _) = open(dir.path(), 1, false);
+        assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), Some(vec![0; 100]));
+        assert_eq!(db.get(&1000_u32.to_be_bytes()).unwrap(), None);
+        drop(db);
+
+        let dir = tempfile::tempdir().unwrap();
+        let (db, cache) = open(dir.path(), 1 << 20, true);
+        assert!(db.memory_usage().table_readers > 0);
+        assert!(cache.usage() > 0);
+    }
+
+    #[test]
+    fn values_live_in_blob_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let open = || {
+            let cf_options = ColumnFamilyOptions::default().with_blob_files(true);
+            Db::open(
+                dir.path(),
+                Options::default().with_default_cf_options(cf_options),
+            )
+            .unwrap()
+        };
+        let files_with_extension = |extension: &str| {
+            std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+                .collect::<Vec<_>>()
+        };
+
+        let db = open();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[n as u8; 1000]).unwrap();
+        }
+        db.delete(&7_u32.to_be_bytes()).unwrap();
+        db.flush().unwrap();
+
+        let blob_files = files_with_extension("blob");
+        assert_eq!(blob_files.len(), 1);
+        assert!(std::fs::metadata(&blob_files[0]).unwrap().len() > 100 * 1000);
+        assert!(db.live_files().iter().all(|file| file.size < 10_000));
+
+        assert_eq!(db.get(&42_u32.to_be_bytes()).unwrap(), Some(vec![42; 1000]));
+        assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(
+            db.multi_get(&[&1_u32.to_be_bytes(), &7_u32.to_be_bytes()])
+                .unwrap(),
+            vec![Some(vec![1; 1000]), None]
+        );
+
+        // Compactions move the blob indexes, not the values
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        assert_eq!(files_with_extension("blob"), blob_files);
+
+        let mut iter = db.iter();
+        iter.seek_to_last().unwrap();
+        assert_eq!(iter.key(), 99_u32.to_be_bytes());
+        assert_eq!(iter.value(), [99; 1000]);
+        iter.seek(&6_u32.to_be_bytes()).unwrap();
+        iter.next().unwrap();
+        assert_eq!(iter.key(), 8_u32.to_be_bytes());
+        assert_eq!(iter.value(), [8; 1000]);
+        iter.prev().unwrap();
+        assert_eq!(iter.value(), [6; 1000]);
+        drop(iter);
+        drop(db);
+
+        let db = open();
+        assert_eq!(db.get(&0_u32.to_be_bytes()).unwrap(), Some(vec![0; 1000]));
+    }
+
+    #[test]
+    fn small_values_stay_in_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let cf_options = ColumnFamilyOptions::default()
+            .with_blob_files(true)
+            .with_min_blob_size(100);
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(cf_options),
+        )
+        .unwrap();
+        let blob_bytes = || {
+            let version = db.inner.state.lock().unwrap().versions.current().clone();
+            version
+                .column_family(0)
+                .unwrap()
+                .blob_files
+                .values()
+                .map(|file| file.blob_bytes)
+                .sum::<u64>()
+        };
+
+        db.put(b"small", &[1; 99]).unwrap();
+        db.put(b"large", &[2; 100]).unwrap();
+        db.flush().unwrap();
+        assert_eq!(blob_bytes(), 100);
+
+        db.set_options(&[("min_blob_size", "1000")]).unwrap();
+        db.put(b"medium", &[3; 500]).unwrap();
+        db.flush().unwrap();
+        assert_eq!(blob_bytes(), 100);
+
+        assert_eq!(db.get(b"small").unwrap(), Some(vec![1; 99]));
+        assert_eq!(db.get(b"large").unwrap(), Some(vec![2; 100]));
+        assert_eq!(db.get(b"medium").unwrap(), Some(vec![3; 500]));
+    }
+
+    #[test]
+    fn large_values_are_streamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let value: Vec<u8> = (0..1_000_000_u32).map(|n| n as u8).collect();
+        let read = |reader: Option<ValueReader>| {
+            let mut contents = Vec::new();
+            reader.unwrap().read_to_end(&mut contents).unwrap();
+            contents
+        };
+
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        let watch = db.watch(..);
+        db.put_reader(b"large", value.as_slice()).unwrap();
+        db.put(b"small", b"value").unwrap();
+
+        assert_eq!(
+            watch.recv().unwrap().change,
+            Change::PutLarge {
+                size: value.len() as u64
+            }
+        );
+        assert!(matches!(
+            db.get_reader(b"large").unwrap(),
+            Some(ValueReader::Blob(_))
+        ));
+        assert_eq!(read(db.get_reader(b"large").unwrap()), value);
+        assert_eq!(read(db.get_reader(b"small").unwrap()), b"value");
+        assert!(db.get_reader(b"missing").unwrap().is_none());
+        assert_eq!(db.get(b"large").unwrap().as_ref(), Some(&value));
+
+        // The reader outlives the overwrite of the value and the deletion of its blob file
+        let reader = db.get_reader(b"large").unwrap();
+        db.put(b"large", b"overwritten").unwrap();
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        assert_eq!(read(reader), value);
+        assert_eq!(db.get(b"large").unwrap(), Some(b"overwritten".to_vec()));
+
+        db.put_reader(b"large", &value[..10]).unwrap();
+        drop(watch);
+        drop(db);
+
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        assert_eq!(read(db.get_reader(b"large").unwrap()), &value[..10]);
+        db.flush().unwrap();
+        assert_eq!(db.get(b"large").unwrap(), Some(value[..10].to_vec()));
+    }
+
+    #[test]
+    fn blob_file_garbage_is_collected() {
+        let dir = tempfile::tempdir().unwrap();
+        let cf_options = ColumnFamilyOptions::default()
+            .with_blob_files(true)
+            .with_blob_garbage_collection_ratio(0.5);
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(cf_options),
+        )
+        .unwrap();
+        let blob_files = || {
+            let mut numbers: Vec<u64> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .filter_map(|entry| {
+                    let name = entry.unwrap().file_name();
+                    blob::parse_blob_file_name(name.to_str().unwrap())
+                })
+                .collect();
+            numbers.sort();
+            numbers
+        };
+        let write = |keys: std::ops::Range<u32>, value: u8| {
+            for n in keys {
+                db.put(&n.to_be_bytes(), &[value; 1000]).unwrap();
+            }
+            db.flush().unwrap();
+            db.compact_range(None, None, &CompactRangeOptions::default())
+                .unwrap();
+            db.wait_for_background_work();
+        };
+
+        // The blob files whose values are all overwritten are deleted
+        write(0..100, 1);
+        let first = blob_files();
+        assert_eq!(first.len(), 1);
+        write(0..100, 2);
+        let second = blob_files();
+        assert_eq!(second.len(), 1);
+        assert_ne!(second, first);
+
+        // The rest of the values of the ones mostly overwritten are moved to a new one
+        write(0..60, 3);
+        let third = blob_files();
+        assert_eq!(third.len(), 2);
+        assert!(!third.contains(&second[0]));
+
+        let version = db.inner.state.lock().unwrap().versions.current().clone();
+        let files = version.column_family(0).unwrap();
+        assert_eq!(files.blob_files.keys().copied().collect::<Vec<_>>(), third);
+        assert!(files
+            .blob_files
+            .values()
+            .all(|file| file.garbage_count == 0));
+        drop(version);
+
+        for n in 0..100_u32 {
+            let value = if n < 60 { 3 } else { 2 };
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), Some(vec![value; 1000]));
+        }
+
+        // Deleting the tables turns their values into garbage too
+        db.delete_files_in_range(None, None).unwrap();
+        assert!(blob_files().is_empty());
+    }
+
+    #[test]
+    fn checkpoints_open_as_of_their_creation() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint_dir = dir.path().join("checkpoint");
+        let options = Options::default()
+            .with_default_cf_options(ColumnFamilyOptions::default().with_blob_files(true));
+        let db = Db::open(dir.path().join("db"), options.clone()).unwrap();
+        let cf = db.create_cf("cf").unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        db.put(b"unflushed", b"value").unwrap();
+        db.put_cf(&cf, b"key", b"value").unwrap();
+
+        db.checkpoint(&checkpoint_dir).unwrap();
+        assert!(matches!(
+            db.checkpoint(&checkpoint_dir),
+            Err(DbError::InvalidArgument(_))
+        ));
+
+        // The database moves on without the checkpoint
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[2; 100]).unwrap();
+        }
+        db.delete(b"unflushed").unwrap();
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        drop(db);
+
+        let checkpoint = Db::open(&checkpoint_dir, options).unwrap();
+        assert_eq!(
+            checkpoint.get(&42_u32.to_be_bytes()).unwrap(),
+            Some(vec![1; 100])
+        );
+        assert_eq!(
+            checkpoint.get(b"unflushed").unwrap(),
+            Some(b"value".to_vec())
+        );
+        let cf = checkpoint.cf_handle("cf").unwrap();
+        assert_eq!(
+            checkpoint.get_cf(&cf, b"key").unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert!(load_latest_options(&checkpoint_dir).is_ok());
+    }
+
+    #[test]
+    fn replicas_follow_the_logs_of_their_primary() {
+        let dir = tempfile::tempdir().unwrap();
+        let replica_dir = dir.path().join("replica");
+        let retention = RetentionPolicy {
+            ttl: Some(Duration::from_secs(3600)),
+            size_limit: None,
+        };
+        let primary = Db::open(
+            dir.path().join("primary"),
+            Options::default().with_wal_retention(retention),
+        )
+        .unwrap();
+        let cf = primary.create_cf("cf").unwrap();
+
+        primary.put(b"before", b"value").unwrap();
+        primary.checkpoint(&replica_dir).unwrap();
+        let replica = Db::open(&replica_dir, Options::default()).unwrap();
+        let replica_cf = replica.cf_handle("cf").unwrap();
+
+        primary.put(b"key", b"first").unwrap();
+        // The log retired by the flush is still read from the archive
+        primary.flush().unwrap();
+        primary.put_cf(&cf, b"key", b"value").unwrap();
+        primary.put(b"key", b"second").unwrap();
+        primary.delete(b"before").unwrap();
+
+        let replicate = |seq| {
+            for batch in primary.get_updates_since(seq).unwrap() {
+                let batch = batch.unwrap();
+                replica
+                    .apply_replicated_batch(batch.sequence(), batch)
+                    .unwrap();
+            }
+        };
+
+        replicate(replica.latest_sequence_number() + 1);
+        assert_eq!(
+            replica.latest_sequence_number(),
+            primary.latest_sequence_number()
+        );
+        assert_eq!(replica.get(b"key").unwrap(), Some(b"second".to_vec()));
+        assert_eq!(replica.get(b"before").unwrap(), None);
+        assert_eq!(
+            replica.get_cf(&replica_cf, b"key").unwrap(),
+            Some(b"value".to_vec())
+        );
+
+        // The batches already applied are skipped
+        replicate(1);
+        assert_eq!(
+            replica.latest_sequence_number(),
+            primary.latest_sequence_number()
+        );
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"key", b"third");
+        let next = primary.latest_sequence_number() + 1;
+        assert!(matches!(
+            replica.apply_replicated_batch(next + 1, batch.clone()),
+            Err(DbError::ReplicationGap { expected, received })
+                if expected == next && received == next + 1
+        ));
+        replica.apply_replicated_batch(next, batch).unwrap();
+        assert_eq!(replica.get(b"key").unwrap(), Some(b"third".to_vec()));
+    }
+
+    #[test]
+    fn column_families_move_between_databases() {
+        let dir = tempfile::tempdir().unwrap();
+        let export_dir = dir.path().join("export");
+        let source = Db::open(dir.path().join("source"), Options::default()).unwrap();
+        let tenant = source.create_cf("tenant").unwrap();
+
+        // A level holding the older versions, and level 0 tables holding newer ones
+        for n in 0..100_u32 {
+            source.put_cf(&tenant, &n.to_be_bytes(), b"old").unwrap();
+        }
+        source
+            .compact_range_cf(&tenant, None, None, &CompactRangeOptions::default())
+            .unwrap();
+        for n in 0..10_u32 {
+            source.put_cf(&tenant, &n.to_be_bytes(), b"new").unwrap();
+        }
+        source.flush().unwrap();
+        source.delete_cf(&tenant, &99_u32.to_be_bytes()).unwrap();
+
+        let metadata = source.export_cf(&tenant, &export_dir).unwrap();
+        assert_eq!(metadata.files.len(), 3);
+        assert!(metadata.files.iter().all(|file| file.path.exists()));
+        assert!(matches!(
+            source.export_cf(&tenant, &export_dir),
+            Err(DbError::InvalidArgument(_))
+        ));
+        drop(source);
+
+        let target_path = dir.path().join("target");
+        {
+            let target = Db::open(&target_path, Options::default()).unwrap();
+            target.put(b"key", b"value").unwrap();
+
+            let moved = target
+                .create_cf_with_import("moved", ColumnFamilyOptions::default(), &metadata)
+                .unwrap();
+            assert!(target
+                .create_cf_with_import("moved", ColumnFamilyOptions::default(), &metadata)
+                .is_err());
+
+            assert_eq!(
+                target.get_cf(&moved, &1_u32.to_be_bytes()).unwrap(),
+                Some(b"new".to_vec())
+            );
+            assert_eq!(target.get_cf(&moved, &99_u32.to_be_bytes()).unwrap(), None);
+
+            // The later writes are newer than the imported ones
+            target
+                .put_cf(&moved, &2_u32.to_be_bytes(), b"newer")
+                .unwrap();
+        }
+
+        let target = Db::open(&target_path, Options::default()).unwrap();
+        let moved = target.cf_handle("moved").unwrap();
+        assert_eq!(target.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(
+            target.get_cf(&moved, &1_u32.to_be_bytes()).unwrap(),
+            Some(b"new".to_vec())
+        );
+        assert_eq!(
+            target.get_cf(&moved, &2_u32.to_be_bytes()).unwrap(),
+            Some(b"newer".to_vec())
+        );
+        assert_eq!(
+            target.get_cf(&moved, &50_u32.to_be_bytes()).unwrap(),
+            Some(b"old".to_vec())
+        );
+        // The exported files stay
+        assert!(metadata.files.iter().all(|file| file.path.exists()));
+    }
+
+    #[test]
+    fn block_caches_are_warmed_up_from_a_dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let dump_path = dir.path().join("block_cache.dump");
+        let open = |cache: &Arc<BlockCache>| {
+            let cf_options = ColumnFamilyOptions::default().with_block_cache(cache.clone());
+            Db::open(
+                dir.path(),
+                Options::default().with_default_cf_options(cf_options),
+            )
+            .unwrap()
+        };
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let db = open(&cache);
+        for n in 0..1000_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        db.flush().unwrap();
+
+        for n in [1_u32, 500, 999] {
+            db.get(&n.to_be_bytes()).unwrap();
+        }
+        assert_eq!(db.dump_block_cache(&dump_path).unwrap(), 3);
+        let usage = cache.usage();
+        drop(db);
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let db = open(&cache);
+        assert_eq!(db.load_block_cache(&dump_path).unwrap(), 3);
+        assert_eq!(cache.usage(), usage);
+
+        perf_context::enable();
+        perf_context::reset();
+        db.get(&500_u32.to_be_bytes()).unwrap();
+        assert_eq!(perf_context::get().block_cache_hit_count, 1);
+        perf_context::disable();
+    }
+
+    #[test]
+    fn pinned_values_borrow_their_data_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let cf_options = ColumnFamilyOptions::default()
+            .with_merge_operator(Arc::new(UInt64Add))
+            .with_block_cache(cache.clone());
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(cf_options),
+        )
+        .unwrap();
+
+        db.put(b"large", &[7; 10_000]).unwrap();
+        db.merge(b"counter", &1_u64.to_le_bytes()).unwrap();
+        db.flush().unwrap();
+
+        let large = db.get_pinned(b"large").unwrap().unwrap();
+        assert!(large.is_pinned());
+        assert_eq!(&*large, &[7; 10_000]);
+
+        // The block outlives its eviction
+        let cache_usage = cache.usage();
+        for n in 0..2000_u32 {
+            db.put(&n.to_be_bytes(), &[0; 1000]).unwrap();
+        }
+        db.flush().unwrap();
+        for n in 0..2000_u32 {
+            db.get(&n.to_be_bytes()).unwrap();
+        }
+        assert!(cache.usage() > cache_usage);
+        assert_eq!(&*large, &[7; 10_000]);
+
+        db.put(b"fresh", b"value").unwrap();
+        db.merge(b"counter", &2_u64.to_le_bytes()).unwrap();
+        let fresh = db.get_pinned(b"fresh").unwrap().unwrap();
+        assert!(!fresh.is_pinned());
+        assert_eq!(&*fresh, b"value");
+        assert_eq!(
+            &*db.get_pinned(b"counter").unwrap().unwrap(),
+            &3_u64.to_le_bytes()
+        );
+        assert!(db.get_pinned(b"missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn point_lookups_are_answered_by_the_row_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(RowCache::new(1 << 20));
+        let cf_options = ColumnFamilyOptions::default()
+            .with_merge_operator(Arc::new(UInt64Add))
+            .with_row_cache(cache.clone());
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(cf_options),
+        )
+        .unwrap();
+
+        db.put(b"key", b"first").unwrap();
+        db.merge(b"counter", &1_u64.to_le_bytes()).unwrap();
+        let snapshot = db.snapshot();
+        db.put(b"key", b"second").unwrap();
+        db.merge(b"counter", &2_u64.to_le_bytes()).unwrap();
+        db.flush().unwrap();
+
+        perf_context::enable();
+        perf_context::reset();
+
+        for _ in 0..2 {
+            assert_eq!(db.get(b"key").unwrap(), Some(b"second".to_vec()));
+            assert_eq!(
+                db.get(b"counter").unwrap(),
+                Some(3_u64.to_le_bytes().to_vec())
+            );
+        }
+        assert_eq!(perf_context::get().row_cache_hit_count, 2);
+        assert!(cache.usage() > 0);
+        assert_eq!(db.memory_usage().row_cache, cache.usage());
+
+        // Older reads don't see every cached version
+        assert_eq!(
+            db.get_at(b"key", &snapshot).unwrap(),
+            Some(b"first".to_vec())
+        );
+        assert_eq!(perf_context::get().row_cache_hit_count, 2);
+
+        db.put(b"key", b"third").unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.get(b"key").unwrap(), Some(b"third".to_vec()));
+        perf_context::disable();
+    }
+
+    #[test]
+    fn reads_as_of_a_sequence_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        db.put(b"key", b"first").unwrap();
+        let first = db.latest_sequence_number();
+        db.flush().unwrap();
+        db.put(b"key", b"second").unwrap();
+        db.put(b"other", b"value").unwrap();
+
+        assert_eq!(db.oldest_readable_sequence_number(), 0);
+
+        let at = |sequence| ReadOptions {
+            sequence: Some(sequence),
+            ..ReadOptions::default()
+        };
+
+        assert_eq!(
+            db.get_with_options(b"key", &at(first)).unwrap(),
+            Some(b"first".to_vec())
+        );
+        assert_eq!(db.get_with_options(b"key", &at(first - 1)).unwrap(), None);
+        assert_eq!(
+            db.multi_get_with_options(&[b"key", b"other"], &at(first))
+                .unwrap(),
+            vec![Some(b"first".to_vec()), None]
+        );
+        assert_eq!(
+            collect(db.iter_with_options(&at(first))),
+            vec![(b"key".to_vec(), b"first".to_vec())]
+        );
+        assert_eq!(collect(db.iter_with_options(&at(u64::MAX))).len(), 2);
+
+        // The snapshot wins
+        let read_options = ReadOptions {
+            snapshot: Some(db.snapshot()),
+            ..at(first)
+        };
+        assert_eq!(
+            db.get_with_options(b"key", &read_options).unwrap(),
+            Some(b"second".to_vec())
+        );
+    }
+
+    #[test]
+    fn user_timestamps_read_as_of_a_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = small_options()
+            .with_cf_options("versions", small_cf_options().with_user_timestamps(true));
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+        let cf = db.create_cf("versions").unwrap();
+
+        db.put_cf_with_ts(&cf, b"key", 10, b"v10").unwrap();
+        db.put_cf_with_ts(&cf, b"key", 20, b"v20").unwrap();
+        db.flush().unwrap();
+        db.delete_cf_with_ts(&cf, b"key", 30).unwrap();
+        db.put_cf_with_ts(&cf, b"key\x00", 15, b"other").unwrap();
+
+        assert!(matches!(
+            db.put_cf(&cf, b"key", b"value"),
+            Err(DbError::InvalidArgument(_))
+        ));
+
+        let at = |timestamp| ReadOptions {
+            timestamp,
+            ..ReadOptions::default()
+        };
+        let get = |ts| db.get_cf_with_ts(&cf, b"key", &at(Some(ts))).unwrap();
+
+        assert_eq!(get(5), None);
+        assert_eq!(get(15), Some(b"v10".to_vec()));
+        assert_eq!(get(25), Some(b"v20".to_vec()));
+        assert_eq!(get(35), None);
+        assert_eq!(db.get_cf_with_ts(&cf, b"key", &at(None)).unwrap(), None);
+
+        let mut iter = db.iter_cf_with_ts(&cf, &at(Some(25))).unwrap();
+        let mut entries = Vec::new();
+        iter.seek_to_first().unwrap();
+
+        while iter.valid() {
+            entries.push((iter.key().to_vec(), iter.timestamp(), iter.value().to_vec()));
+            iter.next().unwrap();
+        }
+
+        assert_eq!(
+            entries,
+            vec![
+                (b"key".to_vec(), 20, b"v20".to_vec()),
+                (b"key\x00".to_vec(), 15, b"other".to_vec()),
+            ]
+        );
+
+        db.increase_full_history_ts_low(&cf, 12).unwrap();
+        assert!(db.increase_full_history_ts_low(&cf, 11).is_err());
+        assert!(db.get_cf_with_ts(&cf, b"key", &at(Some(11))).is_err());
+        assert!(db
+            .increase_full_history_ts_low(&db.default_cf(), 12)
+            .is_err());
+
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        let cf = db.cf_handle("versions").unwrap();
+
+        assert_eq!(db.full_history_ts_low(&cf).unwrap(), 12);
+        assert_eq!(
+            db.get_cf_with_ts(&cf, b"key", &at(Some(12))).unwrap(),
+            Some(b"v10".to_vec())
+        );
+    }
+
+    #[test]
+    fn flushes_drop_the_history_before_the_horizon() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(
+            dir.path(),
+            Options::default()
+                .with_default_cf_options(ColumnFamilyOptions::default().with_user_timestamps(true)),
+        )
+        .unwrap();
+        let cf = db.default_cf();
+        let num_entries = |db: &Db| {
+            db.live_files()
+                .iter()
+                .map(|file| file.num_entries)
+                .collect::<Vec<_>>()
+        };
+
+        for ts in 1..=5 {
+            db.put_cf_with_ts(&cf, b"key", ts, &[ts as u8]).unwrap();
+        }
+        db.put_cf_with_ts(&cf, b"other", 1, b"value").unwrap();
+        db.increase_full_history_ts_low(&cf, 3).unwrap();
+        db.flush().unwrap();
+
+        // The versions at 3, 4 and 5, and the only one of the other key
+        assert_eq!(num_entries(&db), vec![4]);
+
+        let read_options = ReadOptions {
+            timestamp: Some(3),
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            db.get_cf_with_ts(&cf, b"key", &read_options).unwrap(),
+            Some(vec![3])
+        );
+
+        // A snapshot taken before the version at 4 still sees the one at 3 as of 5
+        for ts in 1..=3 {
+            db.put_cf_with_ts(&cf, b"new", ts, &[ts as u8]).unwrap();
+        }
+        let snapshot = db.snapshot();
+        db.put_cf_with_ts(&cf, b"new", 4, &[4]).unwrap();
+        db.increase_full_history_ts_low(&cf, 5).unwrap();
+        db.flush().unwrap();
+
+        assert_eq!(num_entries(&db), vec![2, 4]);
+
+        let read_options = ReadOptions {
+            snapshot: Some(snapshot),
+            timestamp: Some(5),
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            db.get_cf_with_ts(&cf, b"new", &read_options).unwrap(),
+            Some(vec![3])
+        );
+    }
+
+    #[test]
+    fn expired_entries_read_as_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        let hour = Duration::from_secs(3600);
+
+        db.put(b"expired", b"old").unwrap();
+        db.put_with_ttl(b"expired", b"new", Duration::ZERO).unwrap();
+        db.put_with_ttl(b"live", b"value", hour).unwrap();
+
+        let check = |db: &Db| {
+            assert_eq!(db.get(b"expired").unwrap(), None);
+            assert_eq!(db.get(b"live").unwrap(), Some(b"value".to_vec()));
+            assert_eq!(
+                collect(db.iter()),
+                vec![(b"live".to_vec(), b"value".to_vec())]
+            );
+        };
+
+        check(&db);
+        db.flush().unwrap();
+        check(&db);
+
+        // The expired value was not written out
+        let files = db.live_files();
+        assert_eq!(files[0].num_entries, 3);
+        assert_eq!(files[0].num_deletions, 1);
+
+        drop(db);
+        check(&Db::open(dir.path(), small_options()).unwrap());
+    }
+
+    #[test]
+    fn persistent_snapshots_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        db.put(b"key", b"old").unwrap();
+        let snapshot = db.create_persistent_snapshot("backup").unwrap();
+        assert!(db.create_persistent_snapshot("backup").is_err());
+        db.put(b"key", b"new").unwrap();
+        db.flush().unwrap();
+        drop(snapshot);
+        crash(db);
+
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        let snapshot = db.persistent_snapshot("backup").unwrap();
+
+        assert_eq!(db.persistent_snapshot_names(), vec!["backup".to_string()]);
+        assert_eq!(db.get_at(b"key", &snapshot).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(b"key").unwrap(), Some(b"new".to_vec()));
+        assert!(db.latest_sequence_number() > snapshot.sequence());
+
+        db.release_persistent_snapshot("backup").unwrap();
+        assert!(db.release_persistent_snapshot("backup").is_err());
+        assert!(db.persistent_snapshot("backup").is_none());
+
+        // Still usable until dropped
+        assert_eq!(db.get_at(b"key", &snapshot).unwrap(), Some(b"old".to_vec()));
+
+        drop(snapshot);
+        drop(db);
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        assert!(db.persistent_snapshot_names().is_empty());
+    }
+
+    #[test]
+    fn tailing_iterators_see_later_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+
+        db.put(b"a", b"1").unwrap();
+        db.put(b"c", b"3").unwrap();
+
+        let mut iter = db.tailing_iter(&ReadOptions::default()).unwrap();
+        iter.seek_to_first().unwrap();
+        assert_eq!(iter.key(), b"a");
+
+        db.put(b"b", b"2").unwrap();
+        iter.next().unwrap();
+        assert_eq!((iter.key(), iter.value()), (&b"b"[..], &b"2"[..]));
+
+        iter.next().unwrap();
+        assert_eq!(iter.key(), b"c");
+        iter.next().unwrap();
+        assert!(!iter.valid());
+
+        db.put(b"d", b"4").unwrap();
+        db.flush().unwrap();
+        iter.next().unwrap();
+        assert_eq!((iter.key(), iter.value()), (&b"d"[..], &b"4"[..]));
+
+        db.put(b"d", b"5").unwrap();
+        assert_eq!(iter.value(), b"4");
+        iter.refresh().unwrap();
+        assert_eq!((iter.key(), iter.value()), (&b"d"[..], &b"5"[..]));
+
+        let snapshot = db.snapshot();
+        assert!(db
+            .tailing_iter(&ReadOptions {
+                snapshot: Some(snapshot),
+                ..ReadOptions::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn watches_receive_the_writes_to_their_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), small_options()).unwrap();
+        let cf = db.create_cf("other").unwrap();
+
+        db.put(b"b", b"before").unwrap();
+
+        let watch = db.watch(b"b".to_vec()..b"d".to_vec());
+        let everything = db.watch_cf(&cf, ..).unwrap();
+        let dropped = db.watch(..);
+        drop(dropped);
+
+        db.put(b"a", b"outside").unwrap();
+        db.put(b"b", b"1").unwrap();
+        db.put_cf(&cf, b"b", b"2").unwrap();
+        db.delete(b"c").unwrap();
+        db.delete_range(b"a", b"b").unwrap();
+        db.delete_range(b"c", b"z").unwrap();
+
+        let events: Vec<_> = std::iter::from_fn(|| watch.try_recv())
+            .map(|event| (event.key, event.change))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (b"b".to_vec(), Change::Put(b"1".to_vec())),
+                (b"c".to_vec(), Change::Delete),
+                (b"c".to_vec(), Change::DeleteRange { end: b"z".to_vec() }),
+            ]
+        );
+
+        let event = everything.recv().unwrap();
+        assert_eq!(event.column_family_id, cf.id());
+        assert_eq!(event.change, Change::Put(b"2".to_vec()));
+        assert_eq!(everything.try_recv(), None);
+
+        let thread_watch = db.watch(..);
+        std::thread::scope(|scope| {
+            let receiver = scope.spawn(|| thread_watch.recv());
+            db.put(b"k", b"v").unwrap();
+
+            assert_eq!(receiver.join().unwrap().unwrap().key, b"k");
+        });
+
+        drop(db);
+        assert_eq!(watch.recv(), None);
+    }
+
+    #[test]
+    fn compactions_merge_level0_into_the_lower_levels() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = Arc::new(RecordingListener::default());
+        let options = Options::default()
+            .with_default_cf_options(
+                small_cf_options()
+                    .with_level0_file_num_compaction_trigger(2)
+                    .with_max_bytes_for_level(4096, 2),
+            )
+            .with_listener(listener.clone());
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        let snapshot = db.snapshot();
+        db.put(b"kept", b"old").unwrap();
+        let kept_snapshot = db.snapshot();
+        db.put(b"kept", b"new").unwrap();
+        db.put(b"gone", b"value").unwrap();
+        db.delete(b"gone").unwrap();
+        db.delete_range(&0_u32.to_be_bytes(), &10_u32.to_be_bytes())
+            .unwrap();
+        drop(snapshot);
+
+        for n in 0..1000_u32 {
+            db.put(&n.to_be_bytes(), &[n as u8; 50]).unwrap();
+        }
+        db.flush().unwrap();
+        db.wait_for_background_work();
+
+        let levels: Vec<_> = (0..4)
+            .map(|level| {
+                db.get_property(&format!("fyodor.num-files-at-level{}", level))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap()
+            })
+            .collect();
+        assert!(levels[0] < 2, "{:?}", levels);
+        assert!(levels[2] > 0, "{:?}", levels);
+        assert!(listener
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|event| event.starts_with("compaction completed")));
+
+        let check = |db: &Db| {
+            assert_eq!(db.get(b"kept").unwrap(), Some(b"new".to_vec()));
+            assert_eq!(db.get(b"gone").unwrap(), None);
+            for n in [0_u32, 9, 10, 500, 999] {
+                assert_eq!(db.get(&n.to_be_bytes()).unwrap(), Some(vec![n as u8; 50]));
+            }
+            assert_eq!(collect(db.iter()).len(), 1001);
+        };
+        check(&db);
+        assert_eq!(
+            db.get_at(b"kept", &kept_snapshot).unwrap(),
+            Some(b"old".to_vec())
+        );
+        assert!(db.oldest_readable_sequence_number() > 0);
+
+        let mut live: Vec<_> = db
+            .live_files()
+            .iter()
+            .map(|file| file.path.clone())
+            .collect();
+        let mut on_disk: Vec<_> = table_numbers(dir.path())
+            .into_iter()
+            .map(|number| crate::table::table_file_name(dir.path(), number))
+            .collect();
+        live.sort();
+        on_disk.sort();
+        assert_eq!(live, on_disk);
+
+        drop(kept_snapshot);
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        check(&db);
+    }
+
+    #[test]
+    fn universal_compactions_keep_few_sorted_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            small_cf_options()
+                .with_compaction_style(CompactionStyle::Universal)
+                .with_level0_file_num_compaction_trigger(3),
+        );
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        for round in 0..20_u32 {
+            for n in 0..20_u32 {
+                db.put(&n.to_be_bytes(), &round.to_be_bytes()).unwrap();
+            }
+            db.flush().unwrap();
+            db.wait_for_background_work();
+
+            let files = db.live_files();
+            let level0_files = files.iter().filter(|file| file.level == 0).count();
+            let mut levels: Vec<_> = files
+                .iter()
+                .filter(|file| file.level > 0)
+                .map(|file| file.level)
+                .collect();
+            levels.sort();
+            levels.dedup();
+            assert!(level0_files + levels.len() < 3);
+        }
+
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(
+            collect(db.iter()),
+            (0..20_u32)
+                .map(|n| (n.to_be_bytes().to_vec(), 19_u32.to_be_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn fifo_compactions_delete_the_oldest_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            small_cf_options()
+                .with_compaction_style(CompactionStyle::Fifo)
+                .with_fifo_max_table_files_size(4096),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for round in 0..20_u32 {
+            for n in 0..20_u32 {
+                db.put(&(round * 20 + n).to_be_bytes(), &[0; 64]).unwrap();
+            }
+            db.flush().unwrap();
+            db.wait_for_background_work();
+
+            let files = db.live_files();
+            assert!(files.iter().all(|file| file.level == 0));
+            assert!(files.iter().map(|file| file.size).sum::<u64>() <= 4096);
+        }
+
+        // The newest writes are kept, the oldest ones are gone
+        assert!(db.get(&399_u32.to_be_bytes()).unwrap().is_some());
+        assert_eq!(db.get(&0_u32.to_be_bytes()).unwrap(), None);
+        assert!(table_numbers(dir.path()).len() < 20);
+
+        // Every table is older than a zero TTL
+        db.set_options(&[("fifo_ttl_ms", "0")]).unwrap();
+        db.put(b"key", b"value").unwrap();
+        db.flush().unwrap();
+        db.wait_for_background_work();
+        assert!(db.live_files().is_empty());
+        assert_eq!(db.get(b"key").unwrap(), None);
+    }
+
+    #[test]
+    fn compact_range_moves_the_range_to_the_bottom_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default()
+            .with_default_cf_options(small_cf_options().with_disable_auto_compactions(true));
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        db.flush().unwrap();
+        db.delete_range(&0_u32.to_be_bytes(), &150_u32.to_be_bytes())
+            .unwrap();
+        db.delete(&199_u32.to_be_bytes()).unwrap();
+
+        let size_before: u64 = db.live_files().iter().map(|file| file.size).sum();
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+
+        let files = db.live_files();
+        assert!(files
+            .iter()
+            .all(|file| file.level == crate::version::NUM_LEVELS - 1));
+        assert!(files.iter().map(|file| file.size).sum::<u64>() < size_before);
+        assert_eq!(
+            collect(db.iter()),
+            (150..199_u32)
+                .map(|n| (n.to_be_bytes().to_vec(), vec![1; 32]))
+                .collect::<Vec<_>>()
+        );
+
+        // The tables already in the bottom level are left alone unless asked for
+        let numbers = |db: &Db| -> Vec<u64> {
+            db.live_files()
+                .iter()
+                .map(|file| file.file_number)
+                .collect()
+        };
+        let before = numbers(&db);
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        assert_eq!(numbers(&db), before);
+
+        let options = CompactRangeOptions {
+            bottommost_level_compaction: true,
+            ..CompactRangeOptions::default()
+        };
+        db.compact_range(Some(&180_u32.to_be_bytes()), None, &options)
+            .unwrap();
+        assert_ne!(numbers(&db), before);
+        assert_eq!(collect(db.iter()).len(), 49);
+    }
+
+    #[test]
+    fn flushes_run_in_the_background_and_report_their_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = Arc::new(RecordingListener::default());
+        let options = small_options().with_listener(listener.clone());
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        db.wait_for_background_work();
+        assert!(!table_numbers(dir.path()).is_empty());
+        assert_eq!(db.get_property("fyodor.background-errors").unwrap(), "0");
+
+        // The writers don't see the flushes failing
+        std::fs::remove_dir_all(dir.path()).unwrap();
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        db.wait_for_background_work();
+
+        assert_ne!(db.get_property("fyodor.background-errors").unwrap(), "0");
+        assert!(listener
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|event| event.starts_with("background error")));
+        assert_eq!(db.get(&99_u32.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+    }
+
+    #[test]
+    fn flushes_write_their_tables_with_the_database_unlocked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (listener, begun, resume) = BlockingListener::new();
+        let db = Db::open(dir.path(), Options::default().with_listener(listener)).unwrap();
+        let property = |name| db.get_property(name).unwrap();
+
+        db.put(b"key", b"value").unwrap();
+
+        std::thread::scope(|scope| {
+            let flush = scope.spawn(|| db.flush());
+            begun.recv().unwrap();
+
+            // The flushed writes are read from the immutable memtable meanwhile
+            assert_eq!(property("fyodor.num-immutable-mem-table"), "1");
+            assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+            db.put(b"other", b"value").unwrap();
+            assert_eq!(collect(db.iter()).len(), 2);
+
+            resume.send(()).unwrap();
+            flush.join().unwrap().unwrap();
+        });
+
+        assert_eq!(property("fyodor.num-immutable-mem-table"), "0");
+        assert_eq!(property("fyodor.num-files-at-level0"), "1");
+        assert_eq!(property("fyodor.num-entries-active-mem-table"), "1");
+        assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn tables_overlapping_nothing_below_are_moved_as_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default().with_disable_auto_compactions(true),
+        );
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        db.flush().unwrap();
+        let flushed = db.live_files();
+        assert_eq!(flushed.len(), 1);
+
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        let moved = db.live_files();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].file_number, flushed[0].file_number);
+        assert_eq!(moved[0].level, crate::version::NUM_LEVELS - 1);
+
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(db.live_files()[0].level, crate::version::NUM_LEVELS - 1);
+        assert_eq!(db.get(&42_u32.to_be_bytes()).unwrap(), Some(vec![1; 32]));
+    }
+
+    #[test]
+    fn bottommost_tables_have_their_own_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default()
+                .with_disable_auto_compactions(true)
+                .with_bottommost_compression(CompressionType::Zstd),
+        );
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        for value in [1, 2] {
+            for n in 0..200_u32 {
+                db.put(&n.to_be_bytes(), &[value; 100]).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        let flushed = db.live_files();
+        assert_eq!(flushed.len(), 2);
+
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        let compacted = db.live_files();
+        assert_eq!(compacted.len(), 1);
+        assert_eq!(compacted[0].level, crate::version::NUM_LEVELS - 1);
+        assert!(compacted[0].size < flushed[0].size / 2);
+
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(db.get(&42_u32.to_be_bytes()).unwrap(), Some(vec![2; 100]));
+    }
+
+    #[test]
+    fn levels_have_their_own_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cf_options = ColumnFamilyOptions::default()
+            .with_disable_auto_compactions(true)
+            .with_compression(CompressionType::Zstd);
+        cf_options.set("compression_per_level", "none:lz4").unwrap();
+        assert_eq!(
+            cf_options.compression_per_level,
+            vec![CompressionType::None, CompressionType::Lz4]
+        );
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(cf_options),
+        )
+        .unwrap();
+
+        for value in [1, 2] {
+            for n in 0..200_u32 {
+                db.put(&n.to_be_bytes(), &[value; 100]).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        // Level 0 is left uncompressed, and the levels below level 1 go on with its compression
+        let flushed = db.live_files();
+        assert!(flushed[0].size > 200 * 100);
+
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        let compacted = db.live_files();
+        assert_eq!(compacted[0].level, crate::version::NUM_LEVELS - 1);
+        assert!(compacted[0].size < flushed[0].size / 2);
+        assert_eq!(db.get(&42_u32.to_be_bytes()).unwrap(), Some(vec![2; 100]));
+    }
+
+    #[test]
+    fn compactions_are_counted_by_level() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default().with_disable_auto_compactions(true),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for value in [1, 2] {
+            for n in 0..200_u32 {
+                db.put(&n.to_be_bytes(), &[value; 100]).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        let flushed: u64 = db.live_files().iter().map(|file| file.size).sum();
+
+        let stats = db.compaction_stats();
+        assert_eq!(stats.len(), crate::version::NUM_LEVELS);
+        assert_eq!((stats[0].count, stats[0].num_files), (2, 2));
+        assert_eq!(stats[0].bytes_written, flushed);
+        assert_eq!(stats[0].bytes_read_input_levels, 2 * 200 * (4 + 8 + 100));
+
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        let compacted = db.live_files();
+        let stats = db.compaction_stats();
+        // The tables of level 0 are merged right into the last level, empty
+        let last = &stats[crate::version::NUM_LEVELS - 1];
+        assert_eq!((last.count, last.num_files), (1, 1));
+        assert_eq!(last.bytes_read_input_levels, flushed);
+        assert_eq!(last.bytes_read_output_level, 0);
+        assert_eq!(last.bytes_written, compacted[0].size);
+        assert!(last.write_amplification() < 1.0);
+        assert_eq!(last.read_amplification(), 1.0);
+        assert_eq!((stats[1].count, stats[1].bytes_written), (0, 0));
+
+        let property = db.get_property("fyodor.compaction-stats").unwrap();
+        assert_eq!(property.lines().count(), crate::version::NUM_LEVELS + 1);
+        assert!(property.lines().nth(2).unwrap().starts_with("    1     0"));
+    }
+
+    #[test]
+    fn suggested_ranges_are_compacted_in_the_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), &[2; 32]).unwrap();
+        }
+        db.flush().unwrap();
+        db.suggest_compact_range(Some(&300_u32.to_be_bytes()), None)
+            .unwrap();
+        db.wait_for_background_work();
+        assert_eq!(db.live_files().len(), 2);
+
+        // The new table goes down level by level, until merged with the old one
+        db.suggest_compact_range(None, Some(&0_u32.to_be_bytes()))
+            .unwrap();
+        db.wait_for_background_work();
+        let files = db.live_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].level, crate::version::NUM_LEVELS - 1);
+        assert_eq!(db.get(&42_u32.to_be_bytes()).unwrap(), Some(vec![2; 32]));
+    }
+
+    #[test]
+    fn files_in_range_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default().with_disable_auto_compactions(true),
+        );
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        for range in [0..100_u32, 100..200, 200..300] {
+            for n in range {
+                db.put(&n.to_be_bytes(), &[1; 32]).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        assert_eq!(table_numbers(dir.path()).len(), 3);
+
+        // The table is only partly in the range
+        db.delete_files_in_range(Some(&100_u32.to_be_bytes()), Some(&150_u32.to_be_bytes()))
+            .unwrap();
+        assert_eq!(db.live_files().len(), 3);
+
+        db.delete_files_in_range(Some(&50_u32.to_be_bytes()), Some(&250_u32.to_be_bytes()))
+            .unwrap();
+        assert_eq!(db.live_files().len(), 2);
+        assert_eq!(table_numbers(dir.path()).len(), 2);
+        assert_eq!(db.get(&150_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(db.get(&50_u32.to_be_bytes()).unwrap(), Some(vec![1; 32]));
+
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(db.get(&150_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(db.get(&250_u32.to_be_bytes()).unwrap(), Some(vec![1; 32]));
+    }
+
+    #[test]
+    fn compactions_combine_merge_operands() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default()
+                .with_disable_auto_compactions(true)
+                .with_merge_operator(Arc::new(UInt64Add)),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+        let num_entries = || -> u64 { db.live_files().iter().map(|file| file.num_entries).sum() };
+        let get = |key: &[u8]| db.get(key).unwrap().map(|value| value.try_into().unwrap());
+
+        for _ in 0..10 {
+            for _ in 0..100 {
+                db.merge(b"counter", &1_u64.to_le_bytes()).unwrap();
+            }
+            db.flush().unwrap();
+        }
+
+        db.put(b"value", &10_u64.to_le_bytes()).unwrap();
+        db.put(b"deleted", &10_u64.to_le_bytes()).unwrap();
+        db.delete(b"deleted").unwrap();
+        for _ in 0..5 {
+            db.merge(b"value", &1_u64.to_le_bytes()).unwrap();
+            db.merge(b"deleted", &2_u64.to_le_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+        assert!(num_entries() > 1000);
+
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        assert_eq!(num_entries(), 3);
+        assert_eq!(get(b"counter"), Some(1000_u64.to_le_bytes()));
+        assert_eq!(get(b"value"), Some(15_u64.to_le_bytes()));
+        assert_eq!(get(b"deleted"), Some(10_u64.to_le_bytes()));
+
+        // The operands written after a snapshot stay apart from the result it sees
+        let snapshot = db.snapshot();
+        db.merge(b"counter", &1_u64.to_le_bytes()).unwrap();
+        db.merge(b"counter", &1_u64.to_le_bytes()).unwrap();
+        let compact_options = CompactRangeOptions {
+            bottommost_level_compaction: true,
+            ..CompactRangeOptions::default()
+        };
+        db.compact_range(None, None, &compact_options).unwrap();
+        assert_eq!(num_entries(), 5);
+        assert_eq!(get(b"counter"), Some(1002_u64.to_le_bytes()));
+        assert_eq!(
+            db.get_at(b"counter", &snapshot).unwrap(),
+            Some(1000_u64.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn compaction_outputs_are_cut_at_the_target_file_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default()
+                .with_disable_auto_compactions(true)
+                .with_target_file_size(16 << 10, 2),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for value in [1, 2] {
+            for n in 0..1000_u32 {
+                db.put(&n.to_be_bytes(), &[value; 100]).unwrap();
+            }
+            db.flush().unwrap();
+        }
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+
+        // The last level gets tables of 16KB times 2^5
+        let files = db.live_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].level, crate::version::NUM_LEVELS - 1);
+
+        let db = {
+            drop(db);
+            let options = Options::default().with_default_cf_options(
+                ColumnFamilyOptions::default()
+                    .with_disable_auto_compactions(true)
+                    .with_target_file_size(16 << 10, 1),
+            );
+            Db::open(dir.path(), options).unwrap()
+        };
+        for n in 0..1000_u32 {
+            db.put(&n.to_be_bytes(), &[3; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+
+        let files = db.live_files();
+        assert!(files.len() > 4);
+
+        for (file, next) in files.iter().zip(&files[1..]) {
+            assert!(file.size >= 16 << 10);
+            assert!(file.largest_key < next.smallest_key);
+        }
+
+        for n in 0..1000_u32 {
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), Some(vec![3; 100]));
+        }
+    }
+
+    #[test]
+    fn compaction_outputs_are_cut_inside_range_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default()
+                .with_disable_auto_compactions(true)
+                .with_target_file_size(16 << 10, 1),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for n in 0..1000_u32 {
+            db.put(&n.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        // Keeps the deleted keys, and the tombstone along with them
+        let snapshot = db.snapshot();
+        db.delete_range(&0_u32.to_be_bytes(), &1000_u32.to_be_bytes())
+            .unwrap();
+        db.flush().unwrap();
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+
+        {
+            let state = db.inner.state.lock().unwrap();
+            let version = state.versions.current();
+            let files = &version.column_family(0).unwrap().levels[NUM_LEVELS - 1];
+            assert!(files.len() > 4);
+
+            // Each output ends with its part of the tombstone, excluding the first key of the
+            // next one
+            for (file, next) in files.iter().zip(&files[1..]) {
+                assert!(file.ends_before(key::user_key(&next.smallest_key)));
+                assert!(file.ends_before(key::user_key(&file.largest_key)));
+                assert!(file.file_size < 32 << 10);
+            }
+
+            let tables = &state.column_families[&0].tables;
+            assert_eq!(tables.len(), files.len());
+            assert!(tables
+                .iter()
+                .all(|table| table.properties().num_range_deletions == 1));
+        }
+
+        for n in (0..1000_u32).step_by(100) {
+            assert_eq!(db.get(&n.to_be_bytes()).unwrap(), None);
+        }
+
+        drop(snapshot);
+        let compact_options = CompactRangeOptions {
+            bottommost_level_compaction: true,
+            ..CompactRangeOptions::default()
+        };
+        db.compact_range(None, None, &compact_options).unwrap();
+        assert!(db.live_files().is_empty());
+    }
+
+    #[test]
+    fn tables_full_of_deletions_are_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default().with_deletion_compaction_ratio(0.5),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+
+        for n in 0..80_u32 {
+            db.delete(&n.to_be_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+        db.wait_for_background_work();
+
+        // The deletions went down to the last level, and were dropped there with the keys
+        let files = db.live_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].level, crate::version::NUM_LEVELS - 1);
+        assert_eq!(collect(db.iter()).len(), 20);
+    }
+
+    #[test]
+    fn old_tables_are_compacted_periodically() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default().with_periodic_compaction_seconds(1),
+        );
+        let db = Db::open(dir.path(), options).unwrap();
+
+        db.put(b"cold", b"value").unwrap();
+        db.flush().unwrap();
+        let flushed = db.live_files()[0].file_number;
+
+        // The table goes down to the last level, where it's rewritten, without any write
+        let start = std::time::Instant::now();
+        let rewritten = loop {
+            db.wait_for_background_work();
+            let files = db.live_files();
+
+            if files[0].file_number != flushed {
+                break files;
+            }
+
+            assert!(start.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        };
+
+        assert_eq!(rewritten.len(), 1);
+        assert_eq!(rewritten[0].level, crate::version::NUM_LEVELS - 1);
+        assert_eq!(db.get(b"cold").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn writes_go_on_while_compactions_run() {
+        let dir = tempfile::tempdir().unwrap();
+        // Slows the compaction down to a few tenths of a second
+        let limiter = Arc::new(RateLimiter::new(64 << 10));
+        let options = Options::default()
+            .with_default_cf_options(small_cf_options().with_disable_auto_compactions(true))
+            .with_rate_limiter(limiter.clone());
+        let db = Arc::new(Db::open(dir.path(), options).unwrap());
+
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        db.flush().unwrap();
+
+        let compacting_db = db.clone();
+        let compaction = std::thread::spawn(move || {
+            compacting_db
+                .compact_range(None, None, &CompactRangeOptions::default())
+                .unwrap()
+        });
+
+        while db.get_property("fyodor.num-running-compactions").unwrap() == "0" {
+            std::thread::yield_now();
+        }
+        db.put(b"during", b"compaction").unwrap();
+        assert_eq!(
+            db.get_property("fyodor.num-running-compactions").unwrap(),
+            "1"
+        );
+
+        compaction.join().unwrap();
+        assert_eq!(db.get(b"during").unwrap(), Some(b"compaction".to_vec()));
+        assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), Some(vec![1; 32]));
+    }
+
+    #[test]
+    fn flushes_and_compactions_go_through_the_rate_limiter() {
+        let dir = tempfile::tempdir().unwrap();
+        let limiter = Arc::new(RateLimiter::new(64 << 20).with_limit_reads(true));
+        let options = Options::default()
+            .with_default_cf_options(small_cf_options().with_disable_auto_compactions(true))
+            .with_rate_limiter(limiter.clone());
+        let db = Db::open(dir.path(), options).unwrap();
+
+        for n in 0..200_u32 {
+            db.put(&n.to_be_bytes(), &[1; 32]).unwrap();
+        }
+        db.flush().unwrap();
+
+        let flushed = limiter.total_bytes_through();
+        assert!(flushed > 200 * 32);
+
+        // The compaction reads the table and writes it again
+        db.compact_range(None, None, &CompactRangeOptions::default())
+            .unwrap();
+        let compacted = limiter.total_bytes_through() - flushed;
+        assert!(compacted > flushed);
+
+        limiter.set_bytes_per_second(1 << 20);
+        assert_eq!(db.get(&7_u32.to_be_bytes()).unwrap(), Some(vec![1; 32]));
+    }
+
+    #[test]
+    fn subcompactions_write_disjoint_tables() {
+        #[derive(Debug, Default)]
+        struct OutputCounter {
+            max_outputs: Mutex<usize>,
+        }
+
+        impl EventListener for OutputCounter {
+            fn on_compaction_completed(&self, info: &CompactionJobInfo) {
+                let mut max_outputs = self.max_outputs.lock().unwrap();
+                *max_outputs = (*max_outputs).max(info.output_files.len());
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let counter = Arc::new(OutputCounter::default());
+        let options = Options::default()
+            .with_default_cf_options(
+                small_cf_options()
+                    .with_level0_file_num_compaction_trigger(2)
+                    .with_max_bytes_for_level(8192, 2),
+            )
+            .with_max_subcompactions(4)
+            .with_listener(counter.clone());
+        let db = Db::open(dir.path(), options.clone()).unwrap();
+
+        for round in 0..4_u32 {
+            for n in 0..500_u32 {
+                db.put(&(n * 7 % 500).to_be_bytes(), &round.to_be_bytes())
+                    .unwrap();
+            }
+            db.delete_range(&100_u32.to_be_bytes(), &110_u32.to_be_bytes())
+                .unwrap();
+        }
+        db.flush().unwrap();
+        db.wait_for_background_work();
+
+        assert!(*counter.max_outputs.lock().unwrap() > 1);
+
+        // Cut inside the range tombstone, a table ends right before the first key of the next one
+        let version = db.inner.state.lock().unwrap().versions.current().clone();
+        for files in &version.column_family(0).unwrap().levels[1..] {
+            for pair in files.windows(2) {
+                assert!(pair[0].ends_before(key::user_key(&pair[1].smallest_key)));
+            }
+        }
+        drop(version);
+
+        let expected: Vec<_> = (0..500_u32)
+            .filter(|n| !(100..110).contains(n))
+            .map(|n| (n.to_be_bytes().to_vec(), 3_u32.to_be_bytes().to_vec()))
+            .collect();
+        assert_eq!(collect(db.iter()), expected);
+
+        drop(db);
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(collect(db.iter()), expected);
+    }
+}
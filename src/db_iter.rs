@@ -0,0 +1,691 @@
+use crate::blob::{BlobFiles, BlobIndex};
+use crate::column_family::ColumnFamily;
+use crate::db::{Db, DbError};
+use crate::iterator::{Direction, InternalIterator, MergingIterator};
+use crate::key::{self, SequenceNumber, ValueType};
+use crate::memtable::MemTable;
+use crate::merge::{self, MergeOperator};
+use crate::options::{ColumnFamilyOptions, ReadOptions};
+use crate::perf_context::{self, PerfContext};
+use crate::prefix::PrefixExtractor;
+use crate::range_del::FragmentedRangeTombstones;
+use crate::snapshot::Snapshot;
+use crate::table::Table;
+use crate::trace::{TraceOp, Tracer};
+use crate::ttl;
+use crate::version::Version;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A cursor over the user keys of a [Db](crate::Db), as of a snapshot
+///
+/// Only the most recent version of every key visible at the snapshot is returned, and deleted
+/// keys are skipped altogether. The snapshot is held for as long as the iterator lives, so the
+/// versions it reads are never compacted away under it.
+///
+/// Keys covered by a range tombstone are skipped like deleted ones, and the merge operands of a
+/// key are combined with its value by [ColumnFamilyOptions::merge_operator].
+///
+/// In [ReadOptions::prefix_same_as_start] mode, the iteration stops at the first key whose prefix
+/// differs from the one of the key the iterator was positioned at. Seeking to a prefix also skips
+/// the tables whose bloom filter rules the prefix out.
+///
+/// A fresh iterator is not positioned: one of the seek methods must be called first.
+pub struct DbIterator {
+    iter: MergingIterator,
+    /// The memtables, newest first
+    mems: Vec<Arc<MemTable>>,
+    /// The tables overlapping the bounds, newest first
+    tables: Vec<Arc<Table>>,
+    /// Keeps the files of the tables from being deleted while they're read
+    _version: Arc<Version>,
+    /// Where the values the tables point to are read from
+    blob_files: Arc<BlobFiles>,
+    /// The range tombstones of every source
+    range_tombstones: FragmentedRangeTombstones,
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+    verify_checksums: bool,
+    fill_cache: bool,
+    readahead_size: usize,
+    /// Only set in prefix mode
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// The prefix every returned key must have, in prefix mode
+    prefix: Option<Vec<u8>>,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    snapshot: Snapshot,
+    /// Moving forward, the underlying iterator is positioned at the current entry. Moving
+    /// backwards, it's positioned before every version of the current key.
+    direction: Direction,
+    valid: bool,
+    /// User key of the current entry
+    key: Vec<u8>,
+    value: Vec<u8>,
+    /// Records the seeks, along with the id of the column family, while a trace runs
+    tracer: Option<(Arc<Tracer>, u32)>,
+}
+
+/// Splits the internal key of an entry, failing on corrupted keys
+fn parse_key(internal_key: &[u8]) -> Result<(&[u8], SequenceNumber, ValueType), DbError> {
+    key::parse(internal_key).ok_or(DbError::Corruption("bad internal key"))
+}
+
+fn record_seek(ctx: &mut PerfContext, elapsed: Duration) {
+    ctx.seek_count += 1;
+    ctx.seek_time += elapsed;
+}
+
+impl DbIterator {
+    pub(crate) fn new(
+        mems: Vec<Arc<MemTable>>,
+        tables: Vec<Arc<Table>>,
+        version: Arc<Version>,
+        blob_files: Arc<BlobFiles>,
+        options: &ColumnFamilyOptions,
+        read_options: &ReadOptions,
+        snapshot: Snapshot,
+    ) -> DbIterator {
+        let lower_bound = read_options.iterate_lower_bound.clone();
+        let upper_bound = read_options.iterate_upper_bound.clone();
+
+        let mem_tombstones: Vec<_> = mems.iter().flat_map(|mem| mem.range_tombstones()).collect();
+        let range_tombstones = FragmentedRangeTombstones::new(
+            mem_tombstones
+                .iter()
+                .chain(tables.iter().flat_map(|table| table.range_tombstones())),
+        );
+
+        let tables = tables
+            .into_iter()
+            .filter(|table| table.overlaps(lower_bound.as_deref(), upper_bound.as_deref()))
+            .collect();
+
+        let mut iter = DbIterator {
+            iter: MergingIterator::new(Vec::new()),
+            mems,
+            tables,
+            _version: version,
+            blob_files,
+            range_tombstones,
+            lower_bound,
+            upper_bound,
+            verify_checksums: read_options.verify_checksums,
+            fill_cache: read_options.fill_cache,
+            readahead_size: read_options.readahead_size,
+            prefix_extractor: options
+                .prefix_extractor
+                .clone()
+                .filter(|_| read_options.prefix_same_as_start),
+            prefix: None,
+            merge_operator: options.merge_operator.clone(),
+            snapshot,
+            direction: Direction::Forward,
+            valid: false,
+            key: Vec::new(),
+            value: Vec::new(),
+            tracer: None,
+        };
+
+        iter.iter = iter.merge(None);
+        iter
+    }
+
+    /// Records the seeks to `tracer` if any, as seeks of the column family `column_family`
+    pub(crate) fn with_tracer(mut self, tracer: Option<Arc<Tracer>>, column_family: u32) -> Self {
+        self.tracer = tracer.map(|tracer| (tracer, column_family));
+        self
+    }
+
+    /// Records the seek returned by `op` if a trace runs
+    fn trace<F: FnOnce(u32) -> TraceOp>(&self, op: F) {
+        if let Some((tracer, column_family)) = &self.tracer {
+            tracer.record(op(*column_family));
+        }
+    }
+
+    /// Merges the sources which may hold keys with `prefix`, or every source if None
+    fn merge(&self, prefix: Option<&[u8]>) -> MergingIterator {
+        let upper = self.upper_bound.as_deref();
+
+        let mut children: Vec<Box<dyn InternalIterator + Send>> = Vec::new();
+
+        for mem in &self.mems {
+            children.push(Box::new(mem.iter()));
+        }
+
+        for table in &self.tables {
+            let may_match = match (&self.prefix_extractor, prefix) {
+                (Some(extractor), Some(prefix)) => {
+                    table.prefix_may_match(extractor.as_ref(), prefix)
+                }
+                _ => true,
+            };
+
+            if may_match {
+                children.push(Box::new(
+                    table
+                        .iter()
+                        .with_upper_bound(upper)
+                        .with_verify_checksums(self.verify_checksums)
+                        .with_fill_cache(self.fill_cache)
+                        .with_readahead_size(self.readahead_size),
+                ));
+            }
+        }
+
+        MergingIterator::new(children).with_bounds(self.lower_bound.as_deref(), upper)
+    }
+
+    /// In prefix mode, restarts the iteration from the sources which may hold the prefix of
+    /// `target`, which every returned key must then have
+    fn start_prefix(&mut self, target: Option<&[u8]>) {
+        if let Some(extractor) = &self.prefix_extractor {
+            self.prefix = target
+                .and_then(|target| extractor.prefix(target))
+                .map(<[u8]>::to_vec);
+            self.iter = self.merge(self.prefix.as_deref());
+        }
+    }
+
+    /// In prefix mode, uses the prefix of the current key if no prefix was set by a seek
+    fn set_prefix_from_key(&mut self) {
+        if let Some(extractor) = &self.prefix_extractor {
+            if self.valid {
+                self.prefix = extractor.prefix(&self.key).map(<[u8]>::to_vec);
+            }
+        }
+    }
+
+    /// In prefix mode, invalidates the iterator once it's past the prefix
+    fn check_prefix(&mut self) {
+        if let (Some(extractor), Some(prefix)) = (&self.prefix_extractor, &self.prefix) {
+            if self.valid && extractor.prefix(&self.key) != Some(prefix.as_slice()) {
+                self.valid = false;
+            }
+        }
+    }
+
+    fn sequence(&self) -> SequenceNumber {
+        self.snapshot.sequence()
+    }
+
+    /// Returns the type of an entry as seen through the iterator, which is a deletion if the entry
+    /// is a value or a merge operand covered by a range tombstone, or an expired value
+    fn effective_type(
+        &self,
+        user_key: &[u8],
+        seq: SequenceNumber,
+        value_type: ValueType,
+        value: &[u8],
+    ) -> ValueType {
+        let (value_type, _) = ttl::resolve(value_type, value);
+        let tombstone_seq = self
+            .range_tombstones
+            .max_covering_seq(user_key, self.sequence());
+
+        let is_live = matches!(
+            value_type,
+            ValueType::Value | ValueType::Merge | ValueType::BlobIndex
+        );
+
+        if is_live && tombstone_seq > seq {
+            ValueType::Deletion
+        } else {
+            value_type
+        }
+    }
+
+    /// Reads the value the blob `index` points to
+    fn read_blob(&self, index: &[u8]) -> Result<Vec<u8>, DbError> {
+        Ok(self.blob_files.get(&BlobIndex::decode(index)?)?)
+    }
+
+    /// Moves the underlying iterator to the first visible and live entry whose user key is not
+    /// `skip`, starting from its current position
+    fn find_next_user_entry(&mut self, mut skip: Option<Vec<u8>>) -> Result<(), DbError> {
+        while self.iter.valid() {
+            let (user_key, seq, value_type) = parse_key(self.iter.key())?;
+
+            if seq <= self.sequence() && skip.as_deref() != Some(user_key) {
+                match self.effective_type(user_key, seq, value_type, self.iter.value()) {
+                    ValueType::Deletion | ValueType::RangeDeletion => {
+                        skip = Some(user_key.to_vec())
+                    }
+                    ValueType::Value | ValueType::ValueWithExpiry => {
+                        self.key.clear();
+                        self.key.extend_from_slice(user_key);
+                        self.value.clear();
+                        self.value
+                            .extend_from_slice(ttl::resolve(value_type, self.iter.value()).1);
+                        self.valid = true;
+
+                        return Ok(());
+                    }
+                    ValueType::BlobIndex => {
+                        self.key.clear();
+                        self.key.extend_from_slice(user_key);
+                        self.value = self.read_blob(self.iter.value())?;
+                        self.valid = true;
+
+                        return Ok(());
+                    }
+                    ValueType::Merge => {
+                        self.key.clear();
+                        self.key.extend_from_slice(user_key);
+                        self.merge_forward()?;
+                        self.valid = true;
+
+                        return Ok(());
+                    }
+                }
+            }
+
+            self.iter.next()?;
+        }
+
+        self.valid = false;
+
+        Ok(())
+    }
+
+    /// Combines the merge operand the underlying iterator is positioned at with the older versions
+    /// of the current key, moving past them
+    fn merge_forward(&mut self) -> Result<(), DbError> {
+        let mut operands = vec![self.iter.value().to_vec()];
+        let mut base = None;
+
+        self.iter.next()?;
+
+        while self.iter.valid() {
+            let (user_key, seq, value_type) = parse_key(self.iter.key())?;
+
+            if user_key != self.key.as_slice() {
+                break;
+            }
+
+            match self.effective_type(user_key, seq, value_type, self.iter.value()) {
+                ValueType::Value | ValueType::ValueWithExpiry => {
+                    base = Some(ttl::resolve(value_type, self.iter.value()).1.to_vec());
+                    break;
+                }
+                ValueType::BlobIndex => {
+                    base = Some(self.read_blob(self.iter.value())?);
+                    break;
+                }
+                ValueType::Deletion | ValueType::RangeDeletion => break,
+                ValueType::Merge => operands.push(self.iter.value().to_vec()),
+            }
+
+            self.iter.next()?;
+        }
+
+        self.value = merge::full_merge(
+            self.merge_operator.as_deref(),
+            &self.key,
+            base.as_deref(),
+            &operands,
+        )?;
+
+        Ok(())
+    }
+
+    /// Moves the underlying iterator backwards until it has gone past every version of the
+    /// previous live user key, which becomes the current entry
+    fn find_prev_user_entry(&mut self) -> Result<(), DbError> {
+        // The type of the most recent visible version of the candidate key
+        let mut value_type = ValueType::Deletion;
+        // The value the merge operands of the candidate apply to, and the operands from the
+        // newest to the oldest
+        let mut base: Option<Vec<u8>> = None;
+        // Whether the base is the blob index of the value rather than the value: only the blob of
+        // the version which ends up being the base is read
+        let mut base_in_blob = false;
+        let mut operands: Vec<Vec<u8>> = Vec::new();
+
+        while self.iter.valid() {
+            let (user_key, seq, entry_type) = parse_key(self.iter.key())?;
+
+            if seq <= self.sequence() {
+                let is_live = matches!(
+                    value_type,
+                    ValueType::Value | ValueType::Merge | ValueType::BlobIndex
+                );
+
+                if is_live && user_key < self.key.as_slice() {
+                    // Every version of the candidate has been seen, and it's alive
+                    break;
+                }
+
+                if user_key != self.key.as_slice() {
+                    base = None;
+                    operands.clear();
+                }
+
+                // Versions are met from the oldest to the newest, so the last one seen wins
+                value_type = self.effective_type(user_key, seq, entry_type, self.iter.value());
+                self.key.clear();
+                self.key.extend_from_slice(user_key);
+
+                match value_type {
+                    ValueType::Value | ValueType::ValueWithExpiry => {
+                        base = Some(ttl::resolve(entry_type, self.iter.value()).1.to_vec());
+                        base_in_blob = false;
+                        operands.clear();
+                    }
+                    ValueType::BlobIndex => {
+                        base = Some(self.iter.value().to_vec());
+                        base_in_blob = true;
+                        operands.clear();
+                    }
+                    ValueType::Deletion | ValueType::RangeDeletion => {
+                        base = None;
+                        operands.clear();
+                    }
+                    ValueType::Merge => operands.insert(0, self.iter.value().to_vec()),
+                }
+            }
+
+            self.iter.prev()?;
+        }
+
+        if let Some(index) = base.as_deref().filter(|_| base_in_blob) {
+            base = Some(self.read_blob(index)?);
+        }
+
+        match value_type {
+            ValueType::Value | ValueType::ValueWithExpiry | ValueType::BlobIndex => {
+                self.value = base.unwrap_or_default()
+            }
+            ValueType::Merge => {
+                self.value = merge::full_merge(
+                    self.merge_operator.as_deref(),
+                    &self.key,
+                    base.as_deref(),
+                    &operands,
+                )?
+            }
+            ValueType::Deletion | ValueType::RangeDeletion => {}
+        }
+
+        self.valid = matches!(
+            value_type,
+            ValueType::Value | ValueType::Merge | ValueType::BlobIndex
+        );
+
+        Ok(())
+    }
+
+    /// Returns true if the iterator is positioned at an entry
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<(), DbError> {
+        self.trace(|column_family| TraceOp::Seek {
+            column_family,
+            key: Vec::new(),
+        });
+
+        self.start_prefix(None);
+        self.direction = Direction::Forward;
+        self.iter.seek_to_first()?;
+        self.find_next_user_entry(None)?;
+        self.set_prefix_from_key();
+
+        Ok(())
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<(), DbError> {
+        self.start_prefix(None);
+        self.direction = Direction::Reverse;
+        self.iter.seek_to_last()?;
+        self.find_prev_user_entry()?;
+        self.set_prefix_from_key();
+
+        Ok(())
+    }
+
+    /// Positions the iterator at the first key >= `target`
+    pub fn seek(&mut self, target: &[u8]) -> Result<(), DbError> {
+        let _timer = perf_context::timer(record_seek);
+        self.trace(|column_family| TraceOp::Seek {
+            column_family,
+            key: target.to_vec(),
+        });
+
+        self.start_prefix(Some(target));
+        self.direction = Direction::Forward;
+        self.iter.seek(&key::seek_key(target, self.sequence()))?;
+        self.find_next_user_entry(None)?;
+        self.check_prefix();
+
+        Ok(())
+    }
+
+    /// Positions the iterator at the last key <= `target`
+    pub fn seek_for_prev(&mut self, target: &[u8]) -> Result<(), DbError> {
+        let _timer = perf_context::timer(record_seek);
+        self.trace(|column_family| TraceOp::SeekForPrev {
+            column_family,
+            key: target.to_vec(),
+        });
+
+        self.start_prefix(Some(target));
+        self.direction = Direction::Reverse;
+        // Sequence number 0 makes this the greatest internal key of `target`, so that every one
+        // of its versions is included
+        self.iter
+            .seek_for_prev(&key::encode(target, 0, ValueType::Deletion))?;
+        self.find_prev_user_entry()?;
+        self.check_prefix();
+
+        Ok(())
+    }
+
+    // Not an Iterator: moving can fail, and the cursor can be repositioned with the seek methods
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<(), DbError> {
+        if !self.valid {
+            return Ok(());
+        }
+
+        // The older versions of the current key are hidden by the one just returned
+        let skip = std::mem::take(&mut self.key);
+
+        match self.direction {
+            // The underlying iterator is at the current entry, or past it after a merge: either
+            // way, what's left of the current key is skipped
+            Direction::Forward => {}
+            Direction::Reverse => {
+                // Back to the first version of the current key, skipped right away
+                if self.iter.valid() {
+                    self.iter.next()?;
+                } else {
+                    self.iter.seek_to_first()?;
+                }
+
+                self.direction = Direction::Forward;
+            }
+        }
+
+        self.find_next_user_entry(Some(skip))?;
+        self.check_prefix();
+
+        Ok(())
+    }
+
+    pub fn prev(&mut self) -> Result<(), DbError> {
+        if !self.valid {
+            return Ok(());
+        }
+
+        if self.direction == Direction::Forward {
+            // Moves the underlying iterator before every version of the current key. After a
+            // merge it may be past the current key, or even exhausted.
+            if !self.iter.valid() {
+                self.iter.seek_to_last()?;
+            }
+
+            while self.iter.valid() && parse_key(self.iter.key())?.0 >= self.key.as_slice() {
+                self.iter.prev()?;
+            }
+
+            if !self.iter.valid() {
+                self.valid = false;
+                return Ok(());
+            }
+
+            self.direction = Direction::Reverse;
+        }
+
+        self.find_prev_user_entry()?;
+        self.check_prefix();
+
+        Ok(())
+    }
+
+    /// Returns the user key of the current entry
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+/// Iterates the keys of a column family from the oldest to the newest like a [DbIterator], but
+/// also sees the writes made after its creation, see [Db::tailing_iter_cf]
+///
+/// Once the end is reached, [TailingIterator::next] can be called again later to go on with the
+/// keys written since after the last key returned, without seeking from scratch. Only moves
+/// forward.
+pub struct TailingIterator<'a> {
+    db: &'a Db,
+    cf: ColumnFamily,
+    read_options: ReadOptions,
+    iter: DbIterator,
+    /// Sequence number of the last write seen by `iter`
+    sequence: SequenceNumber,
+    /// The last key returned, which the iterator resumes after
+    last_key: Option<Vec<u8>>,
+}
+
+impl<'a> TailingIterator<'a> {
+    pub(crate) fn new(
+        db: &'a Db,
+        cf: ColumnFamily,
+        read_options: ReadOptions,
+    ) -> Result<TailingIterator<'a>, DbError> {
+        let sequence = db.latest_sequence_number();
+        let iter = db.iter_cf(&cf, &read_options)?;
+
+        Ok(TailingIterator {
+            db,
+            cf,
+            read_options,
+            iter,
+            sequence,
+            last_key: None,
+        })
+    }
+
+    pub fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<(), DbError> {
+        self.renew_if_stale()?;
+        self.iter.seek_to_first()?;
+        self.remember_key();
+
+        Ok(())
+    }
+
+    /// Positions the iterator at the first key >= `target`
+    pub fn seek(&mut self, target: &[u8]) -> Result<(), DbError> {
+        self.renew_if_stale()?;
+        self.iter.seek(target)?;
+        self.remember_key();
+
+        Ok(())
+    }
+
+    /// Moves to the next key, which may have been written after the current one was read, or
+    /// after the end was reached
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<(), DbError> {
+        if self.renew_if_stale()? || !self.iter.valid() {
+            self.resume_after_last_key()?;
+        } else {
+            self.iter.next()?;
+        }
+
+        self.remember_key();
+
+        Ok(())
+    }
+
+    /// Makes the iterator see every write made so far, staying at the same key, with its
+    /// current value
+    pub fn refresh(&mut self) -> Result<(), DbError> {
+        self.renew()?;
+
+        if let Some(key) = self.last_key.clone() {
+            self.iter.seek(&key)?;
+            self.remember_key();
+        }
+
+        Ok(())
+    }
+
+    pub fn key(&self) -> &[u8] {
+        self.iter.key()
+    }
+
+    pub fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    /// Replaces the underlying iterator with one seeing every write made so far, leaving it
+    /// unpositioned
+    fn renew(&mut self) -> Result<(), DbError> {
+        self.sequence = self.db.latest_sequence_number();
+        self.iter = self.db.iter_cf(&self.cf, &self.read_options)?;
+
+        Ok(())
+    }
+
+    /// Renews the underlying iterator if writes were made since it was created, returning
+    /// whether it did
+    fn renew_if_stale(&mut self) -> Result<bool, DbError> {
+        let stale = self.db.latest_sequence_number() > self.sequence;
+
+        if stale {
+            self.renew()?;
+        }
+
+        Ok(stale)
+    }
+
+    /// Positions the underlying iterator at the first key after the last one returned
+    fn resume_after_last_key(&mut self) -> Result<(), DbError> {
+        let Some(last_key) = &self.last_key else {
+            return Ok(());
+        };
+
+        self.iter.seek(last_key)?;
+
+        if self.iter.valid() && self.iter.key() == last_key.as_slice() {
+            self.iter.next()?;
+        }
+
+        Ok(())
+    }
+
+    fn remember_key(&mut self) {
+        if self.iter.valid() {
+            self.last_key = Some(self.iter.key().to_vec());
+        }
+    }
+}
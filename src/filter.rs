@@ -0,0 +1,160 @@
+/// Hash function used by the bloom filter (the same Murmur-like function used by LevelDB)
+pub fn hash(data: &[u8], seed: u32) -> u32 {
+    const M: u32 = 0xc6a4_a793;
+    const R: u32 = 24;
+
+    let mut h = seed ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+
+    for chunk in &mut chunks {
+        h = h.wrapping_add(u32::from_le_bytes(chunk.try_into().unwrap()));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+
+    let rest = chunks.remainder();
+
+    if !rest.is_empty() {
+        for (n, byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((*byte as u32) << (8 * n));
+        }
+
+        h = h.wrapping_mul(M);
+        h ^= h >> R;
+    }
+
+    h
+}
+
+fn bloom_hash(key: &[u8]) -> u32 {
+    hash(key, 0xbc9f_1d34)
+}
+
+/// Collects the keys of a table and builds a bloom filter out of them
+///
+/// The filter layout is:
+/// [ bits, probes ]
+/// where probes is a single byte holding the number of hash functions
+pub struct BloomFilterBuilder {
+    bits_per_key: usize,
+    hashes: Vec<u32>,
+}
+
+impl BloomFilterBuilder {
+    pub fn new(bits_per_key: usize) -> BloomFilterBuilder {
+        BloomFilterBuilder {
+            bits_per_key,
+            hashes: Vec::new(),
+        }
+    }
+
+    pub fn add_key(&mut self, key: &[u8]) {
+        let hash = bloom_hash(key);
+
+        // Multiple versions of the same key are added one after the other
+        if self.hashes.last() != Some(&hash) {
+            self.hashes.push(hash);
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        // ln(2) * bits_per_key minimizes the false positive rate
+        let probes = ((self.bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+
+        let bits = (self.hashes.len() * self.bits_per_key).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let mut filter = vec![0_u8; bytes + 1];
+
+        for hash in self.hashes {
+            let delta = hash.rotate_right(17);
+            let mut hash = hash;
+
+            for _ in 0..probes {
+                let bit = hash as usize % bits;
+                filter[bit / 8] |= 1 << (bit % 8);
+
+                hash = hash.wrapping_add(delta);
+            }
+        }
+
+        filter[bytes] = probes;
+
+        filter
+    }
+}
+
+/// Same as [BloomFilter::may_contain], for the filter serialized as `data`
+pub(crate) fn may_contain(data: &[u8], key: &[u8]) -> bool {
+    if data.len() < 2 {
+        return true;
+    }
+
+    let bits = (data.len() - 1) * 8;
+    let probes = data[data.len() - 1];
+
+    let mut hash = bloom_hash(key);
+    let delta = hash.rotate_right(17);
+
+    for _ in 0..probes {
+        let bit = hash as usize % bits;
+
+        if data[bit / 8] & (1 << (bit % 8)) == 0 {
+            return false;
+        }
+
+        hash = hash.wrapping_add(delta);
+    }
+
+    true
+}
+
+/// A bloom filter built by [BloomFilterBuilder]
+pub struct BloomFilter {
+    data: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub fn new(data: Vec<u8>) -> BloomFilter {
+        BloomFilter { data }
+    }
+
+    /// Returns false if the key was surely not added to the filter
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        may_contain(&self.data, key)
+    }
+
+    /// Returns the size of the filter in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{BloomFilter, BloomFilterBuilder};
+
+    #[test]
+    fn no_false_negatives_and_few_false_positives() {
+        let mut builder = BloomFilterBuilder::new(10);
+
+        for n in 0..1000_u32 {
+            builder.add_key(&n.to_be_bytes());
+        }
+
+        let filter = BloomFilter::new(builder.finish());
+
+        assert!((0..1000_u32).all(|n| filter.may_contain(&n.to_be_bytes())));
+
+        let false_positives = (1000..11000_u32)
+            .filter(|n| filter.may_contain(&n.to_be_bytes()))
+            .count();
+
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
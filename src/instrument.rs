@@ -0,0 +1,178 @@
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// A span of the `tracing` crate around some background work, entered until dropped, which
+/// records its duration in microseconds as its `duration_us` field
+///
+/// Without the `tracing` feature, spans are empty and their fields aren't even evaluated.
+#[cfg(feature = "tracing")]
+pub(crate) struct Span {
+    span: tracing::span::EnteredSpan,
+    start: Instant,
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+impl Span {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn new(span: tracing::Span) -> Span {
+        Span {
+            span: span.entered(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Sets the field `field`, declared after the `;` of [span!]
+    #[cfg(feature = "tracing")]
+    pub(crate) fn record(&self, field: &str, value: u64) {
+        self.span.record(field, value);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn record(&self, _field: &str, _value: u64) {}
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        self.span
+            .record("duration_us", self.start.elapsed().as_micros() as u64);
+    }
+}
+
+/// Enters a [Span] at the info level named `$name`, with the fields `$field = $value`, then the
+/// fields set later with [Span::record] after a `;`
+macro_rules! span {
+    ($name:literal $(, $field:ident = $value:expr)* $(; $($later:ident),+)?) => {{
+        #[cfg(feature = "tracing")]
+        let span = $crate::instrument::Span::new(tracing::info_span!(
+            $name,
+            $($field = $value,)*
+            $($($later = tracing::field::Empty,)+)?
+            duration_us = tracing::field::Empty,
+        ));
+
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::instrument::Span;
+
+        span
+    }};
+}
+
+pub(crate) use span;
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::batch::WriteBatch;
+    use crate::options::{Options, WriteOptions};
+    use crate::Db;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Span name, field name and value of the fields recorded
+    type RecordedFields = Vec<(&'static str, &'static str, u64)>;
+
+    /// Collects the fields of the spans by name
+    #[derive(Default)]
+    struct Spans {
+        next_id: AtomicU64,
+        names: Mutex<HashMap<u64, &'static str>>,
+        fields: Arc<Mutex<RecordedFields>>,
+    }
+
+    struct Fields<'a> {
+        name: &'static str,
+        fields: &'a mut RecordedFields,
+    }
+
+    impl Visit for Fields<'_> {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.fields.push((self.name, field.name(), value));
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+    }
+
+    impl Spans {
+        fn visit(&self, name: &'static str, record: impl FnOnce(&mut Fields)) {
+            let mut fields = self.fields.lock().unwrap();
+            record(&mut Fields {
+                name,
+                fields: &mut fields,
+            });
+        }
+    }
+
+    impl Subscriber for Spans {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+            let name = span.metadata().name();
+            self.names.lock().unwrap().insert(id, name);
+            self.visit(name, |fields| span.record(fields));
+
+            Id::from_u64(id)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let name = self.names.lock().unwrap()[&span.into_u64()];
+            self.visit(name, |fields| values.record(fields));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn background_work_is_traced() {
+        let dir = tempfile::tempdir().unwrap();
+        let spans = Spans::default();
+        let fields = spans.fields.clone();
+
+        tracing::subscriber::with_default(spans, || {
+            {
+                let db = Db::open(dir.path(), Options::default()).unwrap();
+                let mut batch = WriteBatch::new();
+                batch.put(b"key", b"value");
+                let sync = WriteOptions {
+                    sync: true,
+                    ..WriteOptions::default()
+                };
+                db.write_with_options(batch, &sync).unwrap();
+                db.flush().unwrap();
+                db.put(b"unflushed", b"value").unwrap();
+            }
+
+            Db::open(dir.path(), Options::default()).unwrap();
+        });
+
+        let fields = fields.lock().unwrap();
+        let field = |span, name| {
+            fields
+                .iter()
+                .find(|(span_name, field, _)| *span_name == span && *field == name)
+                .map(|(_, _, value)| *value)
+        };
+
+        assert_eq!(field("flush", "entries"), Some(1));
+        assert!(field("flush", "bytes").unwrap() > 0);
+        assert!(field("flush", "duration_us").is_some());
+        assert!(field("wal_sync", "records").is_some());
+        assert!(field("recover_manifest", "manifest_number").is_some());
+        assert_eq!(field("replay_log", "last_sequence"), Some(2));
+        assert_eq!(field("flush_recovered", "entries"), Some(1));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::db::DbError;
+use crate::key;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// A cursor over a sorted source of internal keys (memtables, tables, ...)
+///
+/// A fresh iterator is not positioned: one of the seek methods must be called first.
+pub trait InternalIterator {
+    /// Returns true if the iterator is positioned at an entry
+    fn valid(&self) -> bool;
+
+    fn seek_to_first(&mut self) -> Result<(), DbError>;
+
+    fn seek_to_last(&mut self) -> Result<(), DbError>;
+
+    /// Positions the iterator at the first entry >= `target`
+    fn seek(&mut self, target: &[u8]) -> Result<(), DbError>;
+
+    /// Positions the iterator at the last entry <= `target`
+    fn seek_for_prev(&mut self, target: &[u8]) -> Result<(), DbError> {
+        self.seek(target)?;
+
+        if !self.valid() {
+            self.seek_to_last()
+        } else if key::compare(self.key(), target) == Ordering::Greater {
+            self.prev()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn next(&mut self) -> Result<(), DbError>;
+
+    fn prev(&mut self) -> Result<(), DbError>;
+
+    /// Returns the internal key of the current entry
+    fn key(&self) -> &[u8];
+
+    fn value(&self) -> &[u8];
+}
+
+/// Positions `iter` at the last entry < `target`
+fn seek_before(iter: &mut dyn InternalIterator, target: &[u8]) -> Result<(), DbError> {
+    iter.seek(target)?;
+
+    if iter.valid() {
+        iter.prev()
+    } else {
+        iter.seek_to_last()
+    }
+}
+
+/// The direction an iterator is moving towards
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// A child of a [MergingIterator] in the heap, ordered so that the next key in the iteration
+/// direction is on top
+struct HeapEntry {
+    key: Vec<u8>,
+    child: usize,
+    direction: Direction,
+}
+
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap, so moving forward the comparison is reversed. On equal keys
+        // the child which comes first (i.e. the newest source) wins.
+        let order = match self.direction {
+            Direction::Forward => key::compare(&other.key, &self.key),
+            Direction::Reverse => key::compare(&self.key, &other.key),
+        };
+
+        order.then_with(|| other.child.cmp(&self.child))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+/// Merges several sorted iterators into a single sorted stream of internal keys
+///
+/// Children are expected to be passed from the newest to the oldest source, which is only
+/// relevant if the same internal key shows up in more than one of them. Versions of the same user
+/// key are not collapsed: that's the job of the consumers (e.g. [DbIterator] or compaction).
+///
+/// [DbIterator]: crate::db_iter::DbIterator
+pub struct MergingIterator {
+    children: Vec<Box<dyn InternalIterator + Send>>,
+    /// The valid children within the bounds, keyed by their current key
+    heap: BinaryHeap<HeapEntry>,
+    direction: Direction,
+    lower_bound: Option<Vec<u8>>,
+    upper_bound: Option<Vec<u8>>,
+}
+
+impl MergingIterator {
+    pub fn new(children: Vec<Box<dyn InternalIterator + Send>>) -> MergingIterator {
+        MergingIterator {
+            heap: BinaryHeap::with_capacity(children.len()),
+            children,
+            direction: Direction::Forward,
+            lower_bound: None,
+            upper_bound: None,
+        }
+    }
+
+    /// Restricts the iteration to the user keys in [lower, upper), where a missing bound is
+    /// unbounded
+    ///
+    /// Children are never moved past a bound once they reach it.
+    pub fn with_bounds(mut self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> MergingIterator {
+        self.lower_bound = lower.map(<[u8]>::to_vec);
+        self.upper_bound = upper.map(<[u8]>::to_vec);
+        self
+    }
+
+    fn after_lower_bound(&self, internal_key: &[u8]) -> bool {
+        self.lower_bound
+            .as_deref()
+            .is_none_or(|lower| key::user_key(internal_key) >= lower)
+    }
+
+    fn before_upper_bound(&self, internal_key: &[u8]) -> bool {
+        self.upper_bound
+            .as_deref()
+            .is_none_or(|upper| key::user_key(internal_key) < upper)
+    }
+
+    /// Adds `entry` to the heap, unless its child is exhausted or out of bounds
+    fn push(&mut self, mut entry: HeapEntry) {
+        let iter = &self.children[entry.child];
+
+        if iter.valid() && self.after_lower_bound(iter.key()) && self.before_upper_bound(iter.key())
+        {
+            entry.key.clear();
+            entry.key.extend_from_slice(iter.key());
+            entry.direction = self.direction;
+            self.heap.push(entry);
+        }
+    }
+
+    fn rebuild_heap(&mut self, direction: Direction) {
+        self.heap.clear();
+        self.direction = direction;
+
+        for child in 0..self.children.len() {
+            self.push(HeapEntry {
+                key: Vec::new(),
+                child,
+                direction,
+            });
+        }
+    }
+
+    fn current(&self) -> &dyn InternalIterator {
+        let top = self.heap.peek().expect("iterator is not positioned");
+
+        self.children[top.child].as_ref()
+    }
+}
+
+impl InternalIterator for MergingIterator {
+    fn valid(&self) -> bool {
+        !self.heap.is_empty()
+    }
+
+    fn seek_to_first(&mut self) -> Result<(), DbError> {
+        if let Some(lower) = self.lower_bound.clone() {
+            return self.seek(&key::seek_key(&lower, key::MAX_SEQUENCE_NUMBER));
+        }
+
+        for child in &mut self.children {
+            child.seek_to_first()?;
+        }
+
+        self.rebuild_heap(Direction::Forward);
+
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<(), DbError> {
+        let upper_target = self
+            .upper_bound
+            .as_deref()
+            .map(|upper| key::seek_key(upper, key::MAX_SEQUENCE_NUMBER));
+
+        for child in &mut self.children {
+            match &upper_target {
+                Some(target) => seek_before(child.as_mut(), target)?,
+                None => child.seek_to_last()?,
+            }
+        }
+
+        self.rebuild_heap(Direction::Reverse);
+
+        Ok(())
+    }
+
+    fn seek(&mut self, target: &[u8]) -> Result<(), DbError> {
+        let lower_target = match &self.lower_bound {
+            Some(lower) if key::user_key(target) < lower.as_slice() => {
+                Some(key::seek_key(lower, key::MAX_SEQUENCE_NUMBER))
+            }
+            _ => None,
+        };
+        let target = lower_target.as_deref().unwrap_or(target);
+
+        if !self.before_upper_bound(target) {
+            self.heap.clear();
+            return Ok(());
+        }
+
+        for child in &mut self.children {
+            child.seek(target)?;
+        }
+
+        self.rebuild_heap(Direction::Forward);
+
+        Ok(())
+    }
+
+    fn seek_for_prev(&mut self, target: &[u8]) -> Result<(), DbError> {
+        if !self.before_upper_bound(target) {
+            return self.seek_to_last();
+        }
+
+        if !self.after_lower_bound(target) {
+            self.heap.clear();
+            return Ok(());
+        }
+
+        for child in &mut self.children {
+            child.seek_for_prev(target)?;
+        }
+
+        self.rebuild_heap(Direction::Reverse);
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), DbError> {
+        if !self.valid() {
+            return Ok(());
+        }
+
+        if self.direction == Direction::Reverse {
+            // Moves every child after the current key: since internal keys are unique, only the
+            // current child can be positioned exactly at it
+            let current = self.key().to_vec();
+
+            for child in &mut self.children {
+                child.seek(&current)?;
+
+                if child.valid() && key::compare(child.key(), &current) == Ordering::Equal {
+                    child.next()?;
+                }
+            }
+
+            self.rebuild_heap(Direction::Forward);
+
+            return Ok(());
+        }
+
+        if let Some(top) = self.heap.pop() {
+            self.children[top.child].next()?;
+            self.push(top);
+        }
+
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<(), DbError> {
+        if !self.valid() {
+            return Ok(());
+        }
+
+        if self.direction == Direction::Forward {
+            // Moves every child before the current key
+            let current = self.key().to_vec();
+
+            for child in &mut self.children {
+                seek_before(child.as_mut(), &current)?;
+            }
+
+            self.rebuild_heap(Direction::Reverse);
+
+            return Ok(());
+        }
+
+        if let Some(top) = self.heap.pop() {
+            self.children[top.child].prev()?;
+            self.push(top);
+        }
+
+        Ok(())
+    }
+
+    fn key(&self) -> &[u8] {
+        self.current().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current().value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iterator::{InternalIterator, MergingIterator};
+    use crate::key::{self, ValueType};
+    use crate::memtable::MemTable;
+    use std::sync::Arc;
+
+    #[test]
+    fn merging_iterator_interleaves_children() {
+        let old = Arc::new(MemTable::new());
+        let new = Arc::new(MemTable::new());
+
+        for n in 0..10_u8 {
+            old.add(n as u64, ValueType::Value, &[n], b"old");
+        }
+
+        for n in (0..10_u8).step_by(3) {
+            new.add(20 + n as u64, ValueType::Value, &[n], b"new");
+        }
+
+        let mut iter = MergingIterator::new(vec![Box::new(new.iter()), Box::new(old.iter())]);
+        let mut entries = Vec::new();
+
+        iter.seek(&key::seek_key(&[3], key::MAX_SEQUENCE_NUMBER))
+            .unwrap();
+
+        while iter.valid() {
+            let (user_key, seq, _) = key::parse(iter.key()).unwrap();
+            entries.push((user_key[0], seq, iter.value().to_vec()));
+
+            iter.next().unwrap();
+        }
+
+        assert_eq!(entries.len(), 10);
+        assert_eq!(entries[0], (3, 23, b"new".to_vec()));
+        assert_eq!(entries[1], (3, 3, b"old".to_vec()));
+        assert_eq!(entries[2], (4, 4, b"old".to_vec()));
+        assert_eq!(entries[9], (9, 9, b"old".to_vec()));
+    }
+
+    #[test]
+    fn merging_iterator_changes_direction() {
+        let even = Arc::new(MemTable::new());
+        let odd = Arc::new(MemTable::new());
+
+        for n in 0..10_u8 {
+            let mem = if n % 2 == 0 { &even } else { &odd };
+            mem.add(n as u64, ValueType::Value, &[n], &[n]);
+        }
+
+        let mut iter = MergingIterator::new(vec![Box::new(even.iter()), Box::new(odd.iter())])
+            .with_bounds(Some(&[2]), Some(&[8]));
+
+        iter.seek_to_last().unwrap();
+        assert_eq!(iter.value(), [7]);
+
+        iter.prev().unwrap();
+        iter.prev().unwrap();
+        assert_eq!(iter.value(), [5]);
+
+        iter.next().unwrap();
+        assert_eq!(iter.value(), [6]);
+
+        iter.seek_for_prev(&key::seek_key(&[4], 0)).unwrap();
+        assert_eq!(iter.value(), [4]);
+
+        iter.seek_to_first().unwrap();
+        assert_eq!(iter.value(), [2]);
+
+        iter.prev().unwrap();
+        assert!(!iter.valid());
+    }
+}
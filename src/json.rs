@@ -0,0 +1,51 @@
+//! Formatting of the JSON dumps of the tables and of the database, see
+//! [TableDump::to_json](crate::table::TableDump::to_json), shared with `fyodor-cli`
+
+/// Formats `bytes` as a JSON string of lowercase hexadecimal digits
+pub fn hex(bytes: &[u8]) -> String {
+    let digits: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+    format!("\"{digits}\"")
+}
+
+/// Quotes `string`, escaping the characters JSON doesn't allow in strings
+pub fn string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json;
+
+    #[test]
+    fn strings_round_trip_through_json() {
+        assert_eq!(json::string("\"\\\n\u{1}é"), r#""\"\\\n\u0001é""#);
+
+        let tricky: String = (0..0x20_u8)
+            .map(char::from)
+            .chain("\"\\\u{7f}é/".chars())
+            .collect();
+        let parsed: String = serde_json::from_str(&json::string(&tricky)).unwrap();
+        assert_eq!(parsed, tricky);
+
+        let key = b"\"\\\x00\x01\n\xff";
+        let parsed: String = serde_json::from_str(&json::hex(key)).unwrap();
+        assert_eq!(parsed, "225c00010aff");
+    }
+}
@@ -0,0 +1,132 @@
+use std::cmp::Ordering;
+
+/// Monotonically increasing number assigned to every write, used to order versions of the same key
+pub type SequenceNumber = u64;
+
+/// Sequence numbers share a u64 with the [ValueType], so only 56 bits are available
+pub const MAX_SEQUENCE_NUMBER: SequenceNumber = (1 << 56) - 1;
+
+/// Size of the trailer appended to every user key to obtain an internal key
+pub const TRAILER_SIZE: usize = 8;
+
+/// The kind of mutation an internal key represents
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ValueType {
+    Deletion = 0,
+    Value = 1,
+    /// Deletes every key in [key, value)
+    RangeDeletion = 2,
+    /// An operand of the merge operator
+    Merge = 3,
+    /// A value which reads as a deletion once expired, see
+    /// [WriteBatch::put_with_ttl](crate::batch::WriteBatch::put_with_ttl)
+    ValueWithExpiry = 4,
+    /// A value stored in a blob file, the entry holding its
+    /// [BlobIndex](crate::blob::BlobIndex)
+    BlobIndex = 5,
+}
+
+impl ValueType {
+    /// The type used when building seek keys: since trailers are sorted in decreasing order,
+    /// it must be the highest one so that the seek key sorts before every entry with the same
+    /// sequence number
+    pub const FOR_SEEK: ValueType = ValueType::BlobIndex;
+
+    pub fn from_u8(value: u8) -> Option<ValueType> {
+        match value {
+            0 => Some(ValueType::Deletion),
+            1 => Some(ValueType::Value),
+            2 => Some(ValueType::RangeDeletion),
+            3 => Some(ValueType::Merge),
+            4 => Some(ValueType::ValueWithExpiry),
+            5 => Some(ValueType::BlobIndex),
+            _ => None,
+        }
+    }
+}
+
+/// Packs a sequence number and a value type into the 8 bytes trailer
+fn pack_trailer(seq: SequenceNumber, value_type: ValueType) -> u64 {
+    debug_assert!(seq <= MAX_SEQUENCE_NUMBER);
+
+    (seq << 8) | value_type as u64
+}
+
+/// Encodes an internal key, i.e. the key format used by every structure of the LSM-tree
+///
+/// The memory layout is:
+/// [ user_key, trailer ]
+/// where trailer is a little-endian u64 packing `seq << 8 | value_type`
+pub fn encode(user_key: &[u8], seq: SequenceNumber, value_type: ValueType) -> Vec<u8> {
+    let mut internal_key = Vec::with_capacity(user_key.len() + TRAILER_SIZE);
+
+    internal_key.extend_from_slice(user_key);
+    internal_key.extend_from_slice(&pack_trailer(seq, value_type).to_le_bytes());
+
+    internal_key
+}
+
+/// Builds the internal key which sorts before every version of `user_key` visible at `seq`
+pub fn seek_key(user_key: &[u8], seq: SequenceNumber) -> Vec<u8> {
+    encode(user_key, seq, ValueType::FOR_SEEK)
+}
+
+/// Returns the user key part of an internal key
+pub fn user_key(internal_key: &[u8]) -> &[u8] {
+    &internal_key[..internal_key.len() - TRAILER_SIZE]
+}
+
+fn trailer(internal_key: &[u8]) -> u64 {
+    let trailer_index = internal_key.len() - TRAILER_SIZE;
+
+    u64::from_le_bytes(internal_key[trailer_index..].try_into().unwrap())
+}
+
+/// Splits an internal key into user key, sequence number and value type.
+///
+/// Returns None if the key is too short or has an unknown value type
+pub fn parse(internal_key: &[u8]) -> Option<(&[u8], SequenceNumber, ValueType)> {
+    if internal_key.len() < TRAILER_SIZE {
+        return None;
+    }
+
+    let trailer = trailer(internal_key);
+    let value_type = ValueType::from_u8((trailer & 0xff) as u8)?;
+
+    Some((user_key(internal_key), trailer >> 8, value_type))
+}
+
+/// Orders internal keys by user key ascending, then by sequence number (and type) descending,
+/// so that the most recent version of a key comes first
+pub fn compare(a: &[u8], b: &[u8]) -> Ordering {
+    user_key(a)
+        .cmp(user_key(b))
+        .then_with(|| trailer(b).cmp(&trailer(a)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{compare, encode, parse, seek_key, ValueType};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn newer_versions_sort_first() {
+        let old = encode(b"key", 1, ValueType::Value);
+        let new = encode(b"key", 2, ValueType::Deletion);
+
+        assert_eq!(compare(&new, &old), Ordering::Less);
+        assert_eq!(
+            compare(
+                &seek_key(b"key", 1),
+                &encode(b"key", 1, ValueType::Deletion)
+            ),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare(&old, &encode(b"kez", 9, ValueType::Value)),
+            Ordering::Less
+        );
+        assert_eq!(parse(&new), Some((&b"key"[..], 2, ValueType::Deletion)));
+    }
+}
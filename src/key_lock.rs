@@ -0,0 +1,49 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks keys by hashing them to a fixed set of stripes, so that writers of the same key can be
+/// serialized without keeping a lock per key
+///
+/// Different keys may share a stripe, in which case they are serialized too.
+#[derive(Debug)]
+pub(crate) struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    pub(crate) fn new(num_stripes: usize) -> KeyLocks {
+        KeyLocks {
+            stripes: (0..num_stripes.max(1)).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    fn stripe(&self, key: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        (hasher.finish() % self.stripes.len() as u64) as usize
+    }
+
+    /// Locks `key` until the guard is dropped
+    pub(crate) fn lock(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        self.stripes[self.stripe(key)].lock().unwrap()
+    }
+
+    /// Locks every key of `keys` until the guards are dropped
+    ///
+    /// The stripes are always taken in the same order, so that concurrent callers can't deadlock.
+    pub(crate) fn lock_all<'a, I>(&self, keys: I) -> Vec<MutexGuard<'_, ()>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut stripes: Vec<_> = keys.into_iter().map(|key| self.stripe(key)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+
+        stripes
+            .into_iter()
+            .map(|stripe| self.stripes[stripe].lock().unwrap())
+            .collect()
+    }
+}
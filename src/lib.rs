@@ -0,0 +1,66 @@
+pub mod backup;
+pub mod batch;
+pub mod blob;
+pub mod block_cache;
+pub mod column_family;
+mod compaction;
+pub mod compaction_service;
+pub mod db;
+pub mod db_iter;
+pub mod filter;
+mod instrument;
+pub mod iterator;
+#[doc(hidden)]
+pub mod json;
+pub mod key;
+mod key_lock;
+pub mod listener;
+mod lock_manager;
+pub mod memtable;
+pub mod merge;
+pub mod merge_operators;
+pub mod options;
+pub mod perf_context;
+pub mod prefix;
+pub mod range_del;
+pub mod rate_limiter;
+pub mod repair;
+pub mod replication;
+pub mod row_cache;
+mod scheduler;
+pub mod snapshot;
+pub mod storage;
+pub mod table;
+pub mod timestamp;
+pub mod trace;
+pub mod transaction;
+pub mod ttl;
+pub mod version;
+pub mod wal;
+pub mod watch;
+pub mod write_buffer_manager;
+
+pub use backup::{BackupEngine, BackupInfo, BackupOptions, RestoreOptions};
+pub use blob::ValueReader;
+pub use block_cache::{BlockCache, CachePolicy, CachePriority, SecondaryCache};
+pub use column_family::ColumnFamily;
+pub use compaction_service::{CompactionService, CompactionServiceJob, CompactionServiceResult};
+pub use db::{
+    CompactionStats, Db, DbError, ExportImportFilesMetaData, LiveFileMetaData, MemoryUsage,
+};
+pub use db_iter::{DbIterator, TailingIterator};
+pub use lock_manager::{DeadlockInfo, LockWait};
+pub use options::{
+    load_latest_options, ColumnFamilyOptions, CompactRangeOptions, Options, ReadOptions,
+    TransactionOptions, WriteOptions,
+};
+pub use rate_limiter::RateLimiter;
+pub use repair::{repair, RepairReport};
+pub use replication::Replica;
+pub use row_cache::RowCache;
+pub use snapshot::Snapshot;
+pub use storage::PinnedValue;
+pub use trace::{ReplayStats, Replayer, TraceReader};
+pub use transaction::{IsolationLevel, Transaction};
+pub use watch::{Change, ChangeEvent, Watch};
+pub use write_buffer_manager::WriteBufferManager;
@@ -0,0 +1,76 @@
+use crate::db::DbError;
+use crate::table::TableProperties;
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+/// Receives notifications of the work done by a database, e.g. to export metrics or to react to
+/// failures, once registered with [Options::with_listener](crate::Options::with_listener)
+///
+/// Every callback does nothing by default. They are called by the thread doing the work, mostly
+/// while the database is locked: they must be quick, and must not call the database back.
+pub trait EventListener: Debug + Send + Sync {
+    /// Called before a memtable is written to a table
+    fn on_flush_begin(&self, _info: &FlushJobInfo) {}
+
+    /// Called once a flushed table is part of the database
+    fn on_flush_completed(&self, _info: &FlushJobInfo) {}
+
+    /// Called before tables are merged by a compaction
+    fn on_compaction_begin(&self, _info: &CompactionJobInfo) {}
+
+    /// Called once the outputs of a compaction replaced its inputs
+    fn on_compaction_completed(&self, _info: &CompactionJobInfo) {}
+
+    fn on_table_file_created(&self, _info: &TableFileCreationInfo) {}
+
+    fn on_table_file_deleted(&self, _info: &TableFileDeletionInfo) {}
+
+    /// Called when work which no caller waits for fails
+    fn on_background_error(&self, _error: &DbError) {}
+}
+
+#[derive(Clone, Debug)]
+pub struct FlushJobInfo {
+    pub column_family_id: u32,
+    pub column_family_name: String,
+    /// Number of the table the memtable is written to
+    pub file_number: u64,
+    /// Number of entries of the memtable
+    pub num_entries: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CompactionJobInfo {
+    pub column_family_id: u32,
+    pub column_family_name: String,
+    /// Numbers of the tables merged by the compaction
+    pub input_files: Vec<u64>,
+    /// Numbers of the tables written by the compaction, empty until it completes
+    pub output_files: Vec<u64>,
+    pub output_level: usize,
+}
+
+/// Why a table was written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFileCreationReason {
+    Flush,
+    Compaction,
+    /// The writes replayed from the logs at open were flushed
+    Recovery,
+}
+
+#[derive(Clone, Debug)]
+pub struct TableFileCreationInfo {
+    pub column_family_id: u32,
+    pub file_number: u64,
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub properties: TableProperties,
+    pub reason: TableFileCreationReason,
+}
+
+#[derive(Clone, Debug)]
+pub struct TableFileDeletionInfo {
+    pub file_number: u64,
+    pub path: PathBuf,
+}
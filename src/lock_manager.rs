@@ -0,0 +1,210 @@
+use crate::db::DbError;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A transaction waiting for the lock of a key, part of a [DeadlockInfo]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockWait {
+    pub transaction_id: u64,
+    pub column_family_id: u32,
+    pub key: Vec<u8>,
+}
+
+/// The cycle of transactions waiting for each other's locks found by a pessimistic
+/// [Transaction](crate::Transaction) about to wait, see [DbError::Deadlock]
+///
+/// Every transaction waits for the key held by the next one, the last one waiting for a key held
+/// by the first one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadlockInfo {
+    pub waits: Vec<LockWait>,
+}
+
+/// Locks the keys written or read for update by the pessimistic transactions, each key being
+/// held by a single transaction at a time
+///
+/// The transactions waiting for a key form a waits-for graph with the holders of the keys: since
+/// a transaction waits for a single key at a time, a deadlock is a cycle found by following the
+/// holders from the waiting transaction.
+#[derive(Debug, Default)]
+pub(crate) struct LockManager {
+    state: Mutex<LockState>,
+    /// Notified whenever keys are unlocked
+    released: Condvar,
+    /// Id handed to the next transaction
+    next_id: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    /// The id of the transaction holding every locked key, by column family and key
+    holders: HashMap<(u32, Vec<u8>), u64>,
+    /// The key every waiting transaction waits for
+    waiting: HashMap<u64, (u32, Vec<u8>)>,
+}
+
+impl LockState {
+    /// Returns the cycle of waits `id` would close by waiting, if any
+    fn deadlock(&self, id: u64) -> Option<DeadlockInfo> {
+        let mut waits = Vec::new();
+        let mut waiter = id;
+
+        // Each transaction waits for a single key, so the path either ends, comes back to `id`,
+        // or loops among the others after visiting each waiting transaction at most once
+        while waits.len() <= self.waiting.len() {
+            let (column_family_id, key) = self.waiting.get(&waiter)?;
+            waits.push(LockWait {
+                transaction_id: waiter,
+                column_family_id: *column_family_id,
+                key: key.clone(),
+            });
+
+            waiter = *self.holders.get(&(*column_family_id, key.clone()))?;
+
+            if waiter == id {
+                return Some(DeadlockInfo { waits });
+            }
+        }
+
+        None
+    }
+}
+
+impl LockManager {
+    pub(crate) fn new() -> LockManager {
+        LockManager::default()
+    }
+
+    /// Returns a new id for a transaction
+    pub(crate) fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Locks `key` of the column family `column_family_id` for the transaction `id`, waiting up
+    /// to `timeout` for its holder to unlock it
+    ///
+    /// Fails with [DbError::LockTimeout] once the timeout expires, or right away with
+    /// [DbError::Deadlock] if `detect_deadlocks` and the holder waits for `id`, directly or not.
+    pub(crate) fn lock(
+        &self,
+        id: u64,
+        column_family_id: u32,
+        key: &[u8],
+        timeout: Option<Duration>,
+        detect_deadlocks: bool,
+    ) -> Result<(), DbError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let lock_key = (column_family_id, key.to_vec());
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            match state.holders.get(&lock_key) {
+                Some(&holder) if holder != id => {}
+                _ => {
+                    state.waiting.remove(&id);
+                    state.holders.insert(lock_key, id);
+
+                    return Ok(());
+                }
+            }
+
+            state.waiting.insert(id, lock_key.clone());
+
+            if let Some(info) = state.deadlock(id).filter(|_| detect_deadlocks) {
+                state.waiting.remove(&id);
+
+                return Err(DbError::Deadlock(info));
+            }
+
+            state = match deadline {
+                None => self.released.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+
+                    if now >= deadline {
+                        state.waiting.remove(&id);
+
+                        return Err(DbError::LockTimeout);
+                    }
+
+                    self.released.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Unlocks every key held by the transaction `id`
+    pub(crate) fn unlock_all(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        let held = state.holders.len();
+
+        state.holders.retain(|_, holder| *holder != id);
+
+        if state.holders.len() < held {
+            self.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DbError;
+    use crate::lock_manager::LockManager;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn cycles_of_waits_are_deadlocks() {
+        let locks = Arc::new(LockManager::new());
+        let (first, second) = (locks.new_id(), locks.new_id());
+
+        locks.lock(first, 0, b"a", None, true).unwrap();
+        locks.lock(second, 0, b"b", None, true).unwrap();
+
+        let waiter = {
+            let locks = locks.clone();
+            std::thread::spawn(move || locks.lock(first, 0, b"b", None, true))
+        };
+
+        // Retries until the first transaction waits for the second one, without waiting itself
+        // so that the first one can't find the deadlock instead
+        let info = loop {
+            match locks.lock(second, 0, b"a", Some(Duration::ZERO), true) {
+                Err(DbError::Deadlock(info)) => break info,
+                Err(DbError::LockTimeout) => continue,
+                result => panic!("unexpected {:?}", result),
+            }
+        };
+
+        assert_eq!(info.waits.len(), 2);
+        assert_eq!(info.waits[0].transaction_id, second);
+        assert_eq!(info.waits[0].key, b"a");
+        assert_eq!(info.waits[1].transaction_id, first);
+        assert_eq!(info.waits[1].key, b"b");
+
+        locks.unlock_all(second);
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn waits_time_out() {
+        let locks = LockManager::new();
+        let (first, second) = (locks.new_id(), locks.new_id());
+
+        locks.lock(first, 0, b"a", None, true).unwrap();
+        locks.lock(first, 0, b"a", None, true).unwrap();
+        locks.lock(second, 1, b"a", None, true).unwrap();
+
+        assert!(matches!(
+            locks.lock(second, 0, b"a", Some(Duration::from_millis(10)), true),
+            Err(DbError::LockTimeout)
+        ));
+
+        locks.unlock_all(first);
+        locks
+            .lock(second, 0, b"a", Some(Duration::ZERO), true)
+            .unwrap();
+    }
+}
@@ -1,5 +0,0 @@
-mod storage;
-
-fn main() {
-    println!("Hello, world!");
-}
@@ -0,0 +1,576 @@
+use crate::db::DbError;
+use crate::iterator::InternalIterator;
+use crate::key::{self, SequenceNumber, ValueType};
+use crate::range_del::RangeTombstone;
+use crate::storage::{BlockBuffer, PinnedValue};
+use crate::ttl;
+use std::cmp::Ordering;
+use std::mem::size_of;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::{Arc, RwLock};
+
+/// Maximum number of levels of the skiplist
+const MAX_HEIGHT: usize = 12;
+
+/// Each level contains (on average) one node every BRANCHING nodes of the level below
+const BRANCHING: u64 = 4;
+
+/// Index of the sentinel head node. Since the head can never be the successor of another node,
+/// it is also used as the "end of list" marker in the `next` pointers
+const HEAD: usize = 0;
+
+struct Node {
+    key: Box<[u8]>,
+    value: Box<[u8]>,
+    next: Vec<usize>,
+}
+
+/// An append-only skiplist sorted by internal key
+///
+/// Nodes live in an arena and link to each other by index, so a position in the list
+/// stays valid as long as the list itself, even while new nodes are being inserted.
+pub struct SkipList {
+    nodes: Vec<Node>,
+    height: usize,
+    rand_state: u64,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        SkipList::new()
+    }
+}
+
+impl SkipList {
+    pub fn new() -> SkipList {
+        let head = Node {
+            key: Box::default(),
+            value: Box::default(),
+            next: vec![HEAD; MAX_HEIGHT],
+        };
+
+        SkipList {
+            nodes: vec![head],
+            height: 1,
+            rand_state: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Returns the number of entries in the list
+    pub fn len(&self) -> usize {
+        self.nodes.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Picks a height for a new node, with probability 1/BRANCHING of increasing it at each level
+    fn random_height(&mut self) -> usize {
+        let mut height = 1;
+
+        loop {
+            // xorshift64
+            self.rand_state ^= self.rand_state << 13;
+            self.rand_state ^= self.rand_state >> 7;
+            self.rand_state ^= self.rand_state << 17;
+
+            if height >= MAX_HEIGHT || !self.rand_state.is_multiple_of(BRANCHING) {
+                return height;
+            }
+
+            height += 1;
+        }
+    }
+
+    /// Returns true if `key` is greater than the key stored in `node`
+    fn key_is_after_node(&self, key: &[u8], node: usize) -> bool {
+        node != HEAD && key::compare(&self.nodes[node].key, key) == Ordering::Less
+    }
+
+    /// Returns the first node whose key is >= `key` (or HEAD if there is none), filling `prev`
+    /// with the last node visited at each level
+    fn find_greater_or_equal(
+        &self,
+        key: &[u8],
+        mut prev: Option<&mut [usize; MAX_HEIGHT]>,
+    ) -> usize {
+        let mut node = HEAD;
+        let mut level = self.height - 1;
+
+        loop {
+            let next = self.nodes[node].next[level];
+
+            if self.key_is_after_node(key, next) {
+                node = next;
+            } else {
+                if let Some(prev) = prev.as_deref_mut() {
+                    prev[level] = node;
+                }
+
+                if level == 0 {
+                    return next;
+                }
+
+                level -= 1;
+            }
+        }
+    }
+
+    /// Returns the last node whose key is < `key`, or HEAD if there is none
+    fn find_less_than(&self, key: &[u8]) -> usize {
+        let mut node = HEAD;
+        let mut level = self.height - 1;
+
+        loop {
+            let next = self.nodes[node].next[level];
+
+            if self.key_is_after_node(key, next) {
+                node = next;
+            } else if level == 0 {
+                return node;
+            } else {
+                level -= 1;
+            }
+        }
+    }
+
+    /// Inserts a new internal key. The list does not support updates: every internal key is
+    /// expected to be unique, which is guaranteed by the sequence number
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        let mut prev = [HEAD; MAX_HEIGHT];
+        self.find_greater_or_equal(&key, Some(&mut prev));
+
+        let height = self.random_height();
+        self.height = self.height.max(height);
+
+        let index = self.nodes.len();
+        let next = (0..height)
+            .map(|level| self.nodes[prev[level]].next[level])
+            .collect();
+
+        self.nodes.push(Node {
+            key: key.into_boxed_slice(),
+            value: value.into_boxed_slice(),
+            next,
+        });
+
+        for (level, prev) in prev.iter().enumerate().take(height) {
+            self.nodes[*prev].next[level] = index;
+        }
+    }
+
+    /// Returns the position of the first entry >= `key`, if any
+    pub fn seek(&self, key: &[u8]) -> Option<usize> {
+        Some(self.find_greater_or_equal(key, None)).filter(|node| *node != HEAD)
+    }
+
+    /// Returns the position of the last entry < `key`, if any
+    pub fn seek_for_prev(&self, key: &[u8]) -> Option<usize> {
+        Some(self.find_less_than(key)).filter(|node| *node != HEAD)
+    }
+
+    /// Returns the position of the first entry, if any
+    pub fn first(&self) -> Option<usize> {
+        self.next(HEAD)
+    }
+
+    /// Returns the position of the last entry, if any
+    pub fn last(&self) -> Option<usize> {
+        let mut node = HEAD;
+        let mut level = self.height - 1;
+
+        loop {
+            let next = self.nodes[node].next[level];
+
+            if next != HEAD {
+                node = next;
+            } else if level == 0 {
+                return Some(node).filter(|node| *node != HEAD);
+            } else {
+                level -= 1;
+            }
+        }
+    }
+
+    /// Returns the position following `node`, if any
+    pub fn next(&self, node: usize) -> Option<usize> {
+        Some(self.nodes[node].next[0]).filter(|node| *node != HEAD)
+    }
+
+    /// Returns the internal key stored at position `node`
+    pub fn key(&self, node: usize) -> &[u8] {
+        &self.nodes[node].key
+    }
+
+    /// Returns the value stored at position `node`
+    pub fn value(&self, node: usize) -> &[u8] {
+        &self.nodes[node].value
+    }
+}
+
+/// The outcome of a point lookup in a memtable
+#[derive(Debug, PartialEq, Eq)]
+pub enum LookupResult {
+    Value(Vec<u8>),
+    Deleted,
+}
+
+/// The state of a point lookup going through the sources from the newest to the oldest
+#[derive(Debug, Default)]
+pub struct GetContext {
+    /// Sequence number of the newest range tombstone covering the key seen so far
+    pub max_covering_tombstone_seq: SequenceNumber,
+    /// Merge operands met so far, from the newest to the oldest
+    pub operands: Vec<Vec<u8>>,
+    /// Whether a value found in a data block is kept in [GetContext::pinned] rather than copied
+    pub pin_value: bool,
+    /// The value found if borrowed from its data block, see [GetContext::pin_value]: the
+    /// [LookupResult::Value] is then left empty
+    pub pinned: Option<PinnedValue>,
+    /// The [BlobIndex](crate::blob::BlobIndex) of the value found if it's in a blob file: the
+    /// [LookupResult::Value] is then left empty
+    pub blob_index: Option<Vec<u8>>,
+}
+
+impl GetContext {
+    /// Folds in the newest range tombstone of a source covering the key
+    pub(crate) fn add_tombstone_seq(&mut self, tombstone_seq: SequenceNumber) {
+        self.max_covering_tombstone_seq = self.max_covering_tombstone_seq.max(tombstone_seq);
+    }
+
+    /// Handles a version of the key, read from `block` if it's in a data block: returns the
+    /// outcome of the lookup if the version ends it, or None if it's a merge operand and older
+    /// versions must be looked at too
+    pub(crate) fn add_version(
+        &mut self,
+        seq: SequenceNumber,
+        value_type: ValueType,
+        value: &[u8],
+        block: Option<&Arc<BlockBuffer>>,
+    ) -> Option<LookupResult> {
+        if seq < self.max_covering_tombstone_seq {
+            return Some(LookupResult::Deleted);
+        }
+
+        match ttl::resolve(value_type, value) {
+            (ValueType::Value | ValueType::ValueWithExpiry, value) => match block {
+                Some(block) if self.pin_value => {
+                    self.pinned = Some(PinnedValue::from_block(block.clone(), value));
+                    Some(LookupResult::Value(Vec::new()))
+                }
+                _ => Some(LookupResult::Value(value.to_vec())),
+            },
+            (ValueType::BlobIndex, index) => {
+                self.blob_index = Some(index.to_vec());
+                Some(LookupResult::Value(Vec::new()))
+            }
+            (ValueType::Deletion | ValueType::RangeDeletion, _) => Some(LookupResult::Deleted),
+            (ValueType::Merge, _) => {
+                self.operands.push(value.to_vec());
+                None
+            }
+        }
+    }
+
+    /// Returns the outcome of a lookup which went through every version of the key in a source
+    /// without finding a value or a deletion
+    pub(crate) fn source_exhausted(&self) -> Option<LookupResult> {
+        // The versions in older sources are covered by the tombstone too
+        (self.max_covering_tombstone_seq > 0).then_some(LookupResult::Deleted)
+    }
+}
+
+/// In-memory write buffer, where every write lands before being flushed to disk
+///
+/// Range tombstones are kept apart from the point entries, which are the only ones returned by
+/// its iterators.
+pub struct MemTable {
+    list: RwLock<SkipList>,
+    range_tombstones: RwLock<Vec<RangeTombstone>>,
+    memory_usage: AtomicUsize,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        MemTable::new()
+    }
+}
+
+impl MemTable {
+    pub fn new() -> MemTable {
+        MemTable {
+            list: RwLock::new(SkipList::new()),
+            range_tombstones: RwLock::new(Vec::new()),
+            memory_usage: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds a new version of `key`, or a range tombstone from `key` to `value`
+    pub fn add(&self, seq: SequenceNumber, value_type: ValueType, key: &[u8], value: &[u8]) {
+        if value_type == ValueType::RangeDeletion {
+            let usage = key.len() + value.len() + size_of::<RangeTombstone>();
+
+            self.range_tombstones.write().unwrap().push(RangeTombstone {
+                start: key.to_vec(),
+                end: value.to_vec(),
+                seq,
+            });
+            self.memory_usage
+                .fetch_add(usage, atomic::Ordering::Relaxed);
+
+            return;
+        }
+
+        let internal_key = key::encode(key, seq, value_type);
+        let usage = internal_key.len() + value.len() + size_of::<Node>();
+
+        self.list
+            .write()
+            .unwrap()
+            .insert(internal_key, value.to_vec());
+        self.memory_usage
+            .fetch_add(usage, atomic::Ordering::Relaxed);
+    }
+
+    /// Looks up the most recent version of `key` visible at sequence number `seq`, ignoring
+    /// merge operands
+    ///
+    /// Returns None if the memtable knows nothing about the key
+    pub fn get(&self, key: &[u8], seq: SequenceNumber) -> Option<LookupResult> {
+        self.get_with_context(key, seq, &mut GetContext::default())
+    }
+
+    /// Same as [MemTable::get], but carries the state of a lookup through several sources:
+    /// range tombstones of newer sources are honored, and merge operands are collected in `ctx`
+    /// until a value or a deletion is found
+    pub fn get_with_context(
+        &self,
+        key: &[u8],
+        seq: SequenceNumber,
+        ctx: &mut GetContext,
+    ) -> Option<LookupResult> {
+        ctx.add_tombstone_seq(self.max_covering_tombstone_seq(key, seq));
+
+        let list = self.list.read().unwrap();
+        let mut node = list.seek(&key::seek_key(key, seq));
+
+        while let Some(current) = node {
+            match key::parse(list.key(current)) {
+                Some((user_key, entry_seq, value_type)) if user_key == key => {
+                    let result = ctx.add_version(entry_seq, value_type, list.value(current), None);
+
+                    if result.is_some() {
+                        return result;
+                    }
+                }
+                _ => break,
+            }
+
+            node = list.next(current);
+        }
+
+        ctx.source_exhausted()
+    }
+
+    /// Returns the sequence number of the newest range tombstone visible at `seq` which covers
+    /// `key`, or 0 if there is none
+    pub fn max_covering_tombstone_seq(&self, key: &[u8], seq: SequenceNumber) -> SequenceNumber {
+        self.range_tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|tombstone| tombstone.seq <= seq && tombstone.covers(key))
+            .map(|tombstone| tombstone.seq)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns a copy of the range tombstones of the memtable
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        self.range_tombstones.read().unwrap().clone()
+    }
+
+    /// Returns an estimate of the bytes used by this memtable
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.memory_usage.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Returns the bytes taken by the keys and values of the entries with user keys in
+    /// [start, end)
+    pub fn approximate_range_size(&self, start: &[u8], end: &[u8]) -> u64 {
+        let list = self.list.read().unwrap();
+        let mut node = list.seek(&key::seek_key(start, key::MAX_SEQUENCE_NUMBER));
+        let mut size = 0;
+
+        while let Some(current) = node {
+            if key::user_key(list.key(current)) >= end {
+                break;
+            }
+
+            size += (list.key(current).len() + list.value(current).len()) as u64;
+            node = list.next(current);
+        }
+
+        size
+    }
+
+    /// Returns the number of point entries in this memtable
+    pub fn len(&self) -> usize {
+        self.list.read().unwrap().len()
+    }
+
+    /// Returns true if the memtable holds neither point entries nor range tombstones
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0 && self.range_tombstones.read().unwrap().is_empty()
+    }
+
+    /// Returns an iterator over the entries of the memtable, initially not positioned
+    pub fn iter(self: &Arc<Self>) -> MemTableIterator {
+        MemTableIterator {
+            mem: self.clone(),
+            node: None,
+            key: Vec::new(),
+            value: Vec::new(),
+        }
+    }
+}
+
+/// A cursor over the entries of a [MemTable], in internal key order
+///
+/// The memtable is only locked while moving, so writes can proceed while iterating: entries
+/// inserted after the current position may or may not be seen.
+pub struct MemTableIterator {
+    mem: Arc<MemTable>,
+    node: Option<usize>,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl MemTableIterator {
+    /// Moves to `node`, copying its contents out of the list
+    fn position_at(&mut self, list: &SkipList, node: Option<usize>) {
+        self.node = node;
+
+        if let Some(node) = node {
+            self.key.clear();
+            self.key.extend_from_slice(list.key(node));
+            self.value.clear();
+            self.value.extend_from_slice(list.value(node));
+        }
+    }
+}
+
+impl InternalIterator for MemTableIterator {
+    fn valid(&self) -> bool {
+        self.node.is_some()
+    }
+
+    fn seek_to_first(&mut self) -> Result<(), DbError> {
+        let mem = self.mem.clone();
+        let list = mem.list.read().unwrap();
+
+        self.position_at(&list, list.first());
+
+        Ok(())
+    }
+
+    fn seek_to_last(&mut self) -> Result<(), DbError> {
+        let mem = self.mem.clone();
+        let list = mem.list.read().unwrap();
+
+        self.position_at(&list, list.last());
+
+        Ok(())
+    }
+
+    fn seek(&mut self, target: &[u8]) -> Result<(), DbError> {
+        let mem = self.mem.clone();
+        let list = mem.list.read().unwrap();
+
+        self.position_at(&list, list.seek(target));
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), DbError> {
+        let mem = self.mem.clone();
+        let list = mem.list.read().unwrap();
+
+        let next = self.node.and_then(|node| list.next(node));
+        self.position_at(&list, next);
+
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<(), DbError> {
+        let mem = self.mem.clone();
+        let list = mem.list.read().unwrap();
+
+        let prev = self.node.and_then(|_| list.seek_for_prev(&self.key));
+        self.position_at(&list, prev);
+
+        Ok(())
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{self, ValueType};
+    use crate::memtable::{LookupResult, MemTable, SkipList};
+
+    #[test]
+    fn skiplist_iterates_in_order() {
+        let mut list = SkipList::new();
+
+        for n in [5_u8, 1, 9, 3, 7, 0, 2, 8, 4, 6] {
+            list.insert(key::encode(&[n], n as u64, ValueType::Value), vec![n]);
+        }
+
+        let mut node = list.first();
+        let mut expected = 0;
+
+        while let Some(current) = node {
+            assert_eq!(list.value(current), [expected]);
+
+            expected += 1;
+            node = list.next(current);
+        }
+
+        assert_eq!(expected, 10);
+        assert_eq!(
+            list.seek_for_prev(&key::seek_key(&[3], 100))
+                .map(|n| list.value(n)),
+            Some(&[2][..])
+        );
+    }
+
+    #[test]
+    fn get_respects_sequence_numbers() {
+        let mem = MemTable::new();
+
+        mem.add(1, ValueType::Value, b"key", b"first");
+        mem.add(2, ValueType::Value, b"key", b"second");
+        mem.add(3, ValueType::Deletion, b"key", b"");
+
+        assert_eq!(
+            mem.get(b"key", 1),
+            Some(LookupResult::Value(b"first".to_vec()))
+        );
+        assert_eq!(
+            mem.get(b"key", 2),
+            Some(LookupResult::Value(b"second".to_vec()))
+        );
+        assert_eq!(mem.get(b"key", 3), Some(LookupResult::Deleted));
+        assert_eq!(mem.get(b"other", 3), None);
+        assert_eq!(mem.len(), 3);
+    }
+}
@@ -0,0 +1,36 @@
+use crate::db::DbError;
+use std::fmt::Debug;
+
+/// Combines the merge operands written with [Db::merge](crate::Db::merge) with the value they
+/// apply to, turning read-modify-write cycles (counters, lists, ...) into blind writes
+///
+/// Operands are stored as-is and only combined when the key is read (or compacted).
+pub trait MergeOperator: Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Applies `operands`, from the oldest to the newest, to `existing_value` (None if the key
+    /// doesn't exist or was deleted)
+    ///
+    /// Returns None if the operands can't be applied, which is reported as a corruption.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Option<Vec<u8>>;
+}
+
+/// Applies `operands`, collected from the newest to the oldest, to `existing_value`
+pub(crate) fn full_merge(
+    operator: Option<&dyn MergeOperator>,
+    key: &[u8],
+    existing_value: Option<&[u8]>,
+    operands: &[Vec<u8>],
+) -> Result<Vec<u8>, DbError> {
+    let operator = operator.ok_or(DbError::InvalidArgument("no merge operator configured"))?;
+    let operands: Vec<&[u8]> = operands.iter().rev().map(Vec::as_slice).collect();
+
+    operator
+        .full_merge(key, existing_value, &operands)
+        .ok_or(DbError::Corruption("merge operator failed"))
+}
@@ -0,0 +1,241 @@
+use crate::merge::MergeOperator;
+use integer_encoding::*;
+
+/// Adds up little-endian u64 operands, wrapping around on overflow (e.g. counters)
+///
+/// A missing value counts as 0. Values and operands which are not 8 bytes long fail the merge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UInt64Add;
+
+fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+impl MergeOperator for UInt64Add {
+    fn name(&self) -> &str {
+        "fyodor.UInt64Add"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Option<Vec<u8>> {
+        let mut sum = existing_value.map_or(Some(0), decode_u64)?;
+
+        for operand in operands {
+            sum = sum.wrapping_add(decode_u64(operand)?);
+        }
+
+        Some(sum.to_le_bytes().to_vec())
+    }
+}
+
+/// Appends the operands to the value, separated by `delimiter` (e.g. logs, lists)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BytesAppend {
+    pub delimiter: Vec<u8>,
+}
+
+impl MergeOperator for BytesAppend {
+    fn name(&self) -> &str {
+        "fyodor.BytesAppend"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Option<Vec<u8>> {
+        let mut parts = existing_value.into_iter().chain(operands.iter().copied());
+        let mut result = parts.next().unwrap_or_default().to_vec();
+
+        for part in parts {
+            result.extend_from_slice(&self.delimiter);
+            result.extend_from_slice(part);
+        }
+
+        Some(result)
+    }
+}
+
+/// Keeps the greatest of the value and the operands, in bytewise order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Max;
+
+impl MergeOperator for Max {
+    fn name(&self) -> &str {
+        "fyodor.Max"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Option<Vec<u8>> {
+        let max = existing_value
+            .into_iter()
+            .chain(operands.iter().copied())
+            .max();
+
+        Some(max.unwrap_or_default().to_vec())
+    }
+}
+
+/// Keeps the smallest of the value and the operands, in bytewise order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Min;
+
+impl MergeOperator for Min {
+    fn name(&self) -> &str {
+        "fyodor.Min"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Option<Vec<u8>> {
+        let min = existing_value
+            .into_iter()
+            .chain(operands.iter().copied())
+            .min();
+
+        Some(min.unwrap_or_default().to_vec())
+    }
+}
+
+/// Treats the value and the operands as sets of byte strings, encoded with [encode_set], and
+/// keeps their union (e.g. tags, memberships)
+///
+/// Values and operands which are not valid sets fail the merge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SortedSetUnion;
+
+/// Encodes a set of byte strings as sorted and deduplicated length-prefixed elements, the format
+/// expected by [SortedSetUnion]
+pub fn encode_set<'a, I>(elements: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut elements: Vec<_> = elements.into_iter().collect();
+    elements.sort_unstable();
+    elements.dedup();
+
+    let mut encoded = Vec::new();
+
+    for element in elements {
+        encoded.extend_from_slice(&element.len().encode_var_vec());
+        encoded.extend_from_slice(element);
+    }
+
+    encoded
+}
+
+/// Decodes a set encoded with [encode_set], returning None if it's malformed
+pub fn decode_set(mut encoded: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut elements = Vec::new();
+
+    while !encoded.is_empty() {
+        let (len, read) = usize::decode_var(encoded)?;
+        let element = encoded.get(read..read.checked_add(len)?)?;
+
+        elements.push(element);
+        encoded = &encoded[read + len..];
+    }
+
+    Some(elements)
+}
+
+impl MergeOperator for SortedSetUnion {
+    fn name(&self) -> &str {
+        "fyodor.SortedSetUnion"
+    }
+
+    fn full_merge(
+        &self,
+        _key: &[u8],
+        existing_value: Option<&[u8]>,
+        operands: &[&[u8]],
+    ) -> Option<Vec<u8>> {
+        let mut elements = Vec::new();
+
+        for set in existing_value.into_iter().chain(operands.iter().copied()) {
+            elements.extend(decode_set(set)?);
+        }
+
+        Some(encode_set(elements))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merge::MergeOperator;
+    use crate::merge_operators::{
+        decode_set, encode_set, BytesAppend, Max, Min, SortedSetUnion, UInt64Add,
+    };
+
+    #[test]
+    fn uint64_add_sums_operands() {
+        let one = 1_u64.to_le_bytes();
+        let max = u64::MAX.to_le_bytes();
+
+        assert_eq!(
+            UInt64Add.full_merge(b"key", None, &[&one, &one]),
+            Some(2_u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            UInt64Add.full_merge(b"key", Some(&max), &[&one]),
+            Some(0_u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(UInt64Add.full_merge(b"key", Some(b"bad"), &[&one]), None);
+    }
+
+    #[test]
+    fn bytes_append_joins_operands() {
+        let append = BytesAppend {
+            delimiter: b",".to_vec(),
+        };
+
+        assert_eq!(
+            append.full_merge(b"key", Some(b"a"), &[b"b", b"c"]),
+            Some(b"a,b,c".to_vec())
+        );
+        assert_eq!(
+            append.full_merge(b"key", None, &[b"b"]),
+            Some(b"b".to_vec())
+        );
+    }
+
+    #[test]
+    fn max_and_min_keep_extremes() {
+        let operands: [&[u8]; 3] = [b"b", b"d", b"a"];
+
+        assert_eq!(
+            Max.full_merge(b"key", Some(b"c"), &operands),
+            Some(b"d".to_vec())
+        );
+        assert_eq!(
+            Min.full_merge(b"key", Some(b"c"), &operands),
+            Some(b"a".to_vec())
+        );
+    }
+
+    #[test]
+    fn sorted_set_union_merges_sets() {
+        let existing = encode_set([b"b".as_slice(), b"a"]);
+        let operand = encode_set([b"c".as_slice(), b"a"]);
+
+        let merged = SortedSetUnion
+            .full_merge(b"key", Some(&existing), &[&operand])
+            .unwrap();
+
+        assert_eq!(decode_set(&merged), Some(vec![b"a".as_slice(), b"b", b"c"]));
+        assert_eq!(decode_set(&[5, b'a']), None);
+        assert_eq!(SortedSetUnion.full_merge(b"key", Some(&[5]), &[]), None);
+    }
+}
@@ -0,0 +1,1285 @@
+use crate::block_cache::BlockCache;
+use crate::column_family::DEFAULT_COLUMN_FAMILY_NAME;
+use crate::compaction_service::CompactionService;
+use crate::db::DbError;
+use crate::key::SequenceNumber;
+use crate::listener::EventListener;
+use crate::merge::MergeOperator;
+use crate::merge_operators::{Max, Min, SortedSetUnion, UInt64Add};
+use crate::prefix::{FixedPrefix, PrefixExtractor};
+use crate::rate_limiter::RateLimiter;
+use crate::row_cache::RowCache;
+use crate::snapshot::Snapshot;
+use crate::timestamp::Timestamp;
+use crate::transaction::IsolationLevel;
+use crate::wal::{RetentionPolicy, SyncPolicy};
+use crate::write_buffer_manager::WriteBufferManager;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How the data blocks of the tables are compressed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionType {
+    #[default]
+    None,
+    Lz4,
+    /// Zstandard, slower than LZ4 but compressing more
+    Zstd,
+}
+
+/// How the tables of a column family are compacted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompactionStyle {
+    /// Tables are organized in levels of growing size, each one holding disjoint key ranges
+    #[default]
+    Level,
+    /// Similarly sized runs of tables are merged together
+    Universal,
+    /// Tables are never merged, the oldest ones are deleted instead
+    Fifo,
+}
+
+/// Which file of a level a compaction of a column family organized in levels picks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompactionPriority {
+    /// The file holding the oldest entry, which suits workloads updating keys at random: the
+    /// oldest entries are the likeliest to be overwritten
+    OldestSmallestSeqFirst,
+    /// The file overlapping the fewest bytes of the next level relative to its size, which
+    /// minimizes the bytes rewritten
+    #[default]
+    MinOverlappingRatio,
+    /// The file whose newest entry is the oldest, which suits workloads with hot key ranges: the
+    /// cold ranges are pushed down first
+    OldestLargestSeqFirst,
+}
+
+/// Tuning knobs of a column family
+#[derive(Clone, Debug)]
+pub struct ColumnFamilyOptions {
+    /// Size of the memtable after which it's flushed to a table
+    pub write_buffer_size: usize,
+    /// Number of memtables, the one written to included, from which the writes wait for the
+    /// flushes once the one written to is full, and are slowed down one memtable earlier if
+    /// there are more than 3
+    pub max_write_buffer_number: usize,
+    /// Size of the data blocks of the tables
+    pub block_size: usize,
+    /// Bits used by the bloom filters for every key
+    pub bloom_bits_per_key: usize,
+    /// Groups keys by prefix: the prefixes are added to the bloom filters, so that prefix seeks
+    /// can skip the tables without the prefix
+    pub prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// Combines the operands written with [Db::merge](crate::Db::merge)
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Keeps the data blocks read recently in memory, shared with other column families and
+    /// databases if they should share the memory
+    pub block_cache: Option<Arc<BlockCache>>,
+    /// Keeps the versions of the keys point lookups found in the tables, shared like
+    /// [ColumnFamilyOptions::block_cache]
+    pub row_cache: Option<Arc<RowCache>>,
+    /// Keeps the index and filter blocks of the tables in [ColumnFamilyOptions::block_cache]
+    /// rather than in the tables, so that their memory is bounded by the cache, at the cost of
+    /// reading them again once evicted
+    pub cache_index_and_filter_blocks: bool,
+    /// With [ColumnFamilyOptions::cache_index_and_filter_blocks], the level 0 tables keep their
+    /// index and filter blocks all the same: every point lookup checks their filters
+    pub pin_l0_filter_and_index_blocks_in_cache: bool,
+    /// Compression of the data blocks of the tables
+    pub compression: CompressionType,
+    /// Compression of the data blocks of the tables compacted into the bottommost level, if not
+    /// [ColumnFamilyOptions::compression]: a stronger one pays off there, since most of the data
+    /// ends up there, rarely rewritten
+    pub bottommost_compression: Option<CompressionType>,
+    /// Compression of the data blocks of the tables of each level, the last one going for the
+    /// levels below, if not [ColumnFamilyOptions::compression]: the first levels are rewritten
+    /// too often for a slow compression to pay off
+    pub compression_per_level: Vec<CompressionType>,
+    pub compaction_style: CompactionStyle,
+    /// With [CompactionStyle::Level], file of a level picked by the compactions
+    pub compaction_priority: CompactionPriority,
+    /// Only compact when asked to, e.g. during bulk loads: the writes stall once compactions are
+    /// too far behind
+    pub disable_auto_compactions: bool,
+    /// Number of level 0 tables which triggers a compaction
+    pub level0_file_num_compaction_trigger: usize,
+    /// With [CompactionStyle::Level], share of deletions among the entries of a table from which
+    /// it's compacted, even if its level is under its target size, if any
+    ///
+    /// This reclaims the space of the keys deleted in bulk soon after the deletions are flushed.
+    pub deletion_compaction_ratio: Option<f64>,
+    /// With [CompactionStyle::Level], age in seconds from which a table is compacted, even if its
+    /// level is under its target size, if any
+    ///
+    /// This makes sure that the expired entries are dropped from the key ranges no longer written
+    /// to, though they end up rewritten in the last level once per period.
+    pub periodic_compaction_seconds: Option<u64>,
+    /// Size of the tables written by compactions to level 1
+    pub target_file_size_base: u64,
+    /// Growth of the size of the tables from a level to the next one
+    pub target_file_size_multiplier: u64,
+    /// Size of level 1 beyond which it's compacted
+    pub max_bytes_for_level_base: u64,
+    /// Growth of the size of a level from a level to the next one
+    pub max_bytes_for_level_multiplier: u64,
+    /// Number of level 0 tables from which writes are slowed down to
+    /// [Options::delayed_write_rate]
+    pub level0_slowdown_writes_trigger: usize,
+    /// Number of level 0 tables from which writes wait for compactions
+    pub level0_stop_writes_trigger: usize,
+    /// Bytes compactions are behind from which writes are slowed down, 0 for no limit
+    pub soft_pending_compaction_bytes_limit: u64,
+    /// Bytes compactions are behind from which writes wait for them, 0 for no limit
+    pub hard_pending_compaction_bytes_limit: u64,
+    /// With [CompactionStyle::Universal], percentage by which a sorted run may be larger than
+    /// the newer runs together to still be merged with them
+    pub universal_size_ratio: u64,
+    /// With [CompactionStyle::Universal], fewest sorted runs merged by a compaction picked by
+    /// size ratio
+    pub universal_min_merge_width: usize,
+    /// With [CompactionStyle::Universal], most sorted runs merged by a compaction
+    pub universal_max_merge_width: usize,
+    /// With [CompactionStyle::Fifo], total size of the tables beyond which the oldest ones are
+    /// deleted
+    pub fifo_max_table_files_size: u64,
+    /// With [CompactionStyle::Fifo], age from which the tables are deleted, if any
+    pub fifo_ttl: Option<Duration>,
+    /// Every version of a key carries a [Timestamp](crate::timestamp::Timestamp) chosen by the
+    /// application, written with [WriteBatch::put_cf_with_ts](crate::batch::WriteBatch::put_cf_with_ts)
+    /// and read as of a timestamp with [Db::get_cf_with_ts](crate::Db::get_cf_with_ts)
+    ///
+    /// Can't change once the column family holds data, and rules out prefix extractors and merge
+    /// operators.
+    pub user_timestamps: bool,
+    /// Flushes write the values to blob files, the tables only holding the keys along with where
+    /// their values are: compactions then rewrite the keys alone, which saves most of their I/O
+    /// with large values, at the cost of an extra read per value
+    pub enable_blob_files: bool,
+    /// With [ColumnFamilyOptions::enable_blob_files], size in bytes from which the values go to
+    /// blob files, the smaller ones staying in the tables, where a blob index would take about as
+    /// much space as them
+    pub min_blob_size: u64,
+    /// Share of garbage, i.e. of the bytes of the values no table points to anymore, from which
+    /// the compactions move the rest of the values of a blob file to a new one, if any
+    ///
+    /// With [CompactionStyle::Level], the tables pointing to such files are compacted even if
+    /// their level is under its target size. The blob files only holding garbage are deleted
+    /// either way.
+    pub blob_garbage_collection_ratio: Option<f64>,
+}
+
+impl Default for ColumnFamilyOptions {
+    fn default() -> Self {
+        ColumnFamilyOptions {
+            write_buffer_size: 4 << 20,
+            max_write_buffer_number: 2,
+            block_size: 4096,
+            bloom_bits_per_key: 10,
+            prefix_extractor: None,
+            merge_operator: None,
+            block_cache: None,
+            row_cache: None,
+            cache_index_and_filter_blocks: false,
+            pin_l0_filter_and_index_blocks_in_cache: false,
+            compression: CompressionType::default(),
+            bottommost_compression: None,
+            compression_per_level: Vec::new(),
+            compaction_style: CompactionStyle::default(),
+            compaction_priority: CompactionPriority::default(),
+            disable_auto_compactions: false,
+            level0_file_num_compaction_trigger: 4,
+            deletion_compaction_ratio: None,
+            periodic_compaction_seconds: None,
+            target_file_size_base: 64 << 20,
+            target_file_size_multiplier: 1,
+            max_bytes_for_level_base: 256 << 20,
+            max_bytes_for_level_multiplier: 10,
+            level0_slowdown_writes_trigger: 20,
+            level0_stop_writes_trigger: 36,
+            soft_pending_compaction_bytes_limit: 64 << 30,
+            hard_pending_compaction_bytes_limit: 256 << 30,
+            universal_size_ratio: 1,
+            universal_min_merge_width: 2,
+            universal_max_merge_width: usize::MAX,
+            fifo_max_table_files_size: 1 << 30,
+            fifo_ttl: None,
+            user_timestamps: false,
+            enable_blob_files: false,
+            min_blob_size: 0,
+            blob_garbage_collection_ratio: None,
+        }
+    }
+}
+
+impl ColumnFamilyOptions {
+    pub fn with_write_buffer_size(mut self, write_buffer_size: usize) -> Self {
+        self.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn with_max_write_buffer_number(mut self, max_write_buffer_number: usize) -> Self {
+        self.max_write_buffer_number = max_write_buffer_number;
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    pub fn with_prefix_extractor(mut self, prefix_extractor: Arc<dyn PrefixExtractor>) -> Self {
+        self.prefix_extractor = Some(prefix_extractor);
+        self
+    }
+
+    pub fn with_merge_operator(mut self, merge_operator: Arc<dyn MergeOperator>) -> Self {
+        self.merge_operator = Some(merge_operator);
+        self
+    }
+
+    pub fn with_block_cache(mut self, block_cache: Arc<BlockCache>) -> Self {
+        self.block_cache = Some(block_cache);
+        self
+    }
+
+    pub fn with_row_cache(mut self, row_cache: Arc<RowCache>) -> Self {
+        self.row_cache = Some(row_cache);
+        self
+    }
+
+    pub fn with_cache_index_and_filter_blocks(
+        mut self,
+        cache_index_and_filter_blocks: bool,
+        pin_l0_filter_and_index_blocks_in_cache: bool,
+    ) -> Self {
+        self.cache_index_and_filter_blocks = cache_index_and_filter_blocks;
+        self.pin_l0_filter_and_index_blocks_in_cache = pin_l0_filter_and_index_blocks_in_cache;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: CompressionType) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_bottommost_compression(mut self, compression: CompressionType) -> Self {
+        self.bottommost_compression = Some(compression);
+        self
+    }
+
+    pub fn with_compression_per_level(
+        mut self,
+        compression_per_level: Vec<CompressionType>,
+    ) -> Self {
+        self.compression_per_level = compression_per_level;
+        self
+    }
+
+    /// Returns the compression of the tables written into `level`, the bottommost level if
+    /// `bottommost`
+    pub fn output_compression(&self, level: usize, bottommost: bool) -> CompressionType {
+        match self.bottommost_compression {
+            Some(compression) if bottommost => compression,
+            _ => self
+                .compression_per_level
+                .get(level)
+                .or(self.compression_per_level.last())
+                .copied()
+                .unwrap_or(self.compression),
+        }
+    }
+
+    pub fn with_compaction_style(mut self, compaction_style: CompactionStyle) -> Self {
+        self.compaction_style = compaction_style;
+        self
+    }
+
+    pub fn with_compaction_priority(mut self, compaction_priority: CompactionPriority) -> Self {
+        self.compaction_priority = compaction_priority;
+        self
+    }
+
+    pub fn with_disable_auto_compactions(mut self, disable_auto_compactions: bool) -> Self {
+        self.disable_auto_compactions = disable_auto_compactions;
+        self
+    }
+
+    pub fn with_level0_file_num_compaction_trigger(mut self, trigger: usize) -> Self {
+        self.level0_file_num_compaction_trigger = trigger;
+        self
+    }
+
+    pub fn with_deletion_compaction_ratio(mut self, ratio: f64) -> Self {
+        self.deletion_compaction_ratio = Some(ratio);
+        self
+    }
+
+    pub fn with_periodic_compaction_seconds(mut self, seconds: u64) -> Self {
+        self.periodic_compaction_seconds = Some(seconds);
+        self
+    }
+
+    pub fn with_target_file_size(mut self, base: u64, multiplier: u64) -> Self {
+        self.target_file_size_base = base;
+        self.target_file_size_multiplier = multiplier;
+        self
+    }
+
+    pub fn with_max_bytes_for_level(mut self, base: u64, multiplier: u64) -> Self {
+        self.max_bytes_for_level_base = base;
+        self.max_bytes_for_level_multiplier = multiplier;
+        self
+    }
+
+    pub fn with_level0_writes_triggers(mut self, slowdown: usize, stop: usize) -> Self {
+        self.level0_slowdown_writes_trigger = slowdown;
+        self.level0_stop_writes_trigger = stop;
+        self
+    }
+
+    pub fn with_pending_compaction_bytes_limits(mut self, soft: u64, hard: u64) -> Self {
+        self.soft_pending_compaction_bytes_limit = soft;
+        self.hard_pending_compaction_bytes_limit = hard;
+        self
+    }
+
+    pub fn with_universal_merge_widths(mut self, min: usize, max: usize) -> Self {
+        self.universal_min_merge_width = min;
+        self.universal_max_merge_width = max;
+        self
+    }
+
+    pub fn with_universal_size_ratio(mut self, size_ratio: u64) -> Self {
+        self.universal_size_ratio = size_ratio;
+        self
+    }
+
+    pub fn with_fifo_max_table_files_size(mut self, size: u64) -> Self {
+        self.fifo_max_table_files_size = size;
+        self
+    }
+
+    pub fn with_fifo_ttl(mut self, ttl: Duration) -> Self {
+        self.fifo_ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_user_timestamps(mut self, user_timestamps: bool) -> Self {
+        self.user_timestamps = user_timestamps;
+        self
+    }
+
+    pub fn with_blob_files(mut self, enable_blob_files: bool) -> Self {
+        self.enable_blob_files = enable_blob_files;
+        self
+    }
+
+    pub fn with_min_blob_size(mut self, min_blob_size: u64) -> Self {
+        self.min_blob_size = min_blob_size;
+        self
+    }
+
+    pub fn with_blob_garbage_collection_ratio(mut self, ratio: f64) -> Self {
+        self.blob_garbage_collection_ratio = Some(ratio);
+        self
+    }
+
+    /// Returns the knobs as (name, value) pairs, in the format understood by
+    /// [ColumnFamilyOptions::set]
+    ///
+    /// The prefix extractor and the merge operator are represented by their names.
+    pub fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("write_buffer_size", self.write_buffer_size.to_string()),
+            (
+                "max_write_buffer_number",
+                self.max_write_buffer_number.to_string(),
+            ),
+            ("block_size", self.block_size.to_string()),
+            ("bloom_bits_per_key", self.bloom_bits_per_key.to_string()),
+            (
+                "prefix_extractor",
+                self.prefix_extractor
+                    .as_ref()
+                    .map(|extractor| extractor.name())
+                    .unwrap_or_default(),
+            ),
+            (
+                "merge_operator",
+                self.merge_operator
+                    .as_ref()
+                    .map(|operator| operator.name().to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "compression",
+                compression_name(self.compression).to_string(),
+            ),
+            (
+                "bottommost_compression",
+                to_string_or_empty(self.bottommost_compression.map(compression_name)),
+            ),
+            (
+                "compression_per_level",
+                self.compression_per_level
+                    .iter()
+                    .map(|compression| compression_name(*compression))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            ),
+            (
+                "cache_index_and_filter_blocks",
+                self.cache_index_and_filter_blocks.to_string(),
+            ),
+            (
+                "pin_l0_filter_and_index_blocks_in_cache",
+                self.pin_l0_filter_and_index_blocks_in_cache.to_string(),
+            ),
+            (
+                "compaction_style",
+                compaction_style_name(self.compaction_style).to_string(),
+            ),
+            (
+                "compaction_priority",
+                compaction_priority_name(self.compaction_priority).to_string(),
+            ),
+            (
+                "disable_auto_compactions",
+                self.disable_auto_compactions.to_string(),
+            ),
+            (
+                "level0_file_num_compaction_trigger",
+                self.level0_file_num_compaction_trigger.to_string(),
+            ),
+            (
+                "deletion_compaction_ratio",
+                to_string_or_empty(self.deletion_compaction_ratio),
+            ),
+            (
+                "periodic_compaction_seconds",
+                to_string_or_empty(self.periodic_compaction_seconds),
+            ),
+            (
+                "target_file_size_base",
+                self.target_file_size_base.to_string(),
+            ),
+            (
+                "target_file_size_multiplier",
+                self.target_file_size_multiplier.to_string(),
+            ),
+            (
+                "max_bytes_for_level_base",
+                self.max_bytes_for_level_base.to_string(),
+            ),
+            (
+                "max_bytes_for_level_multiplier",
+                self.max_bytes_for_level_multiplier.to_string(),
+            ),
+            (
+                "level0_slowdown_writes_trigger",
+                self.level0_slowdown_writes_trigger.to_string(),
+            ),
+            (
+                "level0_stop_writes_trigger",
+                self.level0_stop_writes_trigger.to_string(),
+            ),
+            (
+                "soft_pending_compaction_bytes_limit",
+                self.soft_pending_compaction_bytes_limit.to_string(),
+            ),
+            (
+                "hard_pending_compaction_bytes_limit",
+                self.hard_pending_compaction_bytes_limit.to_string(),
+            ),
+            (
+                "universal_size_ratio",
+                self.universal_size_ratio.to_string(),
+            ),
+            (
+                "universal_min_merge_width",
+                self.universal_min_merge_width.to_string(),
+            ),
+            (
+                "universal_max_merge_width",
+                self.universal_max_merge_width.to_string(),
+            ),
+            (
+                "fifo_max_table_files_size",
+                self.fifo_max_table_files_size.to_string(),
+            ),
+            (
+                "fifo_ttl_ms",
+                to_string_or_empty(self.fifo_ttl.map(|ttl| ttl.as_millis())),
+            ),
+            ("user_timestamps", self.user_timestamps.to_string()),
+            ("enable_blob_files", self.enable_blob_files.to_string()),
+            ("min_blob_size", self.min_blob_size.to_string()),
+            (
+                "blob_garbage_collection_ratio",
+                to_string_or_empty(self.blob_garbage_collection_ratio),
+            ),
+        ]
+    }
+
+    /// Sets the knob called `name` from its string representation
+    ///
+    /// Prefix extractors and merge operators can only be set by name if they are built into the
+    /// crate and have no parameters hidden from their name: the others are left unset.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), DbError> {
+        match name {
+            "write_buffer_size" => self.write_buffer_size = parse(value)?,
+            "max_write_buffer_number" => self.max_write_buffer_number = parse(value)?,
+            "block_size" => self.block_size = parse(value)?,
+            "bloom_bits_per_key" => self.bloom_bits_per_key = parse(value)?,
+            "prefix_extractor" => self.prefix_extractor = builtin_prefix_extractor(value),
+            "merge_operator" => self.merge_operator = builtin_merge_operator(value),
+            "compression" => self.compression = parse_compression(value)?,
+            "bottommost_compression" => {
+                self.bottommost_compression = match value {
+                    "" => None,
+                    _ => Some(parse_compression(value)?),
+                }
+            }
+            "compression_per_level" => {
+                self.compression_per_level = value
+                    .split(':')
+                    .filter(|name| !name.is_empty())
+                    .map(parse_compression)
+                    .collect::<Result<_, _>>()?
+            }
+            "compaction_style" => {
+                self.compaction_style = match value {
+                    "level" => CompactionStyle::Level,
+                    "universal" => CompactionStyle::Universal,
+                    "fifo" => CompactionStyle::Fifo,
+                    _ => return Err(DbError::InvalidArgument("bad compaction style")),
+                }
+            }
+            "compaction_priority" => {
+                self.compaction_priority = match value {
+                    "oldest_smallest_seq_first" => CompactionPriority::OldestSmallestSeqFirst,
+                    "min_overlapping_ratio" => CompactionPriority::MinOverlappingRatio,
+                    "oldest_largest_seq_first" => CompactionPriority::OldestLargestSeqFirst,
+                    _ => return Err(DbError::InvalidArgument("bad compaction priority")),
+                }
+            }
+            "cache_index_and_filter_blocks" => self.cache_index_and_filter_blocks = parse(value)?,
+            "pin_l0_filter_and_index_blocks_in_cache" => {
+                self.pin_l0_filter_and_index_blocks_in_cache = parse(value)?
+            }
+            "disable_auto_compactions" => self.disable_auto_compactions = parse(value)?,
+            "level0_file_num_compaction_trigger" => {
+                self.level0_file_num_compaction_trigger = parse(value)?
+            }
+            "deletion_compaction_ratio" => self.deletion_compaction_ratio = parse_optional(value)?,
+            "periodic_compaction_seconds" => {
+                self.periodic_compaction_seconds = parse_optional(value)?
+            }
+            "target_file_size_base" => self.target_file_size_base = parse(value)?,
+            "target_file_size_multiplier" => self.target_file_size_multiplier = parse(value)?,
+            "max_bytes_for_level_base" => self.max_bytes_for_level_base = parse(value)?,
+            "max_bytes_for_level_multiplier" => self.max_bytes_for_level_multiplier = parse(value)?,
+            "level0_slowdown_writes_trigger" => self.level0_slowdown_writes_trigger = parse(value)?,
+            "level0_stop_writes_trigger" => self.level0_stop_writes_trigger = parse(value)?,
+            "soft_pending_compaction_bytes_limit" => {
+                self.soft_pending_compaction_bytes_limit = parse(value)?
+            }
+            "hard_pending_compaction_bytes_limit" => {
+                self.hard_pending_compaction_bytes_limit = parse(value)?
+            }
+            "universal_size_ratio" => self.universal_size_ratio = parse(value)?,
+            "universal_min_merge_width" => self.universal_min_merge_width = parse(value)?,
+            "universal_max_merge_width" => self.universal_max_merge_width = parse(value)?,
+            "fifo_max_table_files_size" => self.fifo_max_table_files_size = parse(value)?,
+            "fifo_ttl_ms" => self.fifo_ttl = parse_optional(value)?.map(Duration::from_millis),
+            "user_timestamps" => self.user_timestamps = parse(value)?,
+            "enable_blob_files" => self.enable_blob_files = parse(value)?,
+            "min_blob_size" => self.min_blob_size = parse(value)?,
+            "blob_garbage_collection_ratio" => {
+                self.blob_garbage_collection_ratio = parse_optional(value)?
+            }
+            _ => return Err(DbError::InvalidArgument("unknown option")),
+        }
+
+        Ok(())
+    }
+
+    /// Fails if some knobs have values the database can't work with
+    pub fn validate(&self) -> Result<(), DbError> {
+        let checks = [
+            (
+                self.write_buffer_size > 0,
+                "write_buffer_size must be positive",
+            ),
+            (
+                self.max_write_buffer_number >= 2,
+                "max_write_buffer_number must be at least 2",
+            ),
+            (self.block_size > 0, "block_size must be positive"),
+            (
+                self.level0_file_num_compaction_trigger > 0,
+                "level0_file_num_compaction_trigger must be positive",
+            ),
+            (
+                self.deletion_compaction_ratio
+                    .is_none_or(|ratio| 0.0 < ratio && ratio <= 1.0),
+                "deletion_compaction_ratio must be in (0, 1]",
+            ),
+            (
+                self.blob_garbage_collection_ratio
+                    .is_none_or(|ratio| 0.0 < ratio && ratio <= 1.0),
+                "blob_garbage_collection_ratio must be in (0, 1]",
+            ),
+            (
+                self.periodic_compaction_seconds != Some(0),
+                "periodic_compaction_seconds must be positive",
+            ),
+            (
+                self.target_file_size_base > 0 && self.target_file_size_multiplier > 0,
+                "target file sizes must be positive",
+            ),
+            (
+                self.max_bytes_for_level_base > 0 && self.max_bytes_for_level_multiplier > 0,
+                "level sizes must be positive",
+            ),
+            (
+                0 < self.level0_slowdown_writes_trigger
+                    && self.level0_slowdown_writes_trigger <= self.level0_stop_writes_trigger,
+                "level 0 triggers must be positive, the slowdown one before the stop one",
+            ),
+            (
+                self.hard_pending_compaction_bytes_limit == 0
+                    || self.soft_pending_compaction_bytes_limit
+                        <= self.hard_pending_compaction_bytes_limit,
+                "the soft pending compaction bytes limit must not exceed the hard one",
+            ),
+            (
+                2 <= self.universal_min_merge_width
+                    && self.universal_min_merge_width <= self.universal_max_merge_width,
+                "universal merge widths must be at least 2, the min one not above the max one",
+            ),
+            (
+                !self.user_timestamps
+                    || (self.prefix_extractor.is_none() && self.merge_operator.is_none()),
+                "user timestamps rule out prefix extractors and merge operators",
+            ),
+        ];
+
+        match checks.into_iter().find(|(valid, _)| !valid) {
+            Some((_, message)) => Err(DbError::InvalidArgument(message)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Tuning knobs of a database
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Options of the default column family, and of the others unless set in
+    /// [Options::cf_options]
+    pub default_cf_options: ColumnFamilyOptions,
+    /// Options of the column families by name
+    pub cf_options: HashMap<String, ColumnFamilyOptions>,
+    /// Directory of the write-ahead logs, when they should live apart from the tables (e.g. on a
+    /// faster device). Defaults to the database directory.
+    pub wal_dir: Option<PathBuf>,
+    /// When the write-ahead log is synced to disk
+    pub wal_sync_policy: SyncPolicy,
+    /// Write-ahead log records of at least this many bytes are compressed
+    pub wal_compression_threshold: Option<usize>,
+    /// How long obsolete write-ahead logs are archived before being deleted
+    pub wal_retention: RetentionPolicy,
+    /// Bytes of disk space reserved upfront for every new write-ahead log
+    pub wal_preallocate_size: u64,
+    /// Number of obsolete write-ahead logs kept around to be reused instead of creating new files
+    pub recycle_log_file_num: usize,
+    /// Buffer write-ahead log records in memory until they are explicitly flushed
+    pub manual_wal_flush: bool,
+    /// Bytes per second writes are slowed down to when flushes or compactions fall behind, see
+    /// [ColumnFamilyOptions::level0_slowdown_writes_trigger]
+    pub delayed_write_rate: u64,
+    /// Insert the writes into the memtables concurrently, outside the mutex of the database
+    ///
+    /// A write only becomes visible, and returns, once every write before it was inserted, so that
+    /// the reads keep seeing whole batches in the order of their sequence numbers.
+    pub unordered_write: bool,
+    /// Number of threads flushing the memtables in the background
+    pub max_background_flushes: usize,
+    /// Number of threads compacting the tables in the background
+    pub max_background_compactions: usize,
+    /// Number of threads a compaction is split across, each one merging a key range into its own
+    /// table file
+    pub max_subcompactions: usize,
+    /// Caps the memory of the memtables of every column family, shared with other databases if
+    /// they should be capped together
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>,
+    /// Caps the bytes per second written by the flushes and compactions, shared with other
+    /// databases if they should be capped together
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Runs the compactions instead of the background threads of the database
+    pub compaction_service: Option<Arc<dyn CompactionService>>,
+    /// Notified of the flushes, compactions and table files of the database
+    pub listeners: Vec<Arc<dyn EventListener>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            default_cf_options: ColumnFamilyOptions::default(),
+            cf_options: HashMap::new(),
+            wal_dir: None,
+            wal_sync_policy: SyncPolicy::default(),
+            wal_compression_threshold: None,
+            wal_retention: RetentionPolicy::default(),
+            wal_preallocate_size: 0,
+            recycle_log_file_num: 0,
+            manual_wal_flush: false,
+            delayed_write_rate: 16 << 20,
+            unordered_write: false,
+            max_background_flushes: 1,
+            max_background_compactions: 1,
+            max_subcompactions: 1,
+            write_buffer_manager: None,
+            rate_limiter: None,
+            compaction_service: None,
+            listeners: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    pub fn with_default_cf_options(mut self, cf_options: ColumnFamilyOptions) -> Self {
+        self.default_cf_options = cf_options;
+        self
+    }
+
+    pub fn with_cf_options(mut self, name: &str, cf_options: ColumnFamilyOptions) -> Self {
+        self.cf_options.insert(name.to_string(), cf_options);
+        self
+    }
+
+    pub fn with_listener(mut self, listener: Arc<dyn EventListener>) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn with_wal_dir<P: Into<PathBuf>>(mut self, wal_dir: P) -> Self {
+        self.wal_dir = Some(wal_dir.into());
+        self
+    }
+
+    pub fn with_wal_sync_policy(mut self, wal_sync_policy: SyncPolicy) -> Self {
+        self.wal_sync_policy = wal_sync_policy;
+        self
+    }
+
+    pub fn with_wal_compression_threshold(mut self, threshold: usize) -> Self {
+        self.wal_compression_threshold = Some(threshold);
+        self
+    }
+
+    pub fn with_wal_retention(mut self, wal_retention: RetentionPolicy) -> Self {
+        self.wal_retention = wal_retention;
+        self
+    }
+
+    pub fn with_wal_preallocate_size(mut self, wal_preallocate_size: u64) -> Self {
+        self.wal_preallocate_size = wal_preallocate_size;
+        self
+    }
+
+    pub fn with_recycle_log_file_num(mut self, recycle_log_file_num: usize) -> Self {
+        self.recycle_log_file_num = recycle_log_file_num;
+        self
+    }
+
+    pub fn with_manual_wal_flush(mut self, manual_wal_flush: bool) -> Self {
+        self.manual_wal_flush = manual_wal_flush;
+        self
+    }
+
+    pub fn with_delayed_write_rate(mut self, delayed_write_rate: u64) -> Self {
+        self.delayed_write_rate = delayed_write_rate;
+        self
+    }
+
+    pub fn with_unordered_write(mut self, unordered_write: bool) -> Self {
+        self.unordered_write = unordered_write;
+        self
+    }
+
+    pub fn with_max_background_jobs(mut self, flushes: usize, compactions: usize) -> Self {
+        self.max_background_flushes = flushes;
+        self.max_background_compactions = compactions;
+        self
+    }
+
+    pub fn with_max_subcompactions(mut self, max_subcompactions: usize) -> Self {
+        self.max_subcompactions = max_subcompactions;
+        self
+    }
+
+    pub fn with_write_buffer_manager(mut self, manager: Arc<WriteBufferManager>) -> Self {
+        self.write_buffer_manager = Some(manager);
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn with_compaction_service(mut self, service: Arc<dyn CompactionService>) -> Self {
+        self.compaction_service = Some(service);
+        self
+    }
+
+    /// Returns the directory holding the write-ahead logs of the database at `db_path`
+    pub fn wal_dir<'a>(&'a self, db_path: &'a Path) -> &'a Path {
+        self.wal_dir.as_deref().unwrap_or(db_path)
+    }
+
+    /// Returns the options of the column family called `name`
+    pub fn column_family_options(&self, name: &str) -> &ColumnFamilyOptions {
+        self.cf_options
+            .get(name)
+            .unwrap_or(&self.default_cf_options)
+    }
+
+    /// Returns the knobs of the database, without the ones of the column families, as (name,
+    /// value) pairs in the format understood by [Options::set]
+    pub fn to_pairs(&self) -> Vec<(&'static str, String)> {
+        let (sync_policy, sync_parameter) = match self.wal_sync_policy {
+            SyncPolicy::Always => ("always", String::new()),
+            SyncPolicy::EveryNWrites(n) => ("every_n_writes", n.to_string()),
+            SyncPolicy::Interval(interval) => ("interval", interval.as_millis().to_string()),
+        };
+
+        vec![
+            (
+                "wal_dir",
+                self.wal_dir
+                    .as_ref()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default(),
+            ),
+            ("wal_sync_policy", sync_policy.to_string()),
+            // Number of writes or milliseconds, depending on the policy
+            ("wal_sync_parameter", sync_parameter),
+            (
+                "wal_compression_threshold",
+                to_string_or_empty(self.wal_compression_threshold),
+            ),
+            (
+                "wal_retention_ttl_ms",
+                to_string_or_empty(self.wal_retention.ttl.map(|ttl| ttl.as_millis())),
+            ),
+            (
+                "wal_retention_size_limit",
+                to_string_or_empty(self.wal_retention.size_limit),
+            ),
+            (
+                "wal_preallocate_size",
+                self.wal_preallocate_size.to_string(),
+            ),
+            (
+                "recycle_log_file_num",
+                self.recycle_log_file_num.to_string(),
+            ),
+            ("manual_wal_flush", self.manual_wal_flush.to_string()),
+            ("delayed_write_rate", self.delayed_write_rate.to_string()),
+            ("unordered_write", self.unordered_write.to_string()),
+            (
+                "max_background_flushes",
+                self.max_background_flushes.to_string(),
+            ),
+            (
+                "max_background_compactions",
+                self.max_background_compactions.to_string(),
+            ),
+            ("max_subcompactions", self.max_subcompactions.to_string()),
+        ]
+    }
+
+    /// Sets the knob of the database called `name` from its string representation
+    ///
+    /// The sync policy of the write-ahead log is set in two steps: its kind, then its
+    /// parameter.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), DbError> {
+        match name {
+            "wal_dir" => self.wal_dir = (!value.is_empty()).then(|| PathBuf::from(value)),
+            "wal_sync_policy" => {
+                self.wal_sync_policy = match value {
+                    "always" => SyncPolicy::Always,
+                    "every_n_writes" => SyncPolicy::EveryNWrites(1),
+                    "interval" => SyncPolicy::Interval(Duration::ZERO),
+                    _ => return Err(DbError::InvalidArgument("bad sync policy")),
+                }
+            }
+            "wal_sync_parameter" => match &mut self.wal_sync_policy {
+                SyncPolicy::Always if value.is_empty() => {}
+                SyncPolicy::Always => return Err(DbError::InvalidArgument("bad sync parameter")),
+                SyncPolicy::EveryNWrites(n) => *n = parse(value)?,
+                SyncPolicy::Interval(interval) => *interval = Duration::from_millis(parse(value)?),
+            },
+            "wal_compression_threshold" => self.wal_compression_threshold = parse_optional(value)?,
+            "wal_retention_ttl_ms" => {
+                self.wal_retention.ttl = parse_optional(value)?.map(Duration::from_millis)
+            }
+            "wal_retention_size_limit" => self.wal_retention.size_limit = parse_optional(value)?,
+            "wal_preallocate_size" => self.wal_preallocate_size = parse(value)?,
+            "recycle_log_file_num" => self.recycle_log_file_num = parse(value)?,
+            "manual_wal_flush" => self.manual_wal_flush = parse(value)?,
+            "delayed_write_rate" => self.delayed_write_rate = parse(value)?,
+            "unordered_write" => self.unordered_write = parse(value)?,
+            "max_background_flushes" => self.max_background_flushes = parse(value)?,
+            "max_background_compactions" => self.max_background_compactions = parse(value)?,
+            "max_subcompactions" => self.max_subcompactions = parse(value)?,
+            _ => return Err(DbError::InvalidArgument("unknown option")),
+        }
+
+        Ok(())
+    }
+
+    /// Fails if some knobs have values the database can't work with, checked at open
+    pub fn validate(&self) -> Result<(), DbError> {
+        if self.delayed_write_rate == 0 {
+            return Err(DbError::InvalidArgument(
+                "delayed_write_rate must be positive",
+            ));
+        }
+
+        if self.max_background_flushes == 0
+            || self.max_background_compactions == 0
+            || self.max_subcompactions == 0
+        {
+            return Err(DbError::InvalidArgument(
+                "background work needs at least a thread of each kind",
+            ));
+        }
+
+        self.default_cf_options.validate()?;
+
+        for cf_options in self.cf_options.values() {
+            cf_options.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Knobs of [ColumnFamilyOptions] which can be changed on an open database, see
+/// [Db::set_options](crate::db::Db::set_options)
+pub const MUTABLE_CF_OPTIONS: &[&str] = &[
+    "write_buffer_size",
+    "max_write_buffer_number",
+    "disable_auto_compactions",
+    "level0_file_num_compaction_trigger",
+    "deletion_compaction_ratio",
+    "periodic_compaction_seconds",
+    "target_file_size_base",
+    "target_file_size_multiplier",
+    "max_bytes_for_level_base",
+    "max_bytes_for_level_multiplier",
+    "level0_slowdown_writes_trigger",
+    "level0_stop_writes_trigger",
+    "soft_pending_compaction_bytes_limit",
+    "hard_pending_compaction_bytes_limit",
+    "universal_size_ratio",
+    "universal_min_merge_width",
+    "universal_max_merge_width",
+    "fifo_max_table_files_size",
+    "fifo_ttl_ms",
+    "enable_blob_files",
+    "min_blob_size",
+    "blob_garbage_collection_ratio",
+];
+
+fn compression_name(compression: CompressionType) -> &'static str {
+    match compression {
+        CompressionType::None => "none",
+        CompressionType::Lz4 => "lz4",
+        CompressionType::Zstd => "zstd",
+    }
+}
+
+fn parse_compression(value: &str) -> Result<CompressionType, DbError> {
+    match value {
+        "none" => Ok(CompressionType::None),
+        "lz4" => Ok(CompressionType::Lz4),
+        "zstd" => Ok(CompressionType::Zstd),
+        _ => Err(DbError::InvalidArgument("bad compression")),
+    }
+}
+
+fn compaction_style_name(compaction_style: CompactionStyle) -> &'static str {
+    match compaction_style {
+        CompactionStyle::Level => "level",
+        CompactionStyle::Universal => "universal",
+        CompactionStyle::Fifo => "fifo",
+    }
+}
+
+fn compaction_priority_name(compaction_priority: CompactionPriority) -> &'static str {
+    match compaction_priority {
+        CompactionPriority::OldestSmallestSeqFirst => "oldest_smallest_seq_first",
+        CompactionPriority::MinOverlappingRatio => "min_overlapping_ratio",
+        CompactionPriority::OldestLargestSeqFirst => "oldest_largest_seq_first",
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, DbError> {
+    value
+        .parse()
+        .map_err(|_| DbError::InvalidArgument("bad option value"))
+}
+
+/// Parses an optional value, represented by an empty string when missing
+fn parse_optional<T: FromStr>(value: &str) -> Result<Option<T>, DbError> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    parse(value).map(Some)
+}
+
+fn to_string_or_empty<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Returns the prefix extractor of the crate called `name`, if any
+fn builtin_prefix_extractor(name: &str) -> Option<Arc<dyn PrefixExtractor>> {
+    let len = name.strip_prefix("fyodor.FixedPrefix.")?.parse().ok()?;
+
+    Some(Arc::new(FixedPrefix(len)))
+}
+
+/// Returns the merge operator of the crate called `name`, if any
+fn builtin_merge_operator(name: &str) -> Option<Arc<dyn MergeOperator>> {
+    let operators: [Arc<dyn MergeOperator>; 4] = [
+        Arc::new(UInt64Add),
+        Arc::new(Max),
+        Arc::new(Min),
+        Arc::new(SortedSetUnion),
+    ];
+
+    operators
+        .into_iter()
+        .find(|operator| operator.name() == name)
+}
+
+fn options_file_name(db_path: &Path) -> PathBuf {
+    db_path.join("OPTIONS")
+}
+
+/// Replaces the OPTIONS file of the database at `db_path` with `options`, and the options of its
+/// column families
+///
+/// The file is made of sections, `[db]` then `[cf <name>]` for each column family, holding one
+/// `name=value` line per knob. It's written under a temporary name and renamed once complete.
+pub(crate) fn write_options_file<'a, I>(
+    db_path: &Path,
+    options: &Options,
+    column_families: I,
+) -> Result<(), DbError>
+where
+    I: IntoIterator<Item = (&'a str, &'a ColumnFamilyOptions)>,
+{
+    let mut contents = String::from("[db]\n");
+
+    for (name, value) in options.to_pairs() {
+        contents.push_str(&format!("{}={}\n", name, value));
+    }
+
+    for (cf_name, cf_options) in column_families {
+        contents.push_str(&format!("[cf {}]\n", cf_name));
+
+        for (name, value) in cf_options.to_pairs() {
+            contents.push_str(&format!("{}={}\n", name, value));
+        }
+    }
+
+    let path = options_file_name(db_path);
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, &path)?;
+
+    Ok(())
+}
+
+/// Reads the options the database at `db_path` was last opened with, from its OPTIONS file
+///
+/// The options of the default column family become [Options::default_cf_options], the ones of
+/// the others [Options::cf_options]. Custom prefix extractors and merge operators can't be
+/// restored from their names, and are left unset.
+pub fn load_latest_options<P: AsRef<Path>>(db_path: P) -> Result<Options, DbError> {
+    let contents = std::fs::read_to_string(options_file_name(db_path.as_ref()))?;
+
+    let mut options = Options::default();
+    let mut column_family: Option<(String, ColumnFamilyOptions)> = None;
+
+    for line in contents.lines() {
+        if line == "[db]" {
+            continue;
+        }
+
+        if let Some(name) = line
+            .strip_prefix("[cf ")
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            if let Some((name, cf_options)) = column_family.take() {
+                options.cf_options.insert(name, cf_options);
+            }
+
+            column_family = Some((name.to_string(), ColumnFamilyOptions::default()));
+            continue;
+        }
+
+        let (name, value) = line
+            .split_once('=')
+            .ok_or(DbError::Corruption("bad OPTIONS file"))?;
+
+        match &mut column_family {
+            Some((_, cf_options)) => cf_options.set(name, value)?,
+            None => options.set(name, value)?,
+        }
+    }
+
+    if let Some((name, cf_options)) = column_family.take() {
+        options.cf_options.insert(name, cf_options);
+    }
+
+    if let Some(default_cf_options) = options.cf_options.remove(DEFAULT_COLUMN_FAMILY_NAME) {
+        options.default_cf_options = default_cf_options;
+    }
+
+    Ok(options)
+}
+
+/// Options of a single read
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    /// Reads see the database as of this snapshot, instead of as of their start
+    pub snapshot: Option<Snapshot>,
+    /// Reads see the database as it was right after the write with this sequence number, unless
+    /// [ReadOptions::snapshot] is set
+    ///
+    /// Unlike a snapshot, nothing keeps the versions of the keys it sees from being compacted
+    /// away: see [Db::oldest_readable_sequence_number](crate::Db::oldest_readable_sequence_number).
+    /// Sequence numbers after the last write read as of the last write.
+    pub sequence: Option<SequenceNumber>,
+    /// Whether the checksums of the data blocks read from the tables are verified, true by
+    /// default
+    pub verify_checksums: bool,
+    /// Whether the data blocks read are added to [ColumnFamilyOptions::block_cache], and the
+    /// keys looked up to [ColumnFamilyOptions::row_cache], true by default: long scans would
+    /// rather not evict the blocks read often
+    pub fill_cache: bool,
+    /// Iterators read the tables by chunks of at least this many bytes, which speeds up long
+    /// scans; 0 to read a block at a time
+    pub readahead_size: usize,
+    /// Reads of the column families with [ColumnFamilyOptions::user_timestamps] see the versions
+    /// written at or before this timestamp, the most recent ones if None
+    pub timestamp: Option<Timestamp>,
+    /// Iterators don't return keys before this one
+    pub iterate_lower_bound: Option<Vec<u8>>,
+    /// Iterators stop before this key, which is excluded from the range
+    pub iterate_upper_bound: Option<Vec<u8>>,
+    /// Iterators only return the keys with the same prefix as the one they were positioned at,
+    /// according to [ColumnFamilyOptions::prefix_extractor]
+    pub prefix_same_as_start: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            snapshot: None,
+            sequence: None,
+            verify_checksums: true,
+            fill_cache: true,
+            timestamp: None,
+            readahead_size: 0,
+            iterate_lower_bound: None,
+            iterate_upper_bound: None,
+            prefix_same_as_start: false,
+        }
+    }
+}
+
+/// Options of a single write
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    /// Syncs the write-ahead log before the write returns, whatever [Options::wal_sync_policy]
+    pub sync: bool,
+    /// Skips the write-ahead log: the write is lost on a crash unless its memtable was flushed
+    /// before
+    ///
+    /// Ignored if [WriteOptions::sync] is set.
+    pub disable_wal: bool,
+    /// Fails the write right away instead of waiting while writes are stalled
+    pub no_slowdown: bool,
+}
+
+/// Options of a [Transaction](crate::Transaction)
+#[derive(Clone, Debug)]
+pub struct TransactionOptions {
+    /// What the reads of the transaction see, and which concurrent writes fail its commit
+    pub isolation: IsolationLevel,
+    /// Durability of the commit
+    pub write_options: WriteOptions,
+    /// Locks the keys the transaction writes or reads for update as it goes, until it commits
+    /// or rolls back: the concurrent transactions touching them wait for it instead of failing
+    /// at commit
+    pub pessimistic: bool,
+    /// How long a pessimistic transaction waits for the lock of a key before failing with
+    /// [DbError::LockTimeout], forever if None; one second by default
+    pub lock_timeout: Option<Duration>,
+    /// Fails a pessimistic transaction with [DbError::Deadlock] rather than wait for a lock
+    /// held by a transaction waiting for it, directly or not, true by default
+    pub deadlock_detect: bool,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> TransactionOptions {
+        TransactionOptions {
+            isolation: IsolationLevel::default(),
+            write_options: WriteOptions::default(),
+            pessimistic: false,
+            lock_timeout: Some(Duration::from_secs(1)),
+            deadlock_detect: true,
+        }
+    }
+}
+
+/// Options of a [Db::compact_range](crate::Db::compact_range)
+#[derive(Clone, Debug)]
+pub struct CompactRangeOptions {
+    /// Flushes the memtables first, so that their writes are compacted as well, true by default
+    pub flush: bool,
+    /// Also rewrites the tables of the range already in the bottom level, which drops the
+    /// deletions and the expired entries they hold
+    pub bottommost_level_compaction: bool,
+}
+
+impl Default for CompactRangeOptions {
+    fn default() -> CompactRangeOptions {
+        CompactRangeOptions {
+            flush: true,
+            bottommost_level_compaction: false,
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
+
+/// Counters and timings of the reads done by the current thread since the last [reset]
+///
+/// Nothing is recorded until [enable] is called on the thread, so that reads don't pay for the
+/// bookkeeping when nobody looks at it. Typical use is to reset the context before a slow read,
+/// then look at where the time went with [get].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PerfContext {
+    /// Number of point lookups, with [Db::get](crate::db::Db::get) and alike
+    pub get_count: u64,
+    /// Time spent in point lookups, from start to finish
+    pub get_time: Duration,
+    /// Time spent looking up keys in memtables during point lookups
+    pub get_from_memtable_time: Duration,
+    /// Number of seeks of iterators
+    pub seek_count: u64,
+    /// Time spent in seeks of iterators, from start to finish
+    pub seek_time: Duration,
+    /// Number of data blocks read from table files
+    pub block_read_count: u64,
+    /// Number of data blocks found in the block cache, saving a read
+    pub block_cache_hit_count: u64,
+    /// Number of the data blocks found in the block cache which came back from its secondary
+    /// cache
+    pub secondary_cache_hit_count: u64,
+    /// Number of point lookups in tables answered by the row cache, without reading any block
+    pub row_cache_hit_count: u64,
+    /// Bytes read from table files for data blocks, including their trailers and the bytes read
+    /// ahead
+    pub block_read_bytes: u64,
+    /// Time spent reading data blocks from table files
+    pub block_read_time: Duration,
+    /// Bytes of the compressed data blocks once decompressed
+    pub bytes_decompressed: u64,
+    /// Time spent decompressing data blocks
+    pub decompress_time: Duration,
+    /// Number of checks of the filters of the tables
+    pub filter_check_count: u64,
+    /// Number of checks of the filters which ruled a table out, saving a read
+    pub filter_useful_count: u64,
+    /// Time spent checking the filters of the tables
+    pub filter_check_time: Duration,
+}
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static CONTEXT: RefCell<PerfContext> = RefCell::new(PerfContext::default());
+}
+
+/// Starts recording the reads of the current thread
+pub fn enable() {
+    ENABLED.set(true);
+}
+
+/// Stops recording the reads of the current thread, keeping what was recorded so far
+pub fn disable() {
+    ENABLED.set(false);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.get()
+}
+
+/// Zeroes every counter and timing of the current thread
+pub fn reset() {
+    CONTEXT.with_borrow_mut(|ctx| *ctx = PerfContext::default());
+}
+
+/// Returns what was recorded on the current thread since the last [reset]
+pub fn get() -> PerfContext {
+    CONTEXT.with_borrow(PerfContext::clone)
+}
+
+/// Updates the context of the current thread with `f`, if recording is enabled
+pub(crate) fn record<F: FnOnce(&mut PerfContext)>(f: F) {
+    if is_enabled() {
+        CONTEXT.with_borrow_mut(f);
+    }
+}
+
+/// Returns a guard which measures the time until it's dropped, then records it with `f`, if
+/// recording is enabled
+pub(crate) fn timer<F: FnOnce(&mut PerfContext, Duration)>(f: F) -> Timer<F> {
+    Timer {
+        start: is_enabled().then(Instant::now),
+        record: Some(f),
+    }
+}
+
+/// See [timer]
+pub(crate) struct Timer<F: FnOnce(&mut PerfContext, Duration)> {
+    start: Option<Instant>,
+    record: Option<F>,
+}
+
+impl<F: FnOnce(&mut PerfContext, Duration)> Drop for Timer<F> {
+    fn drop(&mut self) {
+        if let (Some(start), Some(record)) = (self.start, self.record.take()) {
+            let elapsed = start.elapsed();
+
+            CONTEXT.with_borrow_mut(|ctx| record(ctx, elapsed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::options::{ColumnFamilyOptions, CompressionType, Options};
+    use crate::perf_context::{self, PerfContext};
+
+    #[test]
+    fn records_the_reads_of_the_thread_once_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(
+            dir.path(),
+            Options::default().with_default_cf_options(
+                ColumnFamilyOptions::default().with_compression(CompressionType::Lz4),
+            ),
+        )
+        .unwrap();
+
+        for n in 0..100_u32 {
+            db.put(&n.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        db.flush().unwrap();
+
+        db.get(&0_u32.to_be_bytes()).unwrap();
+        assert_eq!(perf_context::get(), PerfContext::default());
+
+        perf_context::enable();
+        db.get(&0_u32.to_be_bytes()).unwrap();
+        db.get(b"missing").unwrap();
+        db.iter().seek(&50_u32.to_be_bytes()).unwrap();
+
+        let ctx = perf_context::get();
+        assert_eq!(ctx.get_count, 2);
+        assert_eq!(ctx.seek_count, 1);
+        assert_eq!(ctx.filter_check_count, 2);
+        assert_eq!(ctx.filter_useful_count, 1);
+        assert_eq!(ctx.block_read_count, 2);
+        assert!(ctx.bytes_decompressed > ctx.block_read_bytes);
+        assert!(ctx.get_time >= ctx.get_from_memtable_time);
+
+        perf_context::reset();
+        perf_context::disable();
+        db.get(&0_u32.to_be_bytes()).unwrap();
+        assert_eq!(perf_context::get(), PerfContext::default());
+    }
+}
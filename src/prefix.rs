@@ -0,0 +1,41 @@
+use std::fmt::Debug;
+
+/// Extracts the prefix of a key, grouping keys for prefix seeks and prefix bloom filters (e.g.
+/// one prefix per tenant)
+///
+/// The keys sharing a prefix must be contiguous in key order, which is the case as long as the
+/// prefix is a leading part of the key.
+pub trait PrefixExtractor: Debug + Send + Sync {
+    /// Identifies the extractor: the prefixes stored in the filters of a table are only used if
+    /// the table was built with an extractor with the same name
+    fn name(&self) -> String;
+
+    /// Returns true if `key` has a prefix
+    fn in_domain(&self, key: &[u8]) -> bool;
+
+    /// Returns the prefix of `key`, which must be in the domain
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8];
+
+    /// Returns the prefix of `key`, if it has one
+    fn prefix<'a>(&self, key: &'a [u8]) -> Option<&'a [u8]> {
+        self.in_domain(key).then(|| self.transform(key))
+    }
+}
+
+/// Uses the first `n` bytes of every key as prefix, shorter keys have no prefix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedPrefix(pub usize);
+
+impl PrefixExtractor for FixedPrefix {
+    fn name(&self) -> String {
+        format!("fyodor.FixedPrefix.{}", self.0)
+    }
+
+    fn in_domain(&self, key: &[u8]) -> bool {
+        key.len() >= self.0
+    }
+
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[..self.0]
+    }
+}
@@ -0,0 +1,147 @@
+use crate::key::SequenceNumber;
+
+/// Deletes every user key in [start, end) written before `seq`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seq: SequenceNumber,
+}
+
+impl RangeTombstone {
+    /// Returns true if `user_key` is in the range of the tombstone
+    pub fn covers(&self, user_key: &[u8]) -> bool {
+        self.start.as_slice() <= user_key && user_key < self.end.as_slice()
+    }
+}
+
+/// A piece of the key space covered by the same set of tombstones
+#[derive(Debug)]
+struct Fragment {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    /// Sequence numbers of the covering tombstones, newest first
+    seqs: Vec<SequenceNumber>,
+}
+
+/// A set of range tombstones split into non-overlapping fragments, so that the tombstones
+/// covering a key can be found with a binary search
+#[derive(Debug, Default)]
+pub struct FragmentedRangeTombstones {
+    /// Sorted by start key
+    fragments: Vec<Fragment>,
+}
+
+impl FragmentedRangeTombstones {
+    pub fn new<'a, I>(tombstones: I) -> FragmentedRangeTombstones
+    where
+        I: IntoIterator<Item = &'a RangeTombstone>,
+    {
+        let mut tombstones: Vec<_> = tombstones
+            .into_iter()
+            .filter(|tombstone| tombstone.start < tombstone.end)
+            .collect();
+        tombstones.sort_by(|a, b| a.start.cmp(&b.start));
+
+        let mut boundaries: Vec<&[u8]> = tombstones
+            .iter()
+            .flat_map(|tombstone| [tombstone.start.as_slice(), tombstone.end.as_slice()])
+            .collect();
+        boundaries.sort();
+        boundaries.dedup();
+
+        let mut fragments = Vec::new();
+        let mut active: Vec<&RangeTombstone> = Vec::new();
+        let mut next_tombstone = 0;
+
+        // Sweeps the boundaries, keeping track of the tombstones covering each of the pieces
+        for pair in boundaries.windows(2) {
+            let (start, end) = (pair[0], pair[1]);
+
+            active.retain(|tombstone| tombstone.end.as_slice() > start);
+
+            while next_tombstone < tombstones.len()
+                && tombstones[next_tombstone].start.as_slice() == start
+            {
+                active.push(tombstones[next_tombstone]);
+                next_tombstone += 1;
+            }
+
+            if active.is_empty() {
+                continue;
+            }
+
+            let mut seqs: Vec<_> = active.iter().map(|tombstone| tombstone.seq).collect();
+            seqs.sort_unstable_by(|a, b| b.cmp(a));
+            seqs.dedup();
+
+            fragments.push(Fragment {
+                start: start.to_vec(),
+                end: end.to_vec(),
+                seqs,
+            });
+        }
+
+        FragmentedRangeTombstones { fragments }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Returns the sequence number of the newest tombstone visible at `seq` which covers
+    /// `user_key`, or 0 if there is none
+    ///
+    /// The versions of the key older than the returned sequence number are deleted.
+    pub fn max_covering_seq(&self, user_key: &[u8], seq: SequenceNumber) -> SequenceNumber {
+        let index = self
+            .fragments
+            .partition_point(|fragment| fragment.start.as_slice() <= user_key);
+
+        let fragment = match index.checked_sub(1).map(|index| &self.fragments[index]) {
+            Some(fragment) if user_key < fragment.end.as_slice() => fragment,
+            _ => return 0,
+        };
+
+        fragment
+            .seqs
+            .iter()
+            .copied()
+            .find(|tombstone_seq| *tombstone_seq <= seq)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::range_del::{FragmentedRangeTombstones, RangeTombstone};
+
+    fn tombstone(start: &[u8], end: &[u8], seq: u64) -> RangeTombstone {
+        RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        }
+    }
+
+    #[test]
+    fn overlapping_tombstones_are_fragmented() {
+        let tombstones = [
+            tombstone(b"b", b"f", 10),
+            tombstone(b"d", b"h", 20),
+            tombstone(b"m", b"p", 5),
+            tombstone(b"z", b"a", 50),
+        ];
+        let fragmented = FragmentedRangeTombstones::new(&tombstones);
+
+        assert_eq!(fragmented.max_covering_seq(b"a", 100), 0);
+        assert_eq!(fragmented.max_covering_seq(b"c", 100), 10);
+        assert_eq!(fragmented.max_covering_seq(b"e", 100), 20);
+        assert_eq!(fragmented.max_covering_seq(b"e", 15), 10);
+        assert_eq!(fragmented.max_covering_seq(b"e", 9), 0);
+        assert_eq!(fragmented.max_covering_seq(b"f", 100), 20);
+        assert_eq!(fragmented.max_covering_seq(b"h", 100), 0);
+        assert_eq!(fragmented.max_covering_seq(b"n", 100), 5);
+        assert_eq!(fragmented.max_covering_seq(b"p", 100), 0);
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Caps the bytes per second written by the flushes and the compactions of every database it's
+/// shared with, see [Options::with_rate_limiter](crate::Options::with_rate_limiter), so that the
+/// background work doesn't saturate the disk and slow down the reads and writes
+///
+/// The limiter is a token bucket refilled at the rate, holding at most a tenth of a second of
+/// tokens. Requests larger than what's left borrow from the next refills, and wait for them.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    limit_reads: bool,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_second: u64,
+    /// Negative when the requests borrowed from the next refills
+    available: i64,
+    last_refill: Instant,
+    /// Bytes requested so far
+    total_bytes: u64,
+}
+
+impl Bucket {
+    fn capacity(&self) -> i64 {
+        (self.bytes_per_second / 10).max(1) as i64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill);
+        let tokens = (elapsed.as_secs_f64() * self.bytes_per_second as f64) as i64;
+
+        self.available = (self.available + tokens).min(self.capacity());
+        self.last_refill = now;
+    }
+}
+
+impl RateLimiter {
+    /// Returns a limiter letting through `bytes_per_second` bytes of background writes, at least
+    /// one
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        let bytes_per_second = bytes_per_second.max(1);
+
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                bytes_per_second,
+                available: (bytes_per_second / 10).max(1) as i64,
+                last_refill: Instant::now(),
+                total_bytes: 0,
+            }),
+            limit_reads: false,
+        }
+    }
+
+    /// Charges the compactions for their reads as well as their writes
+    pub fn with_limit_reads(mut self, limit_reads: bool) -> Self {
+        self.limit_reads = limit_reads;
+        self
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_second
+    }
+
+    pub fn limits_reads(&self) -> bool {
+        self.limit_reads
+    }
+
+    /// Returns the number of bytes which went through the limiter
+    pub fn total_bytes_through(&self) -> u64 {
+        self.bucket.lock().unwrap().total_bytes
+    }
+
+    /// Changes the rate, taking effect on the next requests
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        // The tokens accumulated so far are counted at the old rate
+        bucket.refill(Instant::now());
+        bucket.bytes_per_second = bytes_per_second.max(1);
+        bucket.available = bucket.available.min(bucket.capacity());
+    }
+
+    /// Waits until `bytes` more bytes can go through
+    pub fn request(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.refill(Instant::now());
+            bucket.available -= bytes as i64;
+            bucket.total_bytes += bytes;
+
+            if bucket.available >= 0 {
+                return;
+            }
+
+            Duration::from_secs_f64(-bucket.available as f64 / bucket.bytes_per_second as f64)
+        };
+
+        std::thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limiter::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn requests_wait_for_the_rate() {
+        let limiter = RateLimiter::new(1_000_000);
+        let start = Instant::now();
+
+        // A tenth of a second is in the bucket from the start
+        for _ in 0..30 {
+            limiter.request(10_000);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        assert_eq!(limiter.total_bytes_through(), 300_000);
+
+        limiter.set_bytes_per_second(100_000_000);
+        assert_eq!(limiter.bytes_per_second(), 100_000_000);
+
+        let start = Instant::now();
+        limiter.request(1_000_000);
+        assert!(start.elapsed() < Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,551 @@
+use crate::blob::{self, BlobFiles};
+use crate::column_family::{DEFAULT_COLUMN_FAMILY_ID, DEFAULT_COLUMN_FAMILY_NAME};
+use crate::compaction::{self, Compaction, OutputFiles};
+use crate::db::{self, DbError};
+use crate::key::{self, SequenceNumber};
+use crate::options::{CompactionStyle, Options};
+use crate::table::{self, Table};
+use crate::version::{self, BlobFileMetaData, VersionEdit, VersionSet, NUM_LEVELS};
+use crate::wal;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// What [repair] found and did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of the tables whose entries made it into the repaired database
+    pub tables: usize,
+    /// Number of the data blocks which couldn't be read, whose entries are lost
+    pub lost_blocks: usize,
+    /// The files moved to the `lost` directory of the database, where they are now
+    pub moved_aside: Vec<PathBuf>,
+}
+
+/// What could be read from the manifests, the column families being the live ones by id
+#[derive(Default)]
+struct Salvaged {
+    log_number: Option<u64>,
+    last_sequence: SequenceNumber,
+    next_column_family_id: u32,
+    column_families: BTreeMap<u32, String>,
+    dropped_column_families: HashSet<u32>,
+    full_history_ts_low: BTreeMap<u32, u64>,
+    column_family_log_numbers: BTreeMap<u32, u64>,
+    snapshots: BTreeMap<String, SequenceNumber>,
+}
+
+impl Salvaged {
+    fn apply(&mut self, edit: &VersionEdit) {
+        if let Some(log_number) = edit.log_number {
+            self.log_number = Some(log_number);
+        }
+
+        self.last_sequence = self.last_sequence.max(edit.last_sequence.unwrap_or(0));
+        self.next_column_family_id = self
+            .next_column_family_id
+            .max(edit.next_column_family_id.unwrap_or(0));
+
+        for (id, name) in &edit.added_column_families {
+            self.column_families.insert(*id, name.clone());
+        }
+
+        for id in &edit.dropped_column_families {
+            self.column_families.remove(id);
+            self.dropped_column_families.insert(*id);
+        }
+
+        for (id, ts) in &edit.full_history_ts_low {
+            self.full_history_ts_low.insert(*id, *ts);
+        }
+
+        for (id, log_number) in &edit.column_family_log_numbers {
+            self.column_family_log_numbers.insert(*id, *log_number);
+        }
+
+        for (name, seq) in &edit.added_snapshots {
+            self.snapshots.insert(name.clone(), *seq);
+        }
+
+        for name in &edit.released_snapshots {
+            self.snapshots.remove(name);
+        }
+    }
+}
+
+/// Rebuilds the manifest of the database at `path`, opened with `options`, from the table files
+/// it holds, e.g. once the manifest was lost or corrupted
+///
+/// The column families, the persistent snapshots and the oldest live logs are taken from the
+/// newest manifest which can be read at all, up to its first bad record. The tables of a column
+/// family it doesn't name get one called `recovered_<id>`, rather than being thrown away.
+///
+/// The tables which can't be opened, and the ones of the dropped column families, are moved to
+/// the `lost` directory of the database, along with the old manifests and the blob files no table
+/// points to. So are the tables with data blocks which can't be read, once the entries of their
+/// other blocks are written to a new table. Since the levels of the tables are lost, the tables
+/// of each column family are then merged into the last level, or level 0 with
+/// [CompactionStyle::Fifo], without dropping any version of the keys. The writes still in the
+/// logs are replayed by the next [Db::open](crate::Db::open).
+pub fn repair<P: AsRef<Path>>(path: P, options: &Options) -> Result<RepairReport, DbError> {
+    let path = path.as_ref();
+    let wal_dir = options.wal_dir(path);
+    let lost_dir = path.join("lost");
+    let _lock = db::lock_db(path)?;
+
+    let mut salvaged = salvage_manifest(path)?;
+
+    salvaged
+        .column_families
+        .entry(DEFAULT_COLUMN_FAMILY_ID)
+        .or_insert_with(|| DEFAULT_COLUMN_FAMILY_NAME.to_string());
+
+    // Nothing is allocated below the numbers of the files around, logs and archived ones included
+    let mut max_number = 0;
+    let archive_dir = wal_dir.join("archive");
+
+    for dir in [path, wal_dir, &archive_dir] {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries {
+            let name = entry?.file_name();
+            let number = name.to_str().and_then(|name| {
+                table::parse_table_file_name(name)
+                    .or_else(|| blob::parse_blob_file_name(name))
+                    .or_else(|| version::parse_manifest_file_name(name))
+                    .or_else(|| wal::parse_log_file_name(name))
+                    .or_else(|| wal::parse_recyclable_log_file_name(name))
+            });
+
+            max_number = max_number.max(number.unwrap_or(0));
+        }
+    }
+
+    let next_file_number = AtomicU64::new(max_number + 1);
+    let new_file_number = || next_file_number.fetch_add(1, Ordering::Relaxed);
+
+    let mut report = RepairReport::default();
+    let mut aside = Vec::new();
+    let mut inputs: BTreeMap<u32, Vec<Arc<Table>>> = BTreeMap::new();
+    let mut blob_files = Vec::new();
+    let mut old_manifests = Vec::new();
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        if let Some(number) = blob::parse_blob_file_name(name) {
+            blob_files.push(number);
+        }
+
+        if version::parse_manifest_file_name(name).is_some() {
+            old_manifests.push(entry.path());
+        }
+
+        let number = match table::parse_table_file_name(name) {
+            Some(number) => number,
+            None => continue,
+        };
+
+        let table = match Table::open(&entry.path(), number) {
+            Ok(table) => table,
+            Err(e) => {
+                log::warn!("moving aside table {}: {}", entry.path().display(), e);
+                aside.push(entry.path());
+                continue;
+            }
+        };
+
+        let column_family_id = table.properties().column_family_id as u32;
+
+        if salvaged.dropped_column_families.contains(&column_family_id) {
+            aside.push(entry.path());
+            continue;
+        }
+
+        let name = salvaged
+            .column_families
+            .entry(column_family_id)
+            .or_insert_with(|| format!("recovered_{}", column_family_id));
+        let cf_options = options.column_family_options(name);
+
+        let dump = match table.dump() {
+            Ok(dump) => dump,
+            Err(e) => {
+                log::warn!("moving aside table {}: {}", entry.path().display(), e);
+                aside.push(entry.path());
+                continue;
+            }
+        };
+
+        let lost_blocks = dump
+            .blocks
+            .iter()
+            .filter(|block| block.error.is_some())
+            .count();
+
+        let table = if lost_blocks == 0 {
+            Arc::new(table)
+        } else {
+            log::warn!(
+                "salvaging table {}, {} data blocks of which can't be read",
+                entry.path().display(),
+                lost_blocks
+            );
+            report.lost_blocks += lost_blocks;
+            aside.push(entry.path());
+
+            db::write_table(
+                path,
+                cf_options,
+                0,
+                cf_options.output_compression(0, false),
+                None,
+                new_file_number(),
+                column_family_id,
+                |builder| {
+                    for block in &dump.blocks {
+                        for entry in &block.entries {
+                            let key =
+                                key::encode(&entry.user_key, entry.sequence, entry.value_type);
+                            builder.add(&key, &entry.value)?;
+                        }
+                    }
+
+                    for tombstone in &dump.range_tombstones {
+                        builder.add_range_tombstone(
+                            &tombstone.start,
+                            &tombstone.end,
+                            tombstone.seq,
+                        );
+                    }
+
+                    Ok(())
+                },
+            )?
+        };
+
+        report.tables += 1;
+        salvaged.last_sequence = salvaged.last_sequence.max(table.properties().largest_seqno);
+        inputs.entry(column_family_id).or_default().push(table);
+    }
+
+    salvaged.next_column_family_id = salvaged
+        .next_column_family_id
+        .max(salvaged.column_families.keys().max().map_or(0, |id| id + 1));
+
+    let mut edit = VersionEdit {
+        log_number: Some(salvaged.log_number.unwrap_or(0)),
+        last_sequence: Some(salvaged.last_sequence),
+        next_column_family_id: Some(salvaged.next_column_family_id),
+        added_column_families: salvaged
+            .column_families
+            .iter()
+            .map(|(id, name)| (*id, name.clone()))
+            .collect(),
+        full_history_ts_low: salvaged
+            .full_history_ts_low
+            .iter()
+            .filter(|(id, _)| salvaged.column_families.contains_key(id))
+            .map(|(id, ts)| (*id, *ts))
+            .collect(),
+        column_family_log_numbers: salvaged
+            .column_family_log_numbers
+            .iter()
+            .filter(|(id, _)| salvaged.column_families.contains_key(id))
+            .map(|(id, log_number)| (*id, *log_number))
+            .collect(),
+        added_snapshots: salvaged.snapshots.into_iter().collect(),
+        ..VersionEdit::default()
+    };
+
+    let blob_reader = BlobFiles::new(path);
+    let mut referenced_blob_files = HashSet::new();
+
+    for (column_family_id, tables) in &inputs {
+        let cf_options = options.column_family_options(&salvaged.column_families[column_family_id]);
+        let output_level = match cf_options.compaction_style {
+            CompactionStyle::Fifo => 0,
+            _ => NUM_LEVELS - 1,
+        };
+        let compaction = Compaction {
+            column_family_id: *column_family_id,
+            inputs: tables
+                .iter()
+                .map(|table| (0, Arc::new(db::file_meta_data(table))))
+                .collect(),
+            output_level,
+            bottommost: false,
+            delete_inputs: false,
+            boundaries: Vec::new(),
+            output_numbers: vec![new_file_number()],
+            grandparents: Vec::new(),
+            rewritten_blob_files: HashSet::new(),
+        };
+
+        // No snapshot is older than the first write, so that every version is kept
+        let (outputs, _) = compaction::run(
+            path,
+            cf_options,
+            &compaction,
+            tables,
+            0,
+            None,
+            &OutputFiles {
+                new_file_number: &new_file_number,
+                rate_limiter: None,
+                blob_files: &blob_reader,
+            },
+        )?;
+
+        let mut blob_references: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+
+        for output in &outputs {
+            edit.add_file(*column_family_id, output_level, db::file_meta_data(output));
+
+            for (number, (count, bytes)) in &output.properties().blob_references {
+                let references = blob_references.entry(*number).or_default();
+                references.0 += count;
+                references.1 += bytes;
+            }
+        }
+
+        for (number, (blob_count, blob_bytes)) in blob_references {
+            let file_size = match std::fs::metadata(blob::blob_file_name(path, number)) {
+                Ok(metadata) => metadata.len(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    log::warn!("blob file {} referenced by the tables is missing", number);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            referenced_blob_files.insert(number);
+            edit.new_blob_files.push((
+                *column_family_id,
+                BlobFileMetaData {
+                    number,
+                    blob_count,
+                    blob_bytes,
+                    file_size,
+                    garbage_count: 0,
+                    garbage_bytes: 0,
+                },
+            ));
+        }
+    }
+
+    for number in blob_files {
+        if !referenced_blob_files.contains(&number) {
+            aside.push(blob::blob_file_name(path, number));
+        }
+    }
+
+    edit.next_file_number = Some(next_file_number.load(Ordering::Relaxed));
+
+    let versions = VersionSet::create(path, &edit)?;
+
+    drop(versions);
+    aside.extend(old_manifests);
+
+    if !aside.is_empty() {
+        std::fs::create_dir_all(&lost_dir)?;
+    }
+
+    for file in aside {
+        let to = lost_dir.join(file.file_name().expect("files have a name"));
+        std::fs::rename(&file, &to)?;
+        report.moved_aside.push(to);
+    }
+
+    // The merged tables, salvaged ones included, are all in the outputs now
+    for tables in inputs.values() {
+        for table in tables {
+            let table_path = table::table_file_name(path, table.number());
+
+            match std::fs::remove_file(&table_path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+    }
+
+    db::sync_dir(path)?;
+    log::info!(
+        "repaired {} from {} tables, moving aside {} files",
+        path.display(),
+        report.tables,
+        report.moved_aside.len()
+    );
+
+    Ok(report)
+}
+
+/// Reads what it can of the newest manifest of the database at `path` which can be read at all,
+/// up to its first bad record
+fn salvage_manifest(path: &Path) -> Result<Salvaged, DbError> {
+    let mut manifests = Vec::new();
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+
+        if let Some(number) = entry
+            .file_name()
+            .to_str()
+            .and_then(version::parse_manifest_file_name)
+        {
+            manifests.push((number, entry.path()));
+        }
+    }
+
+    manifests.sort_unstable_by(|a, b| b.cmp(a));
+
+    for (number, manifest) in manifests {
+        let mut reader = match wal::Reader::open(&manifest, number) {
+            Ok(reader) => reader,
+            Err(e) => {
+                log::warn!("skipping manifest {}: {}", manifest.display(), e);
+                continue;
+            }
+        };
+        let mut salvaged = Salvaged::default();
+        let mut records = 0;
+
+        loop {
+            let edit = match reader.read_record() {
+                Ok(Some(record)) => VersionEdit::decode(&record).map_err(DbError::from),
+                Ok(None) => break,
+                Err(e) => Err(e.into()),
+            };
+
+            match edit {
+                Ok(edit) => {
+                    salvaged.apply(&edit);
+                    records += 1;
+                }
+                Err(e) => {
+                    log::warn!(
+                        "manifest {} is cut at a bad record: {}",
+                        manifest.display(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+
+        if records > 0 {
+            return Ok(salvaged);
+        }
+    }
+
+    Ok(Salvaged::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::{ColumnFamilyOptions, Options};
+    use crate::repair::repair;
+    use crate::table::{self, Table};
+    use crate::version;
+    use crate::Db;
+    use std::os::unix::fs::FileExt;
+
+    fn key(n: u32) -> Vec<u8> {
+        format!("key{:04}", n).into_bytes()
+    }
+
+    #[test]
+    fn repairs_rebuild_the_manifest_from_the_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = Options::default()
+            .with_default_cf_options(ColumnFamilyOptions::default().with_block_size(256));
+
+        {
+            let db = Db::open(dir.path(), options.clone()).unwrap();
+            let other = db.create_cf("other").unwrap();
+
+            for n in 0..100 {
+                db.put(&key(n), b"old").unwrap();
+            }
+            db.flush().unwrap();
+
+            db.put(&key(99), b"new").unwrap();
+            db.delete(&key(98)).unwrap();
+            db.put_cf(&other, b"other", b"value").unwrap();
+            db.flush().unwrap();
+
+            db.put(b"unflushed", b"value").unwrap();
+        }
+
+        let mut tables = Vec::new();
+
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+
+            if version::parse_manifest_file_name(&name).is_some() {
+                std::fs::remove_file(entry.path()).unwrap();
+            }
+
+            if let Some(number) = table::parse_table_file_name(&name) {
+                tables.push((number, entry.path()));
+            }
+        }
+
+        std::fs::remove_file(dir.path().join("CURRENT")).unwrap();
+        tables.sort();
+
+        // The first block of the oldest table can't be read anymore
+        let (number, oldest) = &tables[0];
+        let dump = Table::open(oldest, *number).unwrap().dump().unwrap();
+        assert!(dump.blocks.len() > 1);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(oldest)
+            .unwrap();
+        file.write_all_at(b"garbage", dump.blocks[0].handle.offset)
+            .unwrap();
+
+        let lost_keys = dump.blocks[0].entries.len() as u32;
+        std::fs::write(dir.path().join("999999.sst"), b"not a table").unwrap();
+
+        let report = repair(dir.path(), &options).unwrap();
+        assert_eq!(report.tables, tables.len());
+        assert_eq!(report.lost_blocks, 1);
+        let mut moved_aside = report.moved_aside.clone();
+        moved_aside.sort();
+        assert_eq!(
+            moved_aside,
+            vec![
+                dir.path().join("lost").join(oldest.file_name().unwrap()),
+                dir.path().join("lost").join("999999.sst"),
+            ]
+        );
+        assert!(moved_aside.iter().all(|path| path.exists()));
+
+        let db = Db::open(dir.path(), options).unwrap();
+        assert_eq!(db.get(&key(0)).unwrap(), None);
+        assert_eq!(db.get(&key(lost_keys - 1)).unwrap(), None);
+        assert_eq!(db.get(&key(lost_keys)).unwrap(), Some(b"old".to_vec()));
+        assert_eq!(db.get(&key(98)).unwrap(), None);
+        assert_eq!(db.get(&key(99)).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+
+        // Without a manifest, the column family is only known by its id
+        let other = db.cf_handle("recovered_1").unwrap();
+        assert_eq!(
+            db.get_cf(&other, b"other").unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::batch::WriteBatch;
+use crate::db::{Db, DbError};
+use crate::key::SequenceNumber;
+use crate::options::Options;
+use crate::wal::WalError;
+use std::path::Path;
+
+/// A copy of a primary [Db] kept up to date by applying the write batches of its logs, see
+/// [Db::get_updates_since] and [Db::apply_replicated_batch]
+///
+/// The replica starts as a [Db::checkpoint] of the primary, whose last sequence number is the one
+/// of the last batch in the logs it copied: the batches are then read from the next one on, so
+/// that none is missed or applied twice. The primary must archive its logs for long enough, see
+/// [Options::wal_retention], or the replica falls behind for good and has to be created again.
+/// The column families created on the primary after the replica aren't replicated, and neither
+/// are the writes skipping the log.
+pub struct Replica {
+    db: Db,
+}
+
+impl Replica {
+    /// Creates at `dir`, which must not exist, a replica of `primary` as of now, opened with
+    /// `options`
+    pub fn create<P: AsRef<Path>>(
+        primary: &Db,
+        dir: P,
+        options: Options,
+    ) -> Result<Replica, DbError> {
+        primary.checkpoint(&dir)?;
+
+        Replica::open(dir, options)
+    }
+
+    /// Opens the replica created at `dir` by [Replica::create], as of the last batch it applied
+    pub fn open<P: AsRef<Path>>(dir: P, options: Options) -> Result<Replica, DbError> {
+        Ok(Replica {
+            db: Db::open(dir, options)?,
+        })
+    }
+
+    /// Applies the batches written to `primary` since the last one applied, and returns how
+    /// many were
+    pub fn catch_up(&self, primary: &Db) -> Result<usize, DbError> {
+        self.apply_updates(primary.get_updates_since(self.next_sequence())?)
+    }
+
+    /// Applies `updates`, the batches of the primary from [Replica::next_sequence] on, e.g.
+    /// shipped from the [Db::get_updates_since] of a primary in another process, and returns
+    /// how many were applied
+    pub fn apply_updates<I>(&self, updates: I) -> Result<usize, DbError>
+    where
+        I: IntoIterator<Item = Result<WriteBatch, WalError>>,
+    {
+        let mut applied = 0;
+
+        for batch in updates {
+            let batch = batch?;
+            self.db.apply_replicated_batch(batch.sequence(), batch)?;
+            applied += 1;
+        }
+
+        Ok(applied)
+    }
+
+    /// Returns the sequence number of the first write the replica misses
+    pub fn next_sequence(&self) -> SequenceNumber {
+        self.db.latest_sequence_number() + 1
+    }
+
+    /// Returns the database of the replica, to be read
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Stops replicating, e.g. to take over from a failed primary, and returns the database of
+    /// the replica
+    pub fn into_db(self) -> Db {
+        self.db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Db, DbError};
+    use crate::options::Options;
+    use crate::replication::Replica;
+    use crate::wal::{RetentionPolicy, WalError};
+    use std::time::Duration;
+
+    #[test]
+    fn replicas_catch_up_from_their_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let replica_dir = dir.path().join("replica");
+        let retention = RetentionPolicy {
+            ttl: Some(Duration::from_secs(3600)),
+            size_limit: None,
+        };
+        let primary = Db::open(
+            dir.path().join("primary"),
+            Options::default().with_wal_retention(retention),
+        )
+        .unwrap();
+
+        for n in 0..100_u32 {
+            primary.put(&n.to_be_bytes(), b"old").unwrap();
+        }
+        primary.flush().unwrap();
+        primary.put(b"unflushed", b"value").unwrap();
+
+        {
+            let replica = Replica::create(&primary, &replica_dir, Options::default()).unwrap();
+            assert_eq!(replica.next_sequence(), 102);
+            assert_eq!(replica.catch_up(&primary).unwrap(), 0);
+
+            for n in 0..10_u32 {
+                primary.put(&n.to_be_bytes(), b"new").unwrap();
+            }
+            primary.flush().unwrap();
+            primary.delete(b"unflushed").unwrap();
+
+            assert_eq!(replica.catch_up(&primary).unwrap(), 11);
+            assert_eq!(
+                replica.db().get(&1_u32.to_be_bytes()).unwrap(),
+                Some(b"new".to_vec())
+            );
+            assert_eq!(replica.db().get(b"unflushed").unwrap(), None);
+        }
+
+        primary.put(b"later", b"value").unwrap();
+
+        // Reopened, the replica goes on from the last batch it applied
+        let replica = Replica::open(&replica_dir, Options::default()).unwrap();
+        assert_eq!(replica.catch_up(&primary).unwrap(), 1);
+        assert_eq!(replica.db().get(b"later").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(
+            replica.db().latest_sequence_number(),
+            primary.latest_sequence_number()
+        );
+        assert!(matches!(
+            replica.apply_updates(primary.get_updates_since(1_000).unwrap()),
+            Ok(0)
+        ));
+    }
+
+    #[test]
+    fn replicas_fall_behind_once_the_logs_are_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let primary = Db::open(dir.path().join("primary"), Options::default()).unwrap();
+        let replica =
+            Replica::create(&primary, dir.path().join("replica"), Options::default()).unwrap();
+
+        // Without retention, the flush deletes the log of the first write
+        primary.put(b"key", b"first").unwrap();
+        primary.flush().unwrap();
+        primary.put(b"key", b"second").unwrap();
+
+        assert!(matches!(
+            replica.catch_up(&primary),
+            Err(DbError::Wal(WalError::UpdatesUnavailable {
+                requested: 1,
+                available: 2
+            }))
+        ));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::block_cache::{self, Charge, LruShard, NUM_SHARDS};
+use crate::key::{SequenceNumber, ValueType};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Identifies a row: the id a table got from the cache, and the user key
+type RowKey = (u64, Vec<u8>);
+
+/// Bytes a version of a row takes in the cache besides its value
+const VERSION_OVERHEAD: usize = 16;
+
+/// A version of a key in a table: its sequence number, type and value
+pub(crate) type RowVersion = (SequenceNumber, ValueType, Vec<u8>);
+
+/// Keeps the versions of the keys point lookups found in the tables, up to a number of bytes,
+/// once set as [ColumnFamilyOptions::row_cache] of the column families sharing it: hot keys are
+/// then read without looking up the index nor decoding a data block
+///
+/// The rows are cached per table, which never changes: newer writes to a key live in the
+/// memtables and the newer tables, looked up first. Only the lookups at a sequence number past
+/// the newest entry of a table use it, since older lookups may not see every cached version.
+///
+/// [ColumnFamilyOptions::row_cache]: crate::ColumnFamilyOptions::row_cache
+pub struct RowCache {
+    shards: Vec<Mutex<LruShard<RowKey, Arc<Row>>>>,
+    capacity: usize,
+    /// Id handed to the next table, see [RowCache::new_id]
+    next_id: AtomicU64,
+}
+
+/// The versions of a key in a table, from the newest to the first one which isn't a merge
+/// operand
+pub(crate) struct Row {
+    pub(crate) versions: Vec<RowVersion>,
+}
+
+impl Charge for Arc<Row> {
+    fn charge(&self) -> usize {
+        self.versions
+            .iter()
+            .map(|(_, _, value)| value.len() + VERSION_OVERHEAD)
+            .sum()
+    }
+}
+
+impl RowCache {
+    /// Returns an empty cache holding at most `capacity` bytes of rows
+    pub fn new(capacity: usize) -> RowCache {
+        RowCache {
+            shards: (0..NUM_SHARDS)
+                .map(|_| Mutex::new(LruShard::new(capacity.div_ceil(NUM_SHARDS))))
+                .collect(),
+            capacity,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the bytes of the rows in the cache
+    pub fn usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().usage)
+            .sum()
+    }
+
+    /// Returns a new id for the rows of a table, unique among the tables of every database
+    /// sharing the cache
+    pub(crate) fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the versions of `user_key` in the table `id`, if cached
+    pub(crate) fn lookup(&self, id: u64, user_key: &[u8]) -> Option<Arc<Row>> {
+        let key = (id, user_key.to_vec());
+
+        self.shard(&key).lookup(&key).cloned()
+    }
+
+    /// Caches `row`, the versions of `user_key` in the table `id`
+    pub(crate) fn insert(&self, id: u64, user_key: &[u8], row: Arc<Row>) {
+        let key = (id, user_key.to_vec());
+
+        self.shard(&key).insert(key, row);
+    }
+
+    fn shard(&self, key: &RowKey) -> MutexGuard<'_, LruShard<RowKey, Arc<Row>>> {
+        self.shards[block_cache::shard_index(key)].lock().unwrap()
+    }
+}
+
+impl fmt::Debug for RowCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RowCache")
+            .field("capacity", &self.capacity)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
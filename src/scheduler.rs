@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Which threads of a [Scheduler] run a job
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Priority {
+    /// Flushes, which unblock the writes
+    High,
+    /// Compactions
+    Low,
+}
+
+struct Job {
+    /// Jobs with the same name run once when queued together
+    name: &'static str,
+    run: Box<dyn FnOnce() + Send>,
+}
+
+#[derive(Default)]
+struct Queues {
+    high: VecDeque<Job>,
+    low: VecDeque<Job>,
+    /// Number of jobs being run
+    running: usize,
+    shutting_down: bool,
+}
+
+impl Queues {
+    fn queue(&mut self, priority: Priority) -> &mut VecDeque<Job> {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Low => &mut self.low,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    queues: Mutex<Queues>,
+    /// Notified when a job is queued or the scheduler shuts down
+    job_queued: Condvar,
+    /// Notified when a job completes
+    job_done: Condvar,
+}
+
+/// Runs the background work of a database on two pools of threads, one per [Priority], so that
+/// the flushes don't wait behind long compactions
+///
+/// Queuing a job while one with the same name is waiting to start does nothing, since the
+/// waiting one will see the state the new one was queued for.
+pub(crate) struct Scheduler {
+    shared: Arc<Shared>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Scheduler {
+    /// Starts `high_threads` threads running the high priority jobs, and `low_threads` threads
+    /// running the low priority ones
+    pub(crate) fn new(high_threads: usize, low_threads: usize) -> Scheduler {
+        let shared = Arc::new(Shared::default());
+        let pools = [
+            (Priority::High, high_threads, "fyodor-flush"),
+            (Priority::Low, low_threads, "fyodor-compaction"),
+        ];
+
+        let threads = pools
+            .into_iter()
+            .flat_map(|(priority, count, name)| {
+                let shared = shared.clone();
+
+                (0..count).map(move |_| {
+                    let shared = shared.clone();
+
+                    std::thread::Builder::new()
+                        .name(name.to_string())
+                        .spawn(move || work(&shared, priority))
+                        .expect("failed to spawn a background thread")
+                })
+            })
+            .collect();
+
+        Scheduler {
+            shared,
+            threads: Mutex::new(threads),
+        }
+    }
+
+    /// Queues `run` to be run by a thread of `priority`, unless a job called `name` is already
+    /// waiting for one or the scheduler was shut down
+    pub(crate) fn schedule<F>(&self, priority: Priority, name: &'static str, run: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut queues = self.shared.queues.lock().unwrap();
+
+        if queues.shutting_down || queues.queue(priority).iter().any(|job| job.name == name) {
+            return;
+        }
+
+        queues.queue(priority).push_back(Job {
+            name,
+            run: Box::new(run),
+        });
+        self.shared.job_queued.notify_all();
+    }
+
+    /// Calls `run` every `period` from a thread of its own, typically to queue jobs which depend
+    /// on the time passing rather than on writes, until the scheduler shuts down
+    pub(crate) fn schedule_every<F>(&self, period: Duration, run: F)
+    where
+        F: Fn() + Send + 'static,
+    {
+        let shared = self.shared.clone();
+        let thread = std::thread::Builder::new()
+            .name("fyodor-timer".to_string())
+            .spawn(move || loop {
+                let queues = shared.queues.lock().unwrap();
+                let (queues, _) = shared
+                    .job_queued
+                    .wait_timeout_while(queues, period, |queues| !queues.shutting_down)
+                    .unwrap();
+
+                if queues.shutting_down {
+                    return;
+                }
+
+                drop(queues);
+                run();
+            })
+            .expect("failed to spawn a background thread");
+
+        self.threads.lock().unwrap().push(thread);
+    }
+
+    /// Waits until no job is queued or running
+    pub(crate) fn wait_idle(&self) {
+        let queues = self.shared.queues.lock().unwrap();
+
+        drop(
+            self.shared
+                .job_done
+                .wait_while(queues, |queues| {
+                    queues.running > 0 || !queues.high.is_empty() || !queues.low.is_empty()
+                })
+                .unwrap(),
+        );
+    }
+
+    /// Drops the queued jobs and stops the threads, once done with the jobs they're running
+    pub(crate) fn shutdown(&self) {
+        {
+            let mut queues = self.shared.queues.lock().unwrap();
+            queues.shutting_down = true;
+            queues.high.clear();
+            queues.low.clear();
+        }
+
+        self.shared.job_queued.notify_all();
+
+        for thread in self.threads.lock().unwrap().drain(..) {
+            // A job calling back into the scheduler as it shuts down must not join itself
+            if thread.thread().id() != std::thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Runs the jobs of `priority` until the scheduler shuts down
+fn work(shared: &Shared, priority: Priority) {
+    let mut queues = shared.queues.lock().unwrap();
+
+    loop {
+        if queues.shutting_down {
+            return;
+        }
+
+        let Some(job) = queues.queue(priority).pop_front() else {
+            queues = shared.job_queued.wait(queues).unwrap();
+            continue;
+        };
+
+        queues.running += 1;
+        drop(queues);
+
+        if panic::catch_unwind(AssertUnwindSafe(job.run)).is_err() {
+            log::error!("background job {} panicked", job.name);
+        }
+
+        queues = shared.queues.lock().unwrap();
+        queues.running -= 1;
+        shared.job_done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scheduler::{Priority, Scheduler};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_waiting_to_start_are_not_queued_twice() {
+        let scheduler = Scheduler::new(1, 1);
+        let started = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        // Keeps the only low priority thread busy
+        let (job_started, job_release) = (started.clone(), release.clone());
+        scheduler.schedule(Priority::Low, "blocker", move || {
+            job_started.wait();
+            job_release.wait();
+        });
+        started.wait();
+
+        for _ in 0..3 {
+            let runs = runs.clone();
+            scheduler.schedule(Priority::Low, "compaction", move || {
+                runs.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+
+        // The high priority jobs don't wait behind the low priority ones
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let job_flushes = flushes.clone();
+        scheduler.schedule(Priority::High, "flush", move || {
+            job_flushes.fetch_add(1, Ordering::Relaxed);
+        });
+        while flushes.load(Ordering::Relaxed) == 0 {
+            std::thread::yield_now();
+        }
+        assert_eq!(runs.load(Ordering::Relaxed), 0);
+
+        release.wait();
+        scheduler.wait_idle();
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let timer_ticks = ticks.clone();
+        scheduler.schedule_every(Duration::from_millis(1), move || {
+            timer_ticks.fetch_add(1, Ordering::Relaxed);
+        });
+        while ticks.load(Ordering::Relaxed) < 2 {
+            std::thread::yield_now();
+        }
+
+        scheduler.shutdown();
+        scheduler.schedule(Priority::Low, "compaction", || {
+            panic!("ran after the shutdown")
+        });
+        scheduler.wait_idle();
+    }
+}
@@ -0,0 +1,84 @@
+use crate::key::SequenceNumber;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// The sequence numbers pinned by live [Snapshot]s, with the number of snapshots pinning them
+///
+/// Anything that drops old versions of keys (i.e. compaction) must keep the most recent version
+/// visible to each of them.
+#[derive(Debug, Default)]
+pub struct SnapshotList {
+    snapshots: Mutex<BTreeMap<SequenceNumber, usize>>,
+}
+
+impl SnapshotList {
+    pub fn new() -> SnapshotList {
+        SnapshotList::default()
+    }
+
+    /// Pins `seq` until the returned snapshot is dropped
+    pub fn acquire(self: &Arc<Self>, seq: SequenceNumber) -> Snapshot {
+        *self.snapshots.lock().unwrap().entry(seq).or_insert(0) += 1;
+
+        Snapshot {
+            seq,
+            list: self.clone(),
+        }
+    }
+
+    fn release(&self, seq: SequenceNumber) {
+        let mut snapshots = self.snapshots.lock().unwrap();
+
+        if let Some(count) = snapshots.get_mut(&seq) {
+            *count -= 1;
+
+            if *count == 0 {
+                snapshots.remove(&seq);
+            }
+        }
+    }
+
+    /// Returns the oldest pinned sequence number, if any
+    pub fn oldest(&self) -> Option<SequenceNumber> {
+        self.snapshots.lock().unwrap().keys().next().copied()
+    }
+
+    /// Returns every pinned sequence number, oldest first
+    pub fn sequences(&self) -> Vec<SequenceNumber> {
+        self.snapshots.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.lock().unwrap().is_empty()
+    }
+}
+
+/// A consistent, read-only view of the database as of a sequence number
+///
+/// Reads through a snapshot ignore every write that happened after it was taken. The versions
+/// it needs are kept around until it's dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    seq: SequenceNumber,
+    list: Arc<SnapshotList>,
+}
+
+impl Snapshot {
+    /// Returns the sequence number of the last write visible through this snapshot
+    pub fn sequence(&self) -> SequenceNumber {
+        self.seq
+    }
+}
+
+/// Pins the same sequence number again, until both snapshots are dropped
+impl Clone for Snapshot {
+    fn clone(&self) -> Snapshot {
+        self.list.acquire(self.seq)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.list.release(self.seq);
+    }
+}
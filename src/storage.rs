@@ -1,8 +1,10 @@
 use integer_encoding::*;
 use std::cmp::Ordering;
-use std::mem;
+use std::fmt;
 use std::mem::size_of;
-use std::ops::Index;
+use std::ops::{Deref, Index, Range};
+use std::ptr;
+use std::sync::Arc;
 use thiserror::Error;
 
 /// Represents an entry (key + value) in the LSM-tree
@@ -22,6 +24,7 @@ impl Entry {
     /// Returns:
     ///   - The number of bytes used by the key
     ///   - The number of bytes used by the key size
+    ///
     /// respectively, given a slice which contains an Entry
     fn key_len_from_slice(data: &[u8]) -> (u32, usize) {
         u32::decode_var(data).unwrap()
@@ -30,13 +33,14 @@ impl Entry {
     /// Returns:
     ///   - The number of bytes used by the key
     ///   - The number of bytes used by the key size
+    ///
     /// respectively
     fn key_len(&self) -> (u32, usize) {
         Entry::key_len_from_slice(&self.data)
     }
 
     /// Returns a slice containing the key
-    fn key(&self) -> &[u8] {
+    pub fn key(&self) -> &[u8] {
         let (key_size, key_varint_size) = self.key_len();
         let (_, value_varint_size) = self.value_len();
 
@@ -48,6 +52,7 @@ impl Entry {
     /// Returns:
     ///   - The number of bytes used by the value
     ///   - The number of bytes used by the value size
+    ///
     /// respectively, given a slice which contains an Entry
     fn value_len_from_slice(data: &[u8]) -> (u32, usize) {
         let (_, key_varint_size) = Entry::key_len_from_slice(data);
@@ -58,12 +63,13 @@ impl Entry {
     /// Returns:
     ///   - The number of bytes used by the value
     ///   - The number of bytes used by the value size
+    ///
     /// respectively
     fn value_len(&self) -> (u32, usize) {
         Entry::value_len_from_slice(&self.data)
     }
 
-    fn value(&self) -> &[u8] {
+    pub fn value(&self) -> &[u8] {
         let (key_size, key_varint_size) = self.key_len();
         let (value_size, value_varint_size) = self.value_len();
 
@@ -78,8 +84,8 @@ impl Entry {
     }
 
     fn len_from_slice(data: &[u8]) -> u32 {
-        let (key_size, key_varint_size) = Entry::key_len_from_slice(&data);
-        let (value_size, value_varint_size) = Entry::value_len_from_slice(&data);
+        let (key_size, key_varint_size) = Entry::key_len_from_slice(data);
+        let (value_size, value_varint_size) = Entry::value_len_from_slice(data);
 
         key_varint_size as u32 + value_varint_size as u32 + key_size + value_size
     }
@@ -87,19 +93,16 @@ impl Entry {
     /// Creates an Entry, writing it into the memory block pointed by `page_entry`.
     /// Expects `page_entry` to have enough space
     pub fn create(block_entry: &mut [u8], key: &[u8], value: &[u8]) -> *const Entry {
-        unsafe {
-            let key_len = key.len();
-            let key_size = key_len.encode_var(block_entry);
-            let value_size = value.len().encode_var(block_entry[key_size..].as_mut());
+        let key_len = key.len();
+        let key_size = key_len.encode_var(block_entry);
+        let value_size = value.len().encode_var(block_entry[key_size..].as_mut());
 
-            block_entry[key_size + value_size..key_size + value_size + key_len]
-                .copy_from_slice(key);
+        block_entry[key_size + value_size..key_size + value_size + key_len].copy_from_slice(key);
 
-            let value_index = key_size + value_size + key_len;
-            block_entry[value_index..value_index + value.len()].copy_from_slice(value);
+        let value_index = key_size + value_size + key_len;
+        block_entry[value_index..value_index + value.len()].copy_from_slice(value);
 
-            mem::transmute::<&mut [u8], *const Entry>(block_entry)
-        }
+        block_entry as *const [u8] as *const Entry
     }
 }
 
@@ -107,11 +110,16 @@ impl Entry {
 pub enum BlockError {
     #[error("Trying to insert an Entry in a full Block")]
     FullBlock,
+    #[error("Block header is inconsistent with its size")]
+    Corrupted,
 }
 
 /// Frequency after which to save an index snapshot to help binary searching
 const SNAPSHOT_FREQUENCY: u32 = 10;
 
+/// Bytes taken by the `size` and `offset` fields at the start of every [Block]
+pub const BLOCK_HEADER_SIZE: usize = 2 * size_of::<u32>();
+
 /// An [Entry] container
 ///
 /// A Block contains an u32 representing the size of the array, a u32 representing
@@ -134,9 +142,13 @@ pub struct Block {
 
 impl Block {
     /// Creates a new Block from a slice, ideally pointing to an mmap-ed region of memory
+    ///
+    /// The slice must be at least [BLOCK_HEADER_SIZE] bytes long: the header is carved out of it,
+    /// and the rest is used for entries and snapshots
     pub fn new(block: *mut [u8]) -> *mut Block {
         unsafe {
-            let new_block = mem::transmute::<*mut [u8], *mut Block>(block);
+            let data_len = block.len() - BLOCK_HEADER_SIZE;
+            let new_block = ptr::slice_from_raw_parts_mut(block as *mut u8, data_len) as *mut Block;
 
             (*new_block).size = 0;
             (*new_block).offset = 0;
@@ -151,20 +163,22 @@ impl Block {
         let key_len = key.len();
         let value_len = value.len();
 
-        let key_varint_size = key.len().required_space();
-        let value_varint_size = key.len().required_space();
+        let key_varint_size = key_len.required_space();
+        let value_varint_size = value_len.required_space();
 
         let offset_index = self.offset as usize;
-        let remaining_space = self.data.len() - offset_index;
         let entry_size = key_varint_size + value_varint_size + key_len + value_len;
 
-        if entry_size > remaining_space {
+        // The snapshots (including the one this insert might add) grow from the end of the block
+        let snapshots_size = ((self.size + 1) / SNAPSHOT_FREQUENCY) as usize * size_of::<u32>();
+
+        if offset_index + entry_size + snapshots_size > self.data.len() {
             Err(BlockError::FullBlock)?
         }
 
         self.size += 1;
 
-        if self.size % SNAPSHOT_FREQUENCY == 0 {
+        if self.size.is_multiple_of(SNAPSHOT_FREQUENCY) {
             self.save_offset_snapshot();
         }
 
@@ -202,21 +216,61 @@ impl Block {
     /// Unsafe because the caller must make sure that the offset is pointing at the beginning of
     /// a valid entry
     unsafe fn get_at_offset(&self, offset: u32) -> *const Entry {
-        mem::transmute::<&[u8], *const Entry>(&self.data[offset as usize..])
+        &self.data[offset as usize..] as *const [u8] as *const Entry
+    }
+
+    /// Returns the number of entries in the block
+    pub fn len(&self) -> u32 {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Returns the number of bytes that [Block::serialize] would produce
+    pub fn serialized_len(&self) -> usize {
+        BLOCK_HEADER_SIZE
+            + self.offset as usize
+            + (self.size / SNAPSHOT_FREQUENCY) as usize * size_of::<u32>()
+    }
+
+    /// Serializes the block, dropping the free space between the entries and the snapshots.
+    ///
+    /// Snapshots are addressed from the end of the block, so the result is still a valid block
+    pub fn serialize(&self) -> Vec<u8> {
+        let snapshots_size = (self.size / SNAPSHOT_FREQUENCY) as usize * size_of::<u32>();
+        let mut serialized = Vec::with_capacity(self.serialized_len());
+
+        serialized.extend_from_slice(&self.size.to_ne_bytes());
+        serialized.extend_from_slice(&self.offset.to_ne_bytes());
+        serialized.extend_from_slice(&self.data[..self.offset as usize]);
+        serialized.extend_from_slice(&self.data[self.data.len() - snapshots_size..]);
+
+        serialized
+    }
+
+    /// Returns an iterator starting from the entry at `offset`, which must come from
+    /// [Block::binary_search] or from [BlockIterator::offset]
+    pub fn iter_from(&self, offset: u32) -> BlockIterator<'_> {
+        BlockIterator {
+            offset,
+            block: self,
+        }
     }
 
     /// Binary searches the entries in the block, using the offset snapshots as aid, comparing
-    /// entries using the cmp function. It expects the searched value to actually be in the range of
-    /// this block
+    /// entries using the cmp function.
     ///
-    /// Returns the closest snapshot offset which represents a smaller (or equal) entry
-    fn binary_search<T>(&self, cmp: T) -> u32
+    /// Returns the closest snapshot offset which represents a smaller (or equal) entry, or the
+    /// offset of the first entry if every snapshot is greater
+    pub fn binary_search<T>(&self, cmp: T) -> u32
     where
         T: Fn(&[u8]) -> Ordering,
     {
         use Ordering::*;
 
-        let mut left = 0 as usize;
+        let mut left = 0_usize;
         let mut right = self.size as usize / SNAPSHOT_FREQUENCY as usize;
 
         while left < right {
@@ -238,7 +292,11 @@ impl Block {
             }
         }
 
-        self.read_offset_snapshot(left - 1)
+        if left == 0 {
+            0
+        } else {
+            self.read_offset_snapshot(left - 1)
+        }
     }
 }
 
@@ -266,28 +324,32 @@ where
 }
 
 pub struct BlockIterator<'a> {
-    idx: u32,
     offset: u32,
     block: &'a Block,
 }
 
+impl BlockIterator<'_> {
+    /// Returns the offset of the entry which will be returned next
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
 impl<'a> Iterator for BlockIterator<'a> {
     type Item = &'a Entry;
 
     fn next(&mut self) -> Option<Self::Item> {
         unsafe {
-            if self.idx >= self.block.size {
+            if self.offset >= self.block.offset {
                 None
             } else {
                 let data = &self.block.data;
 
-                let entry =
-                    mem::transmute::<*const [u8], *const Entry>(&data[self.offset as usize..])
-                        .as_ref()
-                        .unwrap();
+                let entry = (&data[self.offset as usize..] as *const [u8] as *const Entry)
+                    .as_ref()
+                    .unwrap();
 
                 self.offset += entry.len();
-                self.idx += 1;
 
                 Some(entry)
             }
@@ -300,17 +362,162 @@ impl<'a> IntoIterator for &'a Block {
     type IntoIter = BlockIterator<'a>;
 
     fn into_iter(self) -> Self::IntoIter {
-        BlockIterator {
-            idx: 0,
-            offset: 0,
-            block: self,
+        self.iter_from(0)
+    }
+}
+
+/// A value returned by [Db::get_pinned](crate::Db::get_pinned), either borrowed from the data
+/// block it was found in, which then stays in memory while the value is held (even once evicted
+/// from the block cache), or owned
+pub struct PinnedValue {
+    inner: Pinned,
+}
+
+enum Pinned {
+    Block {
+        block: Arc<BlockBuffer>,
+        range: Range<usize>,
+    },
+    Owned(Vec<u8>),
+}
+
+impl PinnedValue {
+    /// Borrows `value`, a part of `block`
+    pub(crate) fn from_block(block: Arc<BlockBuffer>, value: &[u8]) -> PinnedValue {
+        let start = value.as_ptr() as usize - block.as_bytes().as_ptr() as usize;
+        debug_assert!(start + value.len() <= block.len());
+
+        PinnedValue {
+            inner: Pinned::Block {
+                block,
+                range: start..start + value.len(),
+            },
+        }
+    }
+
+    /// Whether the value borrows its data block rather than owning a copy
+    pub fn is_pinned(&self) -> bool {
+        matches!(self.inner, Pinned::Block { .. })
+    }
+}
+
+impl From<Vec<u8>> for PinnedValue {
+    fn from(value: Vec<u8>) -> PinnedValue {
+        PinnedValue {
+            inner: Pinned::Owned(value),
+        }
+    }
+}
+
+impl Deref for PinnedValue {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.inner {
+            Pinned::Block { block, range } => &block.as_bytes()[range.clone()],
+            Pinned::Owned(value) => value,
+        }
+    }
+}
+
+impl fmt::Debug for PinnedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PinnedValue").field(&self.deref()).finish()
+    }
+}
+
+/// Owned, suitably aligned memory holding a [Block], e.g. a block read back from disk
+pub struct BlockBuffer {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BlockBuffer {
+    /// Allocates a buffer of `len` bytes, initialized as an empty Block
+    pub fn new(len: usize) -> BlockBuffer {
+        let mut buffer = BlockBuffer {
+            words: vec![0; len.div_ceil(size_of::<u64>())],
+            len,
+        };
+
+        Block::new(buffer.as_bytes_mut() as *mut [u8]);
+
+        buffer
+    }
+
+    /// Copies a serialized block, checking that its header is consistent with its size
+    pub fn from_bytes(bytes: &[u8]) -> Result<BlockBuffer, BlockError> {
+        if bytes.len() < BLOCK_HEADER_SIZE {
+            Err(BlockError::Corrupted)?
+        }
+
+        let mut buffer = BlockBuffer {
+            words: vec![0; bytes.len().div_ceil(size_of::<u64>())],
+            len: bytes.len(),
+        };
+        buffer.as_bytes_mut().copy_from_slice(bytes);
+
+        let block = buffer.block();
+        let snapshots_size = (block.size / SNAPSHOT_FREQUENCY) as usize * size_of::<u32>();
+
+        if block.offset as usize + snapshots_size > block.data.len() {
+            Err(BlockError::Corrupted)?
+        }
+
+        Ok(buffer)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.words.as_ptr() as *const u8, self.len) }
+    }
+
+    /// Copies bytes which aren't a serialized block, e.g. a filter, for the caches: the buffer
+    /// is only read with [BlockBuffer::as_bytes]
+    pub(crate) fn from_raw_bytes(bytes: &[u8]) -> BlockBuffer {
+        let mut buffer = BlockBuffer {
+            words: vec![0; bytes.len().div_ceil(size_of::<u64>())],
+            len: bytes.len(),
+        };
+        buffer.as_bytes_mut().copy_from_slice(bytes);
+
+        buffer
+    }
+
+    fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, self.len) }
+    }
+
+    /// Returns the size of the buffer in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn block(&self) -> &Block {
+        let data_len = self.len - BLOCK_HEADER_SIZE;
+
+        unsafe {
+            &*(ptr::slice_from_raw_parts(self.words.as_ptr() as *const u8, data_len)
+                as *const Block)
+        }
+    }
+
+    pub fn block_mut(&mut self) -> &mut Block {
+        let data_len = self.len - BLOCK_HEADER_SIZE;
+
+        unsafe {
+            &mut *(ptr::slice_from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, data_len)
+                as *mut Block)
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::{Block, Entry, SNAPSHOT_FREQUENCY};
+    use crate::storage::{Block, BlockBuffer, Entry, BLOCK_HEADER_SIZE, SNAPSHOT_FREQUENCY};
     use core::array::TryFromSliceError;
     use core::cmp::Ordering;
     use std::mem::size_of;
@@ -318,7 +525,7 @@ mod tests {
     #[test]
     fn create_then_read_is_consistent() {
         unsafe {
-            let mut block = [0 as u8; 11];
+            let mut block = [0_u8; 11];
 
             let key: [u8; 5] = [0, 1, 2, 3, 4];
             let value: [u8; 4] = [5, 6, 7, 8];
@@ -335,7 +542,7 @@ mod tests {
     #[test]
     fn iterator_works() {
         // 55 for the entries + 8 for the idx + offset
-        let mut block_slice = [0 as u8; 55 + 8];
+        let mut block_slice = [0_u8; 55 + 8];
         let block = unsafe { &mut *Block::new(&mut block_slice as *mut [u8]) };
 
         let key_suffix = [0, 1, 2, 3];
@@ -352,9 +559,9 @@ mod tests {
             block.insert(&key, &value).unwrap();
         }
 
-        let mut expected_prefix = 0;
+        for (expected_prefix, entry) in block.into_iter().enumerate() {
+            let expected_prefix = expected_prefix as u8;
 
-        for entry in block.into_iter() {
             let mut expected_key = vec![expected_prefix];
             expected_key.extend_from_slice(&key_suffix);
 
@@ -363,8 +570,6 @@ mod tests {
 
             assert_eq!(entry.key(), expected_key.as_slice());
             assert_eq!(entry.value(), expected_value.as_slice());
-
-            expected_prefix += 1;
         }
     }
 
@@ -375,7 +580,7 @@ mod tests {
         const ENTRIES_SIZE: usize = 11 * ENTRIES_NUM;
         const SNAPSHOTS_SIZE: usize = SNAPSHOT_NUM * size_of::<u32>();
 
-        let mut block_slice = [0 as u8; ENTRIES_SIZE + SNAPSHOTS_SIZE];
+        let mut block_slice = [0_u8; BLOCK_HEADER_SIZE + ENTRIES_SIZE + SNAPSHOTS_SIZE];
 
         let block = unsafe { &mut *Block::new(&mut block_slice as *mut [u8]) };
 
@@ -412,7 +617,7 @@ mod tests {
         const ENTRIES_SIZE: usize = ENTRY_SIZE * ENTRIES_NUM;
         const SNAPSHOTS_SIZE: usize = SNAPSHOT_NUM * size_of::<u32>();
 
-        let mut block_slice = [0 as u8; ENTRIES_SIZE + SNAPSHOTS_SIZE];
+        let mut block_slice = [0_u8; BLOCK_HEADER_SIZE + ENTRIES_SIZE + SNAPSHOTS_SIZE];
 
         let block = unsafe { &mut *Block::new(&mut block_slice as *mut [u8]) };
 
@@ -435,7 +640,7 @@ mod tests {
         needle.push(needle_entry_num);
 
         // The needle must be 8 bytes long to be converted to an u64 below
-        needle.extend_from_slice(&[0 as u8; 3]);
+        needle.extend_from_slice(&[0_u8; 3]);
 
         let res: Result<[u8; 8], TryFromSliceError> = needle.as_slice().try_into();
         let needle_int = u64::from_be_bytes(res.unwrap());
@@ -452,4 +657,33 @@ mod tests {
 
         assert_eq!(offset, needle_entry_num as u32 * ENTRY_SIZE as u32);
     }
+
+    #[test]
+    fn serialized_block_keeps_snapshots() {
+        let mut buffer = BlockBuffer::new(4096);
+        let block = buffer.block_mut();
+
+        for n in 0..35_u8 {
+            block.insert(&[0, n], &[n; 3]).unwrap();
+        }
+
+        let serialized = block.serialize();
+        assert_eq!(serialized.len(), block.serialized_len());
+
+        let loaded = BlockBuffer::from_bytes(&serialized).unwrap();
+        let loaded = loaded.block();
+
+        assert_eq!(loaded.len(), 35);
+        assert_eq!(
+            loaded.read_offset_snapshot(2),
+            block.read_offset_snapshot(2)
+        );
+
+        let offset = loaded.binary_search(|key: &[u8]| key.cmp(&[0, 27]));
+        let entry = loaded.iter_from(offset).next().unwrap();
+
+        assert_eq!(entry.key(), [0, 19]);
+        assert_eq!(loaded.binary_search(|key: &[u8]| key.cmp(&[0, 3])), 0);
+        assert_eq!(loaded.into_iter().count(), 35);
+    }
 }
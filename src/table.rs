@@ -0,0 +1,2072 @@
+use crate::blob::BlobIndex;
+use crate::block_cache::{BlockCache, CachePriority};
+use crate::db::DbError;
+use crate::filter::{self, BloomFilterBuilder};
+use crate::iterator::InternalIterator;
+use crate::json;
+use crate::key::{self, SequenceNumber, ValueType};
+use crate::memtable::{GetContext, LookupResult};
+use crate::options::{ColumnFamilyOptions, CompressionType, ReadOptions};
+use crate::perf_context;
+use crate::prefix::PrefixExtractor;
+use crate::range_del::{FragmentedRangeTombstones, RangeTombstone};
+use crate::rate_limiter::RateLimiter;
+use crate::row_cache::{Row, RowCache};
+use crate::storage::{Block, BlockBuffer, BlockError, BLOCK_HEADER_SIZE};
+use integer_encoding::*;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Magic number closing every table file
+const TABLE_MAGIC: u64 = 0x7373_726f_6479_6f66;
+
+/// Bytes taken by the index, filter, range deletion and properties handles plus the magic number
+const FOOTER_SIZE: usize = 4 * BlockHandle::ENCODED_SIZE + size_of::<u64>();
+
+/// Bytes appended to every block: a compression type byte and a CRC32 of the block contents
+const BLOCK_TRAILER_SIZE: usize = 1 + size_of::<u32>();
+
+/// Compression type of a block stored as-is
+const NO_COMPRESSION: u8 = 0;
+
+/// Compression type of a block compressed with LZ4, prefixed by its uncompressed size
+const LZ4_COMPRESSION: u8 = 1;
+
+/// Compression type of a block compressed into a Zstandard frame
+const ZSTD_COMPRESSION: u8 = 2;
+
+/// Zstandard compression level of the data blocks, the default one of the library
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Error, Debug)]
+pub enum TableError {
+    #[error("I/O error on a table file")]
+    Io(#[from] io::Error),
+    #[error("Corrupted table: {0}")]
+    Corruption(&'static str),
+    #[error("Corrupted block in a table")]
+    Block(#[from] BlockError),
+}
+
+/// Returns the path of the table file with the given number
+pub fn table_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", number))
+}
+
+/// Extracts the table number from a table file name, returning None for any other file
+pub fn parse_table_file_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".sst")?.parse().ok()
+}
+
+/// Position and size (trailer excluded) of a block inside a table file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlockHandle {
+    const ENCODED_SIZE: usize = 2 * size_of::<u64>();
+
+    fn encode(&self) -> [u8; BlockHandle::ENCODED_SIZE] {
+        let mut encoded = [0_u8; BlockHandle::ENCODED_SIZE];
+
+        encoded[..8].copy_from_slice(&self.offset.to_le_bytes());
+        encoded[8..].copy_from_slice(&self.size.to_le_bytes());
+
+        encoded
+    }
+
+    fn decode(data: &[u8]) -> Result<BlockHandle, TableError> {
+        if data.len() < BlockHandle::ENCODED_SIZE {
+            Err(TableError::Corruption("bad block handle"))?
+        }
+
+        Ok(BlockHandle {
+            offset: u64::from_le_bytes(data[..8].try_into().unwrap()),
+            size: u64::from_le_bytes(data[8..16].try_into().unwrap()),
+        })
+    }
+}
+
+/// Statistics collected while building a table, stored in the table itself
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableProperties {
+    pub num_entries: u64,
+    pub num_deletions: u64,
+    pub num_range_deletions: u64,
+    pub num_merge_operands: u64,
+    pub num_data_blocks: u64,
+    pub raw_key_size: u64,
+    pub raw_value_size: u64,
+    pub data_size: u64,
+    pub index_size: u64,
+    pub filter_size: u64,
+    pub smallest_seqno: SequenceNumber,
+    pub largest_seqno: SequenceNumber,
+    /// Seconds since the UNIX epoch
+    pub creation_time: u64,
+    /// Smallest internal key of the table, range tombstones excluded
+    pub smallest_key: Vec<u8>,
+    /// Largest internal key of the table, range tombstones excluded
+    pub largest_key: Vec<u8>,
+    /// Name of the [PrefixExtractor] whose prefixes were added to the filter, if any
+    pub prefix_extractor_name: String,
+    /// Id of the column family the table belongs to
+    pub column_family_id: u64,
+    /// Number and bytes of the values of each blob file the blob indexes of the table point to,
+    /// by blob file number
+    pub blob_references: BTreeMap<u64, (u64, u64)>,
+}
+
+impl TableProperties {
+    fn encode(&self) -> Vec<u8> {
+        let mut properties: Vec<(&str, Vec<u8>)> = [
+            ("fyodor.column.family.id", self.column_family_id),
+            ("fyodor.creation.time", self.creation_time),
+            ("fyodor.data.size", self.data_size),
+            ("fyodor.filter.size", self.filter_size),
+            ("fyodor.index.size", self.index_size),
+            ("fyodor.largest.seqno", self.largest_seqno),
+            ("fyodor.num.data.blocks", self.num_data_blocks),
+            ("fyodor.num.deletions", self.num_deletions),
+            ("fyodor.num.entries", self.num_entries),
+            ("fyodor.num.merge.operands", self.num_merge_operands),
+            ("fyodor.num.range.deletions", self.num_range_deletions),
+            ("fyodor.raw.key.size", self.raw_key_size),
+            ("fyodor.raw.value.size", self.raw_value_size),
+            ("fyodor.smallest.seqno", self.smallest_seqno),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name, value.encode_var_vec()))
+        .collect();
+
+        properties.push(("fyodor.largest.key", self.largest_key.clone()));
+        properties.push((
+            "fyodor.prefix.extractor",
+            self.prefix_extractor_name.as_bytes().to_vec(),
+        ));
+        properties.push(("fyodor.smallest.key", self.smallest_key.clone()));
+        properties.push((
+            "fyodor.blob.references",
+            self.blob_references
+                .iter()
+                .flat_map(|(number, (count, bytes))| [*number, *count, *bytes])
+                .flat_map(|number| number.encode_var_vec())
+                .collect(),
+        ));
+
+        build_block(
+            properties
+                .iter()
+                .map(|(name, value)| (name.as_bytes(), value.as_slice())),
+        )
+    }
+
+    fn decode(block: &BlockBuffer) -> Result<TableProperties, TableError> {
+        let mut properties = TableProperties::default();
+
+        for entry in block.block() {
+            let value = entry.value();
+            let number = || {
+                u64::decode_var(value)
+                    .map(|(number, _)| number)
+                    .ok_or(TableError::Corruption("bad property"))
+            };
+
+            // Unknown properties are skipped, they may have been written by a newer version
+            match entry.key() {
+                b"fyodor.column.family.id" => properties.column_family_id = number()?,
+                b"fyodor.creation.time" => properties.creation_time = number()?,
+                b"fyodor.data.size" => properties.data_size = number()?,
+                b"fyodor.filter.size" => properties.filter_size = number()?,
+                b"fyodor.index.size" => properties.index_size = number()?,
+                b"fyodor.largest.seqno" => properties.largest_seqno = number()?,
+                b"fyodor.num.data.blocks" => properties.num_data_blocks = number()?,
+                b"fyodor.num.deletions" => properties.num_deletions = number()?,
+                b"fyodor.num.entries" => properties.num_entries = number()?,
+                b"fyodor.num.merge.operands" => properties.num_merge_operands = number()?,
+                b"fyodor.num.range.deletions" => properties.num_range_deletions = number()?,
+                b"fyodor.raw.key.size" => properties.raw_key_size = number()?,
+                b"fyodor.raw.value.size" => properties.raw_value_size = number()?,
+                b"fyodor.smallest.seqno" => properties.smallest_seqno = number()?,
+                b"fyodor.largest.key" => properties.largest_key = value.to_vec(),
+                b"fyodor.prefix.extractor" => {
+                    properties.prefix_extractor_name = String::from_utf8_lossy(value).into_owned()
+                }
+                b"fyodor.smallest.key" => properties.smallest_key = value.to_vec(),
+                b"fyodor.blob.references" => {
+                    properties.blob_references = decode_blob_references(value)?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(properties)
+    }
+}
+
+/// Returns the bytes needed by a block holding a single entry with the given key and value
+fn entry_block_size(key: &[u8], value: &[u8]) -> usize {
+    BLOCK_HEADER_SIZE
+        + key.len().required_space()
+        + value.len().required_space()
+        + key.len()
+        + value.len()
+        + size_of::<u32>()
+}
+
+/// Decodes the `fyodor.blob.references` property, a sequence of (number, count, bytes) varints
+fn decode_blob_references(mut data: &[u8]) -> Result<BTreeMap<u64, (u64, u64)>, TableError> {
+    let mut references = BTreeMap::new();
+
+    while !data.is_empty() {
+        let mut fields = [0; 3];
+
+        for field in &mut fields {
+            let (value, size) =
+                u64::decode_var(data).ok_or(TableError::Corruption("bad blob references"))?;
+            *field = value;
+            data = &data[size..];
+        }
+
+        let [number, count, bytes] = fields;
+        references.insert(number, (count, bytes));
+    }
+
+    Ok(references)
+}
+
+/// Builds a serialized block big enough to contain every entry
+fn build_block<'a, I>(entries: I) -> Vec<u8>
+where
+    I: Iterator<Item = (&'a [u8], &'a [u8])> + Clone,
+{
+    let size = entries
+        .clone()
+        .map(|(key, value)| entry_block_size(key, value))
+        .sum::<usize>()
+        + BLOCK_HEADER_SIZE;
+
+    let mut buffer = BlockBuffer::new(size);
+    let block = buffer.block_mut();
+
+    for (key, value) in entries {
+        block.insert(key, value).unwrap();
+    }
+
+    block.serialize()
+}
+
+/// Writes a table file out of a sorted stream of internal keys
+///
+/// The file layout is:
+/// [ data blocks, index block, filter block, range deletion block, properties block, footer ]
+/// where data blocks hold the entries, the index block maps the last key of every data block to
+/// its handle, the filter is a bloom filter of the user keys (and of their prefixes, if there's a
+/// prefix extractor), the range deletion block maps the start of every range tombstone (as an
+/// internal key) to its end, and the footer contains the handles of the index, filter, range
+/// deletion and properties blocks plus a magic number.
+/// Every block is followed by a trailer with its compression type and checksum.
+pub struct TableBuilder {
+    file: BufWriter<File>,
+    offset: u64,
+    block_size: usize,
+    compression: CompressionType,
+    data_block: BlockBuffer,
+    last_key: Vec<u8>,
+    index_entries: Vec<(Vec<u8>, [u8; BlockHandle::ENCODED_SIZE])>,
+    filter: BloomFilterBuilder,
+    prefix_extractor: Option<Arc<dyn PrefixExtractor>>,
+    /// Last prefix added to the filter
+    last_prefix: Option<Vec<u8>>,
+    range_tombstones: Vec<RangeTombstone>,
+    properties: TableProperties,
+    /// Charged for the blocks written, see [TableBuilder::with_rate_limiter]
+    rate_limiter: Option<Arc<RateLimiter>>,
+}
+
+impl TableBuilder {
+    pub fn new(file: File, options: &ColumnFamilyOptions) -> TableBuilder {
+        TableBuilder {
+            file: BufWriter::new(file),
+            offset: 0,
+            block_size: options.block_size,
+            compression: options.compression,
+            data_block: BlockBuffer::new(options.block_size),
+            last_key: Vec::new(),
+            index_entries: Vec::new(),
+            filter: BloomFilterBuilder::new(options.bloom_bits_per_key),
+            prefix_extractor: options.prefix_extractor.clone(),
+            last_prefix: None,
+            range_tombstones: Vec::new(),
+            properties: TableProperties {
+                prefix_extractor_name: options
+                    .prefix_extractor
+                    .as_ref()
+                    .map(|extractor| extractor.name())
+                    .unwrap_or_default(),
+                ..TableProperties::default()
+            },
+            rate_limiter: None,
+        }
+    }
+
+    /// Adds an entry to the table. Expects to be called in the right order, i.e. an earlier call
+    /// must add an internal key < than a later call
+    pub fn add(&mut self, internal_key: &[u8], value: &[u8]) -> Result<(), TableError> {
+        debug_assert!(
+            self.properties.num_entries == 0
+                || key::compare(&self.last_key, internal_key) == Ordering::Less
+        );
+
+        let (user_key, seq, value_type) =
+            key::parse(internal_key).ok_or(TableError::Corruption("bad internal key"))?;
+
+        if self
+            .data_block
+            .block_mut()
+            .insert(internal_key, value)
+            .is_err()
+        {
+            self.flush_data_block()?;
+
+            let size = self.block_size.max(entry_block_size(internal_key, value));
+            self.data_block = BlockBuffer::new(size);
+            self.data_block.block_mut().insert(internal_key, value)?;
+        }
+
+        self.record_seqno(seq);
+
+        let properties = &mut self.properties;
+
+        if properties.num_entries == 0 {
+            properties.smallest_key = internal_key.to_vec();
+        }
+
+        properties.num_entries += 1;
+        properties.raw_key_size += internal_key.len() as u64;
+        properties.raw_value_size += value.len() as u64;
+
+        match value_type {
+            ValueType::Deletion => properties.num_deletions += 1,
+            ValueType::Merge => properties.num_merge_operands += 1,
+            ValueType::BlobIndex => {
+                let index = BlobIndex::decode(value)
+                    .map_err(|_| TableError::Corruption("bad blob index"))?;
+                let (count, bytes) = properties
+                    .blob_references
+                    .entry(index.file_number)
+                    .or_default();
+
+                *count += 1;
+                *bytes += index.size;
+            }
+            _ => {}
+        }
+
+        self.filter.add_key(user_key);
+
+        if let Some(prefix) = self
+            .prefix_extractor
+            .as_ref()
+            .and_then(|extractor| extractor.prefix(user_key))
+        {
+            if self.last_prefix.as_deref() != Some(prefix) {
+                self.filter.add_key(prefix);
+                self.last_prefix = Some(prefix.to_vec());
+            }
+        }
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(internal_key);
+
+        Ok(())
+    }
+
+    /// Compresses the data blocks with `compression` rather than [ColumnFamilyOptions::compression]
+    pub fn with_compression(mut self, compression: CompressionType) -> TableBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Marks the table as belonging to the column family `id`, the default one otherwise
+    pub fn with_column_family_id(mut self, id: u32) -> TableBuilder {
+        self.properties.column_family_id = id as u64;
+        self
+    }
+
+    /// Waits for `rate_limiter` before writing each block
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> TableBuilder {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Adds a tombstone deleting the keys in [start, end) older than `seq`. Tombstones can be
+    /// added in any order
+    pub fn add_range_tombstone(&mut self, start: &[u8], end: &[u8], seq: SequenceNumber) {
+        self.record_seqno(seq);
+        self.properties.num_range_deletions += 1;
+
+        self.range_tombstones.push(RangeTombstone {
+            start: start.to_vec(),
+            end: end.to_vec(),
+            seq,
+        });
+    }
+
+    fn record_seqno(&mut self, seq: SequenceNumber) {
+        let properties = &mut self.properties;
+
+        if properties.num_entries == 0 && properties.num_range_deletions == 0 {
+            properties.smallest_seqno = seq;
+        }
+
+        properties.smallest_seqno = properties.smallest_seqno.min(seq);
+        properties.largest_seqno = properties.largest_seqno.max(seq);
+    }
+
+    /// Returns the number of entries added so far
+    pub fn num_entries(&self) -> u64 {
+        self.properties.num_entries
+    }
+
+    /// Returns the size the file would have if it was finished now, ignoring the metadata blocks
+    pub fn file_size(&self) -> u64 {
+        self.offset + self.data_block.block().serialized_len() as u64
+    }
+
+    fn flush_data_block(&mut self) -> Result<(), TableError> {
+        if self.data_block.block().is_empty() {
+            return Ok(());
+        }
+
+        let contents = self.data_block.block().serialize();
+        let handle = self.write_data_block(&contents)?;
+
+        self.index_entries
+            .push((self.last_key.clone(), handle.encode()));
+        self.properties.num_data_blocks += 1;
+        self.properties.data_size += handle.size + BLOCK_TRAILER_SIZE as u64;
+
+        Ok(())
+    }
+
+    /// Writes a data block, compressed unless compression saves less than 1/8 of its size
+    fn write_data_block(&mut self, contents: &[u8]) -> Result<BlockHandle, TableError> {
+        let compressed = match self.compression {
+            CompressionType::None => None,
+            CompressionType::Lz4 => {
+                Some((lz4_flex::compress_prepend_size(contents), LZ4_COMPRESSION))
+            }
+            CompressionType::Zstd => Some((
+                zstd::bulk::compress(contents, ZSTD_LEVEL)?,
+                ZSTD_COMPRESSION,
+            )),
+        };
+
+        match compressed {
+            Some((compressed, compression))
+                if compressed.len() < contents.len() - contents.len() / 8 =>
+            {
+                self.write_raw_block(&compressed, compression)
+            }
+            _ => self.write_raw_block(contents, NO_COMPRESSION),
+        }
+    }
+
+    fn write_block(&mut self, contents: &[u8]) -> Result<BlockHandle, TableError> {
+        self.write_raw_block(contents, NO_COMPRESSION)
+    }
+
+    fn write_raw_block(
+        &mut self,
+        contents: &[u8],
+        compression: u8,
+    ) -> Result<BlockHandle, TableError> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(contents);
+        hasher.update(&[compression]);
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.request((contents.len() + BLOCK_TRAILER_SIZE) as u64);
+        }
+
+        self.file.write_all(contents)?;
+        self.file.write_all(&[compression])?;
+        self.file.write_all(&hasher.finalize().to_le_bytes())?;
+
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: contents.len() as u64,
+        };
+
+        self.offset += (contents.len() + BLOCK_TRAILER_SIZE) as u64;
+
+        Ok(handle)
+    }
+
+    /// Writes the metadata blocks and the footer, then syncs the file
+    ///
+    /// Returns the properties of the table
+    pub fn finish(mut self) -> Result<TableProperties, TableError> {
+        self.flush_data_block()?;
+
+        self.properties.largest_key = self.last_key.clone();
+        self.properties.creation_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let index = build_block(
+            self.index_entries
+                .iter()
+                .map(|(key, handle)| (key.as_slice(), handle.as_slice())),
+        );
+        let index_handle = self.write_block(&index)?;
+        self.properties.index_size = index_handle.size;
+
+        let filter = std::mem::replace(&mut self.filter, BloomFilterBuilder::new(0)).finish();
+        let filter_handle = self.write_block(&filter)?;
+        self.properties.filter_size = filter_handle.size;
+
+        let mut range_tombstones: Vec<_> = self
+            .range_tombstones
+            .iter()
+            .map(|tombstone| {
+                (
+                    key::encode(&tombstone.start, tombstone.seq, ValueType::RangeDeletion),
+                    tombstone.end.as_slice(),
+                )
+            })
+            .collect();
+        range_tombstones.sort_by(|(a, _), (b, _)| key::compare(a, b));
+
+        let range_deletions = build_block(
+            range_tombstones
+                .iter()
+                .map(|(start, end)| (start.as_slice(), *end)),
+        );
+        let range_deletions_handle = self.write_block(&range_deletions)?;
+
+        let properties_handle = self.write_block(&self.properties.encode())?;
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        footer.extend_from_slice(&index_handle.encode());
+        footer.extend_from_slice(&filter_handle.encode());
+        footer.extend_from_slice(&range_deletions_handle.encode());
+        footer.extend_from_slice(&properties_handle.encode());
+        footer.extend_from_slice(&TABLE_MAGIC.to_le_bytes());
+
+        self.file.write_all(&footer)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        Ok(self.properties)
+    }
+}
+
+/// A version of a key in a [BlockDump]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryDump {
+    pub user_key: Vec<u8>,
+    pub sequence: SequenceNumber,
+    pub value_type: ValueType,
+    pub value: Vec<u8>,
+}
+
+/// A data block of a [TableDump]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockDump {
+    /// Position and size of the block in the file, as recorded in the index
+    pub handle: BlockHandle,
+    /// Key of the index entry of the block, the largest internal key of the block
+    pub index_key: Vec<u8>,
+    /// Compression type of the block, None if the byte of its trailer is unknown
+    pub compression: Option<CompressionType>,
+    pub entries: Vec<EntryDump>,
+    /// Why the block, or its entries from the first bad one on, couldn't be read
+    pub error: Option<String>,
+}
+
+/// The bloom filter of a [TableDump]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterDump {
+    pub handle: BlockHandle,
+    /// Number of bits probed per key
+    pub probes: u8,
+    pub bits: u64,
+    /// Number of bits set, the more the higher the false positive rate
+    pub bits_set: u64,
+}
+
+/// Everything a table file holds, see [Table::dump]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableDump {
+    pub number: u64,
+    pub file_size: u64,
+    pub index_handle: BlockHandle,
+    pub filter: FilterDump,
+    pub properties: TableProperties,
+    pub blocks: Vec<BlockDump>,
+    pub range_tombstones: Vec<RangeTombstone>,
+}
+
+impl TableDump {
+    /// Formats the dump as a JSON object, the keys and values as strings of hexadecimal digits
+    ///
+    /// The internal keys are split into `user_key`, `sequence` and `type`, unless they can't be
+    /// parsed, in which case they are only a `raw` string of hexadecimal digits.
+    pub fn to_json(&self) -> String {
+        let properties = &self.properties;
+        let blob_references: Vec<_> = properties
+            .blob_references
+            .iter()
+            .map(|(file, (count, bytes))| {
+                format!(r#"{{"file":{file},"count":{count},"bytes":{bytes}}}"#)
+            })
+            .collect();
+        let properties = format!(
+            concat!(
+                r#"{{"num_entries":{},"num_deletions":{},"num_range_deletions":{},"#,
+                r#""num_merge_operands":{},"num_data_blocks":{},"raw_key_size":{},"#,
+                r#""raw_value_size":{},"data_size":{},"index_size":{},"filter_size":{},"#,
+                r#""smallest_seqno":{},"largest_seqno":{},"creation_time":{},"#,
+                r#""smallest_key":{},"largest_key":{},"prefix_extractor_name":{},"#,
+                r#""column_family_id":{},"blob_references":[{}]}}"#
+            ),
+            properties.num_entries,
+            properties.num_deletions,
+            properties.num_range_deletions,
+            properties.num_merge_operands,
+            properties.num_data_blocks,
+            properties.raw_key_size,
+            properties.raw_value_size,
+            properties.data_size,
+            properties.index_size,
+            properties.filter_size,
+            properties.smallest_seqno,
+            properties.largest_seqno,
+            properties.creation_time,
+            json_internal_key(&properties.smallest_key),
+            json_internal_key(&properties.largest_key),
+            json::string(&properties.prefix_extractor_name),
+            properties.column_family_id,
+            blob_references.join(","),
+        );
+
+        let blocks: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let entries: Vec<_> = block
+                    .entries
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            r#"{{"user_key":{},"sequence":{},"type":{},"value":{}}}"#,
+                            json::hex(&entry.user_key),
+                            entry.sequence,
+                            json::string(&format!("{:?}", entry.value_type)),
+                            json::hex(&entry.value),
+                        )
+                    })
+                    .collect();
+
+                format!(
+                    r#"{{"handle":{},"index_key":{},"compression":{},"entries":[{}],"error":{}}}"#,
+                    json_handle(block.handle),
+                    json_internal_key(&block.index_key),
+                    block.compression.map_or("null".to_string(), |compression| {
+                        json::string(&format!("{compression:?}"))
+                    }),
+                    entries.join(","),
+                    block
+                        .error
+                        .as_deref()
+                        .map_or("null".to_string(), json::string),
+                )
+            })
+            .collect();
+
+        let range_tombstones: Vec<_> = self
+            .range_tombstones
+            .iter()
+            .map(|tombstone| {
+                format!(
+                    r#"{{"start":{},"end":{},"sequence":{}}}"#,
+                    json::hex(&tombstone.start),
+                    json::hex(&tombstone.end),
+                    tombstone.seq,
+                )
+            })
+            .collect();
+
+        format!(
+            concat!(
+                r#"{{"number":{},"file_size":{},"index_handle":{},"#,
+                r#""filter":{{"handle":{},"probes":{},"bits":{},"bits_set":{}}},"#,
+                r#""properties":{},"blocks":[{}],"range_tombstones":[{}]}}"#
+            ),
+            self.number,
+            self.file_size,
+            json_handle(self.index_handle),
+            json_handle(self.filter.handle),
+            self.filter.probes,
+            self.filter.bits,
+            self.filter.bits_set,
+            properties,
+            blocks.join(","),
+            range_tombstones.join(","),
+        )
+    }
+}
+
+fn json_handle(handle: BlockHandle) -> String {
+    format!(r#"{{"offset":{},"size":{}}}"#, handle.offset, handle.size)
+}
+
+fn json_internal_key(internal_key: &[u8]) -> String {
+    match key::parse(internal_key) {
+        Some((user_key, seq, value_type)) => format!(
+            r#"{{"user_key":{},"sequence":{},"type":{}}}"#,
+            json::hex(user_key),
+            seq,
+            json::string(&format!("{value_type:?}"))
+        ),
+        None => format!(r#"{{"raw":{}}}"#, json::hex(internal_key)),
+    }
+}
+
+/// An immutable, sorted table file (SST) opened for reading
+pub struct Table {
+    file: File,
+    number: u64,
+    file_size: u64,
+    index_handle: BlockHandle,
+    filter_handle: BlockHandle,
+    /// The index block, unless it's only kept in the block cache
+    index: Option<Arc<BlockBuffer>>,
+    /// The filter, unless it's only kept in the block cache
+    filter: Option<Arc<BlockBuffer>>,
+    range_tombstones: Vec<RangeTombstone>,
+    fragmented_range_tombstones: FragmentedRangeTombstones,
+    properties: TableProperties,
+    /// The cache of the data blocks, with the id of the table in it
+    block_cache: Option<(Arc<BlockCache>, u64)>,
+    /// The cache of the versions of the keys looked up, with the id of the table in it
+    row_cache: Option<(Arc<RowCache>, u64)>,
+}
+
+impl Table {
+    /// Opens the table file at `path`, loading its index, filter and properties in memory
+    pub fn open(path: &Path, number: u64) -> Result<Table, TableError> {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+
+        if file_size < FOOTER_SIZE as u64 {
+            Err(TableError::Corruption("file too small"))?
+        }
+
+        let mut footer = [0_u8; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, file_size - FOOTER_SIZE as u64)?;
+
+        if u64::from_le_bytes(footer[FOOTER_SIZE - 8..].try_into().unwrap()) != TABLE_MAGIC {
+            Err(TableError::Corruption("bad magic number"))?
+        }
+
+        let index_handle = BlockHandle::decode(&footer)?;
+        let filter_handle = BlockHandle::decode(&footer[BlockHandle::ENCODED_SIZE..])?;
+        let range_deletions_handle = BlockHandle::decode(&footer[2 * BlockHandle::ENCODED_SIZE..])?;
+        let properties_handle = BlockHandle::decode(&footer[3 * BlockHandle::ENCODED_SIZE..])?;
+
+        let index = BlockBuffer::from_bytes(&read_block_contents(&file, index_handle)?)?;
+        let filter = BlockBuffer::from_raw_bytes(&read_block_contents(&file, filter_handle)?);
+        let range_deletions =
+            BlockBuffer::from_bytes(&read_block_contents(&file, range_deletions_handle)?)?;
+        let properties = TableProperties::decode(&BlockBuffer::from_bytes(&read_block_contents(
+            &file,
+            properties_handle,
+        )?)?)?;
+
+        let range_tombstones = range_deletions
+            .block()
+            .into_iter()
+            .map(|entry| match key::parse(entry.key()) {
+                Some((start, seq, ValueType::RangeDeletion)) => Ok(RangeTombstone {
+                    start: start.to_vec(),
+                    end: entry.value().to_vec(),
+                    seq,
+                }),
+                _ => Err(TableError::Corruption("bad range tombstone")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let fragmented_range_tombstones = FragmentedRangeTombstones::new(&range_tombstones);
+
+        Ok(Table {
+            file,
+            number,
+            file_size,
+            index_handle,
+            filter_handle,
+            index: Some(Arc::new(index)),
+            filter: Some(Arc::new(filter)),
+            range_tombstones,
+            fragmented_range_tombstones,
+            properties,
+            block_cache: None,
+            row_cache: None,
+        })
+    }
+
+    /// Keeps the data blocks read in `block_cache`, and looks them up there first
+    pub fn with_block_cache(mut self, block_cache: Option<Arc<BlockCache>>) -> Table {
+        self.block_cache = block_cache.map(|cache| {
+            let id = cache.new_id();
+            (cache, id)
+        });
+        self
+    }
+
+    /// Keeps the versions of the keys looked up in `row_cache`, and looks them up there first
+    pub fn with_row_cache(mut self, row_cache: Option<Arc<RowCache>>) -> Table {
+        self.row_cache = row_cache.map(|cache| {
+            let id = cache.new_id();
+            (cache, id)
+        });
+        self
+    }
+
+    /// Moves the index and filter blocks to the block cache if `cache_index_and_filter_blocks`,
+    /// reading them back from the file once evicted, unless `pin` where the table keeps them
+    /// all the same. Only takes effect after [Table::with_block_cache].
+    pub fn with_cache_index_and_filter_blocks(
+        mut self,
+        cache_index_and_filter_blocks: bool,
+        pin: bool,
+    ) -> Table {
+        let Some((cache, id)) = self
+            .block_cache
+            .as_ref()
+            .filter(|_| cache_index_and_filter_blocks)
+        else {
+            return self;
+        };
+
+        for (handle, block) in [
+            (self.index_handle, &self.index),
+            (self.filter_handle, &self.filter),
+        ] {
+            if let Some(block) = block {
+                cache.insert(*id, handle.offset, block.clone(), CachePriority::High);
+            }
+        }
+
+        if !pin {
+            self.index = None;
+            self.filter = None;
+        }
+
+        self
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn properties(&self) -> &TableProperties {
+        &self.properties
+    }
+
+    /// Returns an estimate of the bytes kept in memory while the table is open: its index and
+    /// filter unless they are only in the block cache, and its range tombstones
+    pub fn approximate_memory_usage(&self) -> usize {
+        let range_tombstones: usize = self
+            .range_tombstones
+            .iter()
+            .map(|tombstone| {
+                tombstone.start.len() + tombstone.end.len() + size_of::<RangeTombstone>()
+            })
+            .sum();
+
+        // The fragments take about as much as the tombstones they come from
+        let held = |block: &Option<Arc<BlockBuffer>>| block.as_ref().map_or(0, |block| block.len());
+
+        held(&self.index) + held(&self.filter) + 2 * range_tombstones
+    }
+
+    /// Returns the range tombstones of the table
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Returns true if the table may hold user keys in the range [lower, upper), where a missing
+    /// bound is unbounded. Range tombstones are not taken into account.
+    pub fn overlaps(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> bool {
+        if self.properties.num_entries == 0 {
+            return false;
+        }
+
+        let smallest = key::user_key(&self.properties.smallest_key);
+        let largest = key::user_key(&self.properties.largest_key);
+
+        lower.is_none_or(|lower| largest >= lower) && upper.is_none_or(|upper| smallest < upper)
+    }
+
+    /// Returns the approximate offset in the file of the data of `user_key`, i.e. the bytes of
+    /// the data blocks before it, looking only at the index
+    pub fn approximate_offset_of(&self, user_key: &[u8]) -> Result<u64, TableError> {
+        let target = key::seek_key(user_key, key::MAX_SEQUENCE_NUMBER);
+
+        Ok(match self.find_data_block(&target)? {
+            Some((handle, _)) => handle.offset,
+            // Past the last key: the data blocks end where the index starts
+            None => self.properties.data_size,
+        })
+    }
+
+    /// Returns the approximate bytes taken in the file by the keys in [start, end)
+    pub fn approximate_range_size(&self, start: &[u8], end: &[u8]) -> Result<u64, TableError> {
+        if start >= end {
+            return Ok(0);
+        }
+
+        let start = self.approximate_offset_of(start)?;
+        let end = self.approximate_offset_of(end)?;
+
+        Ok(end.saturating_sub(start))
+    }
+
+    /// Returns false if `user_key` is surely not in the table, only looking at its key range and
+    /// its filter (i.e. without reading data blocks)
+    pub fn key_may_exist(&self, user_key: &[u8]) -> bool {
+        if self.properties.num_entries == 0 {
+            return false;
+        }
+
+        let smallest = key::user_key(&self.properties.smallest_key);
+        let largest = key::user_key(&self.properties.largest_key);
+
+        smallest <= user_key && user_key <= largest && self.may_contain(user_key)
+    }
+
+    /// Returns false if the table surely doesn't contain `user_key`
+    pub fn may_contain(&self, user_key: &[u8]) -> bool {
+        let _timer = perf_context::timer(|ctx, elapsed| ctx.filter_check_time += elapsed);
+        // A filter which can't be read back can't rule the key out
+        let may_contain = self.filter().map_or(true, |filter| {
+            filter::may_contain(filter.as_bytes(), user_key)
+        });
+
+        perf_context::record(|ctx| {
+            ctx.filter_check_count += 1;
+            ctx.filter_useful_count += u64::from(!may_contain);
+        });
+
+        may_contain
+    }
+
+    /// Returns false if the table surely doesn't contain keys with `prefix`, according to
+    /// `extractor`
+    pub fn prefix_may_match(&self, extractor: &dyn PrefixExtractor, prefix: &[u8]) -> bool {
+        // The filter only holds the prefixes of the extractor the table was built with
+        self.properties.prefix_extractor_name != extractor.name() || self.may_contain(prefix)
+    }
+
+    /// Returns the id of the table in its block cache, if any
+    pub(crate) fn block_cache(&self) -> Option<&(Arc<BlockCache>, u64)> {
+        self.block_cache.as_ref()
+    }
+
+    /// Reads the data blocks at `offsets` into the block cache, if they aren't there already,
+    /// and returns how many were found
+    pub(crate) fn warm_block_cache(&self, offsets: &HashSet<u64>) -> Result<usize, TableError> {
+        let mut found = 0;
+
+        for handle in [self.index_handle, self.filter_handle] {
+            if offsets.contains(&handle.offset) && self.block_cache.is_some() {
+                if handle == self.index_handle {
+                    self.index()?;
+                } else {
+                    self.filter()?;
+                }
+                found += 1;
+            }
+        }
+
+        let index = self.index()?;
+
+        for index_entry in index.block().iter_from(0) {
+            let handle = BlockHandle::decode(index_entry.value())?;
+
+            if offsets.contains(&handle.offset) {
+                self.read_block(handle, true, true)?;
+                found += 1;
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Reads every block of the table, e.g. to investigate a corruption: the data blocks which
+    /// can't be read are reported in their [BlockDump::error], while the other blocks are
+    /// still read
+    pub fn dump(&self) -> Result<TableDump, TableError> {
+        let index = self.index()?;
+        let mut blocks = Vec::new();
+
+        for index_entry in index.block() {
+            let handle = BlockHandle::decode(index_entry.value())?;
+            let mut block = BlockDump {
+                handle,
+                index_key: index_entry.key().to_vec(),
+                compression: None,
+                entries: Vec::new(),
+                error: None,
+            };
+
+            if let Err(error) = self.dump_block(&mut block) {
+                block.error = Some(error.to_string());
+            }
+
+            blocks.push(block);
+        }
+
+        let filter = self.filter()?;
+        let filter = filter.as_bytes();
+        let (bits, probes) = match filter.split_last() {
+            Some((probes, bits)) if !bits.is_empty() => (bits, *probes),
+            _ => (&[][..], 0),
+        };
+
+        Ok(TableDump {
+            number: self.number,
+            file_size: self.file_size,
+            index_handle: self.index_handle,
+            filter: FilterDump {
+                handle: self.filter_handle,
+                probes,
+                bits: 8 * bits.len() as u64,
+                bits_set: bits.iter().map(|byte| byte.count_ones() as u64).sum(),
+            },
+            properties: self.properties.clone(),
+            blocks,
+            range_tombstones: self.range_tombstones.clone(),
+        })
+    }
+
+    /// Reads the compression type and the entries of the data block of `block`
+    fn dump_block(&self, block: &mut BlockDump) -> Result<(), TableError> {
+        let mut contents = vec![0_u8; block.handle.size as usize + BLOCK_TRAILER_SIZE];
+        self.file
+            .read_exact_at(&mut contents, block.handle.offset)?;
+
+        block.compression = match contents[block.handle.size as usize] {
+            NO_COMPRESSION => Some(CompressionType::None),
+            LZ4_COMPRESSION => Some(CompressionType::Lz4),
+            ZSTD_COMPRESSION => Some(CompressionType::Zstd),
+            _ => None,
+        };
+
+        let data = BlockBuffer::from_bytes(&decode_block_contents(contents, true)?)?;
+
+        for entry in data.block() {
+            let (user_key, sequence, value_type) =
+                key::parse(entry.key()).ok_or(TableError::Corruption("bad internal key"))?;
+
+            block.entries.push(EntryDump {
+                user_key: user_key.to_vec(),
+                sequence,
+                value_type,
+                value: entry.value().to_vec(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the index block, from the block cache if the table doesn't keep it
+    fn index(&self) -> Result<Arc<BlockBuffer>, TableError> {
+        if let Some(index) = &self.index {
+            return Ok(index.clone());
+        }
+
+        self.read_block(self.index_handle, true, true)
+    }
+
+    /// Returns the filter, from the block cache if the table doesn't keep it
+    fn filter(&self) -> Result<Arc<BlockBuffer>, TableError> {
+        if let Some(filter) = &self.filter {
+            return Ok(filter.clone());
+        }
+
+        if let Some(filter) = self.cached_block(self.filter_handle) {
+            return Ok(filter);
+        }
+
+        let filter =
+            BlockBuffer::from_raw_bytes(&read_block_contents(&self.file, self.filter_handle)?);
+
+        Ok(self.cache_block(self.filter_handle, filter, true))
+    }
+
+    /// Returns the data block at `handle` if it's in the block cache
+    fn cached_block(&self, handle: BlockHandle) -> Option<Arc<BlockBuffer>> {
+        let (cache, id) = self.block_cache.as_ref()?;
+        let block = cache.lookup(*id, handle.offset)?;
+
+        perf_context::record(|ctx| ctx.block_cache_hit_count += 1);
+
+        Some(block)
+    }
+
+    /// Adds `block`, read at `handle`, to the block cache if `fill_cache`, with a high priority
+    /// for the index and filter blocks
+    fn cache_block(
+        &self,
+        handle: BlockHandle,
+        block: BlockBuffer,
+        fill_cache: bool,
+    ) -> Arc<BlockBuffer> {
+        let block = Arc::new(block);
+        let priority = if handle == self.index_handle || handle == self.filter_handle {
+            CachePriority::High
+        } else {
+            CachePriority::Low
+        };
+
+        if let Some((cache, id)) = self.block_cache.as_ref().filter(|_| fill_cache) {
+            cache.insert(*id, handle.offset, block.clone(), priority);
+        }
+
+        block
+    }
+
+    /// Reads the data block at `handle`, from the block cache if it's there, adding it there
+    /// otherwise if `fill_cache`
+    fn read_block(
+        &self,
+        handle: BlockHandle,
+        verify_checksum: bool,
+        fill_cache: bool,
+    ) -> Result<Arc<BlockBuffer>, TableError> {
+        if let Some(block) = self.cached_block(handle) {
+            return Ok(block);
+        }
+
+        let len = handle.size as usize + BLOCK_TRAILER_SIZE;
+        let mut contents = vec![0_u8; len];
+
+        {
+            let _timer = perf_context::timer(|ctx, elapsed| {
+                ctx.block_read_count += 1;
+                ctx.block_read_bytes += len as u64;
+                ctx.block_read_time += elapsed;
+            });
+
+            self.file.read_exact_at(&mut contents, handle.offset)?;
+        }
+
+        let block = BlockBuffer::from_bytes(&decode_block_contents(contents, verify_checksum)?)?;
+
+        Ok(self.cache_block(handle, block, fill_cache))
+    }
+
+    /// Returns the handle of the first data block which may contain entries >= `target`, along
+    /// with the offset of its index entry
+    fn find_data_block(&self, target: &[u8]) -> Result<Option<(BlockHandle, u32)>, TableError> {
+        let index = self.index()?;
+        let index = index.block();
+        let offset = index.binary_search(|key| key::compare(key, target));
+        let mut entries = index.iter_from(offset);
+
+        loop {
+            let entry_offset = entries.offset();
+            let entry = match entries.next() {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+
+            if key::compare(entry.key(), target) != Ordering::Less {
+                return Ok(Some((BlockHandle::decode(entry.value())?, entry_offset)));
+            }
+        }
+    }
+
+    /// Looks up the most recent version of `user_key` visible at sequence number `seq`, ignoring
+    /// merge operands
+    ///
+    /// Returns None if the table knows nothing about the key
+    pub fn get(
+        &self,
+        user_key: &[u8],
+        seq: SequenceNumber,
+    ) -> Result<Option<LookupResult>, TableError> {
+        self.get_with_context(
+            user_key,
+            seq,
+            &mut GetContext::default(),
+            &ReadOptions::default(),
+        )
+    }
+
+    /// Same as [Table::get], but carries the state of a lookup through several sources: range
+    /// tombstones of newer sources are honored, and merge operands are collected in `ctx` until
+    /// a value or a deletion is found
+    ///
+    /// The checksums of the data blocks are only verified if
+    /// [ReadOptions::verify_checksums] is set.
+    pub fn get_with_context(
+        &self,
+        user_key: &[u8],
+        seq: SequenceNumber,
+        ctx: &mut GetContext,
+        read_options: &ReadOptions,
+    ) -> Result<Option<LookupResult>, TableError> {
+        ctx.add_tombstone_seq(
+            self.fragmented_range_tombstones
+                .max_covering_seq(user_key, seq),
+        );
+
+        if !self.may_contain(user_key) {
+            return Ok(ctx.source_exhausted());
+        }
+
+        match &self.row_cache {
+            Some((cache, id)) if seq >= self.properties.largest_seqno => {
+                self.get_from_row_cache(cache, *id, user_key, ctx, read_options)
+            }
+            _ => self.get_from_blocks(user_key, seq, ctx, read_options, &mut None),
+        }
+    }
+
+    /// Same as [Table::get_with_context] through the row cache, for a lookup seeing every
+    /// version of `user_key` in the table
+    fn get_from_row_cache(
+        &self,
+        cache: &RowCache,
+        id: u64,
+        user_key: &[u8],
+        ctx: &mut GetContext,
+        read_options: &ReadOptions,
+    ) -> Result<Option<LookupResult>, TableError> {
+        let row = match cache.lookup(id, user_key) {
+            Some(row) => {
+                perf_context::record(|ctx| ctx.row_cache_hit_count += 1);
+                row
+            }
+            None => {
+                let mut versions = Vec::new();
+                self.walk_versions(
+                    user_key,
+                    key::MAX_SEQUENCE_NUMBER,
+                    read_options,
+                    &mut None,
+                    |seq, value_type, value, _| {
+                        versions.push((seq, value_type, value.to_vec()));
+                        value_type != ValueType::Merge
+                    },
+                )?;
+
+                let row = Arc::new(Row { versions });
+                if read_options.fill_cache {
+                    cache.insert(id, user_key, row.clone());
+                }
+                row
+            }
+        };
+
+        for (seq, value_type, value) in &row.versions {
+            let result = ctx.add_version(*seq, *value_type, value, None);
+
+            if result.is_some() {
+                return Ok(result);
+            }
+        }
+
+        Ok(ctx.source_exhausted())
+    }
+
+    /// Same as [Table::get_with_context] for several keys, sorted in ascending order, each with
+    /// its own context
+    ///
+    /// The filter is checked for every key upfront, and the keys falling in the same data block
+    /// share a single read of the block.
+    pub fn multi_get_with_context(
+        &self,
+        user_keys: &[&[u8]],
+        seq: SequenceNumber,
+        ctxs: &mut [GetContext],
+        read_options: &ReadOptions,
+    ) -> Result<Vec<Option<LookupResult>>, TableError> {
+        let may_contain: Vec<_> = user_keys
+            .iter()
+            .map(|user_key| self.may_contain(user_key))
+            .collect();
+
+        let mut cached_block = None;
+        let mut results = Vec::with_capacity(user_keys.len());
+
+        for ((user_key, ctx), may_contain) in user_keys.iter().zip(ctxs).zip(may_contain) {
+            ctx.add_tombstone_seq(
+                self.fragmented_range_tombstones
+                    .max_covering_seq(user_key, seq),
+            );
+
+            results.push(if may_contain {
+                self.get_from_blocks(user_key, seq, ctx, read_options, &mut cached_block)?
+            } else {
+                ctx.source_exhausted()
+            });
+        }
+
+        Ok(results)
+    }
+
+    /// Walks the versions of `user_key` visible at `seq` in the data blocks
+    ///
+    /// `cached_block` holds the last data block read along with the offset of its index entry,
+    /// and is reused if the lookup falls in the same block.
+    fn get_from_blocks(
+        &self,
+        user_key: &[u8],
+        seq: SequenceNumber,
+        ctx: &mut GetContext,
+        read_options: &ReadOptions,
+        cached_block: &mut Option<(u32, Arc<BlockBuffer>)>,
+    ) -> Result<Option<LookupResult>, TableError> {
+        let mut result = None;
+
+        self.walk_versions(
+            user_key,
+            seq,
+            read_options,
+            cached_block,
+            |entry_seq, value_type, value, block| {
+                result = ctx.add_version(entry_seq, value_type, value, Some(block));
+                result.is_some()
+            },
+        )?;
+
+        Ok(result.or_else(|| ctx.source_exhausted()))
+    }
+
+    /// Hands the versions of `user_key` visible at `seq` to `f` along with their data block, from
+    /// the newest, until it returns true, see [Table::get_from_blocks]
+    fn walk_versions<F: FnMut(SequenceNumber, ValueType, &[u8], &Arc<BlockBuffer>) -> bool>(
+        &self,
+        user_key: &[u8],
+        seq: SequenceNumber,
+        read_options: &ReadOptions,
+        cached_block: &mut Option<(u32, Arc<BlockBuffer>)>,
+        mut f: F,
+    ) -> Result<(), TableError> {
+        let target = key::seek_key(user_key, seq);
+
+        let index_offset = match self.find_data_block(&target)? {
+            Some((_, index_offset)) => index_offset,
+            None => return Ok(()),
+        };
+
+        // The versions of the key may span several blocks when merge operands pile up
+        let index = self.index()?;
+        let mut index_entries = index.block().iter_from(index_offset);
+        let mut first_block = true;
+
+        loop {
+            let entry_offset = index_entries.offset();
+            let index_entry = match index_entries.next() {
+                Some(index_entry) => index_entry,
+                None => break,
+            };
+
+            let buffer = match cached_block {
+                Some((offset, buffer)) if *offset == entry_offset => buffer,
+                _ => {
+                    let buffer = self.read_block(
+                        BlockHandle::decode(index_entry.value())?,
+                        read_options.verify_checksums,
+                        read_options.fill_cache,
+                    )?;
+                    &cached_block.insert((entry_offset, buffer)).1
+                }
+            };
+            let block = buffer.block();
+            let offset = if first_block {
+                block.binary_search(|key| key::compare(key, &target))
+            } else {
+                0
+            };
+            first_block = false;
+
+            for entry in block.iter_from(offset) {
+                if key::compare(entry.key(), &target) == Ordering::Less {
+                    continue;
+                }
+
+                match key::parse(entry.key()) {
+                    Some((key, entry_seq, value_type)) if key == user_key => {
+                        if f(entry_seq, value_type, entry.value(), buffer) {
+                            return Ok(());
+                        }
+                    }
+                    Some(_) => return Ok(()),
+                    None => return Err(TableError::Corruption("bad internal key")),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an iterator over the entries of the table, initially not positioned
+    pub fn iter(self: &Arc<Self>) -> TableIterator {
+        TableIterator {
+            table: self.clone(),
+            block: None,
+            block_last_key: Vec::new(),
+            upper_bound: None,
+            verify_checksums: true,
+            fill_cache: true,
+            readahead_size: 0,
+            readahead: None,
+            rate_limiter: None,
+            index_offset: 0,
+            next_index_offset: 0,
+            entry_offsets: Vec::new(),
+            offset: 0,
+            next_offset: 0,
+            key: Vec::new(),
+            value: Vec::new(),
+        }
+    }
+}
+
+/// Reads a block from `file`, verifying its checksum and decompressing it
+fn read_block_contents(file: &File, handle: BlockHandle) -> Result<Vec<u8>, TableError> {
+    let mut contents = vec![0_u8; handle.size as usize + BLOCK_TRAILER_SIZE];
+    file.read_exact_at(&mut contents, handle.offset)?;
+
+    decode_block_contents(contents, true)
+}
+
+/// Splits the trailer off a block read along with it, then decompresses the block, verifying
+/// its checksum first if `verify_checksum`
+fn decode_block_contents(
+    mut contents: Vec<u8>,
+    verify_checksum: bool,
+) -> Result<Vec<u8>, TableError> {
+    let trailer = contents.split_off(contents.len() - BLOCK_TRAILER_SIZE);
+
+    if verify_checksum {
+        let expected_checksum = u32::from_le_bytes(trailer[1..].try_into().unwrap());
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&contents);
+        hasher.update(&trailer[..1]);
+
+        if hasher.finalize() != expected_checksum {
+            Err(TableError::Corruption("block checksum mismatch"))?
+        }
+    }
+
+    match trailer[0] {
+        NO_COMPRESSION => Ok(contents),
+        LZ4_COMPRESSION => {
+            let _timer = perf_context::timer(|ctx, elapsed| ctx.decompress_time += elapsed);
+            let decompressed = lz4_flex::decompress_size_prepended(&contents)
+                .map_err(|_| TableError::Corruption("bad compressed block"))?;
+
+            perf_context::record(|ctx| ctx.bytes_decompressed += decompressed.len() as u64);
+
+            Ok(decompressed)
+        }
+        ZSTD_COMPRESSION => {
+            let _timer = perf_context::timer(|ctx, elapsed| ctx.decompress_time += elapsed);
+            let decompressed = zstd::stream::decode_all(contents.as_slice())
+                .map_err(|_| TableError::Corruption("bad compressed block"))?;
+
+            perf_context::record(|ctx| ctx.bytes_decompressed += decompressed.len() as u64);
+
+            Ok(decompressed)
+        }
+        _ => Err(TableError::Corruption("unknown compression type")),
+    }
+}
+
+/// Returns the offset of every entry of `block`, in order
+fn entry_offsets(block: &Block) -> Vec<u32> {
+    let mut offsets = Vec::with_capacity(block.len() as usize);
+    let mut entries = block.iter_from(0);
+
+    loop {
+        let offset = entries.offset();
+
+        if entries.next().is_none() {
+            return offsets;
+        }
+
+        offsets.push(offset);
+    }
+}
+
+/// A cursor over the entries of a [Table], in internal key order
+pub struct TableIterator {
+    table: Arc<Table>,
+    /// The current data block, None once the iterator is exhausted
+    block: Option<Arc<BlockBuffer>>,
+    /// Last key of the current data block, as stored in the index
+    block_last_key: Vec<u8>,
+    /// User key before which the iteration stops, see [TableIterator::with_upper_bound]
+    upper_bound: Option<Vec<u8>>,
+    verify_checksums: bool,
+    /// Whether the data blocks read are added to the block cache, see
+    /// [TableIterator::with_fill_cache]
+    fill_cache: bool,
+    /// Minimum number of bytes read at once, see [TableIterator::with_readahead_size]
+    readahead_size: usize,
+    /// Offset in the file and contents of the last read ahead
+    readahead: Option<(u64, Vec<u8>)>,
+    /// Charged for the reads ahead, see [TableIterator::with_rate_limiter]
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Offset in the index block of the entry of the current data block
+    index_offset: u32,
+    /// Offset in the index block of the entry following the current data block
+    next_index_offset: u32,
+    /// Offsets of the entries of the current data block, only computed when moving backwards
+    entry_offsets: Vec<u32>,
+    /// Offset in the current data block of the current entry
+    offset: u32,
+    /// Offset in the current data block of the entry following the current one
+    next_offset: u32,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl TableIterator {
+    /// Stops the iterator before the first user key >= `upper_bound`, so that data blocks
+    /// entirely past it are never read
+    ///
+    /// Only forward scans are cut short: moving backwards ignores the bound.
+    pub fn with_upper_bound(mut self, upper_bound: Option<&[u8]>) -> TableIterator {
+        self.upper_bound = upper_bound.map(<[u8]>::to_vec);
+        self
+    }
+
+    /// Only verifies the checksums of the data blocks if `verify_checksums`
+    pub fn with_verify_checksums(mut self, verify_checksums: bool) -> TableIterator {
+        self.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Only adds the data blocks read to the block cache of the table if `fill_cache`, e.g. not
+    /// for scans which would evict the blocks read often
+    pub fn with_fill_cache(mut self, fill_cache: bool) -> TableIterator {
+        self.fill_cache = fill_cache;
+        self
+    }
+
+    /// Reads the data blocks by chunks of at least `readahead_size` bytes, which speeds up long
+    /// scans, instead of one at a time if 0
+    pub fn with_readahead_size(mut self, readahead_size: usize) -> TableIterator {
+        self.readahead_size = readahead_size;
+        self
+    }
+
+    /// Waits for `rate_limiter` before each read ahead, if it [limits the
+    /// reads](RateLimiter::limits_reads)
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<&Arc<RateLimiter>>) -> TableIterator {
+        self.rate_limiter = rate_limiter
+            .filter(|rate_limiter| rate_limiter.limits_reads())
+            .cloned();
+        self
+    }
+
+    /// Reads the data block at `handle`, from the block cache or from the last read ahead if
+    /// they hold the block
+    fn read_block(&mut self, handle: BlockHandle) -> Result<Arc<BlockBuffer>, TableError> {
+        if self.readahead_size == 0 {
+            return self
+                .table
+                .read_block(handle, self.verify_checksums, self.fill_cache);
+        }
+
+        if let Some(block) = self.table.cached_block(handle) {
+            return Ok(block);
+        }
+
+        let start = handle.offset;
+        let end = start + handle.size + BLOCK_TRAILER_SIZE as u64;
+
+        let covered = self.readahead.as_ref().is_some_and(|(offset, contents)| {
+            *offset <= start && end <= offset + contents.len() as u64
+        });
+
+        if !covered {
+            // Reading past the data blocks is harmless, as long as it stays in the file
+            let len = (end - start)
+                .max(self.readahead_size as u64)
+                .min(self.table.file_size - start);
+            let mut contents = vec![0_u8; len as usize];
+
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.request(len);
+            }
+
+            {
+                let _timer = perf_context::timer(|ctx, elapsed| {
+                    ctx.block_read_bytes += len;
+                    ctx.block_read_time += elapsed;
+                });
+
+                self.table.file.read_exact_at(&mut contents, start)?;
+            }
+
+            self.readahead = Some((start, contents));
+        }
+
+        perf_context::record(|ctx| ctx.block_read_count += 1);
+
+        let (offset, contents) = self.readahead.as_ref().unwrap();
+        let block = contents[(start - offset) as usize..(end - offset) as usize].to_vec();
+        let block = BlockBuffer::from_bytes(&decode_block_contents(block, self.verify_checksums)?)?;
+
+        Ok(self.table.cache_block(handle, block, self.fill_cache))
+    }
+
+    /// Loads the data block referenced by the index entry at `index_offset`, if any
+    fn load_block(&mut self, index_offset: u32) -> Result<(), TableError> {
+        let index = self.table.index()?;
+        let mut entries = index.block().iter_from(index_offset);
+
+        self.block = match entries.next() {
+            Some(entry) => {
+                self.block_last_key.clear();
+                self.block_last_key.extend_from_slice(entry.key());
+
+                Some(self.read_block(BlockHandle::decode(entry.value())?)?)
+            }
+            None => None,
+        };
+        self.index_offset = index_offset;
+        self.next_index_offset = entries.offset();
+        self.entry_offsets.clear();
+
+        Ok(())
+    }
+
+    /// Reads the entry at `offset`, moving to the following blocks if the current one is over
+    fn read_entry(&mut self, mut offset: u32) -> Result<(), TableError> {
+        loop {
+            let block = match &self.block {
+                Some(block) => block.block(),
+                None => return Ok(()),
+            };
+
+            let mut entries = block.iter_from(offset);
+
+            if let Some(entry) = entries.next() {
+                self.key.clear();
+                self.key.extend_from_slice(entry.key());
+                self.value.clear();
+                self.value.extend_from_slice(entry.value());
+
+                self.offset = offset;
+                self.next_offset = entries.offset();
+
+                return Ok(());
+            }
+
+            // The following blocks only hold keys past the last one of the current block
+            let past_upper_bound = self
+                .upper_bound
+                .as_deref()
+                .is_some_and(|upper| key::user_key(&self.block_last_key) >= upper);
+
+            if past_upper_bound {
+                self.block = None;
+                return Ok(());
+            }
+
+            self.load_block(self.next_index_offset)?;
+            offset = 0;
+        }
+    }
+
+    /// Reads the last entry of the data block referenced by the index entry at `index_offset`,
+    /// or invalidates the iterator if None
+    fn read_last_entry(&mut self, index_offset: Option<u32>) -> Result<(), TableError> {
+        match index_offset {
+            Some(index_offset) => self.load_block(index_offset)?,
+            None => self.block = None,
+        }
+
+        if let Some(block) = &self.block {
+            self.entry_offsets = entry_offsets(block.block());
+        }
+
+        match self.entry_offsets.last() {
+            Some(&offset) => self.read_entry(offset),
+            None => {
+                self.block = None;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl InternalIterator for TableIterator {
+    fn valid(&self) -> bool {
+        self.block.is_some()
+    }
+
+    fn seek_to_first(&mut self) -> Result<(), DbError> {
+        self.load_block(0)?;
+        Ok(self.read_entry(0)?)
+    }
+
+    fn seek_to_last(&mut self) -> Result<(), DbError> {
+        let last = entry_offsets(self.table.index()?.block()).last().copied();
+
+        Ok(self.read_last_entry(last)?)
+    }
+
+    fn seek(&mut self, target: &[u8]) -> Result<(), DbError> {
+        let index_offset = match self.table.find_data_block(target)? {
+            Some((_, index_offset)) => index_offset,
+            None => {
+                self.block = None;
+                return Ok(());
+            }
+        };
+
+        self.load_block(index_offset)?;
+
+        let offset = match &self.block {
+            Some(block) => block.block().binary_search(|key| key::compare(key, target)),
+            None => return Ok(()),
+        };
+
+        self.read_entry(offset)?;
+
+        while self.valid() && key::compare(&self.key, target) == Ordering::Less {
+            self.next()?;
+        }
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), DbError> {
+        Ok(self.read_entry(self.next_offset)?)
+    }
+
+    fn prev(&mut self) -> Result<(), DbError> {
+        let block = match &self.block {
+            Some(block) => block.block(),
+            None => return Ok(()),
+        };
+
+        if self.entry_offsets.is_empty() {
+            self.entry_offsets = entry_offsets(block);
+        }
+
+        let position = self.entry_offsets.binary_search(&self.offset).unwrap_or(0);
+
+        if position > 0 {
+            return Ok(self.read_entry(self.entry_offsets[position - 1])?);
+        }
+
+        // First entry of the block: moves to the end of the previous one
+        let index_offsets = entry_offsets(self.table.index()?.block());
+        let previous = match index_offsets.binary_search(&self.index_offset) {
+            Ok(position) if position > 0 => Some(index_offsets[position - 1]),
+            _ => None,
+        };
+
+        Ok(self.read_last_entry(previous)?)
+    }
+
+    fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    fn value(&self) -> &[u8] {
+        &self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iterator::InternalIterator;
+    use crate::key::{self, ValueType};
+    use crate::memtable::LookupResult;
+    use crate::options::{ColumnFamilyOptions, CompressionType};
+    use crate::prefix::FixedPrefix;
+    use crate::table::{table_file_name, EntryDump, Table, TableBuilder};
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::fs::FileExt;
+    use std::sync::Arc;
+
+    fn build_table(dir: &std::path::Path, entries: u32) -> Arc<Table> {
+        let path = table_file_name(dir, 1);
+        let options = ColumnFamilyOptions {
+            block_size: 256,
+            ..ColumnFamilyOptions::default()
+        };
+
+        let mut builder = TableBuilder::new(File::create(&path).unwrap(), &options);
+
+        for n in 0..entries {
+            let user_key = format!("key{:05}", n);
+
+            builder
+                .add(
+                    &key::encode(user_key.as_bytes(), 2 * n as u64 + 2, ValueType::Value),
+                    user_key.as_bytes(),
+                )
+                .unwrap();
+            builder
+                .add(
+                    &key::encode(user_key.as_bytes(), 2 * n as u64 + 1, ValueType::Deletion),
+                    b"",
+                )
+                .unwrap();
+        }
+
+        let properties = builder.finish().unwrap();
+
+        assert_eq!(properties.num_entries, 2 * entries as u64);
+        assert!(properties.num_data_blocks > 1);
+
+        Arc::new(Table::open(&path, 1).unwrap())
+    }
+
+    #[test]
+    fn get_finds_visible_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = build_table(dir.path(), 500);
+
+        assert_eq!(
+            table.get(b"key00042", 1000).unwrap(),
+            Some(LookupResult::Value(b"key00042".to_vec()))
+        );
+        assert_eq!(
+            table.get(b"key00042", 85).unwrap(),
+            Some(LookupResult::Deleted)
+        );
+        assert_eq!(table.get(b"key00042", 84).unwrap(), None);
+        assert_eq!(table.get(b"key99999", 1000).unwrap(), None);
+        assert_eq!(table.properties().num_deletions, 500);
+        assert_eq!(table.properties().largest_seqno, 1000);
+    }
+
+    #[test]
+    fn iterator_seeks_and_scans() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = build_table(dir.path(), 500);
+
+        let mut iter = table.iter();
+        iter.seek_to_first().unwrap();
+
+        let mut count = 0;
+
+        while iter.valid() {
+            count += 1;
+            iter.next().unwrap();
+        }
+
+        assert_eq!(count, 1000);
+
+        iter.seek(&key::seek_key(b"key00250", 0)).unwrap();
+
+        assert_eq!(key::user_key(iter.key()), b"key00251");
+        assert_eq!(iter.value(), b"key00251");
+
+        iter.seek(&key::seek_key(b"key00499", 0)).unwrap();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn iterator_scans_backwards() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = build_table(dir.path(), 500);
+
+        let mut iter = table.iter();
+        iter.seek_to_last().unwrap();
+
+        let mut count = 0;
+        let mut last_key = Vec::new();
+
+        while iter.valid() {
+            if !last_key.is_empty() {
+                assert_eq!(
+                    key::compare(iter.key(), &last_key),
+                    std::cmp::Ordering::Less
+                );
+            }
+
+            last_key = iter.key().to_vec();
+            count += 1;
+            iter.prev().unwrap();
+        }
+
+        assert_eq!(count, 1000);
+
+        iter.seek_for_prev(&key::seek_key(b"key00250", 0)).unwrap();
+
+        assert_eq!(
+            key::parse(iter.key()),
+            Some((&b"key00250"[..], 501, ValueType::Deletion))
+        );
+
+        iter.seek_for_prev(&key::seek_key(b"key", 0)).unwrap();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn filter_holds_prefixes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = table_file_name(dir.path(), 1);
+        let options = ColumnFamilyOptions {
+            prefix_extractor: Some(Arc::new(FixedPrefix(4))),
+            ..ColumnFamilyOptions::default()
+        };
+
+        let mut builder = TableBuilder::new(File::create(&path).unwrap(), &options);
+
+        for n in 0..100_u32 {
+            let user_key = format!("ab{:02}{:03}", n / 10, n);
+
+            builder
+                .add(&key::encode(user_key.as_bytes(), 1, ValueType::Value), b"")
+                .unwrap();
+        }
+
+        builder.finish().unwrap();
+
+        let table = Table::open(&path, 1).unwrap();
+
+        assert!(table.prefix_may_match(&FixedPrefix(4), b"ab07"));
+        assert!(!table.prefix_may_match(&FixedPrefix(4), b"zz07"));
+        // Tables built with another extractor can't rule out anything
+        assert!(table.prefix_may_match(&FixedPrefix(3), b"zz0"));
+    }
+
+    #[test]
+    fn compressed_blocks_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut data_sizes = Vec::new();
+
+        for (number, compression) in [
+            (1, CompressionType::None),
+            (2, CompressionType::Lz4),
+            (3, CompressionType::Zstd),
+        ] {
+            let path = table_file_name(dir.path(), number);
+            let options = ColumnFamilyOptions::default().with_compression(compression);
+            let mut builder = TableBuilder::new(File::create(&path).unwrap(), &options);
+
+            for n in 0..1000_u32 {
+                builder
+                    .add(
+                        &key::encode(&n.to_be_bytes(), 1, ValueType::Value),
+                        &[n as u8; 100],
+                    )
+                    .unwrap();
+            }
+
+            data_sizes.push(builder.finish().unwrap().data_size);
+
+            let table = Table::open(&path, number).unwrap();
+            assert_eq!(
+                table.get(&500_u32.to_be_bytes(), 1).unwrap(),
+                Some(LookupResult::Value(vec![500_u32 as u8; 100]))
+            );
+        }
+
+        assert!(data_sizes[1] < data_sizes[0] / 4);
+    }
+
+    #[test]
+    fn dumps_report_the_blocks_they_cannot_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let table = build_table(dir.path(), 500);
+        let dump = table.dump().unwrap();
+
+        assert_eq!(dump.blocks.len() as u64, dump.properties.num_data_blocks);
+        assert_eq!(
+            dump.blocks
+                .iter()
+                .map(|block| block.entries.len())
+                .sum::<usize>(),
+            1000
+        );
+        assert_eq!(
+            dump.blocks[0].entries[..2],
+            [
+                EntryDump {
+                    user_key: b"key00000".to_vec(),
+                    sequence: 2,
+                    value_type: ValueType::Value,
+                    value: b"key00000".to_vec(),
+                },
+                EntryDump {
+                    user_key: b"key00000".to_vec(),
+                    sequence: 1,
+                    value_type: ValueType::Deletion,
+                    value: Vec::new(),
+                },
+            ]
+        );
+        assert!(dump.blocks.iter().all(|block| {
+            let last = block.entries.last().unwrap();
+            block.index_key == key::encode(&last.user_key, last.sequence, last.value_type)
+                && block.compression == Some(CompressionType::None)
+                && block.error.is_none()
+        }));
+        assert!(dump.filter.probes > 0);
+        assert!(dump.filter.bits_set > 0 && dump.filter.bits_set < dump.filter.bits);
+
+        // Flips a byte of the second data block
+        let path = table_file_name(dir.path(), 1);
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let offset = dump.blocks[1].handle.offset + 10;
+        file.write_all_at(&[0xff], offset).unwrap();
+
+        let dump = Table::open(&path, 1).unwrap().dump().unwrap();
+        assert_eq!(
+            dump.blocks[1].error.as_deref(),
+            Some("Corrupted table: block checksum mismatch")
+        );
+        assert!(dump.blocks[1].entries.is_empty());
+        assert!(dump.blocks[2].error.is_none());
+
+        let json: serde_json::Value = serde_json::from_str(&dump.to_json()).unwrap();
+        let first_entry = &json["blocks"][0]["entries"][0];
+        assert_eq!(first_entry["user_key"], "6b65793030303030");
+        assert_eq!(first_entry["type"], "Value");
+        assert_eq!(
+            json["blocks"][1]["error"],
+            "Corrupted table: block checksum mismatch"
+        );
+        assert_eq!(json["blocks"][2]["error"], serde_json::Value::Null);
+        assert_eq!(json["properties"]["num_entries"], 1000);
+        assert_eq!(
+            json["properties"]["smallest_key"]["user_key"],
+            "6b65793030303030"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::batch::BatchOp;
+use crate::db::DbError;
+use crate::db_iter::DbIterator;
+use crate::key::{self, SequenceNumber, ValueType};
+
+/// Timestamp of a version of a key in a column family with
+/// [ColumnFamilyOptions::user_timestamps](crate::ColumnFamilyOptions::user_timestamps), chosen by
+/// the application
+pub type Timestamp = u64;
+
+/// Marks the end of the escaped user key, before the timestamp
+const KEY_TERMINATOR: [u8; 2] = [0x00, 0x01];
+
+/// Follows every 0x00 byte of the user key, so that it can't be mistaken for the terminator
+const ESCAPED_ZERO: u8 = 0xff;
+
+const VALUE_TAG: u8 = 1;
+const DELETION_TAG: u8 = 0;
+
+/// Encodes the start of the keys of every version of `user_key`, which sorts like `user_key`
+/// among the keys of the other user keys
+pub fn key_prefix(user_key: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(user_key.len() + KEY_TERMINATOR.len());
+
+    for &byte in user_key {
+        prefix.push(byte);
+
+        if byte == 0x00 {
+            prefix.push(ESCAPED_ZERO);
+        }
+    }
+
+    prefix.extend_from_slice(&KEY_TERMINATOR);
+
+    prefix
+}
+
+/// Encodes the key of the version of `user_key` written at `ts`
+///
+/// The memory layout is:
+/// [ escaped_user_key, 0x00, 0x01, !ts ]
+/// where the 0x00 bytes of the user key are followed by 0xff, and !ts is a big-endian u64: the
+/// keys sort by user key, then by timestamp descending.
+pub fn encode_key(user_key: &[u8], ts: Timestamp) -> Vec<u8> {
+    let mut key = key_prefix(user_key);
+    key.extend_from_slice(&(!ts).to_be_bytes());
+
+    key
+}
+
+/// Splits a key encoded by [encode_key], returning None if it's not one
+pub fn decode_key(key: &[u8]) -> Option<(Vec<u8>, Timestamp)> {
+    let split = key.len().checked_sub(size_of::<Timestamp>())?;
+    let (escaped, ts) = key.split_at(split);
+    let escaped = escaped.strip_suffix(&KEY_TERMINATOR)?;
+
+    let mut user_key = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.iter();
+
+    while let Some(&byte) = bytes.next() {
+        if byte == 0x00 && bytes.next() != Some(&ESCAPED_ZERO) {
+            return None;
+        }
+
+        user_key.push(byte);
+    }
+
+    let ts = !Timestamp::from_be_bytes(ts.try_into().unwrap());
+
+    Some((user_key, ts))
+}
+
+/// Encodes the value of a version, None standing for a deletion: deleting a key at a timestamp
+/// is writing a version hiding the older ones
+pub(crate) fn encode_value(value: Option<&[u8]>) -> Vec<u8> {
+    match value {
+        Some(value) => [&[VALUE_TAG], value].concat(),
+        None => vec![DELETION_TAG],
+    }
+}
+
+/// Decodes a value encoded by [encode_value], returning None if it's not one
+fn decode_value(value: &[u8]) -> Option<Option<&[u8]>> {
+    match value.split_first()? {
+        (&VALUE_TAG, value) => Some(Some(value)),
+        (&DELETION_TAG, []) => Some(None),
+        _ => None,
+    }
+}
+
+/// Tells whether `op` is a write of a column family with user timestamps, as made by
+/// [WriteBatch::put_cf_with_ts](crate::batch::WriteBatch::put_cf_with_ts) and
+/// [WriteBatch::delete_cf_with_ts](crate::batch::WriteBatch::delete_cf_with_ts)
+pub(crate) fn is_timestamped(op: &BatchOp) -> bool {
+    op.value_type == ValueType::Value
+        && decode_key(op.key).is_some()
+        && decode_value(op.value).is_some()
+}
+
+/// Picks the versions of the keys of a column family with user timestamps which no read can see
+/// anymore, for flushes and compactions to drop them
+///
+/// The reads as of timestamps before the horizon are refused, so the most recent version of a
+/// key written at or before the horizon hides its older versions from every read, as soon as
+/// every snapshot sees it.
+#[derive(Clone)]
+pub(crate) struct HistoryTrimmer {
+    full_history_ts_low: Timestamp,
+    /// Versions written after it are invisible to some snapshots
+    oldest_snapshot: SequenceNumber,
+    /// User key whose remaining versions are dropped
+    trimmed_key: Option<Vec<u8>>,
+}
+
+impl HistoryTrimmer {
+    pub(crate) fn new(
+        full_history_ts_low: Timestamp,
+        oldest_snapshot: SequenceNumber,
+    ) -> HistoryTrimmer {
+        HistoryTrimmer {
+            full_history_ts_low,
+            oldest_snapshot,
+            trimmed_key: None,
+        }
+    }
+
+    /// Tells whether the entry with `internal_key` is kept, given the entries in order
+    pub(crate) fn keep(&mut self, internal_key: &[u8]) -> bool {
+        let Some((stored_key, seq, _)) = key::parse(internal_key) else {
+            return true;
+        };
+        let Some((user_key, ts)) = decode_key(stored_key) else {
+            return true;
+        };
+
+        if self.trimmed_key.as_ref() == Some(&user_key) {
+            return false;
+        }
+
+        if ts <= self.full_history_ts_low && seq <= self.oldest_snapshot {
+            self.trimmed_key = Some(user_key);
+        }
+
+        true
+    }
+}
+
+/// Iterates the keys of a column family with user timestamps, as of a timestamp: each key is
+/// returned once, with its most recent version written at or before the timestamp, unless it's
+/// a deletion
+///
+/// Only moves forward.
+pub struct TimestampedIterator {
+    iter: DbIterator,
+    /// Versions written after it are skipped
+    read_ts: Timestamp,
+    valid: bool,
+    key: Vec<u8>,
+    ts: Timestamp,
+    value: Vec<u8>,
+}
+
+impl TimestampedIterator {
+    pub(crate) fn new(iter: DbIterator, read_ts: Timestamp) -> TimestampedIterator {
+        TimestampedIterator {
+            iter,
+            read_ts,
+            valid: false,
+            key: Vec::new(),
+            ts: 0,
+            value: Vec::new(),
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<(), DbError> {
+        self.iter.seek_to_first()?;
+        self.find_visible_version()
+    }
+
+    /// Moves to the first key at or after `target`
+    pub fn seek(&mut self, target: &[u8]) -> Result<(), DbError> {
+        self.iter.seek(&encode_key(target, self.read_ts))?;
+        self.find_visible_version()
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<(), DbError> {
+        let key = std::mem::take(&mut self.key);
+
+        self.skip_versions(&key)?;
+        self.find_visible_version()
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the timestamp the current value was written at
+    pub fn timestamp(&self) -> Timestamp {
+        self.ts
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Moves the underlying iterator past the remaining versions of `user_key`
+    fn skip_versions(&mut self, user_key: &[u8]) -> Result<(), DbError> {
+        let oldest = encode_key(user_key, 0);
+
+        self.iter.seek(&oldest)?;
+
+        if self.iter.valid() && self.iter.key() == oldest {
+            self.iter.next()?;
+        }
+
+        Ok(())
+    }
+
+    /// Positions the iterator at the first visible version from the position of the underlying
+    /// iterator onwards
+    fn find_visible_version(&mut self) -> Result<(), DbError> {
+        while self.iter.valid() {
+            let (key, ts) = decode_key(self.iter.key())
+                .ok_or(DbError::Corruption("key without a timestamp"))?;
+
+            if ts > self.read_ts {
+                self.iter.seek(&encode_key(&key, self.read_ts))?;
+                continue;
+            }
+
+            match decode_value(self.iter.value()) {
+                Some(Some(value)) => {
+                    self.value = value.to_vec();
+                    self.key = key;
+                    self.ts = ts;
+                    self.valid = true;
+
+                    return Ok(());
+                }
+                Some(None) => self.skip_versions(&key)?,
+                None => return Err(DbError::Corruption("value without a timestamp tag")),
+            }
+        }
+
+        self.valid = false;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::timestamp::{decode_key, encode_key, key_prefix};
+
+    #[test]
+    fn keys_sort_by_user_key_then_newest_timestamp_first() {
+        let keys = [
+            encode_key(b"a", 2),
+            encode_key(b"a", 1),
+            encode_key(b"a\x00", 9),
+            encode_key(b"a\x00\x00", 9),
+            encode_key(b"a\x01", 9),
+            encode_key(b"ab", u64::MAX),
+            encode_key(b"ab", 0),
+        ];
+
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(key_prefix(b"ab") < encode_key(b"ab", u64::MAX));
+        assert_eq!(decode_key(&keys[3]), Some((b"a\x00\x00".to_vec(), 9)));
+        assert_eq!(
+            decode_key(b"a\x00\x02\x00\x00\x00\x00\x00\x00\x00\x00"),
+            None
+        );
+    }
+}
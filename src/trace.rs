@@ -0,0 +1,768 @@
+use crate::batch::WriteBatch;
+use crate::blob::BlobIndex;
+use crate::db::{Db, DbError};
+use crate::key::ValueType;
+use crate::options::ReadOptions;
+use integer_encoding::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Starts every trace file, followed by the version of the format
+const TRACE_MAGIC: &[u8; 8] = b"FYODORTR";
+const TRACE_VERSION: u32 = 1;
+
+const TAG_WRITE: u8 = 1;
+const TAG_GET: u8 = 2;
+const TAG_MULTI_GET: u8 = 3;
+const TAG_SEEK: u8 = 4;
+const TAG_SEEK_FOR_PREV: u8 = 5;
+
+const TAG_PUT: u8 = 1;
+const TAG_DELETE: u8 = 2;
+const TAG_MERGE: u8 = 3;
+const TAG_DELETE_RANGE: u8 = 4;
+
+/// A write of a [TraceOp::Write], whose values are only recorded by size
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TracedWrite {
+    Put {
+        column_family: u32,
+        key: Vec<u8>,
+        value_size: u64,
+    },
+    Delete {
+        column_family: u32,
+        key: Vec<u8>,
+    },
+    Merge {
+        column_family: u32,
+        key: Vec<u8>,
+        value_size: u64,
+    },
+    DeleteRange {
+        column_family: u32,
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+}
+
+/// An operation recorded in a trace, see [Db::start_trace]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    /// The writes of a batch, applied atomically
+    Write(Vec<TracedWrite>),
+    Get {
+        column_family: u32,
+        key: Vec<u8>,
+    },
+    MultiGet {
+        column_family: u32,
+        keys: Vec<Vec<u8>>,
+    },
+    /// A [DbIterator::seek](crate::DbIterator::seek), or a
+    /// [DbIterator::seek_to_first](crate::DbIterator::seek_to_first) with an empty key
+    Seek {
+        column_family: u32,
+        key: Vec<u8>,
+    },
+    SeekForPrev {
+        column_family: u32,
+        key: Vec<u8>,
+    },
+}
+
+impl TraceOp {
+    /// Returns the operation writing `batch`, the values of its writes replaced by their sizes
+    pub(crate) fn write(batch: &WriteBatch) -> TraceOp {
+        let writes = batch.iter().filter_map(Result::ok).map(|op| {
+            let column_family = op.column_family;
+            let key = op.key.to_vec();
+
+            match op.value_type {
+                ValueType::Deletion => TracedWrite::Delete { column_family, key },
+                ValueType::Merge => TracedWrite::Merge {
+                    column_family,
+                    key,
+                    value_size: op.value.len() as u64,
+                },
+                ValueType::RangeDeletion => TracedWrite::DeleteRange {
+                    column_family,
+                    start: key,
+                    end: op.value.to_vec(),
+                },
+                ValueType::BlobIndex => TracedWrite::Put {
+                    column_family,
+                    key,
+                    value_size: BlobIndex::decode(op.value).map_or(0, |index| index.size),
+                },
+                ValueType::Value | ValueType::ValueWithExpiry => TracedWrite::Put {
+                    column_family,
+                    key,
+                    value_size: op.value.len() as u64,
+                },
+            }
+        });
+
+        TraceOp::Write(writes.collect())
+    }
+}
+
+/// An operation of a trace, along with when and by which thread it was made
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Time since the start of the trace
+    pub timestamp: Duration,
+    /// Number of the thread which made the operation, the threads being numbered in the order
+    /// they first made an operation traced by the process
+    pub thread: u64,
+    pub op: TraceOp,
+}
+
+fn put_varint<V: VarInt>(buffer: &mut Vec<u8>, value: V) {
+    buffer.extend_from_slice(&value.encode_var_vec());
+}
+
+fn put_slice(buffer: &mut Vec<u8>, slice: &[u8]) {
+    put_varint(buffer, slice.len());
+    buffer.extend_from_slice(slice);
+}
+
+fn encode_write(buffer: &mut Vec<u8>, write: &TracedWrite) {
+    match write {
+        TracedWrite::Put {
+            column_family,
+            key,
+            value_size,
+        } => {
+            buffer.push(TAG_PUT);
+            put_varint(buffer, *column_family);
+            put_slice(buffer, key);
+            put_varint(buffer, *value_size);
+        }
+        TracedWrite::Delete { column_family, key } => {
+            buffer.push(TAG_DELETE);
+            put_varint(buffer, *column_family);
+            put_slice(buffer, key);
+        }
+        TracedWrite::Merge {
+            column_family,
+            key,
+            value_size,
+        } => {
+            buffer.push(TAG_MERGE);
+            put_varint(buffer, *column_family);
+            put_slice(buffer, key);
+            put_varint(buffer, *value_size);
+        }
+        TracedWrite::DeleteRange {
+            column_family,
+            start,
+            end,
+        } => {
+            buffer.push(TAG_DELETE_RANGE);
+            put_varint(buffer, *column_family);
+            put_slice(buffer, start);
+            put_slice(buffer, end);
+        }
+    }
+}
+
+impl TraceRecord {
+    /// Encodes the record as a varint timestamp in microseconds, a varint thread number, and the
+    /// tag of the operation followed by its fields: varints for numbers, varint-prefixed byte
+    /// strings for keys, and a varint count before the elements of lists
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        put_varint(&mut buffer, self.timestamp.as_micros() as u64);
+        put_varint(&mut buffer, self.thread);
+
+        match &self.op {
+            TraceOp::Write(writes) => {
+                buffer.push(TAG_WRITE);
+                put_varint(&mut buffer, writes.len());
+
+                for write in writes {
+                    encode_write(&mut buffer, write);
+                }
+            }
+            TraceOp::Get { column_family, key } => {
+                buffer.push(TAG_GET);
+                put_varint(&mut buffer, *column_family);
+                put_slice(&mut buffer, key);
+            }
+            TraceOp::MultiGet {
+                column_family,
+                keys,
+            } => {
+                buffer.push(TAG_MULTI_GET);
+                put_varint(&mut buffer, *column_family);
+                put_varint(&mut buffer, keys.len());
+
+                for key in keys {
+                    put_slice(&mut buffer, key);
+                }
+            }
+            TraceOp::Seek { column_family, key } => {
+                buffer.push(TAG_SEEK);
+                put_varint(&mut buffer, *column_family);
+                put_slice(&mut buffer, key);
+            }
+            TraceOp::SeekForPrev { column_family, key } => {
+                buffer.push(TAG_SEEK_FOR_PREV);
+                put_varint(&mut buffer, *column_family);
+                put_slice(&mut buffer, key);
+            }
+        }
+
+        buffer
+    }
+
+    fn decode(data: &[u8]) -> Result<TraceRecord, DbError> {
+        let mut reader = RecordReader { data };
+        let timestamp = Duration::from_micros(reader.varint()?);
+        let thread = reader.varint()?;
+
+        let op = match reader.byte()? {
+            TAG_WRITE => {
+                let count: usize = reader.varint()?;
+                let mut writes = Vec::with_capacity(count.min(data.len()));
+
+                for _ in 0..count {
+                    let tag = reader.byte()?;
+                    let column_family = reader.varint()?;
+                    let key = reader.slice()?;
+
+                    writes.push(match tag {
+                        TAG_PUT => TracedWrite::Put {
+                            column_family,
+                            key,
+                            value_size: reader.varint()?,
+                        },
+                        TAG_MERGE => TracedWrite::Merge {
+                            column_family,
+                            key,
+                            value_size: reader.varint()?,
+                        },
+                        TAG_DELETE => TracedWrite::Delete { column_family, key },
+                        TAG_DELETE_RANGE => TracedWrite::DeleteRange {
+                            column_family,
+                            start: key,
+                            end: reader.slice()?,
+                        },
+                        _ => return Err(DbError::Corruption("bad trace write tag")),
+                    });
+                }
+
+                TraceOp::Write(writes)
+            }
+            TAG_GET => TraceOp::Get {
+                column_family: reader.varint()?,
+                key: reader.slice()?,
+            },
+            TAG_MULTI_GET => {
+                let column_family = reader.varint()?;
+                let count: usize = reader.varint()?;
+                let keys = (0..count)
+                    .map(|_| reader.slice())
+                    .collect::<Result<_, _>>()?;
+
+                TraceOp::MultiGet {
+                    column_family,
+                    keys,
+                }
+            }
+            TAG_SEEK => TraceOp::Seek {
+                column_family: reader.varint()?,
+                key: reader.slice()?,
+            },
+            TAG_SEEK_FOR_PREV => TraceOp::SeekForPrev {
+                column_family: reader.varint()?,
+                key: reader.slice()?,
+            },
+            _ => return Err(DbError::Corruption("bad trace record tag")),
+        };
+
+        Ok(TraceRecord {
+            timestamp,
+            thread,
+            op,
+        })
+    }
+}
+
+/// Reads the fields of an encoded [TraceRecord]
+struct RecordReader<'a> {
+    data: &'a [u8],
+}
+
+impl RecordReader<'_> {
+    fn varint<V: VarInt>(&mut self) -> Result<V, DbError> {
+        let (value, size) =
+            V::decode_var(self.data).ok_or(DbError::Corruption("bad trace varint"))?;
+        self.data = &self.data[size..];
+
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, DbError> {
+        let (&byte, rest) = self
+            .data
+            .split_first()
+            .ok_or(DbError::Corruption("trace record too short"))?;
+        self.data = rest;
+
+        Ok(byte)
+    }
+
+    fn slice(&mut self) -> Result<Vec<u8>, DbError> {
+        let len: usize = self.varint()?;
+        let slice = self
+            .data
+            .get(..len)
+            .ok_or(DbError::Corruption("trace field out of bounds"))?;
+        self.data = &self.data[len..];
+
+        Ok(slice.to_vec())
+    }
+}
+
+/// Hands a number to every thread making a traced operation
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static THREAD: u64 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Appends the operations made on a [Db] to a trace file, see [Db::start_trace]
+pub(crate) struct Tracer {
+    start: Instant,
+    /// The file, unless writing it failed, along with the error then
+    file: Mutex<Result<BufWriter<File>, Option<io::Error>>>,
+}
+
+impl Tracer {
+    /// Creates the trace file at `path`, replacing any file there
+    pub(crate) fn create(path: &Path) -> Result<Tracer, DbError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(TRACE_MAGIC)?;
+        file.write_all(&TRACE_VERSION.encode_var_vec())?;
+
+        Ok(Tracer {
+            start: Instant::now(),
+            file: Mutex::new(Ok(file)),
+        })
+    }
+
+    /// Appends `op` to the trace, made now by the current thread
+    ///
+    /// The operations don't fail because of their trace: the first error writing it stops the
+    /// trace, and is returned by [Tracer::finish].
+    pub(crate) fn record(&self, op: TraceOp) {
+        let record = TraceRecord {
+            timestamp: self.start.elapsed(),
+            thread: THREAD.with(|thread| *thread),
+            op,
+        };
+        let data = record.encode();
+
+        let mut file = self.file.lock().unwrap();
+
+        if let Ok(writer) = file.as_mut() {
+            let written = writer
+                .write_all(&data.len().encode_var_vec())
+                .and_then(|_| writer.write_all(&data));
+
+            if let Err(e) = written {
+                log::warn!("stopped tracing after failing to write the trace: {}", e);
+                *file = Err(Some(e));
+            }
+        }
+    }
+
+    /// Writes the end of the trace to its file
+    pub(crate) fn finish(&self) -> Result<(), DbError> {
+        let mut file = self.file.lock().unwrap();
+
+        match file.as_mut() {
+            Ok(writer) => {
+                writer.flush()?;
+                writer.get_ref().sync_all()?;
+                *file = Err(None);
+
+                Ok(())
+            }
+            Err(e) => match e.take() {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+/// Reads the records of a trace file written by [Db::start_trace], in the order they were made
+///
+/// A record cut short by a crash ends the trace.
+pub struct TraceReader {
+    file: BufReader<File>,
+    failed: bool,
+}
+
+impl TraceReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<TraceReader, DbError> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; TRACE_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+
+        if &magic != TRACE_MAGIC {
+            return Err(DbError::InvalidArgument("not a trace file"));
+        }
+
+        match read_varint::<u32>(&mut file)? {
+            Some(TRACE_VERSION) => Ok(TraceReader {
+                file,
+                failed: false,
+            }),
+            _ => Err(DbError::InvalidArgument("unsupported trace version")),
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<TraceRecord>, DbError> {
+        let Some(len) = read_varint::<usize>(&mut self.file)? else {
+            return Ok(None);
+        };
+
+        let mut data = Vec::new();
+        (&mut self.file).take(len as u64).read_to_end(&mut data)?;
+
+        if data.len() < len {
+            return Ok(None);
+        }
+
+        TraceRecord::decode(&data).map(Some)
+    }
+}
+
+/// Reads a varint from `reader`, returning None at the end of the data, even in the middle of
+/// the varint
+fn read_varint<V: VarInt>(reader: &mut impl Read) -> Result<Option<V>, DbError> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let mut byte = [0];
+
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+
+        bytes.push(byte[0]);
+
+        if byte[0] & 0x80 == 0 {
+            return V::decode_var(&bytes)
+                .map(|(value, _)| Some(value))
+                .ok_or(DbError::Corruption("bad trace varint"));
+        }
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<TraceRecord, DbError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let record = self.next_record().transpose();
+        self.failed = matches!(record, Some(Err(_)));
+
+        record
+    }
+}
+
+/// What a [Replayer] did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Number of operations replayed
+    pub operations: u64,
+    /// Number of threads the operations were replayed by, one per traced thread
+    pub threads: usize,
+    pub elapsed: Duration,
+}
+
+/// Makes the operations of a trace again on a [Db], e.g. a copy of the traced database made
+/// with [Db::checkpoint] right before the trace started, to benchmark it or reproduce a bug
+///
+/// The operations of every traced thread are made by a thread of their own, at the same time
+/// since the start of the replay as they were since the start of the trace, sped up by
+/// [Replayer::with_fast_forward]. The values written are filled with zeros, with the traced
+/// sizes, and the column families are found by id.
+pub struct Replayer<'a> {
+    db: &'a Db,
+    fast_forward: f64,
+}
+
+impl<'a> Replayer<'a> {
+    pub fn new(db: &'a Db) -> Replayer<'a> {
+        Replayer {
+            db,
+            fast_forward: 1.0,
+        }
+    }
+
+    /// Divides the time between the operations by `fast_forward`, infinity making them as fast
+    /// as possible
+    pub fn with_fast_forward(mut self, fast_forward: f64) -> Self {
+        self.fast_forward = fast_forward;
+        self
+    }
+
+    /// Replays the trace at `path`, and returns once every operation is made, or after the first
+    /// one which fails
+    pub fn replay<P: AsRef<Path>>(&self, path: P) -> Result<ReplayStats, DbError> {
+        if self.fast_forward.is_nan() || self.fast_forward <= 0.0 {
+            return Err(DbError::InvalidArgument("fast forward must be positive"));
+        }
+
+        let mut threads: BTreeMap<u64, Vec<TraceRecord>> = BTreeMap::new();
+
+        for record in TraceReader::open(path)? {
+            let record = record?;
+            threads.entry(record.thread).or_default().push(record);
+        }
+
+        let start = Instant::now();
+        let results: Vec<Result<u64, DbError>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = threads
+                .values()
+                .map(|records| scope.spawn(move || self.replay_thread(start, records)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        let mut stats = ReplayStats {
+            threads: threads.len(),
+            ..ReplayStats::default()
+        };
+
+        for result in results {
+            stats.operations += result?;
+        }
+
+        stats.elapsed = start.elapsed();
+
+        Ok(stats)
+    }
+
+    /// Makes the operations `records` of a traced thread, and returns how many were made
+    fn replay_thread(&self, start: Instant, records: &[TraceRecord]) -> Result<u64, DbError> {
+        for record in records {
+            let due = start + record.timestamp.div_f64(self.fast_forward);
+
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+
+            self.replay_op(&record.op)?;
+        }
+
+        Ok(records.len() as u64)
+    }
+
+    fn replay_op(&self, op: &TraceOp) -> Result<(), DbError> {
+        let cf = |id| {
+            self.db
+                .cf_handle_by_id(id)
+                .ok_or(DbError::InvalidArgument("traced column family not found"))
+        };
+
+        match op {
+            TraceOp::Write(writes) => {
+                let mut batch = WriteBatch::new();
+
+                for write in writes {
+                    match write {
+                        TracedWrite::Put {
+                            column_family,
+                            key,
+                            value_size,
+                        } => {
+                            batch.put_cf(&cf(*column_family)?, key, &vec![0; *value_size as usize])
+                        }
+                        TracedWrite::Delete { column_family, key } => {
+                            batch.delete_cf(&cf(*column_family)?, key)
+                        }
+                        TracedWrite::Merge {
+                            column_family,
+                            key,
+                            value_size,
+                        } => batch.merge_cf(
+                            &cf(*column_family)?,
+                            key,
+                            &vec![0; *value_size as usize],
+                        ),
+                        TracedWrite::DeleteRange {
+                            column_family,
+                            start,
+                            end,
+                        } => batch.delete_range_cf(&cf(*column_family)?, start, end),
+                    }
+                }
+
+                self.db.write(batch)
+            }
+            TraceOp::Get { column_family, key } => {
+                self.db.get_cf(&cf(*column_family)?, key).map(|_| ())
+            }
+            TraceOp::MultiGet {
+                column_family,
+                keys,
+            } => {
+                let keys: Vec<_> = keys.iter().map(Vec::as_slice).collect();
+
+                self.db
+                    .multi_get_cf(&cf(*column_family)?, &keys)
+                    .map(|_| ())
+            }
+            TraceOp::Seek { column_family, key } => self
+                .db
+                .iter_cf(&cf(*column_family)?, &ReadOptions::default())?
+                .seek(key),
+            TraceOp::SeekForPrev { column_family, key } => self
+                .db
+                .iter_cf(&cf(*column_family)?, &ReadOptions::default())?
+                .seek_for_prev(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Db, DbError};
+    use crate::merge_operators::BytesAppend;
+    use crate::options::{ColumnFamilyOptions, Options};
+    use crate::trace::{Replayer, TraceOp, TraceReader, TracedWrite};
+    use std::sync::Arc;
+
+    fn options() -> Options {
+        Options::default().with_default_cf_options(
+            ColumnFamilyOptions::default().with_merge_operator(Arc::new(BytesAppend::default())),
+        )
+    }
+
+    #[test]
+    fn traces_replay_the_workload() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = dir.path().join("trace");
+        let db = Db::open(dir.path().join("db"), options()).unwrap();
+        db.put(b"untraced", b"value").unwrap();
+        db.checkpoint(dir.path().join("replayed")).unwrap();
+
+        db.start_trace(&trace).unwrap();
+        assert!(matches!(
+            db.start_trace(&trace),
+            Err(DbError::InvalidArgument(_))
+        ));
+
+        db.put(b"a", b"value").unwrap();
+        db.merge(b"b", b"operand").unwrap();
+        db.delete(b"untraced").unwrap();
+        db.delete_range(b"x", b"z").unwrap();
+        db.get(b"a").unwrap();
+        db.multi_get(&[b"a", b"b"]).unwrap();
+        let mut iter = db.iter();
+        iter.seek(b"a").unwrap();
+        iter.seek_for_prev(b"b").unwrap();
+        iter.seek_to_first().unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| db.put(b"c", b"other thread").unwrap());
+        });
+
+        db.end_trace().unwrap();
+        assert!(matches!(db.end_trace(), Err(DbError::InvalidArgument(_))));
+        db.put(b"after", b"value").unwrap();
+
+        let records: Vec<_> = TraceReader::open(&trace)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let ops: Vec<_> = records.iter().map(|record| record.op.clone()).collect();
+        let key = |key: &[u8]| key.to_vec();
+
+        assert_eq!(
+            ops,
+            vec![
+                TraceOp::Write(vec![TracedWrite::Put {
+                    column_family: 0,
+                    key: key(b"a"),
+                    value_size: 5,
+                }]),
+                TraceOp::Write(vec![TracedWrite::Merge {
+                    column_family: 0,
+                    key: key(b"b"),
+                    value_size: 7,
+                }]),
+                TraceOp::Write(vec![TracedWrite::Delete {
+                    column_family: 0,
+                    key: key(b"untraced"),
+                }]),
+                TraceOp::Write(vec![TracedWrite::DeleteRange {
+                    column_family: 0,
+                    start: key(b"x"),
+                    end: key(b"z"),
+                }]),
+                TraceOp::Get {
+                    column_family: 0,
+                    key: key(b"a"),
+                },
+                TraceOp::MultiGet {
+                    column_family: 0,
+                    keys: vec![key(b"a"), key(b"b")],
+                },
+                TraceOp::Seek {
+                    column_family: 0,
+                    key: key(b"a"),
+                },
+                TraceOp::SeekForPrev {
+                    column_family: 0,
+                    key: key(b"b"),
+                },
+                TraceOp::Seek {
+                    column_family: 0,
+                    key: Vec::new(),
+                },
+                TraceOp::Write(vec![TracedWrite::Put {
+                    column_family: 0,
+                    key: key(b"c"),
+                    value_size: 12,
+                }]),
+            ]
+        );
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        assert_ne!(records[0].thread, records[9].thread);
+
+        let replayed = Db::open(dir.path().join("replayed"), options()).unwrap();
+        let stats = Replayer::new(&replayed)
+            .with_fast_forward(f64::INFINITY)
+            .replay(&trace)
+            .unwrap();
+
+        assert_eq!(stats.operations, 10);
+        assert_eq!(stats.threads, 2);
+        assert_eq!(replayed.get(b"a").unwrap(), Some(vec![0; 5]));
+        assert_eq!(replayed.get(b"b").unwrap(), Some(vec![0; 7]));
+        assert_eq!(replayed.get(b"c").unwrap(), Some(vec![0; 12]));
+        assert_eq!(replayed.get(b"untraced").unwrap(), None);
+        assert_eq!(replayed.get(b"after").unwrap(), None);
+    }
+}
@@ -0,0 +1,606 @@
+use crate::batch::{WriteBatch, WriteBatchWithIndex};
+use crate::column_family::ColumnFamily;
+use crate::db::{Db, DbError};
+use crate::key::SequenceNumber;
+use crate::memtable::LookupResult;
+use crate::options::{ReadOptions, TransactionOptions};
+use crate::snapshot::Snapshot;
+use std::collections::HashMap;
+
+/// What the reads of a [Transaction] see, and which concurrent writes fail its commit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// The transaction takes a snapshot when it starts: its reads are repeatable, and its commit
+    /// fails if one of the keys it wrote or read with [Transaction::get_for_update] was written
+    /// after the snapshot
+    #[default]
+    Snapshot,
+    /// The reads see the last committed writes, until [Transaction::set_snapshot] pins them:
+    /// the commit only fails if one of the keys the transaction wrote or read with
+    /// [Transaction::get_for_update] was written after the transaction first touched it
+    ReadCommitted,
+}
+
+/// A group of writes committed atomically, reading its own writes, and isolated from the
+/// concurrent ones as chosen by [TransactionOptions::isolation]
+///
+/// Transactions are optimistic by default: nothing is locked until the commit, which fails with
+/// [DbError::Conflict] if a key the transaction depends on was written by someone else in the
+/// meantime. [TransactionOptions::pessimistic] ones lock the keys as they write or read them for
+/// update instead, waiting for the other transactions holding them. Dropping the transaction
+/// discards its writes, unless it was prepared, and unlocks its keys.
+///
+/// A named transaction can take part in a two-phase commit: [Transaction::prepare] logs its
+/// writes, which then survive crashes until the transaction commits or rolls back, see
+/// [Db::prepared_transactions].
+pub struct Transaction<'a> {
+    db: &'a Db,
+    id: u64,
+    options: TransactionOptions,
+    /// The reads see this snapshot, if any, instead of the last writes
+    snapshot: Option<Snapshot>,
+    batch: WriteBatchWithIndex,
+    /// The keys written or read for update by column family, with the sequence number of the
+    /// last write they may have been seen at: a write after it is a conflict
+    tracked: HashMap<(u32, Vec<u8>), SequenceNumber>,
+    /// For every savepoint, the keys tracked since, which a rollback to it doesn't check anymore
+    savepoints: Vec<Vec<(u32, Vec<u8>)>>,
+    /// Identifies the transaction in the log once prepared
+    name: Option<String>,
+    prepared: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a Db, options: TransactionOptions) -> Transaction<'a> {
+        let snapshot = match options.isolation {
+            IsolationLevel::Snapshot => Some(db.snapshot()),
+            IsolationLevel::ReadCommitted => None,
+        };
+
+        Transaction {
+            db,
+            id: db.new_transaction_id(),
+            options,
+            snapshot,
+            batch: WriteBatchWithIndex::new(),
+            tracked: HashMap::new(),
+            savepoints: Vec::new(),
+            name: None,
+            prepared: false,
+        }
+    }
+
+    /// Returns the prepared transaction `name`, whose writes are `batch`, found in the log
+    pub(crate) fn recovered(
+        db: &'a Db,
+        name: String,
+        batch: WriteBatch,
+    ) -> Result<Transaction<'a>, DbError> {
+        Ok(Transaction {
+            db,
+            id: db.new_transaction_id(),
+            options: TransactionOptions::default(),
+            snapshot: None,
+            batch: WriteBatchWithIndex::from_batch(batch)?,
+            tracked: HashMap::new(),
+            savepoints: Vec::new(),
+            name: Some(name),
+            prepared: true,
+        })
+    }
+
+    /// Names the transaction, which it needs to be prepared
+    ///
+    /// The name must be unique among the prepared transactions.
+    pub fn set_name(&mut self, name: &str) -> Result<(), DbError> {
+        if self.prepared {
+            return Err(DbError::InvalidArgument("transaction is already prepared"));
+        }
+
+        self.name = Some(name.to_string());
+
+        Ok(())
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns true once [Transaction::prepare] succeeded
+    pub fn is_prepared(&self) -> bool {
+        self.prepared
+    }
+
+    /// Returns the id of the transaction, unique among the ones of the database, as found in a
+    /// [DeadlockInfo](crate::DeadlockInfo)
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn isolation(&self) -> IsolationLevel {
+        self.options.isolation
+    }
+
+    /// Returns the snapshot the reads see, if any
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    /// Takes a new snapshot, which the next reads see, and against which the keys touched from
+    /// now on are checked at commit
+    ///
+    /// The keys touched before keep their check: this makes a [IsolationLevel::ReadCommitted]
+    /// transaction's reads repeatable from now on.
+    pub fn set_snapshot(&mut self) {
+        self.snapshot = Some(self.db.snapshot());
+    }
+
+    /// Drops the snapshot of the transaction, if any: the next reads see the last committed
+    /// writes, like the ones of [IsolationLevel::ReadCommitted]
+    pub fn clear_snapshot(&mut self) {
+        self.snapshot = None;
+    }
+
+    /// Returns the value of `key` as seen by the transaction: its own writes first, then the
+    /// database as of its snapshot
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        self.get_cf(&self.db.default_cf(), key)
+    }
+
+    /// Same as [Transaction::get], in the column family `cf`
+    pub fn get_cf(&self, cf: &ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        let read_options = ReadOptions {
+            snapshot: self.snapshot.clone(),
+            ..ReadOptions::default()
+        };
+
+        self.read(cf, key, &read_options)
+    }
+
+    /// Same as [Transaction::get], also failing the commit if `key` is written by someone else
+    /// after this read, or locking it for a pessimistic transaction
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, DbError> {
+        self.get_for_update_cf(&self.db.default_cf(), key)
+    }
+
+    /// Same as [Transaction::get_for_update], in the column family `cf`
+    pub fn get_for_update_cf(
+        &mut self,
+        cf: &ColumnFamily,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        // The read and the check at commit see the same sequence number
+        let seq = self.track(cf, key)?;
+        let read_options = ReadOptions {
+            snapshot: self.snapshot.clone(),
+            sequence: Some(seq),
+            ..ReadOptions::default()
+        };
+
+        self.read(cf, key, &read_options)
+    }
+
+    /// Sets `key` to `value` in the transaction, failing only if it's prepared already or if a
+    /// pessimistic transaction can't lock the key
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        self.put_cf(&self.db.default_cf(), key, value)
+    }
+
+    pub fn put_cf(&mut self, cf: &ColumnFamily, key: &[u8], value: &[u8]) -> Result<(), DbError> {
+        if self.prepared {
+            return Err(DbError::InvalidArgument("transaction is already prepared"));
+        }
+
+        self.track(cf, key)?;
+        self.batch.put_cf(cf, key, value);
+
+        Ok(())
+    }
+
+    /// Deletes `key` in the transaction, failing only if it's prepared already or if a
+    /// pessimistic transaction can't lock the key
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), DbError> {
+        self.delete_cf(&self.db.default_cf(), key)
+    }
+
+    pub fn delete_cf(&mut self, cf: &ColumnFamily, key: &[u8]) -> Result<(), DbError> {
+        if self.prepared {
+            return Err(DbError::InvalidArgument("transaction is already prepared"));
+        }
+
+        self.track(cf, key)?;
+        self.batch.delete_cf(cf, key);
+
+        Ok(())
+    }
+
+    /// Records the writes of the transaction so far, which [Transaction::rollback_to_savepoint]
+    /// goes back to
+    ///
+    /// Savepoints nest: each rollback goes back to the last savepoint not rolled back or popped.
+    pub fn set_savepoint(&mut self) {
+        self.batch.set_savepoint();
+        self.savepoints.push(Vec::new());
+    }
+
+    /// Discards the writes made since the last savepoint, which is removed too
+    ///
+    /// The keys first written or read for update since then are not checked at commit anymore,
+    /// but the locks of a pessimistic transaction are kept.
+    pub fn rollback_to_savepoint(&mut self) -> Result<(), DbError> {
+        self.batch.rollback_to_savepoint()?;
+
+        for key in self.savepoints.pop().unwrap_or_default() {
+            self.tracked.remove(&key);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the last savepoint, keeping the writes made since
+    pub fn pop_savepoint(&mut self) -> Result<(), DbError> {
+        self.batch.pop_savepoint()?;
+
+        let keys = self.savepoints.pop().unwrap_or_default();
+
+        if let Some(previous) = self.savepoints.last_mut() {
+            previous.extend(keys);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of writes of the transaction
+    pub fn count(&self) -> u32 {
+        self.batch.count()
+    }
+
+    /// Logs the writes of the transaction, the first phase of a two-phase commit, unless one of
+    /// the keys it touched was written by someone else since, see [IsolationLevel]
+    ///
+    /// Fails with [DbError::Conflict] in that case, leaving the transaction unprepared. Once
+    /// prepared, writing the transaction fails with [DbError::InvalidArgument], and its commit
+    /// can't fail because of a conflict: the other transactions writing or reading for update its keys fail instead.
+    /// It survives a crash, after which it's found in [Db::prepared_transactions].
+    pub fn prepare(&mut self) -> Result<(), DbError> {
+        let name = self.name.as_deref().ok_or(DbError::InvalidArgument(
+            "transaction needs a name to be prepared",
+        ))?;
+
+        if self.prepared {
+            return Err(DbError::InvalidArgument("transaction is already prepared"));
+        }
+
+        self.db.prepare_transaction(
+            name,
+            self.batch.batch(),
+            &self.tracked,
+            &self.options.write_options,
+        )?;
+        self.prepared = true;
+
+        Ok(())
+    }
+
+    /// Writes the writes of the transaction atomically, unless one of the keys it touched was
+    /// written by someone else since, see [IsolationLevel]
+    ///
+    /// Fails with [DbError::Conflict] in that case, leaving the database untouched. A prepared
+    /// transaction doesn't check its keys again.
+    pub fn commit(mut self) -> Result<(), DbError> {
+        let batch = std::mem::take(&mut self.batch).into_batch();
+
+        match (&self.name, self.prepared) {
+            (Some(name), true) => {
+                self.db
+                    .commit_prepared_transaction(name, batch, &self.options.write_options)
+            }
+            _ => self
+                .db
+                .commit_transaction(batch, &self.tracked, &self.options.write_options),
+        }
+    }
+
+    /// Discards the writes of the transaction, logging it for a prepared one
+    pub fn rollback(self) -> Result<(), DbError> {
+        match (&self.name, self.prepared) {
+            (Some(name), true) => self.db.rollback_prepared_transaction(name),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks `key` at commit against the writes after the sequence number the transaction sees
+    /// it at, unless it already is, and returns the sequence number it's checked against
+    ///
+    /// A pessimistic transaction locks the key first, and fails right away if it was written
+    /// after that sequence number, since it would fail at commit anyway.
+    fn track(&mut self, cf: &ColumnFamily, key: &[u8]) -> Result<SequenceNumber, DbError> {
+        let tracked_key = (cf.id(), key.to_vec());
+
+        if let Some(seq) = self.tracked.get(&tracked_key) {
+            return Ok(*seq);
+        }
+
+        if self.options.pessimistic {
+            self.db.lock_key(self.id, cf, key, &self.options)?;
+        }
+
+        let seq = match &self.snapshot {
+            Some(snapshot) => snapshot.sequence(),
+            None => self.db.latest_sequence_number(),
+        };
+
+        if self.options.pessimistic {
+            self.db
+                .check_conflicts(&HashMap::from([(tracked_key.clone(), seq)]))?;
+        }
+
+        if let Some(keys) = self.savepoints.last_mut() {
+            keys.push(tracked_key.clone());
+        }
+        self.tracked.insert(tracked_key, seq);
+
+        Ok(seq)
+    }
+
+    fn read(
+        &self,
+        cf: &ColumnFamily,
+        key: &[u8],
+        read_options: &ReadOptions,
+    ) -> Result<Option<Vec<u8>>, DbError> {
+        match self.batch.get_from_batch_cf(cf, key) {
+            Some(LookupResult::Value(value)) => Ok(Some(value)),
+            Some(LookupResult::Deleted) => Ok(None),
+            None => self.db.get_cf_with_options(cf, key, read_options),
+        }
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if self.options.pessimistic {
+            self.db.unlock_keys(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{Db, DbError};
+    use crate::options::{Options, TransactionOptions};
+    use crate::transaction::IsolationLevel;
+    use std::time::Duration;
+
+    fn read_committed() -> TransactionOptions {
+        TransactionOptions {
+            isolation: IsolationLevel::ReadCommitted,
+            ..TransactionOptions::default()
+        }
+    }
+
+    #[test]
+    fn snapshot_isolation_reads_as_of_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let mut txn = db.transaction();
+        db.put(b"a", b"2").unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        txn.put(b"b", b"3").unwrap();
+        assert_eq!(txn.get(b"b").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        // Written after the snapshot
+        txn.put(b"a", b"4").unwrap();
+        assert!(matches!(txn.commit(), Err(DbError::Conflict)));
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        let mut txn = db.transaction();
+        txn.put(b"a", b"5").unwrap();
+        db.put(b"c", b"6").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"5".to_vec()));
+    }
+
+    #[test]
+    fn read_committed_sees_the_last_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let mut txn = db.transaction_with_options(read_committed());
+        db.put(b"a", b"2").unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+        // Touched after the last write: no conflict
+        assert_eq!(txn.get_for_update(b"a").unwrap(), Some(b"2".to_vec()));
+        txn.put(b"a", b"3").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+
+        let mut txn = db.transaction_with_options(read_committed());
+        txn.get_for_update(b"a").unwrap();
+        db.put(b"a", b"4").unwrap();
+        txn.put(b"b", b"5").unwrap();
+        assert!(matches!(txn.commit(), Err(DbError::Conflict)));
+    }
+
+    #[test]
+    fn set_snapshot_makes_reads_repeatable() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let mut txn = db.transaction_with_options(read_committed());
+        assert!(txn.snapshot().is_none());
+        txn.set_snapshot();
+        db.put(b"a", b"2").unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        txn.clear_snapshot();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn savepoints_roll_back_writes_and_tracked_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        let mut txn = db.transaction();
+        txn.put(b"a", b"1").unwrap();
+        txn.set_savepoint();
+        txn.put(b"a", b"2").unwrap();
+        txn.put(b"b", b"3").unwrap();
+        txn.set_savepoint();
+        txn.delete(b"a").unwrap();
+        txn.pop_savepoint().unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), None);
+
+        txn.rollback_to_savepoint().unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(txn.get(b"b").unwrap(), None);
+        assert_eq!(txn.count(), 1);
+        assert!(txn.rollback_to_savepoint().is_err());
+
+        // Not written by the transaction anymore
+        db.put(b"b", b"4").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn prepared_transactions_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = Db::open(dir.path(), Options::default()).unwrap();
+
+            let mut txn = db.transaction();
+            txn.put(b"a", b"1").unwrap();
+            assert!(txn.prepare().is_err());
+            txn.set_name("first").unwrap();
+            txn.prepare().unwrap();
+            assert!(txn.is_prepared());
+
+            let mut txn = db.transaction();
+            txn.set_name("second").unwrap();
+            txn.put(b"b", b"2").unwrap();
+            txn.prepare().unwrap();
+
+            // The keys of the prepared transactions conflict
+            let mut other = db.transaction();
+            other.put(b"a", b"3").unwrap();
+            assert!(matches!(other.commit(), Err(DbError::Conflict)));
+
+            // Logged again in the new log
+            db.put(b"c", b"4").unwrap();
+            db.flush().unwrap();
+            assert_eq!(db.get(b"a").unwrap(), None);
+        }
+
+        {
+            let db = Db::open(dir.path(), Options::default()).unwrap();
+            let mut prepared = db.prepared_transactions().unwrap();
+            assert_eq!(prepared.len(), 2);
+
+            let second = prepared.pop().unwrap();
+            let first = prepared.pop().unwrap();
+            assert_eq!(first.name(), Some("first"));
+            assert_eq!(first.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+            first.commit().unwrap();
+            second.rollback().unwrap();
+            assert!(db.prepared_transactions().unwrap().is_empty());
+            assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        }
+
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        assert!(db.prepared_transactions().unwrap().is_empty());
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn prepared_transactions_cant_be_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+
+        let mut txn = db.transaction();
+        txn.set_name("txn").unwrap();
+        txn.put(b"a", b"1").unwrap();
+        txn.prepare().unwrap();
+
+        assert!(matches!(
+            txn.put(b"b", b"2"),
+            Err(DbError::InvalidArgument(_))
+        ));
+        assert!(matches!(txn.delete(b"a"), Err(DbError::InvalidArgument(_))));
+        assert_eq!(txn.count(), 1);
+
+        txn.commit().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn conflicts_are_found_in_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+
+        let mut txn = db.transaction();
+        txn.put(b"a", b"2").unwrap();
+        db.put(b"a", b"3").unwrap();
+        db.flush().unwrap();
+
+        assert!(matches!(txn.commit(), Err(DbError::Conflict)));
+    }
+
+    #[test]
+    fn pessimistic_transactions_wait_for_locked_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        let options = TransactionOptions {
+            pessimistic: true,
+            lock_timeout: Some(Duration::from_millis(10)),
+            ..TransactionOptions::default()
+        };
+
+        let mut first = db.transaction_with_options(options.clone());
+        let mut second = db.transaction_with_options(options.clone());
+        first.put(b"a", b"1").unwrap();
+        second.put(b"b", b"2").unwrap();
+
+        assert!(matches!(second.put(b"a", b"2"), Err(DbError::LockTimeout)));
+        assert!(matches!(
+            second.get_for_update(b"a"),
+            Err(DbError::LockTimeout)
+        ));
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let mut third = db.transaction_with_options(TransactionOptions {
+                    lock_timeout: None,
+                    ..options.clone()
+                });
+                third.put(b"a", b"3").unwrap();
+                third.commit().unwrap();
+            });
+
+            first.commit().unwrap();
+            waiter.join().unwrap();
+        });
+
+        // The key was written by the third transaction since the second one started
+        assert!(matches!(second.put(b"a", b"2"), Err(DbError::Conflict)));
+        second.commit().unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::key::{self, ValueType};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Encodes the value of an entry living for `ttl` from now
+///
+/// The memory layout is:
+/// [ expiry, value ]
+/// where expiry is a little-endian u64 holding the seconds since the Unix epoch after which the
+/// entry reads as a deletion.
+pub(crate) fn encode_value(value: &[u8], ttl: Duration) -> Vec<u8> {
+    let expiry = now().saturating_add(ttl.as_secs());
+
+    [&expiry.to_le_bytes(), value].concat()
+}
+
+/// Splits a value encoded by [encode_value] into its expiry and the value itself
+pub(crate) fn decode_value(value: &[u8]) -> Option<(u64, &[u8])> {
+    let (expiry, value) = value.split_first_chunk()?;
+
+    Some((u64::from_le_bytes(*expiry), value))
+}
+
+/// Returns the tombstone replacing the entry with `internal_key` and `value` if it's expired, so
+/// that flushes don't write expired values out
+pub(crate) fn expired_tombstone(internal_key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+    match key::parse(internal_key)? {
+        (user_key, seq, value_type @ ValueType::ValueWithExpiry) => {
+            let (value_type, _) = resolve(value_type, value);
+
+            (value_type == ValueType::Deletion)
+                .then(|| key::encode(user_key, seq, ValueType::Deletion))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the type and the value of an entry as seen by a read now: the entries with an expiry
+/// are values, or deletions once expired
+///
+/// Malformed expiries are treated as expired.
+pub(crate) fn resolve(value_type: ValueType, value: &[u8]) -> (ValueType, &[u8]) {
+    if value_type != ValueType::ValueWithExpiry {
+        return (value_type, value);
+    }
+
+    match decode_value(value) {
+        Some((expiry, value)) if now() < expiry => (ValueType::Value, value),
+        _ => (ValueType::Deletion, &[]),
+    }
+}
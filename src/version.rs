@@ -0,0 +1,887 @@
+use crate::key::{self, SequenceNumber};
+use crate::wal::{self, SyncPolicy, WalError};
+use integer_encoding::*;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use thiserror::Error;
+
+/// Number of levels the tables of a column family are organized in
+pub const NUM_LEVELS: usize = 7;
+
+#[derive(Error, Debug)]
+pub enum VersionError {
+    #[error("I/O error on the manifest")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Wal(#[from] WalError),
+    #[error("Manifest is corrupted: {0}")]
+    Corruption(&'static str),
+}
+
+/// Returns the path of the manifest `number` in `dir`
+pub fn manifest_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("MANIFEST-{:06}", number))
+}
+
+/// Extracts the manifest number from a manifest file name, returning None for any other file
+pub fn parse_manifest_file_name(name: &str) -> Option<u64> {
+    name.strip_prefix("MANIFEST-")?.parse().ok()
+}
+
+/// Returns the path of the file holding the name of the current manifest
+fn current_file_name(dir: &Path) -> PathBuf {
+    dir.join("CURRENT")
+}
+
+/// A table file, as recorded in the manifest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileMetaData {
+    pub number: u64,
+    pub file_size: u64,
+    /// Smallest internal key of the table
+    pub smallest_key: Vec<u8>,
+    /// Largest internal key of the table
+    pub largest_key: Vec<u8>,
+}
+
+impl FileMetaData {
+    /// Returns whether every key of the table sorts before `user_key`
+    ///
+    /// A table ending with a range tombstone, e.g. one cut at the boundary of a compaction
+    /// output, ends with the exclusive end of the tombstone: no version of its largest user key
+    /// is in the table then.
+    pub(crate) fn ends_before(&self, user_key: &[u8]) -> bool {
+        let largest = key::user_key(&self.largest_key);
+        let exclusive = key::parse(&self.largest_key)
+            .is_some_and(|(_, seq, _)| seq == key::MAX_SEQUENCE_NUMBER);
+
+        largest < user_key || (exclusive && largest == user_key)
+    }
+
+    /// Returns whether the table holds user keys in [smallest, largest]
+    pub(crate) fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        key::user_key(&self.smallest_key) <= largest && !self.ends_before(smallest)
+    }
+}
+
+/// A blob file, as recorded in the manifest
+///
+/// The values no table points to anymore are garbage: the file is dropped from the version once
+/// they all are.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobFileMetaData {
+    pub number: u64,
+    /// Number of values in the file
+    pub blob_count: u64,
+    /// Bytes of the values in the file
+    pub blob_bytes: u64,
+    pub file_size: u64,
+    /// Number of values in the file which are garbage
+    pub garbage_count: u64,
+    /// Bytes of the values in the file which are garbage
+    pub garbage_bytes: u64,
+}
+
+impl BlobFileMetaData {
+    /// Returns the share of the bytes of the values which are garbage
+    pub fn garbage_ratio(&self) -> f64 {
+        match self.blob_bytes {
+            0 => 1.0,
+            bytes => self.garbage_bytes as f64 / bytes as f64,
+        }
+    }
+}
+
+/// A change to the set of live files and to the counters of the database, i.e. a record of the
+/// manifest
+///
+/// Every record is a sequence of fields, each one being a varint tag followed by its contents:
+/// varints for numbers, and varint-prefixed byte strings for names and keys.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VersionEdit {
+    /// The logs older than this one only hold writes which are in tables
+    pub log_number: Option<u64>,
+    pub next_file_number: Option<u64>,
+    pub last_sequence: Option<SequenceNumber>,
+    pub next_column_family_id: Option<u32>,
+    /// Ids and names of the column families created
+    pub added_column_families: Vec<(u32, String)>,
+    pub dropped_column_families: Vec<u32>,
+    /// Column family, level and metadata of the files added
+    pub new_files: Vec<(u32, usize, FileMetaData)>,
+    /// Column family, level and number of the files removed
+    pub deleted_files: Vec<(u32, usize, u64)>,
+    /// Column family and metadata of the blob files added
+    pub new_blob_files: Vec<(u32, BlobFileMetaData)>,
+    /// Column family, number, and number and bytes of the values of the blob files which became
+    /// garbage
+    pub blob_file_garbage: Vec<(u32, u64, u64, u64)>,
+    /// Column family and new [ColumnFamilyFiles::full_history_ts_low] of the column families
+    /// with user timestamps whose history was cut
+    pub full_history_ts_low: Vec<(u32, u64)>,
+    /// Column family and new [ColumnFamilyFiles::log_number] of the column families flushed
+    pub column_family_log_numbers: Vec<(u32, u64)>,
+    /// Names and sequence numbers of the persistent snapshots created
+    pub added_snapshots: Vec<(String, SequenceNumber)>,
+    /// Names of the persistent snapshots released
+    pub released_snapshots: Vec<String>,
+}
+
+const TAG_LOG_NUMBER: u32 = 1;
+const TAG_NEXT_FILE_NUMBER: u32 = 2;
+const TAG_LAST_SEQUENCE: u32 = 3;
+const TAG_NEXT_COLUMN_FAMILY_ID: u32 = 4;
+const TAG_ADD_COLUMN_FAMILY: u32 = 5;
+const TAG_DROP_COLUMN_FAMILY: u32 = 6;
+const TAG_NEW_FILE: u32 = 7;
+const TAG_DELETED_FILE: u32 = 8;
+const TAG_FULL_HISTORY_TS_LOW: u32 = 9;
+const TAG_ADD_SNAPSHOT: u32 = 10;
+const TAG_RELEASE_SNAPSHOT: u32 = 11;
+const TAG_NEW_BLOB_FILE: u32 = 12;
+const TAG_BLOB_FILE_GARBAGE: u32 = 13;
+const TAG_COLUMN_FAMILY_LOG_NUMBER: u32 = 14;
+
+fn put_varint<V: VarInt>(buffer: &mut Vec<u8>, value: V) {
+    buffer.extend_from_slice(&value.encode_var_vec());
+}
+
+fn put_slice(buffer: &mut Vec<u8>, slice: &[u8]) {
+    put_varint(buffer, slice.len());
+    buffer.extend_from_slice(slice);
+}
+
+/// Reads the fields of an encoded [VersionEdit]
+struct EditReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> EditReader<'a> {
+    fn varint<V: VarInt>(&mut self) -> Result<V, VersionError> {
+        let (value, size) =
+            V::decode_var(self.data).ok_or(VersionError::Corruption("bad varint"))?;
+        self.data = &self.data[size..];
+
+        Ok(value)
+    }
+
+    fn slice(&mut self) -> Result<&'a [u8], VersionError> {
+        let len: usize = self.varint()?;
+        let slice = self
+            .data
+            .get(..len)
+            .ok_or(VersionError::Corruption("field out of bounds"))?;
+        self.data = &self.data[len..];
+
+        Ok(slice)
+    }
+
+    /// Reads a UTF-8 slice, failing with `error` if it's not valid
+    fn string(&mut self, error: &'static str) -> Result<String, VersionError> {
+        String::from_utf8(self.slice()?.to_vec()).map_err(|_| VersionError::Corruption(error))
+    }
+}
+
+impl VersionEdit {
+    pub fn add_file(&mut self, column_family: u32, level: usize, file: FileMetaData) {
+        self.new_files.push((column_family, level, file));
+    }
+
+    pub fn delete_file(&mut self, column_family: u32, level: usize, number: u64) {
+        self.deleted_files.push((column_family, level, number));
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        let numbers = [
+            (TAG_LOG_NUMBER, self.log_number),
+            (TAG_NEXT_FILE_NUMBER, self.next_file_number),
+            (TAG_LAST_SEQUENCE, self.last_sequence),
+            (
+                TAG_NEXT_COLUMN_FAMILY_ID,
+                self.next_column_family_id.map(u64::from),
+            ),
+        ];
+
+        for (tag, number) in numbers {
+            if let Some(number) = number {
+                put_varint(&mut buffer, tag);
+                put_varint(&mut buffer, number);
+            }
+        }
+
+        for (id, name) in &self.added_column_families {
+            put_varint(&mut buffer, TAG_ADD_COLUMN_FAMILY);
+            put_varint(&mut buffer, *id);
+            put_slice(&mut buffer, name.as_bytes());
+        }
+
+        for id in &self.dropped_column_families {
+            put_varint(&mut buffer, TAG_DROP_COLUMN_FAMILY);
+            put_varint(&mut buffer, *id);
+        }
+
+        for (column_family, level, file) in &self.new_files {
+            put_varint(&mut buffer, TAG_NEW_FILE);
+            put_varint(&mut buffer, *column_family);
+            put_varint(&mut buffer, *level);
+            put_varint(&mut buffer, file.number);
+            put_varint(&mut buffer, file.file_size);
+            put_slice(&mut buffer, &file.smallest_key);
+            put_slice(&mut buffer, &file.largest_key);
+        }
+
+        for (column_family, level, number) in &self.deleted_files {
+            put_varint(&mut buffer, TAG_DELETED_FILE);
+            put_varint(&mut buffer, *column_family);
+            put_varint(&mut buffer, *level);
+            put_varint(&mut buffer, *number);
+        }
+
+        for (column_family, file) in &self.new_blob_files {
+            put_varint(&mut buffer, TAG_NEW_BLOB_FILE);
+            put_varint(&mut buffer, *column_family);
+            put_varint(&mut buffer, file.number);
+            put_varint(&mut buffer, file.blob_count);
+            put_varint(&mut buffer, file.blob_bytes);
+            put_varint(&mut buffer, file.file_size);
+            put_varint(&mut buffer, file.garbage_count);
+            put_varint(&mut buffer, file.garbage_bytes);
+        }
+
+        for (column_family, number, count, bytes) in &self.blob_file_garbage {
+            put_varint(&mut buffer, TAG_BLOB_FILE_GARBAGE);
+            put_varint(&mut buffer, *column_family);
+            put_varint(&mut buffer, *number);
+            put_varint(&mut buffer, *count);
+            put_varint(&mut buffer, *bytes);
+        }
+
+        for (column_family, ts) in &self.full_history_ts_low {
+            put_varint(&mut buffer, TAG_FULL_HISTORY_TS_LOW);
+            put_varint(&mut buffer, *column_family);
+            put_varint(&mut buffer, *ts);
+        }
+
+        for (column_family, log_number) in &self.column_family_log_numbers {
+            put_varint(&mut buffer, TAG_COLUMN_FAMILY_LOG_NUMBER);
+            put_varint(&mut buffer, *column_family);
+            put_varint(&mut buffer, *log_number);
+        }
+
+        for (name, seq) in &self.added_snapshots {
+            put_varint(&mut buffer, TAG_ADD_SNAPSHOT);
+            put_slice(&mut buffer, name.as_bytes());
+            put_varint(&mut buffer, *seq);
+        }
+
+        for name in &self.released_snapshots {
+            put_varint(&mut buffer, TAG_RELEASE_SNAPSHOT);
+            put_slice(&mut buffer, name.as_bytes());
+        }
+
+        buffer
+    }
+
+    pub fn decode(data: &[u8]) -> Result<VersionEdit, VersionError> {
+        let mut reader = EditReader { data };
+        let mut edit = VersionEdit::default();
+
+        while !reader.data.is_empty() {
+            match reader.varint()? {
+                TAG_LOG_NUMBER => edit.log_number = Some(reader.varint()?),
+                TAG_NEXT_FILE_NUMBER => edit.next_file_number = Some(reader.varint()?),
+                TAG_LAST_SEQUENCE => edit.last_sequence = Some(reader.varint()?),
+                TAG_NEXT_COLUMN_FAMILY_ID => edit.next_column_family_id = Some(reader.varint()?),
+                TAG_ADD_COLUMN_FAMILY => {
+                    let id = reader.varint()?;
+                    let name = reader.string("bad column family name")?;
+
+                    edit.added_column_families.push((id, name));
+                }
+                TAG_DROP_COLUMN_FAMILY => edit.dropped_column_families.push(reader.varint()?),
+                TAG_NEW_FILE => {
+                    let column_family = reader.varint()?;
+                    let level = reader.varint()?;
+                    let file = FileMetaData {
+                        number: reader.varint()?,
+                        file_size: reader.varint()?,
+                        smallest_key: reader.slice()?.to_vec(),
+                        largest_key: reader.slice()?.to_vec(),
+                    };
+
+                    edit.add_file(column_family, level, file);
+                }
+                TAG_DELETED_FILE => {
+                    let column_family = reader.varint()?;
+                    let level = reader.varint()?;
+                    let number = reader.varint()?;
+
+                    edit.delete_file(column_family, level, number);
+                }
+                TAG_NEW_BLOB_FILE => {
+                    let column_family = reader.varint()?;
+                    let file = BlobFileMetaData {
+                        number: reader.varint()?,
+                        blob_count: reader.varint()?,
+                        blob_bytes: reader.varint()?,
+                        file_size: reader.varint()?,
+                        garbage_count: reader.varint()?,
+                        garbage_bytes: reader.varint()?,
+                    };
+
+                    edit.new_blob_files.push((column_family, file));
+                }
+                TAG_BLOB_FILE_GARBAGE => {
+                    let column_family = reader.varint()?;
+                    let number = reader.varint()?;
+                    let count = reader.varint()?;
+                    let bytes = reader.varint()?;
+
+                    edit.blob_file_garbage
+                        .push((column_family, number, count, bytes));
+                }
+                TAG_FULL_HISTORY_TS_LOW => {
+                    let column_family = reader.varint()?;
+                    let ts = reader.varint()?;
+
+                    edit.full_history_ts_low.push((column_family, ts));
+                }
+                TAG_COLUMN_FAMILY_LOG_NUMBER => {
+                    let column_family = reader.varint()?;
+                    let log_number = reader.varint()?;
+
+                    edit.column_family_log_numbers
+                        .push((column_family, log_number));
+                }
+                TAG_ADD_SNAPSHOT => {
+                    let name = reader.string("bad snapshot name")?;
+                    let seq = reader.varint()?;
+
+                    edit.added_snapshots.push((name, seq));
+                }
+                TAG_RELEASE_SNAPSHOT => {
+                    let name = reader.string("bad snapshot name")?;
+
+                    edit.released_snapshots.push(name);
+                }
+                _ => return Err(VersionError::Corruption("unknown field tag")),
+            }
+        }
+
+        Ok(edit)
+    }
+}
+
+/// The live files of a column family, by level
+#[derive(Clone, Debug)]
+pub struct ColumnFamilyFiles {
+    pub name: String,
+    /// Level 0 files are sorted from the newest to the oldest, since their key ranges overlap,
+    /// while the files of the other levels are sorted by key
+    pub levels: Vec<Vec<Arc<FileMetaData>>>,
+    /// With user timestamps, the reads as of older timestamps are refused, and compactions may
+    /// drop the versions they would see
+    pub full_history_ts_low: u64,
+    /// The logs older than this one only hold writes of the column family which are in its
+    /// tables, on top of the logs older than [VersionSet::log_number]
+    pub log_number: u64,
+    /// The blob files holding the values the tables point to, by number
+    pub blob_files: BTreeMap<u64, Arc<BlobFileMetaData>>,
+}
+
+impl ColumnFamilyFiles {
+    /// Iterates the files in the order reads should look at them
+    pub fn files(&self) -> impl Iterator<Item = &Arc<FileMetaData>> {
+        self.levels.iter().flatten()
+    }
+}
+
+/// The set of live files of every column family at some point in time
+#[derive(Clone, Debug, Default)]
+pub struct Version {
+    column_families: BTreeMap<u32, ColumnFamilyFiles>,
+}
+
+impl Version {
+    /// Iterates the column families by id
+    pub fn column_families(&self) -> impl Iterator<Item = (u32, &ColumnFamilyFiles)> {
+        self.column_families.iter().map(|(id, files)| (*id, files))
+    }
+
+    pub fn column_family(&self, id: u32) -> Option<&ColumnFamilyFiles> {
+        self.column_families.get(&id)
+    }
+
+    /// Iterates the numbers of the table and blob files of every column family
+    pub fn file_numbers(&self) -> impl Iterator<Item = u64> + '_ {
+        self.column_families.values().flat_map(|files| {
+            files
+                .files()
+                .map(|file| file.number)
+                .chain(files.blob_files.keys().copied())
+        })
+    }
+
+    /// Returns the version resulting from applying `edit` to this one
+    fn apply(&self, edit: &VersionEdit) -> Result<Version, VersionError> {
+        let mut version = self.clone();
+
+        for (id, name) in &edit.added_column_families {
+            version.column_families.insert(
+                *id,
+                ColumnFamilyFiles {
+                    name: name.clone(),
+                    levels: vec![Vec::new(); NUM_LEVELS],
+                    full_history_ts_low: 0,
+                    log_number: 0,
+                    blob_files: BTreeMap::new(),
+                },
+            );
+        }
+
+        for (column_family, level, number) in &edit.deleted_files {
+            if let Some(files) = version.column_families.get_mut(column_family) {
+                files
+                    .levels
+                    .get_mut(*level)
+                    .ok_or(VersionError::Corruption("bad level"))?
+                    .retain(|file| file.number != *number);
+            }
+        }
+
+        for (column_family, level, file) in &edit.new_files {
+            let files = version
+                .column_families
+                .get_mut(column_family)
+                .ok_or(VersionError::Corruption("file of an unknown column family"))?;
+            let level_files = files
+                .levels
+                .get_mut(*level)
+                .ok_or(VersionError::Corruption("bad level"))?;
+
+            level_files.push(Arc::new(file.clone()));
+
+            if *level == 0 {
+                level_files.sort_by_key(|file| Reverse(file.number));
+            } else {
+                level_files.sort_by(|a, b| key::compare(&a.smallest_key, &b.smallest_key));
+            }
+        }
+
+        for (column_family, file) in &edit.new_blob_files {
+            version
+                .column_families
+                .get_mut(column_family)
+                .ok_or(VersionError::Corruption("file of an unknown column family"))?
+                .blob_files
+                .insert(file.number, Arc::new(file.clone()));
+        }
+
+        for (column_family, number, count, bytes) in &edit.blob_file_garbage {
+            let Some(files) = version.column_families.get_mut(column_family) else {
+                continue;
+            };
+            let Some(file) = files.blob_files.get_mut(number) else {
+                continue;
+            };
+
+            let file = Arc::make_mut(file);
+            file.garbage_count += count;
+            file.garbage_bytes += bytes;
+
+            if file.garbage_count >= file.blob_count {
+                files.blob_files.remove(number);
+            }
+        }
+
+        for (column_family, ts) in &edit.full_history_ts_low {
+            if let Some(files) = version.column_families.get_mut(column_family) {
+                files.full_history_ts_low = files.full_history_ts_low.max(*ts);
+            }
+        }
+
+        for (column_family, log_number) in &edit.column_family_log_numbers {
+            if let Some(files) = version.column_families.get_mut(column_family) {
+                files.log_number = files.log_number.max(*log_number);
+            }
+        }
+
+        for id in &edit.dropped_column_families {
+            version.column_families.remove(id);
+        }
+
+        Ok(version)
+    }
+}
+
+/// The current [Version] of a database along with its counters, persisted in a manifest: a log
+/// of [VersionEdit]s, named by the CURRENT file, replayed when the database is opened
+///
+/// A new manifest starting with a snapshot of the whole state is written every time the
+/// database is opened, so that the manifests don't grow forever.
+///
+/// Readers hold an `Arc` of the version they read, so the versions replaced by newer ones are
+/// tracked until dropped: their files can't be deleted before then.
+pub struct VersionSet {
+    db_path: PathBuf,
+    manifest: Option<wal::Writer>,
+    manifest_number: u64,
+    current: Arc<Version>,
+    old_versions: Vec<Weak<Version>>,
+    log_number: u64,
+    next_file_number: u64,
+    last_sequence: SequenceNumber,
+    next_column_family_id: u32,
+    /// Sequence numbers of the persistent snapshots by name
+    snapshots: BTreeMap<String, SequenceNumber>,
+}
+
+impl VersionSet {
+    /// Rebuilds the state recorded by the current manifest of the database at `db_path`, then
+    /// switches to a new manifest
+    ///
+    /// Returns None if the database has no manifest.
+    pub fn recover(db_path: &Path) -> Result<Option<VersionSet>, VersionError> {
+        let mut versions = match VersionSet::open_read_only(db_path)? {
+            Some(versions) => versions,
+            None => return Ok(None),
+        };
+
+        let manifest_number = versions.manifest_number;
+
+        versions.start_manifest()?;
+        std::fs::remove_file(manifest_file_name(db_path, manifest_number))?;
+
+        Ok(Some(versions))
+    }
+
+    /// Rebuilds the state recorded by the current manifest of the database at `db_path`,
+    /// without ever writing to it: the returned set can't be changed
+    ///
+    /// Returns None if the database has no manifest.
+    pub fn open_read_only(db_path: &Path) -> Result<Option<VersionSet>, VersionError> {
+        let current = match std::fs::read_to_string(current_file_name(db_path)) {
+            Ok(current) => current,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let manifest_number = parse_manifest_file_name(current.trim_end())
+            .ok_or(VersionError::Corruption("bad CURRENT file"))?;
+
+        let mut reader = wal::Reader::open(
+            manifest_file_name(db_path, manifest_number),
+            manifest_number,
+        )?;
+        let mut versions = VersionSet::empty(db_path);
+
+        while let Some(record) = reader.read_record()? {
+            versions.apply(&VersionEdit::decode(&record)?)?;
+        }
+
+        versions.manifest_number = manifest_number;
+
+        Ok(Some(versions))
+    }
+
+    /// Starts the manifest of the database at `db_path` from `edit`, which describes the whole
+    /// state of the database
+    pub fn create(db_path: &Path, edit: &VersionEdit) -> Result<VersionSet, VersionError> {
+        let mut versions = VersionSet::empty(db_path);
+
+        versions.apply(edit)?;
+        versions.start_manifest()?;
+
+        Ok(versions)
+    }
+
+    fn empty(db_path: &Path) -> VersionSet {
+        VersionSet {
+            db_path: db_path.to_path_buf(),
+            manifest: None,
+            manifest_number: 0,
+            current: Arc::new(Version::default()),
+            old_versions: Vec::new(),
+            log_number: 0,
+            next_file_number: 1,
+            last_sequence: 0,
+            next_column_family_id: 0,
+            snapshots: BTreeMap::new(),
+        }
+    }
+
+    /// Applies `edit` in memory only
+    fn apply(&mut self, edit: &VersionEdit) -> Result<(), VersionError> {
+        let version = Arc::new(self.current.apply(edit)?);
+        let old_version = std::mem::replace(&mut self.current, version);
+        self.old_versions.push(Arc::downgrade(&old_version));
+
+        if let Some(log_number) = edit.log_number {
+            self.log_number = log_number;
+        }
+
+        if let Some(next_file_number) = edit.next_file_number {
+            self.next_file_number = self.next_file_number.max(next_file_number);
+        }
+
+        if let Some(last_sequence) = edit.last_sequence {
+            self.last_sequence = self.last_sequence.max(last_sequence);
+        }
+
+        if let Some(next_column_family_id) = edit.next_column_family_id {
+            self.next_column_family_id = self.next_column_family_id.max(next_column_family_id);
+        }
+
+        for (name, seq) in &edit.added_snapshots {
+            self.snapshots.insert(name.clone(), *seq);
+        }
+
+        for name in &edit.released_snapshots {
+            self.snapshots.remove(name);
+        }
+
+        Ok(())
+    }
+
+    /// Returns an edit rebuilding the current state from scratch
+    pub fn snapshot(&self) -> VersionEdit {
+        let mut edit = VersionEdit {
+            log_number: Some(self.log_number),
+            next_file_number: Some(self.next_file_number),
+            last_sequence: Some(self.last_sequence),
+            next_column_family_id: Some(self.next_column_family_id),
+            ..VersionEdit::default()
+        };
+
+        edit.added_snapshots = self
+            .snapshots
+            .iter()
+            .map(|(name, seq)| (name.clone(), *seq))
+            .collect();
+
+        for (id, files) in self.current.column_families() {
+            edit.added_column_families.push((id, files.name.clone()));
+
+            if files.full_history_ts_low > 0 {
+                edit.full_history_ts_low
+                    .push((id, files.full_history_ts_low));
+            }
+
+            if files.log_number > 0 {
+                edit.column_family_log_numbers.push((id, files.log_number));
+            }
+
+            for (level, level_files) in files.levels.iter().enumerate() {
+                for file in level_files {
+                    edit.add_file(id, level, file.as_ref().clone());
+                }
+            }
+
+            for file in files.blob_files.values() {
+                edit.new_blob_files.push((id, file.as_ref().clone()));
+            }
+        }
+
+        edit
+    }
+
+    /// Writes a snapshot of the current state to a new manifest, and makes it the current one
+    fn start_manifest(&mut self) -> Result<(), VersionError> {
+        let manifest_number = self.new_file_number();
+        let path = manifest_file_name(&self.db_path, manifest_number);
+
+        let mut manifest = wal::Writer::create(&path, manifest_number, SyncPolicy::Always)?;
+        manifest.add_record(&self.snapshot().encode())?;
+
+        // CURRENT is replaced atomically, so that it always names a complete manifest
+        let current_path = current_file_name(&self.db_path);
+        let tmp_path = current_path.with_extension("tmp");
+
+        let mut current = File::create(&tmp_path)?;
+        writeln!(current, "MANIFEST-{:06}", manifest_number)?;
+        current.sync_all()?;
+
+        std::fs::rename(&tmp_path, &current_path)?;
+        File::open(&self.db_path)?.sync_all()?;
+
+        log::info!("started manifest {}", path.display());
+
+        self.manifest = Some(manifest);
+        self.manifest_number = manifest_number;
+
+        Ok(())
+    }
+
+    /// Records `edit` in the manifest, then applies it to the current version
+    ///
+    /// The edit also records the next file number and the next column family id, so that they
+    /// are never reused after a restart.
+    pub fn log_and_apply(&mut self, mut edit: VersionEdit) -> Result<(), VersionError> {
+        edit.next_file_number = Some(self.next_file_number);
+        edit.next_column_family_id = edit
+            .next_column_family_id
+            .max(Some(self.next_column_family_id));
+
+        self.manifest
+            .as_mut()
+            .expect("read-only version sets can't be changed")
+            .add_record(&edit.encode())?;
+
+        self.apply(&edit)
+    }
+
+    pub fn current(&self) -> &Arc<Version> {
+        &self.current
+    }
+
+    /// Returns the numbers of the files of the current version and of the older versions still
+    /// in use, which must not be deleted
+    pub fn live_files(&mut self) -> HashSet<u64> {
+        self.old_versions
+            .retain(|version| version.strong_count() > 0);
+
+        let old_versions: Vec<_> = self.old_versions.iter().filter_map(Weak::upgrade).collect();
+
+        std::iter::once(&self.current)
+            .chain(&old_versions)
+            .flat_map(|version| version.file_numbers())
+            .collect()
+    }
+
+    /// Returns the number of the manifest being written
+    pub fn manifest_number(&self) -> u64 {
+        self.manifest_number
+    }
+
+    /// Returns the number of the oldest log which may hold writes not in tables yet
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    /// Returns the sequence numbers of the persistent snapshots by name
+    pub fn snapshots(&self) -> &BTreeMap<String, SequenceNumber> {
+        &self.snapshots
+    }
+
+    /// Returns the sequence number of the last write stored in tables
+    pub fn last_sequence(&self) -> SequenceNumber {
+        self.last_sequence
+    }
+
+    pub fn next_column_family_id(&self) -> u32 {
+        self.next_column_family_id
+    }
+
+    /// Allocates a new file number, for a table, a log or a manifest
+    pub fn new_file_number(&mut self) -> u64 {
+        let number = self.next_file_number;
+        self.next_file_number += 1;
+
+        number
+    }
+
+    /// Makes sure `number`, used by a file not allocated through this set, is never allocated
+    pub fn mark_file_number_used(&mut self, number: u64) {
+        self.next_file_number = self.next_file_number.max(number + 1);
+    }
+
+    /// Returns the number the next allocated file will get
+    pub fn next_file_number(&self) -> u64 {
+        self.next_file_number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::version::{BlobFileMetaData, FileMetaData, VersionEdit, VersionSet};
+
+    fn file(number: u64) -> FileMetaData {
+        FileMetaData {
+            number,
+            file_size: 1000 + number,
+            smallest_key: format!("a{}", number).into_bytes(),
+            largest_key: format!("z{}", number).into_bytes(),
+        }
+    }
+
+    #[test]
+    fn edit_roundtrip() {
+        let mut edit = VersionEdit {
+            log_number: Some(7),
+            last_sequence: Some(1 << 40),
+            added_column_families: vec![(3, "meta".to_string())],
+            dropped_column_families: vec![2],
+            full_history_ts_low: vec![(3, 42)],
+            column_family_log_numbers: vec![(3, 8)],
+            added_snapshots: vec![("backup".to_string(), 12)],
+            released_snapshots: vec!["old".to_string()],
+            ..VersionEdit::default()
+        };
+        edit.add_file(3, 1, file(5));
+        edit.delete_file(0, 0, 4);
+        edit.new_blob_files.push((
+            3,
+            BlobFileMetaData {
+                number: 6,
+                blob_count: 10,
+                blob_bytes: 1 << 20,
+                file_size: (1 << 20) + 200,
+                garbage_count: 2,
+                garbage_bytes: 1 << 10,
+            },
+        ));
+        edit.blob_file_garbage.push((3, 6, 1, 100));
+
+        assert_eq!(VersionEdit::decode(&edit.encode()).unwrap(), edit);
+        assert!(VersionEdit::decode(&[42]).is_err());
+    }
+
+    #[test]
+    fn manifest_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let base = VersionEdit {
+                added_column_families: vec![(0, "default".to_string())],
+                next_column_family_id: Some(1),
+                ..VersionEdit::default()
+            };
+            let mut versions = VersionSet::create(dir.path(), &base).unwrap();
+
+            let mut edit = VersionEdit::default();
+            edit.add_file(0, 0, file(versions.new_file_number()));
+            edit.add_file(0, 0, file(versions.new_file_number()));
+            versions.log_and_apply(edit).unwrap();
+
+            let mut edit = VersionEdit {
+                log_number: Some(10),
+                last_sequence: Some(99),
+                column_family_log_numbers: vec![(0, 12)],
+                ..VersionEdit::default()
+            };
+            edit.delete_file(0, 0, 2);
+            edit.add_file(0, 1, file(versions.new_file_number()));
+            versions.log_and_apply(edit).unwrap();
+        }
+
+        let versions = VersionSet::recover(dir.path()).unwrap().unwrap();
+        let files = versions.current().column_family(0).unwrap();
+
+        let level0: Vec<_> = files.levels[0].iter().map(|file| file.number).collect();
+        let level1: Vec<_> = files.levels[1].iter().map(|file| file.number).collect();
+
+        assert_eq!(level0, [3]);
+        assert_eq!(level1, [4]);
+        assert_eq!(versions.log_number(), 10);
+        assert_eq!(files.log_number, 12);
+        assert_eq!(versions.last_sequence(), 99);
+        assert!(versions.next_file_number() > versions.manifest_number());
+    }
+}
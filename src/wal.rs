@@ -0,0 +1,1240 @@
+use crate::batch::{BatchError, Marker, WriteBatch};
+use crate::column_family::DEFAULT_COLUMN_FAMILY_ID;
+use crate::instrument::span;
+use crate::key::SequenceNumber;
+use crate::memtable::MemTable;
+use crate::options::Options;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+/// Bytes taken by the checksum, the length, the log number and the flags in front of every record
+const RECORD_HEADER_SIZE: usize = 3 * size_of::<u32>() + 1;
+
+/// Record flag marking a payload compressed with LZ4
+const FLAG_LZ4: u8 = 1;
+
+/// Record flag marking the empty record a recycled file starts with, after which the bytes
+/// following the last record of the log are stale records of an older log rather than a torn
+/// tail
+const FLAG_RECYCLED: u8 = 2;
+
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error("I/O error on the write-ahead log")]
+    Io(#[from] io::Error),
+    #[error("Corrupted write-ahead log record at offset {offset}")]
+    Corruption { offset: u64 },
+    #[error("Corrupted write batch in the write-ahead log")]
+    Batch(#[from] BatchError),
+    #[error("Updates are not available starting from sequence number {requested}, the oldest retained one is {available}")]
+    UpdatesUnavailable {
+        requested: SequenceNumber,
+        available: SequenceNumber,
+    },
+}
+
+/// Computes the checksum of a record, covering its length, log number and payload
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+
+    hasher.update(header);
+    hasher.update(payload);
+
+    hasher.finalize()
+}
+
+/// Returns the path of the log file with the given number
+pub fn log_file_name(dir: &Path, log_number: u64) -> PathBuf {
+    dir.join(format!("{:06}.log", log_number))
+}
+
+/// Extracts the log number from a log file name, returning None for any other file
+pub fn parse_log_file_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".log")?.parse().ok()
+}
+
+/// Returns the path an obsolete log is renamed to while waiting to be recycled, so that it's
+/// never mistaken for a live log during recovery
+pub fn recyclable_log_file_name(dir: &Path, log_number: u64) -> PathBuf {
+    dir.join(format!("{:06}.log.recycle", log_number))
+}
+
+/// Extracts the log number from the name of a log waiting to be recycled
+pub fn parse_recyclable_log_file_name(name: &str) -> Option<u64> {
+    name.strip_suffix(".log.recycle")?.parse().ok()
+}
+
+/// When the [Writer] makes appended records durable by calling fsync
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every record: nothing acknowledged is ever lost, at the cost of one fsync per write
+    #[default]
+    Always,
+    /// Sync once every n records: a crash loses at most the last n - 1 writes
+    EveryNWrites(u32),
+    /// Sync on the first record appended after the interval elapsed since the last sync
+    Interval(Duration),
+}
+
+/// Appends records to a write-ahead log file
+///
+/// Every record is laid out as:
+/// [ checksum, length, log_number, flags, payload ]
+/// where checksum, length and log_number are little-endian u32, flags is a byte, and checksum
+/// is a CRC32 of everything following it.
+///
+/// The log number (truncated to 32 bits) tells apart the records of the current log from the
+/// stale ones left over in a recycled file. The flags tell whether the payload is compressed, or
+/// mark the first record of a recycled file.
+pub struct Writer {
+    file: File,
+    log_number: u32,
+    compression_threshold: Option<usize>,
+    sync_policy: SyncPolicy,
+    writes_since_sync: u32,
+    last_sync: Instant,
+    /// When set, records are buffered in memory until [Writer::flush] is called
+    manual_flush: bool,
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(file: File, log_number: u64, sync_policy: SyncPolicy) -> Writer {
+        Writer {
+            file,
+            log_number: log_number as u32,
+            compression_threshold: None,
+            sync_policy,
+            writes_since_sync: 0,
+            last_sync: Instant::now(),
+            manual_flush: false,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Creates (or truncates) the log file at `path`
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        log_number: u64,
+        sync_policy: SyncPolicy,
+    ) -> Result<Writer, WalError> {
+        Ok(Writer::new(File::create(path)?, log_number, sync_policy))
+    }
+
+    /// Reuses the obsolete log file at `old_path` as the new log `log_number`, renaming it to
+    /// `path`. Records are written over the old ones, so the file doesn't need to be extended
+    /// (and its metadata synced) until the new log outgrows the old one.
+    ///
+    /// The file starts with a [FLAG_RECYCLED] record, synced right away: a single sector, it
+    /// can't be torn, unlike the records written after it.
+    pub fn recycle<P: AsRef<Path>, Q: AsRef<Path>>(
+        old_path: P,
+        path: Q,
+        log_number: u64,
+        sync_policy: SyncPolicy,
+    ) -> Result<Writer, WalError> {
+        std::fs::rename(old_path, &path)?;
+
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        let mut writer = Writer::new(file, log_number, sync_policy);
+        writer.append(&[], FLAG_RECYCLED)?;
+        writer.sync()?;
+
+        Ok(writer)
+    }
+
+    /// Reserves `size` bytes of disk space for the log, without changing its reported length, so
+    /// that appends don't have to allocate new blocks. A no-op where fallocate is not available.
+    pub fn preallocate(&mut self, size: u64) -> Result<(), WalError> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            let result = unsafe {
+                libc::fallocate(
+                    self.file.as_raw_fd(),
+                    libc::FALLOC_FL_KEEP_SIZE,
+                    0,
+                    size as libc::off_t,
+                )
+            };
+
+            if result != 0 {
+                Err(io::Error::last_os_error())?
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        let _ = size;
+
+        Ok(())
+    }
+
+    /// Compresses with LZ4 the payloads of at least `threshold` bytes, or disables compression
+    /// if None. Small payloads are not worth the CPU, and compressed ones are only kept if they
+    /// are actually smaller.
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Enables or disables the manual flush mode: records are kept in memory (and the sync
+    /// policy is not applied) until the application calls [Writer::flush], so that it can batch
+    /// its own durability points. Disabling it flushes the buffered records.
+    pub fn set_manual_flush(&mut self, manual_flush: bool) -> Result<(), WalError> {
+        if !manual_flush {
+            self.flush(false)?;
+        }
+
+        self.manual_flush = manual_flush;
+
+        Ok(())
+    }
+
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    pub fn set_sync_policy(&mut self, sync_policy: SyncPolicy) {
+        self.sync_policy = sync_policy;
+    }
+
+    /// Appends a record to the log, syncing it according to the [SyncPolicy]
+    pub fn add_record(&mut self, payload: &[u8]) -> Result<(), WalError> {
+        self.append(payload, 0)?;
+
+        if self.manual_flush {
+            return Ok(());
+        }
+
+        let sync = match self.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryNWrites(n) => self.writes_since_sync >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+
+        if sync {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends a record to the log, overriding the [SyncPolicy]: if `sync` is true the log is
+    /// synced right away, otherwise the record is only handed to the OS
+    pub fn add_record_with_sync(&mut self, payload: &[u8], sync: bool) -> Result<(), WalError> {
+        self.append(payload, 0)?;
+
+        if sync {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, payload: &[u8], mut flags: u8) -> Result<(), WalError> {
+        let compressed = match self.compression_threshold {
+            Some(threshold) if payload.len() >= threshold => {
+                Some(lz4_flex::compress_prepend_size(payload))
+                    .filter(|compressed| compressed.len() < payload.len())
+            }
+            _ => None,
+        };
+
+        let payload = match &compressed {
+            Some(compressed) => {
+                flags |= FLAG_LZ4;
+                compressed.as_slice()
+            }
+            None => payload,
+        };
+
+        let mut header = [0_u8; RECORD_HEADER_SIZE];
+        header[4..8].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        header[8..12].copy_from_slice(&self.log_number.to_le_bytes());
+        header[12] = flags;
+
+        let checksum = checksum(&header[4..], payload);
+        header[..4].copy_from_slice(&checksum.to_le_bytes());
+
+        if self.manual_flush {
+            self.buffer.extend_from_slice(&header);
+            self.buffer.extend_from_slice(payload);
+        } else {
+            let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+            record.extend_from_slice(&header);
+            record.extend_from_slice(payload);
+
+            self.file.write_all(&record)?;
+        }
+
+        self.writes_since_sync += 1;
+
+        Ok(())
+    }
+
+    /// Hands the records buffered in manual flush mode to the OS, also syncing them if `sync`
+    pub fn flush(&mut self, sync: bool) -> Result<(), WalError> {
+        if !self.buffer.is_empty() {
+            self.file.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+
+        if sync {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of bytes buffered in manual flush mode
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns true if some appended records have not been synced yet
+    pub fn has_unsynced_writes(&self) -> bool {
+        self.writes_since_sync > 0
+    }
+
+    /// Makes every record appended so far durable
+    pub fn sync(&mut self) -> Result<(), WalError> {
+        let _span = span!(
+            "wal_sync",
+            log_number = self.log_number,
+            records = self.writes_since_sync
+        );
+
+        self.flush(false)?;
+        self.file.sync_data()?;
+
+        self.writes_since_sync = 0;
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Best effort: whoever needed to know about failures should have flushed explicitly
+        let _ = self.flush(false);
+    }
+}
+
+/// How long obsolete logs are kept around in the archive. Logs are deleted as soon as they
+/// exceed any of the limits; with no limits at all, obsolete logs are deleted right away
+/// instead of being archived.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Maximum age of an archived log
+    pub ttl: Option<Duration>,
+    /// Maximum total size of the archive, reached by deleting the oldest logs first
+    pub size_limit: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Returns true if obsolete logs are archived rather than deleted
+    pub fn keeps_logs(&self) -> bool {
+        self.ttl.is_some() || self.size_limit.is_some()
+    }
+}
+
+/// The directory where obsolete logs are moved into, so that they can still be read by
+/// downstream consumers (e.g. replication or auditing) after the data they contain was flushed
+pub struct WalArchive {
+    dir: PathBuf,
+    retention: RetentionPolicy,
+}
+
+impl WalArchive {
+    /// Creates the archive of the logs living in `wal_dir`
+    pub fn new(wal_dir: &Path, retention: RetentionPolicy) -> WalArchive {
+        WalArchive {
+            dir: wal_dir.join("archive"),
+            retention,
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Disposes of an obsolete log: it's moved into the archive if the retention policy wants
+    /// to keep it, deleted otherwise
+    pub fn retire(&self, log_path: &Path, log_number: u64) -> Result<(), WalError> {
+        if !self.retention.keeps_logs() {
+            std::fs::remove_file(log_path)?;
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::rename(log_path, log_file_name(&self.dir, log_number))?;
+
+        self.purge()
+    }
+
+    /// Returns the numbers of the archived logs, oldest first
+    pub fn logs(&self) -> Result<Vec<u64>, WalError> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        list_logs(&self.dir)
+    }
+
+    /// Deletes the archived logs which exceed the retention policy
+    pub fn purge(&self) -> Result<(), WalError> {
+        let mut logs = Vec::new();
+
+        for log_number in self.logs()? {
+            let path = log_file_name(&self.dir, log_number);
+            let metadata = std::fs::metadata(&path)?;
+
+            let expired = match self.retention.ttl {
+                Some(ttl) => {
+                    let age = SystemTime::now()
+                        .duration_since(metadata.modified()?)
+                        .unwrap_or_default();
+
+                    age > ttl
+                }
+                None => false,
+            };
+
+            if expired {
+                log::debug!("deleting expired archived log {}", path.display());
+                std::fs::remove_file(&path)?;
+            } else {
+                logs.push((path, metadata.len()));
+            }
+        }
+
+        if let Some(size_limit) = self.retention.size_limit {
+            let mut total_size: u64 = logs.iter().map(|(_, size)| size).sum();
+
+            for (path, size) in logs {
+                if total_size <= size_limit {
+                    break;
+                }
+
+                log::debug!(
+                    "deleting archived log {} over the size limit",
+                    path.display()
+                );
+                std::fs::remove_file(path)?;
+                total_size -= size;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the numbers of the logs stored in `dir`, oldest first
+pub fn list_logs(dir: &Path) -> Result<Vec<u64>, WalError> {
+    let mut logs = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        if let Some(log_number) = entry?.file_name().to_str().and_then(parse_log_file_name) {
+            logs.push(log_number);
+        }
+    }
+
+    logs.sort_unstable();
+
+    Ok(logs)
+}
+
+/// The logs replayed by [recover]
+#[derive(Debug, Default)]
+pub struct Recovery {
+    /// Sequence number of the last replayed write, if any
+    pub last_sequence: Option<SequenceNumber>,
+    /// Numbers and paths of the live logs, oldest first, including the ones skipped because
+    /// their writes are already in tables
+    pub logs: Vec<(u64, PathBuf)>,
+    /// The batches of the transactions prepared but neither committed nor rolled back, by name
+    pub prepared: BTreeMap<String, WriteBatch>,
+}
+
+/// Replays the live logs of the database at `db_path` into the memtables of the column families
+/// returned by `mem_of`, in log number order
+///
+/// The logs older than `min_log_number` only hold writes already stored in tables, so they are
+/// returned without being replayed. So are the writes `mem_of` returns no memtable for, given the
+/// column family and the number of their log.
+///
+/// Logs are looked up both in the configured WAL directory and in the database directory, so
+/// that the logs written before moving the WAL elsewhere are not lost.
+pub fn recover<'a, F>(
+    db_path: &Path,
+    options: &Options,
+    min_log_number: u64,
+    mut mem_of: F,
+) -> Result<Recovery, WalError>
+where
+    F: FnMut(u32, u64) -> Option<&'a MemTable>,
+{
+    let wal_dir = options.wal_dir(db_path);
+    let mut logs = Vec::new();
+
+    let mut dirs = vec![wal_dir];
+
+    if wal_dir != db_path {
+        dirs.push(db_path);
+    }
+
+    for dir in dirs {
+        if !dir.exists() {
+            continue;
+        }
+
+        for log_number in list_logs(dir)? {
+            logs.push((log_number, log_file_name(dir, log_number)));
+        }
+    }
+
+    logs.sort_unstable();
+    logs.dedup_by_key(|(log_number, _)| *log_number);
+
+    let mut recovery = Recovery::default();
+
+    for (log_number, path) in logs {
+        if log_number < min_log_number {
+            recovery.logs.push((log_number, path));
+            continue;
+        }
+
+        log::info!("replaying log {}", path.display());
+        let span = span!("replay_log", log_number = log_number; last_sequence);
+
+        let last_sequence = Reader::open(&path, log_number)?.replay_into_column_families(
+            |column_family_id| mem_of(column_family_id, log_number),
+            &mut recovery.prepared,
+        )?;
+
+        if let Some(last_sequence) = last_sequence {
+            span.record("last_sequence", last_sequence);
+        }
+
+        recovery.last_sequence = last_sequence.or(recovery.last_sequence);
+        recovery.logs.push((log_number, path));
+    }
+
+    Ok(recovery)
+}
+
+/// Returns an iterator over the write batches containing sequence numbers >= `seq`, read from
+/// the live and archived logs of `wal_dir`.
+///
+/// Batches are returned whole, so the first one may also contain some writes older than `seq`.
+/// If the logs containing `seq` were already purged, the iterator returns
+/// [WalError::UpdatesUnavailable].
+pub fn get_updates_since(wal_dir: &Path, seq: SequenceNumber) -> Result<UpdatesIterator, WalError> {
+    let archive = WalArchive::new(wal_dir, RetentionPolicy::default());
+
+    let mut logs = archive.logs()?;
+    logs.extend(list_logs(wal_dir)?);
+    logs.sort_unstable();
+    logs.dedup();
+
+    Ok(UpdatesIterator {
+        wal_dir: wal_dir.to_path_buf(),
+        archive_dir: archive.dir().to_path_buf(),
+        logs: logs.into_iter().rev().collect(),
+        reader: None,
+        next_sequence: seq,
+        failed: false,
+    })
+}
+
+/// Iterator over the write batches stored in the logs, see [get_updates_since]
+pub struct UpdatesIterator {
+    wal_dir: PathBuf,
+    archive_dir: PathBuf,
+    /// Logs still to be read, the next one last
+    logs: Vec<u64>,
+    reader: Option<Reader>,
+    next_sequence: SequenceNumber,
+    failed: bool,
+}
+
+impl UpdatesIterator {
+    /// Opens the next log, looking into the archive if it was retired in the meantime
+    fn open_next_log(&mut self) -> Result<bool, WalError> {
+        let log_number = match self.logs.pop() {
+            Some(log_number) => log_number,
+            None => return Ok(false),
+        };
+
+        let reader = match Reader::open(log_file_name(&self.wal_dir, log_number), log_number) {
+            Err(WalError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                Reader::open(log_file_name(&self.archive_dir, log_number), log_number)?
+            }
+            reader => reader?,
+        };
+
+        self.reader = Some(reader);
+
+        Ok(true)
+    }
+
+    fn next_batch(&mut self) -> Result<Option<WriteBatch>, WalError> {
+        loop {
+            let record = match self.reader.as_mut() {
+                Some(reader) => reader.read_record()?,
+                None => None,
+            };
+
+            let batch = match record {
+                Some(record) => WriteBatch::from_data(record)?,
+                None if self.open_next_log()? => continue,
+                None => return Ok(None),
+            };
+
+            // The writes of a prepared transaction only get sequence numbers once it commits
+            let unsequenced = matches!(
+                batch.marker(),
+                Some((Marker::Prepare | Marker::Rollback, _))
+            );
+
+            if unsequenced || batch.is_empty() || batch.last_sequence() < self.next_sequence {
+                continue;
+            }
+
+            if batch.sequence() > self.next_sequence {
+                Err(WalError::UpdatesUnavailable {
+                    requested: self.next_sequence,
+                    available: batch.sequence(),
+                })?
+            }
+
+            self.next_sequence = batch.last_sequence() + 1;
+
+            return Ok(Some(batch));
+        }
+    }
+}
+
+impl Iterator for UpdatesIterator {
+    type Item = Result<WriteBatch, WalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let batch = self.next_batch().transpose();
+        self.failed = matches!(batch, Some(Err(_)));
+
+        batch
+    }
+}
+
+/// Reads back the records written by a [Writer]
+///
+/// A crash can leave the last record partially written (a "torn tail"): such a record is
+/// silently ignored, since it was never acknowledged as durable. A corrupted record followed
+/// by more data is instead reported as an error.
+///
+/// In a recycled file, see [FLAG_RECYCLED], the records of the log are followed by the stale
+/// ones of an older log, and a torn record may be followed by them too: the log ends at the
+/// first record which fails its checksum or has a different log number. Elsewhere, a record of
+/// another log is reported as an error.
+pub struct Reader {
+    file: BufReader<File>,
+    log_number: u32,
+    offset: u64,
+    file_len: u64,
+    /// Whether the records read so far were written over a recycled file
+    recycled: bool,
+}
+
+impl Reader {
+    pub fn new(file: File, log_number: u64) -> Result<Reader, WalError> {
+        let file_len = file.metadata()?.len();
+
+        Ok(Reader {
+            file: BufReader::new(file),
+            log_number: log_number as u32,
+            offset: 0,
+            file_len,
+            recycled: false,
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, log_number: u64) -> Result<Reader, WalError> {
+        Reader::new(File::open(path)?, log_number)
+    }
+
+    /// Reads the next complete record, returning None at the end of the log (or at a torn tail)
+    pub fn read_record(&mut self) -> Result<Option<Vec<u8>>, WalError> {
+        let remaining = self.file_len - self.offset;
+
+        if remaining < RECORD_HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+
+        let mut header = [0_u8; RECORD_HEADER_SIZE];
+        self.file.read_exact(&mut header)?;
+
+        let expected_checksum = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+        let log_number = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let flags = header[12];
+        let record_size = RECORD_HEADER_SIZE as u64 + length;
+
+        if record_size > remaining {
+            // A garbage length is only a torn tail if nothing was written after the record
+            if !self.recycled && self.log_continues_after(self.offset)? {
+                return Err(WalError::Corruption {
+                    offset: self.offset,
+                });
+            }
+
+            log::warn!(
+                "ignoring the torn record at offset {} of log {}",
+                self.offset,
+                self.log_number
+            );
+            self.offset = self.file_len;
+            return Ok(None);
+        }
+
+        let mut payload = vec![0_u8; length as usize];
+        self.file.read_exact(&mut payload)?;
+
+        if checksum(&header[4..], &payload) != expected_checksum {
+            if self.recycled || self.offset + record_size == self.file_len {
+                log::warn!(
+                    "ignoring the torn record at offset {} of log {}",
+                    self.offset,
+                    self.log_number
+                );
+                self.offset = self.file_len;
+                return Ok(None);
+            }
+
+            return Err(WalError::Corruption {
+                offset: self.offset,
+            });
+        }
+
+        if log_number != self.log_number {
+            // A stale record of an older log, which a recycled file starts with if the crash
+            // came before its first record was written
+            if self.recycled || self.offset == 0 {
+                self.offset = self.file_len;
+                return Ok(None);
+            }
+
+            return Err(WalError::Corruption {
+                offset: self.offset,
+            });
+        }
+
+        let record_offset = self.offset;
+        self.offset += record_size;
+
+        if flags & FLAG_RECYCLED != 0 {
+            self.recycled = true;
+            return self.read_record();
+        }
+
+        if flags & FLAG_LZ4 != 0 {
+            return lz4_flex::decompress_size_prepended(&payload)
+                .map(Some)
+                .map_err(|_| WalError::Corruption {
+                    offset: record_offset,
+                });
+        }
+
+        Ok(Some(payload))
+    }
+
+    /// Returns whether a complete record of the log starts after `offset`, which then can't be
+    /// the start of the last write before a crash
+    fn log_continues_after(&self, offset: u64) -> Result<bool, WalError> {
+        let mut rest = vec![0_u8; (self.file_len - offset - 1) as usize];
+        self.file.get_ref().read_exact_at(&mut rest, offset + 1)?;
+
+        let continues = (0..rest.len().saturating_sub(RECORD_HEADER_SIZE - 1)).any(|start| {
+            let header = &rest[start..start + RECORD_HEADER_SIZE];
+            let length = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+            let log_number = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            let end = start + RECORD_HEADER_SIZE + length;
+
+            log_number == self.log_number
+                && end <= rest.len()
+                && checksum(&header[4..], &rest[start + RECORD_HEADER_SIZE..end])
+                    == u32::from_le_bytes(header[..4].try_into().unwrap())
+        });
+
+        Ok(continues)
+    }
+
+    /// Replays every complete record of the log into `mem`, skipping the writes of column
+    /// families other than the default one
+    ///
+    /// Returns the sequence number of the last replayed write, if any
+    pub fn replay_into(&mut self, mem: &MemTable) -> Result<Option<SequenceNumber>, WalError> {
+        self.replay_into_column_families(
+            |column_family| (column_family == DEFAULT_COLUMN_FAMILY_ID).then_some(mem),
+            &mut BTreeMap::new(),
+        )
+    }
+
+    /// Replays every complete record of the log into the memtables of the column families
+    /// returned by `mem_of`, except the batches of the prepared transactions which are kept in
+    /// `prepared` by name until they commit or roll back
+    ///
+    /// Returns the sequence number of the last replayed write, if any
+    pub fn replay_into_column_families<'a, F>(
+        &mut self,
+        mut mem_of: F,
+        prepared: &mut BTreeMap<String, WriteBatch>,
+    ) -> Result<Option<SequenceNumber>, WalError>
+    where
+        F: FnMut(u32) -> Option<&'a MemTable>,
+    {
+        let mut last_sequence = None;
+
+        while let Some(record) = self.read_record()? {
+            let batch = WriteBatch::from_data(record)?;
+
+            match batch.marker() {
+                Some((Marker::Prepare, name)) => {
+                    prepared.insert(name, batch.without_marker());
+                    continue;
+                }
+                Some((Marker::Rollback, name)) => {
+                    prepared.remove(&name);
+                    continue;
+                }
+                Some((Marker::Commit, name)) => {
+                    prepared.remove(&name);
+                }
+                None => {}
+            }
+
+            batch.insert_into_column_families(&mut mem_of)?;
+
+            if !batch.is_empty() {
+                last_sequence = Some(batch.last_sequence());
+            }
+        }
+
+        Ok(last_sequence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::WriteBatch;
+    use crate::memtable::{LookupResult, MemTable};
+    use crate::options::Options;
+    use crate::wal::{
+        get_updates_since, log_file_name, recover, Reader, RetentionPolicy, SyncPolicy, WalArchive,
+        WalError, Writer,
+    };
+    use std::fs::OpenOptions;
+    use std::time::{Duration, SystemTime};
+
+    fn write_batches(path: &std::path::Path, batches: u64) {
+        let mut writer = Writer::create(path, 1, SyncPolicy::Always).unwrap();
+
+        for n in 0..batches {
+            let mut batch = WriteBatch::new();
+            batch.set_sequence(n * 2 + 1);
+            batch.put(format!("key{}", n).as_bytes(), b"value");
+            batch.delete(b"deleted");
+
+            writer.add_record(batch.data()).unwrap();
+        }
+
+        writer.sync().unwrap();
+    }
+
+    #[test]
+    fn replay_restores_memtable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.log");
+
+        write_batches(&path, 10);
+
+        let mem = MemTable::new();
+        let last_sequence = Reader::open(&path, 1).unwrap().replay_into(&mem).unwrap();
+
+        assert_eq!(last_sequence, Some(20));
+        assert_eq!(mem.len(), 20);
+        assert_eq!(
+            mem.get(b"key9", 20),
+            Some(LookupResult::Value(b"value".to_vec()))
+        );
+        assert_eq!(mem.get(b"deleted", 20), Some(LookupResult::Deleted));
+    }
+
+    #[test]
+    fn recover_from_separate_wal_dir() {
+        let db_dir = tempfile::tempdir().unwrap();
+        let wal_dir = tempfile::tempdir().unwrap();
+
+        let options = Options {
+            wal_dir: Some(wal_dir.path().to_path_buf()),
+            ..Options::default()
+        };
+
+        // A log written before the WAL was moved to its own directory
+        let mut writer =
+            Writer::create(log_file_name(db_dir.path(), 1), 1, SyncPolicy::Always).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(1);
+        batch.put(b"old", b"1");
+        writer.add_record(batch.data()).unwrap();
+
+        let mut writer =
+            Writer::create(log_file_name(wal_dir.path(), 2), 2, SyncPolicy::Always).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(2);
+        batch.put(b"new", b"2");
+        writer.add_record(batch.data()).unwrap();
+
+        let mem = MemTable::new();
+        let recovery = recover(db_dir.path(), &options, 0, |_, _| Some(&mem)).unwrap();
+
+        assert_eq!(recovery.last_sequence, Some(2));
+        assert_eq!(recovery.logs.len(), 2);
+        assert_eq!(mem.get(b"old", 2), Some(LookupResult::Value(b"1".to_vec())));
+        assert_eq!(mem.get(b"new", 2), Some(LookupResult::Value(b"2".to_vec())));
+    }
+
+    #[test]
+    fn torn_tail_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.log");
+
+        write_batches(&path, 3);
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+
+        let mut reader = Reader::open(&path, 1).unwrap();
+        let mut records = 0;
+
+        while reader.read_record().unwrap().is_some() {
+            records += 1;
+        }
+
+        assert_eq!(records, 2);
+    }
+
+    #[test]
+    fn recycled_log_ignores_stale_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_path = log_file_name(dir.path(), 1);
+        let path = log_file_name(dir.path(), 2);
+
+        write_batches(&old_path, 10);
+
+        let mut writer = Writer::recycle(&old_path, &path, 2, SyncPolicy::Always).unwrap();
+        writer.preallocate(1 << 20).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(100);
+        batch.put(b"key0", b"recycled");
+        writer.add_record(batch.data()).unwrap();
+
+        let mem = MemTable::new();
+        let last_sequence = Reader::open(&path, 2).unwrap().replay_into(&mem).unwrap();
+
+        assert!(!old_path.exists());
+        assert_eq!(last_sequence, Some(100));
+        assert_eq!(mem.len(), 1);
+        assert_eq!(
+            mem.get(b"key0", 100),
+            Some(LookupResult::Value(b"recycled".to_vec()))
+        );
+    }
+
+    #[test]
+    fn torn_writes_over_recycled_files_end_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_path = log_file_name(dir.path(), 1);
+        let path = log_file_name(dir.path(), 2);
+
+        write_batches(&old_path, 10);
+        let old_data = std::fs::read(&old_path).unwrap();
+
+        let mut writer = Writer::recycle(&old_path, &path, 2, SyncPolicy::Always).unwrap();
+        writer.add_record(b"first").unwrap();
+        writer.add_record(b"second").unwrap();
+        writer.add_record(&[7; 100]).unwrap();
+        // After the empty record marking the recycled file and the two others
+        let torn_offset = 13 + (13 + 5) + (13 + 6);
+
+        // Only the header and the start of the last record made it over the stale bytes
+        let mut data = std::fs::read(&path).unwrap();
+        let torn_end = torn_offset + 113;
+        data[torn_offset + 20..torn_end].copy_from_slice(&old_data[torn_offset + 20..torn_end]);
+        assert!(torn_end < data.len());
+        std::fs::write(&path, data).unwrap();
+
+        let mut reader = Reader::open(&path, 2).unwrap();
+        assert_eq!(reader.read_record().unwrap(), Some(b"first".to_vec()));
+        assert_eq!(reader.read_record().unwrap(), Some(b"second".to_vec()));
+        assert_eq!(reader.read_record().unwrap(), None);
+    }
+
+    #[test]
+    fn recycled_files_may_hold_no_record_of_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let old_path = log_file_name(dir.path(), 1);
+        let path = log_file_name(dir.path(), 2);
+
+        write_batches(&old_path, 3);
+        Writer::recycle(&old_path, &path, 2, SyncPolicy::Always).unwrap();
+
+        assert_eq!(Reader::open(&path, 2).unwrap().read_record().unwrap(), None);
+    }
+
+    #[test]
+    fn bad_log_numbers_in_the_middle_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.log");
+
+        write_batches(&path, 3);
+
+        let mut data = std::fs::read(&path).unwrap();
+        let second = 13 + u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        data[second + 8] ^= 0x01;
+        std::fs::write(&path, data).unwrap();
+
+        let mut reader = Reader::open(&path, 1).unwrap();
+        assert!(reader.read_record().unwrap().is_some());
+        assert!(matches!(
+            reader.read_record(),
+            Err(WalError::Corruption { offset }) if offset == second as u64
+        ));
+    }
+
+    #[test]
+    fn compressed_records_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = log_file_name(dir.path(), 1);
+
+        let mut writer = Writer::create(&path, 1, SyncPolicy::Always).unwrap();
+        writer.set_compression_threshold(Some(64));
+
+        let large = vec![7_u8; 4096];
+        writer.add_record(&large).unwrap();
+        writer.add_record(b"small").unwrap();
+
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024);
+
+        let mut reader = Reader::open(&path, 1).unwrap();
+
+        assert_eq!(reader.read_record().unwrap(), Some(large));
+        assert_eq!(reader.read_record().unwrap(), Some(b"small".to_vec()));
+        assert_eq!(reader.read_record().unwrap(), None);
+    }
+
+    #[test]
+    fn archive_retention() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for log_number in 1..=4 {
+            std::fs::write(log_file_name(dir.path(), log_number), [0_u8; 100]).unwrap();
+        }
+
+        let deleting = WalArchive::new(dir.path(), RetentionPolicy::default());
+        deleting.retire(&log_file_name(dir.path(), 1), 1).unwrap();
+
+        assert!(!log_file_name(dir.path(), 1).exists());
+        assert!(deleting.logs().unwrap().is_empty());
+
+        let archive = WalArchive::new(
+            dir.path(),
+            RetentionPolicy {
+                ttl: Some(Duration::from_secs(3600)),
+                size_limit: Some(250),
+            },
+        );
+
+        for log_number in 2..=4 {
+            archive
+                .retire(&log_file_name(dir.path(), log_number), log_number)
+                .unwrap();
+        }
+
+        // The size limit only leaves room for two logs
+        assert_eq!(archive.logs().unwrap(), vec![3, 4]);
+
+        let old = SystemTime::now() - Duration::from_secs(7200);
+        let file = OpenOptions::new()
+            .write(true)
+            .open(log_file_name(archive.dir(), 3))
+            .unwrap();
+        file.set_modified(old).unwrap();
+
+        archive.purge().unwrap();
+
+        assert_eq!(archive.logs().unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn updates_since_spans_archived_and_live_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = WalArchive::new(
+            dir.path(),
+            RetentionPolicy {
+                ttl: None,
+                size_limit: Some(1 << 20),
+            },
+        );
+
+        let mut seq = 1;
+
+        for log_number in 1..=3 {
+            let mut writer = Writer::create(
+                log_file_name(dir.path(), log_number),
+                log_number,
+                SyncPolicy::Always,
+            )
+            .unwrap();
+
+            for _ in 0..5 {
+                let mut batch = WriteBatch::new();
+                batch.set_sequence(seq);
+                batch.put(b"a", b"1");
+                batch.put(b"b", b"2");
+                writer.add_record(batch.data()).unwrap();
+
+                seq += 2;
+            }
+        }
+
+        archive.retire(&log_file_name(dir.path(), 1), 1).unwrap();
+
+        let sequences: Vec<_> = get_updates_since(dir.path(), 8)
+            .unwrap()
+            .map(|batch| batch.unwrap().sequence())
+            .collect();
+
+        assert_eq!(sequences, (7..30).step_by(2).collect::<Vec<_>>());
+
+        archive.retire(&log_file_name(dir.path(), 2), 2).unwrap();
+        std::fs::remove_file(log_file_name(archive.dir(), 1)).unwrap();
+        std::fs::remove_file(log_file_name(archive.dir(), 2)).unwrap();
+
+        let mut updates = get_updates_since(dir.path(), 8).unwrap();
+
+        assert!(matches!(
+            updates.next(),
+            Some(Err(WalError::UpdatesUnavailable {
+                requested: 8,
+                available: 21
+            }))
+        ));
+        assert!(updates.next().is_none());
+    }
+
+    #[test]
+    fn manual_flush_buffers_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = log_file_name(dir.path(), 1);
+
+        let mut writer = Writer::create(&path, 1, SyncPolicy::Always).unwrap();
+        writer.set_manual_flush(true).unwrap();
+
+        writer.add_record(b"buffered").unwrap();
+
+        assert!(writer.buffered_bytes() > 0);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        writer.flush(true).unwrap();
+
+        assert_eq!(writer.buffered_bytes(), 0);
+        assert!(!writer.has_unsynced_writes());
+
+        let mut reader = Reader::open(&path, 1).unwrap();
+
+        assert_eq!(reader.read_record().unwrap(), Some(b"buffered".to_vec()));
+    }
+
+    #[test]
+    fn sync_policies() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut writer =
+            Writer::create(dir.path().join("1.log"), 1, SyncPolicy::EveryNWrites(3)).unwrap();
+
+        writer.add_record(b"1").unwrap();
+        writer.add_record(b"2").unwrap();
+        assert!(writer.has_unsynced_writes());
+
+        writer.add_record(b"3").unwrap();
+        assert!(!writer.has_unsynced_writes());
+
+        writer.add_record_with_sync(b"4", true).unwrap();
+        assert!(!writer.has_unsynced_writes());
+
+        writer.set_sync_policy(SyncPolicy::Interval(Duration::from_secs(3600)));
+        writer.add_record(b"5").unwrap();
+        assert!(writer.has_unsynced_writes());
+
+        writer.set_sync_policy(SyncPolicy::Always);
+        writer.add_record_with_sync(b"6", false).unwrap();
+        assert!(writer.has_unsynced_writes());
+
+        writer.add_record(b"7").unwrap();
+        assert!(!writer.has_unsynced_writes());
+    }
+
+    #[test]
+    fn bad_lengths_in_the_middle_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.log");
+
+        write_batches(&path, 3);
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, data).unwrap();
+
+        let mut reader = Reader::open(&path, 1).unwrap();
+
+        assert!(matches!(
+            reader.read_record(),
+            Err(WalError::Corruption { offset: 0 })
+        ));
+    }
+
+    #[test]
+    fn corruption_in_the_middle_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("000001.log");
+
+        write_batches(&path, 3);
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[12] ^= 0xff;
+        std::fs::write(&path, data).unwrap();
+
+        let mut reader = Reader::open(&path, 1).unwrap();
+
+        assert!(matches!(
+            reader.read_record(),
+            Err(WalError::Corruption { offset: 0 })
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::batch::WriteBatch;
+use crate::blob::BlobIndex;
+use crate::key::{SequenceNumber, ValueType};
+use crate::ttl;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::Duration;
+
+/// A write made to a watched key range, see [Db::watch](crate::Db::watch)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub column_family_id: u32,
+    pub sequence: SequenceNumber,
+    pub key: Vec<u8>,
+    pub change: Change,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Put(Vec<u8>),
+    /// A value of `size` bytes streamed to a blob file, which isn't read to be sent, see
+    /// [Db::put_reader](crate::Db::put_reader)
+    PutLarge {
+        size: u64,
+    },
+    Delete,
+    /// A merge operand, the merged value isn't computed
+    Merge(Vec<u8>),
+    /// The keys in [key, end) were deleted
+    DeleteRange {
+        end: Vec<u8>,
+    },
+}
+
+/// The watches of a database, fed by its write path
+#[derive(Default)]
+pub(crate) struct Watchers {
+    /// The watches dropped by their owner are pruned when the next write is published
+    channels: Mutex<Vec<Weak<Channel>>>,
+}
+
+impl Watchers {
+    pub(crate) fn add(
+        &self,
+        column_family_id: u32,
+        lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
+    ) -> Watch {
+        let channel = Arc::new(Channel {
+            column_family_id,
+            lower,
+            upper,
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+        });
+
+        self.channels.lock().unwrap().push(Arc::downgrade(&channel));
+
+        Watch { channel }
+    }
+
+    /// Sends the writes of `batch`, once visible, to the watches of the ranges they touch
+    pub(crate) fn publish(&self, batch: &WriteBatch) {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|channel| channel.strong_count() > 0);
+
+        if channels.is_empty() {
+            return;
+        }
+
+        let channels: Vec<Arc<Channel>> = channels.iter().filter_map(Weak::upgrade).collect();
+
+        for (n, op) in batch.iter().enumerate() {
+            let Ok(op) = op else {
+                return;
+            };
+
+            let change = match op.value_type {
+                ValueType::Value => Change::Put(op.value.to_vec()),
+                ValueType::ValueWithExpiry => match ttl::decode_value(op.value) {
+                    Some((_, value)) => Change::Put(value.to_vec()),
+                    None => continue,
+                },
+                ValueType::Deletion => Change::Delete,
+                ValueType::Merge => Change::Merge(op.value.to_vec()),
+                ValueType::RangeDeletion => Change::DeleteRange {
+                    end: op.value.to_vec(),
+                },
+                ValueType::BlobIndex => match BlobIndex::decode(op.value) {
+                    Ok(index) => Change::PutLarge { size: index.size },
+                    Err(_) => continue,
+                },
+            };
+
+            let event = ChangeEvent {
+                column_family_id: op.column_family,
+                sequence: batch.sequence() + n as u64,
+                key: op.key.to_vec(),
+                change,
+            };
+
+            for channel in channels.iter().filter(|channel| channel.matches(&event)) {
+                channel.send(event.clone());
+            }
+        }
+    }
+
+    /// Ends every watch once the database is closed, after the events already sent
+    pub(crate) fn close(&self) {
+        for channel in self.channels.lock().unwrap().drain(..) {
+            if let Some(channel) = channel.upgrade() {
+                channel.close();
+            }
+        }
+    }
+}
+
+struct Channel {
+    column_family_id: u32,
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    queue: Mutex<Queue>,
+    /// Notified when an event is sent or the channel is closed
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    events: VecDeque<ChangeEvent>,
+    closed: bool,
+    #[cfg(feature = "async")]
+    waker: Option<std::task::Waker>,
+}
+
+impl Channel {
+    /// Tells whether `event` touches the watched range
+    fn matches(&self, event: &ChangeEvent) -> bool {
+        if event.column_family_id != self.column_family_id {
+            return false;
+        }
+
+        let first = event.key.as_slice();
+        let before_upper = match &self.upper {
+            Bound::Included(upper) => first <= upper.as_slice(),
+            Bound::Excluded(upper) => first < upper.as_slice(),
+            Bound::Unbounded => true,
+        };
+
+        match (&event.change, &self.lower) {
+            // The deleted range ends before the watched one if its exclusive end isn't after the
+            // lower bound, counting an excluded lower bound as included
+            (Change::DeleteRange { end }, Bound::Included(lower) | Bound::Excluded(lower)) => {
+                before_upper && end.as_slice() > lower.as_slice()
+            }
+            (_, Bound::Included(lower)) => before_upper && first >= lower.as_slice(),
+            (_, Bound::Excluded(lower)) => before_upper && first > lower.as_slice(),
+            (_, Bound::Unbounded) => before_upper,
+        }
+    }
+
+    fn send(&self, event: ChangeEvent) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.events.push_back(event);
+        self.wake(&mut queue);
+    }
+
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        self.wake(&mut queue);
+    }
+
+    fn wake(&self, _queue: &mut Queue) {
+        self.ready.notify_all();
+
+        #[cfg(feature = "async")]
+        if let Some(waker) = _queue.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Receives the writes made to a key range of a column family after its creation, in the order
+/// they become visible, see [Db::watch](crate::Db::watch)
+///
+/// The events are queued until received, however slowly. Once the database is closed, the
+/// queued events are received, then the watch ends. With the `async` feature, the watch is also
+/// a `futures_core::Stream` of events.
+pub struct Watch {
+    channel: Arc<Channel>,
+}
+
+impl Watch {
+    /// Waits for the next event, returning None once the database is closed
+    pub fn recv(&self) -> Option<ChangeEvent> {
+        let mut queue = self.channel.queue.lock().unwrap();
+
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                return Some(event);
+            }
+
+            if queue.closed {
+                return None;
+            }
+
+            queue = self.channel.ready.wait(queue).unwrap();
+        }
+    }
+
+    /// Same as [Watch::recv], giving up after `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<ChangeEvent> {
+        let queue = self.channel.queue.lock().unwrap();
+        let (mut queue, _) = self
+            .channel
+            .ready
+            .wait_timeout_while(queue, timeout, |queue| {
+                queue.events.is_empty() && !queue.closed
+            })
+            .unwrap();
+
+        queue.events.pop_front()
+    }
+
+    /// Returns the next event if one was already sent
+    pub fn try_recv(&self) -> Option<ChangeEvent> {
+        self.channel.queue.lock().unwrap().events.pop_front()
+    }
+}
+
+#[cfg(feature = "async")]
+impl futures_core::Stream for Watch {
+    type Item = ChangeEvent;
+
+    fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<ChangeEvent>> {
+        let mut queue = self.channel.queue.lock().unwrap();
+
+        if let Some(event) = queue.events.pop_front() {
+            return std::task::Poll::Ready(Some(event));
+        }
+
+        if queue.closed {
+            return std::task::Poll::Ready(None);
+        }
+
+        queue.waker = Some(cx.waker().clone());
+
+        std::task::Poll::Pending
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use crate::db::Db;
+    use crate::options::Options;
+    use futures_core::Stream;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    #[test]
+    fn watches_are_streams() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), Options::default()).unwrap();
+        let mut watch = db.watch(..);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(Pin::new(&mut watch).poll_next(&mut cx).is_pending());
+
+        db.put(b"key", b"value").unwrap();
+        let Poll::Ready(Some(event)) = Pin::new(&mut watch).poll_next(&mut cx) else {
+            panic!("the write wasn't received");
+        };
+        assert_eq!(event.key, b"key");
+
+        drop(db);
+        assert_eq!(Pin::new(&mut watch).poll_next(&mut cx), Poll::Ready(None));
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Caps the memory of the memtables of every database and column family it's shared with, see
+/// [Options::with_write_buffer_manager](crate::Options::with_write_buffer_manager)
+///
+/// Each database charges the manager for the memory of its memtables as they grow, and flushes
+/// the largest one once the total gets close to the cap.
+#[derive(Debug)]
+pub struct WriteBufferManager {
+    buffer_size: usize,
+    memory_usage: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    /// Returns a manager capping the memtables to `buffer_size` bytes in total
+    pub fn new(buffer_size: usize) -> WriteBufferManager {
+        WriteBufferManager {
+            buffer_size,
+            memory_usage: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Returns the bytes used by the memtables charged to the manager
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the memtables should be flushed, once they use 7/8 of the cap: flushing
+    /// before it's reached leaves room for the writes going on meanwhile
+    pub fn should_flush(&self) -> bool {
+        self.memory_usage() >= self.buffer_size - self.buffer_size / 8
+    }
+
+    /// Charges the manager for `bytes` more of memtables
+    pub(crate) fn reserve(&self, bytes: usize) {
+        self.memory_usage.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Gives back `bytes` of memtables charged with [WriteBufferManager::reserve]
+    pub(crate) fn free(&self, bytes: usize) {
+        self.memory_usage.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}